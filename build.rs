@@ -0,0 +1,29 @@
+//! Writes `usync.h` for the C library of the `ffi` feature; nothing otherwise.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    write_header();
+}
+
+/// Declare `src/ffi.rs` in `$OUT_DIR/usync.h`. The copy C callers use is
+/// `include/usync.h`, which a test of `src/ffi.rs` keeps the same as this one.
+#[cfg(feature = "ffi")]
+fn write_header() {
+    use std::path::PathBuf;
+
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        include_guard: Some("USYNC_H".to_string()),
+        header: Some("/* Generated from src/ffi.rs by cbindgen; do not edit. */".to_string()),
+        cpp_compat: true,
+        ..Default::default()
+    };
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("could not generate usync.h")
+        .write_to_file(out_dir.join("usync.h"));
+}
@@ -0,0 +1,12 @@
+#![no_main]
+//! `find -printf` / BusyBox `wc -c` listings from an SSH host.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|output: &str| {
+    if let Ok(listing) = usync::parse::file_list(output) {
+        for entry in &listing.entries {
+            assert!(!entry.path.is_empty());
+        }
+    }
+});
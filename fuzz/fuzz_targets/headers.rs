@@ -0,0 +1,8 @@
+#![no_main]
+//! `curl -I -L` response headers.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|head: &str| {
+    let _ = usync::parse::content_length(head);
+});
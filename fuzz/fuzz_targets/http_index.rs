@@ -0,0 +1,10 @@
+#![no_main]
+//! Directory index pages from Apache, nginx and Python's http.server.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|html: &str| {
+    for entry in usync::parse::http_index(html) {
+        assert!(!entry.name.is_empty() && !entry.name.contains('/'));
+    }
+});
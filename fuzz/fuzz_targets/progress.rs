@@ -0,0 +1,15 @@
+#![no_main]
+//! Progress lines of scp, curl, wget and `aws s3 cp`.
+
+use libfuzzer_sys::fuzz_target;
+use usync::progress::{Reading, Tool};
+
+fuzz_target!(|output: &str| {
+    for line in output.split(['\r', '\n']) {
+        for tool in [Tool::Scp, Tool::Curl, Tool::Wget, Tool::Aws] {
+            if let Some(Reading::Percent(p)) = tool.parse(line) {
+                assert!((0.0..=100.0).contains(&p));
+            }
+        }
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+//! `aws s3api list-objects-v2 --output text` listings, under a few kinds of prefix.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|output: &str| {
+    for prefix in ["", "logs/", "logs", "logs/a.gz"] {
+        if let Ok(listing) = usync::parse::s3_listing(output, prefix) {
+            for entry in &listing.entries {
+                assert!(!entry.path.is_empty() && !entry.path.ends_with('/'));
+            }
+        }
+    }
+});
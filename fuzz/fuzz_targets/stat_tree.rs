@@ -0,0 +1,10 @@
+#![no_main]
+//! The `dir`/`file` and `wc -c` output of a remote stat.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|output: &str| {
+    if let Ok(tree) = usync::parse::stat_tree(output) {
+        assert!(tree.is_dir || tree.sizes.len() == 1);
+    }
+});
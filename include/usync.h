@@ -0,0 +1,70 @@
+/* Generated from src/ffi.rs by cbindgen; do not edit. */
+
+#ifndef USYNC_H
+#define USYNC_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Called as a local copy writes: the source file, the bytes of it written so far, its
+ * size, and the `user_data` given with the callback. `path` is only valid during the
+ * call.
+ */
+typedef void (*UsyncProgressCallback)(const char *path,
+                                      uint64_t copied,
+                                      uint64_t total,
+                                      void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Copy `src` to `dst` with the JSON `options_json` (NULL for the defaults) and return
+ * the report as JSON, or NULL on failure.
+ *
+ * # Safety
+ *
+ * `src`, `dst` and `options_json` must each be NULL or point to a NUL-terminated string.
+ */
+char *usync_copy(const char *src, const char *dst, const char *options_json);
+
+/**
+ * [`usync_copy`] that skips files already matching at the destination (by size and
+ * modification time).
+ *
+ * # Safety
+ *
+ * As for [`usync_copy`].
+ */
+char *usync_sync(const char *src, const char *dst, const char *options_json);
+
+/**
+ * Call `callback` with the progress of every copy from now on, on the thread running
+ * the copy; NULL stops the calls.
+ */
+void usync_set_progress_callback(UsyncProgressCallback callback, void *user_data);
+
+/**
+ * Why the last [`usync_copy`] or [`usync_sync`] on this thread returned NULL, or NULL
+ * when it succeeded. Free the message with [`usync_free_string`].
+ */
+char *usync_last_error(void);
+
+/**
+ * Free a string returned by usync. NULL is ignored.
+ *
+ * # Safety
+ *
+ * `s` must be NULL or a string returned by usync that wasn't freed yet.
+ */
+void usync_free_string(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* USYNC_H */
@@ -0,0 +1,96 @@
+//! `--append`: bring a local destination up to date with a source that only grew, such
+//! as a log file still being written, by copying just the new tail.
+//!
+//! A destination shorter than its source is appended to when its last 64 KiB hash the
+//! same as the source's bytes at that place. Anything else, a destination that is
+//! missing, empty, as long as the source or whose end was rewritten, gets a whole copy.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// How much of the end of the destination is checked against the source.
+pub const CHECKED: u64 = 64 * 1024;
+
+/// What [`append`] found, and did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Appended {
+    /// The source's tail of this many bytes was written after the destination's end.
+    Tail(u64),
+    /// The destination is missing, empty, or not shorter than the source.
+    NoPrefix,
+    /// The destination is shorter, but its end differs from the source there.
+    Mismatch,
+}
+
+/// Append the part of `src` past the end of `dst` to `dst`, if `dst` is a shorter copy
+/// of the start of `src`.
+pub fn append(src: &Path, dst: &Path) -> io::Result<Appended> {
+    let dst_len = match fs::metadata(dst) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => return Ok(Appended::NoPrefix),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Appended::NoPrefix),
+        Err(e) => return Err(e),
+    };
+    let mut input = fs::File::open(src)?;
+    let src_len = input.metadata()?.len();
+    if dst_len == 0 || dst_len >= src_len {
+        return Ok(Appended::NoPrefix);
+    }
+
+    let mut output = fs::OpenOptions::new().read(true).append(true).open(dst)?;
+    let from = dst_len.saturating_sub(CHECKED);
+    if hash_range(&mut input, from, dst_len)? != hash_range(&mut output, from, dst_len)? {
+        return Ok(Appended::Mismatch);
+    }
+    input.seek(SeekFrom::Start(dst_len))?;
+    let appended = io::copy(&mut input.take(src_len - dst_len), &mut output)?;
+    Ok(Appended::Tail(appended))
+}
+
+/// The SHA-256 of the bytes of `file` from `start` to `end`.
+fn hash_range(file: &mut fs::File, start: u64, end: u64) -> io::Result<[u8; 32]> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file.take(end - start), &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_append() {
+        let temp = TempDir::new().unwrap();
+        let (src, dst) = (temp.path().join("app.log"), temp.path().join("copy.log"));
+        let lines: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        fs::write(&src, &lines).unwrap();
+        fs::write(&dst, &lines[..100_000]).unwrap();
+
+        assert_eq!(
+            append(&src, &dst).unwrap(),
+            Appended::Tail(lines.len() as u64 - 100_000)
+        );
+        assert_eq!(fs::read(&dst).unwrap(), lines);
+        // Now as long as the source.
+        assert_eq!(append(&src, &dst).unwrap(), Appended::NoPrefix);
+
+        // A byte changed within the checked end.
+        let mut rewritten = lines[..100_000].to_vec();
+        rewritten[99_000] = b'#';
+        fs::write(&dst, &rewritten).unwrap();
+        assert_eq!(append(&src, &dst).unwrap(), Appended::Mismatch);
+        assert_eq!(fs::read(&dst).unwrap(), rewritten);
+
+        fs::write(&dst, "").unwrap();
+        assert_eq!(append(&src, &dst).unwrap(), Appended::NoPrefix);
+        fs::remove_file(&dst).unwrap();
+        assert_eq!(append(&src, &dst).unwrap(), Appended::NoPrefix);
+    }
+}
@@ -0,0 +1,590 @@
+//! Directories to and from archive files: `usync -r ./project/ ./project.tar.gz` packs
+//! the tree into one file, and `usync -r ./project.tar.gz ./restore/` unpacks it.
+//!
+//! Archives are written and read by the `tar` tool (piped through `gzip` or `zstd` for
+//! compressed ones) or by `zip`/`unzip`. What goes in comes from the same filtered walk
+//! as directory uploads, and extraction applies the filters to the archive's listing.
+//! That listing is checked first: an archive with an absolute or `../` entry is refused
+//! before anything is written.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+
+use crate::copy::{self, CopyError, CopyOptions};
+use crate::protocol::Path as ProtocolPath;
+use crate::remote::RemoteCopyError;
+use crate::stream;
+use crate::transform::{Codec, Direction};
+use crate::utils;
+
+/// An archive format, recognised by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarZstd,
+    Zip,
+}
+
+impl Format {
+    pub fn from_name(path: &Path) -> Option<Format> {
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Format::TarZstd)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+
+    /// The compression wrapped around the tar stream.
+    fn codec(self) -> Option<Codec> {
+        match self {
+            Format::TarGz => Some(Codec::Gzip),
+            Format::TarZstd => Some(Codec::Zstd),
+            Format::Tar | Format::Zip => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Extract,
+}
+
+/// Whether copying `src` to `dst` creates or extracts an archive: a local directory
+/// copied to a local archive name, or a local archive copied recursively (`-r`) to a
+/// local directory. Without `-r`, an archive is copied like any other file.
+pub fn detect(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    recursive: bool,
+) -> Option<(Operation, Format)> {
+    let (ProtocolPath::Local(src), ProtocolPath::Local(dst)) = (src, dst) else {
+        return None;
+    };
+    if src.is_dir() {
+        let format = Format::from_name(dst.as_path())?;
+        return (!dst.is_dir()).then_some((Operation::Create, format));
+    }
+    let format = Format::from_name(src.as_path())?;
+    (recursive && src.is_file()).then_some((Operation::Extract, format))
+}
+
+/// What went into or came out of an archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub dirs: usize,
+    pub files: usize,
+    /// Size of the files' contents; only known when creating.
+    pub bytes: Option<u64>,
+    /// Size of the archive file; zero for a dry run.
+    pub archive_bytes: u64,
+}
+
+/// `3 directories, 12 files, 48213 bytes -> 10240 byte archive` when creating,
+/// `3 directories, 12 files from a 10240 byte archive` when extracting.
+pub fn describe(report: &ArchiveReport) -> String {
+    let mut text = format!("{} directories, {} files", report.dirs, report.files);
+    if let Some(bytes) = report.bytes {
+        text.push_str(&format!(", {} bytes", bytes));
+    }
+    if report.archive_bytes > 0 {
+        let arrow = if report.bytes.is_some() {
+            " ->"
+        } else {
+            " from"
+        };
+        text.push_str(&format!(
+            "{} a {} byte archive",
+            arrow, report.archive_bytes
+        ));
+    }
+    text
+}
+
+fn tool_error(message: String, error: String) -> CopyError {
+    CopyError::RemoteError(RemoteCopyError::IoError { message, error })
+}
+
+fn spawn(cmd: &mut Command, tool: &str) -> Result<Child, CopyError> {
+    cmd.spawn()
+        .map_err(|e| tool_error(format!("Failed to execute {}", tool), e.to_string()))
+}
+
+fn wait(child: &mut Child, tool: &str) -> Result<ExitStatus, CopyError> {
+    child
+        .wait()
+        .map_err(|e| tool_error(format!("Failed to wait for {}", tool), e.to_string()))
+}
+
+/// Wait for every tool of a pipeline, failing with the first one that didn't succeed.
+fn wait_all(children: &mut [(Child, &'static str)], action: &str) -> Result<(), CopyError> {
+    let mut failed = None;
+    for (child, tool) in children.iter_mut() {
+        let status = wait(child, tool)?;
+        if !status.success() && failed.is_none() {
+            failed = Some(tool_error(
+                format!("{} failed to {}", tool, action),
+                format!("Exit code: {}", status.code().unwrap_or(-1)),
+            ));
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Pack the directory `src` into the archive file `archive`, or with `dry_run` list
+/// what would go in.
+pub fn create(
+    src: &Path,
+    archive: &Path,
+    format: Format,
+    opts: &CopyOptions,
+    dry_run: bool,
+) -> Result<ArchiveReport, CopyError> {
+    let tree = copy::list_upload_tree(src, &opts.filters).map_err(|e| CopyError::IoError {
+        message: format!("Failed to read source directory: {}", src.display()),
+        error: e,
+    })?;
+    let mut report = ArchiveReport {
+        dirs: tree.dirs.len(),
+        files: tree.files.len(),
+        bytes: Some(tree.files.iter().map(|(_, size)| size).sum()),
+        archive_bytes: 0,
+    };
+    let entries: Vec<&PathBuf> = tree
+        .dirs
+        .iter()
+        .chain(tree.files.iter().map(|(rel, _)| rel))
+        .collect();
+    if dry_run {
+        for rel in &tree.dirs {
+            println!("Would archive {}/", rel.display());
+        }
+        for (rel, _) in &tree.files {
+            println!("Would archive {}", rel.display());
+        }
+        for (rel, decision) in &tree.excluded {
+            println!("Would skip {} ({})", rel.display(), decision);
+        }
+        return Ok(report);
+    }
+
+    // Both tools follow symlinks, as the walk did.
+    let (mut archiver, tool, list) = if format == Format::Zip {
+        let mut list = Vec::new();
+        for rel in &entries {
+            let name = utils::slash_path(rel);
+            if name.contains('\n') {
+                return Err(CopyError::InvalidSource(format!(
+                    "{} has a newline in its name and can't be added to a zip archive",
+                    src.join(rel).display()
+                )));
+            }
+            list.extend_from_slice(name.as_bytes());
+            list.push(b'\n');
+        }
+        let mut cmd = Command::new("zip");
+        cmd.arg("-q").arg("-").arg("-@").current_dir(src);
+        (cmd, "zip", list)
+    } else {
+        let mut list = Vec::new();
+        for rel in &entries {
+            list.extend_from_slice(utils::slash_path(rel).as_bytes());
+            list.push(0);
+        }
+        let mut cmd = Command::new("tar");
+        cmd.arg("-c")
+            .arg("-h")
+            .arg("-f")
+            .arg("-")
+            .arg("-C")
+            .arg(src)
+            .arg("--no-recursion")
+            .arg("--null")
+            .arg("-T")
+            .arg("-");
+        (cmd, "tar", list)
+    };
+    let mut archiver = spawn(archiver.stdin(Stdio::piped()).stdout(Stdio::piped()), tool)?;
+    let mut list_in = archiver.stdin.take().expect("stdin is piped");
+    let archiver_out = archiver.stdout.take().expect("stdout is piped");
+    let mut children = vec![(archiver, tool)];
+    let mut output: Box<dyn Read + Send> = match format.codec() {
+        Some(codec) => {
+            let mut compressor = spawn(
+                codec
+                    .command(Direction::Compress)
+                    .stdin(archiver_out)
+                    .stdout(Stdio::piped()),
+                codec.name(),
+            )?;
+            let out = compressor.stdout.take().expect("stdout is piped");
+            children.push((compressor, codec.name()));
+            Box::new(out)
+        }
+        None => Box::new(archiver_out),
+    };
+
+    let mut file = fs::File::create(archive).map_err(|e| CopyError::IoError {
+        message: format!("Failed to create archive: {}", archive.display()),
+        error: e,
+    })?;
+    let pumped = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            // A tool that exits early makes this fail; its exit status says why.
+            let _ = list_in.write_all(&list);
+        });
+        stream::pump(&mut output, |buf| file.write_all(buf), None, opts.progress)
+    });
+    drop(output);
+    let result = match pumped {
+        Ok(Ok(written)) => {
+            report.archive_bytes = written.bytes;
+            wait_all(&mut children, "create the archive").and_then(|()| {
+                file.sync_all().map_err(|e| CopyError::IoError {
+                    message: format!("Failed to write archive: {}", archive.display()),
+                    error: e,
+                })
+            })
+        }
+        Ok(Err(e)) => {
+            let _ = wait_all(&mut children, "create the archive");
+            Err(tool_error(
+                format!("Failed to read from {}", tool),
+                e.to_string(),
+            ))
+        }
+        Err(e) => {
+            let _ = wait_all(&mut children, "create the archive");
+            Err(CopyError::IoError {
+                message: format!("Failed to write archive: {}", archive.display()),
+                error: e,
+            })
+        }
+    };
+    if let Err(e) = result {
+        drop(file);
+        let _ = fs::remove_file(archive);
+        return Err(e);
+    }
+    Ok(report)
+}
+
+/// The names of the entries in `archive`, as the tool lists them.
+fn list_entries(archive: &Path, format: Format) -> Result<Vec<String>, CopyError> {
+    let open = || {
+        fs::File::open(archive).map_err(|e| CopyError::IoError {
+            message: format!("Failed to open archive: {}", archive.display()),
+            error: e,
+        })
+    };
+    let mut children = Vec::new();
+    let mut lister = if format == Format::Zip {
+        let mut cmd = Command::new("unzip");
+        cmd.arg("-Z1").arg(archive);
+        (cmd, "unzip")
+    } else {
+        let mut cmd = Command::new("tar");
+        cmd.arg("-t").arg("-f").arg("-");
+        match format.codec() {
+            Some(codec) => {
+                let mut decompressor = spawn(
+                    codec
+                        .command(Direction::Decompress)
+                        .stdin(open()?)
+                        .stdout(Stdio::piped()),
+                    codec.name(),
+                )?;
+                cmd.stdin(decompressor.stdout.take().expect("stdout is piped"));
+                children.push((decompressor, codec.name()));
+            }
+            None => {
+                cmd.stdin(open()?);
+            }
+        }
+        (cmd, "tar")
+    };
+    let mut child = spawn(lister.0.stdout(Stdio::piped()), lister.1)?;
+    let mut listing = Vec::new();
+    let read = child
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .read_to_end(&mut listing);
+    children.push((child, lister.1));
+    wait_all(&mut children, "list the archive")?;
+    read.map_err(|e| tool_error(format!("Failed to read from {}", lister.1), e.to_string()))?;
+    Ok(String::from_utf8_lossy(&listing)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Why an entry name would write outside the extraction directory, if it would.
+fn unsafe_entry(name: &str) -> Option<&'static str> {
+    let bytes = name.as_bytes();
+    if name.starts_with('/')
+        || name.starts_with('\\')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+    {
+        return Some("it is an absolute path");
+    }
+    if name.split(['/', '\\']).any(|part| part == "..") {
+        return Some("it leads out of the destination with ..");
+    }
+    None
+}
+
+/// Unpack `archive` into the directory `dst`, or with `dry_run` list what would come out.
+pub fn extract(
+    archive: &Path,
+    dst: &Path,
+    format: Format,
+    opts: &CopyOptions,
+    dry_run: bool,
+) -> Result<ArchiveReport, CopyError> {
+    if dst.exists() && !dst.is_dir() {
+        return Err(CopyError::InvalidDestination(format!(
+            "{} is not a directory; an archive is extracted into one",
+            dst.display()
+        )));
+    }
+    let entries = list_entries(archive, format)?;
+    if let Some((name, reason)) = entries
+        .iter()
+        .find_map(|name| unsafe_entry(name).map(|reason| (name, reason)))
+    {
+        return Err(CopyError::InvalidSource(format!(
+            "{} has an unsafe entry {:?} ({}); nothing was extracted",
+            archive.display(),
+            name,
+            reason
+        )));
+    }
+    let (selected, excluded): (Vec<_>, Vec<_>) = entries
+        .iter()
+        .map(|name| (name, opts.filters.decide_entry(name)))
+        .partition(|(_, decision)| decision.included);
+    let selected: Vec<&String> = selected.into_iter().map(|(name, _)| name).collect();
+    let dirs = selected.iter().filter(|name| name.ends_with('/')).count();
+    let mut report = ArchiveReport {
+        dirs,
+        files: selected.len() - dirs,
+        bytes: None,
+        archive_bytes: 0,
+    };
+    if dry_run {
+        for name in &selected {
+            println!("Would extract {}", name);
+        }
+        for (name, decision) in &excluded {
+            println!("Would skip {} ({})", name, decision);
+        }
+        return Ok(report);
+    }
+    fs::create_dir_all(dst).map_err(|e| CopyError::IoError {
+        message: format!("Failed to create directory: {}", dst.display()),
+        error: e,
+    })?;
+    let filtered = selected.len() < entries.len();
+
+    if format == Format::Zip {
+        let mut cmd = Command::new("unzip");
+        cmd.arg("-q").arg("-o").arg(archive);
+        if filtered {
+            if selected.is_empty() {
+                return Ok(report);
+            }
+            // unzip takes wildcards; put the special characters in brackets.
+            cmd.args(selected.iter().map(|name| {
+                name.chars()
+                    .map(|c| match c {
+                        '*' | '?' | '[' => format!("[{}]", c),
+                        c => c.to_string(),
+                    })
+                    .collect::<String>()
+            }));
+        }
+        let mut children = vec![(spawn(cmd.arg("-d").arg(dst), "unzip")?, "unzip")];
+        wait_all(&mut children, "extract the archive")?;
+        report.archive_bytes = fs::metadata(archive).map(|m| m.len()).unwrap_or(0);
+        return Ok(report);
+    }
+
+    // With filters, tar is handed the selected names; the list needs a file, as tar's
+    // stdin carries the archive.
+    let scratch = if filtered {
+        let dir = utils::scratch_dir(opts.tmpdir.as_deref(), "usync-extract").map_err(|e| {
+            CopyError::IoError {
+                message: "Failed to create a temporary directory".to_string(),
+                error: e,
+            }
+        })?;
+        let mut list = Vec::new();
+        for name in &selected {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
+        }
+        let path = dir.path().join("entries");
+        fs::write(&path, list).map_err(|e| CopyError::IoError {
+            message: "Failed to write the list of entries".to_string(),
+            error: e,
+        })?;
+        Some((dir, path))
+    } else {
+        None
+    };
+
+    let mut tar = Command::new("tar");
+    tar.arg("-x").arg("-f").arg("-").arg("-C").arg(dst);
+    if let Some((_, list)) = &scratch {
+        tar.arg("--no-recursion").arg("--null").arg("-T").arg(list);
+    }
+    let (mut input, mut children) = match format.codec() {
+        Some(codec) => {
+            let mut decompressor = spawn(
+                codec
+                    .command(Direction::Decompress)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped()),
+                codec.name(),
+            )?;
+            let input = decompressor.stdin.take().expect("stdin is piped");
+            tar.stdin(decompressor.stdout.take().expect("stdout is piped"));
+            let tar = spawn(&mut tar, "tar")?;
+            (input, vec![(decompressor, codec.name()), (tar, "tar")])
+        }
+        None => {
+            let mut tar = spawn(tar.stdin(Stdio::piped()), "tar")?;
+            let input = tar.stdin.take().expect("stdin is piped");
+            (input, vec![(tar, "tar")])
+        }
+    };
+
+    let mut file = fs::File::open(archive).map_err(|e| CopyError::IoError {
+        message: format!("Failed to open archive: {}", archive.display()),
+        error: e,
+    })?;
+    let pumped = stream::pump(&mut file, |buf| input.write_all(buf), None, opts.progress);
+    // Closing the input is what lets the tools finish.
+    drop(input);
+    let waited = wait_all(&mut children, "extract the archive");
+    match pumped {
+        Ok(Ok(read)) => report.archive_bytes = read.bytes,
+        Ok(Err(e)) => {
+            return Err(CopyError::IoError {
+                message: format!("Failed to read archive: {}", archive.display()),
+                error: e,
+            })
+        }
+        // The tool stopped reading; its exit status says why.
+        Err(e) => {
+            waited?;
+            return Err(tool_error(
+                "tar stopped reading the archive".to_string(),
+                e.to_string(),
+            ));
+        }
+    }
+    waited?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has_tool(tool: &str) -> bool {
+        Command::new(tool)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
+    }
+
+    #[test]
+    fn test_formats_from_names() {
+        assert_eq!(Format::from_name(Path::new("a.tar")), Some(Format::Tar));
+        assert_eq!(
+            Format::from_name(Path::new("a.TAR.GZ")),
+            Some(Format::TarGz)
+        );
+        assert_eq!(Format::from_name(Path::new("a.tgz")), Some(Format::TarGz));
+        assert_eq!(
+            Format::from_name(Path::new("a.tar.zst")),
+            Some(Format::TarZstd)
+        );
+        assert_eq!(Format::from_name(Path::new("a.zip")), Some(Format::Zip));
+        // A lone .gz is a compressed file, not an archive.
+        assert_eq!(Format::from_name(Path::new("a.log.gz")), None);
+    }
+
+    #[test]
+    fn test_unsafe_entries() {
+        assert_eq!(unsafe_entry("src/main.rs"), None);
+        assert_eq!(unsafe_entry("./src/"), None);
+        assert_eq!(unsafe_entry("a..b/c"), None);
+        assert!(unsafe_entry("../etc/passwd").is_some());
+        assert!(unsafe_entry("src/../../x").is_some());
+        assert!(unsafe_entry("/etc/passwd").is_some());
+        assert!(unsafe_entry("C:\\Windows\\x").is_some());
+    }
+
+    #[test]
+    fn test_tar_round_trip_refuses_traversal() {
+        if !has_tool("tar") || !has_tool("gzip") {
+            return;
+        }
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub/empty")).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        fs::write(src.join("sub/b.bin"), vec![7u8; 70_000]).unwrap();
+        let archive = temp.path().join("src.tar.gz");
+        let opts = CopyOptions::default();
+
+        let created = create(&src, &archive, Format::TarGz, &opts, false).unwrap();
+        assert_eq!((created.dirs, created.files), (2, 2));
+        assert_eq!(created.bytes, Some(70_005));
+        let restored = temp.path().join("restored");
+        let extracted = extract(&archive, &restored, Format::TarGz, &opts, false).unwrap();
+        assert_eq!((extracted.dirs, extracted.files), (2, 2));
+        assert_eq!(fs::read(restored.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read(restored.join("sub/b.bin")).unwrap(),
+            vec![7u8; 70_000]
+        );
+        assert!(restored.join("sub/empty").is_dir());
+
+        // tar -P keeps the `../` that a safe extraction must not follow.
+        let evil = temp.path().join("evil.tar");
+        let status = Command::new("tar")
+            .arg("-c")
+            .arg("-P")
+            .arg("-f")
+            .arg(&evil)
+            .arg("../a.txt")
+            .current_dir(src.join("sub"))
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let out = temp.path().join("out");
+        assert!(matches!(
+            extract(&evil, &out, Format::Tar, &opts, false),
+            Err(CopyError::InvalidSource(_))
+        ));
+        assert!(!out.exists());
+    }
+}
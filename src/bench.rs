@@ -0,0 +1,345 @@
+//! `usync bench`: time the single-file copy strategies in [`crate::utils`] on this machine.
+//!
+//! Each run copies a freshly written file of its own, flushed and (on Linux) dropped
+//! from the page cache before the clock starts, so no run reads what an earlier one left
+//! in memory. The clock stops once the destination is synced to disk. Everything is
+//! written to a temporary directory that is removed at the end.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::utils;
+
+/// A way of copying one file.
+pub struct Strategy {
+    pub name: &'static str,
+    copy: fn(&Path, &Path) -> io::Result<u64>,
+}
+
+/// The strategies available on this platform, `std` (`fs::copy`) first as the baseline.
+pub fn strategies() -> Vec<Strategy> {
+    let mut strategies = vec![
+        Strategy {
+            name: "std",
+            copy: |src, dst| fs::copy(src, dst),
+        },
+        Strategy {
+            name: "buffered",
+            copy: utils::copy_file_buffered,
+        },
+    ];
+    #[cfg(target_os = "linux")]
+    strategies.push(Strategy {
+        name: "sendfile",
+        copy: utils::copy_file_sendfile,
+    });
+    #[cfg(target_os = "macos")]
+    strategies.push(Strategy {
+        name: "copyfile",
+        copy: |src, dst| utils::copy_file_range_macos(src, dst, false),
+    });
+    #[cfg(target_os = "windows")]
+    strategies.push(Strategy {
+        name: "copyfileex",
+        copy: |src, dst| utils::copy_file_windows(src, dst, |_, _| true),
+    });
+    strategies.push(Strategy {
+        name: "ram",
+        copy: |src, dst| utils::copy_file_via_ram(src, dst, false),
+    });
+    strategies
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// The largest file size tried.
+    pub max_size: u64,
+    /// Where the test files go (default: the system temp directory).
+    pub dir: Option<PathBuf>,
+    /// Runs per strategy and size; the median is reported.
+    pub runs: usize,
+}
+
+/// The median speed of one strategy at one file size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub size: u64,
+    pub strategy: &'static str,
+    pub mb_per_sec: f64,
+}
+
+/// The file sizes to try: 64 KiB, then every 64x up to `max`, and `max` itself.
+pub fn sizes(max: u64) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    let mut size = 64 * 1024;
+    while size < max {
+        sizes.push(size);
+        size *= 64;
+    }
+    sizes.push(max);
+    sizes
+}
+
+/// Parse `512K`, `64M`, `1G` or a plain byte count (binary units).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().trim_end_matches("IB") {
+        "" | "B" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("invalid size {:?}; use e.g. 512K, 64M or 1G", s)),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size {:?}; use e.g. 512K, 64M or 1G", s))?;
+    match n.checked_mul(1 << shift) {
+        Some(0) | None => Err(format!("invalid size {:?}", s)),
+        Some(size) => Ok(size),
+    }
+}
+
+/// `64 KiB`, `4 MiB`, `1.5 GiB`.
+pub fn format_size(size: u64) -> String {
+    for (shift, unit) in [(30, "GiB"), (20, "MiB"), (10, "KiB")] {
+        if size >= 1 << shift {
+            return if size.is_multiple_of(1 << shift) {
+                format!("{} {}", size >> shift, unit)
+            } else {
+                format!("{:.1} {}", size as f64 / (1u64 << shift) as f64, unit)
+            };
+        }
+    }
+    format!("{} B", size)
+}
+
+/// Tell the kernel it can forget the cached pages of `file`.
+#[cfg(target_os = "linux")]
+fn drop_cache(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+    }
+    const POSIX_FADV_DONTNEED: i32 = 4;
+    // Only advice: if it's ignored, the run is merely less pessimistic.
+    unsafe {
+        posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cache(_file: &fs::File) {}
+
+/// Write `size` bytes of incompressible data to `path`, sync it and evict it from the
+/// page cache. `seed` makes every file different.
+fn write_test_file(path: &Path, size: u64, seed: u64) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut chunk = vec![0u8; 1024 * 1024];
+    let mut left = size;
+    while left > 0 {
+        for word in chunk.chunks_exact_mut(8) {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            word.copy_from_slice(&state.to_le_bytes());
+        }
+        let n = left.min(chunk.len() as u64) as usize;
+        file.write_all(&chunk[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    drop_cache(&file);
+    Ok(())
+}
+
+/// Copy `src` to `dst` with `strategy` and return the speed in MB/s, counting the time
+/// until `dst` is on disk.
+fn time_copy(strategy: &Strategy, src: &Path, dst: &Path) -> io::Result<f64> {
+    let start = Instant::now();
+    let bytes = (strategy.copy)(src, dst)?;
+    let file = fs::File::open(dst)?;
+    file.sync_all()?;
+    let seconds = start.elapsed().as_secs_f64().max(1e-9);
+    drop_cache(&file);
+    Ok(bytes as f64 / 1_048_576.0 / seconds)
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Run the benchmark, calling `on_result` as each measurement completes.
+pub fn run(
+    opts: &BenchOptions,
+    mut on_result: impl FnMut(&Measurement),
+) -> io::Result<Vec<Measurement>> {
+    let scratch = utils::scratch_dir(opts.dir.as_deref(), "usync-bench")?;
+    // A source and its copy exist at the same time.
+    let needed = opts.max_size.saturating_mul(2);
+    if let Ok(available) = utils::available_space(scratch.path()) {
+        if available < needed {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "{} needs {} free but only {} is available; use a smaller --size or \
+                     another --dir",
+                    scratch.path().display(),
+                    format_size(needed),
+                    format_size(available)
+                ),
+            ));
+        }
+    }
+
+    let strategies = strategies();
+    let mut results = Vec::new();
+    let mut seed = 0;
+    for size in sizes(opts.max_size) {
+        for strategy in &strategies {
+            let mut speeds = Vec::with_capacity(opts.runs);
+            for _ in 0..opts.runs.max(1) {
+                seed += 1;
+                let src = scratch.path().join(format!("src-{}", seed));
+                let dst = scratch.path().join(format!("dst-{}", seed));
+                write_test_file(&src, size, seed)?;
+                let speed = time_copy(strategy, &src, &dst);
+                fs::remove_file(&src)?;
+                let _ = fs::remove_file(&dst);
+                speeds.push(speed?);
+            }
+            let measurement = Measurement {
+                size,
+                strategy: strategy.name,
+                mb_per_sec: median(speeds),
+            };
+            on_result(&measurement);
+            results.push(measurement);
+        }
+    }
+    // `scratch` is removed with anything left in it when it drops.
+    Ok(results)
+}
+
+/// The fastest strategy for each size, in size order.
+pub fn winners(results: &[Measurement]) -> Vec<&Measurement> {
+    let mut winners: Vec<&Measurement> = Vec::new();
+    for m in results {
+        match winners.iter_mut().find(|w| w.size == m.size) {
+            Some(w) if m.mb_per_sec > w.mb_per_sec => *w = m,
+            Some(_) => {}
+            None => winners.push(m),
+        }
+    }
+    winners
+}
+
+/// Print `results` as a table of MB/s, one row per size and one column per strategy.
+pub fn print_table(results: &[Measurement], out: &mut dyn Write) -> io::Result<()> {
+    let names: Vec<&str> = strategies().iter().map(|s| s.name).collect();
+    write!(out, "{:<10}", "size")?;
+    for name in &names {
+        write!(out, "{:>12}", name)?;
+    }
+    writeln!(out, "   (MB/s)")?;
+    for size in sizes_in(results) {
+        write!(out, "{:<10}", format_size(size))?;
+        for name in &names {
+            match results
+                .iter()
+                .find(|m| m.size == size && m.strategy == *name)
+            {
+                Some(m) => write!(out, "{:>12.1}", m.mb_per_sec)?,
+                None => write!(out, "{:>12}", "-")?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn sizes_in(results: &[Measurement]) -> Vec<u64> {
+    let mut sizes: Vec<u64> = results.iter().map(|m| m.size).collect();
+    sizes.dedup();
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("256m"), Ok(256 << 20));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("fast").is_err());
+
+        assert_eq!(format_size(64 * 1024), "64 KiB");
+        assert_eq!(format_size(1 << 30), "1 GiB");
+        assert_eq!(format_size(1000), "1000 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(sizes(256 << 20), vec![64 << 10, 4 << 20, 256 << 20]);
+        assert_eq!(sizes(1000), vec![1000]);
+    }
+
+    #[test]
+    fn test_winners_and_table() {
+        let m = |size, strategy, mb_per_sec| Measurement {
+            size,
+            strategy,
+            mb_per_sec,
+        };
+        let results = vec![
+            m(1024, "std", 10.0),
+            m(1024, "ram", 30.0),
+            m(2048, "std", 50.0),
+            m(2048, "ram", 20.0),
+        ];
+        let winners = winners(&results);
+        assert_eq!(winners.len(), 2);
+        assert_eq!((winners[0].size, winners[0].strategy), (1024, "ram"));
+        assert_eq!((winners[1].size, winners[1].strategy), (2048, "std"));
+
+        let mut table = Vec::new();
+        print_table(&results, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.contains("30.0"));
+    }
+
+    /// Writes and copies real files; run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_bench_runs_and_cleans_up() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let opts = BenchOptions {
+            max_size: 256 * 1024,
+            dir: Some(temp.path().to_path_buf()),
+            runs: 1,
+        };
+        let mut seen = 0;
+        let results = run(&opts, |_| seen += 1).unwrap();
+        assert_eq!(results.len(), seen);
+        assert_eq!(results.len(), 2 * strategies().len());
+        assert!(results.iter().all(|m| m.mb_per_sec > 0.0));
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+//! `--case-collisions`: names that only differ in case.
+//!
+//! A case-sensitive source can hold both `README` and `readme`; on a case-insensitive
+//! destination (macOS, Windows, ExFAT) the second one copied overwrites the first. A
+//! directory copy claims each destination path, case-folded, as it walks the source,
+//! so the second of two such names is noticed before it is written.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::copy::CopyError;
+
+/// What to do with a source name that collides with an earlier one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CaseCollisions {
+    /// Stop the copy.
+    Error,
+    /// Copy it anyway, with a warning.
+    Warn,
+    /// Copy it under a free name with a `~N` suffix, with a warning.
+    Rename,
+}
+
+/// Destination paths claimed so far by one directory copy, by their case-folded form.
+#[derive(Debug)]
+pub struct CaseFolder {
+    policy: CaseCollisions,
+    seen: Mutex<HashMap<String, PathBuf>>,
+}
+
+/// What [`CaseFolder::claim`] made of a destination path.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Claim {
+    /// No earlier name collides with it.
+    Free,
+    /// It collides with the earlier name, and is written anyway (`warn`).
+    Collides(PathBuf),
+    /// It collides with the earlier name, and is written to the new path (`rename`).
+    Renamed(PathBuf, PathBuf),
+}
+
+impl CaseFolder {
+    pub fn new(policy: CaseCollisions) -> Self {
+        CaseFolder {
+            policy,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Claim the destination path `dst` for a source entry.
+    pub fn claim(&self, dst: &Path) -> Result<Claim, CopyError> {
+        let mut seen = self.seen.lock().unwrap();
+        let earlier = match seen.get(&fold(dst)) {
+            None => {
+                seen.insert(fold(dst), dst.to_path_buf());
+                return Ok(Claim::Free);
+            }
+            Some(earlier) => earlier.clone(),
+        };
+        match self.policy {
+            CaseCollisions::Error => Err(CopyError::CaseCollision {
+                first: earlier,
+                second: dst.to_path_buf(),
+            }),
+            CaseCollisions::Warn => Ok(Claim::Collides(earlier)),
+            CaseCollisions::Rename => {
+                let renamed = (1..)
+                    .map(|n| with_suffix(dst, n))
+                    .find(|candidate| !seen.contains_key(&fold(candidate)))
+                    .expect("some suffix is free");
+                seen.insert(fold(&renamed), renamed.clone());
+                Ok(Claim::Renamed(earlier, renamed))
+            }
+        }
+    }
+}
+
+/// `path` as a case-insensitive filesystem compares it.
+fn fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// `dir/name~N.ext` for `dir/name.ext`.
+fn with_suffix(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::new();
+    if let Some(stem) = path.file_stem() {
+        name.push(stem);
+    }
+    name.push(format!("~{}", n));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_policies() {
+        let folder = CaseFolder::new(CaseCollisions::Warn);
+        assert_eq!(folder.claim(Path::new("d/README")).unwrap(), Claim::Free);
+        assert_eq!(folder.claim(Path::new("d/notes")).unwrap(), Claim::Free);
+        assert_eq!(
+            folder.claim(Path::new("d/readme")).unwrap(),
+            Claim::Collides(PathBuf::from("d/README"))
+        );
+        // Only names in the same directory collide.
+        assert_eq!(folder.claim(Path::new("e/readme")).unwrap(), Claim::Free);
+
+        let folder = CaseFolder::new(CaseCollisions::Error);
+        folder.claim(Path::new("Makefile")).unwrap();
+        assert!(matches!(
+            folder.claim(Path::new("makefile")),
+            Err(CopyError::CaseCollision { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rename_finds_a_free_name() {
+        let folder = CaseFolder::new(CaseCollisions::Rename);
+        for name in ["Notes.txt", "notes~1.txt"] {
+            folder.claim(Path::new(name)).unwrap();
+        }
+        assert_eq!(
+            folder.claim(Path::new("NOTES.txt")).unwrap(),
+            Claim::Renamed(PathBuf::from("Notes.txt"), PathBuf::from("NOTES~2.txt"))
+        );
+        assert_eq!(
+            folder.claim(Path::new("notes.TXT")).unwrap(),
+            Claim::Renamed(PathBuf::from("Notes.txt"), PathBuf::from("notes~3.TXT"))
+        );
+    }
+
+    #[test]
+    fn test_with_suffix() {
+        assert_eq!(
+            with_suffix(Path::new("a/README"), 1),
+            Path::new("a/README~1")
+        );
+        assert_eq!(
+            with_suffix(Path::new("a/b.tar.gz"), 2),
+            Path::new("a/b.tar~2.gz")
+        );
+        assert_eq!(with_suffix(Path::new(".bashrc"), 1), Path::new(".bashrc~1"));
+    }
+}
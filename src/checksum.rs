@@ -0,0 +1,468 @@
+//! `usync checksum`: write and verify `sha256sum`-compatible manifests of a tree.
+//!
+//! Lines are `<hex digest>  <path relative to the tree root>`, with `/` separators.
+//! Names containing a backslash or newline are escaped the way coreutils does it (a
+//! leading `\` on the line, `\\` and `\n` in the name), so `cd <root> && sha256sum -c
+//! manifest.txt` accepts our output and we accept theirs.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::utils;
+
+/// Hash algorithm for a manifest; each matches the coreutils `<name>sum` tool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// The coreutils tool that prints this digest (`sha256sum`, …).
+    pub fn tool(self) -> String {
+        format!("{}sum", self.name())
+    }
+
+    /// Length of a hex digest, used to tell which algorithm a manifest was written with.
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha1 => 40,
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+
+    fn from_hex_len(len: usize) -> Option<Self> {
+        [
+            Algorithm::Md5,
+            Algorithm::Sha1,
+            Algorithm::Sha256,
+            Algorithm::Sha512,
+        ]
+        .into_iter()
+        .find(|a| a.hex_len() == len)
+    }
+}
+
+/// Whether `hash` looks like a hex digest produced by `algorithm`.
+pub fn is_digest(hash: &str, algorithm: Algorithm) -> bool {
+    hash.len() == algorithm.hex_len() && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn digest_reader<D: Digest, R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = D::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// An incremental digest, for data that is only seen once as it streams past.
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    /// The lowercase hex digest of everything passed to [`Hasher::update`].
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(h) => to_hex(&h.finalize()),
+            Hasher::Sha1(h) => to_hex(&h.finalize()),
+            Hasher::Sha256(h) => to_hex(&h.finalize()),
+            Hasher::Sha512(h) => to_hex(&h.finalize()),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex digest of the file at `path`, read in fixed-size chunks.
+pub fn hash_file(path: &Path, algorithm: Algorithm) -> io::Result<String> {
+    let file = fs::File::open(path)?;
+    match algorithm {
+        Algorithm::Md5 => digest_reader::<Md5, _>(file),
+        Algorithm::Sha1 => digest_reader::<Sha1, _>(file),
+        Algorithm::Sha256 => digest_reader::<Sha256, _>(file),
+        Algorithm::Sha512 => digest_reader::<Sha512, _>(file),
+    }
+}
+
+/// The ETag S3 gives an object uploaded in parts of `part_size` bytes: the MD5 of the
+/// concatenated binary MD5s of each part, followed by `-<part count>`.
+pub fn s3_multipart_etag(path: &Path, part_size: u64) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut combined = Md5::new();
+    let mut parts = 0u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let mut part = Md5::new();
+        let mut remaining = part_size;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            part.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+        if remaining == part_size && parts > 0 {
+            break;
+        }
+        combined.update(part.finalize());
+        parts += 1;
+        if remaining > 0 {
+            break;
+        }
+    }
+    Ok(format!("{}-{}", to_hex(&combined.finalize()), parts))
+}
+
+/// The files a manifest for `root` covers, keyed by their manifest path. A single file
+/// is listed under its own name.
+fn list_tree(root: &Path) -> io::Result<BTreeMap<String, PathBuf>> {
+    if root.is_file() {
+        let name = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        return Ok(BTreeMap::from([(name, root.to_path_buf())]));
+    }
+    Ok(utils::list_files_relative(root)?
+        .into_iter()
+        .map(|(rel, _)| (utils::slash_path(&rel), root.join(rel)))
+        .collect())
+}
+
+fn format_line(hash: &str, name: &str) -> String {
+    if name.contains(['\\', '\n']) {
+        let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{}  {}", hash, escaped)
+    } else {
+        format!("{}  {}", hash, name)
+    }
+}
+
+/// Split a manifest line into (hash, name); accepts text (`  `) and binary (` *`) markers.
+fn parse_line(line: &str) -> Option<(&str, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (hash, rest) = line.split_once(' ')?;
+    let name = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let name = if escaped {
+        let mut out = String::with_capacity(name.len());
+        let mut chars = name.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('\\')) => {
+                    out.push('\\');
+                    chars.next();
+                }
+                ('\\', Some('n')) => {
+                    out.push('\n');
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    } else {
+        name.to_string()
+    };
+    Some((hash, name))
+}
+
+/// Digest of every file below `root`, keyed by manifest path.
+pub fn hash_tree(root: &Path, algorithm: Algorithm) -> io::Result<BTreeMap<String, String>> {
+    hash_tree_with(root, |_, path| hash_file(path, algorithm))
+}
+
+/// [`hash_tree`] with the digest of each file coming from `hash`, given its manifest
+/// path and location.
+pub fn hash_tree_with(
+    root: &Path,
+    mut hash: impl FnMut(&str, &Path) -> io::Result<String>,
+) -> io::Result<BTreeMap<String, String>> {
+    list_tree(root)?
+        .into_iter()
+        .map(|(name, path)| {
+            let digest = hash(&name, &path)?;
+            Ok((name, digest))
+        })
+        .collect()
+}
+
+/// Hash every file below `root` and write one manifest line per file, in the stable
+/// order of [`utils::walk_files`], as each is hashed. `max_depth` limits how deep the
+/// walk goes. Returns the number of files written.
+pub fn write_manifest(
+    root: &Path,
+    algorithm: Algorithm,
+    max_depth: Option<usize>,
+    out: &mut dyn Write,
+) -> io::Result<usize> {
+    if root.is_file() {
+        let name = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        writeln!(out, "{}", format_line(&hash_file(root, algorithm)?, &name))?;
+        out.flush()?;
+        return Ok(1);
+    }
+    let mut count = 0;
+    let mut failed = None;
+    let flow = utils::walk_files(root, max_depth, |rel, _| {
+        let line = hash_file(&root.join(rel), algorithm)
+            .and_then(|hash| writeln!(out, "{}", format_line(&hash, &utils::slash_path(rel))));
+        match line {
+            Ok(()) => {
+                count += 1;
+                ControlFlow::Continue(())
+            }
+            Err(e) => {
+                failed = Some(e);
+                ControlFlow::Break(())
+            }
+        }
+    })?;
+    if let (ControlFlow::Break(()), Some(e)) = (flow, failed) {
+        return Err(e);
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Differences between a manifest and the tree it was checked against.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    pub matched: usize,
+    /// Listed in the manifest but absent from the tree.
+    pub missing: Vec<String>,
+    /// Present in the tree but not listed in the manifest.
+    pub extra: Vec<String>,
+    /// Present in both with a different digest (or unreadable).
+    pub mismatched: Vec<String>,
+}
+
+impl Verification {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Re-hash the tree at `root` against `manifest`. Without an explicit `algorithm` it is
+/// inferred from the digest length of the first entry.
+pub fn verify_manifest(
+    manifest: &mut dyn BufRead,
+    root: &Path,
+    algorithm: Option<Algorithm>,
+) -> io::Result<Verification> {
+    let mut expected = BTreeMap::new();
+    for (number, line) in manifest.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (hash, name) = parse_line(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed manifest line {}: {}", number + 1, line),
+            )
+        })?;
+        expected.insert(name, hash.to_ascii_lowercase());
+    }
+
+    let algorithm = match algorithm {
+        Some(a) => a,
+        None => expected
+            .values()
+            .next()
+            .and_then(|h| Algorithm::from_hex_len(h.len()))
+            .unwrap_or_default(),
+    };
+
+    let mut actual = list_tree(root)?;
+    let mut result = Verification::default();
+    for (name, hash) in expected {
+        match actual.remove(&name) {
+            None => result.missing.push(name),
+            Some(path) => match hash_file(&path, algorithm) {
+                Ok(h) if h == hash => result.matched += 1,
+                _ => result.mismatched.push(name),
+            },
+        }
+    }
+    result.extra = actual.into_keys().collect();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_tree() -> TempDir {
+        let temp = TempDir::new().unwrap();
+        fs::create_dir_all(temp.path().join("sub")).unwrap();
+        fs::write(temp.path().join("a.txt"), "hello\n").unwrap();
+        fs::write(temp.path().join("sub").join("b.txt"), "").unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_manifest_format_matches_sha256sum() {
+        let temp = sample_tree();
+        let mut out = Vec::new();
+        let count = write_manifest(temp.path(), Algorithm::Sha256, None, &mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  a.txt\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  sub/b.txt\n"
+        );
+
+        let mut top = Vec::new();
+        assert_eq!(
+            write_manifest(temp.path(), Algorithm::Sha256, Some(1), &mut top).unwrap(),
+            1
+        );
+        assert!(String::from_utf8(top).unwrap().ends_with("  a.txt\n"));
+    }
+
+    #[test]
+    fn test_verify_reports_differences() {
+        let temp = sample_tree();
+        let mut manifest = Vec::new();
+        write_manifest(temp.path(), Algorithm::Sha1, None, &mut manifest).unwrap();
+
+        let clean = verify_manifest(&mut manifest.as_slice(), temp.path(), None).unwrap();
+        assert!(clean.is_clean());
+        assert_eq!(clean.matched, 2);
+
+        fs::write(temp.path().join("a.txt"), "changed").unwrap();
+        fs::remove_file(temp.path().join("sub").join("b.txt")).unwrap();
+        fs::write(temp.path().join("new.txt"), "new").unwrap();
+
+        let dirty = verify_manifest(&mut manifest.as_slice(), temp.path(), None).unwrap();
+        assert_eq!(dirty.mismatched, vec!["a.txt"]);
+        assert_eq!(dirty.missing, vec!["sub/b.txt"]);
+        assert_eq!(dirty.extra, vec!["new.txt"]);
+        assert!(!dirty.is_clean());
+    }
+
+    #[test]
+    fn test_s3_multipart_etag() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("object");
+        let data: Vec<u8> = (0..2500u32).map(|i| ((i * 7 + 3) % 251) as u8).collect();
+        fs::write(&file, &data).unwrap();
+
+        assert_eq!(
+            s3_multipart_etag(&file, 1000).unwrap(),
+            "9b7bcee9c02e1a95ec38759a0778f778-3"
+        );
+        // Exactly one full part, and a part size larger than the object.
+        assert_eq!(
+            s3_multipart_etag(&file, 2500).unwrap(),
+            "4c487a9d1f48177cfbde47cbb9cda2fc-1"
+        );
+        assert_eq!(
+            s3_multipart_etag(&file, 8 * 1024 * 1024).unwrap(),
+            "4c487a9d1f48177cfbde47cbb9cda2fc-1"
+        );
+
+        let empty = temp.path().join("empty");
+        fs::write(&empty, "").unwrap();
+        assert_eq!(
+            s3_multipart_etag(&empty, 1000).unwrap(),
+            "59adb24ef3cdbe0297f05b395827453f-1"
+        );
+    }
+
+    #[test]
+    fn test_incremental_hasher_matches_hash_file() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("data");
+        fs::write(&file, b"streamed in two pieces").unwrap();
+        for algorithm in [
+            Algorithm::Md5,
+            Algorithm::Sha1,
+            Algorithm::Sha256,
+            Algorithm::Sha512,
+        ] {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"streamed in ");
+            hasher.update(b"two pieces");
+            assert_eq!(hasher.finish(), hash_file(&file, algorithm).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_escaped_names_round_trip() {
+        let line = format_line("00ff", "odd\\name\nhere");
+        assert_eq!(line, "\\00ff  odd\\\\name\\nhere");
+        assert_eq!(
+            parse_line(&line),
+            Some(("00ff", "odd\\name\nhere".to_string()))
+        );
+        assert_eq!(
+            parse_line("00ff *bin.dat"),
+            Some(("00ff", "bin.dat".to_string()))
+        );
+        assert_eq!(parse_line("not a manifest line"), None);
+    }
+}
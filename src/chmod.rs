@@ -0,0 +1,317 @@
+//! `--chmod`: permission overrides for destination files and directories.
+//!
+//! The spec is a comma-separated list of clauses. A clause is an octal mode (`644`) or
+//! a chmod(1) symbolic one (`u+w`, `go-rwx`, `a=rX`), optionally prefixed with `F` or
+//! `D` to apply to files or directories only. Without a prefix, the first octal mode is
+//! for files and the second for directories (`644,755`), while symbolic clauses apply
+//! to both. Clauses apply in order, after the mode `--perms` (or the copy) gave.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A parsed `--chmod` spec.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chmod {
+    files: Vec<Clause>,
+    dirs: Vec<Clause>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Clause {
+    Octal(u32),
+    /// Who (as the mode bits they own) and the operations applied to them.
+    Symbolic {
+        who: u32,
+        ops: Vec<(char, String)>,
+    },
+}
+
+/// Mode bits belonging to `u`, `g` and `o`, special bits included.
+const USER: u32 = 0o4700;
+const GROUP: u32 = 0o2070;
+const OTHER: u32 = 0o1007;
+
+impl Chmod {
+    /// Parse a spec such as `D755,F644`, `644,755` or `go-w,Fa-x`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = |clause: &str| {
+            format!(
+                "invalid --chmod clause {:?}; use e.g. 644,755, D755,F644 or go-w",
+                clause
+            )
+        };
+        let mut chmod = Chmod::default();
+        let mut bare_octal = 0;
+        for item in spec.split(',') {
+            let (kind, body) = match item.strip_prefix('D') {
+                Some(body) => (Some(true), body),
+                None => match item.strip_prefix('F') {
+                    Some(body) => (Some(false), body),
+                    None => (None, item),
+                },
+            };
+            let clause = Clause::parse(body).ok_or_else(|| invalid(item))?;
+            let is_dir = match (kind, &clause) {
+                (Some(is_dir), _) => Some(is_dir),
+                (None, Clause::Octal(_)) => {
+                    bare_octal += 1;
+                    match bare_octal {
+                        1 => Some(false),
+                        2 => Some(true),
+                        _ => return Err(invalid(item)),
+                    }
+                }
+                (None, Clause::Symbolic { .. }) => None,
+            };
+            if is_dir != Some(true) {
+                chmod.files.push(clause.clone());
+            }
+            if is_dir != Some(false) {
+                chmod.dirs.push(clause);
+            }
+        }
+        Ok(chmod)
+    }
+
+    fn clauses(&self, is_dir: bool) -> &[Clause] {
+        if is_dir {
+            &self.dirs
+        } else {
+            &self.files
+        }
+    }
+
+    /// Whether the spec changes anything for files (or directories).
+    pub fn applies_to(&self, is_dir: bool) -> bool {
+        !self.clauses(is_dir).is_empty()
+    }
+
+    /// `mode` (permission and special bits) after the spec.
+    pub fn mode(&self, mode: u32, is_dir: bool) -> u32 {
+        self.clauses(is_dir)
+            .iter()
+            .fold(mode & 0o7777, |mode, clause| clause.apply(mode, is_dir))
+    }
+
+    /// The clauses for files (or directories) as chmod(1) arguments, to run in turn.
+    pub fn args(&self, is_dir: bool) -> Vec<String> {
+        self.clauses(is_dir).iter().map(Clause::to_string).collect()
+    }
+
+    /// Apply the spec to the existing `path`.
+    #[cfg(unix)]
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = fs::metadata(path)?;
+        if !self.applies_to(metadata.is_dir()) {
+            return Ok(());
+        }
+        let old = metadata.permissions().mode() & 0o7777;
+        let new = self.mode(old, metadata.is_dir());
+        if new != old {
+            fs::set_permissions(path, fs::Permissions::from_mode(new))?;
+        }
+        Ok(())
+    }
+
+    /// Only the read-only bit exists here: it follows the owner's write permission.
+    #[cfg(not(unix))]
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let metadata = fs::metadata(path)?;
+        if !self.applies_to(metadata.is_dir()) {
+            return Ok(());
+        }
+        let old = if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        };
+        let mut permissions = metadata.permissions();
+        permissions.set_readonly(self.mode(old, metadata.is_dir()) & 0o200 == 0);
+        fs::set_permissions(path, permissions)
+    }
+}
+
+impl Clause {
+    fn parse(body: &str) -> Option<Self> {
+        if !body.is_empty() && body.len() <= 4 && body.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+            return u32::from_str_radix(body, 8).ok().map(Clause::Octal);
+        }
+        let op_start = body.find(['+', '-', '='])?;
+        let mut who = 0;
+        for c in body[..op_start].chars() {
+            who |= match c {
+                'u' => USER,
+                'g' => GROUP,
+                'o' => OTHER,
+                'a' => USER | GROUP | OTHER,
+                _ => return None,
+            };
+        }
+        let mut ops: Vec<(char, String)> = Vec::new();
+        for c in body[op_start..].chars() {
+            match c {
+                '+' | '-' | '=' => ops.push((c, String::new())),
+                'r' | 'w' | 'x' | 'X' | 's' | 't' => ops.last_mut()?.1.push(c),
+                _ => return None,
+            }
+        }
+        Some(Clause::Symbolic {
+            who: if who == 0 { USER | GROUP | OTHER } else { who },
+            ops,
+        })
+    }
+
+    fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let (who, ops) = match self {
+            Clause::Octal(octal) => return *octal,
+            Clause::Symbolic { who, ops } => (*who, ops),
+        };
+        ops.iter().fold(mode, |mode, (op, perms)| {
+            let mut bits = 0;
+            for perm in perms.chars() {
+                bits |= match perm {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    _ => 0,
+                };
+            }
+            let bits = bits & who;
+            match op {
+                '+' => mode | bits,
+                '-' => mode & !bits,
+                _ => (mode & !who) | bits,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Clause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clause::Octal(mode) => write!(f, "{:o}", mode),
+            Clause::Symbolic { who, ops } => {
+                for (bits, c) in [(USER, 'u'), (GROUP, 'g'), (OTHER, 'o')] {
+                    if who & bits != 0 {
+                        write!(f, "{}", c)?;
+                    }
+                }
+                for (op, perms) in ops {
+                    write!(f, "{}{}", op, perms)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_octal_pair() {
+        let chmod = Chmod::parse("644,755").unwrap();
+        assert_eq!(chmod.mode(0o600, false), 0o644);
+        assert_eq!(chmod.mode(0o700, true), 0o755);
+
+        // A single mode is for files only.
+        let chmod = Chmod::parse("640").unwrap();
+        assert_eq!(chmod.mode(0o777, false), 0o640);
+        assert_eq!(chmod.mode(0o700, true), 0o700);
+        assert!(!chmod.applies_to(true));
+
+        assert!(Chmod::parse("644,755,700").is_err());
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let chmod = Chmod::parse("D755,F644").unwrap();
+        assert_eq!(chmod.mode(0o600, false), 0o644);
+        assert_eq!(chmod.mode(0o700, true), 0o755);
+
+        let chmod = Chmod::parse("Dg+s,Fo-r").unwrap();
+        assert_eq!(chmod.mode(0o644, false), 0o640);
+        assert_eq!(chmod.mode(0o755, true), 0o2755);
+    }
+
+    #[test]
+    fn test_symbolic() {
+        let chmod = Chmod::parse("go-w").unwrap();
+        assert_eq!(chmod.mode(0o666, false), 0o644);
+        assert_eq!(chmod.mode(0o777, true), 0o755);
+
+        // No `who` means everyone, with no umask involved.
+        assert_eq!(Chmod::parse("+x").unwrap().mode(0o644, false), 0o755);
+        assert_eq!(Chmod::parse("a=r").unwrap().mode(0o755, false), 0o444);
+        assert_eq!(
+            Chmod::parse("u=rw,g=r,o=").unwrap().mode(0o777, false),
+            0o640
+        );
+        assert_eq!(Chmod::parse("u+w-x").unwrap().mode(0o500, false), 0o600);
+        assert_eq!(Chmod::parse("u+s").unwrap().mode(0o755, false), 0o4755);
+        assert_eq!(Chmod::parse("o+t").unwrap().mode(0o777, true), 0o1777);
+        // `=` clears the special bits of the classes it sets.
+        assert_eq!(Chmod::parse("g=rx").unwrap().mode(0o2775, true), 0o755);
+    }
+
+    #[test]
+    fn test_capital_x() {
+        // Execute only for directories and files someone can already execute.
+        let chmod = Chmod::parse("a+rX").unwrap();
+        assert_eq!(chmod.mode(0o600, false), 0o644);
+        assert_eq!(chmod.mode(0o700, false), 0o755);
+        assert_eq!(chmod.mode(0o700, true), 0o755);
+        assert_eq!(chmod.mode(0o600, true), 0o755);
+    }
+
+    #[test]
+    fn test_clauses_apply_in_order() {
+        let chmod = Chmod::parse("F600,Fg+r,go-w").unwrap();
+        assert_eq!(chmod.mode(0o777, false), 0o640);
+        assert_eq!(chmod.mode(0o777, true), 0o755);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for spec in [
+            "", "8", "77777", "u", "w+r", "u+q", "F", "Dx", "644,", "f644", "u+r,z",
+        ] {
+            assert!(Chmod::parse(spec).is_err(), "{:?} accepted", spec);
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let chmod = Chmod::parse("644,755,a+rX,Dg+s").unwrap();
+        assert_eq!(chmod.args(false), ["644", "ugo+rX"]);
+        assert_eq!(chmod.args(true), ["755", "ugo+rX", "g+s"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let file = temp.path().join("file");
+        fs::write(&file, "x").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        Chmod::parse("D700,F644").unwrap().apply(&file).unwrap();
+        Chmod::parse("D700,F644")
+            .unwrap()
+            .apply(temp.path())
+            .unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&file), 0o644);
+        assert_eq!(mode(temp.path()), 0o700);
+    }
+}
@@ -1,24 +1,79 @@
 use std::fs;
 use std::io;
 use std::path::Path;
-#[cfg(feature = "parallel")]
-use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+use crate::append::Appended;
+use crate::case::CaseCollisions;
+use crate::chmod::Chmod;
+use crate::delete;
+use crate::filter::{FilterChain, FilterDecision};
+use crate::http::{Download, HttpChecks};
+use crate::limit::{Exceeded, Limits};
+use crate::output;
+use crate::owner::Ownership;
 use crate::path::LocalPath;
+use crate::progress::RunProgress;
 use crate::protocol::Path as ProtocolPath;
 use crate::remote;
+use crate::rename::Template;
+use crate::report::{FileError, Operation, SlowFiles, TransferReport};
+use crate::sanitize::NameRules;
 use crate::utils;
-
-#[cfg(feature = "parallel")]
-use rayon::prelude::*;
+use crate::verify::Outcome;
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct CopyStats {
     pub bytes_copied: u64,
     pub files_copied: usize,
     pub files_skipped: usize,
+    /// Size of the files skipped as unchanged (`--compare`).
+    pub bytes_skipped: u64,
+    /// Files and directories the filters left out; an excluded directory counts once,
+    /// whatever is below it.
+    pub files_excluded: usize,
+    /// Directories a directory copy created at the destination.
+    pub dirs_created: usize,
+    /// Files that disappeared between listing and copying and were skipped.
+    pub files_vanished: usize,
+    /// FIFOs, sockets and device nodes skipped rather than recreated.
+    pub specials_skipped: usize,
+    /// Names that collide case-insensitively with an earlier one (`--case-collisions`).
+    pub case_collisions: usize,
+    /// Names changed for the destination filesystem (`--sanitize-names`).
+    pub names_sanitized: usize,
+    /// Entries of a `--files-from` list gone through.
+    pub files_listed: usize,
+    /// Listed entries the source doesn't have, skipped with `--ignore-missing-args`.
+    pub files_missing: usize,
+    /// Files hard-linked (within the tree or from `--link-dest`) instead of copied.
+    pub files_linked: usize,
+    /// Files copied by appending their new tail (`--append`); also in `files_copied`.
+    pub files_appended: usize,
+    /// Files whose extended attributes or ACLs could not all be copied (`--xattrs`,
+    /// `--acls`).
+    pub files_xattrs_incomplete: usize,
+    /// Bytes of `--delta` updates reused from the existing destination.
+    pub delta_matched: u64,
+    /// Bytes of `--delta` updates that had to be sent.
+    pub delta_literal: u64,
+    /// Destination files removed by `--delete`.
+    pub files_deleted: usize,
+    pub bytes_deleted: u64,
+    /// Empty destination directories removed by `--prune-empty-dirs`.
+    pub dirs_pruned: usize,
+    /// Destination files moved to where the source now has them (`--detect-renames`).
+    pub files_renamed: usize,
+    /// Directories not copied again because a symlink led back to one already walked.
+    pub symlink_loops: usize,
+    /// Files and directories skipped because they couldn't be read (`--continue-on-error`).
+    pub errors: Vec<FileError>,
+    /// The slowest files copied (`--stats-detail`).
+    pub slowest: SlowFiles,
+    /// Files and bytes a local source directory held when it was listed before the copy
+    /// (for the limits, `--space-check` or the files bar), so it is listed only once.
+    pub planned: Option<(u64, u64)>,
     pub start_time: Option<Instant>,
 }
 
@@ -29,6 +84,28 @@ impl CopyStats {
             files_copied: 0,
             bytes_copied: 0,
             files_skipped: 0,
+            bytes_skipped: 0,
+            files_excluded: 0,
+            dirs_created: 0,
+            files_vanished: 0,
+            specials_skipped: 0,
+            case_collisions: 0,
+            names_sanitized: 0,
+            files_listed: 0,
+            files_missing: 0,
+            files_linked: 0,
+            files_appended: 0,
+            files_xattrs_incomplete: 0,
+            delta_matched: 0,
+            delta_literal: 0,
+            files_deleted: 0,
+            bytes_deleted: 0,
+            dirs_pruned: 0,
+            files_renamed: 0,
+            symlink_loops: 0,
+            errors: Vec::new(),
+            slowest: SlowFiles::default(),
+            planned: None,
             start_time: Some(Instant::now()),
         }
     }
@@ -39,113 +116,856 @@ impl CopyStats {
             files_copied: 0,
             bytes_copied: 0,
             files_skipped: 0,
+            bytes_skipped: 0,
+            files_excluded: 0,
+            dirs_created: 0,
+            files_vanished: 0,
+            specials_skipped: 0,
+            case_collisions: 0,
+            names_sanitized: 0,
+            files_listed: 0,
+            files_missing: 0,
+            files_linked: 0,
+            files_appended: 0,
+            files_xattrs_incomplete: 0,
+            delta_matched: 0,
+            delta_literal: 0,
+            files_deleted: 0,
+            bytes_deleted: 0,
+            dirs_pruned: 0,
+            files_renamed: 0,
+            symlink_loops: 0,
+            errors: Vec::new(),
+            slowest: SlowFiles::default(),
+            planned: None,
             start_time: None,
         }
     }
 
-    #[inline]
-    pub fn print_summary(&self, verbose: bool) {
-        if let Some(start) = self.start_time {
-            let duration = start.elapsed();
-            let speed = if duration.as_secs_f64() > 0.0 {
-                self.bytes_copied as f64 / duration.as_secs_f64() / 1_048_576.0
-            } else {
-                0.0
-            };
+    /// Note that copying `path` (`bytes` of it) took since `started`, for
+    /// `--stats-detail`.
+    pub fn time_file(&mut self, path: &Path, bytes: u64, started: Instant) {
+        if self.start_time.is_some() {
+            self.slowest.record(path, bytes, started.elapsed());
+        }
+    }
 
-            if verbose {
-                println!("\n=== Copy Summary ===");
-                println!("Files copied: {}", self.files_copied);
-                println!(
-                    "Bytes transferred: {} ({:.2} MB)",
-                    self.bytes_copied,
-                    self.bytes_copied as f64 / 1_048_576.0
-                );
-                println!("Files skipped: {}", self.files_skipped);
-                println!("Time taken: {:.2}s", duration.as_secs_f64());
-                println!("Average speed: {:.2} MB/s", speed);
-            } else {
-                println!(
-                    "\nSummary: {} files, {:.2} MB, {:.2}s, {:.2} MB/s",
-                    self.files_copied,
-                    self.bytes_copied as f64 / 1_048_576.0,
-                    duration.as_secs_f64(),
-                    speed
-                );
+    /// Add the counts of `other`, kept for part of the same copy, to these.
+    pub fn merge(&mut self, mut other: CopyStats) {
+        self.bytes_copied += other.bytes_copied;
+        self.files_copied += other.files_copied;
+        self.files_skipped += other.files_skipped;
+        self.bytes_skipped += other.bytes_skipped;
+        self.files_excluded += other.files_excluded;
+        self.dirs_created += other.dirs_created;
+        self.files_vanished += other.files_vanished;
+        self.specials_skipped += other.specials_skipped;
+        self.case_collisions += other.case_collisions;
+        self.names_sanitized += other.names_sanitized;
+        self.files_listed += other.files_listed;
+        self.files_missing += other.files_missing;
+        self.files_linked += other.files_linked;
+        self.files_appended += other.files_appended;
+        self.files_xattrs_incomplete += other.files_xattrs_incomplete;
+        self.delta_matched += other.delta_matched;
+        self.delta_literal += other.delta_literal;
+        self.files_deleted += other.files_deleted;
+        self.bytes_deleted += other.bytes_deleted;
+        self.dirs_pruned += other.dirs_pruned;
+        self.files_renamed += other.files_renamed;
+        self.symlink_loops += other.symlink_loops;
+        self.errors.append(&mut other.errors);
+        self.slowest.merge(other.slowest);
+    }
+}
+
+#[cfg(feature = "progress")]
+use indicatif::ProgressBar;
+
+/// Which source metadata is carried over to local destinations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preserve {
+    /// Permission bits.
+    pub mode: bool,
+    /// Access and modification times.
+    pub times: bool,
+    /// Extended attributes (and, on macOS, resource forks and ACLs).
+    pub xattrs: bool,
+    /// POSIX access and default ACLs (Linux), or the ACL (macOS).
+    pub acls: bool,
+}
+
+impl Preserve {
+    /// What `-a` preserves: permissions and times. Extended attributes and ACLs stay
+    /// opt-in.
+    pub fn all() -> Self {
+        Self {
+            mode: true,
+            times: true,
+            xattrs: false,
+            acls: false,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.mode || self.times || self.xattrs || self.acls
+    }
+}
+
+/// How symbolic links in a local source are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Copy the file or directory the link points to.
+    #[default]
+    Follow,
+    /// Recreate the link itself at the destination.
+    Preserve,
+}
+
+/// Engine used for the files of a local → local directory copy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CopyMode {
+    /// The regular per-file copy.
+    #[default]
+    Auto,
+    /// Batch open/read/write/close through io_uring (Linux, `io-uring` feature);
+    /// falls back to `auto` when the kernel doesn't support it.
+    Uring,
+}
+
+/// The order a local directory copy sends its files in (`--order`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Order {
+    /// Directory by directory, each as it is listed
+    #[default]
+    Default,
+    /// Smallest files first, so most of them are in before a connection drops
+    SmallFirst,
+    /// Largest files first, so parallel copies don't end on one big file
+    LargeFirst,
+    /// Most recently modified files first
+    NewestFirst,
+}
+
+/// How [`copy_contents`] moves the bytes of one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Read the whole file, then write it in one go.
+    Ram,
+    /// A read/write loop with a 64 KiB buffer.
+    Buffered,
+    /// The platform's own copy: sendfile on Linux, copyfile(3) on macOS, `CopyFileExW`
+    /// on Windows.
+    Direct,
+}
+
+/// Default `--ram-threshold`: below it, reading the file whole beats a read/write loop.
+pub const RAM_THRESHOLD: u64 = 1024 * 1024;
+/// Default `--direct-threshold`: above it, the kernel copy beats a buffer in user space.
+pub const DIRECT_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// Default `--depth-limit`: how many levels of directories a local walk goes down before
+/// it gives up.
+pub const DEPTH_LIMIT: usize = 256;
+
+/// How `--compare` decides that a file already at the destination matches its source,
+/// so a directory copy can skip it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compare {
+    /// Same size, and the destination is not older than the source (`cp -u`, `aws s3 sync`)
+    #[value(name = "size-mtime")]
+    SizeMtime,
+    /// Same size, whatever the times
+    #[value(name = "size-only")]
+    SizeOnly,
+    /// The destination file exists
+    Existence,
+}
+
+impl Compare {
+    /// Whether a destination file of `dst` (size, mtime in seconds) can stand for a source
+    /// of `src`. When either side has no time, `size-mtime` compares sizes only, since
+    /// SSH hosts and object stores don't always report one; `-v` says so for `name`.
+    pub fn unchanged(
+        self,
+        name: &str,
+        src: (u64, Option<i64>),
+        dst: (u64, Option<i64>),
+        verbose: bool,
+    ) -> bool {
+        match self {
+            Compare::Existence => true,
+            Compare::SizeOnly => src.0 == dst.0,
+            Compare::SizeMtime => {
+                src.0 == dst.0
+                    && match (src.1, dst.1) {
+                        (Some(src_time), Some(dst_time)) => dst_time >= src_time,
+                        _ => {
+                            if verbose {
+                                println!(
+                                    "No modification time for {}; comparing by size only",
+                                    name
+                                );
+                            }
+                            true
+                        }
+                    }
             }
         }
     }
 }
 
-#[cfg(feature = "progress")]
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+/// Size and modification time (whole seconds since the epoch) of a local file.
+pub(crate) fn file_state(metadata: &fs::Metadata) -> (u64, Option<i64>) {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    (metadata.len(), modified)
+}
+
+/// Options controlling a single [`copy`] invocation.
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    pub verbose: bool,
+    pub progress: bool,
+    /// `--ram` (`Some(true)`) or `--no-ram` (`Some(false)`); `None` lets the size of each
+    /// file decide, see [`CopyOptions::strategy`].
+    pub use_ram: Option<bool>,
+    /// Files smaller than this are copied through memory (`--ram-threshold`).
+    pub ram_threshold: u64,
+    /// Files larger than this are copied by the kernel, where the platform can
+    /// (`--direct-threshold`).
+    pub direct_threshold: u64,
+    /// Directories are copied recursively without asking for confirmation.
+    pub recursive: bool,
+    pub preserve: Preserve,
+    /// Owner and group given to local destinations (`--chown`, `--owner`, `--group`).
+    pub ownership: Ownership,
+    /// Permission overrides for local and SSH destinations (`--chmod`).
+    pub chmod: Option<Chmod>,
+    /// Create missing parents of a file destination (`--mkpath`; implied by `recursive`).
+    pub mkpath: bool,
+    /// Recreate FIFOs and sockets instead of skipping them (`--specials`).
+    pub specials: bool,
+    /// Recreate device nodes instead of skipping them (`--devices`, needs root).
+    pub devices: bool,
+    /// Check directory copies for names that only differ in case.
+    pub case_collisions: Option<CaseCollisions>,
+    /// Rewrite names the destination filesystem can't store (`--sanitize-names`).
+    pub sanitize_names: Option<NameRules>,
+    /// Name each copied file from this template (`--rename`).
+    pub rename: Option<Template>,
+    /// Copy only these paths, relative to the source directory, instead of walking it
+    /// (`--files-from`).
+    pub files_from: Option<Vec<std::path::PathBuf>>,
+    /// Skip listed paths the source doesn't have, with a warning, instead of failing
+    /// (`--ignore-missing-args`).
+    pub ignore_missing_args: bool,
+    pub symlinks: SymlinkPolicy,
+    /// Fail when a local source directory is nested deeper than this (`--depth-limit`).
+    pub depth_limit: usize,
+    pub ssh_opts: Vec<String>,
+    /// Share one SSH connection between all scp/ssh calls of the operation (ControlMaster).
+    pub ssh_multiplex: bool,
+    /// Always stream directory uploads over ssh through a single tar pipe.
+    pub ssh_batch: bool,
+    /// Compress data in transit (ssh compression, gzip'd tar streams, HTTP content encoding).
+    pub compress: bool,
+    /// Skip (and count) files that disappear between listing a directory and copying them.
+    pub ignore_vanished: bool,
+    /// Skip, with a warning, files and directories of a directory copy that can't be
+    /// read (permission denied), instead of failing; they end up in `CopyStats::errors`.
+    pub continue_on_error: bool,
+    pub copy_mode: CopyMode,
+    /// The order the files of a local directory copy are sent in; anything but the
+    /// default lists the whole tree first.
+    pub order: Order,
+    /// Recreate hard links between files of a source directory instead of copying twice.
+    pub hard_links: bool,
+    /// Previous snapshot to hard-link unchanged files from (`--link-dest`).
+    pub link_dest: Option<std::path::PathBuf>,
+    /// Update an existing destination file by sending only changed blocks.
+    pub delta: bool,
+    /// Copy only the new tail of a local source that grew past its destination
+    /// (`--append`).
+    pub append: bool,
+    /// Which entries of a directory copy take part (`--exclude`, `--include`).
+    pub filters: FilterChain,
+    /// Skip files of a directory copy that already match at the destination.
+    pub compare: Option<Compare>,
+    /// How `progress` reports directory uploads.
+    pub progress_format: crate::progress::Format,
+    /// Where temporary files and relay copies go, instead of the system temp directory.
+    pub tmpdir: Option<std::path::PathBuf>,
+    /// Check for enough free space before a local copy or relay starts.
+    pub space_check: bool,
+    /// Flush each file written locally to disk before counting it as copied (`--fsync`).
+    pub fsync: bool,
+    /// `--max-files`, `--max-total-size` and `--max-deletes`.
+    pub limits: Limits,
+    /// Remove destination files a directory copy's source doesn't have (`--delete`).
+    pub delete: bool,
+    /// Share of the destination `delete` may remove, from 0 to 1.
+    pub max_delete_ratio: f64,
+    /// Delete past `max_delete_ratio`.
+    pub force_delete: bool,
+    /// Remove the directories a directory copy leaves empty at the destination
+    /// (`--prune-empty-dirs`).
+    pub prune_empty_dirs: bool,
+    /// Move destination files that `delete` would remove to where the source has a file
+    /// of the same size and SHA-256 the destination lacks (`--detect-renames`).
+    pub detect_renames: bool,
+    /// How HTTP downloads are made and checked: `--expect-content-type`,
+    /// `--max-redirects`, `--update`, `--http-segments`, `--http-mirror`.
+    pub http: HttpChecks,
+    /// Keep this many of the slowest files in the report (`--stats-detail`).
+    pub stats_detail: Option<usize>,
+    /// List and count what the copy, `delete` and `prune_empty_dirs` would do, changing
+    /// nothing (`--dry-run`). Needs a local source, and a local or SSH destination.
+    pub dry_run: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            progress: false,
+            use_ram: None,
+            ram_threshold: RAM_THRESHOLD,
+            direct_threshold: DIRECT_THRESHOLD,
+            recursive: false,
+            preserve: Preserve::default(),
+            ownership: Ownership::default(),
+            chmod: None,
+            mkpath: false,
+            specials: false,
+            devices: false,
+            case_collisions: None,
+            sanitize_names: None,
+            rename: None,
+            files_from: None,
+            ignore_missing_args: false,
+            symlinks: SymlinkPolicy::default(),
+            depth_limit: DEPTH_LIMIT,
+            ssh_opts: Vec::new(),
+            ssh_multiplex: true,
+            ssh_batch: false,
+            compress: false,
+            ignore_vanished: true,
+            continue_on_error: false,
+            copy_mode: CopyMode::default(),
+            order: Order::default(),
+            hard_links: false,
+            link_dest: None,
+            delta: false,
+            append: false,
+            filters: FilterChain::default(),
+            compare: None,
+            progress_format: crate::progress::Format::default(),
+            tmpdir: None,
+            space_check: true,
+            fsync: false,
+            limits: Limits::default(),
+            delete: false,
+            max_delete_ratio: crate::delete::DEFAULT_MAX_RATIO,
+            force_delete: false,
+            prune_empty_dirs: false,
+            detect_renames: false,
+            http: HttpChecks::default(),
+            stats_detail: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl CopyOptions {
+    /// How a file of `size` bytes is copied: through memory with `--ram`, never with
+    /// `--no-ram`; otherwise through memory below `ram_threshold`, by the kernel above
+    /// `direct_threshold`, and with a buffered loop in between.
+    pub fn strategy(&self, size: u64) -> Strategy {
+        match self.use_ram {
+            Some(true) => Strategy::Ram,
+            None if size < self.ram_threshold => Strategy::Ram,
+            _ if size > self.direct_threshold => Strategy::Direct,
+            _ => Strategy::Buffered,
+        }
+    }
+
+    /// The `-o` options for ssh and scp: `--ssh-opt`, and with `--compress`
+    /// `Compression=yes`, the same as `scp -C` / `ssh -C`.
+    pub(crate) fn transit_ssh_opts(&self) -> Vec<String> {
+        let mut ssh_opts = self.ssh_opts.clone();
+        if self.compress {
+            ssh_opts.push("Compression=yes".to_string());
+        }
+        ssh_opts
+    }
+}
 
 pub fn copy(
     src: &ProtocolPath,
     dst: &ProtocolPath,
-    verbose: bool,
-    ssh_opts: &[String],
-    progress: bool,
-    use_ram: bool,
-) -> Result<CopyStats, CopyError> {
-    let mut stats = if verbose || progress {
-        CopyStats::new()
+    opts: &CopyOptions,
+) -> Result<TransferReport, CopyError> {
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+
+    if opts.rename.is_some() && (opts.compare.is_some() || opts.delete) {
+        // Both find a file's counterpart under the same name.
+        return Err(CopyError::InvalidDestination(
+            "--rename can't be combined with --compare or --delete".to_string(),
+        ));
+    }
+
+    if opts.files_from.is_some()
+        && !matches!((src, dst), (ProtocolPath::Local(s), ProtocolPath::Local(_)) if s.is_dir())
+    {
+        return Err(CopyError::InvalidSource(
+            "--files-from needs a local source directory and a local destination".to_string(),
+        ));
+    }
+
+    // Before anything is listed or written, rather than halfway through.
+    crate::tools::check(src, dst)?;
+
+    // Always counted: the report is the result, not just the verbose summary.
+    let mut stats = CopyStats::new();
+    stats.slowest = SlowFiles::new(opts.stats_detail.unwrap_or(0));
+
+    let ssh_remotes: Vec<&crate::protocol::RemotePath> = [src, dst]
+        .into_iter()
+        .filter_map(|p| match p {
+            ProtocolPath::Remote(r)
+                if matches!(
+                    r.protocol,
+                    crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp
+                ) =>
+            {
+                Some(r)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let multiplex = if opts.ssh_multiplex && !ssh_remotes.is_empty() {
+        remote::SshMultiplex::start(&ssh_remotes, &opts.ssh_opts, verbose)
+    } else {
+        None
+    };
+    let mut opts = opts.clone();
+    if let Some(m) = &multiplex {
+        opts.ssh_opts = m.ssh_opts();
+    }
+    opts.limits = opts.limits.fresh();
+    let is_remote =
+        matches!(src, ProtocolPath::Remote(_)) || matches!(dst, ProtocolPath::Remote(_));
+    if is_remote {
+        // Carried to every ssh call with the other options.
+        opts.ssh_opts = opts.transit_ssh_opts();
+    }
+    if verbose && is_remote {
+        println!(
+            "In-transit compression: {}",
+            if opts.compress { "enabled" } else { "disabled" }
+        );
+    }
+    let ssh_opts = opts.ssh_opts.as_slice();
+
+    if opts.limits.is_set() && opts.compare.is_none() {
+        if let Some((files, bytes)) = planned_size(src, &opts, &mut stats) {
+            if let Err(exceeded) = opts.limits.check_plan(files, bytes) {
+                let mut report = TransferReport::from_stats(Operation::Copy, &stats);
+                report.limit_reached = Some(exceeded);
+                return Ok(report);
+            }
+        }
+    }
+
+    let deletions = if opts.delete {
+        Some(plan_deletions(src, dst, &opts)?)
     } else {
-        CopyStats::new_minimal()
+        None
     };
+    if let Some(plan) = &deletions {
+        delete::rename(plan, dst, &opts, &mut stats)?;
+    }
 
     let result = match (src, dst) {
+        _ if opts.dry_run => plan_copy(src, dst, &opts, &mut stats),
         (ProtocolPath::Local(src_local), ProtocolPath::Local(dst_local)) => {
-            copy_local_with_stats(src_local, dst_local, verbose, progress, use_ram, &mut stats)
+            if opts.space_check {
+                check_local_space(src_local, dst_local, &opts, &mut stats)?;
+            }
+            copy_local_with_stats(src_local, dst_local, &opts, &mut stats)
+        }
+        (ProtocolPath::Remote(_), ProtocolPath::Remote(_)) if opts.rename.is_some() => {
+            copied_whole("a copy between two remote hosts", &opts)
+        }
+        (ProtocolPath::Remote(src_remote), ProtocolPath::Remote(dst_remote))
+            if is_ssh(src_remote) && is_ssh(dst_remote) =>
+        {
+            copy_between_ssh(src_remote, dst_remote, &opts, &mut stats)
         }
         (ProtocolPath::Remote(src_remote), ProtocolPath::Remote(dst_remote)) => {
             remote::copy_remote(src_remote, dst_remote, verbose, ssh_opts, progress)
-                .map_err(CopyError::RemoteError)
+                .map_err(CopyError::from)
                 .map(|_| ())
         }
         (ProtocolPath::Remote(src_remote), ProtocolPath::Local(dst_local)) => {
-            copy_from_remote_to_local(src_remote, dst_local, verbose, ssh_opts, progress)
+            copy_from_remote_to_local(src_remote, dst_local, &opts, &mut stats)
         }
         (ProtocolPath::Local(src_local), ProtocolPath::Remote(dst_remote)) => {
-            copy_from_local_to_remote(src_local, dst_remote, verbose, ssh_opts, progress)
+            copy_from_local_to_remote(src_local, dst_remote, &opts, &mut stats)
+        }
+    };
+
+    result?;
+    if !opts.limits.stopped() {
+        // Whatever had no running total is checked now that it is known.
+        if let Err(exceeded) = opts
+            .limits
+            .check_plan(stats.files_copied as u64, stats.bytes_copied)
+        {
+            opts.limits.stop(Exceeded {
+                planned: false,
+                ..exceeded
+            });
+        }
+    }
+    if let Some(plan) = deletions.filter(|_| !opts.limits.stopped() && stats.errors.is_empty()) {
+        delete::apply(&plan, dst, &opts, &mut stats)?;
+    }
+    let tree = match src {
+        ProtocolPath::Local(local) => local.is_dir(),
+        ProtocolPath::Remote(_) => opts.recursive,
+    };
+    if opts.prune_empty_dirs && tree && !opts.limits.stopped() {
+        delete::prune(dst, &opts, &mut stats)?;
+    }
+    let mut report = TransferReport::from_stats(Operation::Copy, &stats);
+    report.limit_reached = opts.limits.exceeded();
+    Ok(report)
+}
+
+/// What `--delete` will remove from `dst`, checked against the source and
+/// `--max-delete-ratio`. Going over `--max-deletes` stops the copy at that limit, like a
+/// source too big for `--max-files`.
+fn plan_deletions(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+) -> Result<delete::Plan, CopyError> {
+    let src = match src {
+        ProtocolPath::Local(local) if local.is_dir() => local.as_path(),
+        _ => {
+            return Err(CopyError::InvalidSource(
+                "--delete needs a local source directory".to_string(),
+            ))
+        }
+    };
+    if let ProtocolPath::Remote(remote) = dst {
+        if !is_ssh(remote) {
+            return Err(CopyError::UnsupportedProtocol(format!(
+                "--delete doesn't support {} destinations",
+                remote.protocol
+            )));
+        }
+    }
+    let plan = delete::plan(src, dst, opts)?;
+    plan.check(opts)?;
+    if let Err(exceeded) = opts.limits.check_deletes(plan.files.len() as u64) {
+        opts.limits.stop(exceeded);
+    }
+    Ok(plan)
+}
+
+/// `--dry-run`: list and count the files a copy from `src` to `dst` would write, writing
+/// nothing. Those `--compare` finds unchanged are counted as skipped.
+fn plan_copy(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let src = match src {
+        ProtocolPath::Local(local) if local.exists() => local.as_path(),
+        ProtocolPath::Local(local) => {
+            return Err(CopyError::SourceNotFound(
+                local.to_string_lossy().to_string(),
+            ))
+        }
+        ProtocolPath::Remote(_) => {
+            return Err(CopyError::InvalidSource(
+                "--dry-run needs a local source".to_string(),
+            ))
+        }
+    };
+    if let ProtocolPath::Remote(remote) = dst {
+        if !is_ssh(remote) {
+            return Err(CopyError::UnsupportedProtocol(format!(
+                "--dry-run doesn't support {} destinations",
+                remote.protocol
+            )));
+        }
+    }
+
+    let mut would_copy = |name: &str, size: u64, unchanged: bool| {
+        if unchanged {
+            if opts.verbose {
+                println!("Unchanged: {}", name);
+            }
+            stats.files_skipped += 1;
+            stats.bytes_skipped += size;
+        } else {
+            println!("Would copy {}", name);
+            stats.files_copied += 1;
+            stats.bytes_copied += size;
+        }
+    };
+    if !src.is_dir() {
+        let size = fs::metadata(src).map_or(0, |m| m.len());
+        let unchanged = match dst {
+            ProtocolPath::Local(local) => {
+                local_unchanged(src, &file_destination(src, local.as_path())?, opts)
+            }
+            ProtocolPath::Remote(_) => false,
+        };
+        would_copy(&src.display().to_string(), size, unchanged);
+        return Ok(());
+    }
+
+    let files: Vec<(std::path::PathBuf, u64)> = match &opts.files_from {
+        Some(list) => list
+            .iter()
+            .filter(|rel| opts.filters.is_included(rel, false))
+            .filter_map(|rel| {
+                let metadata = fs::metadata(src.join(rel)).ok()?;
+                metadata.is_file().then(|| (rel.clone(), metadata.len()))
+            })
+            .collect(),
+        None => {
+            list_upload_tree(src, &opts.filters.without_debug())
+                .map_err(|e| CopyError::IoError {
+                    message: format!("Failed to read source directory: {}", src.display()),
+                    error: e,
+                })?
+                .files
+        }
+    };
+    // What is already there, for `--compare`; a destination that can't be listed (it
+    // may not exist yet) would get everything.
+    let existing: std::collections::HashMap<String, (u64, Option<i64>)> = match dst {
+        _ if opts.compare.is_none() => Default::default(),
+        ProtocolPath::Local(local) if !local.as_path().is_dir() => Default::default(),
+        _ => {
+            let listing = crate::list::ListOptions {
+                ssh_opts: opts.ssh_opts.clone(),
+                ..crate::list::ListOptions::default()
+            };
+            crate::list::list(dst, &listing)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| {
+                    (
+                        e.path.trim_start_matches("./").to_string(),
+                        (e.size, e.modified),
+                    )
+                })
+                .collect()
         }
     };
+    for (rel, size) in files {
+        let name = utils::slash_path(&rel);
+        let unchanged = match (opts.compare, existing.get(&name)) {
+            (Some(compare), Some(&state)) => {
+                let modified = fs::metadata(src.join(&rel))
+                    .map(|m| file_state(&m).1)
+                    .unwrap_or(None);
+                compare.unchanged(&name, (size, modified), state, opts.verbose)
+            }
+            _ => false,
+        };
+        would_copy(&name, size, unchanged);
+    }
+    Ok(())
+}
 
-    result.map(|_| stats)
+/// The files and bytes a copy from `src` would transfer, where that can be known
+/// up front: a local file or tree (after filters), or one on an SSH host.
+fn planned_size(
+    src: &ProtocolPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Option<(u64, u64)> {
+    match src {
+        ProtocolPath::Local(local) if local.is_dir() => prescan(local.as_path(), opts, stats),
+        ProtocolPath::Local(local) => fs::metadata(local.as_path())
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|m| (1, m.len())),
+        ProtocolPath::Remote(remote) if is_ssh(remote) => {
+            let tree = remote::ssh_stat_tree(remote, &opts.ssh_opts).ok()?;
+            Some((tree.sizes.len() as u64, tree.total_size()))
+        }
+        ProtocolPath::Remote(_) => None,
+    }
+}
+
+/// The files and bytes below the local directory `dir` that pass the filters (of those
+/// `--files-from` lists, with a list), listed at most once per copy and kept in [`CopyStats::planned`].
+pub(crate) fn prescan(dir: &Path, opts: &CopyOptions, stats: &mut CopyStats) -> Option<(u64, u64)> {
+    if stats.planned.is_none() {
+        stats.planned = Some(match &opts.files_from {
+            // Only the listed files, not what is below a listed directory.
+            Some(list) => list
+                .iter()
+                .filter(|rel| opts.filters.is_included(rel, false))
+                .filter_map(|rel| fs::metadata(dir.join(rel)).ok())
+                .filter(|m| m.is_file())
+                .fold((0, 0), |(files, bytes), m| (files + 1, bytes + m.len())),
+            None => {
+                let tree = list_upload_tree(dir, &opts.filters.without_debug()).ok()?;
+                let bytes = tree.files.iter().map(|(_, size)| size).sum();
+                (tree.files.len() as u64, bytes)
+            }
+        });
+    }
+    stats.planned
 }
 
 fn copy_local_with_stats(
     src: &LocalPath,
     dst: &LocalPath,
-    verbose: bool,
-    progress: bool,
-    use_ram: bool,
+    opts: &CopyOptions,
     stats: &mut CopyStats,
 ) -> Result<(), CopyError> {
+    let src_path = src.as_path();
+    let dst_path = dst.as_path();
+
+    if dst.ends_with_separator() && !src.is_dir() {
+        ensure_dir_destination(dst, opts)?;
+    }
+
+    let is_symlink = fs::symlink_metadata(src_path)
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false);
+    if is_symlink && opts.symlinks == SymlinkPolicy::Preserve {
+        let final_dst = if dst_path.is_dir() {
+            match src_path.file_name() {
+                Some(name) => dst_path.join(name),
+                None => dst_path.to_path_buf(),
+            }
+        } else {
+            dst_path.to_path_buf()
+        };
+        if let Some(parent) = final_dst.parent() {
+            ensure_parent(parent, opts)?;
+        }
+        copy_symlink(src_path, &final_dst, opts.verbose)?;
+        if stats.start_time.is_some() {
+            stats.files_copied += 1;
+        }
+        return Ok(());
+    }
+
     if !src.exists() {
         let path_str = src.to_string_lossy();
         return Err(CopyError::SourceNotFound(path_str.as_ref().to_string()));
     }
 
-    let src_path = src.as_path();
-    let dst_path = dst.as_path();
+    // A file copied into a directory keeps its name, so it may need sanitizing.
+    let sanitized = match &opts.sanitize_names {
+        Some(rules) if dst_path.is_dir() && !src.is_dir() => src_path
+            .file_name()
+            .and_then(|name| rules.sanitize(name))
+            .map(|name| dst_path.join(name)),
+        _ => None,
+    };
+    if let Some(renamed) = &sanitized {
+        if opts.verbose {
+            println!("Renaming: {} -> {}", src_path.display(), renamed.display());
+        }
+        stats.names_sanitized += 1;
+    }
+    let dst_path = sanitized.as_deref().unwrap_or(dst_path);
+    let renamed_file = match &opts.rename {
+        Some(_) if src.is_file() => Some(renamed(
+            file_destination(src_path, dst_path)?,
+            fs::metadata(src_path).ok().as_ref(),
+            1,
+            opts,
+        )?),
+        _ => None,
+    };
+    let dst_path = renamed_file.as_deref().unwrap_or(dst_path);
 
     if src.is_file() {
-        let bytes = copy_file(
-            src_path,
-            dst_path,
-            verbose,
-            progress,
-            use_ram,
-            stats.start_time.is_some(),
-        )?;
+        let final_dst = file_destination(src_path, dst_path)?;
+        if utils::same_file(src_path, &final_dst) {
+            skip_same_file(src_path, &final_dst, stats);
+            return Ok(());
+        }
+        if opts.delta && copy_file_delta(src_path, dst_path, opts, stats)? {
+            return Ok(());
+        }
+        let started = Instant::now();
+        if let Some((bytes, xattrs_complete)) = opts
+            .append
+            .then(|| copy_appended(src_path, &final_dst, opts))
+            .transpose()?
+            .flatten()
+        {
+            if !xattrs_complete {
+                stats.files_xattrs_incomplete += 1;
+            }
+            if stats.start_time.is_some() {
+                stats.files_copied += 1;
+                stats.files_appended += 1;
+                stats.bytes_copied += bytes;
+            }
+            stats.time_file(src_path, bytes, started);
+            return Ok(());
+        }
+        let (bytes, xattrs_complete) =
+            copy_file(src_path, dst_path, opts, stats.start_time.is_some())?;
+        if !xattrs_complete {
+            stats.files_xattrs_incomplete += 1;
+        }
         if stats.start_time.is_some() {
             stats.files_copied += 1;
             stats.bytes_copied += bytes;
         }
+        stats.time_file(src_path, bytes, started);
         Ok(())
     } else if src.is_dir() {
-        copy_directory_with_stats(src_path, dst_path, verbose, progress, use_ram, stats)
+        if opts.verbose && opts.files_from.is_none() {
+            let unreadable = find_unreadable(src_path, opts);
+            for path in &unreadable {
+                println!("Unreadable: {}", path.display());
+            }
+            if !unreadable.is_empty() {
+                println!(
+                    "{} path(s) can't be read; {}",
+                    unreadable.len(),
+                    if opts.continue_on_error {
+                        "they will be skipped"
+                    } else {
+                        "the copy will stop at the first (see --continue-on-error)"
+                    }
+                );
+            }
+        }
+        copy_directory_with_stats(src_path, dst_path, opts, stats)
+    } else if let Some(kind) = fs::metadata(src_path)
+        .ok()
+        .and_then(|m| utils::Special::of(m.file_type()))
+    {
+        let final_dst = file_destination(src_path, dst_path)?;
+        if let Some(parent) = final_dst.parent() {
+            ensure_parent(parent, opts)?;
+        }
+        copy_special(src_path, &final_dst, kind, opts, stats)
     } else {
         Err(CopyError::InvalidSource(
             "Source path is neither a file nor a directory".to_string(),
@@ -153,833 +973,3343 @@ fn copy_local_with_stats(
     }
 }
 
-fn copy_from_remote_to_local(
-    src: &crate::protocol::RemotePath,
-    dst: &LocalPath,
-    verbose: bool,
-    ssh_opts: &[String],
-    progress: bool,
-) -> Result<(), CopyError> {
-    match src.protocol {
-        crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp => {
-            let dst_path = dst.as_path();
-            remote::copy_from_ssh_to_file(src, dst_path, verbose, ssh_opts, progress)
-                .map_err(CopyError::RemoteError)
-        }
-        crate::protocol::Protocol::Http | crate::protocol::Protocol::Https => {
-            let dst_path = dst.as_path();
-            remote::copy_from_http_to_file(src, dst_path, verbose, progress)
-                .map_err(CopyError::RemoteError)
+/// Make sure a destination written as `dir/` is a directory, creating it if missing.
+fn ensure_dir_destination(dst: &LocalPath, opts: &CopyOptions) -> Result<(), CopyError> {
+    // `file/` fails to stat with ENOTDIR, so look at the path without the separator.
+    let dst_path: std::path::PathBuf = dst.as_path().components().collect();
+    if dst_path.exists() {
+        if !dst_path.is_dir() {
+            return Err(CopyError::InvalidDestination(format!(
+                "{} ends with a path separator but is not a directory",
+                dst.to_string_lossy()
+            )));
         }
-        crate::protocol::Protocol::S3 => {
-            let dst_path = dst.as_path();
-            remote::copy_from_s3_to_file(src, dst_path, verbose, progress)
-                .map_err(CopyError::RemoteError)
-        }
-        _ => Err(CopyError::UnsupportedProtocol(format!(
-            "Copying from {} protocol is not supported",
-            src.protocol
-        ))),
+        return Ok(());
+    }
+    // The directory named is created, but its parents only as they would be for a file.
+    if let Some(parent) = dst_path.parent() {
+        ensure_parent(parent, opts)?;
+    }
+    if make_dirs(&dst_path, opts)? {
+        apply_chmod(&dst_path, opts)?;
     }
+    Ok(())
 }
 
-fn copy_from_local_to_remote(
-    src: &LocalPath,
-    dst: &crate::protocol::RemotePath,
-    verbose: bool,
-    ssh_opts: &[String],
-    progress: bool,
-) -> Result<(), CopyError> {
-    match dst.protocol {
-        crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp => {
-            let src_path = src.as_path();
-            if src.is_file() {
-                remote::copy_file_to_ssh(src_path, dst, verbose, ssh_opts, progress)
-                    .map_err(CopyError::RemoteError)
-            } else {
-                Err(CopyError::UnsupportedProtocol(
-                    "Directory copying to remote is not yet implemented".to_string(),
-                ))
-            }
-        }
-        crate::protocol::Protocol::S3 => {
-            let src_path = src.as_path();
-            if src.is_file() {
-                remote::copy_file_to_s3(src_path, dst, verbose, progress)
-                    .map_err(CopyError::RemoteError)
-            } else if src.is_dir() {
-                remote::copy_directory_to_s3(src_path, dst, verbose, progress)
-                    .map_err(CopyError::RemoteError)
-            } else {
-                Err(CopyError::UnsupportedProtocol(
-                    "Source must be a file or directory".to_string(),
-                ))
+/// Make sure the directory `dir` that a file is written into exists. Without `--mkpath`
+/// (or `-r`), a missing one is an error, as it is for `cp`.
+pub fn ensure_parent(dir: &Path, opts: &CopyOptions) -> Result<(), CopyError> {
+    if dir.as_os_str().is_empty() || dir.is_dir() {
+        return Ok(());
+    }
+    if !opts.mkpath && !opts.recursive {
+        return Err(CopyError::MissingParent(dir.to_path_buf()));
+    }
+    if opts.verbose {
+        println!("Creating directory: {}", dir.display());
+    }
+    if make_dirs(dir, opts)? {
+        apply_chmod(dir, opts)?;
+    }
+    Ok(())
+}
+
+/// Create the directory `dir` and any missing parents: the one place a copy creates
+/// directories. They get the umask's permissions, as with `mkdir -p`, and the parents
+/// created get the `--chmod` directory mode straight away; `dir` is left to the caller,
+/// which sets its metadata once its contents are in. Returns whether `dir` was created.
+pub fn make_dirs(dir: &Path, opts: &CopyOptions) -> Result<bool, CopyError> {
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|d| !d.as_os_str().is_empty() && fs::symlink_metadata(d).is_err())
+        .collect();
+    for created in missing.iter().rev() {
+        match fs::create_dir(created) {
+            // Another worker of a parallel copy got there first.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && created.is_dir() => continue,
+            Err(e) => {
+                return Err(CopyError::IoError {
+                    message: format!(
+                        "Failed to create destination directory: {}",
+                        created.display()
+                    ),
+                    error: e,
+                })
             }
+            Ok(()) if *created != dir => apply_chmod(created, opts)?,
+            Ok(()) => {}
         }
-        _ => Err(CopyError::UnsupportedProtocol(format!(
-            "Copying to {} protocol is not supported",
-            dst.protocol
-        ))),
+        #[cfg(test)]
+        CREATED_DIRS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(created.to_path_buf());
     }
+    Ok(!missing.is_empty())
 }
 
-#[allow(dead_code)]
-pub fn copy_local(
-    src: &LocalPath,
-    dst: &LocalPath,
-    verbose: bool,
-    progress: bool,
-) -> Result<(), CopyError> {
-    let mut stats = CopyStats::new();
-    copy_local_with_stats(src, dst, verbose, progress, false, &mut stats)
-}
+/// Every directory [`make_dirs`] created, for tests that count them.
+#[cfg(test)]
+pub(crate) static CREATED_DIRS: std::sync::Mutex<Vec<std::path::PathBuf>> =
+    std::sync::Mutex::new(Vec::new());
 
-#[inline]
-fn copy_file(
-    src: &Path,
-    dst: &Path,
-    verbose: bool,
-    progress: bool,
-    use_ram: bool,
-    track_stats: bool,
-) -> Result<u64, CopyError> {
-    let final_dst = if dst.is_dir() {
-        if let Some(file_name) = src.file_name() {
-            dst.join(file_name)
-        } else {
-            return Err(CopyError::InvalidSource(
-                "Source file has no name".to_string(),
-            ));
-        }
-    } else {
-        dst.to_path_buf()
+/// `--chmod` for the directory or file `path`, when given.
+fn apply_chmod(path: &Path, opts: &CopyOptions) -> Result<(), CopyError> {
+    let Some(chmod) = &opts.chmod else {
+        return Ok(());
     };
+    chmod.apply(path).map_err(|e| CopyError::IoError {
+        message: format!("Failed to apply --chmod to: {}", path.display()),
+        error: e,
+    })
+}
 
-    if let Some(parent) = final_dst.parent() {
-        if verbose {
-            println!("Creating directory: {}", parent.display());
-        }
-        fs::create_dir_all(parent).map_err(|e| CopyError::IoError {
-            message: format!(
-                "Failed to create destination directory: {}",
-                parent.display()
-            ),
-            error: e,
-        })?;
+/// Where a download of `src` lands: inside `dst` when it names a directory (an
+/// existing one, or one written with a trailing separator), otherwise at `dst` itself.
+/// The directory it lands in must exist, or be created with `--mkpath`.
+fn download_target(
+    src: &crate::protocol::RemotePath,
+    dst: &LocalPath,
+    opts: &CopyOptions,
+) -> Result<std::path::PathBuf, CopyError> {
+    if dst.ends_with_separator() {
+        ensure_dir_destination(dst, opts)?;
+    }
+    let target = match src.file_name() {
+        Some(name) if dst.is_dir() => dst.as_path().join(name),
+        _ => dst.as_path().to_path_buf(),
+    };
+    if let Some(parent) = target.parent() {
+        ensure_parent(parent, opts)?;
     }
+    Ok(target)
+}
 
-    let src_size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+/// Where an upload of the local file `src` lands: inside `dst` when it was written
+/// with a trailing `/`, otherwise at `dst` itself.
+pub fn upload_target(
+    src: &Path,
+    dst: &crate::protocol::RemotePath,
+) -> Result<crate::protocol::RemotePath, CopyError> {
+    if !dst.is_dir_hint() {
+        return Ok(dst.clone());
+    }
+    let name = src
+        .file_name()
+        .ok_or_else(|| CopyError::InvalidSource("Source file has no name".to_string()))?;
+    Ok(dst.join(&name.to_string_lossy()))
+}
 
-    #[cfg(feature = "progress")]
-    let pb: Option<ProgressBar> = if progress {
-        use std::io::IsTerminal;
-        if std::io::stdout().is_terminal() {
-            let pb = ProgressBar::new(src_size);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) {bytes_per_sec} ETA: {eta}")
-                    .unwrap()
-                    .progress_chars("#>-"),
-            );
-            Some(pb)
-        } else {
-            None
-        }
-    } else {
-        None
+/// `--rename` for a file that would be written to `target`: the same directory, under
+/// the name the template makes of `target`'s. `metadata` is the source's, where known;
+/// `counter` numbers the file among those renamed.
+pub(crate) fn renamed(
+    target: std::path::PathBuf,
+    metadata: Option<&fs::Metadata>,
+    counter: u64,
+    opts: &CopyOptions,
+) -> Result<std::path::PathBuf, CopyError> {
+    let Some(template) = &opts.rename else {
+        return Ok(target);
     };
+    let name = target.file_name().unwrap_or(target.as_os_str());
+    let name = template
+        .render(name, metadata, counter)
+        .map_err(CopyError::InvalidDestination)?;
+    if opts.verbose && !opts.progress {
+        println!(
+            "Renaming: {} -> {}",
+            target.display(),
+            name.to_string_lossy()
+        );
+    }
+    Ok(target.with_file_name(name))
+}
 
-    if verbose && !progress {
-        println!("Copying file: {} -> {}", src.display(), final_dst.display());
-    } else if progress {
-        let show_simple = {
-            #[cfg(feature = "progress")]
-            {
-                pb.is_none()
-            }
-            #[cfg(not(feature = "progress"))]
-            {
-                true
-            }
-        };
-
-        if show_simple {
-            print!("Copying {} ({} bytes)... ", src.display(), src_size);
-            use std::io::Write;
-            io::stdout().flush().unwrap();
-        }
+/// [`renamed`] for a download to `target`, whose source can't be read before it arrives.
+fn renamed_download(
+    target: std::path::PathBuf,
+    opts: &CopyOptions,
+) -> Result<std::path::PathBuf, CopyError> {
+    if opts.rename.as_ref().is_some_and(Template::reads_source) {
+        return Err(CopyError::InvalidSource(
+            "--rename can only use {mtime} and {size} with a local source".to_string(),
+        ));
     }
+    renamed(target, None, 1, opts)
+}
 
-    let start = if track_stats {
-        Some(Instant::now())
-    } else {
-        None
+/// [`renamed`] for the upload of the local file `src` to `target`.
+fn renamed_upload(
+    src: &Path,
+    target: crate::protocol::RemotePath,
+    opts: &CopyOptions,
+) -> Result<crate::protocol::RemotePath, CopyError> {
+    let Some(name) = target.file_name().filter(|_| opts.rename.is_some()) else {
+        return Ok(target);
     };
+    let local = renamed(
+        std::path::PathBuf::from(name),
+        fs::metadata(src).ok().as_ref(),
+        1,
+        opts,
+    )?;
+    Ok(target.with_file_name(&local.to_string_lossy()))
+}
 
-    let result: Result<u64, CopyError> = if !verbose && !progress && !use_ram && !track_stats {
-        fs::copy(src, &final_dst).map_err(|e| CopyError::IoError {
-            message: format!("Failed to copy file: {}", final_dst.display()),
-            error: e,
-        })
-    } else if use_ram {
-        if src_size > 100 * 1024 * 1024 && verbose {
-            eprintln!(
-                "Warning: File is large ({} MB), RAM copy may use significant memory",
-                src_size as f64 / 1_048_576.0
-            );
-        }
-        utils::copy_file_via_ram(src, &final_dst).map_err(|e| CopyError::IoError {
-            message: format!("Failed to copy file via RAM: {}", final_dst.display()),
+/// Refuse `--rename` for `what`, which a transfer tool copies as a whole.
+fn copied_whole(what: &str, opts: &CopyOptions) -> Result<(), CopyError> {
+    if opts.rename.is_none() {
+        return Ok(());
+    }
+    Err(CopyError::UnsupportedProtocol(format!(
+        "--rename can't rename the files of {}; it works on single files and local \
+         directory copies",
+        what
+    )))
+}
+
+/// Fail when a transfer left `actual` bytes at `path` instead of `expected`.
+fn check_size(path: &str, expected: u64, actual: u64) -> Result<(), CopyError> {
+    if expected == actual {
+        return Ok(());
+    }
+    Err(CopyError::SizeMismatch {
+        path: path.to_string(),
+        expected,
+        actual,
+    })
+}
+
+fn local_size(path: &Path) -> Result<u64, CopyError> {
+    fs::metadata(path)
+        .map(|m| m.len())
+        .map_err(|e| CopyError::IoError {
+            message: format!("Failed to read metadata: {}", path.display()),
             error: e,
         })
-    } else {
-        #[cfg(target_os = "linux")]
-        {
-            if src_size > 1024 * 1024 {
-                utils::copy_file_sendfile(src, &final_dst)
-                    .or_else(|_| utils::copy_file_buffered(src, &final_dst))
-                    .map_err(|e| CopyError::IoError {
-                        message: format!("Failed to copy file: {}", final_dst.display()),
-                        error: e,
-                    })
-            } else {
-                utils::copy_file_buffered(src, &final_dst).map_err(|e| CopyError::IoError {
-                    message: format!("Failed to copy file: {}", final_dst.display()),
-                    error: e,
-                })
+}
+
+/// Size of `remote` as its side reports it, or `None` (with a warning in verbose mode)
+/// when it can't be asked. The transfer itself already succeeded, so that isn't fatal.
+fn transferred_size(remote: &crate::protocol::RemotePath, opts: &CopyOptions) -> Option<u64> {
+    match remote::remote_size(remote, &opts.ssh_opts) {
+        Ok(size) => size,
+        Err(e) => {
+            if opts.verbose {
+                output::warn(format_args!(
+                    "could not check the size of {}: {}",
+                    remote.url, e
+                ));
             }
+            None
         }
-        #[cfg(target_os = "macos")]
-        {
-            utils::copy_file_range_macos(src, &final_dst)
-                .or_else(|_| utils::copy_file_buffered(src, &final_dst))
-                .map_err(|e| CopyError::IoError {
-                    message: format!("Failed to copy file: {}", final_dst.display()),
-                    error: e,
-                })
-        }
-        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-        utils::copy_file_buffered(src, &final_dst).map_err(|e| CopyError::IoError {
-            message: format!("Failed to copy file: {}", final_dst.display()),
-            error: e,
-        })
-    };
+    }
+}
 
-    match result {
-        Ok(bytes_copied) => {
-            #[cfg(feature = "progress")]
-            {
-                if let Some(ref p) = pb {
-                    p.finish_with_message("Done");
-                } else if progress {
-                    println!("✓");
+/// After a CLI upload of the local file `src`, started at `started`, check that `dst`
+/// has the same size and count the file.
+fn verify_upload(
+    src: &Path,
+    dst: &crate::protocol::RemotePath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+    started: Instant,
+) -> Result<(), CopyError> {
+    let expected = local_size(src)?;
+    if let Some(actual) = transferred_size(dst, opts) {
+        check_size(dst.url.as_str(), expected, actual)?;
+    }
+    if stats.start_time.is_some() {
+        stats.files_copied += 1;
+        stats.bytes_copied += expected;
+    }
+    stats.time_file(src, expected, started);
+    Ok(())
+}
+
+/// After a CLI download of `src` into the local file `dst`, started at `started`,
+/// check that `dst` has the size `src` reports and count the file.
+fn verify_download(
+    src: &crate::protocol::RemotePath,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+    started: Instant,
+) -> Result<(), CopyError> {
+    let actual = local_size(dst)?;
+    if let Some(expected) = transferred_size(src, opts) {
+        check_size(&dst.display().to_string(), expected, actual)?;
+    }
+    if stats.start_time.is_some() {
+        stats.files_copied += 1;
+        stats.bytes_copied += actual;
+    }
+    stats.time_file(Path::new(src.url.as_str()), actual, started);
+    Ok(())
+}
+
+fn copy_from_remote_to_local(
+    src: &crate::protocol::RemotePath,
+    dst: &LocalPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+    let ssh_opts = opts.ssh_opts.as_slice();
+
+    match src.protocol {
+        crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp => {
+            let dst_path = download_target(src, dst, opts)?;
+            if source_is_dir(src, opts)? {
+                if !opts.recursive {
+                    return Err(CopyError::InvalidSource(format!(
+                        "{} is a directory; use -r to copy it",
+                        src.url
+                    )));
                 }
+                copied_whole("a remote directory", opts)?;
+                remote::copy_dir_from_ssh(src, &dst_path, verbose, ssh_opts, progress)
+                    .map_err(CopyError::from)?;
+                if stats.start_time.is_some() {
+                    let _ = utils::walk_files(&dst_path, None, |_, size| {
+                        stats.files_copied += 1;
+                        stats.bytes_copied += size;
+                        std::ops::ControlFlow::Continue(())
+                    });
+                }
+                return Ok(());
             }
-            #[cfg(not(feature = "progress"))]
-            {
-                if progress {
-                    println!("✓");
+            let dst_path = renamed_download(dst_path, opts)?;
+            if opts.delta {
+                match crate::delta::download_ssh(src, &dst_path, ssh_opts, opts.tmpdir.as_deref())
+                    .map_err(CopyError::from)?
+                {
+                    Some(delta) => {
+                        record_delta(stats, delta, verbose);
+                        return Ok(());
+                    }
+                    None if verbose => {
+                        println!("Delta not possible, copying whole file")
+                    }
+                    None => {}
                 }
             }
-
-            if verbose && track_stats {
-                if let Some(start_time) = start {
-                    let duration = start_time.elapsed();
-                    let speed = if duration.as_secs_f64() > 0.0 {
-                        bytes_copied as f64 / duration.as_secs_f64() / 1_048_576.0
-                    } else {
-                        0.0
-                    };
-                    println!(
-                        "Copied {} bytes in {:.2}s ({:.2} MB/s)",
-                        bytes_copied,
-                        duration.as_secs_f64(),
-                        speed
-                    );
+            let started = Instant::now();
+            remote::copy_from_ssh_to_file(src, &dst_path, verbose, ssh_opts, progress)
+                .map_err(CopyError::from)?;
+            verify_download(src, &dst_path, opts, stats, started)
+        }
+        crate::protocol::Protocol::Http | crate::protocol::Protocol::Https => {
+            let dst_path = download_target(src, dst, opts)?;
+            if opts.http.mirror {
+                copied_whole("an --http-mirror", opts)?;
+                return crate::mirror::mirror(src, &dst_path, opts, stats);
+            }
+            let dst_path = renamed_download(dst_path, opts)?;
+            let started = Instant::now();
+            let download = remote::copy_from_http_to_file(
+                src,
+                &dst_path,
+                verbose,
+                progress,
+                opts.compress,
+                &opts.http,
+            )
+            .map_err(CopyError::from)?;
+            if download == Download::NotModified {
+                if stats.start_time.is_some() {
+                    stats.files_skipped += 1;
+                    stats.bytes_skipped += local_size(&dst_path)?;
                 }
-            } else if verbose {
-                println!("Copied {} bytes", bytes_copied);
+                return Ok(());
+            }
+            verify_download(src, &dst_path, opts, stats, started)
+        }
+        crate::protocol::Protocol::S3 => {
+            let dst_path = download_target(src, dst, opts)?;
+            // A prefix named without its trailing `/` is still a prefix.
+            let prefix = (!src.is_dir_hint() && source_is_dir(src, opts)?).then(|| {
+                let mut prefix = src.clone();
+                prefix.path.push('/');
+                prefix
+            });
+            let src = prefix.as_ref().unwrap_or(src);
+            // Wildcards and prefixes are synced as a whole tree; single objects are checked.
+            let tree = src.path.ends_with('/') || src.path.contains('*');
+            let dst_path = if tree {
+                copied_whole("an S3 prefix", opts)?;
+                dst_path
+            } else {
+                renamed_download(dst_path, opts)?
+            };
+            let started = Instant::now();
+            remote::copy_from_s3_to_file(src, &dst_path, verbose, progress)
+                .map_err(CopyError::from)?;
+            if tree {
+                return Ok(());
             }
-            Ok(bytes_copied)
+            verify_download(src, &dst_path, opts, stats, started)
         }
+        _ => Err(CopyError::UnsupportedProtocol(format!(
+            "Copying from {} protocol is not supported",
+            src.protocol
+        ))),
+    }
+}
+
+/// Whether the remote source is a directory, asked of the source path itself. If the
+/// remote side can't tell, the source is taken to be a file, as before this was checked.
+fn source_is_dir(src: &crate::protocol::RemotePath, opts: &CopyOptions) -> Result<bool, CopyError> {
+    match remote::remote_is_dir(src, &opts.ssh_opts) {
+        Ok(is_dir) => Ok(is_dir),
         Err(e) => {
-            #[cfg(feature = "progress")]
-            {
-                if let Some(ref p) = pb {
-                    p.abandon();
-                }
+            if opts.verbose {
+                output::warn(format_args!(
+                    "could not tell whether {} is a directory ({}); copying it as a file",
+                    src.url, e
+                ));
             }
-            Err(e)
+            Ok(false)
         }
     }
 }
 
-fn copy_directory_with_stats(
-    src: &Path,
-    dst: &Path,
-    verbose: bool,
-    progress: bool,
-    use_ram: bool,
+fn copy_from_local_to_remote(
+    src: &LocalPath,
+    dst: &crate::protocol::RemotePath,
+    opts: &CopyOptions,
     stats: &mut CopyStats,
 ) -> Result<(), CopyError> {
-    if !dst.exists() {
-        if verbose {
-            println!("Creating destination directory: {}", dst.display());
-        }
-        fs::create_dir_all(dst).map_err(|e| CopyError::IoError {
-            message: format!("Failed to create destination directory: {}", dst.display()),
-            error: e,
-        })?;
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+    let ssh_opts = opts.ssh_opts.as_slice();
+    if src.is_dir() {
+        copied_whole("a directory upload", opts)?;
     }
 
-    copy_directory_recursive_with_stats(src, dst, verbose, progress, use_ram, stats)?;
-
-    Ok(())
+    match dst.protocol {
+        crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp => {
+            let src_path = src.as_path();
+            if src.is_file() {
+                if dst.is_dir_hint() {
+                    remote::ssh_mkdir_p(dst, std::slice::from_ref(&dst.path), ssh_opts)
+                        .map_err(CopyError::from)?;
+                }
+                let target = renamed_upload(src_path, upload_target(src_path, dst)?, opts)?;
+                if opts.delta {
+                    match crate::delta::upload_ssh(
+                        src_path,
+                        &target,
+                        ssh_opts,
+                        opts.tmpdir.as_deref(),
+                    )
+                    .map_err(CopyError::from)?
+                    {
+                        Some(delta) => {
+                            record_delta(stats, delta, verbose);
+                            return chmod_remote(
+                                &target,
+                                std::slice::from_ref(&target.path),
+                                &[],
+                                opts,
+                            );
+                        }
+                        None if verbose => {
+                            println!("Delta not possible or not worthwhile, copying whole file")
+                        }
+                        None => {}
+                    }
+                }
+                let started = Instant::now();
+                remote::copy_file_to_ssh(src_path, &target, verbose, ssh_opts, progress)
+                    .map_err(CopyError::from)?;
+                verify_upload(src_path, &target, opts, stats, started)?;
+                chmod_remote(&target, std::slice::from_ref(&target.path), &[], opts)
+            } else if src.is_dir() {
+                copy_directory_to_ssh(src_path, dst, opts, stats)
+            } else {
+                Err(CopyError::UnsupportedProtocol(
+                    "Source must be a file or directory".to_string(),
+                ))
+            }
+        }
+        crate::protocol::Protocol::S3 => {
+            let src_path = src.as_path();
+            if src.is_file() {
+                let target = renamed_upload(src_path, upload_target(src_path, dst)?, opts)?;
+                let started = Instant::now();
+                remote::copy_file_to_s3(src_path, &target, verbose, progress)
+                    .map_err(CopyError::from)?;
+                verify_upload(src_path, &target, opts, stats, started)
+            } else if src.is_dir() && opts.compare == Some(Compare::Existence) {
+                copy_missing_to_s3(src_path, dst, opts, stats)
+            } else if src.is_dir() {
+                let size_only = opts.compare == Some(Compare::SizeOnly);
+                remote::copy_directory_to_s3(src_path, dst, verbose, progress, size_only)
+                    .map_err(CopyError::from)
+            } else {
+                Err(CopyError::UnsupportedProtocol(
+                    "Source must be a file or directory".to_string(),
+                ))
+            }
+        }
+        _ => Err(CopyError::UnsupportedProtocol(format!(
+            "Copying to {} protocol is not supported",
+            dst.protocol
+        ))),
+    }
 }
 
-#[allow(dead_code)]
-fn copy_directory(src: &Path, dst: &Path, verbose: bool, progress: bool) -> Result<(), CopyError> {
-    let mut stats = CopyStats::new();
-    copy_directory_with_stats(src, dst, verbose, progress, false, &mut stats)
+/// Fail early when the filesystem holding `dir` has less than `needed` bytes free.
+/// `action` completes "Not enough space to ... <dir>". Filesystems that can't report
+/// their free space pass.
+fn ensure_space(dir: &Path, needed: u64, action: &str) -> Result<(), CopyError> {
+    let Ok(available) = utils::available_space(dir) else {
+        return Ok(());
+    };
+    if available >= needed {
+        return Ok(());
+    }
+    Err(CopyError::IoError {
+        message: format!(
+            "Not enough space to {} {}: {} bytes ({:.2} MB) needed, {} bytes ({:.2} MB) available \
+             (use --no-space-check to try anyway)",
+            action,
+            dir.display(),
+            needed,
+            needed as f64 / 1_048_576.0,
+            available,
+            available as f64 / 1_048_576.0
+        ),
+        error: io::Error::from(io::ErrorKind::StorageFull),
+    })
 }
 
-fn copy_directory_recursive_with_stats(
-    src: &Path,
-    dst: &Path,
-    verbose: bool,
-    progress: bool,
-    use_ram: bool,
+/// Preflight for a local copy: compare what `src` holds (after filters) with the free
+/// space where `dst` will be written. A file being overwritten counts as free.
+pub(crate) fn check_local_space(
+    src: &LocalPath,
+    dst: &LocalPath,
+    opts: &CopyOptions,
     stats: &mut CopyStats,
 ) -> Result<(), CopyError> {
-    #[cfg(feature = "progress")]
-    let (multi, overall_pb, current_pb) = {
-        let total_files = count_files(src)?;
-        use std::io::IsTerminal;
-        if progress && std::io::stdout().is_terminal() {
-            let multi = MultiProgress::new();
-            let overall_pb = multi.add(ProgressBar::new(total_files as u64));
-            overall_pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{bar:40.cyan/blue}] {pos}/{len} files ({percent}%)")
-                    .unwrap()
-                    .progress_chars("#>-"),
-            );
-            let current_pb = multi.add(ProgressBar::new(0));
-            current_pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("  [{bar:30.green/yellow}] {bytes}/{total_bytes} ({percent}%) {msg}")
-                    .unwrap()
-                    .progress_chars("=>-"),
-            );
-            (Some(multi), Some(overall_pb), Some(current_pb))
-        } else {
-            (None, None, None)
+    let src_path = src.as_path();
+    let needed = if src.is_file() {
+        let len = fs::metadata(src_path).map(|m| m.len()).unwrap_or(0);
+        let replaced = file_destination(src_path, dst.as_path())
+            .ok()
+            .and_then(|d| fs::metadata(d).ok())
+            .filter(|m| m.is_file())
+            .map_or(0, |m| m.len());
+        len.saturating_sub(replaced)
+    } else if src.is_dir() {
+        match prescan(src_path, opts, stats) {
+            Some((_, bytes)) => bytes,
+            // The copy itself reports unreadable directories.
+            None => return Ok(()),
         }
+    } else {
+        return Ok(());
     };
+    // The destination may not exist yet; its nearest existing ancestor is where it lands.
+    let Some(existing) = dst.as_path().ancestors().find(|p| p.exists()) else {
+        return Ok(());
+    };
+    ensure_space(existing, needed, "copy to")
+}
 
-    #[cfg(not(feature = "progress"))]
-    let (_multi, _overall_pb, _current_pb): (Option<()>, Option<()>, Option<()>) =
-        (None, None, None);
+fn is_ssh(remote: &crate::protocol::RemotePath) -> bool {
+    matches!(
+        remote.protocol,
+        crate::protocol::Protocol::Ssh | crate::protocol::Protocol::Sftp
+    )
+}
 
-    #[cfg(feature = "progress")]
-    copy_directory_recursive_impl(
-        src,
-        dst,
-        verbose,
-        progress,
-        use_ram,
-        stats,
-        &overall_pb,
-        &current_pb,
-    )?;
-    #[cfg(not(feature = "progress"))]
-    copy_directory_recursive_impl(src, dst, verbose, progress, use_ram, stats, &None, &None)?;
+/// Copy between two SSH hosts through this machine: `scp -3` when the local scp has it,
+/// otherwise a download into a temporary directory followed by an upload.
+fn copy_between_ssh(
+    src: &crate::protocol::RemotePath,
+    dst: &crate::protocol::RemotePath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let ssh_opts = opts.ssh_opts.as_slice();
+    let tree = remote::ssh_stat_tree(src, ssh_opts).map_err(CopyError::from)?;
+    if tree.is_dir && !opts.recursive {
+        return Err(CopyError::InvalidSource(format!(
+            "{} is a directory; use -r to copy it",
+            src.path
+        )));
+    }
 
-    #[cfg(feature = "progress")]
-    if let (Some(ref o), Some(ref c)) = (overall_pb, current_pb) {
-        o.finish();
-        c.finish();
+    match remote::copy_ssh_to_ssh(src, dst, tree.is_dir, opts.verbose, ssh_opts, opts.progress) {
+        Ok(()) => {
+            if !tree.is_dir {
+                let target = match src.file_name() {
+                    Some(name) if dst.is_dir_hint() => dst.join(name),
+                    _ => dst.clone(),
+                };
+                if let Some(actual) = transferred_size(&target, opts) {
+                    check_size(target.url.as_str(), tree.total_size(), actual)?;
+                }
+            }
+            if stats.start_time.is_some() {
+                stats.files_copied += tree.sizes.len();
+                stats.bytes_copied += tree.total_size();
+            }
+            return Ok(());
+        }
+        Err(remote::RemoteCopyError::NotImplemented(reason)) => {
+            if opts.verbose {
+                println!("Relaying through a local copy: {}", reason);
+            }
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let temp = utils::scratch_dir(opts.tmpdir.as_deref(), ".usync-relay-").map_err(|e| {
+        CopyError::IoError {
+            message: "Failed to create a temporary directory for the relay".to_string(),
+            error: e,
+        }
+    })?;
+    if opts.space_check {
+        ensure_space(temp.path(), tree.total_size(), "relay through")?;
     }
 
+    let name = src.file_name().unwrap_or("relay");
+    let local = temp.path().join(name);
+    let download = if tree.is_dir {
+        remote::copy_dir_from_ssh(src, &local, opts.verbose, ssh_opts, opts.progress)
+    } else {
+        remote::copy_from_ssh_to_file(src, &local, opts.verbose, ssh_opts, opts.progress)
+    };
+    download.map_err(CopyError::from)?;
+
+    // Like `scp -r`, a directory copied onto an existing directory goes inside it.
+    let target = if tree.is_dir
+        && remote::ssh_output(
+            dst,
+            &format!("test -d {} && echo dir", remote::shell_quote(&dst.path)),
+            ssh_opts,
+        )
+        .is_ok_and(|out| out.trim() == "dir")
+    {
+        dst.join(name)
+    } else {
+        dst.clone()
+    };
+    let local = LocalPath::parse(&local.to_string_lossy()).map_err(|e| {
+        CopyError::InvalidSource(format!("Invalid relay path {}: {}", local.display(), e))
+    })?;
+    // The temporary directory is removed when `temp` drops, whatever the outcome.
+    copy_from_local_to_remote(&local, &target, opts, stats)
+}
+
+/// Upload the files of `src` that have no object under the prefix `dst` yet, one
+/// `aws s3 cp` each; `aws s3 sync` has no existence-only comparison.
+fn copy_missing_to_s3(
+    src: &Path,
+    dst: &crate::protocol::RemotePath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let mut prefix = dst.clone();
+    if !prefix.is_dir_hint() {
+        prefix.path.push('/');
+    }
+    let UploadTree {
+        mut files,
+        excluded,
+        loops,
+        ..
+    } = list_upload_tree(src, &opts.filters).map_err(|e| CopyError::IoError {
+        message: format!("Failed to read source directory: {}", src.display()),
+        error: e,
+    })?;
+    stats.files_excluded += excluded.len();
+    skip_loops(src, &loops, stats);
+    let mut run = RunProgress::new(opts.progress, opts.progress_format);
+    let listed = remote::s3_list_objects(&prefix);
+    skip_unchanged(
+        src,
+        &prefix,
+        &mut files,
+        listed,
+        Compare::Existence,
+        opts,
+        stats,
+        &mut run,
+    );
+    run.planned(files.len(), files.iter().map(|(_, size)| size).sum());
+    for (rel, size) in &files {
+        let target = prefix.join(&utils::slash_path(rel));
+        let started = Instant::now();
+        remote::copy_file_to_s3(&src.join(rel), &target, opts.verbose, run.per_file())
+            .map_err(CopyError::from)?;
+        if stats.start_time.is_some() {
+            stats.files_copied += 1;
+            stats.bytes_copied += size;
+        }
+        stats.time_file(&src.join(rel), *size, started);
+        run.sent(1, *size);
+    }
+    run.finish();
     Ok(())
 }
 
-#[allow(dead_code)]
-fn count_files(path: &Path) -> Result<usize, CopyError> {
-    let mut count = 0;
-    if path.is_dir() {
-        let entries = fs::read_dir(path).map_err(|e| CopyError::IoError {
-            message: format!("Failed to read directory: {}", path.display()),
-            error: e,
-        })?;
-        for entry in entries {
-            let entry = entry.map_err(|e| CopyError::IoError {
-                message: format!("Failed to read directory entry: {}", path.display()),
-                error: e,
-            })?;
-            let path = entry.path();
-            if path.is_dir() {
-                count += count_files(&path)?;
-            } else {
-                count += 1;
+/// Files smaller than this count towards automatically enabling the tar batch path.
+const SSH_BATCH_SMALL_FILE: u64 = 256 * 1024;
+/// Number of small files above which a directory upload is batched through tar.
+const SSH_BATCH_MIN_FILES: usize = 64;
+
+fn copy_directory_to_ssh(
+    src: &Path,
+    dst: &crate::protocol::RemotePath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let UploadTree {
+        dirs,
+        mut files,
+        excluded,
+        loops,
+    } = list_upload_tree(src, &opts.filters).map_err(|e| CopyError::IoError {
+        message: format!("Failed to read source directory: {}", src.display()),
+        error: e,
+    })?;
+    stats.files_excluded += excluded.len();
+    skip_loops(src, &loops, stats);
+    let mut run = RunProgress::new(opts.progress, opts.progress_format);
+    if let Some(compare) = opts.compare {
+        let listed = remote::ssh_list_files(dst, &opts.ssh_opts);
+        skip_unchanged(src, dst, &mut files, listed, compare, opts, stats, &mut run);
+    }
+    let planned_bytes = files.iter().map(|(_, size)| size).sum();
+    run.planned(files.len(), planned_bytes);
+
+    let small_files = files
+        .iter()
+        .filter(|(_, size)| *size < SSH_BATCH_SMALL_FILE)
+        .count();
+    let batch = opts.ssh_batch || small_files > SSH_BATCH_MIN_FILES;
+
+    // Files a failed tar stream got across whole, which scp doesn't send again.
+    let mut delivered = Vec::new();
+    if batch {
+        let rel_paths: Vec<_> = files.iter().map(|(p, _)| p.clone()).collect();
+        match remote::copy_files_to_ssh_tar(
+            src,
+            &rel_paths,
+            dst,
+            opts.verbose,
+            opts.compress,
+            &opts.ssh_opts,
+        ) {
+            Ok(()) => {
+                run.sent(files.len(), planned_bytes);
+                run.finish();
+                verify_uploaded_files(dst, &files, opts)?;
+                if stats.start_time.is_some() {
+                    stats.files_copied += files.len();
+                    stats.bytes_copied += planned_bytes;
+                }
+                // tar only recreates the directories that hold a file.
+                make_remote_dirs(dst, &dirs, &opts.ssh_opts)?;
+                return chmod_uploaded(dst, &dirs, &files, opts);
+            }
+            Err(e) => {
+                if opts.verbose {
+                    output::warn(format_args!(
+                        "tar batch transfer failed ({}), falling back to scp",
+                        e
+                    ));
+                }
+                delivered = take_delivered(src, dst, &mut files, opts);
             }
         }
-    } else {
-        count = 1;
     }
-    Ok(count)
+    let mut uploaded = Vec::with_capacity(files.len() + delivered.len());
+    for (rel, size) in delivered {
+        if stats.start_time.is_some() {
+            stats.files_copied += 1;
+            stats.bytes_copied += size;
+        }
+        run.sent(1, size);
+        uploaded.push((rel, size));
+    }
+
+    make_remote_dirs(dst, &dirs, &opts.ssh_opts)?;
+    for (rel, size) in &files {
+        let local = src.join(rel);
+        let mut target = dst.clone();
+        target.path = remote::remote_join(&dst.path, rel);
+        if opts.ignore_vanished && fs::symlink_metadata(&local).is_err() {
+            output::warn(format_args!(
+                "file vanished before it could be copied: {}",
+                local.display()
+            ));
+            stats.files_vanished += 1;
+            continue;
+        }
+        let started = Instant::now();
+        remote::copy_file_to_ssh(
+            &local,
+            &target,
+            opts.verbose,
+            &opts.ssh_opts,
+            run.per_file(),
+        )
+        .map_err(CopyError::from)?;
+        if stats.start_time.is_some() {
+            stats.files_copied += 1;
+            stats.bytes_copied += size;
+        }
+        stats.time_file(&local, *size, started);
+        run.sent(1, *size);
+        uploaded.push((rel.clone(), *size));
+    }
+    run.finish();
+
+    verify_uploaded_files(dst, &uploaded, opts)?;
+    chmod_uploaded(dst, &dirs, &uploaded, opts)
+}
+
+/// After a tar upload to `dst` failed partway, move the `files` it got across whole out
+/// of `files` and return them. tar gives a file its source's modification time once all
+/// of it is written, so one at `dst` with the source's size and time is done; the one
+/// cut short, and those never reached, are left to send again. Without a listing of
+/// `dst` with times, nothing is taken.
+fn take_delivered(
+    src: &Path,
+    dst: &crate::protocol::RemotePath,
+    files: &mut Vec<(std::path::PathBuf, u64)>,
+    opts: &CopyOptions,
+) -> Vec<(std::path::PathBuf, u64)> {
+    let Ok(listed) = remote::ssh_list_files(dst, &opts.ssh_opts) else {
+        return Vec::new();
+    };
+    let arrived: std::collections::HashMap<String, (u64, Option<i64>)> = listed
+        .into_iter()
+        .map(|e| (e.path, (e.size, e.modified)))
+        .collect();
+    let (delivered, rest) = std::mem::take(files).into_iter().partition(|(rel, size)| {
+        let local = fs::metadata(src.join(rel)).map(|m| file_state(&m)).ok();
+        match (arrived.get(&utils::slash_path(rel)), local) {
+            (Some(&(remote_size, Some(remote_time))), Some((_, Some(local_time)))) => {
+                remote_size == *size && remote_time == local_time
+            }
+            _ => false,
+        }
+    });
+    *files = rest;
+    if opts.verbose {
+        println!(
+            "{} file(s) arrived before the tar stream failed; sending the other {}",
+            delivered.len(),
+            files.len()
+        );
+    }
+    delivered
+}
+
+/// `--chmod` for the remote `files` and `dirs` an SSH upload wrote.
+fn chmod_remote(
+    dst: &crate::protocol::RemotePath,
+    files: &[String],
+    dirs: &[String],
+    opts: &CopyOptions,
+) -> Result<(), CopyError> {
+    let Some(chmod) = &opts.chmod else {
+        return Ok(());
+    };
+    remote::ssh_chmod(dst, &chmod.args(false), files, &opts.ssh_opts)
+        .and_then(|()| remote::ssh_chmod(dst, &chmod.args(true), dirs, &opts.ssh_opts))
+        .map_err(CopyError::from)
+}
+
+/// `--chmod` for a directory upload to `dst`: its files, subdirectories and `dst` itself.
+fn chmod_uploaded(
+    dst: &crate::protocol::RemotePath,
+    dirs: &[std::path::PathBuf],
+    files: &[(std::path::PathBuf, u64)],
+    opts: &CopyOptions,
+) -> Result<(), CopyError> {
+    if opts.chmod.is_none() {
+        return Ok(());
+    }
+    let files: Vec<String> = files
+        .iter()
+        .map(|(rel, _)| remote::remote_join(&dst.path, rel))
+        .collect();
+    let mut remote_dirs = vec![dst.path.clone()];
+    remote_dirs.extend(dirs.iter().map(|d| remote::remote_join(&dst.path, d)));
+    chmod_remote(dst, &files, &remote_dirs, opts)
 }
 
+/// Drop the `files` of an upload from `src` that `listed`, the files already under `dst`,
+/// shows to be unchanged, counting them as skipped. When `dst` can't be listed (it may
+/// not exist yet), everything is uploaded.
 #[allow(clippy::too_many_arguments)]
-fn copy_directory_recursive_impl(
+fn skip_unchanged(
     src: &Path,
-    dst: &Path,
+    dst: &crate::protocol::RemotePath,
+    files: &mut Vec<(std::path::PathBuf, u64)>,
+    listed: Result<Vec<crate::list::Entry>, remote::RemoteCopyError>,
+    compare: Compare,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+    run: &mut RunProgress,
+) {
+    let existing: std::collections::HashMap<String, (u64, Option<i64>)> = match listed {
+        Ok(entries) => entries
+            .into_iter()
+            .map(|e| (e.path, (e.size, e.modified)))
+            .collect(),
+        Err(e) => {
+            if opts.verbose {
+                println!("Could not list {} ({}); uploading everything", dst.url, e);
+            }
+            return;
+        }
+    };
+    let (total, mut examined) = (files.len(), 0);
+    files.retain(|(rel, size)| {
+        examined += 1;
+        run.compared(examined, total);
+        let name = utils::slash_path(rel);
+        let Some(&remote_state) = existing.get(&name) else {
+            return true;
+        };
+        let local_modified = fs::metadata(src.join(rel))
+            .map(|m| file_state(&m).1)
+            .unwrap_or(None);
+        let unchanged =
+            compare.unchanged(&name, (*size, local_modified), remote_state, opts.verbose);
+        if unchanged {
+            if opts.verbose && !opts.progress {
+                println!("Unchanged: {}", name);
+            }
+            stats.files_skipped += 1;
+            stats.bytes_skipped += size;
+        }
+        !unchanged
+    });
+}
+
+/// Check that each of `files` (relative to `dst`, with their local sizes) arrived whole,
+/// asking the remote host for the sizes in batches rather than once per file.
+fn verify_uploaded_files(
+    dst: &crate::protocol::RemotePath,
+    files: &[(std::path::PathBuf, u64)],
+    opts: &CopyOptions,
+) -> Result<(), CopyError> {
+    let paths: Vec<String> = files
+        .iter()
+        .map(|(rel, _)| remote::remote_join(&dst.path, rel))
+        .collect();
+    let sizes = match remote::ssh_file_sizes(dst, &paths, &opts.ssh_opts) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            if opts.verbose {
+                output::warn(format_args!(
+                    "could not check the uploaded file sizes: {}",
+                    e
+                ));
+            }
+            return Ok(());
+        }
+    };
+    for ((path, (_, expected)), actual) in paths.iter().zip(files).zip(sizes) {
+        match actual {
+            Some(actual) => check_size(path, *expected, actual)?,
+            None => {
+                return Err(CopyError::RemoteError(remote::RemoteCopyError::IoError {
+                    message: format!("Uploaded file is missing on the remote host: {}", path),
+                    error: "not found after the transfer".to_string(),
+                }))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create `dst` and the directories `dirs` (relative to it) with a single `ssh mkdir -p`.
+fn make_remote_dirs(
+    dst: &crate::protocol::RemotePath,
+    dirs: &[std::path::PathBuf],
+    ssh_opts: &[String],
+) -> Result<(), CopyError> {
+    let mut remote_dirs = vec![dst.path.clone()];
+    remote_dirs.extend(dirs.iter().map(|d| remote::remote_join(&dst.path, d)));
+    remote::ssh_mkdir_p(dst, &remote_dirs, ssh_opts).map_err(CopyError::from)
+}
+
+/// What a directory upload sends, relative to the source directory and in walk order.
+#[derive(Debug, Default)]
+pub struct UploadTree {
+    pub dirs: Vec<std::path::PathBuf>,
+    /// Files with their sizes.
+    pub files: Vec<(std::path::PathBuf, u64)>,
+    /// Entries the filters left out, and why; nothing below an excluded directory.
+    pub excluded: Vec<(std::path::PathBuf, FilterDecision)>,
+    /// Directories not entered because they were already listed by another path: a
+    /// symlink back up the tree, or a second one to the same directory.
+    pub loops: Vec<std::path::PathBuf>,
+}
+
+/// The directories and files below `root` that pass `filters`. Symlinks are followed,
+/// but each directory is only entered once; excluded directories aren't entered.
+pub fn list_upload_tree(root: &Path, filters: &FilterChain) -> io::Result<UploadTree> {
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        filters: &FilterChain,
+        visited: &mut std::collections::HashSet<utils::DirId>,
+        tree: &mut UploadTree,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let metadata = match fs::metadata(&path) {
+                Ok(m) => m,
+                // Gone since the listing, or a dangling symlink; the copy skips it too.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let rel = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            let decision = filters.decide(&rel, metadata.is_dir());
+            if !decision.included {
+                tree.excluded.push((rel, decision));
+                continue;
+            }
+            if metadata.is_dir() {
+                if utils::DirId::of(&path).is_some_and(|id| !visited.insert(id)) {
+                    tree.loops.push(rel);
+                    continue;
+                }
+                let prune = filters.matches_dir(&rel);
+                tree.dirs.push(rel);
+                if !prune {
+                    walk(root, &path, filters, visited, tree)?;
+                }
+            } else if metadata.is_file() {
+                tree.files.push((rel, metadata.len()));
+            }
+        }
+        Ok(())
+    }
+
+    let mut tree = UploadTree::default();
+    let mut visited = utils::DirId::of(root).into_iter().collect();
+    walk(root, root, filters, &mut visited, &mut tree)?;
+    Ok(tree)
+}
+
+/// Warn about the directories of an [`UploadTree`] of `src` that were left out as
+/// [`UploadTree::loops`], and count them.
+fn skip_loops(src: &Path, loops: &[std::path::PathBuf], stats: &mut CopyStats) {
+    for rel in loops {
+        output::warn(format_args!(
+            "skipping {}: it leads to a directory already copied",
+            src.join(rel).display()
+        ));
+    }
+    stats.symlink_loops += loops.len();
+}
+
+#[allow(dead_code)]
+pub fn copy_local(
+    src: &LocalPath,
+    dst: &LocalPath,
     verbose: bool,
     progress: bool,
-    use_ram: bool,
+) -> Result<(), CopyError> {
+    let mut stats = CopyStats::new();
+    let opts = CopyOptions {
+        verbose,
+        progress,
+        ..CopyOptions::default()
+    };
+    copy_local_with_stats(src, dst, &opts, &mut stats)
+}
+
+/// Recreate the symbolic link `src` at `dst`, replacing whatever is there.
+pub(crate) fn copy_symlink(src: &Path, dst: &Path, verbose: bool) -> Result<(), CopyError> {
+    if verbose {
+        println!("Copying symlink: {} -> {}", src.display(), dst.display());
+    }
+    utils::copy_symlink(src, dst).map_err(|e| CopyError::IoError {
+        message: format!("Failed to create symlink: {}", dst.display()),
+        error: e,
+    })
+}
+
+/// The special file `src` met by a copy: recreated at `dst` with `--specials` or
+/// `--devices`, otherwise skipped with a warning and counted. With `--compare`, one
+/// already there as the same kind (and device) counts as unchanged.
+pub(crate) fn copy_special(
+    src: &Path,
+    dst: &Path,
+    kind: utils::Special,
+    opts: &CopyOptions,
     stats: &mut CopyStats,
-    #[cfg(feature = "progress")] overall_pb: &Option<ProgressBar>,
-    #[cfg(feature = "progress")] current_pb: &Option<ProgressBar>,
-    #[cfg(not(feature = "progress"))] _overall_pb: &Option<()>,
-    #[cfg(not(feature = "progress"))] _current_pb: &Option<()>,
 ) -> Result<(), CopyError> {
-    let entries: Vec<_> = fs::read_dir(src)
-        .map_err(|e| CopyError::IoError {
-            message: format!("Failed to read source directory: {}", src.display()),
-            error: e,
-        })?
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| CopyError::IoError {
-            message: format!("Failed to read directory entry in: {}", src.display()),
+    let (wanted, flag) = if kind.is_device() {
+        (opts.devices, "--devices")
+    } else {
+        (opts.specials, "--specials")
+    };
+    if !wanted {
+        output::warn(format_args!(
+            "skipping {} {} (use {} to recreate it)",
+            kind,
+            src.display(),
+            flag
+        ));
+        stats.specials_skipped += 1;
+        return Ok(());
+    }
+    if opts.compare.is_some() && utils::same_special(src, dst, kind) {
+        stats.files_skipped += 1;
+        return Ok(());
+    }
+    if opts.verbose && !opts.progress {
+        println!("Creating {}: {} -> {}", kind, src.display(), dst.display());
+    }
+    if let Err(e) = utils::copy_special(src, dst, kind) {
+        output::warn(format_args!(
+            "could not create {} {}: {}",
+            kind,
+            dst.display(),
+            e
+        ));
+        stats.specials_skipped += 1;
+        return Ok(());
+    }
+    // Setting times opens the file, which for a FIFO waits for a writer.
+    let preserve = Preserve {
+        times: false,
+        ..opts.preserve
+    };
+    if !apply_metadata(src, dst, preserve, opts)? {
+        stats.files_xattrs_incomplete += 1;
+    }
+    if stats.start_time.is_some() {
+        stats.files_copied += 1;
+    }
+    Ok(())
+}
+
+/// Apply the requested [`Preserve`] metadata, [`Ownership`] and `--chmod` from `src`
+/// to `dst`.
+///
+/// Extended attributes and ACLs are best effort: a failure prints a warning and makes
+/// this return `Ok(false)` so the caller can count it. Ownership comes first, as changing
+/// it clears set-user-ID bits the mode puts back. Extended attributes follow, as a
+/// read-only mode applied beforehand would stop `user.*` attributes from being set; then
+/// the mode and ACLs, which rewrite part of each other, and `--chmod`, which wins.
+pub fn preserve_metadata(src: &Path, dst: &Path, opts: &CopyOptions) -> Result<bool, CopyError> {
+    apply_metadata(src, dst, opts.preserve, opts)
+}
+
+/// [`preserve_metadata`] with `preserve` in place of `opts.preserve`.
+pub(crate) fn apply_metadata(
+    src: &Path,
+    dst: &Path,
+    preserve: Preserve,
+    opts: &CopyOptions,
+) -> Result<bool, CopyError> {
+    let ownership = &opts.ownership;
+    if !preserve.any() && !ownership.any() && opts.chmod.is_none() {
+        return Ok(true);
+    }
+    crate::owner::apply(src, dst, ownership).map_err(|e| CopyError::IoError {
+        message: format!("Failed to set owner of: {}", dst.display()),
+        error: e,
+    })?;
+    let xattrs_complete = !preserve.xattrs
+        || match utils::copy_xattrs(src, dst) {
+            Ok(()) => true,
+            Err(e) => {
+                output::warn(format_args!(
+                    "could not preserve extended attributes on {}: {}",
+                    dst.display(),
+                    e
+                ));
+                false
+            }
+        };
+    utils::copy_metadata(src, dst, preserve.mode, preserve.times).map_err(|e| {
+        CopyError::IoError {
+            message: format!("Failed to preserve metadata on: {}", dst.display()),
             error: e,
+        }
+    })?;
+    let acls_complete = !preserve.acls
+        || match utils::copy_acls(src, dst) {
+            Ok(()) => true,
+            Err(e) => {
+                output::warn(format_args!(
+                    "could not preserve ACLs on {}: {}",
+                    dst.display(),
+                    e
+                ));
+                false
+            }
+        };
+    apply_chmod(dst, opts)?;
+    Ok(xattrs_complete && acls_complete)
+}
+
+/// Where copying the file `src` to `dst` writes: inside `dst` when it is a directory.
+pub fn file_destination(src: &Path, dst: &Path) -> Result<std::path::PathBuf, CopyError> {
+    if !dst.is_dir() {
+        return Ok(dst.to_path_buf());
+    }
+    src.file_name()
+        .map(|name| dst.join(name))
+        .ok_or_else(|| CopyError::InvalidSource("Source file has no name".to_string()))
+}
+
+/// Count a `--delta` update in `stats` and report it.
+fn record_delta(stats: &mut CopyStats, delta: crate::delta::DeltaStats, verbose: bool) {
+    if verbose {
+        println!(
+            "Delta transfer: {} bytes matched, {} bytes literal",
+            delta.matched, delta.literal
+        );
+    }
+    if stats.start_time.is_some() {
+        stats.delta_matched += delta.matched;
+        stats.delta_literal += delta.literal;
+    }
+}
+
+/// `--delta` for a local file: rewrite an existing destination from the blocks it
+/// already has. Returns `false`, leaving the destination alone, when a plain copy
+/// should be done instead.
+fn copy_file_delta(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<bool, CopyError> {
+    let final_dst = file_destination(src, dst)?;
+    let delta =
+        crate::delta::update_local(src, &final_dst, opts.tmpdir.as_deref()).map_err(|e| {
+            CopyError::IoError {
+                message: format!("Delta update failed: {}", final_dst.display()),
+                error: e,
+            }
         })?;
+    let Some(delta) = delta else {
+        if opts.verbose {
+            println!(
+                "Delta not worthwhile, copying whole file: {}",
+                src.display()
+            );
+        }
+        return Ok(false);
+    };
+    if !preserve_metadata(src, &final_dst, opts)? {
+        stats.files_xattrs_incomplete += 1;
+    }
+    record_delta(stats, delta, opts.verbose);
+    if stats.start_time.is_some() {
+        stats.files_copied += 1;
+        stats.bytes_copied += delta.matched + delta.literal;
+    }
+    Ok(true)
+}
+
+/// `--append` for a local file: write the part of `src` past the end of `dst` when `dst`
+/// is a shorter copy of its start. Returns the bytes appended and whether all extended
+/// attributes were preserved, or `None` when the file needs a whole copy, which is also
+/// the fallback when appending fails.
+pub(crate) fn copy_appended(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+) -> Result<Option<(u64, bool)>, CopyError> {
+    let bytes = match crate::append::append(src, dst) {
+        Ok(Appended::Tail(bytes)) => bytes,
+        Ok(Appended::NoPrefix) => return Ok(None),
+        Ok(Appended::Mismatch) => {
+            if opts.verbose {
+                println!(
+                    "Destination no longer matches the start of the source, copying whole file: {}",
+                    src.display()
+                );
+            }
+            return Ok(None);
+        }
+        Err(e) => {
+            if opts.verbose {
+                println!(
+                    "Could not append ({}), copying whole file: {}",
+                    e,
+                    src.display()
+                );
+            }
+            return Ok(None);
+        }
+    };
+    if opts.verbose {
+        println!(
+            "Appended {} bytes: {} -> {}",
+            bytes,
+            src.display(),
+            dst.display()
+        );
+    }
+    if opts.fsync {
+        utils::sync_file(dst).context(|| format!("Failed to flush {} to disk", dst.display()))?;
+    }
+    let xattrs_complete = preserve_metadata(src, dst, opts)?;
+    Ok(Some((bytes, xattrs_complete)))
+}
+
+/// Copy the contents and permission bits of the file `src`, `size` bytes, to `dst`, the
+/// way [`CopyOptions::strategy`] picks for its size. The direct copy falls back to the
+/// buffered one where the platform call fails, and other platforms always loop. Flushed
+/// to disk with `--fsync`. `on_progress` hears the bytes copied so far where the platform
+/// call reports them.
+pub(crate) fn copy_contents(
+    src: &Path,
+    dst: &Path,
+    size: u64,
+    opts: &CopyOptions,
+    on_progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    let strategy = opts.strategy(size);
+    #[cfg(test)]
+    STRATEGIES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((src.to_path_buf(), strategy));
+    #[cfg(test)]
+    if let Some(result) = fill_disk(src, dst) {
+        return result;
+    }
+    if strategy == Strategy::Ram {
+        let bytes = utils::copy_file_via_ram(src, dst, opts.fsync)?;
+        fs::set_permissions(dst, fs::metadata(src)?.permissions())?;
+        return Ok(bytes);
+    }
+
+    #[cfg(target_os = "linux")]
+    let bytes = {
+        let _ = on_progress;
+        if strategy == Strategy::Direct {
+            utils::copy_file_sendfile(src, dst)
+                .or_else(|_| utils::copy_file_buffered_large(src, dst))
+        } else {
+            utils::copy_file_buffered_large(src, dst)
+        }
+    }?;
+    #[cfg(target_os = "macos")]
+    let bytes = {
+        let _ = on_progress;
+        if strategy == Strategy::Direct {
+            utils::copy_file_range_macos(src, dst, opts.preserve.xattrs)
+                .or_else(|_| utils::copy_file_buffered_large(src, dst))
+        } else {
+            utils::copy_file_buffered_large(src, dst)
+        }
+    }?;
+    #[cfg(target_os = "windows")]
+    let bytes = {
+        let mut on_progress = on_progress;
+        if strategy == Strategy::Direct {
+            utils::copy_file_windows(src, dst, |copied, _total| {
+                on_progress(copied);
+                true
+            })
+            .or_else(|_| utils::copy_file_buffered_large(src, dst))
+        } else {
+            utils::copy_file_buffered_large(src, dst)
+        }
+    }?;
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    let bytes = {
+        let _ = on_progress;
+        utils::copy_file_buffered_large(src, dst)
+    }?;
+
+    // As fs::copy would: a new file gets the umask's bits, not the source's.
+    fs::set_permissions(dst, fs::metadata(src)?.permissions())?;
+    if opts.fsync {
+        utils::sync_file(dst)?;
+    }
+    Ok(bytes)
+}
+
+/// Leave `src` alone, with a note, because `dst` is the same file (see
+/// [`utils::same_file`]); it counts as skipped.
+pub(crate) fn skip_same_file(src: &Path, dst: &Path, stats: &mut CopyStats) {
+    output::info(format_args!(
+        "Skipping {}: {} is the same file",
+        src.display(),
+        dst.display()
+    ));
+    stats.files_skipped += 1;
+    stats.bytes_skipped += fs::metadata(src).map_or(0, |m| m.len());
+}
+
+/// Whether `error` means the destination is full (`ENOSPC`, or a quota reached).
+pub(crate) fn disk_full(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// The [`CopyError::NoSpace`] for a write to `dst` that filled its filesystem with
+/// `remaining` bytes of the copy left. The partial file is removed first, so it is
+/// neither mistaken for a copy nor counted against the free space.
+pub(crate) fn out_of_space(dst: &Path, remaining: Option<u64>) -> CopyError {
+    let _ = fs::remove_file(dst);
+    let available = dst
+        .parent()
+        .and_then(|dir| utils::available_space(dir).ok());
+    CopyError::NoSpace {
+        path: dst.to_path_buf(),
+        remaining,
+        available,
+    }
+}
+
+/// A directory, and the bytes the destinations below it have room for in all, for tests
+/// that fill the disk.
+#[cfg(test)]
+pub(crate) static DISK_ROOM: std::sync::Mutex<Option<(std::path::PathBuf, u64)>> =
+    std::sync::Mutex::new(None);
+
+/// Copy `src` to `dst` as a destination under [`DISK_ROOM`] would: what fits is written,
+/// and then the write fails with `ENOSPC`.
+#[cfg(test)]
+fn fill_disk(src: &Path, dst: &Path) -> Option<io::Result<u64>> {
+    let mut room = DISK_ROOM.lock().unwrap_or_else(|e| e.into_inner());
+    let (_, left) = room.as_mut().filter(|(dir, _)| dst.starts_with(dir))?;
+    let data = match fs::read(src) {
+        Ok(data) => data,
+        Err(e) => return Some(Err(e)),
+    };
+    let fits = data.len().min(*left as usize);
+    *left -= fits as u64;
+    Some(fs::write(dst, &data[..fits]).and_then(|()| {
+        if fits < data.len() {
+            Err(io::ErrorKind::StorageFull.into())
+        } else {
+            Ok(fits as u64)
+        }
+    }))
+}
+
+/// The strategy [`copy_contents`] chose for each source file, for tests that check it.
+#[cfg(test)]
+pub(crate) static STRATEGIES: std::sync::Mutex<Vec<(std::path::PathBuf, Strategy)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Copy one file. Returns the bytes copied and whether its extended attributes (when
+/// requested) were all preserved.
+#[inline]
+fn copy_file(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    track_stats: bool,
+) -> Result<(u64, bool), CopyError> {
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+
+    let final_dst = file_destination(src, dst)?;
+    if let Some(parent) = final_dst.parent() {
+        ensure_parent(parent, opts)?;
+    }
+
+    let src_size = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+
+    #[cfg(feature = "progress")]
+    let pb: Option<ProgressBar> = if progress {
+        use std::io::IsTerminal;
+        if std::io::stdout().is_terminal() {
+            let pb = ProgressBar::new(src_size);
+            pb.set_style(crate::progress::bar_style());
+            Some(pb)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    if verbose && !progress {
+        println!("Copying file: {} -> {}", src.display(), final_dst.display());
+    } else if progress {
+        let show_simple = {
+            #[cfg(feature = "progress")]
+            {
+                pb.is_none()
+            }
+            #[cfg(not(feature = "progress"))]
+            {
+                true
+            }
+        };
+
+        if show_simple {
+            print!("Copying {} ({} bytes)... ", src.display(), src_size);
+            use std::io::Write;
+            io::stdout().flush().unwrap();
+        }
+    }
+
+    let start = if track_stats {
+        Some(Instant::now())
+    } else {
+        None
+    };
+
+    if opts.strategy(src_size) == Strategy::Ram && src_size > 100 * 1024 * 1024 && verbose {
+        output::warn(format_args!(
+            "File is large ({} MB), RAM copy may use significant memory",
+            src_size as f64 / 1_048_576.0
+        ));
+    }
+    let on_progress = |_copied: u64| {
+        #[cfg(feature = "progress")]
+        if let Some(ref p) = pb {
+            p.set_position(_copied);
+        }
+    };
+    let result = copy_contents(src, &final_dst, src_size, opts, on_progress)
+        .context(|| format!("Failed to copy file: {}", final_dst.display()))
+        .and_then(|bytes| {
+            let xattrs_complete = preserve_metadata(src, &final_dst, opts)?;
+            Ok((bytes, xattrs_complete))
+        });
+    let result = match result {
+        Err(CopyError::IoError { error, .. }) if disk_full(&error) => {
+            Err(out_of_space(&final_dst, Some(src_size)))
+        }
+        result => result,
+    };
+
+    match result {
+        Ok((bytes_copied, xattrs_complete)) => {
+            #[cfg(feature = "progress")]
+            {
+                if let Some(ref p) = pb {
+                    p.finish_with_message("Done");
+                } else if progress {
+                    println!("✓");
+                }
+            }
+            #[cfg(not(feature = "progress"))]
+            {
+                if progress {
+                    println!("✓");
+                }
+            }
+
+            if verbose && track_stats {
+                if let Some(start_time) = start {
+                    let duration = start_time.elapsed();
+                    let speed = if duration.as_secs_f64() > 0.0 {
+                        bytes_copied as f64 / duration.as_secs_f64() / 1_048_576.0
+                    } else {
+                        0.0
+                    };
+                    println!(
+                        "Copied {} bytes in {:.2}s ({:.2} MB/s)",
+                        bytes_copied,
+                        duration.as_secs_f64(),
+                        speed
+                    );
+                }
+            } else if verbose {
+                println!("Copied {} bytes", bytes_copied);
+            }
+            Ok((bytes_copied, xattrs_complete))
+        }
+        Err(e) => {
+            #[cfg(feature = "progress")]
+            {
+                if let Some(ref p) = pb {
+                    p.abandon();
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+fn copy_directory_with_stats(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    if make_dirs(dst, opts)? {
+        stats.dirs_created += 1;
+    }
+
+    match &opts.files_from {
+        Some(list) => crate::walk::copy_listed(src, dst, list, opts, stats),
+        None => crate::walk::copy_tree(src, dst, opts, stats),
+    }
+}
+
+#[allow(dead_code)]
+fn copy_directory(src: &Path, dst: &Path, verbose: bool, progress: bool) -> Result<(), CopyError> {
+    let mut stats = CopyStats::new();
+    let opts = CopyOptions {
+        verbose,
+        progress,
+        ..CopyOptions::default()
+    };
+    copy_directory_with_stats(src, dst, &opts, &mut stats)
+}
+
+/// Whether `--compare` lets the local copy of `src` to `dst` be skipped.
+pub(crate) fn local_unchanged(src: &Path, dst: &Path, opts: &CopyOptions) -> bool {
+    let Some(compare) = opts.compare else {
+        return false;
+    };
+    match (fs::metadata(src), fs::symlink_metadata(dst)) {
+        (Ok(src_meta), Ok(dst_meta)) if dst_meta.is_file() => compare.unchanged(
+            &dst.display().to_string(),
+            file_state(&src_meta),
+            file_state(&dst_meta),
+            opts.verbose,
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `error` means a source can't be read and `--continue-on-error` skips it.
+pub(crate) fn unreadable(error: &io::Error, opts: &CopyOptions) -> bool {
+    opts.continue_on_error && error.kind() == io::ErrorKind::PermissionDenied
+}
+
+/// The files and directories below `root` a directory copy with `opts` couldn't read,
+/// for a listing before it starts. Entries the filters leave out aren't checked.
+pub fn find_unreadable(root: &Path, opts: &CopyOptions) -> Vec<std::path::PathBuf> {
+    // The copy traces `--debug-filters` decisions itself.
+    let filters = opts.filters.without_debug();
+    let mut found = Vec::new();
+    let mut visited: std::collections::HashSet<_> = utils::DirId::of(root).into_iter().collect();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                found.push(dir);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_symlink() && opts.symlinks == SymlinkPolicy::Preserve {
+                continue;
+            }
+            let is_dir = path.is_dir();
+            let rel = path.strip_prefix(root).unwrap_or(&path);
+            if !filters.is_empty() && !filters.is_included(rel, is_dir) {
+                continue;
+            }
+            if is_dir && !filters.matches_dir(rel) {
+                if utils::DirId::of(&path).is_none_or(|id| visited.insert(id)) {
+                    pending.push(path);
+                }
+            } else if path.is_file()
+                && fs::File::open(&path).is_err_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+            {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Whether a `--move` may remove its source after a copy in which `failed` paths
+/// weren't copied and, with `--checksum`, the check came out as `verification`. Only a
+/// copy that is complete and, when checked, matched all the way gives the source up;
+/// otherwise the reason to keep it.
+pub fn check_move(failed: usize, verification: Option<&Outcome>) -> Result<(), String> {
+    if failed > 0 {
+        return Err(format!("{} path(s) could not be copied", failed));
+    }
+    match verification {
+        None | Some(Outcome::Match) => Ok(()),
+        Some(Outcome::SizeMatch) => Err("the copy was only checked by size".to_string()),
+        Some(Outcome::Mismatch(reason)) => Err(format!("the checksums differ ({})", reason)),
+        Some(Outcome::Unverifiable(reason)) => {
+            Err(format!("the copy could not be verified ({})", reason))
+        }
+    }
+}
+
+/// Remove the source of a finished `--move`: a local file or directory, or one on an
+/// SSH host. With `dry_run`, only say what would be removed.
+///
+/// Call it once [`check_move`] agrees.
+pub fn delete_source(path: &ProtocolPath, opts: &CopyOptions, dry_run: bool) -> Result<(), String> {
+    use crate::protocol::Protocol;
+
+    if let ProtocolPath::Remote(remote) = path {
+        if !matches!(remote.protocol, Protocol::Ssh | Protocol::Sftp) {
+            return Err(format!(
+                "Cannot remove {} sources; only local and SSH sources can be moved",
+                remote.url.scheme()
+            ));
+        }
+    }
+    if dry_run {
+        println!("Dry run: {} would be removed", describe_path(path));
+        return Ok(());
+    }
+    let verbose = opts.verbose;
+    match path {
+        ProtocolPath::Local(local_path) => {
+            let path = local_path.as_path();
+            if path.is_dir() {
+                if verbose {
+                    println!("Removing directory and all contents: {}", path.display());
+                }
+                fs::remove_dir_all(path)
+                    .map_err(|e| format!("Failed to remove directory {}: {}", path.display(), e))?;
+                if verbose {
+                    println!("Removed directory: {}", path.display());
+                }
+            } else {
+                fs::remove_file(path)
+                    .map_err(|e| format!("Failed to remove file {}: {}", path.display(), e))?;
+                if verbose {
+                    println!("Removed file: {}", path.display());
+                }
+            }
+        }
+        ProtocolPath::Remote(remote) => {
+            if verbose {
+                println!("Removing remote {}", remote.url);
+            }
+            remote::ssh_output(
+                remote,
+                &format!("rm -rf -- {}", remote::shell_quote(&remote.path)),
+                &opts.ssh_opts,
+            )
+            .map_err(|e| format!("Failed to remove {}: {}", remote.url, e))?;
+            if verbose {
+                println!("Removed remote {}", remote.url);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe_path(path: &ProtocolPath) -> String {
+    match path {
+        ProtocolPath::Local(local) => local.as_path().display().to_string(),
+        ProtocolPath::Remote(remote) => remote.url.to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub enum CopyError {
+    SourceNotFound(String),
+    InvalidSource(String),
+    InvalidDestination(String),
+    IoError {
+        message: String,
+        error: io::Error,
+    },
+    RemoteError(crate::remote::RemoteCopyError),
+    UnsupportedProtocol(String),
+    /// The directory a file would be written into doesn't exist (and `--mkpath` wasn't given).
+    MissingParent(std::path::PathBuf),
+    /// Two source names map to one destination path case-insensitively
+    /// (`--case-collisions error`).
+    CaseCollision {
+        first: std::path::PathBuf,
+        second: std::path::PathBuf,
+    },
+    /// A transfer tool reported success but the destination's size differs from the source's.
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// `--delete` would remove more of the destination than is safe; nothing was copied.
+    DeleteRefused(String),
+    /// `--delete` from a source with no files would empty a destination that has
+    /// `destination_files`; nothing was copied. `--force-delete` doesn't lift this one.
+    EmptySource {
+        destination_files: usize,
+    },
+    /// The destination filled up while `path` was written; the partial file was removed.
+    /// `remaining` is what the copy still had to write, `available` the space left.
+    NoSpace {
+        path: std::path::PathBuf,
+        remaining: Option<u64>,
+        available: Option<u64>,
+    },
+    /// The directory `path` is more than `limit` levels below the source (`--depth-limit`).
+    TooDeep {
+        path: std::path::PathBuf,
+        limit: usize,
+    },
+    /// A program a remote side needs, such as `scp` or `aws`, isn't on `PATH`. `hint`
+    /// says how to install it.
+    ToolMissing {
+        tool: String,
+        hint: String,
+    },
+}
+
+impl std::fmt::Display for CopyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyError::SourceNotFound(path) => {
+                write!(f, "Source path not found: {}\n\nSuggestion: Check that the file or directory exists and you have read permissions.", path)
+            }
+            CopyError::InvalidSource(msg) => {
+                write!(f, "Invalid source: {}\n\nSuggestion: Ensure the source is a valid file or directory.", msg)
+            }
+            CopyError::InvalidDestination(msg) => {
+                write!(f, "Invalid destination: {}\n\nSuggestion: Remove the trailing separator to copy to a file, or choose a different directory.", msg)
+            }
+            CopyError::IoError { message, error } => {
+                write!(f, "{}\n\nError details: {}\n\nSuggestion: Check file permissions and available disk space.", message, error)
+            }
+            CopyError::RemoteError(e) => {
+                write!(f, "Remote copy error: {}\n\nSuggestion: Verify network connectivity and remote server access.", e)
+            }
+            CopyError::UnsupportedProtocol(msg) => {
+                write!(f, "Unsupported protocol: {}\n\nSupported protocols: ssh://, sftp://, http://, https://, s3://\nFor more information, see: https://github.com/yassinbousaadi/usync", msg)
+            }
+            CopyError::MissingParent(dir) => {
+                write!(f, "Destination directory does not exist: {}\n\nSuggestion: Create it first, or pass --mkpath to create missing destination directories.", dir.display())
+            }
+            CopyError::CaseCollision { first, second } => {
+                write!(f, "Case collision: {} and {} are the same path on a case-insensitive filesystem\n\nSuggestion: Rename one of them in the source, or pass --case-collisions rename to copy the later one under a new name.", first.display(), second.display())
+            }
+            CopyError::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => {
+                write!(f, "Incomplete transfer: {} is {} bytes, expected {} bytes\n\nSuggestion: The transfer was cut short; check space and quotas on the destination and run the copy again.", path, actual, expected)
+            }
+            CopyError::DeleteRefused(msg) => {
+                write!(f, "Refusing to delete: {}\n\nSuggestion: Check that the source is complete and mounted; pass --force-delete only if the deletions are intended.", msg)
+            }
+            CopyError::EmptySource { destination_files } => {
+                write!(f, "Refusing to delete: the source has no files, but the destination has {}; is the source mounted?\n\nSuggestion: Check that the source is mounted and complete. usync never empties a destination from an empty source, even with --force-delete; to clear it, remove its files yourself.", destination_files)
+            }
+            CopyError::NoSpace {
+                path,
+                remaining,
+                available,
+            } => {
+                let mb = |bytes: u64| bytes as f64 / 1_048_576.0;
+                write!(f, "No space left on the destination while writing {}; the partial file was removed", path.display())?;
+                match (remaining, available) {
+                    (Some(remaining), Some(available)) => {
+                        let needed = remaining.saturating_sub(*available);
+                        write!(f, "\n\n{} bytes ({:.2} MB) of the copy were left to write and {} bytes ({:.2} MB) are free: {} bytes ({:.2} MB) more are needed", remaining, mb(*remaining), available, mb(*available), needed, mb(needed))?;
+                    }
+                    (Some(remaining), None) => {
+                        write!(
+                            f,
+                            "\n\n{} bytes ({:.2} MB) of the copy were left to write",
+                            remaining,
+                            mb(*remaining)
+                        )?;
+                    }
+                    _ => {}
+                }
+                write!(f, "\n\nSuggestion: Free up space on the destination, or copy to a larger one, and run the copy again.")
+            }
+            CopyError::TooDeep { path, limit } => {
+                write!(f, "Directory nested too deeply: {} is more than {} levels below the source\n\nSuggestion: Look for a symlink or bind mount that leads back into the source, or raise --depth-limit if the tree really is that deep.", path.display(), limit)
+            }
+            CopyError::ToolMissing { tool, hint } => {
+                write!(
+                    f,
+                    "{} is not installed: it was not found in PATH\n\nSuggestion: {}",
+                    tool, hint
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CopyError {}
+
+impl From<crate::remote::RemoteCopyError> for CopyError {
+    /// A source the server says isn't there is [`CopyError::SourceNotFound`], like a
+    /// missing local one; anything else stays a [`CopyError::RemoteError`].
+    fn from(error: crate::remote::RemoteCopyError) -> Self {
+        match error {
+            crate::remote::RemoteCopyError::HttpResponse {
+                url,
+                status: Some(404 | 410),
+                ..
+            } => CopyError::SourceNotFound(url),
+            error => CopyError::RemoteError(error),
+        }
+    }
+}
+
+/// Say what was being done, and to which path, when an I/O step fails.
+pub trait Context<T> {
+    /// The error as a [`CopyError::IoError`] with `message`, which is only made on
+    /// failure. The I/O error itself, and its kind, are kept.
+    fn context(self, message: impl FnOnce() -> String) -> Result<T, CopyError>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, message: impl FnOnce() -> String) -> Result<T, CopyError> {
+        self.map_err(|error| CopyError::IoError {
+            message: message(),
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup_test_files() -> (TempDir, LocalPath, LocalPath) {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let dst_dir = temp_dir.path().join("dst");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::create_dir_all(&dst_dir).unwrap();
+
+        fs::write(src_dir.join("test.txt"), "test content").unwrap();
+
+        let src_path = LocalPath::parse(src_dir.join("test.txt").to_str().unwrap()).unwrap();
+        let dst_path = LocalPath::parse(dst_dir.join("test_copy.txt").to_str().unwrap()).unwrap();
+
+        (temp_dir, src_path, dst_path)
+    }
+
+    #[test]
+    fn test_copy_local_file() {
+        let (_temp, src, dst) = setup_test_files();
+        let result = copy_local(&src, &dst, false, false);
+        assert!(result.is_ok());
+        assert!(dst.as_path().exists());
+
+        let content = fs::read_to_string(dst.as_path()).unwrap();
+        assert_eq!(content, "test content");
+    }
+
+    #[test]
+    fn test_copy_to_nonexistent_source() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = LocalPath::parse("/nonexistent/path/file.txt").unwrap();
+        let dst = LocalPath::parse(temp_dir.path().join("dest.txt").to_str().unwrap()).unwrap();
+
+        let result = copy_local(&src, &dst, false, false);
+        assert!(result.is_err());
+        assert!(matches!(result, Err(CopyError::SourceNotFound(_))));
+    }
+
+    #[test]
+    fn test_copy_directory() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let dst_dir = temp_dir.path().join("dst");
+
+        fs::create_dir_all(src_dir.join("subdir")).unwrap();
+        fs::write(src_dir.join("file1.txt"), "content1").unwrap();
+        fs::write(src_dir.join("subdir").join("file2.txt"), "content2").unwrap();
+
+        let src = LocalPath::parse(src_dir.to_str().unwrap()).unwrap();
+        let dst = LocalPath::parse(dst_dir.to_str().unwrap()).unwrap();
+
+        let result = copy_local(&src, &dst, false, false);
+        assert!(result.is_ok());
+        assert!(dst_dir.join("file1.txt").exists());
+        assert!(dst_dir.join("subdir").join("file2.txt").exists());
+
+        let content1 = fs::read_to_string(dst_dir.join("file1.txt")).unwrap();
+        let content2 = fs::read_to_string(dst_dir.join("subdir").join("file2.txt")).unwrap();
+        assert_eq!(content1, "content1");
+        assert_eq!(content2, "content2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_preserves_mode_and_times() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_temp, src, dst) = setup_test_files();
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs::set_permissions(src.as_path(), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::File::open(src.as_path())
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let opts = CopyOptions {
+            preserve: Preserve::all(),
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_local_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+
+        let meta = fs::metadata(dst.as_path()).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!(meta.modified().unwrap(), mtime);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_directory_preserves_symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let dst_dir = temp_dir.path().join("dst");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(src_dir.join("target.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("target.txt", src_dir.join("link.txt")).unwrap();
+
+        let src = LocalPath::parse(src_dir.to_str().unwrap()).unwrap();
+        let dst = LocalPath::parse(dst_dir.to_str().unwrap()).unwrap();
+        let opts = CopyOptions {
+            symlinks: SymlinkPolicy::Preserve,
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_local_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+
+        let link = dst_dir.join("link.txt");
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("target.txt"));
+        assert_eq!(stats.files_copied, 2);
+    }
+
+    #[test]
+    fn test_compare_modes() {
+        let same = (10, Some(100));
+        assert!(Compare::SizeMtime.unchanged("f", same, (10, Some(100)), false));
+        assert!(Compare::SizeMtime.unchanged("f", same, (10, Some(200)), false));
+        assert!(!Compare::SizeMtime.unchanged("f", same, (10, Some(50)), false));
+        assert!(!Compare::SizeMtime.unchanged("f", same, (11, Some(200)), false));
+        // A missing time on either side falls back to the size.
+        assert!(Compare::SizeMtime.unchanged("f", same, (10, None), false));
+        assert!(Compare::SizeMtime.unchanged("f", (10, None), (10, Some(1)), false));
+        assert!(!Compare::SizeMtime.unchanged("f", same, (9, None), false));
+        assert!(Compare::SizeOnly.unchanged("f", same, (10, Some(50)), false));
+        assert!(!Compare::SizeOnly.unchanged("f", same, (9, Some(200)), false));
+        assert!(Compare::Existence.unchanged("f", same, (0, None), false));
+    }
+
+    #[test]
+    fn test_skip_unchanged_uploads() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path();
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for (name, data) in [("a.txt", "aaaa"), ("b.txt", "bb"), ("sub/c.txt", "c")] {
+            fs::write(src.join(name), data).unwrap();
+        }
+        let dst = match crate::protocol::parse_path("user@host:/backup").unwrap() {
+            ProtocolPath::Remote(remote) => remote,
+            _ => unreachable!(),
+        };
+        let files = || {
+            vec![
+                (std::path::PathBuf::from("a.txt"), 4),
+                (std::path::PathBuf::from("b.txt"), 2),
+                (Path::new("sub").join("c.txt"), 1),
+            ]
+        };
+        // As an SSH host without `find -printf` or an object store might list them.
+        let listing = || {
+            Ok(vec![
+                crate::list::Entry {
+                    path: "a.txt".to_string(),
+                    size: 4,
+                    modified: None,
+                },
+                crate::list::Entry {
+                    path: "sub/c.txt".to_string(),
+                    size: 5,
+                    modified: None,
+                },
+            ])
+        };
+        let opts = CopyOptions::default();
+        let mut run = RunProgress::new(false, crate::progress::Format::Bar);
+
+        let mut left = files();
+        let mut stats = CopyStats::new();
+        skip_unchanged(
+            src,
+            &dst,
+            &mut left,
+            listing(),
+            Compare::SizeMtime,
+            &opts,
+            &mut stats,
+            &mut run,
+        );
+        assert_eq!(left, files()[1..]);
+        assert_eq!(stats.files_skipped, 1);
+
+        let mut left = files();
+        skip_unchanged(
+            src,
+            &dst,
+            &mut left,
+            listing(),
+            Compare::Existence,
+            &opts,
+            &mut stats,
+            &mut run,
+        );
+        assert_eq!(left, files()[1..2]);
+        assert_eq!(stats.files_skipped, 3);
+
+        let mut left = files();
+        let unlisted = Err(remote::RemoteCopyError::IoError {
+            message: "find failed".to_string(),
+            error: "No such file or directory".to_string(),
+        });
+        skip_unchanged(
+            src,
+            &dst,
+            &mut left,
+            unlisted,
+            Compare::SizeOnly,
+            &opts,
+            &mut stats,
+            &mut run,
+        );
+        assert_eq!(left, files());
+    }
+
+    #[test]
+    fn test_compare_skips_unchanged_local_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let src_dir = temp_dir.path().join("src");
+        let dst_dir = temp_dir.path().join("dst");
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::create_dir_all(&dst_dir).unwrap();
+        fs::write(src_dir.join("same.txt"), "same").unwrap();
+        fs::write(src_dir.join("grown.txt"), "longer now").unwrap();
+        fs::write(src_dir.join("new.txt"), "new").unwrap();
+        // Written after the sources, so not older than them.
+        fs::write(dst_dir.join("same.txt"), "SAME").unwrap();
+        fs::write(dst_dir.join("grown.txt"), "short").unwrap();
+
+        let src = LocalPath::parse(src_dir.to_str().unwrap()).unwrap();
+        let dst = LocalPath::parse(dst_dir.to_str().unwrap()).unwrap();
+        let opts = CopyOptions {
+            recursive: true,
+            compare: Some(Compare::SizeMtime),
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_local_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(
+            fs::read_to_string(dst_dir.join("same.txt")).unwrap(),
+            "SAME"
+        );
+        assert_eq!(
+            fs::read_to_string(dst_dir.join("grown.txt")).unwrap(),
+            "longer now"
+        );
+        assert_eq!(fs::read_to_string(dst_dir.join("new.txt")).unwrap(), "new");
+        assert_eq!((stats.files_copied, stats.files_skipped), (2, 1));
+    }
+
+    #[test]
+    fn test_trailing_separator_creates_directory() {
+        let (temp, src, _) = setup_test_files();
+        let dst_dir = temp.path().join("newdir");
+        let dst = LocalPath::parse(&format!("{}/", dst_dir.display())).unwrap();
+
+        let mut stats = CopyStats::new();
+        copy_local_with_stats(&src, &dst, &CopyOptions::default(), &mut stats).unwrap();
+
+        assert!(dst_dir.is_dir());
+        assert_eq!(
+            fs::read_to_string(dst_dir.join("test.txt")).unwrap(),
+            "test content"
+        );
+    }
+
+    #[test]
+    fn test_missing_parent_needs_mkpath() {
+        let (temp, src, _) = setup_test_files();
+        let file = temp.path().join("a/b/copy.txt");
+        let dst = LocalPath::parse(file.to_str().unwrap()).unwrap();
+
+        let result =
+            copy_local_with_stats(&src, &dst, &CopyOptions::default(), &mut CopyStats::new());
+        match result {
+            Err(CopyError::MissingParent(dir)) => assert_eq!(dir, temp.path().join("a/b")),
+            other => panic!("expected MissingParent, got {:?}", other),
+        }
+        assert!(!temp.path().join("a").exists());
+
+        // `dir/` is created, but not its parents.
+        let dir = LocalPath::parse(&format!("{}/", temp.path().join("c/d").display())).unwrap();
+        let result =
+            copy_local_with_stats(&src, &dir, &CopyOptions::default(), &mut CopyStats::new());
+        assert!(matches!(result, Err(CopyError::MissingParent(_))));
+        assert!(!temp.path().join("c").exists());
+
+        for opts in [
+            CopyOptions {
+                mkpath: true,
+                ..CopyOptions::default()
+            },
+            CopyOptions {
+                recursive: true,
+                ..CopyOptions::default()
+            },
+        ] {
+            fs::remove_dir_all(temp.path().join("a")).ok();
+            copy_local_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+            assert_eq!(fs::read_to_string(&file).unwrap(), "test content");
+        }
+        copy_local_with_stats(
+            &src,
+            &dir,
+            &CopyOptions {
+                mkpath: true,
+                ..CopyOptions::default()
+            },
+            &mut CopyStats::new(),
+        )
+        .unwrap();
+        assert!(temp.path().join("c/d/test.txt").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mkpath_parents_get_chmod() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (temp, src, _) = setup_test_files();
+        let file = temp.path().join("web/assets/copy.txt");
+        let dst = LocalPath::parse(file.to_str().unwrap()).unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        // Without --chmod, created parents get what the umask leaves, like `mkdir -p`.
+        let opts = CopyOptions {
+            mkpath: true,
+            ..CopyOptions::default()
+        };
+        copy_local_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+        let umask_mode = mode(&temp.path().join("web"));
+        assert_eq!(mode(&temp.path().join("web/assets")), umask_mode);
+        fs::remove_dir_all(temp.path().join("web")).unwrap();
+
+        let opts = CopyOptions {
+            mkpath: true,
+            chmod: Some(Chmod::parse("D750,F640").unwrap()),
+            ..CopyOptions::default()
+        };
+        copy_local_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+        assert_eq!(mode(&temp.path().join("web")), 0o750);
+        assert_eq!(mode(&temp.path().join("web/assets")), 0o750);
+        assert_eq!(mode(&file), 0o640);
+    }
+
+    #[test]
+    fn test_trailing_separator_rejects_existing_file() {
+        let (temp, src, _) = setup_test_files();
+        let existing = temp.path().join("occupied");
+        fs::write(&existing, "not a dir").unwrap();
+        let dst = LocalPath::parse(&format!("{}/", existing.display())).unwrap();
+
+        let mut stats = CopyStats::new();
+        let result = copy_local_with_stats(&src, &dst, &CopyOptions::default(), &mut stats);
+        assert!(matches!(result, Err(CopyError::InvalidDestination(_))));
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "not a dir");
+    }
+
+    #[test]
+    fn test_download_and_upload_targets() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("downloads");
+        let Ok(ProtocolPath::Remote(src)) =
+            crate::protocol::parse_path("https://example.com/files/data.bin")
+        else {
+            panic!("Expected remote path");
+        };
+
+        let dst = LocalPath::parse(&format!("{}/", dir.display())).unwrap();
+        assert_eq!(
+            download_target(&src, &dst, &CopyOptions::default()).unwrap(),
+            dir.join("data.bin")
+        );
+
+        let plain = LocalPath::parse(temp.path().join("out.bin").to_str().unwrap()).unwrap();
+        assert_eq!(
+            download_target(&src, &plain, &CopyOptions::default()).unwrap(),
+            temp.path().join("out.bin")
+        );
+
+        let Ok(ProtocolPath::Remote(s3_dir)) = crate::protocol::parse_path("s3://bucket/prefix/")
+        else {
+            panic!("Expected remote path");
+        };
+        let target = upload_target(Path::new("/tmp/report.csv"), &s3_dir).unwrap();
+        assert_eq!(target.path, "/prefix/report.csv");
+
+        let Ok(ProtocolPath::Remote(ssh_file)) =
+            crate::protocol::parse_path("ssh://user@host/srv/report.csv")
+        else {
+            panic!("Expected remote path");
+        };
+        let target = upload_target(Path::new("/tmp/other.csv"), &ssh_file).unwrap();
+        assert_eq!(target.path, "/srv/report.csv");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_directory_preserves_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "shared").unwrap();
+        fs::hard_link(src.join("a.txt"), src.join("sub").join("b.txt")).unwrap();
+        fs::write(src.join("c.txt"), "alone").unwrap();
+
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            hard_links: true,
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+
+        let a = fs::metadata(dst.join("a.txt")).unwrap();
+        let b = fs::metadata(dst.join("sub").join("b.txt")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(a.nlink(), 2);
+        assert_eq!(fs::metadata(dst.join("c.txt")).unwrap().nlink(), 1);
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(stats.files_linked, 1);
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        // Built on the (case-sensitive) temp filesystem; skip where it folds case.
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("Docs")).unwrap();
+        fs::create_dir_all(src.join("docs")).unwrap();
+        fs::write(src.join("README"), "upper").unwrap();
+        fs::write(src.join("readme"), "lower").unwrap();
+        fs::write(src.join("Docs/a.txt"), "a").unwrap();
+        fs::write(src.join("docs/b.txt"), "b").unwrap();
+        if fs::read_dir(&src).unwrap().count() != 4 {
+            return;
+        }
+        let with = |policy| CopyOptions {
+            case_collisions: Some(policy),
+            ..CopyOptions::default()
+        };
+
+        let dst = temp.path().join("error");
+        let result = copy_directory_with_stats(
+            &src,
+            &dst,
+            &with(CaseCollisions::Error),
+            &mut CopyStats::new(),
+        );
+        assert!(matches!(result, Err(CopyError::CaseCollision { .. })));
+
+        let dst = temp.path().join("warn");
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &with(CaseCollisions::Warn), &mut stats).unwrap();
+        assert_eq!(stats.case_collisions, 2);
+        assert_eq!(fs::read_to_string(dst.join("readme")).unwrap(), "lower");
+
+        // The later name, in byte order, gets the suffix; for a directory, its contents
+        // go with it.
+        let dst = temp.path().join("rename");
+        let opts = CopyOptions {
+            compare: Some(Compare::SizeMtime),
+            ..with(CaseCollisions::Rename)
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.case_collisions, 2);
+        assert_eq!(fs::read_to_string(dst.join("README")).unwrap(), "upper");
+        assert_eq!(fs::read_to_string(dst.join("readme~1")).unwrap(), "lower");
+        assert!(dst.join("Docs/a.txt").exists());
+        assert!(dst.join("docs~1/b.txt").exists());
+        assert!(!dst.join("readme").exists() && !dst.join("docs").exists());
+
+        // A sync renames the same way, so the renamed copies are up to date.
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.files_copied, 0);
+        assert_eq!(stats.files_skipped, 4);
+    }
+
+    #[test]
+    fn test_rename_template() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("sub/b.txt"), "bb").unwrap();
+        let with = |spec: &str| CopyOptions {
+            recursive: true,
+            rename: Some(Template::parse(spec).unwrap()),
+            ..CopyOptions::default()
+        };
+
+        // Files are renamed, directories keep their names.
+        let dst = temp.path().join("sized");
+        copy_directory_with_stats(
+            &src,
+            &dst,
+            &with("{stem}-{size}.{ext}"),
+            &mut CopyStats::new(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dst.join("a-1.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("sub/b-2.txt")).unwrap(), "bb");
+        assert!(!dst.join("a.txt").exists());
+
+        // Without a --case-collisions policy, names that meet get a suffix.
+        let dst = temp.path().join("same");
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(src.join("c.txt"), "c").unwrap();
+        copy_directory_with_stats(&src, &dst, &with("fixed.{ext}"), &mut CopyStats::new()).unwrap();
+        let mut names: Vec<_> = fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["fixed.txt", "fixed~1.txt", "sub"]);
+
+        // A single file takes the template for the name it would have had.
+        let single = |dst: &Path| {
+            let from =
+                ProtocolPath::Local(LocalPath::parse(src.join("a.txt").to_str().unwrap()).unwrap());
+            let to = ProtocolPath::Local(LocalPath::parse(dst.to_str().unwrap()).unwrap());
+            copy(&from, &to, &with("{counter}_{name}"))
+        };
+        single(&temp.path().join("same")).unwrap();
+        assert!(temp.path().join("same/1_a.txt").exists());
+        single(&temp.path().join("out.txt")).unwrap();
+        assert!(temp.path().join("1_out.txt").exists());
+
+        let sync = CopyOptions {
+            compare: Some(Compare::SizeMtime),
+            ..with("{name}")
+        };
+        let result = copy(
+            &ProtocolPath::Local(LocalPath::parse(src.to_str().unwrap()).unwrap()),
+            &ProtocolPath::Local(LocalPath::parse(dst.to_str().unwrap()).unwrap()),
+            &sync,
+        );
+        assert!(matches!(result, Err(CopyError::InvalidDestination(_))));
+    }
+
+    #[test]
+    fn test_sanitize_names() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("q?")).unwrap();
+        fs::write(src.join("q?/12:30.txt"), "a").unwrap();
+        fs::write(src.join("12_30.txt"), "b").unwrap();
+        fs::write(src.join("12:30.txt"), "c").unwrap();
+        fs::write(src.join("draft. "), "d").unwrap();
+
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            sanitize_names: Some(NameRules::parse("fat").unwrap()),
+            compare: Some(Compare::SizeMtime),
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.names_sanitized, 4);
+        assert_eq!(fs::read_to_string(dst.join("q_/12_30.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("draft")).unwrap(), "d");
+        // 12:30.txt comes out as 12_30.txt, which the file of that name keeps.
+        assert_eq!(fs::read_to_string(dst.join("12_30.txt")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dst.join("12_30~1.txt")).unwrap(), "c");
+        assert_eq!(stats.case_collisions, 1);
+
+        // The next sync compares against the sanitized names and copies nothing.
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.files_copied, 0);
+        assert_eq!(stats.files_skipped, 4);
+
+        // A single file copied into a directory is sanitized too.
+        let single = temp.path().join("single");
+        fs::create_dir_all(&single).unwrap();
+        let (from, to) = (
+            LocalPath::parse(src.join("12:30.txt").to_str().unwrap()).unwrap(),
+            LocalPath::parse(&format!("{}/", single.display())).unwrap(),
+        );
+        copy_local_with_stats(&from, &to, &opts, &mut CopyStats::new()).unwrap();
+        assert_eq!(fs::read_to_string(single.join("12_30.txt")).unwrap(), "c");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_directory_fifo() {
+        use std::os::unix::fs::FileTypeExt;
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        let made = std::process::Command::new("mkfifo")
+            .arg(src.join("pipe"))
+            .status()
+            .unwrap();
+        assert!(made.success());
+
+        // Skipped and counted by default, rather than blocking on a read.
+        let dst = temp.path().join("dst");
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &CopyOptions::default(), &mut stats).unwrap();
+        assert_eq!(stats.files_copied, 1);
+        assert_eq!(stats.specials_skipped, 1);
+        assert!(!dst.join("pipe").exists());
+
+        let opts = CopyOptions {
+            specials: true,
+            compare: Some(Compare::SizeMtime),
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.specials_skipped, 0);
+        assert!(fs::symlink_metadata(dst.join("pipe"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+
+        // A sync leaves the FIFO already there alone.
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(stats.files_copied, 0);
+        assert_eq!(stats.files_skipped, 2);
+    }
+
+    #[test]
+    fn test_delta_updates_existing_file() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("dump.sql");
+        let dst_dir = temp.path().join("backup");
+        fs::create_dir_all(&dst_dir).unwrap();
+        let old: Vec<u8> = (0..512 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut new = old.clone();
+        new[300_000..300_005].copy_from_slice(b"edit!");
+        fs::write(dst_dir.join("dump.sql"), &old).unwrap();
+        fs::write(&src, &new).unwrap();
+
+        let opts = CopyOptions {
+            delta: true,
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_local_with_stats(
+            &LocalPath::parse(src.to_str().unwrap()).unwrap(),
+            &LocalPath::parse(dst_dir.to_str().unwrap()).unwrap(),
+            &opts,
+            &mut stats,
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(dst_dir.join("dump.sql")).unwrap(), new);
+        assert_eq!(stats.files_copied, 1);
+        assert_eq!(stats.delta_matched + stats.delta_literal, new.len() as u64);
+        assert!(stats.delta_literal < 8 * 1024, "{}", stats.delta_literal);
+
+        // A destination that doesn't exist yet is copied whole.
+        let mut stats = CopyStats::new();
+        let fresh = temp.path().join("fresh.sql");
+        copy_local_with_stats(
+            &LocalPath::parse(src.to_str().unwrap()).unwrap(),
+            &LocalPath::parse(fresh.to_str().unwrap()).unwrap(),
+            &opts,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&fresh).unwrap(), new);
+        assert_eq!(stats.delta_matched + stats.delta_literal, 0);
+    }
+
+    #[test]
+    fn test_link_dest_links_unchanged_files() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("same.txt"), "unchanged").unwrap();
+        fs::write(src.join("edited.txt"), "v1").unwrap();
+
+        let archive = CopyOptions {
+            preserve: Preserve::all(),
+            ..CopyOptions::default()
+        };
+        let first = temp.path().join("snap1");
+        copy_directory_with_stats(&src, &first, &archive, &mut CopyStats::new()).unwrap();
+
+        fs::write(src.join("edited.txt"), "version 2").unwrap();
+        fs::write(src.join("new.txt"), "new").unwrap();
+
+        let second = temp.path().join("snap2");
+        let opts = CopyOptions {
+            link_dest: Some(first.clone()),
+            ..archive
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &second, &opts, &mut stats).unwrap();
+
+        assert_eq!(stats.files_linked, 1);
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(
+            fs::read_to_string(second.join("edited.txt")).unwrap(),
+            "version 2"
+        );
+        assert_eq!(fs::read_to_string(first.join("edited.txt")).unwrap(), "v1");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(
+                fs::metadata(first.join("same.txt")).unwrap().ino(),
+                fs::metadata(second.join("same.txt")).unwrap().ino()
+            );
+        }
+    }
 
-    let mut dirs = Vec::new();
-    let mut files = Vec::new();
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_directory_keeps_empty_dirs_and_dir_times() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("empty")).unwrap();
+        fs::create_dir_all(src.join("full").join("deeper")).unwrap();
+        fs::write(src.join("full").join("deeper").join("f.txt"), "x").unwrap();
 
-    for entry in entries {
-        let entry_path = entry.path();
-        let file_name = entry.file_name();
-        let dst_path = dst.join(&file_name);
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        for dir in ["empty", "full/deeper", "full", ""] {
+            fs::File::open(src.join(dir))
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
 
-        if entry_path.is_dir() {
-            dirs.push((entry_path, dst_path));
-        } else {
-            files.push((entry_path, dst_path, file_name));
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            preserve: Preserve {
+                times: true,
+                ..Preserve::default()
+            },
+            ..CopyOptions::default()
+        };
+        copy_directory_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+
+        assert!(dst.join("empty").is_dir());
+        for dir in ["empty", "full/deeper", "full", ""] {
+            assert_eq!(
+                fs::metadata(dst.join(dir)).unwrap().modified().unwrap(),
+                old,
+                "mtime of {:?}",
+                dir
+            );
         }
     }
 
-    #[cfg(feature = "parallel")]
-    {
-        let stats_arc = if stats.start_time.is_some() {
-            Some(Arc::new(Mutex::new(CopyStats {
-                bytes_copied: 0,
-                files_copied: 0,
-                files_skipped: 0,
-                start_time: stats.start_time,
-            })))
-        } else {
-            None
+    #[test]
+    fn test_copy_directory_applies_filters() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("js")).unwrap();
+        fs::create_dir_all(src.join("node_modules").join("pkg")).unwrap();
+        fs::write(src.join("index.html"), "x").unwrap();
+        fs::write(src.join("js").join("app.js"), "x").unwrap();
+        fs::write(src.join("js").join("app.js.map"), "x").unwrap();
+        fs::write(src.join("node_modules").join("pkg").join("index.js"), "x").unwrap();
+
+        let mut filters = FilterChain::default();
+        filters.push(crate::filter::PatternFilter::new(
+            &[],
+            &["*.map".to_string(), "node_modules/".to_string()],
+        ));
+        let opts = CopyOptions {
+            filters: filters.clone(),
+            ..CopyOptions::default()
         };
+        let dst = temp.path().join("dst");
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
 
-        dirs.par_iter()
-            .try_for_each(|(src_path, dst_path)| -> Result<(), CopyError> {
-                if verbose && !progress {
-                    println!(
-                        "Copying directory: {} -> {}",
-                        src_path.display(),
-                        dst_path.display()
-                    );
-                }
-                fs::create_dir_all(dst_path).map_err(|e| CopyError::IoError {
-                    message: format!("Failed to create directory: {}", dst_path.display()),
-                    error: e,
-                })?;
-
-                let mut local_stats = if let Some(ref arc) = stats_arc {
-                    CopyStats {
-                        bytes_copied: 0,
-                        files_copied: 0,
-                        files_skipped: 0,
-                        start_time: arc.lock().unwrap().start_time,
-                    }
-                } else {
-                    CopyStats::new_minimal()
-                };
+        assert!(dst.join("index.html").exists());
+        assert!(dst.join("js").join("app.js").exists());
+        assert!(!dst.join("js").join("app.js.map").exists());
+        assert!(!dst.join("node_modules").exists());
+        assert_eq!(stats.files_copied, 2);
+        // The map file, and node_modules once for everything in it.
+        assert_eq!(stats.files_excluded, 2);
 
-                #[cfg(feature = "progress")]
-                copy_directory_recursive_impl(
-                    src_path,
-                    dst_path,
-                    verbose,
-                    progress,
-                    use_ram,
-                    &mut local_stats,
-                    overall_pb,
-                    current_pb,
-                )?;
-                #[cfg(not(feature = "progress"))]
-                copy_directory_recursive_impl(
-                    src_path,
-                    dst_path,
-                    verbose,
-                    progress,
-                    use_ram,
-                    &mut local_stats,
-                    &None,
-                    &None,
-                )?;
-
-                if let Some(ref arc) = stats_arc {
-                    let mut s = arc.lock().unwrap();
-                    s.files_copied += local_stats.files_copied;
-                    s.bytes_copied += local_stats.bytes_copied;
-                    s.files_skipped += local_stats.files_skipped;
-                }
+        // The SSH upload walks the same way.
+        let tree = list_upload_tree(&src, &filters).unwrap();
+        assert_eq!(tree.dirs, vec![std::path::PathBuf::from("js")]);
+        let files: Vec<_> = tree.files.iter().map(|(p, _)| p.clone()).collect();
+        assert_eq!(
+            files,
+            vec![
+                std::path::PathBuf::from("index.html"),
+                std::path::Path::new("js").join("app.js")
+            ]
+        );
+    }
 
-                Ok(())
-            })?;
+    #[test]
+    fn test_copy_directory_counts_skips() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("logs")).unwrap();
+        fs::write(src.join("kept.txt"), "kept").unwrap();
+        fs::write(src.join("changed.txt"), "old").unwrap();
+        fs::write(src.join("notes.tmp"), "x").unwrap();
+        fs::write(src.join("logs").join("a.log"), "x").unwrap();
+        let mut filters = FilterChain::default();
+        filters.push(crate::filter::PatternFilter::new(
+            &[],
+            &["*.tmp".to_string(), "logs/".to_string()],
+        ));
+        let opts = CopyOptions {
+            filters,
+            compare: Some(Compare::SizeMtime),
+            ..CopyOptions::default()
+        };
+        let dst = temp.path().join("dst");
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_copied, stats.files_skipped), (2, 0));
+        assert_eq!(stats.files_excluded, 2);
 
-        if let Some(ref arc) = stats_arc {
-            let s = arc.lock().unwrap();
-            stats.files_copied += s.files_copied;
-            stats.bytes_copied += s.bytes_copied;
-            stats.files_skipped += s.files_skipped;
-        }
+        fs::write(src.join("changed.txt"), "newer").unwrap();
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_copied, stats.bytes_copied), (1, 5));
+        assert_eq!((stats.files_skipped, stats.bytes_skipped), (1, 4));
+        assert_eq!(stats.files_excluded, 2);
+        let report = TransferReport::from_stats(Operation::Sync, &stats);
+        assert_eq!(report.files_excluded, 2);
     }
 
-    #[cfg(not(feature = "parallel"))]
-    {
-        for (src_path, dst_path) in dirs {
-            if verbose && !progress {
-                println!(
-                    "Copying directory: {} -> {}",
-                    src_path.display(),
-                    dst_path.display()
-                );
-            }
-            fs::create_dir_all(&dst_path).map_err(|e| CopyError::IoError {
-                message: format!("Failed to create directory: {}", dst_path.display()),
-                error: e,
-            })?;
-            #[cfg(feature = "progress")]
-            copy_directory_recursive_impl(
-                &src_path, &dst_path, verbose, progress, use_ram, stats, overall_pb, current_pb,
-            )?;
-            #[cfg(not(feature = "progress"))]
-            copy_directory_recursive_impl(
-                &src_path, &dst_path, verbose, progress, use_ram, stats, &None, &None,
-            )?;
+    #[test]
+    fn test_stats_detail_times_each_file() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for name in ["a.txt", "b.txt", "sub/c.txt"] {
+            fs::write(src.join(name), name).unwrap();
         }
+        let to = |dir: &str| {
+            ProtocolPath::Local(LocalPath::parse(temp.path().join(dir).to_str().unwrap()).unwrap())
+        };
+        let opts = CopyOptions {
+            recursive: true,
+            stats_detail: Some(2),
+            ..CopyOptions::default()
+        };
+        let report = copy(&to("src"), &to("dst"), &opts).unwrap();
+        assert_eq!(report.files_copied, 3);
+        assert_eq!(report.slowest_files.len(), 2);
+        assert!(report.slowest_files[0].duration >= report.slowest_files[1].duration);
+        assert!(report.slowest_files[0].path.ends_with(".txt"));
+
+        let report = copy(&to("src"), &to("again"), &CopyOptions::default()).unwrap();
+        assert!(report.slowest_files.is_empty());
     }
 
-    #[cfg(feature = "parallel")]
-    {
-        let stats_arc: Option<Arc<Mutex<(usize, u64)>>> = if stats.start_time.is_some() {
-            Some(Arc::new(Mutex::new((0usize, 0u64))))
-        } else {
-            None
+    #[test]
+    fn test_strategy_by_size() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        for (name, size) in [("small", 1000), ("medium", 10_000), ("large", 100_000)] {
+            fs::write(src.join(name), vec![b'x'; size]).unwrap();
+        }
+        let chosen = |use_ram: Option<bool>| {
+            let dst = temp.path().join(format!("{:?}", use_ram));
+            let opts = CopyOptions {
+                use_ram,
+                ram_threshold: 4096,
+                direct_threshold: 65536,
+                ..CopyOptions::default()
+            };
+            copy_directory_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+            assert_eq!(fs::read(dst.join("large")).unwrap().len(), 100_000);
+            let mut chosen: Vec<(String, Strategy)> = STRATEGIES
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .filter(|(path, _)| path.starts_with(&src))
+                .map(|(path, strategy)| {
+                    (
+                        path.file_name().unwrap().to_string_lossy().into(),
+                        *strategy,
+                    )
+                })
+                .collect();
+            STRATEGIES
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|(path, _)| !path.starts_with(&src));
+            chosen.sort_by(|a, b| a.0.cmp(&b.0));
+            chosen
+        };
+        let names = |strategies: [Strategy; 3]| {
+            ["large", "medium", "small"]
+                .map(String::from)
+                .into_iter()
+                .zip(strategies)
+                .collect::<Vec<_>>()
         };
-        files
-            .iter()
-            .try_for_each(|(src_path, dst_path, file_name)| -> Result<(), CopyError> {
-                let file_size = fs::metadata(src_path).map(|m| m.len()).unwrap_or(0);
-
-                #[cfg(feature = "progress")]
-                if let Some(ref pb) = current_pb {
-                    pb.set_length(file_size);
-                    let file_name_str = file_name.to_string_lossy();
-                    pb.set_message(file_name_str.as_ref().to_string());
-                    pb.set_position(0);
-                }
 
-                if verbose && !progress {
-                    println!(
-                        "Copying file: {} -> {}",
-                        src_path.display(),
-                        dst_path.display()
-                    );
-                } else if progress {
-                    let show_simple = {
-                        #[cfg(feature = "progress")]
-                        {
-                            current_pb.is_none()
-                        }
-                        #[cfg(not(feature = "progress"))]
-                        {
-                            true
-                        }
-                    };
-                    if show_simple {
-                        print!(
-                            "  {} ({} bytes)... ",
-                            file_name.to_string_lossy(),
-                            file_size
-                        );
-                        use std::io::Write;
-                        io::stdout().flush().unwrap();
-                    }
-                }
+        assert_eq!(
+            chosen(None),
+            names([Strategy::Direct, Strategy::Buffered, Strategy::Ram])
+        );
+        assert_eq!(chosen(Some(true)), names([Strategy::Ram; 3]));
+        assert_eq!(
+            chosen(Some(false)),
+            names([Strategy::Direct, Strategy::Buffered, Strategy::Buffered])
+        );
 
-                let bytes = if use_ram {
-                    utils::copy_file_via_ram(src_path, dst_path).map_err(|e| {
-                        CopyError::IoError {
-                            message: format!(
-                                "Failed to copy file from {} to {}",
-                                src_path.display(),
-                                dst_path.display()
-                            ),
-                            error: e,
-                        }
-                    })?
-                } else {
-                    fs::copy(src_path, dst_path).map_err(|e| CopyError::IoError {
-                        message: format!(
-                            "Failed to copy file from {} to {}",
-                            src_path.display(),
-                            dst_path.display()
-                        ),
-                        error: e,
-                    })?
-                };
+        let defaults = CopyOptions::default();
+        assert_eq!(defaults.strategy(RAM_THRESHOLD - 1), Strategy::Ram);
+        assert_eq!(defaults.strategy(RAM_THRESHOLD), Strategy::Buffered);
+        assert_eq!(defaults.strategy(DIRECT_THRESHOLD), Strategy::Buffered);
+        assert_eq!(defaults.strategy(DIRECT_THRESHOLD + 1), Strategy::Direct);
+    }
 
-                #[cfg(feature = "progress")]
-                if let Some(ref pb) = current_pb {
-                    pb.finish();
-                }
+    #[test]
+    fn test_same_file_is_skipped() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        let data = vec![b'x'; 10_000];
+        fs::write(src.join("data.bin"), &data).unwrap();
+        fs::write(src.join("other.txt"), "other").unwrap();
+        fs::hard_link(src.join("data.bin"), dst.join("data.bin")).unwrap();
+        fs::hard_link(src.join("data.bin"), temp.path().join("alias.bin")).unwrap();
 
-                if let Some(ref stats_lock) = stats_arc {
-                    let mut s = stats_lock.lock().unwrap();
-                    s.0 += 1;
-                    s.1 += bytes;
-                }
+        // A single file, and one in a tree, whatever path the copy would take.
+        for use_ram in [Some(true), Some(false)] {
+            let opts = CopyOptions {
+                use_ram,
+                direct_threshold: 1000,
+                ..CopyOptions::default()
+            };
+            let mut stats = CopyStats::new();
+            let alias = LocalPath::parse(temp.path().join("alias.bin").to_str().unwrap()).unwrap();
+            let file = LocalPath::parse(src.join("data.bin").to_str().unwrap()).unwrap();
+            copy_local_with_stats(&file, &alias, &opts, &mut stats).unwrap();
+            assert_eq!((stats.files_copied, stats.files_skipped), (0, 1));
+            assert_eq!(stats.bytes_skipped, 10_000);
 
-                #[cfg(feature = "progress")]
-                if let Some(ref pb) = overall_pb {
-                    pb.inc(1);
-                }
+            let mut stats = CopyStats::new();
+            copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+            assert_eq!((stats.files_copied, stats.files_skipped), (1, 1));
+            assert_eq!(fs::read(src.join("data.bin")).unwrap(), data);
+        }
 
-                {
-                    let show_check = {
-                        #[cfg(feature = "progress")]
-                        {
-                            progress && current_pb.is_none()
-                        }
-                        #[cfg(not(feature = "progress"))]
-                        {
-                            progress
-                        }
-                    };
-                    if show_check {
-                        println!("✓");
-                    }
-                }
+        // The copy functions themselves refuse rather than truncate the source.
+        let (from, to) = (src.join("data.bin"), dst.join("data.bin"));
+        assert!(utils::same_file(&from, &to));
+        assert!(!utils::same_file(&from, &src.join("other.txt")));
+        assert!(!utils::same_file(&from, &dst.join("missing")));
+        for copy in [
+            utils::copy_file_buffered,
+            utils::copy_file_sendfile,
+            |src: &Path, dst: &Path| utils::copy_file_via_ram(src, dst, false),
+        ] {
+            let e = copy(&from, &to).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            assert!(e.to_string().contains("are the same file"), "{}", e);
+        }
+        assert_eq!(fs::read(&from).unwrap(), data);
+    }
 
-                Ok(())
-            })?;
+    #[test]
+    fn test_same_file_is_refused_by_tee() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("s.bin");
+        let data = vec![b'x'; 10_000];
+        fs::write(&src, &data).unwrap();
+        fs::hard_link(&src, temp.path().join("a.bin")).unwrap();
+        fs::create_dir_all(temp.path().join("out")).unwrap();
 
-        if let Some(ref stats_lock) = stats_arc {
-            let (files_count, bytes_count) = *stats_lock.lock().unwrap();
-            if stats.start_time.is_some() {
-                stats.files_copied += files_count;
-                stats.bytes_copied += bytes_count;
-            }
-        }
+        let dsts: Vec<ProtocolPath> = [temp.path().join("out/c.bin"), temp.path().join("a.bin")]
+            .iter()
+            .map(|p| crate::protocol::parse_path(p.to_str().unwrap()).unwrap())
+            .collect();
+        let results = crate::tee::tee(&src, &dsts, &CopyOptions::default()).unwrap();
+        assert_eq!(*results[0].as_ref().unwrap(), 10_000);
+        let e = results[1].as_ref().unwrap_err().to_string();
+        assert!(e.contains("are the same file"), "{}", e);
+        assert_eq!(fs::read(&src).unwrap(), data);
+        assert_eq!(fs::read(temp.path().join("out/c.bin")).unwrap(), data);
     }
 
-    #[cfg(not(feature = "parallel"))]
-    {
-        for (src_path, dst_path, file_name) in files {
-            let file_size = fs::metadata(&src_path).map(|m| m.len()).unwrap_or(0);
+    #[test]
+    fn test_disk_full_removes_the_partial_file() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        for name in ["a", "b", "sub/c"] {
+            fs::write(src.join(name), vec![b'x'; 100]).unwrap();
+        }
+        let room = |bytes| {
+            *DISK_ROOM.lock().unwrap_or_else(|e| e.into_inner()) = Some((dst.clone(), bytes));
+        };
 
-            #[cfg(feature = "progress")]
-            if let Some(ref pb) = current_pb {
-                pb.set_length(file_size);
-                pb.set_message(file_name.to_string_lossy().to_string());
-                pb.set_position(0);
-            }
+        // The first file fits, the second runs out halfway and the third never starts.
+        room(150);
+        let error =
+            copy_directory_with_stats(&src, &dst, &CopyOptions::default(), &mut CopyStats::new())
+                .unwrap_err();
+        let CopyError::NoSpace {
+            path, remaining, ..
+        } = &error
+        else {
+            panic!("{}", error);
+        };
+        assert!(!path.exists());
+        assert_eq!(*remaining, Some(200));
+        assert!(error
+            .to_string()
+            .contains("200 bytes (0.00 MB) of the copy were left"));
+        let mut written: Vec<u64> = ["a", "b", "sub/c"]
+            .iter()
+            .filter_map(|name| fs::metadata(dst.join(name)).ok())
+            .map(|m| m.len())
+            .collect();
+        written.sort();
+        assert_eq!(written, [100]);
 
-            if verbose && !progress {
-                println!(
-                    "Copying file: {} -> {}",
-                    src_path.display(),
-                    dst_path.display()
-                );
-            } else if progress {
-                let show_simple = {
-                    #[cfg(feature = "progress")]
-                    {
-                        current_pb.is_none()
-                    }
-                    #[cfg(not(feature = "progress"))]
-                    {
-                        true
-                    }
-                };
-                if show_simple {
-                    print!(
-                        "  {} ({} bytes)... ",
-                        file_name.to_string_lossy(),
-                        file_size
-                    );
-                    use std::io::Write;
-                    io::stdout().flush().unwrap();
+        // A single file is removed too, and was all that was left.
+        room(10);
+        let file = LocalPath::parse(src.join("a").to_str().unwrap()).unwrap();
+        let target = LocalPath::parse(dst.join("single").to_str().unwrap()).unwrap();
+        let error = copy_local_with_stats(
+            &file,
+            &target,
+            &CopyOptions::default(),
+            &mut CopyStats::new(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                CopyError::NoSpace {
+                    remaining: Some(100),
+                    ..
                 }
-            }
+            ),
+            "{}",
+            error
+        );
+        assert!(!dst.join("single").exists());
+        *DISK_ROOM.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
 
-            let bytes = if use_ram {
-                utils::copy_file_via_ram(&src_path, &dst_path).map_err(|e| CopyError::IoError {
-                    message: format!(
-                        "Failed to copy file from {} to {}",
-                        src_path.display(),
-                        dst_path.display()
-                    ),
-                    error: e,
-                })?
-            } else {
-                fs::copy(&src_path, &dst_path).map_err(|e| CopyError::IoError {
-                    message: format!(
-                        "Failed to copy file from {} to {}",
-                        src_path.display(),
-                        dst_path.display()
-                    ),
-                    error: e,
-                })?
-            };
+    #[test]
+    fn test_remote_error_mapping() {
+        use crate::remote::RemoteCopyError;
+        let http = |status| RemoteCopyError::HttpResponse {
+            url: "https://example.com/a.iso".to_string(),
+            status,
+            content_type: None,
+            problem: "the server returned an error".to_string(),
+        };
+        for status in [404, 410] {
+            assert!(matches!(
+                CopyError::from(http(Some(status))),
+                CopyError::SourceNotFound(url) if url == "https://example.com/a.iso"
+            ));
+        }
+        for status in [Some(403), Some(500), None] {
+            assert!(matches!(
+                CopyError::from(http(status)),
+                CopyError::RemoteError(RemoteCopyError::HttpResponse { .. })
+            ));
+        }
+        assert!(matches!(
+            CopyError::from(RemoteCopyError::AuthenticationError("denied".to_string())),
+            CopyError::RemoteError(RemoteCopyError::AuthenticationError(_))
+        ));
+    }
 
-            #[cfg(feature = "progress")]
-            if let Some(ref pb) = current_pb {
-                pb.finish();
+    #[test]
+    fn test_context_keeps_error_kind() {
+        let missing = Path::new("/nonexistent/usync/file");
+        let error = fs::read(missing)
+            .context(|| format!("Failed to read {}", missing.display()))
+            .unwrap_err();
+        match error {
+            CopyError::IoError { message, error } => {
+                assert_eq!(message, "Failed to read /nonexistent/usync/file");
+                assert_eq!(error.kind(), io::ErrorKind::NotFound);
             }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(Ok::<_, io::Error>(3).context(|| unreachable!()).unwrap(), 3);
+    }
 
-            if stats.start_time.is_some() {
-                stats.files_copied += 1;
-                stats.bytes_copied += bytes;
-            }
+    #[test]
+    fn test_stats_merge() {
+        let mut stats = CopyStats {
+            files_copied: 1,
+            bytes_copied: 10,
+            slowest: SlowFiles::new(5),
+            ..CopyStats::new()
+        };
+        let mut part = CopyStats {
+            files_copied: 2,
+            bytes_copied: 5,
+            files_excluded: 3,
+            delta_matched: 7,
+            slowest: SlowFiles::new(5),
+            errors: vec![FileError {
+                path: "x".to_string(),
+                message: "cannot read file".to_string(),
+            }],
+            ..CopyStats::new()
+        };
+        part.time_file(Path::new("b.txt"), 5, Instant::now());
+        stats.merge(part);
+        assert_eq!((stats.files_copied, stats.bytes_copied), (3, 15));
+        assert_eq!((stats.files_excluded, stats.delta_matched), (3, 7));
+        assert_eq!(stats.errors.len(), 1);
+        assert_eq!(stats.slowest.sorted().len(), 1);
+    }
 
-            #[cfg(feature = "progress")]
-            if let Some(ref pb) = overall_pb {
-                pb.inc(1);
-            }
+    /// Records every path it is asked about, so a test can tell which directories a
+    /// walk read.
+    #[derive(Debug)]
+    struct Recording {
+        inner: crate::filter::PatternFilter,
+        seen: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
 
-            {
-                let show_check = {
-                    #[cfg(feature = "progress")]
-                    {
-                        progress && current_pb.is_none()
-                    }
-                    #[cfg(not(feature = "progress"))]
-                    {
-                        progress
-                    }
-                };
-                if show_check {
-                    println!("✓");
-                }
-            }
+    impl crate::filter::Filter for Recording {
+        fn is_included(&self, rel: &Path, is_dir: bool) -> bool {
+            self.seen.lock().unwrap().push(utils::slash_path(rel));
+            self.inner.is_included(rel, is_dir)
+        }
+
+        fn matches_dir(&self, rel: &Path) -> bool {
+            self.inner.matches_dir(rel)
         }
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_excluded_directories_are_not_read() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let modules = src.join("web").join("node_modules");
+        for i in 0..200 {
+            let pkg = modules.join(format!("pkg{}", i));
+            fs::create_dir_all(&pkg).unwrap();
+            fs::write(pkg.join("index.js"), "x").unwrap();
+        }
+        fs::write(src.join("web").join("app.js"), "x").unwrap();
 
-#[derive(Debug)]
-pub enum CopyError {
-    SourceNotFound(String),
-    InvalidSource(String),
-    IoError { message: String, error: io::Error },
-    RemoteError(crate::remote::RemoteCopyError),
-    UnsupportedProtocol(String),
-}
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut filters = FilterChain::default();
+        filters.push(Recording {
+            inner: crate::filter::PatternFilter::new(&[], &["**/node_modules/**".to_string()]),
+            seen: seen.clone(),
+        });
+        let inside = |seen: &std::sync::Mutex<Vec<String>>| {
+            let seen = seen.lock().unwrap();
+            assert!(seen.iter().any(|p| p == "web/app.js"), "{:?}", seen);
+            seen.iter()
+                .filter(|p| p.starts_with("web/node_modules/"))
+                .count()
+        };
 
-impl std::fmt::Display for CopyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            CopyError::SourceNotFound(path) => {
-                write!(f, "Source path not found: {}\n\nSuggestion: Check that the file or directory exists and you have read permissions.", path)
-            }
-            CopyError::InvalidSource(msg) => {
-                write!(f, "Invalid source: {}\n\nSuggestion: Ensure the source is a valid file or directory.", msg)
-            }
-            CopyError::IoError { message, error } => {
-                write!(f, "{}\n\nError details: {}\n\nSuggestion: Check file permissions and available disk space.", message, error)
-            }
-            CopyError::RemoteError(e) => {
-                write!(f, "Remote copy error: {}\n\nSuggestion: Verify network connectivity and remote server access.", e)
-            }
-            CopyError::UnsupportedProtocol(msg) => {
-                write!(f, "Unsupported protocol: {}\n\nSupported protocols: ssh://, sftp://, http://, https://, s3://\nFor more information, see: https://github.com/yassinbousaadi/usync", msg)
-            }
-        }
+        let tree = list_upload_tree(&src, &filters).unwrap();
+        assert_eq!(inside(&seen), 0);
+        assert_eq!(tree.files.len(), 1);
+        assert!(tree.dirs.contains(&Path::new("web").join("node_modules")));
+
+        seen.lock().unwrap().clear();
+        let opts = CopyOptions {
+            filters,
+            ..CopyOptions::default()
+        };
+        let dst = temp.path().join("dst");
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(inside(&seen), 0);
+        assert_eq!(stats.files_copied, 1);
+        // Kept, as the pattern only excludes what is inside it.
+        assert!(dst.join("web").join("node_modules").is_dir());
+        assert!(fs::read_dir(dst.join("web").join("node_modules"))
+            .unwrap()
+            .next()
+            .is_none());
     }
-}
 
-impl std::error::Error for CopyError {}
+    #[test]
+    fn test_source_is_listed_once_before_copying() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("sub/b.txt"), "b").unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let listings = |seen: &std::sync::Mutex<Vec<String>>| {
+            let mut seen = seen.lock().unwrap();
+            let count = seen.iter().filter(|p| *p == "sub/b.txt").count();
+            seen.clear();
+            count
+        };
+        let mut opts = CopyOptions {
+            recursive: true,
+            // Not on a terminal, so no bar and nothing to count files for.
+            progress: true,
+            space_check: false,
+            ..CopyOptions::default()
+        };
+        opts.filters.push(Recording {
+            inner: crate::filter::PatternFilter::new(&[], &[]),
+            seen: seen.clone(),
+        });
+        let local = |dir: &str| {
+            ProtocolPath::Local(LocalPath::parse(temp.path().join(dir).to_str().unwrap()).unwrap())
+        };
 
-    fn setup_test_files() -> (TempDir, LocalPath, LocalPath) {
-        let temp_dir = TempDir::new().unwrap();
-        let src_dir = temp_dir.path().join("src");
-        let dst_dir = temp_dir.path().join("dst");
-        fs::create_dir_all(&src_dir).unwrap();
-        fs::create_dir_all(&dst_dir).unwrap();
+        copy(&local("src"), &local("plain"), &opts).unwrap();
+        assert_eq!(listings(&seen), 1);
 
-        fs::write(src_dir.join("test.txt"), "test content").unwrap();
+        // The limits and the space check share one listing.
+        opts.limits.max_files = Some(100);
+        opts.space_check = true;
+        let report = copy(&local("src"), &local("checked"), &opts).unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(listings(&seen), 2);
+    }
 
-        let src_path = LocalPath::parse(src_dir.join("test.txt").to_str().unwrap()).unwrap();
-        let dst_path = LocalPath::parse(dst_dir.join("test_copy.txt").to_str().unwrap()).unwrap();
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_directory_preserves_xattrs() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("tagged.txt"), "x").unwrap();
+        if xattr::set(src.join("tagged.txt"), "user.usync.tag", b"red").is_err() {
+            return;
+        }
+        // Read-only sources must still get their attributes before the mode is applied.
+        fs::set_permissions(
+            src.join("tagged.txt"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o444),
+        )
+        .unwrap();
 
-        (temp_dir, src_path, dst_path)
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            preserve: Preserve {
+                mode: true,
+                xattrs: true,
+                ..Preserve::default()
+            },
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+
+        assert_eq!(
+            xattr::get(dst.join("tagged.txt"), "user.usync.tag").unwrap(),
+            Some(b"red".to_vec())
+        );
+        assert_eq!(stats.files_xattrs_incomplete, 0);
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn test_copy_local_file() {
-        let (_temp, src, dst) = setup_test_files();
-        let result = copy_local(&src, &dst, false, false);
-        assert!(result.is_ok());
-        assert!(dst.as_path().exists());
+    fn test_copy_directory_preserves_acls() {
+        const ACCESS: &str = "system.posix_acl_access";
+        const DEFAULT: &str = "system.posix_acl_default";
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("shared")).unwrap();
+        fs::write(src.join("shared/report.txt"), "x").unwrap();
+        let acl = crate::utils::tests::sample_acl();
+        if xattr::set(src.join("shared/report.txt"), ACCESS, &acl).is_err() {
+            return;
+        }
+        xattr::set(src.join("shared"), DEFAULT, &acl).unwrap();
 
-        let content = fs::read_to_string(dst.as_path()).unwrap();
-        assert_eq!(content, "test content");
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            preserve: Preserve {
+                mode: true,
+                acls: true,
+                ..Preserve::default()
+            },
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_directory_with_stats(&src, &dst, &opts, &mut stats).unwrap();
+
+        assert_eq!(
+            xattr::get(dst.join("shared/report.txt"), ACCESS).unwrap(),
+            Some(acl.clone())
+        );
+        assert_eq!(xattr::get(dst.join("shared"), DEFAULT).unwrap(), Some(acl));
+        assert_eq!(stats.files_xattrs_incomplete, 0);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_copy_to_nonexistent_source() {
-        let temp_dir = TempDir::new().unwrap();
-        let src = LocalPath::parse("/nonexistent/path/file.txt").unwrap();
-        let dst = LocalPath::parse(temp_dir.path().join("dest.txt").to_str().unwrap()).unwrap();
+    fn test_copy_directory_applies_ownership() {
+        use std::os::unix::fs::MetadataExt;
 
-        let result = copy_local(&src, &dst, false, false);
-        assert!(result.is_err());
-        assert!(matches!(result, Err(CopyError::SourceNotFound(_))));
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/file.txt"), "x").unwrap();
+        // Changing owners needs root; otherwise the copy goes ahead without it.
+        let root = fs::metadata(&src).unwrap().uid() == 0;
+
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            ownership: Ownership {
+                chown: crate::owner::Chown {
+                    uid: Some(4321),
+                    gid: None,
+                },
+                group: true,
+                groupmap: vec![(fs::metadata(&src).unwrap().gid(), 4322)],
+                ..Ownership::default()
+            },
+            ..CopyOptions::default()
+        };
+        copy_directory_with_stats(&src, &dst, &opts, &mut CopyStats::new()).unwrap();
+
+        assert_eq!(fs::read(dst.join("sub/file.txt")).unwrap(), b"x");
+        if root {
+            for path in [dst.clone(), dst.join("sub"), dst.join("sub/file.txt")] {
+                let metadata = fs::metadata(&path).unwrap();
+                assert_eq!((metadata.uid(), metadata.gid()), (4321, 4322), "{:?}", path);
+            }
+        }
     }
 
     #[test]
-    fn test_copy_directory() {
-        let temp_dir = TempDir::new().unwrap();
-        let src_dir = temp_dir.path().join("src");
-        let dst_dir = temp_dir.path().join("dst");
+    fn test_unreadable_paths_are_skipped() {
+        use std::os::unix::fs::PermissionsExt;
 
-        fs::create_dir_all(src_dir.join("subdir")).unwrap();
-        fs::write(src_dir.join("file1.txt"), "content1").unwrap();
-        fs::write(src_dir.join("subdir").join("file2.txt"), "content2").unwrap();
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("locked")).unwrap();
+        fs::write(src.join("ok.txt"), "ok").unwrap();
+        fs::write(src.join("secret.txt"), "secret").unwrap();
+        fs::write(src.join("locked/inner.txt"), "inner").unwrap();
+        let lock = |mode| {
+            for name in ["secret.txt", "locked"] {
+                fs::set_permissions(src.join(name), fs::Permissions::from_mode(mode)).unwrap();
+            }
+        };
+        lock(0o000);
+        // Root reads it anyway; there is nothing to skip then.
+        if fs::File::open(src.join("secret.txt")).is_ok() {
+            lock(0o755);
+            return;
+        }
 
-        let src = LocalPath::parse(src_dir.to_str().unwrap()).unwrap();
-        let dst = LocalPath::parse(dst_dir.to_str().unwrap()).unwrap();
+        assert_eq!(
+            find_unreadable(&src, &CopyOptions::default()),
+            vec![src.join("locked"), src.join("secret.txt")]
+        );
+        let strict = copy_directory_with_stats(
+            &src,
+            &temp.path().join("strict"),
+            &CopyOptions::default(),
+            &mut CopyStats::new(),
+        );
+        assert!(matches!(strict, Err(CopyError::IoError { .. })));
 
-        let result = copy_local(&src, &dst, false, false);
-        assert!(result.is_ok());
-        assert!(dst_dir.join("file1.txt").exists());
-        assert!(dst_dir.join("subdir").join("file2.txt").exists());
+        let dst = temp.path().join("dst");
+        let opts = CopyOptions {
+            continue_on_error: true,
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        let result = copy_directory_with_stats(&src, &dst, &opts, &mut stats);
+        lock(0o755);
+        result.unwrap();
+        assert_eq!(fs::read(dst.join("ok.txt")).unwrap(), b"ok");
+        assert!(!dst.join("secret.txt").exists());
+        assert!(!dst.join("locked/inner.txt").exists());
+        let mut failed: Vec<_> = stats.errors.iter().map(|e| e.path.clone()).collect();
+        failed.sort();
+        assert_eq!(
+            failed,
+            [src.join("locked"), src.join("secret.txt")].map(|p| p.display().to_string())
+        );
+        assert_eq!(
+            TransferReport::from_stats(Operation::Copy, &stats).files_failed,
+            2
+        );
+    }
 
-        let content1 = fs::read_to_string(dst_dir.join("file1.txt")).unwrap();
-        let content2 = fs::read_to_string(dst_dir.join("subdir").join("file2.txt")).unwrap();
-        assert_eq!(content1, "content1");
-        assert_eq!(content2, "content2");
+    #[test]
+    fn test_space_preflight() {
+        let (_temp, src, dst) = setup_test_files();
+        // `dst` doesn't exist yet; its parent directory is checked.
+        check_local_space(&src, &dst, &CopyOptions::default(), &mut CopyStats::new()).unwrap();
+
+        if utils::available_space(src.as_path()).is_ok() {
+            let err = ensure_space(src.as_path(), u64::MAX, "copy to").unwrap_err();
+            let message = err.to_string();
+            assert!(
+                message.contains("Not enough space to copy to"),
+                "{}",
+                message
+            );
+            assert!(message.contains("--no-space-check"), "{}", message);
+        }
     }
 
     #[test]
@@ -989,4 +4319,47 @@ mod tests {
         assert!(display.contains("test.txt"));
         assert!(display.contains("not found"));
     }
+
+    #[test]
+    fn test_size_mismatch_display() {
+        let error = CopyError::SizeMismatch {
+            path: "s3://bucket/big.iso".to_string(),
+            expected: 4096,
+            actual: 1024,
+        };
+        let display = error.to_string();
+        assert!(display.contains("s3://bucket/big.iso is 1024 bytes, expected 4096 bytes"));
+    }
+
+    #[test]
+    fn test_check_move() {
+        assert!(check_move(0, None).is_ok());
+        assert!(check_move(0, Some(&Outcome::Match)).is_ok());
+        // A copy that failed anywhere, or whose check didn't match, keeps its source.
+        assert_eq!(
+            check_move(2, Some(&Outcome::Match)).unwrap_err(),
+            "2 path(s) could not be copied"
+        );
+        assert!(check_move(0, Some(&Outcome::SizeMatch)).is_err());
+        assert!(check_move(0, Some(&Outcome::Mismatch("a.txt".to_string()))).is_err());
+        assert!(check_move(0, Some(&Outcome::Unverifiable("no sha256sum".to_string()))).is_err());
+    }
+
+    #[test]
+    fn test_delete_source() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let src = crate::protocol::parse_path(file.to_str().unwrap()).unwrap();
+        let opts = CopyOptions::default();
+
+        delete_source(&src, &opts, true).unwrap();
+        assert!(file.exists());
+        delete_source(&src, &opts, false).unwrap();
+        assert!(!file.exists());
+        assert!(delete_source(&src, &opts, false).is_err());
+
+        let s3 = crate::protocol::parse_path("s3://bucket/a.txt").unwrap();
+        assert!(delete_source(&s3, &opts, true).is_err());
+    }
 }
@@ -0,0 +1,367 @@
+//! `usync dedupe`: replace byte-identical files in a local tree with hard links (or
+//! copy-on-write reflinks), reclaiming the space the extra copies use.
+//!
+//! Candidates are grouped by size, confirmed with a streaming SHA-256, and then split
+//! by device, since links can't cross filesystems. Paths that already share an inode
+//! count as one file. A duplicate is replaced by linking the kept file to a temporary
+//! name next to it and renaming that over the duplicate, so the path never goes missing.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{self, Algorithm};
+use crate::utils;
+
+/// How files are grouped before being compared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum GroupBy {
+    /// Hash every file.
+    Checksum,
+    /// Only hash files that share their size with another file.
+    #[default]
+    #[value(name = "size+checksum")]
+    SizeChecksum,
+}
+
+/// What a duplicate is replaced with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkKind {
+    #[default]
+    Hard,
+    /// A copy-on-write clone: the data is shared but each path keeps its own inode and
+    /// metadata.
+    Reflink,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DedupeOptions {
+    pub by: GroupBy,
+    pub link: LinkKind,
+    pub dry_run: bool,
+}
+
+/// A regular file found in the tree.
+#[derive(Debug, Clone)]
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    /// (device, inode); `None` where the platform has no such notion.
+    inode: Option<(u64, u64)>,
+    nlink: u64,
+    /// (mode, uid, gid): hard links share these, so they have to agree.
+    owner: (u32, u32, u32),
+}
+
+#[cfg(unix)]
+fn entry(path: PathBuf, meta: &fs::Metadata) -> Entry {
+    use std::os::unix::fs::MetadataExt;
+    Entry {
+        path,
+        size: meta.len(),
+        inode: Some((meta.dev(), meta.ino())),
+        nlink: meta.nlink(),
+        owner: (meta.mode(), meta.uid(), meta.gid()),
+    }
+}
+
+#[cfg(not(unix))]
+fn entry(path: PathBuf, meta: &fs::Metadata) -> Entry {
+    Entry {
+        path,
+        size: meta.len(),
+        inode: None,
+        nlink: 1,
+        owner: (0, 0, 0),
+    }
+}
+
+/// Non-empty regular files below `dir`, without following symlinks.
+fn walk(dir: &Path, out: &mut Vec<Entry>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for dir_entry in entries {
+        let path = dir_entry.path();
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            walk(&path, out)?;
+        } else if meta.is_file() && meta.len() > 0 {
+            out.push(entry(path, &meta));
+        }
+    }
+    Ok(())
+}
+
+/// Files with identical content on one device: `keep` stays, `duplicates` get linked to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub size: u64,
+    pub keep: PathBuf,
+    pub duplicates: Vec<PathBuf>,
+    /// Bytes freed once every duplicate is replaced.
+    pub reclaimable: u64,
+}
+
+/// Paths of one inode (or one path, without inode information).
+#[derive(Debug)]
+struct Inode {
+    paths: Vec<PathBuf>,
+    device: Option<u64>,
+    size: u64,
+    nlink: u64,
+    owner: (u32, u32, u32),
+}
+
+impl Inode {
+    /// Whether replacing every path in the tree frees the data: no links outside it.
+    fn freed_by_replacing(&self) -> bool {
+        self.nlink <= self.paths.len() as u64
+    }
+}
+
+fn group_inodes(entries: Vec<Entry>) -> Vec<Inode> {
+    let mut by_inode: BTreeMap<(u64, u64), Inode> = BTreeMap::new();
+    let mut inodes = Vec::new();
+    for e in entries {
+        let inode = Inode {
+            paths: vec![e.path],
+            device: e.inode.map(|(dev, _)| dev),
+            size: e.size,
+            nlink: e.nlink,
+            owner: e.owner,
+        };
+        match e.inode {
+            Some(key) => match by_inode.get_mut(&key) {
+                Some(existing) => existing.paths.extend(inode.paths),
+                None => {
+                    by_inode.insert(key, inode);
+                }
+            },
+            None => inodes.push(inode),
+        }
+    }
+    inodes.extend(by_inode.into_values());
+    inodes
+}
+
+/// Group the files below `root` by content. Files that can't be linked to the kept one
+/// (another device, or different permissions/owner for hard links) are left out.
+pub fn find_duplicates(root: &Path, opts: &DedupeOptions) -> io::Result<Vec<Group>> {
+    let mut entries = Vec::new();
+    walk(root, &mut entries)?;
+
+    let mut by_size: BTreeMap<u64, Vec<Inode>> = BTreeMap::new();
+    for inode in group_inodes(entries) {
+        by_size.entry(inode.size).or_default().push(inode);
+    }
+
+    let mut groups = Vec::new();
+    for (size, inodes) in by_size {
+        if inodes.len() < 2 && opts.by == GroupBy::SizeChecksum {
+            continue;
+        }
+        let mut by_content: BTreeMap<(String, Option<u64>), Vec<Inode>> = BTreeMap::new();
+        for inode in inodes {
+            let digest = checksum::hash_file(&inode.paths[0], Algorithm::Sha256)?;
+            by_content
+                .entry((digest, inode.device))
+                .or_default()
+                .push(inode);
+        }
+        for (_, mut same) in by_content {
+            if same.len() < 2 {
+                continue;
+            }
+            // Keep the most-linked inode (fewest replacements), then the first path.
+            same.sort_by(|a, b| b.nlink.cmp(&a.nlink).then(a.paths.cmp(&b.paths)));
+            let keep = same.remove(0);
+            let compatible: Vec<Inode> = same
+                .into_iter()
+                .filter(|i| opts.link == LinkKind::Reflink || i.owner == keep.owner)
+                .collect();
+            if compatible.is_empty() {
+                continue;
+            }
+            groups.push(Group {
+                size,
+                keep: keep.paths[0].clone(),
+                reclaimable: compatible
+                    .iter()
+                    .filter(|i| i.freed_by_replacing())
+                    .map(|i| i.size)
+                    .sum(),
+                duplicates: compatible.into_iter().flat_map(|i| i.paths).collect(),
+            });
+        }
+    }
+    Ok(groups)
+}
+
+/// Replace `dup` with a link to `keep`, atomically.
+fn replace_with_link(keep: &Path, dup: &Path, link: LinkKind) -> io::Result<()> {
+    let name = dup
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let temp = dup.with_file_name(format!(".{}.usync-dedupe", name.to_string_lossy()));
+    let result = match link {
+        LinkKind::Hard => fs::hard_link(keep, &temp),
+        LinkKind::Reflink => utils::reflink_file(keep, &temp)
+            .and_then(|()| utils::copy_metadata(dup, &temp, true, true)),
+    }
+    .and_then(|()| fs::rename(&temp, dup));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Outcome of [`dedupe`].
+#[derive(Debug, Default)]
+pub struct DedupeReport {
+    pub groups: Vec<Group>,
+    /// Paths replaced by a link (or that would be, with `dry_run`).
+    pub linked: usize,
+    pub bytes_reclaimed: u64,
+    /// Duplicates that could not be replaced, with the reason.
+    pub failed: Vec<(PathBuf, io::Error)>,
+}
+
+/// Find duplicate files below `root` and, unless `dry_run`, link them together.
+pub fn dedupe(root: &Path, opts: &DedupeOptions) -> io::Result<DedupeReport> {
+    let groups = find_duplicates(root, opts)?;
+    let mut report = DedupeReport::default();
+    for group in &groups {
+        if opts.dry_run {
+            report.linked += group.duplicates.len();
+            report.bytes_reclaimed += group.reclaimable;
+            continue;
+        }
+        let mut all_linked = true;
+        for dup in &group.duplicates {
+            match replace_with_link(&group.keep, dup, opts.link) {
+                Ok(()) => report.linked += 1,
+                Err(e) => {
+                    all_linked = false;
+                    report.failed.push((dup.clone(), e));
+                }
+            }
+        }
+        if all_linked {
+            report.bytes_reclaimed += group.reclaimable;
+        }
+    }
+    report.groups = groups;
+    Ok(report)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tempfile::TempDir;
+
+    fn ino(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    fn sample_tree() -> TempDir {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/report.pdf"), "same content").unwrap();
+        fs::write(root.join("b/report.pdf"), "same content").unwrap();
+        fs::write(root.join("b/report (1).pdf"), "same content").unwrap();
+        // Same size, different bytes.
+        fs::write(root.join("b/other.pdf"), "diff content").unwrap();
+        fs::write(root.join("empty1"), "").unwrap();
+        fs::write(root.join("empty2"), "").unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_dry_run_lists_without_modifying() {
+        let temp = sample_tree();
+        let root = temp.path();
+        let before: Vec<u64> = ["a/report.pdf", "b/report.pdf", "b/report (1).pdf"]
+            .iter()
+            .map(|p| ino(&root.join(p)))
+            .collect();
+
+        let opts = DedupeOptions {
+            dry_run: true,
+            ..DedupeOptions::default()
+        };
+        let report = dedupe(root, &opts).unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].keep, root.join("a/report.pdf"));
+        assert_eq!(
+            report.groups[0].duplicates,
+            vec![root.join("b/report (1).pdf"), root.join("b/report.pdf")]
+        );
+        assert_eq!(report.linked, 2);
+        assert_eq!(report.bytes_reclaimed, 24);
+
+        let after: Vec<u64> = ["a/report.pdf", "b/report.pdf", "b/report (1).pdf"]
+            .iter()
+            .map(|p| ino(&root.join(p)))
+            .collect();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_hard_links_duplicates() {
+        let temp = sample_tree();
+        let root = temp.path();
+        let report = dedupe(root, &DedupeOptions::default()).unwrap();
+        assert_eq!(report.linked, 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.bytes_reclaimed, 24);
+
+        let kept = ino(&root.join("a/report.pdf"));
+        assert_eq!(ino(&root.join("b/report.pdf")), kept);
+        assert_eq!(ino(&root.join("b/report (1).pdf")), kept);
+        assert_ne!(ino(&root.join("b/other.pdf")), kept);
+        assert_eq!(
+            fs::read_to_string(root.join("b/report.pdf")).unwrap(),
+            "same content"
+        );
+
+        // Already linked: nothing left to do, whichever grouping is used.
+        for by in [GroupBy::SizeChecksum, GroupBy::Checksum] {
+            let opts = DedupeOptions {
+                by,
+                ..DedupeOptions::default()
+            };
+            assert!(dedupe(root, &opts).unwrap().groups.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_skips_different_permissions_and_outside_links() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path().join("tree");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("one"), "payload").unwrap();
+        fs::write(root.join("two"), "payload").unwrap();
+        fs::set_permissions(root.join("two"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(find_duplicates(&root, &DedupeOptions::default())
+            .unwrap()
+            .is_empty());
+
+        // The file with a link outside the tree is kept; replacing the other frees it.
+        let same_mode = fs::metadata(root.join("one")).unwrap().permissions();
+        fs::set_permissions(root.join("two"), same_mode).unwrap();
+        fs::hard_link(root.join("two"), temp.path().join("outside")).unwrap();
+        let groups = find_duplicates(&root, &DedupeOptions::default()).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].keep, root.join("two"));
+        assert_eq!(groups[0].reclaimable, 7);
+
+        // With outside links on both, the duplicate's data stays alive: nothing reclaimed.
+        fs::hard_link(root.join("one"), temp.path().join("outside2")).unwrap();
+        let groups = find_duplicates(&root, &DedupeOptions::default()).unwrap();
+        assert_eq!(groups[0].reclaimable, 0);
+    }
+}
@@ -0,0 +1,715 @@
+//! `--delete`: remove the files at the destination of a sync that its source doesn't
+//! have, as rsync does.
+//!
+//! What goes is planned before anything is copied and removed once the copy is done,
+//! so a failed copy deletes nothing. Files the filters exclude are never deleted. A
+//! source listing that comes back empty, as when an NFS mount dropped, never deletes a
+//! destination that isn't, and a plan to delete more than `--max-delete-ratio` of the
+//! destination is refused unless `--force-delete` is given.
+//!
+//! `--prune-empty-dirs` is here too: once a directory copy is done, it removes the
+//! directories left at the destination with no files below them, deepest first, but
+//! never the destination itself or a directory the filters exclude.
+//!
+//! With `--detect-renames`, a file the plan deletes is moved instead to where the source
+//! now has a file the destination lacks, when the two are the only files of their size
+//! on each side and hash the same with SHA-256. The moves are made before the copy, so
+//! a reorganized tree isn't sent again; the copy then finds the files in place.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::{self, Algorithm};
+use crate::copy::{self, CopyError, CopyOptions, CopyStats};
+use crate::list::{self, Entry, ListOptions};
+use crate::output;
+use crate::protocol::{Path as ProtocolPath, Protocol, RemotePath};
+use crate::remote::{self, RemoteCopyError};
+use crate::tag;
+use crate::utils;
+
+/// Share of the destination a sync may delete without `--force-delete`.
+pub const DEFAULT_MAX_RATIO: f64 = 0.5;
+
+/// What a `--delete` sync removes from its destination.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Files to remove, relative to the destination, `/`-separated.
+    pub files: Vec<Entry>,
+    /// Directories that only held such files, deepest first; removed when left empty.
+    pub dirs: Vec<String>,
+    /// Files to move rather than delete, from where they are to where the source has
+    /// them (`--detect-renames`).
+    pub renames: Vec<(String, String)>,
+    /// Files in the source, after filters.
+    pub source_files: usize,
+    /// Files at the destination, after filters.
+    pub destination_files: usize,
+}
+
+impl Plan {
+    /// Whether the plan is safe to carry out: the source isn't empty while the
+    /// destination has files, and no more than `--max-delete-ratio` of the destination
+    /// goes unless `--force-delete`.
+    pub fn check(&self, opts: &CopyOptions) -> Result<(), CopyError> {
+        if self.source_files == 0 && self.destination_files > 0 {
+            return Err(CopyError::EmptySource {
+                destination_files: self.destination_files,
+            });
+        }
+        if self.files.is_empty() || opts.force_delete {
+            return Ok(());
+        }
+        let ratio = self.files.len() as f64 / self.destination_files as f64;
+        if ratio > opts.max_delete_ratio {
+            return Err(CopyError::DeleteRefused(format!(
+                "{} of the {} files at the destination ({:.0}%) would be deleted, \
+                 over --max-delete-ratio {}%",
+                self.files.len(),
+                self.destination_files,
+                ratio * 100.0,
+                opts.max_delete_ratio * 100.0
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|e| e.size).sum()
+    }
+}
+
+/// Parse a `--max-delete-ratio`: a fraction (`0.5`) or a percentage (`50%`).
+pub fn parse_ratio(s: &str) -> Result<f64, String> {
+    let (number, scale) = match s.trim().strip_suffix('%') {
+        Some(percent) => (percent, 100.0),
+        None => (s.trim(), 1.0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if (0.0..=scale).contains(&n) => Ok(n / scale),
+        _ => Err(format!(
+            "invalid ratio '{}': expected a fraction from 0 to 1 or a percentage",
+            s
+        )),
+    }
+}
+
+/// Plan a `--delete` sync of the local directory `src` to `dst`.
+pub fn plan(src: &Path, dst: &ProtocolPath, opts: &CopyOptions) -> Result<Plan, CopyError> {
+    // The copy traces `--debug-filters` decisions; these walks would repeat them.
+    let tree = copy::list_upload_tree(src, &opts.filters.without_debug()).map_err(|e| {
+        CopyError::IoError {
+            message: format!("Failed to read source directory: {}", src.display()),
+            error: e,
+        }
+    })?;
+    let keep: HashSet<String> = tree
+        .files
+        .iter()
+        .map(|(rel, _)| utils::slash_path(rel))
+        .collect();
+    let source_dirs: HashSet<String> = tree.dirs.iter().map(|d| utils::slash_path(d)).collect();
+
+    let listing = ListOptions {
+        filters: opts.filters.without_debug(),
+        ssh_opts: opts.ssh_opts.clone(),
+        ..ListOptions::default()
+    };
+    let existing = match dst {
+        ProtocolPath::Local(local) if !local.as_path().exists() => Vec::new(),
+        _ => list::list(dst, &listing)?,
+    };
+    let present: HashSet<&str> = existing
+        .iter()
+        .map(|e| e.path.trim_start_matches("./"))
+        .collect();
+    let arrivals: Vec<(String, u64)> = tree
+        .files
+        .iter()
+        .map(|(rel, size)| (utils::slash_path(rel), *size))
+        .filter(|(path, _)| !present.contains(path.as_str()))
+        .collect();
+
+    let mut plan = Plan {
+        source_files: keep.len(),
+        destination_files: existing.len(),
+        ..Plan::default()
+    };
+    let mut dirs = BTreeSet::new();
+    for entry in existing {
+        let path = entry.path.trim_start_matches("./").to_string();
+        if keep.contains(&path) {
+            continue;
+        }
+        // A `--tag-checksums` sidecar stays with the file it tags.
+        if tag::tagged_file(&path).is_some_and(|file| keep.contains(&file)) {
+            plan.destination_files -= 1;
+            continue;
+        }
+        // Directories the source doesn't have go too, once they are empty.
+        for dir in Path::new(&path).ancestors().skip(1) {
+            let dir = utils::slash_path(dir);
+            if dir.is_empty() || source_dirs.contains(&dir) {
+                break;
+            }
+            dirs.insert(dir);
+        }
+        plan.files.push(Entry { path, ..entry });
+    }
+    // Deepest first, so a directory is emptied of its subdirectories before it is tried.
+    let mut dirs: Vec<String> = dirs.into_iter().collect();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.matches('/').count()));
+    plan.dirs = dirs;
+    if opts.detect_renames {
+        detect_renames(src, dst, &mut plan, &arrivals, opts)?;
+    }
+    Ok(plan)
+}
+
+/// `--detect-renames`: move from `plan.files` to `plan.renames` each file that is the
+/// only one of its size both there and among `arrivals`, the source files the
+/// destination lacks, and whose SHA-256 matches its counterpart's. Empty files, and
+/// files that can't be read on either side, are left to be deleted and copied.
+fn detect_renames(
+    src: &Path,
+    dst: &ProtocolPath,
+    plan: &mut Plan,
+    arrivals: &[(String, u64)],
+    opts: &CopyOptions,
+) -> Result<(), CopyError> {
+    let mut sizes: HashMap<u64, (Vec<usize>, Vec<usize>)> = HashMap::new();
+    for (i, entry) in plan.files.iter().enumerate() {
+        sizes.entry(entry.size).or_default().0.push(i);
+    }
+    for (i, (_, size)) in arrivals.iter().enumerate() {
+        sizes.entry(*size).or_default().1.push(i);
+    }
+    let candidates: Vec<(usize, usize)> = sizes
+        .into_iter()
+        .filter(|(size, _)| *size > 0)
+        .filter_map(|(_, sides)| match sides {
+            (gone, new) if gone.len() == 1 && new.len() == 1 => Some((gone[0], new[0])),
+            _ => None,
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let gone: Vec<&str> = candidates
+        .iter()
+        .map(|&(i, _)| plan.files[i].path.as_str())
+        .collect();
+    let digests: HashMap<String, String> = match dst {
+        ProtocolPath::Local(local) => gone
+            .iter()
+            .filter_map(|path| {
+                let digest = checksum::hash_file(&local.as_path().join(path), Algorithm::Sha256);
+                Some((path.to_string(), digest.ok()?))
+            })
+            .collect(),
+        ProtocolPath::Remote(remote) => remote::ssh_sha256(remote, &gone, &opts.ssh_opts)?,
+    };
+
+    let mut renamed = HashSet::new();
+    for (i, j) in candidates {
+        let (from, (to, _)) = (&plan.files[i].path, &arrivals[j]);
+        let Some(digest) = digests.get(from) else {
+            continue;
+        };
+        match checksum::hash_file(&src.join(to), Algorithm::Sha256) {
+            Ok(source) if source.eq_ignore_ascii_case(digest) => {
+                plan.renames.push((from.clone(), to.clone()));
+                renamed.insert(i);
+            }
+            _ => {}
+        }
+    }
+    let mut i = 0;
+    plan.files.retain(|_| {
+        i += 1;
+        !renamed.contains(&(i - 1))
+    });
+    plan.renames.sort();
+    Ok(())
+}
+
+/// Make the moves `--detect-renames` found in `plan` at `dst`, counting them in `stats`.
+pub fn rename(
+    plan: &Plan,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    if plan.renames.is_empty() {
+        return Ok(());
+    }
+    if opts.dry_run {
+        for (from, to) in &plan.renames {
+            println!("Would move {} -> {}", from, to);
+        }
+        stats.files_renamed += plan.renames.len();
+        return Ok(());
+    }
+    if opts.verbose {
+        for (from, to) in &plan.renames {
+            println!("Moving: {} -> {}", from, to);
+        }
+    }
+    match dst {
+        ProtocolPath::Local(local) => {
+            let root = local.as_path();
+            for (from, to) in &plan.renames {
+                let (from, to) = (root.join(from), root.join(to));
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent).map_err(|e| CopyError::IoError {
+                        message: format!("Failed to create directory {}", parent.display()),
+                        error: e,
+                    })?;
+                }
+                fs::rename(&from, &to).map_err(|e| CopyError::IoError {
+                    message: format!("Failed to move {} to {}", from.display(), to.display()),
+                    error: e,
+                })?;
+                stats.files_renamed += 1;
+            }
+        }
+        ProtocolPath::Remote(remote) => {
+            let pairs: Vec<&str> = plan
+                .renames
+                .iter()
+                .flat_map(|(from, to)| [from.as_str(), to.as_str()])
+                .collect();
+            remote::ssh_xargs(
+                remote,
+                "-n 2 sh -c 'mkdir -p -- \"$(dirname -- \"$2\")\" && mv -- \"$1\" \"$2\"' sh",
+                &pairs,
+                &opts.ssh_opts,
+                &format!("Failed to move files on {}", remote.url),
+            )?;
+            stats.files_renamed += plan.renames.len();
+        }
+    }
+    Ok(())
+}
+
+/// Carry out `plan` at `dst`, counting what was removed in `stats`.
+pub fn apply(
+    plan: &Plan,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    // Directories emptied by `--detect-renames` moves go too.
+    if plan.files.is_empty() && plan.dirs.is_empty() {
+        return Ok(());
+    }
+    if opts.dry_run {
+        for entry in &plan.files {
+            println!("Would delete {}", entry.path);
+        }
+        stats.files_deleted += plan.files.len();
+        stats.bytes_deleted += plan.bytes();
+        return Ok(());
+    }
+    if opts.verbose {
+        for entry in &plan.files {
+            println!("Deleting: {}", entry.path);
+        }
+    }
+    match dst {
+        ProtocolPath::Local(local) => {
+            let root = local.as_path();
+            for entry in &plan.files {
+                let path = root.join(&entry.path);
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(CopyError::IoError {
+                            message: format!("Failed to delete {}", path.display()),
+                            error: e,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+            for dir in &plan.dirs {
+                // Not empty: something the filters protect is still in it.
+                let _ = fs::remove_dir(root.join(dir));
+            }
+        }
+        ProtocolPath::Remote(remote)
+            if matches!(remote.protocol, Protocol::Ssh | Protocol::Sftp) =>
+        {
+            let files: Vec<&str> = plan.files.iter().map(|e| e.path.as_str()).collect();
+            ssh_remove(remote, "rm -f --", &files, opts).map_err(CopyError::from)?;
+            let dirs: Vec<&str> = plan.dirs.iter().map(String::as_str).collect();
+            if let Err(e) = ssh_remove(remote, "rmdir --", &dirs, opts) {
+                if opts.verbose {
+                    output::warn(format_args!("could not remove empty directories: {}", e));
+                }
+            }
+        }
+        ProtocolPath::Remote(remote) => {
+            return Err(CopyError::UnsupportedProtocol(format!(
+                "--delete doesn't support {} destinations",
+                remote.protocol
+            )))
+        }
+    }
+    stats.files_deleted += plan.files.len();
+    stats.bytes_deleted += plan.bytes();
+    Ok(())
+}
+
+/// The directories below `dst` with no files below them, relative and `/`-separated,
+/// deepest first. Directories the filters exclude, and those below them, are kept.
+pub fn empty_dirs(dst: &ProtocolPath, opts: &CopyOptions) -> Result<Vec<String>, CopyError> {
+    let (dirs, files) = match dst {
+        ProtocolPath::Local(local) if !local.as_path().is_dir() => return Ok(Vec::new()),
+        ProtocolPath::Local(local) => {
+            let (mut dirs, mut files) = (Vec::new(), Vec::new());
+            list_local(local.as_path(), Path::new(""), &mut dirs, &mut files).map_err(|e| {
+                CopyError::IoError {
+                    message: format!("Failed to read {}", local.as_path().display()),
+                    error: e,
+                }
+            })?;
+            (dirs, files)
+        }
+        ProtocolPath::Remote(remote)
+            if matches!(remote.protocol, Protocol::Ssh | Protocol::Sftp) =>
+        {
+            // Directories, an empty name, then everything else.
+            let listing = remote::ssh_output(
+                remote,
+                &format!(
+                    "cd {} && find . -mindepth 1 -type d -print0 && printf '\\0' && \
+                     find . -mindepth 1 ! -type d -print0",
+                    remote::shell_quote(&remote.path)
+                ),
+                &opts.ssh_opts,
+            )?;
+            let mut names = listing
+                .split('\0')
+                .map(|p| p.trim_start_matches("./").to_string());
+            let dirs = names.by_ref().take_while(|p| !p.is_empty()).collect();
+            (dirs, names.filter(|p| !p.is_empty()).collect())
+        }
+        // S3 and HTTP have no directories, only prefixes of the files.
+        ProtocolPath::Remote(_) => return Ok(Vec::new()),
+    };
+
+    let mut occupied = HashSet::new();
+    for file in &files {
+        for dir in Path::new(file).ancestors().skip(1) {
+            if !occupied.insert(utils::slash_path(dir)) {
+                break;
+            }
+        }
+    }
+    let filters = opts.filters.without_debug();
+    let mut dirs: Vec<String> = dirs;
+    // Parents first, so a directory below an excluded one is known to be kept.
+    dirs.sort();
+    let mut protected: HashSet<String> = HashSet::new();
+    let mut empty = Vec::new();
+    for dir in dirs {
+        let path = Path::new(&dir);
+        let parent = path.parent().map(utils::slash_path).unwrap_or_default();
+        if protected.contains(&parent) || !filters.is_included(path, true) {
+            // Kept, and so are the directories it is in.
+            for ancestor in path.ancestors().skip(1) {
+                occupied.insert(utils::slash_path(ancestor));
+            }
+            protected.insert(dir);
+        } else if !occupied.contains(&dir) {
+            empty.push(dir);
+        }
+    }
+    empty.retain(|dir| !occupied.contains(dir));
+    empty.sort_by_key(|d| std::cmp::Reverse(d.matches('/').count()));
+    Ok(empty)
+}
+
+/// `--prune-empty-dirs`: remove the [`empty_dirs`] of `dst`, counting them in `stats`.
+pub fn prune(
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let dirs = empty_dirs(dst, opts)?;
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    if opts.dry_run {
+        for dir in &dirs {
+            println!("Would prune {}/", dir);
+        }
+        stats.dirs_pruned += dirs.len();
+        return Ok(());
+    }
+    if opts.verbose {
+        for dir in &dirs {
+            println!("Pruning: {}/", dir);
+        }
+    }
+    match dst {
+        ProtocolPath::Local(local) => {
+            for dir in &dirs {
+                let path = local.as_path().join(dir);
+                match fs::remove_dir(&path) {
+                    Ok(()) => stats.dirs_pruned += 1,
+                    // Something was written into it since it was listed.
+                    Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(CopyError::IoError {
+                            message: format!("Failed to remove {}", path.display()),
+                            error: e,
+                        })
+                    }
+                }
+            }
+        }
+        ProtocolPath::Remote(remote) => {
+            let paths: Vec<&str> = dirs.iter().map(String::as_str).collect();
+            match ssh_remove(remote, "rmdir --", &paths, opts) {
+                Ok(()) => stats.dirs_pruned += dirs.len(),
+                Err(e) => output::warn(format_args!("could not prune empty directories: {}", e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add the directories and other entries below `dir` (at `rel` of the destination) to
+/// `dirs` and `files`, without following symlinks.
+fn list_local(
+    dir: &Path,
+    rel: &Path,
+    dirs: &mut Vec<String>,
+    files: &mut Vec<String>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            dirs.push(utils::slash_path(&rel));
+            list_local(&entry.path(), &rel, dirs, files)?;
+        } else {
+            files.push(utils::slash_path(&rel));
+        }
+    }
+    Ok(())
+}
+
+/// Remove `paths`, relative to the destination directory of `remote`, with `command`
+/// (`rm -f --`, `rmdir --`) over a single ssh connection.
+fn ssh_remove(
+    remote: &RemotePath,
+    command: &str,
+    paths: &[&str],
+    opts: &CopyOptions,
+) -> Result<(), RemoteCopyError> {
+    let failure = format!("Failed to delete files on {}", remote.url);
+    remote::ssh_xargs(remote, command, paths, &opts.ssh_opts, &failure).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use tempfile::TempDir;
+
+    fn plan_of(files: usize, source_files: usize, destination_files: usize) -> Plan {
+        let entry = Entry {
+            path: "gone".to_string(),
+            size: 1,
+            modified: None,
+        };
+        Plan {
+            files: vec![entry; files],
+            source_files,
+            destination_files,
+            ..Plan::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_ratio() {
+        assert_eq!(parse_ratio("0.25"), Ok(0.25));
+        assert_eq!(parse_ratio("30%"), Ok(0.3));
+        assert_eq!(parse_ratio("100%"), Ok(1.0));
+        assert!(parse_ratio("1.5").is_err());
+        assert!(parse_ratio("-10%").is_err());
+        assert!(parse_ratio("half").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut opts = CopyOptions::default();
+        assert!(plan_of(2, 8, 10).check(&opts).is_ok());
+        assert!(plan_of(5, 5, 10).check(&opts).is_ok());
+        let refused = plan_of(6, 4, 10).check(&opts).unwrap_err().to_string();
+        assert!(
+            refused.contains("6 of the 10 files at the destination (60%)"),
+            "{}",
+            refused
+        );
+        assert!(refused.contains("--max-delete-ratio 50%"), "{}", refused);
+
+        opts.max_delete_ratio = 0.1;
+        assert!(plan_of(2, 8, 10).check(&opts).is_err());
+        opts.force_delete = true;
+        assert!(plan_of(6, 4, 10).check(&opts).is_ok());
+        // Nothing overrides an empty source.
+        let refused = plan_of(3, 0, 3).check(&opts).unwrap_err().to_string();
+        assert!(refused.contains("the source has no files"), "{}", refused);
+        assert!(plan_of(0, 0, 0).check(&opts).is_ok());
+    }
+
+    #[test]
+    fn test_refusal_suggestions() {
+        let opts = CopyOptions::default();
+        let ratio = plan_of(6, 4, 10).check(&opts).unwrap_err().to_string();
+        assert!(ratio.contains("pass --force-delete only if"), "{}", ratio);
+        // --force-delete wouldn't help, so it isn't suggested.
+        let empty = plan_of(3, 0, 3).check(&opts).unwrap_err().to_string();
+        assert!(!empty.contains("pass --force-delete"), "{}", empty);
+        assert!(empty.contains("even with --force-delete"), "{}", empty);
+    }
+
+    #[test]
+    fn test_plan_and_apply() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(src.join("keep")).unwrap();
+        fs::create_dir_all(dst.join("keep")).unwrap();
+        fs::create_dir_all(dst.join("old/deeper")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("keep/b.txt"), "b").unwrap();
+        fs::write(dst.join("a.txt"), "a").unwrap();
+        fs::write(dst.join("keep/stale.txt"), "stale").unwrap();
+        fs::write(dst.join("old/deeper/c.txt"), "c").unwrap();
+        fs::write(dst.join("notes.log"), "log").unwrap();
+
+        let mut opts = CopyOptions::default();
+        opts.filters.push(crate::filter::PatternFilter::new(
+            &[],
+            &["*.log".to_string()],
+        ));
+        let dst_path = parse_path(dst.to_str().unwrap()).unwrap();
+        let plan = plan(&src, &dst_path, &opts).unwrap();
+        let mut files: Vec<_> = plan.files.iter().map(|e| e.path.as_str()).collect();
+        files.sort();
+        assert_eq!(files, ["keep/stale.txt", "old/deeper/c.txt"]);
+        assert_eq!(plan.dirs, ["old/deeper", "old"]);
+        assert_eq!((plan.source_files, plan.destination_files), (2, 3));
+
+        let mut stats = CopyStats::new();
+        apply(&plan, &dst_path, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_deleted, stats.bytes_deleted), (2, 6));
+        assert!(!dst.join("keep/stale.txt").exists());
+        assert!(dst.join("keep").is_dir());
+        assert!(!dst.join("old").exists());
+        // Excluded, so protected.
+        assert!(dst.join("notes.log").exists());
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        let photo: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        let mut edited = photo.clone();
+        edited[10] ^= 1;
+        for (dir, path, data) in [
+            (&dst, "2023/summer/beach.jpg", &photo[..]),
+            (&src, "albums/beach/beach.jpg", &photo[..]),
+            // Same size, different bytes: not a move.
+            (&dst, "2023/edited.jpg", &edited[..100]),
+            (&src, "albums/edited.jpg", &photo[..100]),
+            // Two files of one size: ambiguous, so left alone.
+            (&dst, "2023/a.txt", b"aaaa"),
+            (&dst, "2023/b.txt", b"bbbb"),
+            (&src, "albums/a.txt", b"aaaa"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        let opts = CopyOptions {
+            delete: true,
+            detect_renames: true,
+            ..CopyOptions::default()
+        };
+        let dst_path = parse_path(dst.to_str().unwrap()).unwrap();
+        let plan = plan(&src, &dst_path, &opts).unwrap();
+        assert_eq!(
+            plan.renames,
+            [(
+                "2023/summer/beach.jpg".to_string(),
+                "albums/beach/beach.jpg".to_string()
+            )]
+        );
+        let mut files: Vec<_> = plan.files.iter().map(|e| e.path.as_str()).collect();
+        files.sort();
+        assert_eq!(files, ["2023/a.txt", "2023/b.txt", "2023/edited.jpg"]);
+
+        let mut stats = CopyStats::new();
+        rename(&plan, &dst_path, &opts, &mut stats).unwrap();
+        apply(&plan, &dst_path, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_renamed, stats.files_deleted), (1, 3));
+        assert_eq!(fs::read(dst.join("albums/beach/beach.jpg")).unwrap(), photo);
+        // The directories the move emptied went with the deleted files.
+        assert!(!dst.join("2023").exists());
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let temp = TempDir::new().unwrap();
+        let dst = temp.path().join("dst");
+        for dir in ["skeleton/a/b", "kept/empty", "cache/x", "data/raw"] {
+            fs::create_dir_all(dst.join(dir)).unwrap();
+        }
+        fs::write(dst.join("kept/file.txt"), "file").unwrap();
+        fs::write(dst.join("data/raw/.keep"), "").unwrap();
+
+        let mut opts = CopyOptions::default();
+        opts.filters.push(crate::filter::PatternFilter::new(
+            &[],
+            &["cache".to_string()],
+        ));
+        let dst_path = parse_path(dst.to_str().unwrap()).unwrap();
+        let dirs = empty_dirs(&dst_path, &opts).unwrap();
+        assert_eq!(dirs[0], "skeleton/a/b");
+        assert_eq!(dirs[3], "skeleton");
+        let mut sorted = dirs.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            ["kept/empty", "skeleton", "skeleton/a", "skeleton/a/b"]
+        );
+
+        let mut stats = CopyStats::new();
+        prune(&dst_path, &opts, &mut stats).unwrap();
+        assert_eq!(stats.dirs_pruned, 4);
+        assert!(!dst.join("skeleton").exists());
+        assert!(dst.join("kept/file.txt").exists() && !dst.join("kept/empty").exists());
+        assert!(dst.join("data/raw/.keep").exists());
+        // Excluded, so kept with everything below it.
+        assert!(dst.join("cache/x").is_dir());
+
+        // The destination itself stays, however empty.
+        let bare = temp.path().join("bare");
+        fs::create_dir(&bare).unwrap();
+        let bare_path = parse_path(bare.to_str().unwrap()).unwrap();
+        prune(&bare_path, &opts, &mut CopyStats::new()).unwrap();
+        assert!(bare.is_dir());
+    }
+}
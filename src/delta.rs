@@ -0,0 +1,759 @@
+//! `--delta`: update an existing destination file by sending only what changed, using
+//! the rsync algorithm.
+//!
+//! The side holding the old file (the *basis*) cuts it into fixed-size blocks and sends a
+//! [`Signature`]: a rolling checksum and an MD5 per block. The side holding the new file
+//! slides a window over it, and wherever the rolling checksum and then the MD5 match a
+//! basis block it emits a block reference instead of the bytes. The basis side rebuilds
+//! the file from those references plus the literal bytes into a temporary file next to
+//! it and renames it into place, so readers never see a half-written file.
+//!
+//! Over SSH the remote half runs as `usync delta-server <signature|delta|patch> PATH`,
+//! so `usync` has to be installed on the remote host; without it the copy falls back to
+//! sending the whole file.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use md5::{Digest, Md5};
+
+use crate::protocol::RemotePath;
+use crate::remote::{self, RemoteCopyError};
+use crate::utils;
+
+/// Files smaller than this are always copied whole.
+pub const MIN_FILE_SIZE: u64 = 64 * 1024;
+
+const SIGNATURE_MAGIC: &[u8; 4] = b"USIG";
+const DELTA_MAGIC: &[u8; 4] = b"UDLT";
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_LITERAL: u8 = 2;
+/// Literal runs are flushed in pieces of at most this size.
+const LITERAL_CHUNK: usize = 64 * 1024;
+const READ_CHUNK: usize = 256 * 1024;
+
+/// Block size for a basis of `len` bytes: about its square root, like rsync, so the
+/// signature stays small for large files without making small edits expensive.
+pub fn block_size_for(len: u64) -> u32 {
+    let root = (len as f64).sqrt() as u32;
+    (root.clamp(700, 128 * 1024) + 7) & !7
+}
+
+/// rsync's rolling checksum over one window.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    /// Slide the window one byte: drop `out` from the front, append `incoming`.
+    fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(out as u32)
+            .wrapping_add(incoming as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    Md5::digest(block).into()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockSignature {
+    weak: u32,
+    strong: [u8; 16],
+}
+
+/// Per-block checksums of the basis file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    block_size: u32,
+    len: u64,
+    blocks: Vec<BlockSignature>,
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl Signature {
+    /// Checksum every block of `basis`, which is `len` bytes long.
+    pub fn of(basis: impl Read, len: u64) -> io::Result<Self> {
+        let block_size = block_size_for(len);
+        let mut reader = BufReader::new(basis);
+        let mut buf = vec![0u8; block_size as usize];
+        let mut blocks = Vec::new();
+        loop {
+            let n = read_full(&mut reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            blocks.push(BlockSignature {
+                weak: Rolling::new(&buf[..n]).digest(),
+                strong: strong(&buf[..n]),
+            });
+            if n < buf.len() {
+                break;
+            }
+        }
+        Ok(Signature {
+            block_size,
+            len,
+            blocks,
+        })
+    }
+
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        Self::of(file, len)
+    }
+
+    /// Length of block `index`; only the last one can be short.
+    fn block_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.block_size as u64;
+        (self.len - start).min(self.block_size as u64) as usize
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(SIGNATURE_MAGIC)?;
+        out.write_all(&self.block_size.to_le_bytes())?;
+        out.write_all(&self.len.to_le_bytes())?;
+        out.write_all(&(self.blocks.len() as u64).to_le_bytes())?;
+        for block in &self.blocks {
+            out.write_all(&block.weak.to_le_bytes())?;
+            out.write_all(&block.strong)?;
+        }
+        out.flush()
+    }
+
+    pub fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != SIGNATURE_MAGIC {
+            return Err(invalid("not a delta signature"));
+        }
+        let block_size = read_u32(input)?;
+        let len = read_u64(input)?;
+        let count = read_u64(input)?;
+        if block_size == 0 || count != len.div_ceil(block_size as u64) {
+            return Err(invalid("inconsistent delta signature"));
+        }
+        let mut blocks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let weak = read_u32(input)?;
+            let mut strong = [0u8; 16];
+            input.read_exact(&mut strong)?;
+            blocks.push(BlockSignature { weak, strong });
+        }
+        Ok(Signature {
+            block_size,
+            len,
+            blocks,
+        })
+    }
+}
+
+/// Read until `buf` is full or the input ends; returns the bytes read.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// How much of a file was reused from the basis and how much had to be sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub matched: u64,
+    pub literal: u64,
+}
+
+impl DeltaStats {
+    /// Whether the delta saves enough over sending the file whole (at least a tenth).
+    pub fn worthwhile(&self) -> bool {
+        self.matched > 0 && self.matched * 10 >= self.matched + self.literal
+    }
+}
+
+/// Writes delta operations, merging adjacent block references.
+struct DeltaWriter<W: Write> {
+    out: W,
+    /// Pending run of consecutive basis blocks: (first block, count).
+    copy: Option<(u64, u32)>,
+    hasher: Md5,
+    stats: DeltaStats,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    fn flush_copy(&mut self) -> io::Result<()> {
+        if let Some((start, count)) = self.copy.take() {
+            self.out.write_all(&[OP_COPY])?;
+            self.out.write_all(&start.to_le_bytes())?;
+            self.out.write_all(&count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, block: u64, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.stats.matched += data.len() as u64;
+        match &mut self.copy {
+            Some((start, count)) if *start + *count as u64 == block => *count += 1,
+            _ => {
+                self.flush_copy()?;
+                self.copy = Some((block, 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        self.hasher.update(data);
+        self.stats.literal += data.len() as u64;
+        self.out.write_all(&[OP_LITERAL])?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(data)
+    }
+
+    fn finish(mut self) -> io::Result<DeltaStats> {
+        self.flush_copy()?;
+        self.out.write_all(&[OP_END])?;
+        let digest: [u8; 16] = self.hasher.finalize().into();
+        self.out.write_all(&digest)?;
+        self.out.flush()?;
+        Ok(self.stats)
+    }
+}
+
+/// Describe `source` in terms of the basis `signature`, writing the operations to `out`.
+fn write_ops(signature: &Signature, source: impl Read, out: impl Write) -> io::Result<DeltaStats> {
+    let block_size = signature.block_size as usize;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    let mut tail = None;
+    for (i, block) in signature.blocks.iter().enumerate() {
+        if signature.block_len(i) == block_size {
+            index.entry(block.weak).or_default().push(i);
+        } else {
+            tail = Some(i);
+        }
+    }
+
+    let mut source = source;
+    let mut writer = DeltaWriter {
+        out: BufWriter::new(out),
+        copy: None,
+        hasher: Md5::new(),
+        stats: DeltaStats::default(),
+    };
+    let mut buf: Vec<u8> = Vec::new();
+    let (mut pos, mut literal_start) = (0usize, 0usize);
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // Keep one byte beyond the window so it can roll.
+        while !eof && buf.len() - pos <= block_size {
+            let old_len = buf.len();
+            buf.resize(old_len + READ_CHUNK, 0);
+            let n = read_full(&mut source, &mut buf[old_len..])?;
+            buf.truncate(old_len + n);
+            eof = n == 0;
+        }
+        if buf.len() - pos < block_size {
+            break;
+        }
+
+        let window = &buf[pos..pos + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = index.get(&weak).and_then(|candidates| {
+            let digest = strong(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|&i| signature.blocks[i].strong == digest)
+        });
+
+        if let Some(block) = matched {
+            writer.literal(&buf[literal_start..pos])?;
+            writer.copy(block as u64, &buf[pos..pos + block_size])?;
+            pos += block_size;
+            literal_start = pos;
+            rolling = None;
+        } else {
+            if let (Some(r), Some(&incoming)) = (rolling.as_mut(), buf.get(pos + block_size)) {
+                r.roll(buf[pos], incoming);
+            } else {
+                rolling = None;
+            }
+            pos += 1;
+            if pos - literal_start >= LITERAL_CHUNK {
+                writer.literal(&buf[literal_start..pos])?;
+                literal_start = pos;
+            }
+        }
+
+        if literal_start >= 4 * READ_CHUNK {
+            buf.drain(..literal_start);
+            pos -= literal_start;
+            literal_start = 0;
+        }
+    }
+
+    // What's left is shorter than a block. Its end can still be the basis's short last
+    // block, which only ever matches at the end of the file.
+    let tail_start = tail
+        .map(|i| (i, buf.len().saturating_sub(signature.block_len(i))))
+        .filter(|&(i, start)| start >= pos && signature.blocks[i].strong == strong(&buf[start..]));
+    match tail_start {
+        Some((i, start)) => {
+            writer.literal(&buf[literal_start..start])?;
+            writer.copy(i as u64, &buf[start..])?;
+        }
+        None => writer.literal(&buf[literal_start..])?,
+    }
+    writer.finish()
+}
+
+/// Rebuild the new file from `basis` and a delta, writing it to `out`. Fails if the
+/// result doesn't hash to what the sender saw.
+pub fn apply_delta(
+    mut basis: impl Read + Seek,
+    delta: impl Read,
+    out: impl Write,
+) -> io::Result<DeltaStats> {
+    let mut delta = BufReader::new(delta);
+    let mut out = BufWriter::new(out);
+    let mut magic = [0u8; 4];
+    delta.read_exact(&mut magic)?;
+    if &magic != DELTA_MAGIC {
+        return Err(invalid("not a delta"));
+    }
+    let block_size = read_u32(&mut delta)? as u64;
+
+    let mut hasher = Md5::new();
+    let mut stats = DeltaStats::default();
+    let mut buf = vec![0u8; READ_CHUNK];
+    loop {
+        let mut op = [0u8; 1];
+        delta.read_exact(&mut op)?;
+        match op[0] {
+            OP_COPY => {
+                let start = read_u64(&mut delta)?;
+                let count = read_u32(&mut delta)? as u64;
+                basis.seek(SeekFrom::Start(start * block_size))?;
+                let mut remaining = count * block_size;
+                while remaining > 0 {
+                    let want = remaining.min(buf.len() as u64) as usize;
+                    let n = read_full(&mut basis, &mut buf[..want])?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                    out.write_all(&buf[..n])?;
+                    stats.matched += n as u64;
+                    remaining -= n as u64;
+                }
+            }
+            OP_LITERAL => {
+                let len = read_u32(&mut delta)? as usize;
+                let mut data = vec![0u8; len];
+                delta.read_exact(&mut data)?;
+                hasher.update(&data);
+                out.write_all(&data)?;
+                stats.literal += len as u64;
+            }
+            OP_END => {
+                let mut expected = [0u8; 16];
+                delta.read_exact(&mut expected)?;
+                let actual: [u8; 16] = hasher.finalize().into();
+                if actual != expected {
+                    return Err(invalid("delta result does not match the source checksum"));
+                }
+                out.flush()?;
+                return Ok(stats);
+            }
+            _ => return Err(invalid("unknown delta operation")),
+        }
+    }
+}
+
+/// Describe `source` in terms of the basis `signature`, writing the delta to `out`: a
+/// header with the block size the delta refers to, then the operations.
+pub fn write_delta(
+    signature: &Signature,
+    source: impl Read,
+    mut out: impl Write,
+) -> io::Result<DeltaStats> {
+    out.write_all(DELTA_MAGIC)?;
+    out.write_all(&signature.block_size.to_le_bytes())?;
+    write_ops(signature, source, out)
+}
+
+/// Replace `path` with the result of applying `delta` to it, via a temporary file in the
+/// same directory that is renamed over it. The old file's permissions are kept.
+pub fn patch_file(path: &Path, delta: impl Read) -> io::Result<DeltaStats> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let basis = fs::File::open(path)?;
+    let permissions = basis.metadata()?.permissions();
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    let stats = apply_delta(basis, delta, temp.as_file_mut())?;
+    temp.as_file().sync_all()?;
+    fs::set_permissions(temp.path(), permissions)?;
+    temp.persist(path).map_err(|e| e.error)?;
+    Ok(stats)
+}
+
+/// Update the local file `dst` to match `src`. Returns `None` without touching `dst`
+/// when a delta isn't worth it (small or very different files).
+pub fn update_local(
+    src: &Path,
+    dst: &Path,
+    tmpdir: Option<&Path>,
+) -> io::Result<Option<DeltaStats>> {
+    let src_len = fs::metadata(src)?.len();
+    if src_len < MIN_FILE_SIZE || !dst.is_file() {
+        return Ok(None);
+    }
+    let signature = Signature::of_file(dst)?;
+    let mut delta = utils::scratch_file(tmpdir)?;
+    let stats = write_delta(&signature, fs::File::open(src)?, &mut delta)?;
+    if !stats.worthwhile() {
+        return Ok(None);
+    }
+    delta.seek(SeekFrom::Start(0))?;
+    patch_file(dst, delta)
+        .map(Some)
+        .map_err(|e| io::Error::new(e.kind(), format!("applying delta: {}", e)))
+}
+
+fn remote_command(
+    remote: &RemotePath,
+    mode: &str,
+    ssh_opts: &[String],
+) -> Result<Command, RemoteCopyError> {
+    let mut cmd = remote::ssh_command(remote, ssh_opts)?;
+    cmd.arg(format!(
+        "usync delta-server {} {}",
+        mode,
+        remote::shell_quote(&remote.path)
+    ));
+    Ok(cmd)
+}
+
+fn ssh_error(message: &str, error: impl ToString) -> RemoteCopyError {
+    RemoteCopyError::IoError {
+        message: message.to_string(),
+        error: error.to_string(),
+    }
+}
+
+/// Update the remote file `dst` to match the local `src`. Returns `None` when the caller
+/// should upload the whole file instead: the remote file is missing, `usync` isn't
+/// installed there, or the delta wouldn't save much.
+pub fn upload_ssh(
+    src: &Path,
+    dst: &RemotePath,
+    ssh_opts: &[String],
+    tmpdir: Option<&Path>,
+) -> Result<Option<DeltaStats>, RemoteCopyError> {
+    let src_len = fs::metadata(src)
+        .map_err(|e| ssh_error("Failed to read source file", e))?
+        .len();
+    if src_len < MIN_FILE_SIZE {
+        return Ok(None);
+    }
+
+    let output = remote_command(dst, "signature", ssh_opts)?
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| ssh_error("Failed to execute ssh", e))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let signature = Signature::read_from(&mut output.stdout.as_slice())
+        .map_err(|e| ssh_error("Invalid signature from remote usync", e))?;
+
+    let mut delta =
+        utils::scratch_file(tmpdir).map_err(|e| ssh_error("Failed to create temp file", e))?;
+    let source = fs::File::open(src).map_err(|e| ssh_error("Failed to read source file", e))?;
+    let stats = write_delta(&signature, source, &mut delta)
+        .map_err(|e| ssh_error("Failed to compute delta", e))?;
+    if !stats.worthwhile() {
+        return Ok(None);
+    }
+    delta
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| ssh_error("Failed to read delta", e))?;
+
+    let status = remote_command(dst, "patch", ssh_opts)?
+        .stdin(Stdio::from(delta))
+        .status()
+        .map_err(|e| ssh_error("Failed to execute ssh", e))?;
+    if !status.success() {
+        return Err(ssh_error(
+            "Remote usync failed to apply the delta",
+            format!("Exit code: {}", status.code().unwrap_or(-1)),
+        ));
+    }
+    Ok(Some(stats))
+}
+
+/// Update the local file `dst` to match the remote `src`. Returns `None` when the caller
+/// should download the whole file instead (no local file yet, or no `usync` remotely).
+pub fn download_ssh(
+    src: &RemotePath,
+    dst: &Path,
+    ssh_opts: &[String],
+    tmpdir: Option<&Path>,
+) -> Result<Option<DeltaStats>, RemoteCopyError> {
+    if !dst.is_file() {
+        return Ok(None);
+    }
+    let mut signature = Vec::new();
+    Signature::of_file(dst)
+        .and_then(|s| s.write_to(&mut signature))
+        .map_err(|e| ssh_error("Failed to read destination file", e))?;
+
+    let mut delta =
+        utils::scratch_file(tmpdir).map_err(|e| ssh_error("Failed to create temp file", e))?;
+    let mut child = remote_command(src, "delta", ssh_opts)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(
+            delta
+                .try_clone()
+                .map_err(|e| ssh_error("Failed to create temp file", e))?,
+        ))
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| ssh_error("Failed to execute ssh", e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(&signature));
+    let status = child
+        .wait()
+        .map_err(|e| ssh_error("Failed to execute ssh", e))?;
+    // A remote that exits early (no usync) closes the pipe; that's reported by `status`.
+    let _ = writer.join();
+    if !status.success() {
+        return Ok(None);
+    }
+
+    delta
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| ssh_error("Failed to read delta", e))?;
+    patch_file(dst, delta)
+        .map(Some)
+        .map_err(|e| ssh_error("Failed to apply delta", e))
+}
+
+/// `usync delta-server MODE PATH`, the remote half of an SSH delta transfer:
+/// `signature` prints the signature of PATH, `delta` reads a signature on stdin and
+/// prints the delta that turns it into PATH, and `patch` applies a delta read on stdin
+/// to PATH. Returns the process exit code.
+pub fn serve(args: &[String]) -> i32 {
+    let result = match args {
+        [mode, path] if mode == "signature" => {
+            Signature::of_file(Path::new(path)).and_then(|s| s.write_to(&mut io::stdout().lock()))
+        }
+        [mode, path] if mode == "delta" => Signature::read_from(&mut io::stdin().lock())
+            .and_then(|signature| {
+                let source = fs::File::open(path)?;
+                write_delta(&signature, source, io::stdout().lock())
+            })
+            .map(|_| ()),
+        [mode, path] if mode == "patch" => {
+            patch_file(Path::new(path), io::stdin().lock()).map(|_| ())
+        }
+        _ => {
+            eprintln!("usage: usync delta-server <signature|delta|patch> PATH");
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("usync delta-server: {}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Deterministic pseudo-random bytes, so rolling checksums don't collide by design.
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(old: &[u8], new: &[u8]) -> (Vec<u8>, DeltaStats) {
+        let signature = Signature::of(old, old.len() as u64).unwrap();
+        let mut encoded = Vec::new();
+        signature.write_to(&mut encoded).unwrap();
+        let signature = Signature::read_from(&mut encoded.as_slice()).unwrap();
+
+        let mut delta = Vec::new();
+        let sent = write_delta(&signature, new, &mut delta).unwrap();
+        let mut rebuilt = Vec::new();
+        let applied = apply_delta(io::Cursor::new(old), delta.as_slice(), &mut rebuilt).unwrap();
+        assert_eq!(sent, applied);
+        (rebuilt, applied)
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let bytes = data(300, 1);
+        let mut rolling = Rolling::new(&bytes[0..100]);
+        for start in 1..200 {
+            rolling.roll(bytes[start - 1], bytes[start + 99]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&bytes[start..start + 100]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trips_edits() {
+        let old = data(200_000, 7);
+        let block = block_size_for(old.len() as u64) as u64;
+
+        // Unchanged file: everything is matched.
+        let (rebuilt, stats) = round_trip(&old, &old);
+        assert_eq!(rebuilt, old);
+        assert_eq!(stats.literal, 0);
+
+        // Bytes inserted near the front and a block rewritten in the middle.
+        let mut new = old.clone();
+        new.splice(1000..1000, b"inserted".iter().copied());
+        new[100_000..100_100].copy_from_slice(&data(100, 9));
+        new.extend_from_slice(b"appended tail");
+        let (rebuilt, stats) = round_trip(&old, &new);
+        assert_eq!(rebuilt, new);
+        assert_eq!(stats.matched + stats.literal, new.len() as u64);
+        assert!(stats.literal < 4 * block + 100, "{:?}", stats);
+        assert!(stats.worthwhile());
+
+        // Nothing in common.
+        let other = data(50_000, 11);
+        let (rebuilt, stats) = round_trip(&old, &other);
+        assert_eq!(rebuilt, other);
+        assert_eq!(stats.matched, 0);
+        assert!(!stats.worthwhile());
+
+        // Empty basis and empty source.
+        assert_eq!(round_trip(&[], &new).0, new);
+        assert_eq!(round_trip(&old, &[]).0, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_short_last_block_is_matched() {
+        let old = data(10_000, 3);
+        let mut new = data(5_000, 4);
+        let block = block_size_for(old.len() as u64) as usize;
+        let tail_start = old.len() / block * block;
+        new.extend_from_slice(&old[tail_start..]);
+        let (rebuilt, stats) = round_trip(&old, &new);
+        assert_eq!(rebuilt, new);
+        assert_eq!(stats.matched, (old.len() - tail_start) as u64);
+    }
+
+    #[test]
+    fn test_corrupted_basis_is_detected() {
+        let old = data(20_000, 5);
+        let signature = Signature::of(old.as_slice(), old.len() as u64).unwrap();
+        let mut delta = Vec::new();
+        write_delta(&signature, old.as_slice(), &mut delta).unwrap();
+
+        let mut changed = old.clone();
+        changed[10] ^= 0xff;
+        let err = apply_delta(io::Cursor::new(changed), delta.as_slice(), Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_update_local_file() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("new.bin");
+        let dst = temp.path().join("old.bin");
+        let old = data(1 << 20, 21);
+        let mut new = old.clone();
+        new[500_000..500_010].copy_from_slice(b"0123456789");
+        fs::write(&dst, &old).unwrap();
+        fs::write(&src, &new).unwrap();
+
+        let stats = update_local(&src, &dst, None).unwrap().unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), new);
+        assert_eq!(stats.matched + stats.literal, new.len() as u64);
+        assert!(stats.literal <= 2 * block_size_for(old.len() as u64) as u64);
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 2);
+
+        // No destination yet, or a tiny file: copy whole.
+        assert_eq!(
+            update_local(&src, &temp.path().join("missing"), None).unwrap(),
+            None
+        );
+        fs::write(&src, "small").unwrap();
+        assert_eq!(update_local(&src, &dst, None).unwrap(), None);
+    }
+}
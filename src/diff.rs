@@ -0,0 +1,397 @@
+//! `usync diff`: how two directories differ, without copying anything.
+//!
+//! Both sides are listed as `usync list` lists them, so either can be local, on an SSH
+//! host or under an S3 prefix, and the same filters apply. A file is only in one side,
+//! or in both and different by the `--compare` rule a sync would use with A as its
+//! source: a file that such a sync would copy is different. With `--checksum`, files
+//! of the same size are hashed with SHA-256 instead of comparing times; that works for
+//! local and SSH sides.
+//!
+//! [`compare`] is the listing-only core, the same decision a sync makes per file.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::checksum::{self, Algorithm};
+use crate::copy::{Compare, CopyError};
+use crate::filter::FilterChain;
+use crate::list::{self, Entry, ListOptions};
+use crate::protocol::{Path as ProtocolPath, Protocol};
+use crate::remote;
+
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    pub filters: FilterChain,
+    /// How a file in both sides is found to be the same.
+    pub compare: Compare,
+    /// Compare the contents of files of the same size, whatever their times.
+    pub checksum: bool,
+    pub ssh_opts: Vec<String>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            filters: FilterChain::default(),
+            compare: Compare::SizeMtime,
+            checksum: false,
+            ssh_opts: Vec::new(),
+        }
+    }
+}
+
+/// Why a file in both sides differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Size,
+    /// Same size, but B is older than A.
+    Mtime,
+    Checksum,
+}
+
+impl Reason {
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::Size => "size",
+            Reason::Mtime => "mtime",
+            Reason::Checksum => "checksum",
+        }
+    }
+}
+
+/// A file in both sides that differs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changed {
+    pub a: Entry,
+    pub b: Entry,
+    pub reason: Reason,
+}
+
+/// How two directories differ; paths are relative and `/`-separated, in name order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub only_in_a: Vec<Entry>,
+    pub only_in_b: Vec<Entry>,
+    pub changed: Vec<Changed>,
+    /// Files in both sides found the same.
+    pub same: usize,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.changed.is_empty()
+    }
+
+    /// The diff as one JSON object, with `only_in_a`, `only_in_b` and `changed` lists.
+    pub fn to_json(&self) -> String {
+        let file = |entry: &Entry| {
+            format!(
+                r#"{{"size":{},"modified":{}}}"#,
+                entry.size,
+                entry
+                    .modified
+                    .map_or_else(|| "null".to_string(), |t| t.to_string())
+            )
+        };
+        let listed = |entries: &[Entry]| {
+            let items: Vec<String> = entries
+                .iter()
+                .map(|e| format!(r#"{{"path":{},"file":{}}}"#, json_string(&e.path), file(e)))
+                .collect();
+            format!("[{}]", items.join(","))
+        };
+        let changed: Vec<String> = self
+            .changed
+            .iter()
+            .map(|c| {
+                format!(
+                    r#"{{"path":{},"reason":"{}","a":{},"b":{}}}"#,
+                    json_string(&c.a.path),
+                    c.reason.name(),
+                    file(&c.a),
+                    file(&c.b)
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"only_in_a":{},"only_in_b":{},"changed":[{}],"same":{}}}"#,
+            listed(&self.only_in_a),
+            listed(&self.only_in_b),
+            changed.join(","),
+            self.same
+        )
+    }
+}
+
+/// Sort the listings `a` and `b` into a [`Diff`] by the sizes and times they hold.
+pub fn compare(mut a: Vec<Entry>, b: Vec<Entry>, rule: Compare) -> Diff {
+    let mut b: BTreeMap<String, Entry> = b.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let mut diff = Diff::default();
+    a.sort_by(|x, y| x.path.cmp(&y.path));
+    for entry in a {
+        let Some(other) = b.remove(&entry.path) else {
+            diff.only_in_a.push(entry);
+            continue;
+        };
+        let unchanged = rule.unchanged(
+            &entry.path,
+            (entry.size, entry.modified),
+            (other.size, other.modified),
+            false,
+        );
+        if unchanged {
+            diff.same += 1;
+            continue;
+        }
+        let reason = if entry.size != other.size {
+            Reason::Size
+        } else {
+            Reason::Mtime
+        };
+        diff.changed.push(Changed {
+            a: entry,
+            b: other,
+            reason,
+        });
+    }
+    diff.only_in_b = b.into_values().collect();
+    diff
+}
+
+/// List `a` and `b` and find how they differ.
+pub fn diff(a: &ProtocolPath, b: &ProtocolPath, opts: &DiffOptions) -> Result<Diff, CopyError> {
+    for side in [a, b] {
+        match side {
+            ProtocolPath::Local(local) if !local.is_dir() => {
+                return Err(CopyError::InvalidSource(format!(
+                    "{} is not a directory",
+                    local.to_string_lossy()
+                )))
+            }
+            ProtocolPath::Remote(remote)
+                if opts.checksum && !matches!(remote.protocol, Protocol::Ssh | Protocol::Sftp) =>
+            {
+                return Err(CopyError::UnsupportedProtocol(format!(
+                    "--checksum compares local and SSH directories, not {}",
+                    remote.protocol
+                )))
+            }
+            _ => {}
+        }
+    }
+    let listing = ListOptions {
+        filters: opts.filters.clone(),
+        ssh_opts: opts.ssh_opts.clone(),
+        ..ListOptions::default()
+    };
+    let listed = |side: &ProtocolPath| -> Result<Vec<Entry>, CopyError> {
+        let mut entries = list::list(side, &listing)?;
+        for entry in &mut entries {
+            if let Some(path) = entry.path.strip_prefix("./") {
+                entry.path = path.to_string();
+            }
+        }
+        Ok(entries)
+    };
+    let (a_files, b_files) = (listed(a)?, listed(b)?);
+    if !opts.checksum {
+        return Ok(compare(a_files, b_files, opts.compare));
+    }
+
+    // Same size is where the contents decide; the times don't matter.
+    let mut diff = compare(a_files.clone(), b_files.clone(), Compare::SizeOnly);
+    let b_files: BTreeMap<&str, &Entry> = b_files.iter().map(|e| (e.path.as_str(), e)).collect();
+    let both: Vec<(&Entry, &Entry)> = a_files
+        .iter()
+        .filter_map(|e| Some((e, *b_files.get(e.path.as_str())?)))
+        .filter(|(x, y)| x.size == y.size)
+        .collect();
+    let paths: Vec<&str> = both.iter().map(|(e, _)| e.path.as_str()).collect();
+    let (a_digests, b_digests) = (digests(a, &paths, opts)?, digests(b, &paths, opts)?);
+    for (x, y) in both {
+        if a_digests.get(&x.path) != b_digests.get(&y.path) {
+            diff.same -= 1;
+            diff.changed.push(Changed {
+                a: x.clone(),
+                b: y.clone(),
+                reason: Reason::Checksum,
+            });
+        }
+    }
+    diff.changed.sort_by(|x, y| x.a.path.cmp(&y.a.path));
+    Ok(diff)
+}
+
+/// The SHA-256 of each of `paths` below the directory `side`.
+fn digests(
+    side: &ProtocolPath,
+    paths: &[&str],
+    opts: &DiffOptions,
+) -> Result<BTreeMap<String, String>, CopyError> {
+    let digests: BTreeMap<String, String> = match side {
+        ProtocolPath::Local(local) => {
+            let mut digests = BTreeMap::new();
+            for path in paths {
+                let file = local.as_path().join(Path::new(path));
+                let digest = checksum::hash_file(&file, Algorithm::Sha256).map_err(|e| {
+                    CopyError::IoError {
+                        message: format!("Failed to hash {}", file.display()),
+                        error: e,
+                    }
+                })?;
+                digests.insert(path.to_string(), digest);
+            }
+            digests
+        }
+        ProtocolPath::Remote(remote) => remote::ssh_sha256(remote, paths, &opts.ssh_opts)?
+            .into_iter()
+            .collect(),
+    };
+    if let Some(missing) = paths.iter().find(|p| !digests.contains_key(**p)) {
+        return Err(CopyError::InvalidSource(format!(
+            "could not hash {} on {}",
+            missing,
+            match side {
+                ProtocolPath::Local(local) => local.to_string_lossy().to_string(),
+                ProtocolPath::Remote(remote) => remote.url.to_string(),
+            }
+        )));
+    }
+    Ok(digests)
+}
+
+/// `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    fn entry(path: &str, size: u64, modified: i64) -> Entry {
+        Entry {
+            path: path.to_string(),
+            size,
+            modified: Some(modified),
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let a = vec![
+            entry("same.txt", 5, 100),
+            entry("grown.log", 9, 100),
+            entry("touched.txt", 5, 200),
+            entry("new.txt", 1, 100),
+            entry("older.txt", 5, 100),
+        ];
+        let b = vec![
+            entry("same.txt", 5, 100),
+            entry("grown.log", 4, 100),
+            entry("touched.txt", 5, 100),
+            entry("gone.txt", 1, 100),
+            entry("older.txt", 5, 300),
+        ];
+        let diff = compare(a.clone(), b.clone(), Compare::SizeMtime);
+        assert_eq!(diff.only_in_a, [entry("new.txt", 1, 100)]);
+        assert_eq!(diff.only_in_b, [entry("gone.txt", 1, 100)]);
+        let changed: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|c| (c.a.path.as_str(), c.reason))
+            .collect();
+        // B being newer is what a sync leaves alone.
+        assert_eq!(
+            changed,
+            [("grown.log", Reason::Size), ("touched.txt", Reason::Mtime)]
+        );
+        assert_eq!(diff.same, 2);
+        assert!(!diff.is_empty());
+
+        let by_size = compare(a.clone(), b.clone(), Compare::SizeOnly);
+        assert_eq!(by_size.changed.len(), 1);
+        assert!(compare(a.clone(), a, Compare::SizeMtime).is_empty());
+    }
+
+    #[test]
+    fn test_diff_with_checksum() {
+        let temp = TempDir::new().unwrap();
+        let (a, b) = (temp.path().join("a"), temp.path().join("b"));
+        for (dir, path, data) in [
+            (&a, "docs/report.txt", "version 2"),
+            (&b, "docs/report.txt", "version 1"),
+            (&a, "same.txt", "same"),
+            (&b, "same.txt", "same"),
+            (&a, "only \"a\".txt", "a"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        // Older in B, but identical.
+        let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(b.join("same.txt"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let (a_path, b_path) = (
+            parse_path(a.to_str().unwrap()).unwrap(),
+            parse_path(b.to_str().unwrap()).unwrap(),
+        );
+
+        // Written in the same second, the reports look the same by size and time.
+        let by_time = diff(&a_path, &b_path, &DiffOptions::default()).unwrap();
+        let changed: Vec<_> = by_time
+            .changed
+            .iter()
+            .map(|c| (c.a.path.as_str(), c.reason))
+            .collect();
+        assert_eq!(changed, [("same.txt", Reason::Mtime)]);
+
+        let opts = DiffOptions {
+            checksum: true,
+            ..DiffOptions::default()
+        };
+        let by_content = diff(&a_path, &b_path, &opts).unwrap();
+        assert_eq!(by_content.changed.len(), 1);
+        assert_eq!(by_content.changed[0].a.path, "docs/report.txt");
+        assert_eq!(by_content.changed[0].reason, Reason::Checksum);
+        assert_eq!(by_content.same, 1);
+
+        let json = by_content.to_json();
+        assert!(
+            json.starts_with(r#"{"only_in_a":[{"path":"only \"a\".txt","file":{"size":1,"#),
+            "{}",
+            json
+        );
+        assert!(json.contains(r#""only_in_b":[],"changed":[{"path":"docs/report.txt","reason":"checksum","a":{"size":9,"#));
+        assert!(json.ends_with(r#""same":1}"#), "{}", json);
+
+        let file = parse_path(a.join("same.txt").to_str().unwrap()).unwrap();
+        assert!(diff(&file, &b_path, &opts).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! usync as a C library (`ffi` feature), for programs in other languages that would
+//! rather not start the binary for each copy.
+//!
+//! The declarations are in `include/usync.h`, which cbindgen generates from this file
+//! when the feature is on; a test fails when the copy there is out of date. The library
+//! itself is built with `cargo rustc --release --lib --features ffi --crate-type
+//! cdylib`, which leaves `libusync.so` (`.dylib`, `.dll`) in `target/release/`. The
+//! calls mirror `usync serve`:
+//! [`usync_copy`] and [`usync_sync`] take the paths as on the command line and the
+//! options as the JSON object of a request ([`Options`]), and return the
+//! [`TransferReport`](crate::TransferReport) as JSON.
+//!
+//! Strings passed in must be NUL-terminated UTF-8 and are only read during the call.
+//! Every non-NULL string returned belongs to the caller, who frees it with
+//! [`usync_free_string`]. A call that fails returns NULL, and [`usync_last_error`] says
+//! why. Panics are caught at the boundary and fail the call the same way.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+
+use crate::nonblocking::Event;
+use crate::rpc::{Options, Transfer};
+
+/// Called as a local copy writes: the source file, the bytes of it written so far, its
+/// size, and the `user_data` given with the callback. `path` is only valid during the
+/// call.
+pub type UsyncProgressCallback = Option<
+    unsafe extern "C" fn(path: *const c_char, copied: u64, total: u64, user_data: *mut c_void),
+>;
+
+#[derive(Clone, Copy)]
+struct Progress {
+    callback: unsafe extern "C" fn(*const c_char, u64, u64, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// Whoever registers `user_data` accepts calls with it from the thread of any copy.
+unsafe impl Send for Progress {}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Copy `src` to `dst` with the JSON `options_json` (NULL for the defaults) and return
+/// the report as JSON, or NULL on failure.
+///
+/// # Safety
+///
+/// `src`, `dst` and `options_json` must each be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn usync_copy(
+    src: *const c_char,
+    dst: *const c_char,
+    options_json: *const c_char,
+) -> *mut c_char {
+    boundary(|| unsafe { transfer(src, dst, options_json, false) })
+}
+
+/// [`usync_copy`] that skips files already matching at the destination (by size and
+/// modification time).
+///
+/// # Safety
+///
+/// As for [`usync_copy`].
+#[no_mangle]
+pub unsafe extern "C" fn usync_sync(
+    src: *const c_char,
+    dst: *const c_char,
+    options_json: *const c_char,
+) -> *mut c_char {
+    boundary(|| unsafe { transfer(src, dst, options_json, true) })
+}
+
+/// Call `callback` with the progress of every copy from now on, on the thread running
+/// the copy; NULL stops the calls.
+#[no_mangle]
+pub extern "C" fn usync_set_progress_callback(
+    callback: UsyncProgressCallback,
+    user_data: *mut c_void,
+) {
+    let progress = callback.map(|callback| Progress {
+        callback,
+        user_data,
+    });
+    *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = progress;
+}
+
+/// Why the last [`usync_copy`] or [`usync_sync`] on this thread returned NULL, or NULL
+/// when it succeeded. Free the message with [`usync_free_string`].
+#[no_mangle]
+pub extern "C" fn usync_last_error() -> *mut c_char {
+    LAST_ERROR.with(|e| {
+        e.borrow()
+            .clone()
+            .map_or(std::ptr::null_mut(), CString::into_raw)
+    })
+}
+
+/// Free a string returned by usync. NULL is ignored.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by usync that wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn usync_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+/// Run `call`, turning its error or panic into NULL and the last error.
+fn boundary(call: impl FnOnce() -> Result<String, String>) -> *mut c_char {
+    let result = panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        Err(format!("usync panicked: {}", message))
+    });
+    let (json, error) = match result {
+        Ok(json) => (CString::new(json).ok(), None),
+        Err(message) => (None, Some(c_string(&message))),
+    };
+    LAST_ERROR.with(|e| *e.borrow_mut() = error);
+    json.map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+unsafe fn transfer(
+    src: *const c_char,
+    dst: *const c_char,
+    options_json: *const c_char,
+    sync: bool,
+) -> Result<String, String> {
+    let src = unsafe { text(src, "src") }?.ok_or("src is NULL")?;
+    let dst = unsafe { text(dst, "dst") }?.ok_or("dst is NULL")?;
+    let options: Options = match unsafe { text(options_json, "options") }? {
+        Some(json) => serde_json::from_str(json).map_err(|e| format!("options: {}", e))?,
+        None => Options::default(),
+    };
+    let transfer = Transfer {
+        src: src.to_string(),
+        dst: dst.to_string(),
+        options,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("could not start the copy: {}", e))?;
+    let (events, mut received) = mpsc::channel(64);
+    let report = runtime.block_on(async {
+        let forward = async {
+            while let Some(event) = received.recv().await {
+                report_progress(event);
+            }
+        };
+        let (result, ()) = tokio::join!(transfer.run(sync, events), forward);
+        result
+    })?;
+    serde_json::to_string(&report).map_err(|e| e.to_string())
+}
+
+/// The UTF-8 string at `s`, or `None` for NULL.
+unsafe fn text<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map(Some)
+        .map_err(|e| format!("{} is not UTF-8: {}", name, e))
+}
+
+fn report_progress(event: Event) {
+    let Event::Bytes {
+        path,
+        copied,
+        total,
+    } = event
+    else {
+        return;
+    };
+    // Copied out, so the callback may register another one.
+    let progress = *PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(progress) = progress {
+        let path = c_string(&path.to_string_lossy());
+        unsafe { (progress.callback)(path.as_ptr(), copied, total, progress.user_data) };
+    }
+}
+
+/// `s` for C, with any NUL in it replaced.
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "\u{FFFD}")).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tempfile::TempDir;
+
+    fn owned(s: *mut c_char) -> Option<String> {
+        if s.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        unsafe { usync_free_string(s) };
+        Some(text)
+    }
+
+    #[test]
+    fn test_copy_and_errors() {
+        let temp = TempDir::new().unwrap();
+        fs::write(temp.path().join("a.txt"), "hello").unwrap();
+        let src = CString::new(temp.path().join("a.txt").to_str().unwrap()).unwrap();
+        let dst = CString::new(temp.path().join("b.txt").to_str().unwrap()).unwrap();
+
+        let report = owned(unsafe { usync_copy(src.as_ptr(), dst.as_ptr(), std::ptr::null()) });
+        let report: serde_json::Value = serde_json::from_str(&report.unwrap()).unwrap();
+        assert_eq!(report["files_copied"], 1);
+        assert_eq!(owned(usync_last_error()), None);
+        assert_eq!(
+            fs::read_to_string(temp.path().join("b.txt")).unwrap(),
+            "hello"
+        );
+
+        let options = CString::new(r#"{"recursve":true}"#).unwrap();
+        let failed = unsafe { usync_sync(src.as_ptr(), dst.as_ptr(), options.as_ptr()) };
+        assert!(failed.is_null());
+        assert!(owned(usync_last_error())
+            .unwrap()
+            .starts_with("options: unknown field"));
+
+        let latin1 = b"caf\xe9\0".map(|b| b as c_char);
+        let failed = unsafe { usync_copy(latin1.as_ptr(), dst.as_ptr(), std::ptr::null()) };
+        assert!(failed.is_null());
+        assert!(owned(usync_last_error())
+            .unwrap()
+            .starts_with("src is not UTF-8"));
+
+        let failed = unsafe { usync_copy(std::ptr::null(), dst.as_ptr(), std::ptr::null()) };
+        assert!(failed.is_null());
+        assert_eq!(owned(usync_last_error()).unwrap(), "src is NULL");
+    }
+
+    /// A sync with `delete` removes what the source no longer has, as `usync` would.
+    #[test]
+    fn test_sync_with_delete() {
+        let temp = TempDir::new().unwrap();
+        let (src, dst) = (temp.path().join("src"), temp.path().join("dst"));
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(&dst).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(src.join(name), "newer").unwrap();
+            fs::write(dst.join(name), "old").unwrap();
+        }
+        fs::write(dst.join("extra.txt"), "gone").unwrap();
+        let c_src = CString::new(src.to_str().unwrap()).unwrap();
+        let c_dst = CString::new(dst.to_str().unwrap()).unwrap();
+        let options = CString::new(r#"{"recursive":true,"delete":true}"#).unwrap();
+
+        let report = owned(unsafe { usync_sync(c_src.as_ptr(), c_dst.as_ptr(), options.as_ptr()) });
+        let report: serde_json::Value = serde_json::from_str(&report.unwrap()).unwrap();
+        assert_eq!(report["operation"], "sync");
+        assert_eq!(report["files_copied"], 3);
+        assert_eq!(report["files_deleted"], 1);
+        assert!(!dst.join("extra.txt").exists());
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "newer");
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        // Static: a copy of another test may still call back after this one is done.
+        static COPIED: AtomicU64 = AtomicU64::new(0);
+        unsafe extern "C" fn on_progress(
+            _path: *const c_char,
+            copied: u64,
+            _total: u64,
+            user_data: *mut c_void,
+        ) {
+            let copied_so_far = unsafe { &*(user_data as *const AtomicU64) };
+            copied_so_far.fetch_max(copied, Ordering::SeqCst);
+        }
+
+        let temp = TempDir::new().unwrap();
+        fs::write(temp.path().join("big.bin"), vec![7; 3000]).unwrap();
+        let src = CString::new(temp.path().join("big.bin").to_str().unwrap()).unwrap();
+        let dst = CString::new(temp.path().join("copy.bin").to_str().unwrap()).unwrap();
+
+        usync_set_progress_callback(
+            Some(on_progress),
+            &COPIED as *const AtomicU64 as *mut c_void,
+        );
+        let report = owned(unsafe { usync_copy(src.as_ptr(), dst.as_ptr(), std::ptr::null()) });
+        usync_set_progress_callback(None, std::ptr::null_mut());
+        assert!(report.is_some());
+        assert_eq!(COPIED.load(Ordering::SeqCst), 3000);
+    }
+
+    /// `include/usync.h` is what cbindgen makes of this file.
+    #[test]
+    fn test_header_is_current() {
+        let generated = concat!(env!("OUT_DIR"), "/usync.h");
+        let checked_in = concat!(env!("CARGO_MANIFEST_DIR"), "/include/usync.h");
+        assert!(
+            fs::read_to_string(generated).unwrap() == fs::read_to_string(checked_in).unwrap(),
+            "include/usync.h is out of date: copy {} over it",
+            generated
+        );
+    }
+
+    #[test]
+    fn test_panics_are_caught() {
+        let result = boundary(|| panic!("boom"));
+        assert!(result.is_null());
+        assert_eq!(owned(usync_last_error()).unwrap(), "usync panicked: boom");
+    }
+}
@@ -0,0 +1,106 @@
+//! `--files-from`: the list of files to copy, read from a file or standard input.
+//!
+//! One path per line, or with `--from0` per NUL byte, as `find -print0` writes them.
+//! A line's trailing `\r` goes, so lists written on Windows work, and empty lines are
+//! skipped. Each path is relative to the source directory; a leading `./` is dropped,
+//! and absolute paths or paths with `..` are refused.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, PathBuf};
+
+use crate::stream;
+
+/// Read the list at `source` (`-` for standard input).
+pub fn read(source: &str, from0: bool) -> Result<Vec<PathBuf>, String> {
+    let data = if source == stream::STDIO {
+        let mut data = Vec::new();
+        io::stdin()
+            .read_to_end(&mut data)
+            .map(|_| data)
+            .map_err(|e| format!("could not read the file list from standard input: {}", e))?
+    } else {
+        fs::read(source).map_err(|e| format!("could not read the file list {}: {}", source, e))?
+    };
+    parse(&data, from0)
+}
+
+/// The paths in the list `data`.
+pub fn parse(data: &[u8], from0: bool) -> Result<Vec<PathBuf>, String> {
+    let separator = if from0 { b'\0' } else { b'\n' };
+    let mut paths = Vec::new();
+    for (i, entry) in data.split(|&b| b == separator).enumerate() {
+        let entry = match entry {
+            [rest @ .., b'\r'] if !from0 => rest,
+            entry => entry,
+        };
+        if entry.is_empty() {
+            continue;
+        }
+        let path = path_of(entry);
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                _ => {
+                    return Err(format!(
+                        "entry {} of the file list, {}, must be a path inside the source \
+                         directory",
+                        i + 1,
+                        path.display()
+                    ))
+                }
+            }
+        }
+        if !relative.as_os_str().is_empty() {
+            paths.push(relative);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_of(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_of(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let list = b"a.txt\r\nMy Documents/b c.txt\r\n\r\n./d/e.txt\nf\n";
+        assert_eq!(
+            parse(list, false).unwrap(),
+            ["a.txt", "My Documents/b c.txt", "d/e.txt", "f"].map(PathBuf::from)
+        );
+        // With --from0, newlines and carriage returns belong to the names.
+        assert_eq!(
+            parse(b"new\nline\0cr\r\0", true).unwrap(),
+            ["new\nline", "cr\r"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_parse_refuses_paths_outside_the_source() {
+        for list in [
+            &b"ok.txt\n../secret\n"[..],
+            b"/etc/passwd\n",
+            b"a/../../b\n",
+        ] {
+            let e = parse(list, false).unwrap_err();
+            assert!(e.contains("inside the source directory"), "{}", e);
+        }
+        assert!(parse(b"ok.txt\n../secret\n", false)
+            .unwrap_err()
+            .starts_with("entry 2 "));
+    }
+}
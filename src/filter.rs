@@ -0,0 +1,756 @@
+//! Include/exclude filters for directory copies (`--exclude`, `--include`).
+//!
+//! Filters see each entry's path relative to the directory being copied, with `/`
+//! separators. Patterns are globs: `*` and `?` stay within one path component, `**`
+//! crosses them, and `[...]` is a character class. A pattern without a `/` matches the
+//! entry's name at any depth; one with a `/` (or a leading `/`) matches the whole
+//! relative path. A trailing `/` restricts the pattern to directories.
+//!
+//! `--filter` rules are rsync's instead: `+ PATTERN` includes, `- PATTERN` excludes, and
+//! the first rule that matches an entry decides, directories included, so `+ /src/***`
+//! followed by `- *` copies only `src`. `DIR/***` matches the directory and everything
+//! in it, and `!` clears the rules before it. An [`OrderedPatternFilter`] holds them,
+//! next to the `--include`/`--exclude` [`PatternFilter`]; an entry must pass both.
+//!
+//! [`FilterChain::decide`] also says which rule left an entry out, and with
+//! [`FilterChain::set_debug`] (`--debug-filters`) every decision is printed as the walk
+//! makes it.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Decides whether an entry of a directory walk takes part in the copy.
+pub trait Filter: fmt::Debug + Send + Sync {
+    /// `rel` is relative to the walk's root. Returning `false` for a directory skips
+    /// everything below it.
+    fn is_included(&self, rel: &Path, is_dir: bool) -> bool;
+
+    /// Whether nothing below the directory `rel` can be included, so a walk needn't
+    /// read it at all. By default that is when the directory itself is excluded; a
+    /// filter overrides it to also prune directories it keeps but empties, such as
+    /// `node_modules` under `--exclude '**/node_modules/**'`.
+    fn matches_dir(&self, rel: &Path) -> bool {
+        !self.is_included(rel, true)
+    }
+
+    /// The filter as the user wrote it, for `--debug-filters`.
+    fn describe(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    /// Like [`is_included`](Filter::is_included), naming the rule that left the entry
+    /// out. Filters made of several rules override it to name the one that matched.
+    fn decide(&self, rel: &Path, is_dir: bool) -> FilterDecision {
+        if self.is_included(rel, is_dir) {
+            FilterDecision::default()
+        } else {
+            FilterDecision::rejected(self.describe())
+        }
+    }
+}
+
+/// Whether an entry takes part in a copy, and if not, the rule that left it out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterDecision {
+    pub included: bool,
+    pub rejected_by: Option<String>,
+}
+
+impl FilterDecision {
+    fn rejected(rule: String) -> Self {
+        FilterDecision {
+            included: false,
+            rejected_by: Some(rule),
+        }
+    }
+}
+
+impl Default for FilterDecision {
+    /// Included, as no filter objected.
+    fn default() -> Self {
+        FilterDecision {
+            included: true,
+            rejected_by: None,
+        }
+    }
+}
+
+impl fmt::Display for FilterDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.rejected_by {
+            _ if self.included => write!(f, "included by default"),
+            Some(rule) => write!(f, "excluded by {}", rule),
+            None => write!(f, "excluded"),
+        }
+    }
+}
+
+/// A single glob pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// As given, for messages.
+    source: String,
+    glob: Vec<char>,
+    /// Matched against the whole relative path instead of the entry's name.
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let source = pattern.to_string();
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        let pattern = if dir_only {
+            &pattern[..pattern.len() - 1]
+        } else {
+            pattern
+        };
+        let anchored = pattern.contains('/');
+        Pattern {
+            source,
+            glob: pattern.trim_start_matches('/').chars().collect(),
+            anchored,
+            dir_only,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches everything below the directory `rel`: it ends in
+    /// `/**` and the rest of it matches `rel`.
+    pub fn matches_below(&self, rel: &Path) -> bool {
+        let Some(dir) = self.glob.strip_suffix(&['/', '*', '*']) else {
+            return false;
+        };
+        let rel = crate::utils::slash_path(rel);
+        let text = if self.anchored {
+            rel.as_str()
+        } else {
+            rel.rsplit('/').next().unwrap_or_default()
+        };
+        glob_match(dir, &text.chars().collect::<Vec<_>>())
+    }
+
+    pub fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = crate::utils::slash_path(rel);
+        let text = if self.anchored {
+            rel.as_str()
+        } else {
+            rel.rsplit('/').next().unwrap_or_default()
+        };
+        glob_match(&self.glob, &text.chars().collect::<Vec<_>>())
+    }
+}
+
+/// Match `text` against a glob, backtracking over `*` and `**`.
+fn glob_match(glob: &[char], text: &[char]) -> bool {
+    match glob.first() {
+        None => text.is_empty(),
+        Some('*') if glob.get(1) == Some(&'*') => {
+            // `**/` also matches no directories at all.
+            let rest = &glob[2..];
+            if rest.first() == Some(&'/') && glob_match(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &glob[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => {
+            matches!(text.first(), Some(c) if *c != '/') && glob_match(&glob[1..], &text[1..])
+        }
+        Some('[') => match (class_match(&glob[1..], text.first().copied()), text.first()) {
+            (Some((true, len)), Some(_)) => glob_match(&glob[1 + len..], &text[1..]),
+            (Some(_), _) => false,
+            // No closing `]`: a literal `[`.
+            (None, Some('[')) => glob_match(&glob[1..], &text[1..]),
+            (None, _) => false,
+        },
+        Some(c) => text.first() == Some(c) && glob_match(&glob[1..], &text[1..]),
+    }
+}
+
+/// Match `c` against the class starting right after a `[`. Returns whether it matched
+/// and the length of the class including the closing `]`, or `None` if it isn't closed.
+fn class_match(class: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let start = usize::from(negated);
+    // A `]` right at the start is part of the set.
+    let end = class
+        .iter()
+        .skip(start + 1)
+        .position(|&ch| ch == ']')
+        .map(|p| p + start + 1)?;
+    let c = c?;
+    let set = &class[start..end];
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found |= set[i] <= c && c <= set[i + 2];
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated && c != '/', end + 1))
+}
+
+/// `--exclude` and `--include` patterns. An entry matching an exclude is skipped; when
+/// includes are given, files must also match one of them (directories are still entered).
+#[derive(Debug, Clone, Default)]
+pub struct PatternFilter {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+}
+
+impl PatternFilter {
+    pub fn new(includes: &[String], excludes: &[String]) -> Self {
+        PatternFilter {
+            includes: includes.iter().map(|p| Pattern::new(p)).collect(),
+            excludes: excludes.iter().map(|p| Pattern::new(p)).collect(),
+        }
+    }
+}
+
+impl Filter for PatternFilter {
+    fn is_included(&self, rel: &Path, is_dir: bool) -> bool {
+        self.decide(rel, is_dir).included
+    }
+
+    fn matches_dir(&self, rel: &Path) -> bool {
+        self.excludes
+            .iter()
+            .any(|p| p.matches(rel, true) || p.matches_below(rel))
+    }
+
+    fn describe(&self) -> String {
+        let excludes = self.excludes.iter().map(|p| ("--exclude", p));
+        let includes = self.includes.iter().map(|p| ("--include", p));
+        excludes
+            .chain(includes)
+            .map(|(flag, p)| format!("{} {}", flag, p.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn decide(&self, rel: &Path, is_dir: bool) -> FilterDecision {
+        if let Some(p) = self.excludes.iter().find(|p| p.matches(rel, is_dir)) {
+            return FilterDecision::rejected(format!("--exclude {}", p.as_str()));
+        }
+        if is_dir
+            || self.includes.is_empty()
+            || self.includes.iter().any(|p| p.matches(rel, is_dir))
+        {
+            return FilterDecision::default();
+        }
+        let includes: Vec<&str> = self.includes.iter().map(Pattern::as_str).collect();
+        FilterDecision::rejected(format!("no --include matching ({})", includes.join(", ")))
+    }
+}
+
+/// What a `--filter` rule does with the entries it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    /// `+`
+    Include,
+    /// `-`
+    Exclude,
+    /// `!`: forget the rules before it.
+    Clear,
+}
+
+/// One `--filter` rule, like rsync's: `+ PATTERN`, `- PATTERN` or `!`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    kind: RuleKind,
+    /// `DIR/***` is two: the directory, and everything below it.
+    patterns: Vec<Pattern>,
+    /// As given, for messages.
+    source: String,
+}
+
+impl Rule {
+    /// Parse a rule: `+ PATTERN` or `include PATTERN`, `- PATTERN` or
+    /// `exclude PATTERN`, or `!` (`clear`).
+    pub fn parse(rule: &str) -> Result<Rule, String> {
+        let rule = rule.trim();
+        let (kind, pattern) = match rule.split_once([' ', '_']) {
+            _ if rule == "!" || rule == "clear" => (RuleKind::Clear, ""),
+            Some(("+" | "include", pattern)) => (RuleKind::Include, pattern),
+            Some(("-" | "exclude", pattern)) => (RuleKind::Exclude, pattern),
+            _ => {
+                return Err(format!(
+                    "invalid filter rule '{}': expected '+ PATTERN', '- PATTERN' or '!'",
+                    rule
+                ))
+            }
+        };
+        if kind != RuleKind::Clear && pattern.is_empty() {
+            return Err(format!("invalid filter rule '{}': no pattern", rule));
+        }
+        let patterns = match pattern.strip_suffix("/***") {
+            Some(dir) => {
+                // The whole rule has a `/`, so the directory is matched from the root.
+                let dir = if dir.starts_with('/') {
+                    dir.to_string()
+                } else {
+                    format!("/{}", dir)
+                };
+                vec![Pattern::new(&dir), Pattern::new(&format!("{}/**", dir))]
+            }
+            None if kind == RuleKind::Clear => Vec::new(),
+            None => vec![Pattern::new(pattern)],
+        };
+        Ok(Rule {
+            kind,
+            patterns,
+            source: rule.to_string(),
+        })
+    }
+
+    /// The rules of a `--filter-file`, one per line; blank lines and those starting
+    /// with `#` or `;` are skipped. Errors name the line.
+    pub fn parse_file(text: &str) -> Result<Vec<Rule>, String> {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with(['#', ';']))
+            .map(|(i, line)| Rule::parse(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+            .collect()
+    }
+
+    pub fn kind(&self) -> RuleKind {
+        self.kind
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        self.patterns.iter().any(|p| p.matches(rel, is_dir))
+    }
+
+    fn matches_below(&self, rel: &Path) -> bool {
+        self.patterns.iter().any(|p| p.matches_below(rel))
+    }
+}
+
+/// Whether an entry no `--filter` rule matches is included (`--filter-default`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FilterDefault {
+    #[default]
+    Include,
+    Exclude,
+}
+
+/// `--filter` rules, tried in order; the first that matches an entry decides.
+#[derive(Debug, Clone, Default)]
+pub struct OrderedPatternFilter {
+    rules: Vec<Rule>,
+    default: FilterDefault,
+}
+
+impl OrderedPatternFilter {
+    pub fn new(rules: &[Rule], default: FilterDefault) -> Self {
+        let start = rules
+            .iter()
+            .rposition(|r| r.kind == RuleKind::Clear)
+            .map_or(0, |i| i + 1);
+        OrderedPatternFilter {
+            rules: rules[start..].to_vec(),
+            default,
+        }
+    }
+}
+
+impl Filter for OrderedPatternFilter {
+    fn is_included(&self, rel: &Path, is_dir: bool) -> bool {
+        self.decide(rel, is_dir).included
+    }
+
+    fn matches_dir(&self, rel: &Path) -> bool {
+        if !self.is_included(rel, true) {
+            return true;
+        }
+        // Only a rule matching all of it decides for everything below, and only when no
+        // rule before it might pick out some entries first.
+        self.rules
+            .first()
+            .is_some_and(|r| r.kind == RuleKind::Exclude && r.matches_below(rel))
+    }
+
+    fn describe(&self) -> String {
+        let rules: Vec<&str> = self.rules.iter().map(Rule::as_str).collect();
+        let mut description = format!("--filter rules: {}", rules.join(", "));
+        if self.default == FilterDefault::Exclude {
+            description.push_str(" (--filter-default exclude)");
+        }
+        description
+    }
+
+    fn decide(&self, rel: &Path, is_dir: bool) -> FilterDecision {
+        match self.rules.iter().find(|r| r.matches(rel, is_dir)) {
+            Some(rule) if rule.kind == RuleKind::Exclude => {
+                FilterDecision::rejected(format!("--filter '{}'", rule.as_str()))
+            }
+            Some(_) => FilterDecision::default(),
+            None if self.default == FilterDefault::Exclude => FilterDecision::rejected(
+                "no --filter rule matching (--filter-default exclude)".to_string(),
+            ),
+            None => FilterDecision::default(),
+        }
+    }
+}
+
+/// All filters of a copy; an entry is included when every filter includes it.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<dyn Filter>>,
+    /// Print each decision to stderr (`--debug-filters`).
+    debug: bool,
+}
+
+impl FilterChain {
+    pub fn push(&mut self, filter: impl Filter + 'static) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Print every decision to stderr as it is made, with the filter that made it.
+    pub fn set_debug(&mut self, on: bool) {
+        self.debug = on;
+    }
+
+    /// The same filters, not printing their decisions; for walks ahead of the copy's
+    /// own, so that each path is traced once.
+    pub fn without_debug(&self) -> Self {
+        FilterChain {
+            debug: false,
+            ..self.clone()
+        }
+    }
+
+    /// The filters in the order they are applied.
+    pub fn describe(&self) -> Vec<String> {
+        self.filters.iter().map(|f| f.describe()).collect()
+    }
+
+    pub fn is_included(&self, rel: &Path, is_dir: bool) -> bool {
+        self.decide(rel, is_dir).included
+    }
+
+    /// Whether `rel` is included, and if not, which filter left it out first.
+    pub fn decide(&self, rel: &Path, is_dir: bool) -> FilterDecision {
+        let (index, decision) = self.first_rejection(rel, is_dir);
+        self.trace(&crate::utils::slash_path(rel), is_dir, index, &decision);
+        decision
+    }
+
+    /// Whether a walk can skip reading the directory `rel`, as nothing below it can be
+    /// included.
+    pub fn matches_dir(&self, rel: &Path) -> bool {
+        if rel.as_os_str().is_empty() {
+            return false;
+        }
+        let pruned = self.filters.iter().position(|f| f.matches_dir(rel));
+        if let (true, Some(i)) = (self.debug, pruned) {
+            eprintln!(
+                "filter: {}/: not entered, nothing below it is included (filter {})",
+                crate::utils::slash_path(rel),
+                i + 1
+            );
+        }
+        pruned.is_some()
+    }
+
+    /// Whether `name`, a `/`-separated path from a flat listing (an archive, a remote
+    /// `find`, S3 keys), passes: it and every directory above it must, as in a walk that
+    /// doesn't enter excluded directories. A trailing `/` marks a directory.
+    pub fn includes_entry(&self, name: &str) -> bool {
+        self.decide_entry(name).included
+    }
+
+    /// [`includes_entry`](FilterChain::includes_entry), naming the filter that left the
+    /// entry, or the directory above it, out.
+    pub fn decide_entry(&self, name: &str) -> FilterDecision {
+        let is_dir = name.ends_with('/');
+        let parts: Vec<&str> = name
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+        let mut result = (None, FilterDecision::default());
+        for n in 1..=parts.len() {
+            let rel: PathBuf = parts[..n].iter().collect();
+            let (index, decision) = self.first_rejection(&rel, n < parts.len() || is_dir);
+            if !decision.included {
+                result = (index, decision);
+                break;
+            }
+        }
+        self.trace(&parts.join("/"), is_dir, result.0, &result.1);
+        result.1
+    }
+
+    fn first_rejection(&self, rel: &Path, is_dir: bool) -> (Option<usize>, FilterDecision) {
+        for (i, filter) in self.filters.iter().enumerate() {
+            let decision = filter.decide(rel, is_dir);
+            if !decision.included {
+                return (Some(i), decision);
+            }
+        }
+        (None, FilterDecision::default())
+    }
+
+    fn trace(&self, rel: &str, is_dir: bool, index: Option<usize>, decision: &FilterDecision) {
+        if !self.debug {
+            return;
+        }
+        let slash = if is_dir { "/" } else { "" };
+        match index {
+            Some(i) => eprintln!("filter: {}{}: {} (filter {})", rel, slash, decision, i + 1),
+            None => eprintln!("filter: {}{}: {}", rel, slash, decision),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::new(pattern).matches(Path::new(path), false)
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        assert!(matches("*.map", "app.js.map"));
+        assert!(matches("*.map", "static/js/app.js.map"));
+        assert!(!matches("*.map", "app.js"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(matches("file[0-9].log", "logs/file7.log"));
+        assert!(!matches("file[!0-9].log", "file7.log"));
+
+        // With a slash the pattern matches the whole relative path.
+        assert!(matches("static/*.css", "static/site.css"));
+        assert!(!matches("static/*.css", "static/css/site.css"));
+        assert!(!matches("static/*.css", "other/static/site.css"));
+        assert!(matches("/build", "build"));
+        assert!(!matches("/build", "src/build"));
+        assert!(matches("static/**/*.css", "static/site.css"));
+        assert!(matches("static/**/*.css", "static/css/v2/site.css"));
+        assert!(matches("**/cache", "a/b/cache"));
+        assert!(matches("**/cache", "cache"));
+
+        // A trailing slash only matches directories.
+        let dir_only = Pattern::new("node_modules/");
+        assert!(dir_only.matches(Path::new("web/node_modules"), true));
+        assert!(!dir_only.matches(Path::new("web/node_modules"), false));
+    }
+
+    #[test]
+    fn test_pattern_filter_and_chain() {
+        let filter = PatternFilter::new(&["*.html".to_string()], &["draft*".to_string()]);
+        assert!(filter.is_included(Path::new("index.html"), false));
+        assert!(!filter.is_included(Path::new("drafts.html"), false));
+        assert!(!filter.is_included(Path::new("style.css"), false));
+        assert!(filter.is_included(Path::new("blog"), true));
+        assert!(!filter.is_included(Path::new("drafts"), true));
+
+        let mut chain = FilterChain::default();
+        assert!(chain.is_included(Path::new("anything"), false));
+        chain.push(filter);
+        chain.push(PatternFilter::new(&[], &["old/".to_string()]));
+        assert!(!chain.is_empty());
+        assert!(chain.is_included(Path::new("blog/post.html"), false));
+        assert!(!chain.is_included(Path::new("old"), true));
+        assert!(!chain.is_included(Path::new("style.css"), false));
+    }
+
+    #[test]
+    fn test_decisions_name_the_rule() {
+        let mut chain = FilterChain::default();
+        assert_eq!(
+            chain.decide(Path::new("a.txt"), false).to_string(),
+            "included by default"
+        );
+        chain.push(PatternFilter::new(&[], &["*.tmp".to_string()]));
+        chain.push(PatternFilter::new(
+            &["*.html".to_string(), "*.css".to_string()],
+            &["draft*".to_string(), "old/".to_string()],
+        ));
+        assert_eq!(
+            chain.describe(),
+            [
+                "--exclude *.tmp",
+                "--exclude draft*, --exclude old/, --include *.html, --include *.css"
+            ]
+        );
+        assert_eq!(
+            chain.decide(Path::new("index.html"), false),
+            FilterDecision::default()
+        );
+        assert_eq!(
+            chain
+                .decide(Path::new("draft.html"), false)
+                .rejected_by
+                .as_deref(),
+            Some("--exclude draft*")
+        );
+        // The first filter to reject it is named, not the later ones.
+        assert_eq!(
+            chain.decide(Path::new("draft.tmp"), false).to_string(),
+            "excluded by --exclude *.tmp"
+        );
+        assert_eq!(
+            chain.decide(Path::new("notes.md"), false).to_string(),
+            "excluded by no --include matching (*.html, *.css)"
+        );
+        // A file under an excluded directory is left out by that directory's rule.
+        let decision = chain.decide_entry("site/old/index.html");
+        assert!(!decision.included);
+        assert_eq!(decision.rejected_by.as_deref(), Some("--exclude old/"));
+    }
+
+    fn rules(rules: &[&str]) -> Vec<Rule> {
+        rules.iter().map(|r| Rule::parse(r).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_rule_parsing() {
+        let rule = Rule::parse("+ /src/***").unwrap();
+        assert_eq!(rule.kind(), RuleKind::Include);
+        assert_eq!(rule.as_str(), "+ /src/***");
+        assert_eq!(
+            Rule::parse("exclude *.o").unwrap().kind(),
+            RuleKind::Exclude
+        );
+        assert_eq!(Rule::parse("-_*.o").unwrap().kind(), RuleKind::Exclude);
+        assert_eq!(Rule::parse("!").unwrap().kind(), RuleKind::Clear);
+        assert!(Rule::parse("* foo").is_err());
+        assert!(Rule::parse("+").is_err());
+        assert!(Rule::parse("*.o").is_err());
+
+        let parsed = Rule::parse_file("# build output\n\n- target/\n; legacy\n+ *.rs\n").unwrap();
+        assert_eq!(parsed, rules(&["- target/", "+ *.rs"]));
+        assert_eq!(
+            Rule::parse_file("+ a\nkeep b\n").unwrap_err(),
+            "line 2: invalid filter rule 'keep b': expected '+ PATTERN', '- PATTERN' or '!'"
+        );
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let filter =
+            OrderedPatternFilter::new(&rules(&["+ /src/***", "- *"]), FilterDefault::Include);
+        assert!(filter.is_included(Path::new("src"), true));
+        assert!(filter.is_included(Path::new("src/lib/a.rs"), false));
+        assert!(!filter.is_included(Path::new("docs"), true));
+        assert!(!filter.is_included(Path::new("README.md"), false));
+        assert_eq!(
+            filter
+                .decide(Path::new("README.md"), false)
+                .rejected_by
+                .as_deref(),
+            Some("--filter '- *'")
+        );
+
+        // Unlike --include, an include before an exclude wins.
+        let filter = OrderedPatternFilter::new(
+            &rules(&["+ keep.log", "- *.log", "+ */", "+ *.rs"]),
+            FilterDefault::Exclude,
+        );
+        assert!(filter.is_included(Path::new("logs/keep.log"), false));
+        assert!(!filter.is_included(Path::new("logs/other.log"), false));
+        assert!(filter.is_included(Path::new("src/main.rs"), false));
+        assert!(!filter.is_included(Path::new("Cargo.toml"), false));
+        assert_eq!(
+            filter.decide(Path::new("Cargo.toml"), false).to_string(),
+            "excluded by no --filter rule matching (--filter-default exclude)"
+        );
+
+        // `!` drops everything before it.
+        let filter =
+            OrderedPatternFilter::new(&rules(&["- *", "!", "- *.tmp"]), FilterDefault::Include);
+        assert!(filter.is_included(Path::new("a.txt"), false));
+        assert!(!filter.is_included(Path::new("a.tmp"), false));
+
+        // Alongside --exclude, an entry must pass both.
+        let mut chain = FilterChain::default();
+        chain.push(PatternFilter::new(&[], &["*.bak".to_string()]));
+        chain.push(OrderedPatternFilter::new(
+            &rules(&["+ /src/***", "- *"]),
+            FilterDefault::Include,
+        ));
+        assert!(chain.includes_entry("src/a.rs"));
+        assert!(!chain.includes_entry("src/a.rs.bak"));
+        // Pruned with its directory.
+        assert!(!chain.includes_entry("other/src/a.rs"));
+    }
+
+    #[test]
+    fn test_directories_to_prune() {
+        let filter = PatternFilter::new(
+            &["*.js".to_string()],
+            &["**/node_modules/**".to_string(), "/build".to_string()],
+        );
+        // Kept, but emptied: not worth reading.
+        assert!(filter.is_included(Path::new("web/node_modules"), true));
+        assert!(filter.matches_dir(Path::new("web/node_modules")));
+        assert!(filter.matches_dir(Path::new("build")));
+        assert!(!filter.matches_dir(Path::new("src/build")));
+        // Includes only pick files; directories are still read.
+        assert!(!filter.matches_dir(Path::new("src")));
+
+        let ordered = OrderedPatternFilter::new(
+            &rules(&["- /cache/**", "+ /src/***", "- *"]),
+            FilterDefault::Include,
+        );
+        assert!(ordered.matches_dir(Path::new("cache")));
+        assert!(ordered.matches_dir(Path::new("docs")));
+        assert!(!ordered.matches_dir(Path::new("src")));
+        let ordered = OrderedPatternFilter::new(
+            &rules(&["+ keep.txt", "- /cache/**"]),
+            FilterDefault::Include,
+        );
+        assert!(!ordered.matches_dir(Path::new("cache")));
+
+        let mut chain = FilterChain::default();
+        chain.push(filter);
+        assert!(chain.matches_dir(Path::new("a/node_modules")));
+        assert!(!chain.matches_dir(Path::new("")));
+    }
+
+    #[test]
+    fn test_entries_are_filtered_with_their_directories() {
+        let mut filters = FilterChain::default();
+        filters.push(PatternFilter::new(&[], &["cache/".to_string()]));
+        assert!(filters.includes_entry("./src/a.rs"));
+        assert!(!filters.includes_entry("cache/"));
+        assert!(!filters.includes_entry("./web/cache/x.bin"));
+        // A file named like an excluded directory is kept.
+        assert!(filters.includes_entry("cache"));
+    }
+}
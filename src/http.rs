@@ -0,0 +1,249 @@
+//! Checks on what an HTTP download got back: `--expect-content-type`,
+//! `--max-redirects`, and a warning when a file that should be binary comes back as
+//! a small HTML page, such as a "login required" page saved as `dataset.tar.gz`.
+//! Also the validators `--update` sends so an unchanged file isn't fetched again.
+//!
+//! curl dumps the headers of every response it follows (`-D`) into a scratch file;
+//! the last one is the response that was saved.
+
+use std::path::{Path, PathBuf};
+
+use crate::remote::RemoteCopyError;
+
+/// An HTML page at most this big, saved under a binary file's name, is warned about.
+const SUSPICIOUS_HTML_SIZE: u64 = 100 * 1024;
+
+/// Extensions of files that are never HTML.
+const BINARY_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "bin", "bz2", "db", "deb", "dll", "dmg", "exe", "gif", "gz", "img", "iso", "jar",
+    "jpeg", "jpg", "mkv", "mp3", "mp4", "msi", "parquet", "pdf", "png", "rar", "rpm", "so",
+    "sqlite", "tar", "tbz", "tgz", "txz", "war", "webp", "whl", "xz", "zip", "zst",
+];
+
+/// How an HTTP download is made and what it must get back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpChecks {
+    /// Fail unless the Content-Type starts with this (`--expect-content-type`).
+    pub expect_content_type: Option<String>,
+    /// Redirects to follow at most (`--max-redirects`); curl's own limit otherwise.
+    pub max_redirects: Option<u32>,
+    /// Only download when the server has something newer than the destination
+    /// (`--update`).
+    pub update: bool,
+    /// Byte ranges to download at once (`--http-segments`); one stream when below 2.
+    pub segments: u32,
+    /// Treat the source as a directory index and download what it links to
+    /// (`--http-mirror`).
+    pub mirror: bool,
+    /// Levels of a mirrored index to follow (`--max-depth`; 1: its own files).
+    pub max_depth: Option<usize>,
+}
+
+/// What became of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Download {
+    Fetched,
+    /// `--update`: the server answered 304 and the destination was left as it was.
+    NotModified,
+}
+
+/// The final response of a download, from curl's header dump.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub status: Option<u16>,
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+    /// `Accept-Ranges: bytes`: the server serves parts of the file.
+    pub accept_ranges: bool,
+    /// Redirects followed to get to it.
+    pub redirects: u32,
+}
+
+impl Response {
+    /// Parse the headers curl wrote with `-D`: one block per response, each starting
+    /// with its status line.
+    pub fn parse(dump: &str) -> Response {
+        let mut responses = 0u32;
+        let mut last = Response::default();
+        for line in dump.lines().map(str::trim_end) {
+            if line.starts_with("HTTP/") {
+                responses += 1;
+                last = Response {
+                    status: line.split_whitespace().nth(1).and_then(|s| s.parse().ok()),
+                    ..Response::default()
+                };
+            } else if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-type") {
+                    last.content_type = Some(value.trim().to_string());
+                } else if name.eq_ignore_ascii_case("etag") {
+                    last.etag = Some(value.trim().to_string());
+                } else if name.eq_ignore_ascii_case("accept-ranges") {
+                    last.accept_ranges = value.trim().eq_ignore_ascii_case("bytes");
+                }
+            }
+        }
+        last.redirects = responses.saturating_sub(1);
+        last
+    }
+
+    /// The error for `problem` with this response, for `url`.
+    pub fn error(&self, url: &str, problem: impl Into<String>) -> RemoteCopyError {
+        RemoteCopyError::HttpResponse {
+            url: url.to_string(),
+            status: self.status,
+            content_type: self.content_type.clone(),
+            problem: problem.into(),
+        }
+    }
+
+    fn is_html(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_some_and(|t| t.to_ascii_lowercase().starts_with("text/html"))
+    }
+}
+
+impl HttpChecks {
+    /// Check the response a download of `url` into `dst` got. `Ok(Some(warning))` is a
+    /// file that is probably not what was asked for, but wasn't required to be.
+    pub fn check(
+        &self,
+        url: &str,
+        response: &Response,
+        dst: &Path,
+    ) -> Result<Option<String>, RemoteCopyError> {
+        if let Some(expected) = &self.expect_content_type {
+            let matches = response.content_type.as_deref().is_some_and(|t| {
+                t.to_ascii_lowercase()
+                    .starts_with(&expected.to_ascii_lowercase())
+            });
+            if !matches {
+                return Err(response.error(
+                    url,
+                    format!("the response is not the expected {}", expected),
+                ));
+            }
+            return Ok(None);
+        }
+        let size = std::fs::metadata(dst).map_or(0, |m| m.len());
+        if response.is_html() && expects_binary(dst) && size <= SUSPICIOUS_HTML_SIZE {
+            return Ok(Some(format!(
+                "{} is an HTML page ({} bytes), not the file its name suggests; \
+                 the server may want a login (--expect-content-type makes this an error)",
+                dst.display(),
+                size
+            )));
+        }
+        Ok(None)
+    }
+}
+
+/// Where `--update` keeps the ETag of the download saved at `dst`: a hidden
+/// `.NAME.usync-etag` file next to it.
+pub fn etag_path(dst: &Path) -> PathBuf {
+    let name = dst.file_name().unwrap_or_default().to_string_lossy();
+    dst.with_file_name(format!(".{}.usync-etag", name))
+}
+
+/// The ETag stored for `dst`, if `dst` is still there.
+pub fn stored_etag(dst: &Path) -> Option<String> {
+    if !dst.is_file() {
+        return None;
+    }
+    let etag = std::fs::read_to_string(etag_path(dst)).ok()?;
+    Some(etag.trim().to_string()).filter(|e| !e.is_empty())
+}
+
+/// Remember the ETag `dst` was downloaded with, or forget a stale one. Failing to is
+/// only a missed chance to skip the next download.
+pub fn store_etag(dst: &Path, etag: Option<&str>) {
+    let path = etag_path(dst);
+    let _ = match etag {
+        Some(etag) => std::fs::write(path, format!("{}\n", etag)),
+        None => std::fs::remove_file(path),
+    };
+}
+
+/// Whether `path` names a file that is never HTML, judging by its extension.
+pub fn expects_binary(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| BINARY_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_headers() {
+        let dump = "HTTP/1.1 302 Found\r\nLocation: /login\r\nContent-Type: text/plain\r\n\r\n\
+                    HTTP/2 200 \r\ncontent-type: text/html; charset=utf-8\r\nETag: \"v2\"\r\n\
+                    Accept-Ranges: bytes\r\n\r\n";
+        assert_eq!(
+            Response::parse(dump),
+            Response {
+                status: Some(200),
+                content_type: Some("text/html; charset=utf-8".to_string()),
+                etag: Some("\"v2\"".to_string()),
+                accept_ranges: true,
+                redirects: 1,
+            }
+        );
+        assert_eq!(Response::parse(""), Response::default());
+    }
+
+    #[test]
+    fn test_checks() {
+        let temp = TempDir::new().unwrap();
+        let archive = temp.path().join("dataset.tar.gz");
+        std::fs::write(&archive, "<html>Please log in</html>").unwrap();
+        let html = Response {
+            status: Some(200),
+            content_type: Some("text/html".to_string()),
+            ..Response::default()
+        };
+        let url = "https://example.com/dataset.tar.gz";
+
+        let warning = HttpChecks::default().check(url, &html, &archive).unwrap();
+        assert!(warning.unwrap().contains("is an HTML page (26 bytes)"));
+        // An HTML file saved as one is fine.
+        let page = temp.path().join("index.html");
+        std::fs::write(&page, "<html></html>").unwrap();
+        assert_eq!(
+            HttpChecks::default().check(url, &html, &page).unwrap(),
+            None
+        );
+
+        let checks = HttpChecks {
+            expect_content_type: Some("application/".to_string()),
+            ..HttpChecks::default()
+        };
+        let error = checks.check(url, &html, &archive).unwrap_err().to_string();
+        assert!(
+            error.contains("not the expected application/ (HTTP 200, Content-Type text/html)"),
+            "{}",
+            error
+        );
+        let gzip = Response {
+            content_type: Some("Application/Gzip".to_string()),
+            ..html
+        };
+        assert_eq!(checks.check(url, &gzip, &archive).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stored_etag() {
+        let temp = TempDir::new().unwrap();
+        let dst = temp.path().join("big.json");
+        assert_eq!(etag_path(&dst), temp.path().join(".big.json.usync-etag"));
+        store_etag(&dst, Some("\"abc\""));
+        // Not without the file it belongs to.
+        assert_eq!(stored_etag(&dst), None);
+        std::fs::write(&dst, "{}").unwrap();
+        assert_eq!(stored_etag(&dst).as_deref(), Some("\"abc\""));
+        store_etag(&dst, None);
+        assert_eq!(stored_etag(&dst), None);
+    }
+}
@@ -0,0 +1,1017 @@
+//! usync as a library: the copy engine behind the `usync` command.
+//!
+//! [`Usync`] runs one copy the way the command line does, from two path strings and
+//! the same settings as its flags:
+//!
+//! ```no_run
+//! use usync::checksum::Algorithm;
+//! use usync::Usync;
+//!
+//! let report = Usync::new("./photos/", "backup@nas:/srv/photos/")
+//!     .recursive(true)
+//!     .exclude("*.tmp")
+//!     .checksum(Algorithm::Sha256)
+//!     .run()?;
+//! println!("{} files in {:?}", report.files_copied, report.duration);
+//! # Ok::<(), usync::Error>(())
+//! ```
+//!
+//! Underneath, [`copy::copy`] copies between two [`protocol::Path`]s (local paths,
+//! `ssh://`, `s3://`, `http(s)://`) with [`copy::CopyOptions`]. Remote sides are handled
+//! by the usual tools (scp, ssh, aws, curl), which must be on `PATH`.
+//!
+//! With the `tokio` feature, [`nonblocking`] offers the same copy as an `async fn`, and
+//! with `rpc`, [`rpc`] serves copies to other programs over JSON lines.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use case::CaseCollisions;
+use chmod::Chmod;
+use copy::{Compare, CopyError, CopyMode, CopyOptions, Order, Preserve, SymlinkPolicy};
+use owner::Ownership;
+use protocol::Path as ProtocolPath;
+use rename::Template;
+use sanitize::NameRules;
+use verify::{Outcome, VerifyMode};
+
+pub mod append;
+pub mod archive;
+pub mod bench;
+pub mod case;
+pub mod checksum;
+pub mod chmod;
+pub mod copy;
+pub mod dedupe;
+pub mod delete;
+pub mod delta;
+pub mod diff;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod files_from;
+pub mod filter;
+pub mod http;
+pub mod limit;
+pub mod list;
+pub mod lock;
+pub mod mirror;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod output;
+pub mod owner;
+pub mod parse;
+pub mod path;
+pub mod priority;
+pub mod progress;
+pub mod protocol;
+pub mod remote;
+pub mod rename;
+pub mod report;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod sanitize;
+pub mod segment;
+pub mod state;
+pub mod stream;
+pub mod tag;
+pub mod tee;
+pub mod tools;
+pub mod transform;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod utils;
+pub mod verify;
+pub mod walk;
+
+pub use report::{FileError, Operation, Severity, TransferReport};
+
+/// One copy from a source to a destination, set up like the command line.
+///
+/// Each setter matches a flag of `usync SOURCE DEST`; nothing is checked until
+/// [`run`](Usync::run).
+#[derive(Debug, Clone)]
+pub struct Usync {
+    src: String,
+    dst: String,
+    opts: CopyOptions,
+    port: Option<u16>,
+    includes: Vec<String>,
+    excludes: Vec<String>,
+    rules: Vec<filter::Rule>,
+    filter_default: filter::FilterDefault,
+    checksum: Option<checksum::Algorithm>,
+    verify_mode: VerifyMode,
+    tag_checksums: bool,
+    remove_source: bool,
+    continue_on_error: Option<bool>,
+    lock: Option<bool>,
+    wait_lock: Option<Duration>,
+    state_file: Option<PathBuf>,
+    no_state: bool,
+}
+
+impl Usync {
+    /// A copy of `src` to `dst`, written as on the command line (`./dir/`,
+    /// `user@host:path`, `ssh://...`, `s3://...`, `https://...`).
+    pub fn new(src: impl Into<String>, dst: impl Into<String>) -> Self {
+        Self {
+            src: src.into(),
+            dst: dst.into(),
+            opts: CopyOptions::default(),
+            port: None,
+            includes: Vec::new(),
+            excludes: Vec::new(),
+            rules: Vec::new(),
+            filter_default: filter::FilterDefault::default(),
+            checksum: None,
+            verify_mode: VerifyMode::default(),
+            tag_checksums: false,
+            remove_source: false,
+            continue_on_error: None,
+            lock: None,
+            wait_lock: None,
+            state_file: None,
+            no_state: false,
+        }
+    }
+
+    /// `-v`
+    pub fn verbose(mut self, on: bool) -> Self {
+        self.opts.verbose = on;
+        self
+    }
+
+    /// `-p`
+    pub fn progress(mut self, on: bool) -> Self {
+        self.opts.progress = on;
+        self
+    }
+
+    /// `--progress-format`
+    pub fn progress_format(mut self, format: progress::Format) -> Self {
+        self.opts.progress_format = format;
+        self
+    }
+
+    /// `--ram` (`Some(true)`) or `--no-ram` (`Some(false)`); `None` goes by the file size.
+    pub fn use_ram(mut self, on: Option<bool>) -> Self {
+        self.opts.use_ram = on;
+        self
+    }
+
+    /// `--ram-threshold`: files smaller than this are copied through memory.
+    pub fn ram_threshold(mut self, size: u64) -> Self {
+        self.opts.ram_threshold = size;
+        self
+    }
+
+    /// `--direct-threshold`: files larger than this are copied by the kernel.
+    pub fn direct_threshold(mut self, size: u64) -> Self {
+        self.opts.direct_threshold = size;
+        self
+    }
+
+    /// `-r`: copy a directory source without asking.
+    pub fn recursive(mut self, on: bool) -> Self {
+        self.opts.recursive = on;
+        self
+    }
+
+    /// `-a`: recursive, keeping permissions, times and symlinks. Call [`preserve`] or
+    /// [`symlinks`] afterwards for the `--no-*` variants.
+    ///
+    /// [`preserve`]: Usync::preserve
+    /// [`symlinks`]: Usync::symlinks
+    pub fn archive(mut self, on: bool) -> Self {
+        if on {
+            self.opts.recursive = true;
+            self.opts.preserve = Preserve::all();
+            self.opts.symlinks = SymlinkPolicy::Preserve;
+        }
+        self
+    }
+
+    /// `--perms`, `--times`, `--xattrs`, `--acls`
+    pub fn preserve(mut self, preserve: Preserve) -> Self {
+        self.opts.preserve = preserve;
+        self
+    }
+
+    /// `--chown`, `--owner`, `--group`, `--usermap`, `--groupmap`
+    pub fn ownership(mut self, ownership: Ownership) -> Self {
+        self.opts.ownership = ownership;
+        self
+    }
+
+    /// `--chmod`
+    pub fn chmod(mut self, chmod: Chmod) -> Self {
+        self.opts.chmod = Some(chmod);
+        self
+    }
+
+    /// `--mkpath`
+    pub fn mkpath(mut self, on: bool) -> Self {
+        self.opts.mkpath = on;
+        self
+    }
+
+    /// `--specials`
+    pub fn specials(mut self, on: bool) -> Self {
+        self.opts.specials = on;
+        self
+    }
+
+    /// `--devices`
+    pub fn devices(mut self, on: bool) -> Self {
+        self.opts.devices = on;
+        self
+    }
+
+    /// `--case-collisions`
+    pub fn case_collisions(mut self, policy: CaseCollisions) -> Self {
+        self.opts.case_collisions = Some(policy);
+        self
+    }
+
+    /// `--sanitize-names`
+    pub fn sanitize_names(mut self, rules: NameRules) -> Self {
+        self.opts.sanitize_names = Some(rules);
+        self
+    }
+
+    /// `--rename`
+    pub fn rename(mut self, template: Template) -> Self {
+        self.opts.rename = Some(template);
+        self
+    }
+
+    /// `--files-from`: copy only `list`, paths relative to the source directory.
+    pub fn files_from(mut self, list: Vec<PathBuf>) -> Self {
+        self.opts.files_from = Some(list);
+        self
+    }
+
+    /// `--ignore-missing-args`
+    pub fn ignore_missing_args(mut self, on: bool) -> Self {
+        self.opts.ignore_missing_args = on;
+        self
+    }
+
+    /// `--links`
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.opts.symlinks = policy;
+        self
+    }
+
+    /// `--depth-limit`: fail on a local source directory more than `n` levels deep.
+    pub fn depth_limit(mut self, n: usize) -> Self {
+        self.opts.depth_limit = n;
+        self
+    }
+
+    /// `-s`: one more ssh option, e.g. `IdentityFile=~/.ssh/backup`.
+    pub fn ssh_opt(mut self, option: impl Into<String>) -> Self {
+        self.opts.ssh_opts.push(option.into());
+        self
+    }
+
+    /// `-P`: SSH port for remotes that don't give one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// `--no-ssh-multiplex` when `false`.
+    pub fn ssh_multiplex(mut self, on: bool) -> Self {
+        self.opts.ssh_multiplex = on;
+        self
+    }
+
+    /// `--ssh-batch`
+    pub fn ssh_batch(mut self, on: bool) -> Self {
+        self.opts.ssh_batch = on;
+        self
+    }
+
+    /// `-z`
+    pub fn compress(mut self, on: bool) -> Self {
+        self.opts.compress = on;
+        self
+    }
+
+    /// `--copy-mode`
+    pub fn copy_mode(mut self, mode: CopyMode) -> Self {
+        self.opts.copy_mode = mode;
+        self
+    }
+
+    /// `--order`
+    pub fn order(mut self, order: Order) -> Self {
+        self.opts.order = order;
+        self
+    }
+
+    /// `--exclude`
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.excludes.push(pattern.into());
+        self
+    }
+
+    /// `--include`
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.includes.push(pattern.into());
+        self
+    }
+
+    /// `--filter`: an rsync-style rule; the first rule matching an entry decides.
+    pub fn filter(mut self, rule: filter::Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// `--filter-default`: what happens to entries no [`filter`](Usync::filter) rule
+    /// matches.
+    pub fn filter_default(mut self, default: filter::FilterDefault) -> Self {
+        self.filter_default = default;
+        self
+    }
+
+    /// `--debug-filters`: print each filter decision of a directory copy to stderr.
+    pub fn debug_filters(mut self, on: bool) -> Self {
+        self.opts.filters.set_debug(on);
+        self
+    }
+
+    /// `--compare`
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.opts.compare = Some(compare);
+        self
+    }
+
+    /// `-H`
+    pub fn hard_links(mut self, on: bool) -> Self {
+        self.opts.hard_links = on;
+        self
+    }
+
+    /// `--link-dest`
+    pub fn link_dest(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.opts.link_dest = Some(dir.into());
+        self
+    }
+
+    /// `--delta`
+    pub fn delta(mut self, on: bool) -> Self {
+        self.opts.delta = on;
+        self
+    }
+
+    /// `--append`: copy only the new end of a local file that grew since its last copy.
+    pub fn append(mut self, on: bool) -> Self {
+        self.opts.append = on;
+        self
+    }
+
+    /// `--checksum=ALGO`: verify the copy afterwards.
+    pub fn checksum(mut self, algorithm: checksum::Algorithm) -> Self {
+        self.checksum = Some(algorithm);
+        self
+    }
+
+    /// `--verify-mode`
+    pub fn verify_mode(mut self, mode: VerifyMode) -> Self {
+        self.verify_mode = mode;
+        self
+    }
+
+    /// `--tag-checksums`: keep the digests a [`checksum`](Usync::checksum) run verified
+    /// on the copied files, for `usync checksum --cached`.
+    pub fn tag_checksums(mut self, on: bool) -> Self {
+        self.tag_checksums = on;
+        self
+    }
+
+    /// `--tmpdir`
+    pub fn tmpdir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.opts.tmpdir = Some(dir.into());
+        self
+    }
+
+    /// `--no-space-check` when `false`.
+    pub fn space_check(mut self, on: bool) -> Self {
+        self.opts.space_check = on;
+        self
+    }
+
+    /// `--fsync`: flush each file written locally to disk before counting it as copied.
+    pub fn fsync(mut self, on: bool) -> Self {
+        self.opts.fsync = on;
+        self
+    }
+
+    /// `--max-files`: stop rather than copy more than `n` files.
+    pub fn max_files(mut self, n: u64) -> Self {
+        self.opts.limits.max_files = Some(n);
+        self
+    }
+
+    /// `--max-total-size`: stop rather than copy more than `bytes` bytes.
+    pub fn max_total_size(mut self, bytes: u64) -> Self {
+        self.opts.limits.max_total_size = Some(bytes);
+        self
+    }
+
+    /// `--max-deletes`: stop a move or `--delete` that would remove more than `n` files.
+    pub fn max_deletes(mut self, n: u64) -> Self {
+        self.opts.limits.max_deletes = Some(n);
+        self
+    }
+
+    /// `--expect-content-type`: fail an HTTP download whose Content-Type doesn't start
+    /// with `prefix`.
+    pub fn expect_content_type(mut self, prefix: impl Into<String>) -> Self {
+        self.opts.http.expect_content_type = Some(prefix.into());
+        self
+    }
+
+    /// `--max-redirects`: fail an HTTP download that is redirected more than `n` times.
+    pub fn max_redirects(mut self, n: u32) -> Self {
+        self.opts.http.max_redirects = Some(n);
+        self
+    }
+
+    /// `--http-segments`: download an HTTP file as up to `n` byte ranges at once.
+    pub fn http_segments(mut self, n: u32) -> Self {
+        self.opts.http.segments = n;
+        self
+    }
+
+    /// `--http-mirror`: download the files an HTTP directory index links to, and the
+    /// directories below it.
+    pub fn http_mirror(mut self, on: bool) -> Self {
+        self.opts.http.mirror = on;
+        self
+    }
+
+    /// `--max-depth`: follow a mirrored index at most `n` levels down.
+    pub fn max_depth(mut self, n: usize) -> Self {
+        self.opts.http.max_depth = Some(n);
+        self
+    }
+
+    /// `--update`: download an HTTP file only if the server has a newer one than the
+    /// destination.
+    pub fn update(mut self, on: bool) -> Self {
+        self.opts.http.update = on;
+        self
+    }
+
+    /// `--delete`: remove destination files the source directory doesn't have.
+    pub fn delete(mut self, on: bool) -> Self {
+        self.opts.delete = on;
+        self
+    }
+
+    /// `--max-delete-ratio`: refuse a `--delete` that would remove more than `ratio`
+    /// (0 to 1) of the destination's files.
+    pub fn max_delete_ratio(mut self, ratio: f64) -> Self {
+        self.opts.max_delete_ratio = ratio;
+        self
+    }
+
+    /// `--force-delete`: carry out a `--delete` past `--max-delete-ratio`.
+    pub fn force_delete(mut self, on: bool) -> Self {
+        self.opts.force_delete = on;
+        self
+    }
+
+    /// `--prune-empty-dirs`: once a directory copy is done, remove the directories below
+    /// the destination that hold no files.
+    pub fn prune_empty_dirs(mut self, on: bool) -> Self {
+        self.opts.prune_empty_dirs = on;
+        self
+    }
+
+    /// `--detect-renames`: with `delete` and `compare`, move a destination file the
+    /// source no longer has to where the source has the same content under a new name,
+    /// instead of deleting it and copying that file again.
+    pub fn detect_renames(mut self, on: bool) -> Self {
+        self.opts.detect_renames = on;
+        self
+    }
+
+    /// `--soft-limit`: a limit reached is a warning in the report, not a failure.
+    pub fn soft_limit(mut self, on: bool) -> Self {
+        self.opts.limits.soft = on;
+        self
+    }
+
+    /// `--stats-detail`: keep the `n` slowest files, with their time and speed, in the
+    /// report.
+    pub fn stats_detail(mut self, n: usize) -> Self {
+        self.opts.stats_detail = Some(n);
+        self
+    }
+
+    /// `--no-ignore-vanished` when `false`.
+    pub fn ignore_vanished(mut self, on: bool) -> Self {
+        self.opts.ignore_vanished = on;
+        self
+    }
+
+    /// `--continue-on-error`/`--no-continue-on-error`: skip the files and directories of
+    /// a directory copy that can't be read, listing them in
+    /// [`TransferReport::errors`], instead of failing. On by default for syncs
+    /// (`--compare`).
+    pub fn continue_on_error(mut self, on: bool) -> Self {
+        self.continue_on_error = Some(on);
+        self
+    }
+
+    /// `-m`: remove the source (local or SSH) once everything was copied and, with
+    /// [`checksum`](Usync::checksum), the checksums matched; see [`copy::check_move`].
+    pub fn remove_source(mut self, on: bool) -> Self {
+        self.remove_source = on;
+        self
+    }
+
+    /// `-n`: list and count what the copy, [`delete`](Usync::delete) and
+    /// [`prune_empty_dirs`](Usync::prune_empty_dirs) would do, changing nothing. The
+    /// source must be local, and the destination local or on an SSH host.
+    pub fn dry_run(mut self, on: bool) -> Self {
+        self.opts.dry_run = on;
+        self
+    }
+
+    /// `--lock`/`--no-lock`: hold a lock on the destination while copying, so a second
+    /// usync for it fails with [`Error::Locked`]. On by default for syncs (`--compare`).
+    pub fn lock(mut self, on: bool) -> Self {
+        self.lock = Some(on);
+        self
+    }
+
+    /// `--wait-lock`: wait this long for the destination lock instead of failing.
+    pub fn wait_lock(mut self, wait: Duration) -> Self {
+        self.wait_lock = Some(wait);
+        self
+    }
+
+    /// `--state-file`: where [`checksum`](Usync::checksum) remembers the digests of a
+    /// directory copy between runs (default: the XDG cache directory).
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// `--no-state`: hash every file of a directory copy on each run.
+    pub fn no_state(mut self, on: bool) -> Self {
+        self.no_state = on;
+        self
+    }
+
+    /// The [`CopyOptions`] these settings amount to.
+    pub fn copy_options(&self) -> CopyOptions {
+        let mut opts = self.opts.clone();
+        opts.continue_on_error = self.continue_on_error.unwrap_or(opts.compare.is_some());
+        if !self.includes.is_empty() || !self.excludes.is_empty() {
+            opts.filters
+                .push(filter::PatternFilter::new(&self.includes, &self.excludes));
+        }
+        if !self.rules.is_empty() || self.filter_default == filter::FilterDefault::Exclude {
+            opts.filters.push(filter::OrderedPatternFilter::new(
+                &self.rules,
+                self.filter_default,
+            ));
+        }
+        opts
+    }
+
+    /// Check the settings, copy, verify with [`checksum`](Usync::checksum) and remove
+    /// the source for [`remove_source`](Usync::remove_source).
+    pub fn run(&self) -> Result<TransferReport, Error> {
+        let start = Instant::now();
+        let Prepared {
+            src,
+            dst,
+            opts,
+            lock: _lock,
+        } = self.prepare()?;
+
+        let mut report = copy::copy(&src, &dst, &opts).map_err(Error::Copy)?;
+        // A dry run wrote nothing to check.
+        if let Some(algorithm) = self.checksum.filter(|_| !opts.dry_run) {
+            report.verification = Some(self.verify(&src, &dst, algorithm, &opts)?);
+        }
+
+        // A source with files that weren't copied, a copy that wasn't verified when
+        // asked to be, or one stopped at a limit, is kept, and the report stays a copy.
+        let size = if self.remove_source && report.check_move().is_ok() {
+            let size = source_size(&src, &opts);
+            report.limit_reached = opts.limits.check_deletes(size.0 as u64).err();
+            size
+        } else {
+            (0, 0)
+        };
+        if self.remove_source && report.check_move().is_ok() {
+            report.operation = Operation::Move;
+            match copy::delete_source(&src, &opts, opts.dry_run) {
+                Ok(()) => {
+                    report.files_deleted += size.0;
+                    report.bytes_deleted += size.1;
+                }
+                Err(message) => report.add_error(&self.src, message),
+            }
+        } else if opts.compare.is_some() || opts.delete {
+            report.operation = Operation::Sync;
+        }
+        report.set_duration(start.elapsed());
+        Ok(report)
+    }
+
+    /// Parse the paths, check the settings and take the destination lock: everything
+    /// [`run`](Usync::run) does before it copies. The `rpc` server does the same.
+    pub(crate) fn prepare(&self) -> Result<Prepared, Error> {
+        let src = self.parse(&self.src, "source")?;
+        let dst = self.parse(&self.dst, "destination")?;
+        let opts = self.validate(&src)?;
+        let lock = if self.lock.unwrap_or(opts.compare.is_some()) {
+            lock::JobLock::acquire(&dst, self.wait_lock).map_err(Error::Locked)?
+        } else {
+            None
+        };
+        Ok(Prepared {
+            src,
+            dst,
+            opts,
+            lock,
+        })
+    }
+
+    fn parse(&self, arg: &str, side: &'static str) -> Result<ProtocolPath, Error> {
+        let mut path = protocol::parse_path(arg).map_err(|error| Error::Path { side, error })?;
+        if let (ProtocolPath::Remote(remote), Some(port)) = (&mut path, self.port) {
+            remote.set_default_port(port);
+        }
+        Ok(path)
+    }
+
+    /// Refuse settings that can't work together; drop, with a warning, those that
+    /// can't apply, as the command line does.
+    fn validate(&self, src: &ProtocolPath) -> Result<CopyOptions, Error> {
+        let mut opts = self.copy_options();
+        if opts.dry_run && !matches!(src, ProtocolPath::Local(_)) {
+            return Err(Error::Invalid("--dry-run needs a local source".to_string()));
+        }
+        if let ProtocolPath::Local(local) = src {
+            if local.is_dir() && !opts.recursive && opts.files_from.is_none() {
+                return Err(Error::Invalid(format!(
+                    "{} is a directory; copy it recursively",
+                    local.to_string_lossy()
+                )));
+            }
+        }
+        if opts.files_from.is_some() && self.checksum.is_some() {
+            return Err(Error::Invalid(
+                "--files-from can't be combined with --checksum, which checks the whole source \
+                 directory"
+                    .to_string(),
+            ));
+        }
+        if opts.rename.is_some() && self.checksum.is_some() {
+            return Err(Error::Invalid(
+                "--rename can't be combined with --checksum, which finds each copy under its \
+                 source's name"
+                    .to_string(),
+            ));
+        }
+        if opts.detect_renames && !(opts.delete && opts.compare.is_some()) {
+            return Err(Error::Invalid(
+                "--detect-renames needs --delete and --compare, which then skips the moved files"
+                    .to_string(),
+            ));
+        }
+        if let Some(dir) = opts.tmpdir.as_deref().filter(|d| !d.is_dir()) {
+            return Err(Error::Invalid(format!(
+                "temporary directory {} does not exist",
+                dir.display()
+            )));
+        }
+        if let Some(dir) = opts.link_dest.as_deref().filter(|d| !d.is_dir()) {
+            output::warn(format_args!(
+                "--link-dest {} is not a directory; copying everything",
+                dir.display()
+            ));
+            opts.link_dest = None;
+        }
+        if opts.copy_mode == CopyMode::Uring
+            && !cfg!(all(target_os = "linux", feature = "io-uring"))
+        {
+            output::warn("this build has no io_uring support; using the regular copy path");
+        }
+        Ok(opts)
+    }
+
+    /// The remembered digests for verifying a local directory copy, unless turned off.
+    fn state(
+        &self,
+        src: &ProtocolPath,
+        dst: &ProtocolPath,
+        algorithm: checksum::Algorithm,
+    ) -> Option<state::StateFile> {
+        let local_tree = matches!(src, ProtocolPath::Local(p) if p.is_dir())
+            && matches!(dst, ProtocolPath::Local(_));
+        if self.no_state || !local_tree {
+            return None;
+        }
+        let path = self
+            .state_file
+            .clone()
+            .or_else(|| state::StateFile::default_path(src, dst))?;
+        Some(state::StateFile::load(&path, algorithm))
+    }
+
+    /// Verify a finished copy. Failures that [`VerifyMode`] lets pass come back as
+    /// [`Outcome::Unverifiable`].
+    fn verify(
+        &self,
+        src: &ProtocolPath,
+        dst: &ProtocolPath,
+        algorithm: checksum::Algorithm,
+        opts: &CopyOptions,
+    ) -> Result<Outcome, Error> {
+        let mut state = self.state(src, dst, algorithm);
+        let result = verify::verify_copy(
+            src,
+            dst,
+            algorithm,
+            self.verify_mode,
+            &opts.ssh_opts,
+            opts.tmpdir.as_deref(),
+            state.as_mut(),
+        );
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => Outcome::Unverifiable(e.to_string()),
+        };
+        if outcome == Outcome::Match && self.tag_checksums {
+            self.tag(src, dst, algorithm, state.as_mut());
+        }
+        if let (Outcome::Match, Some(state)) = (&outcome, state) {
+            if let Err(e) = state.save() {
+                output::warn(format_args!(
+                    "could not save the sync state to {}: {}",
+                    state.path().display(),
+                    e
+                ));
+            }
+        }
+        match outcome {
+            Outcome::Mismatch(_) => Err(Error::Verification(outcome)),
+            Outcome::Unverifiable(_) if self.verify_mode == VerifyMode::Strict => {
+                Err(Error::Verification(outcome))
+            }
+            outcome => Ok(outcome),
+        }
+    }
+
+    /// Tag a verified local copy with its digests. A file left untagged only means
+    /// `usync checksum --cached` can't check it, so failures are warnings.
+    fn tag(
+        &self,
+        src: &ProtocolPath,
+        dst: &ProtocolPath,
+        algorithm: checksum::Algorithm,
+        state: Option<&mut state::StateFile>,
+    ) {
+        let Some(path) = verify::local_destination(src, dst) else {
+            output::warn("--tag-checksums only tags local destinations");
+            return;
+        };
+        let result = if path.is_dir() {
+            let digests = match state {
+                Some(state) => state.hash_tree("dst", &path),
+                None => checksum::hash_tree(&path, algorithm),
+            };
+            digests.and_then(|digests| tag::store_tree(&path, algorithm, &digests))
+        } else {
+            checksum::hash_file(&path, algorithm)
+                .and_then(|digest| tag::store(&path, algorithm, &digest))
+                .map(|stored| usize::from(stored == tag::Stored::Sidecar))
+        };
+        match result {
+            Ok(sidecars) if sidecars > 0 && self.opts.verbose => println!(
+                "{} has no extended attributes; {} checksum(s) kept in .usync-{} files",
+                path.display(),
+                sidecars,
+                algorithm.name()
+            ),
+            Ok(_) => {}
+            Err(e) => output::warn(format_args!(
+                "could not tag {} with its checksums: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The source or destination string isn't a path usync understands.
+    Path {
+        side: &'static str,
+        error: protocol::PathParseError,
+    },
+    /// Settings that can't be used together, or with these paths.
+    Invalid(String),
+    Copy(CopyError),
+    /// [`Usync::checksum`] found a mismatch, or couldn't check in strict mode.
+    Verification(Outcome),
+    /// Another usync holds the destination lock ([`Usync::lock`]).
+    Locked(lock::Held),
+}
+
+/// A transfer checked by [`Usync::prepare`], holding the destination lock until dropped.
+pub(crate) struct Prepared {
+    pub src: ProtocolPath,
+    pub dst: ProtocolPath,
+    pub opts: CopyOptions,
+    pub lock: Option<lock::JobLock>,
+}
+
+/// Files and bytes under a local or SSH source, before a move removes it.
+fn source_size(src: &ProtocolPath, opts: &CopyOptions) -> (usize, u64) {
+    let local = match src {
+        ProtocolPath::Local(local) => local,
+        ProtocolPath::Remote(remote)
+            if matches!(
+                remote.protocol,
+                protocol::Protocol::Ssh | protocol::Protocol::Sftp
+            ) =>
+        {
+            return remote::ssh_stat_tree(remote, &opts.ssh_opts)
+                .map_or((0, 0), |tree| (tree.sizes.len(), tree.total_size()))
+        }
+        ProtocolPath::Remote(_) => return (0, 0),
+    };
+    if !local.is_dir() {
+        return (1, std::fs::metadata(local.as_path()).map_or(0, |m| m.len()));
+    }
+    let (mut files, mut bytes) = (0, 0);
+    let _ = utils::walk_files(local.as_path(), None, |_, size| {
+        files += 1;
+        bytes += size;
+        std::ops::ControlFlow::Continue(())
+    });
+    (files, bytes)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Path { side, error } => write!(f, "Invalid {} path: {}", side, error),
+            Error::Invalid(msg) => write!(f, "{}", msg),
+            Error::Copy(e) => write!(f, "{}", e),
+            Error::Verification(Outcome::Mismatch(reason)) => {
+                write!(f, "Checksum mismatch: {}", reason)
+            }
+            Error::Verification(Outcome::Unverifiable(reason)) => {
+                write!(f, "could not verify copy: {}", reason)
+            }
+            Error::Verification(_) => write!(f, "verification failed"),
+            Error::Locked(held) => write!(f, "{}", held),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn tree() -> (TempDir, String, String) {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("sub/b.log"), "b").unwrap();
+        let dst = dir.path().join("dst");
+        let (src, dst) = (src.display().to_string(), dst.display().to_string());
+        (dir, src, dst)
+    }
+
+    #[test]
+    fn test_run_copies_and_verifies() {
+        let (_dir, src, dst) = tree();
+        let report = Usync::new(&src, &dst)
+            .recursive(true)
+            .checksum(checksum::Algorithm::Sha256)
+            .run()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{}/sub/b.log", dst)).unwrap(),
+            "b"
+        );
+        assert_eq!(report.verification, Some(Outcome::Match));
+        assert!(report.errors.is_empty());
+
+        let filtered = format!("{}-filtered", dst);
+        Usync::new(&src, &filtered)
+            .recursive(true)
+            .exclude("*.log")
+            .run()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(format!("{}/a.txt", filtered)).unwrap(),
+            "a"
+        );
+        assert!(!std::path::Path::new(&format!("{}/sub/b.log", filtered)).exists());
+    }
+
+    #[test]
+    fn test_checksum_reuses_the_state_file() {
+        let (dir, src, dst) = tree();
+        let state = dir.path().join("state");
+        let run = |no_state: bool| {
+            Usync::new(&src, &dst)
+                .recursive(true)
+                .compare(Compare::SizeMtime)
+                .checksum(checksum::Algorithm::Sha256)
+                .state_file(&state)
+                .no_state(no_state)
+                .lock(false)
+                .run()
+        };
+        assert_eq!(run(false).unwrap().verification, Some(Outcome::Match));
+        let saved = fs::read_to_string(&state).unwrap();
+        assert_eq!(saved.lines().count(), 5, "{}", saved);
+
+        // Same size and time: the remembered digest stands in for reading it again.
+        let copy = std::path::Path::new(&dst).join("a.txt");
+        let mtime = fs::metadata(&copy).unwrap().modified().unwrap();
+        fs::write(&copy, "z").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&copy)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert_eq!(run(false).unwrap().verification, Some(Outcome::Match));
+        assert!(matches!(run(true), Err(Error::Verification(_))));
+    }
+
+    #[test]
+    fn test_directory_needs_recursive() {
+        let (_dir, src, dst) = tree();
+        let err = Usync::new(&src, &dst).run().unwrap_err();
+        assert!(matches!(err, Error::Invalid(_)), "{}", err);
+        assert!(!std::path::Path::new(&dst).exists());
+    }
+
+    #[test]
+    fn test_invalid_combinations() {
+        let (_dir, src, dst) = tree();
+        let file = format!("{}/a.txt", src);
+        assert!(matches!(
+            Usync::new("user@host:/srv/a.txt", &dst).dry_run(true).run(),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            Usync::new(&file, &dst).tmpdir("/nonexistent/usync").run(),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            Usync::new("https://", &dst).run(),
+            Err(Error::Path { side: "source", .. })
+        ));
+    }
+
+    #[test]
+    fn test_remove_source() {
+        let (_dir, src, dst) = tree();
+        let file = format!("{}/a.txt", src);
+        let report = Usync::new(&file, &dst).remove_source(true).run().unwrap();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.operation, Operation::Move);
+        assert_eq!((report.files_deleted, report.bytes_deleted), (1, 1));
+        assert!(!std::path::Path::new(&file).exists());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_archive_then_overrides() {
+        let opts = Usync::new("a", "b")
+            .archive(true)
+            .symlinks(SymlinkPolicy::Follow)
+            .copy_options();
+        assert!(opts.recursive);
+        assert_eq!(opts.preserve, Preserve::all());
+        assert_eq!(opts.symlinks, SymlinkPolicy::Follow);
+    }
+}
@@ -0,0 +1,233 @@
+//! `--max-files`, `--max-total-size` and `--max-deletes`: safety rails for a job that
+//! would move far more than it was meant to, such as a whole NFS mount mirrored into a
+//! small disk.
+//!
+//! When the size of a copy is known up front (a local or SSH source, outside of
+//! `--compare` syncs, which only copy what changed), it is checked before anything is
+//! written. A local directory copy also keeps a running total and stops before the first
+//! file that would go over. Other copies are checked once they finish. Either way the
+//! transfer stops there; `--soft-limit` makes that a warning instead of a failure.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use crate::bench::format_size;
+
+/// How much one copy may transfer, and how much of the source a move may remove.
+///
+/// Clones share the running totals, so the workers of one copy charge the same budget;
+/// [`copy`](crate::copy::copy) starts each copy on a [`fresh`](Limits::fresh) one.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_files: Option<u64>,
+    /// Bytes.
+    pub max_total_size: Option<u64>,
+    /// Files a `--move` or `--delete` may remove.
+    pub max_deletes: Option<u64>,
+    /// Stopping at a limit is a warning, not a failure (`--soft-limit`).
+    pub soft: bool,
+    used: Arc<Usage>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    /// The first limit gone over; nothing is charged after it.
+    exceeded: OnceLock<Exceeded>,
+}
+
+/// Which limit a transfer went over, and what it was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Limit {
+    Files(u64),
+    TotalSize(u64),
+    Deletes(u64),
+}
+
+/// A transfer stopped by a limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Exceeded {
+    pub limit: Limit,
+    /// Files or bytes the transfer planned, or had reached when it stopped.
+    pub attempted: u64,
+    /// Whether the plan went over, so that nothing was copied.
+    pub planned: bool,
+    /// `--soft-limit` was given.
+    pub soft: bool,
+}
+
+impl Limits {
+    /// Whether there is a limit on what a copy transfers.
+    pub fn is_set(&self) -> bool {
+        self.max_files.is_some() || self.max_total_size.is_some()
+    }
+
+    /// The same limits with nothing charged yet.
+    pub fn fresh(&self) -> Self {
+        Limits {
+            used: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Check a copy of `files` files holding `bytes` bytes before it starts.
+    pub fn check_plan(&self, files: u64, bytes: u64) -> Result<(), Exceeded> {
+        self.check(files, bytes, true)
+    }
+
+    /// Count one more file of `bytes` bytes about to be copied. `false` means it would
+    /// go over a limit (or one was already reached): the file, and the rest of the copy,
+    /// is left out.
+    pub fn charge(&self, bytes: u64) -> bool {
+        if self.stopped() {
+            return false;
+        }
+        let files = self.used.files.fetch_add(1, Ordering::Relaxed) + 1;
+        let total = self.used.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        match self.check(files, total, false) {
+            Ok(()) => true,
+            Err(exceeded) => {
+                self.stop(exceeded);
+                false
+            }
+        }
+    }
+
+    /// Record that the copy stopped at `exceeded`; the first one recorded stands.
+    pub fn stop(&self, exceeded: Exceeded) {
+        let _ = self.used.exceeded.set(exceeded);
+    }
+
+    /// Whether the copy has stopped at a limit.
+    pub fn stopped(&self) -> bool {
+        self.used.exceeded.get().is_some()
+    }
+
+    /// The limit the copy stopped at, if any.
+    pub fn exceeded(&self) -> Option<Exceeded> {
+        self.used.exceeded.get().cloned()
+    }
+
+    /// Check a move or `--delete` that would remove `files` files.
+    pub fn check_deletes(&self, files: u64) -> Result<(), Exceeded> {
+        match self.max_deletes {
+            Some(max) if files > max => Err(self.exceeded_by(Limit::Deletes(max), files, true)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check(&self, files: u64, bytes: u64, planned: bool) -> Result<(), Exceeded> {
+        if let Some(max) = self.max_files.filter(|&max| files > max) {
+            return Err(self.exceeded_by(Limit::Files(max), files, planned));
+        }
+        if let Some(max) = self.max_total_size.filter(|&max| bytes > max) {
+            return Err(self.exceeded_by(Limit::TotalSize(max), bytes, planned));
+        }
+        Ok(())
+    }
+
+    fn exceeded_by(&self, limit: Limit, attempted: u64, planned: bool) -> Exceeded {
+        Exceeded {
+            limit,
+            attempted,
+            planned,
+            soft: self.soft,
+        }
+    }
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let how = if self.planned { "planned" } else { "attempted" };
+        match self.limit {
+            Limit::Files(max) => write!(
+                f,
+                "--max-files {} exceeded: {} files {}",
+                max, self.attempted, how
+            ),
+            Limit::TotalSize(max) => write!(
+                f,
+                "--max-total-size {} exceeded: {} ({} bytes) {}",
+                format_size(max),
+                format_size(self.attempted),
+                self.attempted,
+                how
+            ),
+            Limit::Deletes(max) => write!(
+                f,
+                "--max-deletes {} exceeded: {} files would be removed",
+                max, self.attempted
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let limits = Limits {
+            max_files: Some(10),
+            max_total_size: Some(1 << 20),
+            ..Limits::default()
+        };
+        assert!(limits.check_plan(10, 1 << 20).is_ok());
+        let exceeded = limits.check_plan(11, 0).unwrap_err();
+        assert_eq!(exceeded.limit, Limit::Files(10));
+        assert_eq!(
+            exceeded.to_string(),
+            "--max-files 10 exceeded: 11 files planned"
+        );
+        assert_eq!(
+            limits.check_plan(1, 3 << 20).unwrap_err().to_string(),
+            "--max-total-size 1 MiB exceeded: 3 MiB (3145728 bytes) planned"
+        );
+        assert!(Limits::default().check_plan(u64::MAX, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_running_total() {
+        let limits = Limits {
+            max_total_size: Some(100),
+            soft: true,
+            ..Limits::default()
+        };
+        let worker = limits.clone();
+        assert!(limits.charge(60));
+        assert!(worker.charge(40));
+        assert!(!limits.charge(1));
+        // Once stopped, even what would still fit is refused.
+        assert!(!worker.charge(0));
+        let exceeded = worker.exceeded().unwrap();
+        assert_eq!(exceeded.attempted, 101);
+        assert!(!exceeded.planned && exceeded.soft);
+
+        let next = limits.fresh();
+        assert!(!next.stopped());
+        assert!(next.charge(100));
+    }
+
+    #[test]
+    fn test_deletes() {
+        let limits = Limits {
+            max_deletes: Some(2),
+            ..Limits::default()
+        };
+        assert!(!limits.is_set());
+        assert!(limits.check_deletes(2).is_ok());
+        assert_eq!(
+            limits.check_deletes(3).unwrap_err().to_string(),
+            "--max-deletes 2 exceeded: 3 files would be removed"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! `usync list`: the files below a local directory, an SSH path or an S3 prefix, with
+//! their sizes and modification times, filtered, sorted and cut to the first N.
+//!
+//! Paths are relative to the listed directory with `/` separators, as filters see them.
+//! Each side is listed where it lives: a local walk, one `find` over ssh, and
+//! `aws s3api list-objects-v2` for S3. Sorting is stable, so entries that compare equal
+//! keep the listing order, and entries without a modification time sort last.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::copy::{self, CopyError};
+use crate::filter::FilterChain;
+use crate::protocol::{Path as ProtocolPath, Protocol};
+use crate::remote;
+use crate::utils;
+
+/// One listed file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative to the listed directory, `/`-separated; the file's name when a single
+    /// file was listed.
+    pub path: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch, when the side reports it.
+    pub modified: Option<i64>,
+}
+
+/// What `--sort` orders by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    /// Listing order (names sorted within each directory)
+    #[default]
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub filters: FilterChain,
+    /// `None` keeps the listing order.
+    pub sort: Option<SortKey>,
+    /// Largest, newest or last name first.
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub ssh_opts: Vec<String>,
+}
+
+/// List the files at `src` and apply the filters, sort and limit in `opts`.
+pub fn list(src: &ProtocolPath, opts: &ListOptions) -> Result<Vec<Entry>, CopyError> {
+    let mut entries = match src {
+        ProtocolPath::Local(local) => {
+            list_local(local.as_path(), &opts.filters).map_err(|e| CopyError::IoError {
+                message: format!("Failed to list {}", local.to_string_lossy()),
+                error: e,
+            })?
+        }
+        ProtocolPath::Remote(remote) => {
+            let mut entries = match remote.protocol {
+                Protocol::Ssh | Protocol::Sftp => remote::ssh_list_files(remote, &opts.ssh_opts),
+                Protocol::S3 => remote::s3_list_objects(remote),
+                _ => {
+                    return Err(CopyError::UnsupportedProtocol(format!(
+                        "Listing {} URLs is not supported",
+                        remote.protocol
+                    )))
+                }
+            }
+            .map_err(CopyError::from)?;
+            entries.retain(|e| opts.filters.includes_entry(&e.path));
+            entries
+        }
+    };
+    if let Some(key) = opts.sort {
+        sort(&mut entries, key, opts.reverse);
+    }
+    if let Some(limit) = opts.limit {
+        entries.truncate(limit);
+    }
+    Ok(entries)
+}
+
+/// Stable sort by `key`; entries without a modification time stay last either way.
+pub fn sort(entries: &mut [Entry], key: SortKey, reverse: bool) {
+    let direction = |ordering: Ordering| {
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    };
+    match key {
+        SortKey::Name => entries.sort_by(|a, b| direction(a.path.cmp(&b.path))),
+        SortKey::Size => entries.sort_by(|a, b| direction(a.size.cmp(&b.size))),
+        SortKey::Mtime => entries.sort_by(|a, b| match (a.modified, b.modified) {
+            (Some(a), Some(b)) => direction(a.cmp(&b)),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }),
+    }
+}
+
+fn modified(metadata: &fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    Some(match modified.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    })
+}
+
+/// The files below `root` that pass `filters`; excluded directories aren't read.
+fn list_local(root: &Path, filters: &FilterChain) -> std::io::Result<Vec<Entry>> {
+    let metadata = fs::metadata(root)?;
+    if !metadata.is_dir() {
+        let name = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !filters.includes_entry(&name) {
+            return Ok(Vec::new());
+        }
+        return Ok(vec![Entry {
+            path: name,
+            size: metadata.len(),
+            modified: modified(&metadata),
+        }]);
+    }
+    let tree = copy::list_upload_tree(root, filters)?;
+    Ok(tree
+        .files
+        .into_iter()
+        .map(|(rel, size)| Entry {
+            modified: fs::metadata(root.join(&rel))
+                .ok()
+                .as_ref()
+                .and_then(modified),
+            path: utils::slash_path(&rel),
+            size,
+        })
+        .collect())
+}
+
+/// `2024-03-01 12:00:00` (UTC), or `-` without a time.
+pub fn format_time(secs: Option<i64>) -> String {
+    let Some(secs) = secs else {
+        return "-".to_string();
+    };
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Seconds since the epoch for an RFC 3339 time as S3 reports it
+/// (`2024-03-01T12:00:00.000Z`, `2024-03-01T12:00:00+00:00`).
+pub fn parse_time(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = s.get(range)?;
+        part.bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| part.parse().ok())?
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !matches!(s.get(10..11), Some("T" | "t" | " ")) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let mut rest = s.get(19..)?;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        rest = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    let offset = match rest {
+        "Z" | "z" | "" => 0,
+        _ => {
+            let sign = match rest.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let (h, m) = rest.get(1..)?.split_once(':')?;
+            sign * (h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60)
+        }
+    };
+    // Civil date to days, the inverse of the conversion in `format_time`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::PatternFilter;
+
+    fn entry(path: &str, size: u64, modified: Option<i64>) -> Entry {
+        Entry {
+            path: path.to_string(),
+            size,
+            modified,
+        }
+    }
+
+    fn paths(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_is_stable_with_missing_mtimes_last() {
+        let listed = vec![
+            entry("a", 10, None),
+            entry("b", 30, Some(200)),
+            entry("c", 10, Some(100)),
+            entry("d", 20, None),
+            entry("e", 30, Some(300)),
+        ];
+
+        let mut entries = listed.clone();
+        sort(&mut entries, SortKey::Size, true);
+        assert_eq!(paths(&entries), ["b", "e", "d", "a", "c"]);
+        sort(&mut entries, SortKey::Size, false);
+        assert_eq!(paths(&entries), ["a", "c", "d", "b", "e"]);
+
+        let mut entries = listed.clone();
+        sort(&mut entries, SortKey::Mtime, false);
+        assert_eq!(paths(&entries), ["c", "b", "e", "a", "d"]);
+        sort(&mut entries, SortKey::Mtime, true);
+        assert_eq!(paths(&entries), ["e", "b", "c", "a", "d"]);
+
+        let mut entries = listed;
+        sort(&mut entries, SortKey::Name, true);
+        assert_eq!(paths(&entries), ["e", "d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_list_local_tree() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("logs/old")).unwrap();
+        fs::write(root.join("small.txt"), "x").unwrap();
+        fs::write(root.join("logs/big.log"), vec![b'x'; 300]).unwrap();
+        fs::write(root.join("logs/old/medium.log"), vec![b'x'; 200]).unwrap();
+        fs::write(root.join("logs/old/skip.tmp"), vec![b'x'; 900]).unwrap();
+
+        let src = crate::protocol::parse_path(root.to_str().unwrap()).unwrap();
+        let mut opts = ListOptions::default();
+        let all = list(&src, &opts).unwrap();
+        assert_eq!(
+            paths(&all),
+            [
+                "logs/big.log",
+                "logs/old/medium.log",
+                "logs/old/skip.tmp",
+                "small.txt"
+            ]
+        );
+        assert!(all.iter().all(|e| e.modified.is_some()));
+
+        opts.filters
+            .push(PatternFilter::new(&[], &["*.tmp".to_string()]));
+        opts.sort = Some(SortKey::Size);
+        opts.reverse = true;
+        opts.limit = Some(2);
+        let biggest = list(&src, &opts).unwrap();
+        assert_eq!(paths(&biggest), ["logs/big.log", "logs/old/medium.log"]);
+        assert_eq!(biggest[0].size, 300);
+
+        let file = crate::protocol::parse_path(root.join("small.txt").to_str().unwrap()).unwrap();
+        assert_eq!(
+            paths(&list(&file, &ListOptions::default()).unwrap()),
+            ["small.txt"]
+        );
+    }
+
+    #[test]
+    fn test_times() {
+        assert_eq!(format_time(Some(0)), "1970-01-01 00:00:00");
+        assert_eq!(format_time(Some(1_709_294_400)), "2024-03-01 12:00:00");
+        assert_eq!(format_time(Some(-1)), "1969-12-31 23:59:59");
+        assert_eq!(format_time(None), "-");
+
+        assert_eq!(parse_time("2024-03-01T12:00:00.000Z"), Some(1_709_294_400));
+        assert_eq!(parse_time("2024-03-01T12:00:00+00:00"), Some(1_709_294_400));
+        assert_eq!(parse_time("2024-03-01T14:30:00+02:30"), Some(1_709_294_400));
+        assert_eq!(parse_time("2000-02-29T00:00:00Z"), Some(951_782_400));
+        assert_eq!(parse_time("None"), None);
+        assert_eq!(parse_time("2024-13-01T00:00:00Z"), None);
+    }
+}
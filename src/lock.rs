@@ -0,0 +1,249 @@
+//! `--lock`: one usync at a time per destination.
+//!
+//! The lock is an advisory lock (`flock` on Unix) on a file in the state directory
+//! (`$XDG_STATE_HOME/usync/locks`, else `~/.local/state/usync/locks`), named after a
+//! hash of the destination, so local and remote destinations are handled alike and
+//! nothing is written into the tree being synced. The holder writes its PID and start
+//! time into the file for the message the next one prints. The operating system drops
+//! the lock when the holder exits, however it exits, so a stale file never blocks.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::checksum;
+use crate::list;
+use crate::output;
+use crate::protocol::Path as ProtocolPath;
+
+/// How often a `--wait-lock` wait tries again.
+const RETRY: Duration = Duration::from_millis(200);
+
+/// A held destination lock, released when dropped.
+#[derive(Debug)]
+pub struct JobLock {
+    file: File,
+}
+
+/// Another process holds the lock for the destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Held {
+    pub destination: String,
+    pub pid: Option<u32>,
+    /// When the holder started, in seconds since the epoch.
+    pub started: Option<i64>,
+}
+
+impl JobLock {
+    /// Lock the destination `dst`, waiting up to `wait` for another holder to finish.
+    ///
+    /// `Ok(None)` means there was nowhere to keep the lock file; the copy goes ahead
+    /// unlocked after a warning.
+    pub fn acquire(dst: &ProtocolPath, wait: Option<Duration>) -> Result<Option<JobLock>, Held> {
+        let destination = identity(dst);
+        let path = match lock_dir().and_then(|dir| {
+            fs::create_dir_all(&dir)?;
+            Ok(dir.join(lock_name(&destination)))
+        }) {
+            Ok(path) => path,
+            Err(e) => {
+                output::warn(format_args!(
+                    "cannot create a lock file ({}); not locking",
+                    e
+                ));
+                return Ok(None);
+            }
+        };
+        match Self::acquire_at(&path, wait) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(LockError::Held(mut file)) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+                let mut lines = contents.lines();
+                Err(Held {
+                    destination,
+                    pid: lines.next().and_then(|l| l.parse().ok()),
+                    started: lines.next().and_then(|l| l.parse().ok()),
+                })
+            }
+            Err(LockError::Io(e)) => {
+                output::warn(format_args!(
+                    "cannot lock {} ({}); not locking",
+                    path.display(),
+                    e
+                ));
+                Ok(None)
+            }
+        }
+    }
+
+    fn acquire_at(path: &Path, wait: Option<Duration>) -> Result<JobLock, LockError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(LockError::Io)?;
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {
+                    if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+                        return Err(LockError::Held(file));
+                    }
+                    thread::sleep(RETRY);
+                }
+                Err(TryLockError::Error(e)) => return Err(LockError::Io(e)),
+            }
+        }
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| writeln!(file, "{}\n{}", std::process::id(), started))
+            .map_err(LockError::Io)?;
+        Ok(JobLock { file })
+    }
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        // Closing the file would do it too; leave it behind empty for the next run.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+enum LockError {
+    Held(File),
+    Io(io::Error),
+}
+
+impl fmt::Display for Held {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "another usync is syncing this destination ({}",
+            self.destination
+        )?;
+        if let Some(pid) = self.pid {
+            write!(f, ", pid {}", pid)?;
+        }
+        if let Some(started) = self.started {
+            write!(f, ", started {} UTC", list::format_time(Some(started)))?;
+        }
+        write!(
+            f,
+            ")\n\nSuggestion: Wait for it to finish, or pass --wait-lock SECS to wait for it."
+        )
+    }
+}
+
+/// The destination as one string, the same however it was written.
+fn identity(dst: &ProtocolPath) -> String {
+    match dst {
+        ProtocolPath::Local(local) => {
+            let path = std::path::absolute(local.as_path())
+                .unwrap_or_else(|_| local.as_path().to_path_buf());
+            let path = fs::canonicalize(&path).unwrap_or(path);
+            path.components().collect::<PathBuf>().display().to_string()
+        }
+        ProtocolPath::Remote(remote) => remote.url.to_string().trim_end_matches('/').to_string(),
+    }
+}
+
+fn lock_name(destination: &str) -> String {
+    let digest = Sha256::digest(destination.as_bytes());
+    format!("{}.lock", checksum::to_hex(&digest[..16]))
+}
+
+/// `usync/locks` in the XDG state directory.
+fn lock_dir() -> io::Result<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no HOME or XDG_STATE_HOME"))?;
+    Ok(state.join("usync").join("locks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+
+    #[test]
+    fn test_second_holder_is_refused() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("a.lock");
+
+        let first = JobLock::acquire_at(&path, None).ok().unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(&format!("{}\n", std::process::id())));
+        assert!(matches!(
+            JobLock::acquire_at(&path, None),
+            Err(LockError::Held(_))
+        ));
+        // A short wait still runs out while the first holder keeps it.
+        assert!(matches!(
+            JobLock::acquire_at(&path, Some(Duration::from_millis(300))),
+            Err(LockError::Held(_))
+        ));
+
+        drop(first);
+        assert!(JobLock::acquire_at(&path, None).is_ok());
+    }
+
+    #[test]
+    fn test_wait_for_the_holder() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("a.lock");
+        let first = JobLock::acquire_at(&path, None).ok().unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(first);
+        });
+        assert!(JobLock::acquire_at(&path, Some(Duration::from_secs(10))).is_ok());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn test_identity() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dir = temp.path().display().to_string();
+        let plain = identity(&parse_path(&dir).unwrap());
+        assert_eq!(identity(&parse_path(&format!("{}/", dir)).unwrap()), plain);
+        assert_eq!(
+            identity(&parse_path(&format!("{}/./", dir)).unwrap()),
+            plain
+        );
+        assert_eq!(lock_name(&plain), lock_name(&plain));
+        assert_ne!(lock_name(&plain), lock_name("/elsewhere"));
+
+        assert_eq!(
+            identity(&parse_path("ssh://user@host/srv/backup/").unwrap()),
+            identity(&parse_path("ssh://user@host/srv/backup").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_held_message() {
+        let held = Held {
+            destination: "/mnt/backup".to_string(),
+            pid: Some(4242),
+            started: Some(1_700_000_000),
+        };
+        let message = held.to_string();
+        assert!(message.starts_with(
+            "another usync is syncing this destination (/mnt/backup, pid 4242, started 2023-11-14 22:13:20 UTC)"
+        ));
+    }
+}
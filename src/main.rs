@@ -1,17 +1,18 @@
-mod copy;
-mod path;
-mod protocol;
-mod remote;
-mod utils;
+use usync::{
+    archive, bench, case, checksum, chmod, copy, dedupe, delete, delta, diff, files_from, filter,
+    list, output, owner, path, priority, progress, protocol, remote, rename, sanitize, stream, tag,
+    tee, transform, verify, Operation, Severity, Usync,
+};
 
-use clap::Parser;
+use clap::builder::FalseyValueParser;
+use clap::{CommandFactory, Parser};
 
-use copy::copy;
+use copy::{CopyError, CopyMode, CopyOptions, Order, Preserve, SymlinkPolicy};
+use output::{ColorChoice, Stream};
+use owner::Ownership;
 use protocol::parse_path;
 use std::fs;
-
-#[cfg(feature = "color")]
-use colored::*;
+use verify::{Outcome, VerifyMode};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -63,9 +64,42 @@ EXAMPLES:
   usync -s "IdentityFile=~/.ssh/id_rsa" -s "StrictHostKeyChecking=no" \
         ssh://user@host:/path/file.txt ./local.txt
 
+  # Write and verify a checksum manifest (see `usync checksum --help`)
+  usync checksum ./mydir/ --output manifest.txt
+  usync checksum --verify manifest.txt ./mydir/
+
+  # Hard-link identical files in a tree (see `usync dedupe --help`)
+  usync dedupe ./backups/ --dry-run
+
+  # The 20 biggest files under a prefix (see `usync list --help`)
+  usync list s3://bucket/logs/ --sort size --reverse --limit 20
+
+  # What a sync would change, without copying (see `usync diff --help`)
+  usync diff ./site/ user@host:/var/www/site/
+
+  # Find the fastest copy strategy on this machine
+  usync bench --size 1G
+
+  # Shell completions and the man page, for packaging
+  usync completions bash > usync.bash
+  usync manpage > usync.1
+
+SUBCOMMANDS:
+  A first argument of checksum, list, diff, bench, dedupe, serve, completions,
+  manpage or delta-server runs that subcommand. To copy a relative source with
+  one of those names, write it as ./list or put -- first: usync -- list ./backup/
+
 ENVIRONMENT VARIABLES:
-  USYNC_VERBOSE    Enable verbose mode (any non-empty value)
-  USYNC_SSH_OPTS   SSH options (space-separated)
+  Used when the matching flag isn't given. Switches are off when unset, empty, or
+  0, false, no, off, n or f, and on for any other value.
+  USYNC_VERBOSE      -v, --verbose
+  USYNC_PROGRESS     -p, --progress
+  USYNC_COMPRESS     -z, --compress
+  USYNC_DRY_RUN      -n, --dry-run
+  USYNC_CHECKSUM     --checksum=ALGO
+  USYNC_VERIFY_MODE  --verify-mode
+  USYNC_TMPDIR       --tmpdir (empty means the system temp directory)
+  USYNC_SSH_OPTS     SSH options, space-separated, when no -s is given
 
 For more information, visit: https://github.com/yassinbousaadi/usync"#,
     after_help = r#"EXAMPLES:
@@ -106,257 +140,2408 @@ struct Args {
     dst: String,
 
     /// Enable verbose output
-    #[arg(short, long)]
+    #[arg(short, long, env = "USYNC_VERBOSE", value_parser = FalseyValueParser::new())]
     verbose: bool,
 
-    /// SSH options to pass to scp (can be used multiple times)
+    /// SSH options to pass to scp (can be used multiple times; default: the
+    /// space-separated options in $USYNC_SSH_OPTS)
     /// Example: --ssh-opt "IdentityFile=~/.ssh/id_rsa" --ssh-opt "StrictHostKeyChecking=no"
     #[arg(short = 's', long = "ssh-opt", value_name = "OPTION")]
     ssh_opts: Vec<String>,
 
+    /// SSH port for remotes that don't give one, e.g. `user@host:path` (like `scp -P`)
+    #[arg(short = 'P', long = "port", value_name = "PORT")]
+    port: Option<u16>,
+
+    /// Answer SSH password prompts with the first line of FILE (through SSH_ASKPASS), for
+    /// unattended copies to servers without key login. Keep the file private (chmod 600).
+    #[arg(
+        long = "ssh-password-file",
+        value_name = "FILE",
+        env = "USYNC_SSH_PASSWORD_FILE",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    ssh_password_file: Option<std::path::PathBuf>,
+
     /// Copy directories recursively
     #[arg(short = 'r', long = "recursive", alias = "rec")]
     recursive: bool,
 
+    /// Archive mode: recursive, preserve permissions and times, keep symlinks as symlinks.
+    /// Individual parts can be turned off with --no-perms, --no-times and --no-links.
+    #[arg(short = 'a', long = "archive")]
+    archive: bool,
+
+    /// Preserve permission bits on local destinations
+    #[arg(long = "perms")]
+    perms: bool,
+
+    /// Don't preserve permission bits (overrides -a)
+    #[arg(long = "no-perms")]
+    no_perms: bool,
+
+    /// Preserve modification and access times on local destinations
+    #[arg(short = 't', long = "times")]
+    times: bool,
+
+    /// Don't preserve times (overrides -a)
+    #[arg(long = "no-times")]
+    no_times: bool,
+
+    /// Copy symlinks as symlinks instead of copying what they point to
+    #[arg(short = 'l', long = "links")]
+    links: bool,
+
+    /// Follow symlinks (overrides -a)
+    #[arg(long = "no-links")]
+    no_links: bool,
+
+    /// Fail on a local source directory nested more than N levels deep (default: 256).
+    /// Directories a symlink leads back to are skipped either way.
+    #[arg(long = "depth-limit", value_name = "N")]
+    depth_limit: Option<usize>,
+
+    /// Preserve extended attributes (and resource forks/ACLs on macOS) on local destinations
+    #[arg(short = 'X', long = "xattrs")]
+    xattrs: bool,
+
+    /// Preserve POSIX ACLs (access and directory default ACLs) on local destinations
+    #[arg(short = 'A', long = "acls")]
+    acls: bool,
+
+    /// Create missing parent directories of the destination (always done with -r)
+    #[arg(long = "mkpath")]
+    mkpath: bool,
+
+    /// Recreate FIFOs and sockets in local destinations instead of skipping them
+    #[arg(long = "specials")]
+    specials: bool,
+
+    /// Recreate character and block devices in local destinations (needs root)
+    #[arg(long = "devices")]
+    devices: bool,
+
+    /// Same as --devices --specials
+    #[arg(short = 'D')]
+    devices_and_specials: bool,
+
+    /// Check directory copies for names that only differ in case (README and readme),
+    /// which overwrite each other on case-insensitive filesystems
+    #[arg(long = "case-collisions", value_name = "ACTION", value_enum)]
+    case_collisions: Option<case::CaseCollisions>,
+
+    /// Rewrite names the destination can't store: illegal characters become _, trailing
+    /// spaces and dots go, long names are cut to 255 bytes (RULES: windows, fat,
+    /// custom:<chars>)
+    #[arg(
+        long = "sanitize-names",
+        value_name = "RULES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "windows",
+        value_parser = sanitize::NameRules::parse
+    )]
+    sanitize_names: Option<sanitize::NameRules>,
+
+    /// Name each copied file from TEMPLATE, e.g. "{stem}_{mtime}.{ext}": {name}, {stem},
+    /// {ext}, {date} (today), {mtime}, {size} and {counter}; times are UTC. Names that
+    /// end up the same follow --case-collisions (default: rename)
+    #[arg(
+        long = "rename",
+        value_name = "TEMPLATE",
+        value_parser = rename::Template::parse,
+        conflicts_with_all = ["compare", "delete", "checksum", "also_to", "gzip", "zstd", "decompress"]
+    )]
+    rename: Option<rename::Template>,
+
+    /// Same as --sanitize-names=fat, for FAT32/exFAT drives
+    #[arg(long = "fat-safe", conflicts_with = "sanitize_names")]
+    fat_safe: bool,
+
+    /// Character that replaces the ones --sanitize-names rejects
+    #[arg(long = "sanitize-char", value_name = "CHAR", default_value_t = '_')]
+    sanitize_char: char,
+
+    /// Override destination permissions: FILEMODE[,DIRMODE], or clauses like D755,F644 or go-w
+    #[arg(long = "chmod", value_name = "MODES", value_parser = chmod::Chmod::parse)]
+    chmod: Option<chmod::Chmod>,
+
+    /// Give every local destination file and directory this owner (USER:GROUP, USER or :GROUP)
+    #[arg(long = "chown", value_name = "USER:GROUP", value_parser = owner::parse_chown)]
+    chown: Option<owner::Chown>,
+
+    /// Give local copies the source's owner (needs root)
+    #[arg(long = "owner")]
+    owner: bool,
+
+    /// Give local copies the source's group
+    #[arg(long = "group")]
+    group: bool,
+
+    /// Translate source user IDs for --owner (OLD:NEW, by name or ID; repeatable)
+    #[arg(long = "usermap", value_name = "OLD:NEW", value_delimiter = ',', requires = "owner", value_parser = owner::parse_user_pair)]
+    usermap: Vec<(u32, u32)>,
+
+    /// Translate source group IDs for --group (OLD:NEW, by name or ID; repeatable)
+    #[arg(long = "groupmap", value_name = "OLD:NEW", value_delimiter = ',', requires = "group", value_parser = owner::parse_group_pair)]
+    groupmap: Vec<(u32, u32)>,
+
     /// Show progress during copy
-    #[arg(short = 'p', long = "progress")]
+    #[arg(
+        short = 'p',
+        long = "progress",
+        env = "USYNC_PROGRESS",
+        value_parser = FalseyValueParser::new()
+    )]
     progress: bool,
 
-    /// Copy via RAM (load entire file into memory first). Useful for small files or ensuring data integrity.
-    /// Warning: Uses more memory, not recommended for very large files.
-    #[arg(long = "ram", alias = "memory")]
+    /// How --progress reports directory uploads: `bar`, or `json` for one event per line
+    /// on stderr (files compared, then files and bytes sent)
+    #[arg(
+        long = "progress-format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = progress::Format::Bar,
+        env = "USYNC_PROGRESS_FORMAT"
+    )]
+    progress_format: progress::Format,
+
+    /// Time each file and list the N slowest (default 10) with their speed after the copy
+    #[arg(
+        long = "stats-detail",
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10"
+    )]
+    stats_detail: Option<usize>,
+
+    /// Copy every file via RAM (load it whole into memory first), not just those under
+    /// --ram-threshold. Warning: not recommended for very large files.
+    #[arg(long = "ram", alias = "memory", conflicts_with = "no_ram")]
     use_ram: bool,
 
+    /// Never copy via RAM, not even files under --ram-threshold
+    #[arg(long = "no-ram")]
+    no_ram: bool,
+
+    /// Files smaller than this are copied via RAM, unless --ram or --no-ram is given
+    #[arg(
+        long = "ram-threshold",
+        value_name = "SIZE",
+        env = "USYNC_RAM_THRESHOLD",
+        default_value = "1M",
+        value_parser = bench::parse_size
+    )]
+    ram_threshold: u64,
+
+    /// Files larger than this are copied by the kernel (sendfile on Linux, copyfile on
+    /// macOS, CopyFileEx on Windows); files in between use a buffered loop
+    #[arg(
+        long = "direct-threshold",
+        value_name = "SIZE",
+        env = "USYNC_DIRECT_THRESHOLD",
+        default_value = "64M",
+        value_parser = bench::parse_size
+    )]
+    direct_threshold: u64,
+
+    /// How files of a local directory copy are copied. `uring` batches the work through
+    /// io_uring (Linux, built with --features io-uring) and falls back on older kernels.
+    #[arg(long = "copy-mode", value_enum, default_value_t = CopyMode::Auto)]
+    copy_mode: CopyMode,
+
+    /// Order the files of a local directory copy are sent in. Anything but `default`
+    /// lists the whole tree first, then copies from one queue in that order.
+    #[arg(long = "order", value_enum, default_value_t = Order::Default)]
+    order: Order,
+
+    /// Run usync and the ssh, scp, aws and curl it starts at this niceness, from -20 to 19
+    /// (below-normal priority on Windows when positive)
+    #[arg(long = "nice", value_name = "N", value_parser = priority::parse_nice, allow_negative_numbers = true)]
+    nice: Option<i32>,
+
+    /// Run usync and the tools it starts in this I/O scheduling class: idle,
+    /// best-effort[:0-7] or realtime[:0-7] (Linux)
+    #[arg(long = "ionice", value_name = "CLASS[:LEVEL]", value_parser = priority::IoNice::parse)]
+    ionice: Option<priority::IoNice>,
+
+    /// Skip files and directories matching PATTERN in directory copies (can be used
+    /// multiple times). `*.map` matches at any depth, `build/` only directories,
+    /// `/dist` or `static/*.css` paths relative to the source directory.
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Only copy files matching PATTERN (can be used multiple times); excludes still win
+    #[arg(long = "include", value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// An rsync-style filter rule, '+ PATTERN' or '- PATTERN' (or '!' to clear the
+    /// rules so far; can be used multiple times). Rules are tried in order and the first
+    /// that matches decides, so '+ /src/***' then '- *' copies only src
+    #[arg(long = "filter", value_name = "RULE", value_parser = filter::Rule::parse, allow_hyphen_values = true)]
+    filter: Vec<filter::Rule>,
+
+    /// Read --filter rules from FILE, one per line ('#' and ';' start comments); its
+    /// rules come before those of --filter
+    #[arg(long = "filter-file", value_name = "FILE")]
+    filter_file: Vec<std::path::PathBuf>,
+
+    /// Whether files and directories no --filter rule matches are copied
+    #[arg(long = "filter-default", value_name = "WHAT", value_enum, default_value_t = filter::FilterDefault::Include)]
+    filter_default: filter::FilterDefault,
+
+    /// Print, for every path a directory copy looks at, whether the filters include it
+    /// and which one left it out
+    #[arg(long = "debug-filters")]
+    debug_filters: bool,
+
+    /// Copy only the files FILE lists (- for standard input), one path per line relative
+    /// to the source directory, to the same paths below the destination; the source
+    /// isn't walked
+    #[arg(
+        long = "files-from",
+        value_name = "FILE",
+        conflicts_with_all = ["checksum", "also_to"]
+    )]
+    files_from: Option<String>,
+
+    /// The --files-from list is separated by NUL bytes, as find -print0 writes it
+    #[arg(long = "from0", requires = "files_from")]
+    from0: bool,
+
+    /// Warn about and skip --files-from entries the source doesn't have, instead of failing
+    #[arg(long = "ignore-missing-args", requires = "files_from")]
+    ignore_missing_args: bool,
+
+    /// Skip files of a directory copy that already match at the destination:
+    /// size-mtime (same size, destination not older), size-only or existence. Without a
+    /// time on either side, size-mtime compares sizes only
+    #[arg(long = "compare", value_name = "MODE", value_enum)]
+    compare: Option<copy::Compare>,
+
+    /// Lock the destination while copying so a second usync for it stops with exit code
+    /// 75 (on by default with --compare)
+    #[arg(long = "lock", overrides_with = "no_lock")]
+    lock: bool,
+
+    /// Don't lock the destination, even with --compare
+    #[arg(long = "no-lock")]
+    no_lock: bool,
+
+    /// Remember the --checksum digests of a local directory copy in FILE, so the next run
+    /// only hashes files whose size or time changed (default: under ~/.cache/usync)
+    #[arg(long = "state-file", value_name = "FILE", conflicts_with = "no_state")]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Hash every file again for --checksum instead of reusing remembered digests
+    #[arg(long = "no-state")]
+    no_state: bool,
+
+    /// Wait up to SECS for another usync to release the destination lock
+    #[arg(long = "wait-lock", value_name = "SECS")]
+    wait_lock: Option<u64>,
+
+    /// Preserve hard links: files that share an inode in the source share one at the destination
+    #[arg(short = 'H', long = "hard-links")]
+    hard_links: bool,
+
+    /// Hard-link files that are unchanged (same size and mtime) in DIR instead of copying
+    /// them, for incremental snapshot backups
+    #[arg(long = "link-dest", value_name = "DIR")]
+    link_dest: Option<std::path::PathBuf>,
+
+    /// Update an existing destination file by sending only the blocks that changed
+    /// (rsync algorithm). Local and SSH file copies; SSH needs usync on the remote host.
+    #[arg(long = "delta")]
+    delta: bool,
+
+    /// When a local destination file is shorter than its source and matches its start,
+    /// copy only the rest, as for log files that grow; other files are copied whole
+    #[arg(
+        long = "append",
+        conflicts_with_all = ["delta", "also_to", "gzip", "zstd", "decompress"]
+    )]
+    append: bool,
+
     /// Move files instead of copying (removes source after successful copy)
     #[arg(short = 'm', long = "move")]
     move_files: bool,
+
+    /// Also copy a local source file to DEST (can be used multiple times). The source is
+    /// read once and written to every destination; each one succeeds or fails on its own.
+    #[arg(long = "also-to", value_name = "DEST")]
+    also_to: Vec<String>,
+
+    /// Disable SSH connection sharing (ControlMaster) between scp/ssh calls.
+    /// Use this for servers that forbid multiplexed sessions.
+    #[arg(long = "no-ssh-multiplex")]
+    no_ssh_multiplex: bool,
+
+    /// Upload directories over SSH as a single tar stream instead of one scp per file.
+    /// Enabled automatically for directories with many small files.
+    #[arg(long = "ssh-batch")]
+    ssh_batch: bool,
+
+    /// Compress data in transit (scp/ssh -C, HTTP Accept-Encoding). Helps on slow links.
+    #[arg(
+        short = 'z',
+        long = "compress",
+        alias = "compressed",
+        env = "USYNC_COMPRESS",
+        value_parser = FalseyValueParser::new()
+    )]
+    compress: bool,
+
+    /// Fail an HTTP download whose Content-Type doesn't start with PREFIX
+    /// (e.g. application/), removing what was saved
+    #[arg(long = "expect-content-type", value_name = "PREFIX")]
+    expect_content_type: Option<String>,
+
+    /// Fail an HTTP download that is redirected more than N times
+    #[arg(long = "max-redirects", value_name = "N")]
+    max_redirects: Option<u32>,
+
+    /// Download an HTTP file only if it changed since the copy at the destination
+    /// (If-Modified-Since, and If-None-Match with the ETag kept from last time)
+    #[arg(short = 'u', long = "update")]
+    update: bool,
+
+    /// Download an HTTP file as N byte ranges at once, when the server allows it
+    #[arg(long = "http-segments", value_name = "N")]
+    http_segments: Option<u32>,
+
+    /// Copy an HTTP directory index (Apache, nginx, python -m http.server): the files
+    /// it links to, and its subdirectories
+    #[arg(long = "http-mirror")]
+    http_mirror: bool,
+
+    /// With --http-mirror, follow the index at most N levels down (1: its own files)
+    #[arg(long = "max-depth", value_name = "N", requires = "http_mirror")]
+    max_depth: Option<usize>,
+
+    /// After copying, compare checksums of source and destination (sha256 unless
+    /// --checksum=ALGO). Each side is hashed where it lives, e.g. on the SSH host.
+    #[arg(
+        long = "checksum",
+        value_name = "ALGO",
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "sha256",
+        env = "USYNC_CHECKSUM"
+    )]
+    checksum: Option<checksum::Algorithm>,
+
+    /// What --checksum does when a side can't produce the digest (S3 ETags, HTTP):
+    /// `best-effort` compares sizes with a warning, `strict` downloads and hashes it
+    #[arg(
+        long = "verify-mode",
+        value_enum,
+        default_value_t = VerifyMode::BestEffort,
+        env = "USYNC_VERIFY_MODE"
+    )]
+    verify_mode: VerifyMode,
+
+    /// Keep each verified digest on the copied file, in a user.usync.ALGO extended
+    /// attribute or a .NAME.usync-ALGO file, for `usync checksum --cached`
+    #[arg(long = "tag-checksums", requires = "checksum")]
+    tag_checksums: bool,
+
+    /// Directory for temporary files: relay copies between SSH hosts, delta scratch
+    /// files, downloads for --verify-mode strict (default: the system temp directory)
+    #[arg(
+        long = "tmpdir",
+        value_name = "DIR",
+        env = "USYNC_TMPDIR",
+        value_hint = clap::ValueHint::DirPath
+    )]
+    tmpdir: Option<std::ffi::OsString>,
+
+    /// Don't check for enough free space at the destination (and --tmpdir) before
+    /// copying
+    #[arg(long = "no-space-check")]
+    no_space_check: bool,
+
+    /// Compress the file with gzip on the way (automatic for a .gz destination)
+    #[arg(long = "gzip")]
+    gzip: bool,
+
+    /// Compress the file with zstd on the way (automatic for a .zst destination)
+    #[arg(long = "zstd")]
+    zstd: bool,
+
+    /// Decompress a .gz or .zst file on the way
+    #[arg(long = "decompress")]
+    decompress: bool,
+
+    /// Only list what would be copied, deleted, pruned, archived or extracted
+    /// (local sources; local or SSH destinations)
+    #[arg(
+        short = 'n',
+        long = "dry-run",
+        env = "USYNC_DRY_RUN",
+        value_parser = FalseyValueParser::new()
+    )]
+    dry_run: bool,
+
+    /// Flush each file written locally to disk before counting it as copied
+    #[arg(long = "fsync")]
+    fsync: bool,
+
+    /// Fail when a file disappears while a directory is being copied
+    /// (by default it is skipped with a warning and the exit code is 24)
+    #[arg(long = "no-ignore-vanished")]
+    no_ignore_vanished: bool,
+
+    /// Skip files and directories that can't be read (permission denied), list them in
+    /// the summary and exit with code 23 (on by default with --compare)
+    #[arg(long = "continue-on-error", overrides_with = "no_continue_on_error")]
+    continue_on_error: bool,
+
+    /// Stop at the first file or directory that can't be read, even with --compare
+    #[arg(long = "no-continue-on-error")]
+    no_continue_on_error: bool,
+
+    /// Stop, and fail, rather than copy more than N files
+    #[arg(long = "max-files", value_name = "N")]
+    max_files: Option<u64>,
+
+    /// Stop, and fail, rather than copy more than SIZE (e.g. 500M, 20G)
+    #[arg(long = "max-total-size", value_name = "SIZE", value_parser = bench::parse_size)]
+    max_total_size: Option<u64>,
+
+    /// Stop a --move or --delete that would remove more than N files
+    #[arg(long = "max-deletes", value_name = "N")]
+    max_deletes: Option<u64>,
+
+    /// Remove files from the destination directory that the source no longer has
+    #[arg(long = "delete")]
+    delete: bool,
+
+    /// Refuse a --delete that would remove more than this share of the destination's
+    /// files (a fraction or a percentage, default 50%)
+    #[arg(long = "max-delete-ratio", value_name = "RATIO", value_parser = delete::parse_ratio)]
+    max_delete_ratio: Option<f64>,
+
+    /// Carry out a --delete past --max-delete-ratio
+    #[arg(long = "force-delete", requires = "delete")]
+    force_delete: bool,
+
+    /// After a directory copy, remove the directories at the destination that hold no
+    /// files, such as those left by excluded files (never the destination itself)
+    #[arg(long = "prune-empty-dirs")]
+    prune_empty_dirs: bool,
+
+    /// With --delete and --compare, move a destination file the source no longer has to
+    /// where the source has a file of the same size and SHA-256, instead of copying it
+    /// again
+    #[arg(long = "detect-renames", requires_all = ["delete", "compare"])]
+    detect_renames: bool,
+
+    /// Reaching --max-files, --max-total-size or --max-deletes stops with a warning
+    /// instead of an error
+    #[arg(long = "soft-limit")]
+    soft_limit: bool,
+
+    /// When to color messages: on terminals only (auto, also turned off by NO_COLOR),
+    /// always or never
+    #[arg(long = "color", value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Never color messages (same as --color never)
+    #[arg(long = "no-color")]
+    no_color: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+/// `usync checksum`: write or verify a sha256sum-compatible manifest of a tree.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync checksum",
+    about = "Write or verify a checksum manifest of a file or directory",
+    after_help = r#"The manifest holds "<hash>  <relative/path>" lines, so it can also be checked with
+`cd <path> && sha256sum -c manifest.txt` (or md5sum/sha1sum/sha512sum).
 
-    let env_verbose = std::env::var("USYNC_VERBOSE")
-        .map(|v| !v.is_empty() && v != "0" && v.to_lowercase() != "false")
-        .unwrap_or(false);
-    let verbose = args.verbose || env_verbose;
+EXAMPLES:
+  usync checksum ./photos/ --output photos.sha256
+  usync checksum ./photos/ --algorithm sha512 > photos.sha512
+  usync checksum --verify photos.sha256 ./photos/
+  cat photos.sha256 | usync checksum --verify - ./photos/
+  usync checksum --cached /backup/photos/"#
+)]
+struct ChecksumArgs {
+    #[arg(value_name = "PATH")]
+    path: String,
 
-    let src_path = match parse_path(&args.src) {
-        Ok(path) => path,
-        Err(e) => {
-            #[cfg(feature = "color")]
-            eprintln!("{}: {}", "Error parsing source path".red().bold(), e);
-            #[cfg(not(feature = "color"))]
-            eprintln!("Error parsing source path: {}", e);
-            std::process::exit(1);
-        }
-    };
+    /// Hash algorithm (default sha256; inferred from the manifest when verifying)
+    #[arg(short = 'a', long = "algorithm", value_enum)]
+    algorithm: Option<checksum::Algorithm>,
 
-    let dst_path = match parse_path(&args.dst) {
-        Ok(path) => path,
+    /// Write the manifest to FILE instead of stdout (`-` for stdout)
+    #[arg(
+        short = 'o',
+        long = "output",
+        value_name = "FILE",
+        conflicts_with = "verify"
+    )]
+    output: Option<String>,
+
+    /// Check PATH against MANIFEST (`-` for stdin) and report missing, extra and
+    /// mismatching files; exits non-zero on any difference
+    #[arg(long = "verify", value_name = "MANIFEST")]
+    verify: Option<String>,
+
+    /// Only hash files at most N levels below PATH (1: PATH's own files)
+    #[arg(long = "max-depth", value_name = "N", conflicts_with = "verify")]
+    max_depth: Option<usize>,
+
+    /// Check the files under PATH against the checksums `--tag-checksums` kept on them,
+    /// without the source; exits non-zero if any changed
+    #[arg(long = "cached", conflicts_with_all = ["output", "verify", "max_depth"])]
+    cached: bool,
+}
+
+/// Run `usync checksum` and return the process exit code.
+fn run_checksum(args: ChecksumArgs) -> i32 {
+    use std::io::{BufReader, Write};
+
+    let root = match path::LocalPath::parse(&args.path) {
+        Ok(p) => p,
         Err(e) => {
-            #[cfg(feature = "color")]
-            eprintln!("{}: {}", "Error parsing destination path".red().bold(), e);
-            #[cfg(not(feature = "color"))]
-            eprintln!("Error parsing destination path: {}", e);
-            std::process::exit(1);
+            output::error(e);
+            return 2;
         }
     };
+    if !root.exists() {
+        output::error(format_args!(
+            "Source path does not exist: {}",
+            root.to_string_lossy()
+        ));
+        return 2;
+    }
 
-    let is_dir = match &src_path {
-        protocol::Path::Local(local_path) => {
-            if !local_path.exists() {
-                #[cfg(feature = "color")]
-                eprintln!(
-                    "{}: {}",
-                    "Error".red().bold(),
-                    format!(
-                        "Source path does not exist: {}",
-                        local_path.to_string_lossy()
-                    )
+    if let Some(manifest) = &args.verify {
+        let reader: Result<Box<dyn std::io::BufRead>, std::io::Error> = if manifest == "-" {
+            Ok(Box::new(std::io::stdin().lock()))
+        } else {
+            fs::File::open(manifest).map(|f| Box::new(BufReader::new(f)) as Box<_>)
+        };
+        let result = reader
+            .and_then(|mut r| checksum::verify_manifest(&mut r, root.as_path(), args.algorithm));
+        return match result {
+            Ok(report) => {
+                for name in &report.mismatched {
+                    println!("FAILED: {}", name);
+                }
+                for name in &report.missing {
+                    println!("MISSING: {}", name);
+                }
+                for name in &report.extra {
+                    println!("EXTRA: {}", name);
+                }
+                println!(
+                    "{} OK, {} failed, {} missing, {} extra",
+                    report.matched,
+                    report.mismatched.len(),
+                    report.missing.len(),
+                    report.extra.len()
                 );
-                #[cfg(not(feature = "color"))]
-                eprintln!(
-                    "Error: Source path does not exist: {}",
-                    local_path.to_string_lossy()
+                if report.is_clean() {
+                    0
+                } else {
+                    1
+                }
+            }
+            Err(e) => {
+                output::error(format_args!("Failed to verify {}: {}", manifest, e));
+                2
+            }
+        };
+    }
+
+    let algorithm = args.algorithm.unwrap_or_default();
+    if args.cached {
+        return match tag::check_tree(root.as_path(), algorithm) {
+            Ok(checked) => {
+                for name in &checked.mismatched {
+                    println!("FAILED: {}", name);
+                }
+                println!(
+                    "{} OK, {} failed, {} untagged",
+                    checked.matched,
+                    checked.mismatched.len(),
+                    checked.untagged
                 );
-                std::process::exit(1);
+                if checked.mismatched.is_empty() {
+                    0
+                } else {
+                    1
+                }
             }
-            local_path.is_dir()
-        }
-        protocol::Path::Remote(_) => false,
+            Err(e) => {
+                output::error(format_args!("Failed to check {}: {}", args.path, e));
+                2
+            }
+        };
+    }
+    let result = match args.output.as_deref() {
+        None | Some("-") => checksum::write_manifest(
+            root.as_path(),
+            algorithm,
+            args.max_depth,
+            &mut std::io::stdout().lock(),
+        ),
+        Some(file) => fs::File::create(file).and_then(|f| {
+            let mut out = std::io::BufWriter::new(f);
+            let count =
+                checksum::write_manifest(root.as_path(), algorithm, args.max_depth, &mut out)?;
+            out.flush()?;
+            Ok(count)
+        }),
     };
+    match result {
+        Ok(count) => {
+            if let Some(file) = args.output.as_deref().filter(|f| *f != "-") {
+                eprintln!("Wrote {} checksum(s) to {}", count, file);
+            }
+            0
+        }
+        Err(e) => {
+            output::error(format_args!("Failed to write checksums: {}", e));
+            2
+        }
+    }
+}
 
-    if is_dir && !args.recursive {
-        println!("Source is a directory. This will copy recursively.");
-        print!("Continue? [y/N]: ");
-        use std::io::{self, Write};
-        io::stdout().flush().unwrap();
+/// `usync dedupe`: replace identical files in a local tree with links to one copy.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync dedupe",
+    about = "Replace identical files in a directory with hard links to a single copy",
+    after_help = r#"Files are grouped by size and confirmed with SHA-256. Files on different devices,
+and (for hard links) files with a different owner or mode, are left alone.
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+EXAMPLES:
+  usync dedupe ./backups/ --dry-run
+  usync dedupe ./backups/
+  usync dedupe ./photos/ --reflink"#
+)]
+struct DedupeArgs {
+    #[arg(value_name = "DIR")]
+    dir: String,
 
-        let trimmed = input.trim().to_lowercase();
-        if trimmed != "y" && trimmed != "yes" {
-            if verbose {
-                println!("Copy cancelled by user.");
-            } else {
-                println!("Copy cancelled.");
-            }
-            std::process::exit(0);
+    /// List the duplicate groups and the space that would be reclaimed; change nothing
+    #[arg(short = 'n', long = "dry-run")]
+    dry_run: bool,
+
+    /// How candidates are found: hash every file, or only files sharing a size
+    #[arg(long = "by", value_enum, default_value = "size+checksum")]
+    by: dedupe::GroupBy,
+
+    /// Use copy-on-write clones (btrfs, XFS, APFS) instead of hard links, so each file
+    /// keeps its own metadata and later writes don't affect the others
+    #[arg(long = "reflink")]
+    reflink: bool,
+}
+
+/// Run `usync dedupe` and return the process exit code.
+fn run_dedupe(args: DedupeArgs) -> i32 {
+    let root = match path::LocalPath::parse(&args.dir) {
+        Ok(p) => p,
+        Err(e) => {
+            output::error(e);
+            return 2;
         }
+    };
+    if !root.as_path().is_dir() {
+        output::error(format_args!("Not a directory: {}", root.to_string_lossy()));
+        return 2;
     }
 
-    let src_str = match &src_path {
-        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
-        protocol::Path::Remote(r) => r.url.to_string(),
+    let opts = dedupe::DedupeOptions {
+        by: args.by,
+        link: if args.reflink {
+            dedupe::LinkKind::Reflink
+        } else {
+            dedupe::LinkKind::Hard
+        },
+        dry_run: args.dry_run,
     };
-    let dst_str = match &dst_path {
-        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
-        protocol::Path::Remote(r) => r.url.to_string(),
+    let report = match dedupe::dedupe(root.as_path(), &opts) {
+        Ok(report) => report,
+        Err(e) => {
+            output::error(format_args!(
+                "Failed to deduplicate {}: {}",
+                root.to_string_lossy(),
+                e
+            ));
+            return 2;
+        }
     };
 
-    let ssh_opts = if !args.ssh_opts.is_empty() {
-        args.ssh_opts
-    } else {
-        std::env::var("USYNC_SSH_OPTS")
-            .map(|v| v.split_whitespace().map(|s| s.to_string()).collect())
-            .unwrap_or_default()
+    let relative = |p: &std::path::Path| {
+        p.strip_prefix(root.as_path())
+            .unwrap_or(p)
+            .display()
+            .to_string()
     };
-
-    let env_progress = std::env::var("USYNC_PROGRESS")
-        .map(|v| !v.is_empty() && v != "0" && v.to_lowercase() != "false")
-        .unwrap_or(false);
-    let show_progress = args.progress || env_progress;
-
-    if verbose {
-        if args.move_files {
-            println!("Moving {} to {}...", src_str, dst_str);
+    for group in &report.groups {
+        println!("{} bytes x {}:", group.size, group.duplicates.len() + 1);
+        println!("  keep {}", relative(&group.keep));
+        for dup in &group.duplicates {
+            println!("  link {}", relative(dup));
+        }
+    }
+    for (path, e) in &report.failed {
+        output::error(format_args!("Failed to link {}: {}", relative(path), e));
+    }
+    println!(
+        "{} duplicate(s) in {} group(s); {} bytes {}",
+        report.linked,
+        report.groups.len(),
+        report.bytes_reclaimed,
+        if args.dry_run {
+            "would be reclaimed"
         } else {
-            println!("Copying {} to {}...", src_str, dst_str);
+            "reclaimed"
         }
+    );
+    if report.failed.is_empty() {
+        0
+    } else {
+        1
     }
+}
 
-    match copy(
-        &src_path,
-        &dst_path,
-        verbose,
-        &ssh_opts,
-        show_progress,
-        args.use_ram,
-    ) {
-        Ok(stats) => {
-            if args.move_files {
-                match delete_source(&src_path, verbose) {
-                    Ok(()) => {
-                        if verbose {
-                            #[cfg(feature = "color")]
-                            println!(
-                                "{} {} and removed source",
-                                "✓".green().bold(),
-                                if args.use_ram {
-                                    "Moved via RAM"
-                                } else {
-                                    "Moved"
-                                }
-                            );
-                            #[cfg(not(feature = "color"))]
-                            println!("✓ Moved and removed source");
-                        } else {
-                            #[cfg(feature = "color")]
-                            println!("{} {} to {}", "Moved".green(), src_str, dst_str);
-                            #[cfg(not(feature = "color"))]
-                            println!("Moved {} to {}", src_str, dst_str);
-                        }
-                    }
-                    Err(e) => {
-                        #[cfg(feature = "color")]
-                        eprintln!(
-                            "{}: Copy succeeded but failed to remove source: {}",
-                            "Warning".yellow().bold(),
-                            e
-                        );
-                        #[cfg(not(feature = "color"))]
-                        eprintln!("Warning: Copy succeeded but failed to remove source: {}", e);
-                    }
-                }
-            } else if verbose {
-                #[cfg(feature = "color")]
-                println!(
-                    "{} {} to {}",
-                    "✓".green().bold(),
-                    "Successfully copied".green(),
-                    format!("{} to {}", src_str, dst_str)
-                );
-                #[cfg(not(feature = "color"))]
-                println!("✓ Successfully copied {} to {}", src_str, dst_str);
-            } else {
-                #[cfg(feature = "color")]
-                println!(
-                    "{} {} to {}",
-                    "Successfully copied".green(),
-                    src_str,
-                    dst_str
-                );
-                #[cfg(not(feature = "color"))]
-                println!("Successfully copied {} to {}", src_str, dst_str);
-            }
-            if verbose || show_progress {
-                stats.print_summary(verbose);
-            }
+/// `usync list`: the files below a local, SSH or S3 path, filtered and sorted.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync list",
+    about = "List the files below a local directory, SSH path or S3 prefix with sizes and times",
+    after_help = r#"Each line shows the size in bytes, the modification time (UTC, `-` when the side
+doesn't report one) and the path relative to PATH. Without --sort, names are sorted
+within each directory. Sorting is stable, and files without a time sort last.
+
+EXAMPLES:
+  usync list s3://bucket/logs/ --sort size --reverse --limit 20
+  usync list user@host:/var/backups --sort mtime --exclude '*.tmp'
+  usync list ./photos/ --include '*.jpg'"#
+)]
+struct ListArgs {
+    #[arg(value_name = "PATH")]
+    path: String,
+
+    /// Sort by name, size or modification time
+    #[arg(long = "sort", value_name = "KEY", value_enum)]
+    sort: Option<list::SortKey>,
+
+    /// Reverse the sort: largest, newest or last name first
+    #[arg(long = "reverse", requires = "sort")]
+    reverse: bool,
+
+    /// Show only the first N files (after sorting)
+    #[arg(long = "limit", value_name = "N")]
+    limit: Option<usize>,
+
+    /// Leave out files and directories matching PATTERN (can be used multiple times)
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Only list files matching PATTERN (can be used multiple times); excludes still win
+    #[arg(long = "include", value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// SSH options (can be used multiple times; default: $USYNC_SSH_OPTS)
+    #[arg(short = 's', long = "ssh-opt", value_name = "OPTION")]
+    ssh_opts: Vec<String>,
+
+    /// SSH port for remotes that don't give one
+    #[arg(short = 'P', long = "port", value_name = "PORT")]
+    port: Option<u16>,
+
+    /// Answer SSH password prompts with the first line of FILE
+    #[arg(
+        long = "ssh-password-file",
+        value_name = "FILE",
+        env = "USYNC_SSH_PASSWORD_FILE",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    ssh_password_file: Option<std::path::PathBuf>,
+}
+
+/// Set up `--ssh-password-file`, reporting a bad file and returning the exit code.
+fn ssh_password_file(file: Option<&std::path::Path>) -> Result<(), i32> {
+    let Some(file) = file else {
+        return Ok(());
+    };
+    remote::use_password_file(file).map_err(|e| {
+        output::error(format_args!(
+            "--ssh-password-file {}: {}",
+            file.display(),
+            e
+        ));
+        2
+    })
+}
+
+/// ssh runs usync as its `SSH_ASKPASS` helper for `--ssh-password-file`, with the prompt
+/// as the only argument; print the answer and return the exit code.
+fn run_askpass(password_file: &std::ffi::OsStr) -> i32 {
+    let prompt = std::env::args().nth(1).unwrap_or_default();
+    match remote::askpass_reply(&prompt, std::path::Path::new(password_file)) {
+        Ok(Some(answer)) => {
+            println!("{}", answer);
+            0
         }
+        Ok(None) => 1,
         Err(e) => {
-            #[cfg(feature = "color")]
-            eprintln!(
-                "{}: {}",
-                if args.move_files {
-                    "Error moving"
-                } else {
-                    "Error copying"
-                }
-                .red()
-                .bold(),
-                e
-            );
-            #[cfg(not(feature = "color"))]
-            eprintln!(
-                "{}: {}",
-                if args.move_files {
-                    "Error moving"
-                } else {
-                    "Error copying"
-                },
-                e
-            );
-            std::process::exit(1);
+            eprintln!("usync: cannot read the SSH password file: {}", e);
+            1
         }
     }
 }
 
-fn delete_source(path: &protocol::Path, verbose: bool) -> Result<(), String> {
-    match path {
-        protocol::Path::Local(local_path) => {
-            let path = local_path.as_path();
-            if path.is_dir() {
-                if verbose {
-                    println!("Removing directory and all contents: {}", path.display());
-                }
-                fs::remove_dir_all(path)
-                    .map_err(|e| format!("Failed to remove directory {}: {}", path.display(), e))?;
-                if verbose {
-                    println!("Removed directory: {}", path.display());
-                }
-            } else {
-                fs::remove_file(path)
-                    .map_err(|e| format!("Failed to remove file {}: {}", path.display(), e))?;
-                if verbose {
-                    println!("Removed file: {}", path.display());
-                }
-            }
-            Ok(())
+/// Run `usync list` and return the process exit code.
+fn run_list(args: ListArgs) -> i32 {
+    let src = match parse_cli_path(&args.path, args.port) {
+        Ok(path) => path,
+        Err(e) => {
+            output::error(e);
+            return 2;
         }
-        protocol::Path::Remote(_) => Err(
-            "Cannot remove remote files. Move operation only supported for local files."
-                .to_string(),
-        ),
+    };
+    if let Err(code) = ssh_password_file(args.ssh_password_file.as_deref()) {
+        return code;
+    }
+    let mut filters = filter::FilterChain::default();
+    if !args.include.is_empty() || !args.exclude.is_empty() {
+        filters.push(filter::PatternFilter::new(&args.include, &args.exclude));
+    }
+    let opts = list::ListOptions {
+        filters,
+        sort: args.sort,
+        reverse: args.reverse,
+        limit: args.limit,
+        ssh_opts: ssh_options(&args.ssh_opts),
+    };
+    let entries = match list::list(&src, &opts) {
+        Ok(entries) => entries,
+        Err(e) => {
+            output::error(e);
+            return 1;
+        }
+    };
+    for entry in &entries {
+        println!(
+            "{:>12}  {}  {}",
+            entry.size,
+            list::format_time(entry.modified),
+            entry.path
+        );
+    }
+    0
+}
+
+/// `usync diff`: how two directories differ, without copying anything.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync diff",
+    about = "Show the files only in A, only in B, and in both but different, without copying",
+    after_help = r#"A and B can each be a local directory, an SSH path or an S3 prefix. A file in both
+is different when a sync from A to B would copy it, by the --compare rule: with the
+default size-mtime, when the sizes differ or B's copy is older. --checksum compares
+the contents of files of the same size instead (local and SSH only).
+
+Exits with 0 when the directories match, 1 when they differ and 2 on errors.
+
+EXAMPLES:
+  usync diff ./site/ user@host:/var/www/site/
+  usync diff ./photos/ /mnt/backup/photos/ --checksum
+  usync diff ./data/ s3://bucket/data/ --compare size-only --json"#
+)]
+struct DiffArgs {
+    #[arg(value_name = "A")]
+    a: String,
+
+    #[arg(value_name = "B")]
+    b: String,
+
+    /// How a file in both is found unchanged: size-mtime (default), size-only or existence
+    #[arg(
+        long = "compare",
+        value_name = "MODE",
+        value_enum,
+        conflicts_with = "checksum"
+    )]
+    compare: Option<copy::Compare>,
+
+    /// Compare files of the same size by SHA-256, whatever their times
+    #[arg(long = "checksum")]
+    checksum: bool,
+
+    /// Print the differences as one JSON object
+    #[arg(long = "json")]
+    json: bool,
+
+    /// Leave out files and directories matching PATTERN (can be used multiple times)
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Only compare files matching PATTERN (can be used multiple times); excludes still win
+    #[arg(long = "include", value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// SSH options (can be used multiple times; default: $USYNC_SSH_OPTS)
+    #[arg(short = 's', long = "ssh-opt", value_name = "OPTION")]
+    ssh_opts: Vec<String>,
+
+    /// SSH port for remotes that don't give one
+    #[arg(short = 'P', long = "port", value_name = "PORT")]
+    port: Option<u16>,
+
+    /// Answer SSH password prompts with the first line of FILE
+    #[arg(
+        long = "ssh-password-file",
+        value_name = "FILE",
+        env = "USYNC_SSH_PASSWORD_FILE",
+        value_hint = clap::ValueHint::FilePath
+    )]
+    ssh_password_file: Option<std::path::PathBuf>,
+}
+
+/// Run `usync diff` and return the process exit code: 1 when the sides differ.
+fn run_diff(args: DiffArgs) -> i32 {
+    let mut sides = Vec::new();
+    for side in [&args.a, &args.b] {
+        match parse_cli_path(side, args.port) {
+            Ok(path) => sides.push(path),
+            Err(e) => {
+                output::error(e);
+                return 2;
+            }
+        }
+    }
+    if let Err(code) = ssh_password_file(args.ssh_password_file.as_deref()) {
+        return code;
+    }
+    let mut filters = filter::FilterChain::default();
+    if !args.include.is_empty() || !args.exclude.is_empty() {
+        filters.push(filter::PatternFilter::new(&args.include, &args.exclude));
+    }
+    let opts = diff::DiffOptions {
+        filters,
+        compare: args.compare.unwrap_or(copy::Compare::SizeMtime),
+        checksum: args.checksum,
+        ssh_opts: ssh_options(&args.ssh_opts),
+    };
+    let found = match diff::diff(&sides[0], &sides[1], &opts) {
+        Ok(found) => found,
+        Err(e) => {
+            output::error(e);
+            return 2;
+        }
+    };
+    if args.json {
+        println!("{}", found.to_json());
+    } else {
+        for (side, entries) in [(&args.a, &found.only_in_a), (&args.b, &found.only_in_b)] {
+            if !entries.is_empty() {
+                println!("Only in {}:", side);
+                for entry in entries {
+                    println!("  {}", entry.path);
+                }
+            }
+        }
+        if !found.changed.is_empty() {
+            println!("Different:");
+            for changed in &found.changed {
+                let (a, b) = (&changed.a, &changed.b);
+                let detail = match changed.reason {
+                    diff::Reason::Size => format!("size {} vs {} bytes", a.size, b.size),
+                    diff::Reason::Mtime => format!(
+                        "modified {} vs {}",
+                        list::format_time(a.modified),
+                        list::format_time(b.modified)
+                    ),
+                    diff::Reason::Checksum => "contents (sha256)".to_string(),
+                };
+                println!("  {}  ({})", a.path, detail);
+            }
+        }
+        println!(
+            "{} only in A, {} only in B, {} different, {} the same",
+            found.only_in_a.len(),
+            found.only_in_b.len(),
+            found.changed.len(),
+            found.same
+        );
+    }
+    if found.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// `usync bench`: compare the single-file copy strategies on this machine.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync bench",
+    about = "Time each file copy strategy on this machine and print MB/s per file size",
+    after_help = r#"Test files of 64 KiB, then every 64x larger up to --size, are written to a temporary
+directory under --dir, copied with each strategy and removed. Every run copies a file
+of its own that was flushed out of the page cache first, and the time includes syncing
+the copy to disk, so the numbers reflect the disk rather than memory.
+
+EXAMPLES:
+  usync bench
+  usync bench --size 1G --dir /mnt/backup"#
+)]
+struct BenchArgs {
+    /// Largest file size to try (e.g. 512K, 64M, 1G)
+    #[arg(long = "size", value_name = "SIZE", default_value = "256M", value_parser = bench::parse_size)]
+    size: u64,
+
+    /// Directory to write the test files in, e.g. on the disk you copy to
+    /// (default: the system temp directory)
+    #[arg(long = "dir", value_name = "PATH", value_hint = clap::ValueHint::DirPath)]
+    dir: Option<std::path::PathBuf>,
+
+    /// Runs per strategy and size; the median is shown
+    #[arg(long = "runs", value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..))]
+    runs: u16,
+}
+
+/// Run `usync bench` and return the process exit code.
+fn run_bench(args: BenchArgs) -> i32 {
+    let opts = bench::BenchOptions {
+        max_size: args.size,
+        dir: args.dir,
+        runs: args.runs.into(),
+    };
+    let results = bench::run(&opts, |m| {
+        eprintln!(
+            "{:>8}  {:<10} {:>10.1} MB/s",
+            bench::format_size(m.size),
+            m.strategy,
+            m.mb_per_sec
+        );
+    });
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => {
+            output::error(format_args!("Benchmark failed: {}", e));
+            return 1;
+        }
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    if let Err(e) = bench::print_table(&results, &mut stdout) {
+        output::error(format_args!("Failed to write results: {}", e));
+        return 1;
+    }
+    println!();
+    let winners = bench::winners(&results);
+    for winner in &winners {
+        println!(
+            "Fastest for {}: {}",
+            bench::format_size(winner.size),
+            winner.strategy
+        );
+    }
+    // usync picks its strategy per copy; RAM copying is the one that can be asked for.
+    if winners.iter().any(|w| w.strategy == "ram") {
+        println!("RAM copying won for some sizes; pass --ram to use it for such files.");
+    }
+    0
+}
+
+/// `usync completions`: print a shell completion script.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync completions",
+    about = "Print a completion script for SHELL to stdout",
+    after_help = r#"EXAMPLES:
+  usync completions bash > /usr/share/bash-completion/completions/usync
+  usync completions zsh > "${fpath[1]}/_usync"
+  usync completions fish > ~/.config/fish/completions/usync.fish"#
+)]
+struct CompletionsArgs {
+    #[arg(value_name = "SHELL", value_enum)]
+    shell: clap_complete::Shell,
+}
+
+/// `usync manpage`: print the man page.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync manpage",
+    about = "Print the usync(1) man page (roff) to stdout",
+    after_help = r#"EXAMPLES:
+  usync manpage > /usr/share/man/man1/usync.1
+  usync manpage | man -l -"#
+)]
+struct ManpageArgs {}
+
+/// `usync serve`: run copies for another program.
+#[derive(Parser, Debug)]
+#[command(
+    name = "usync serve",
+    about = "Run copies and syncs requested as JSON lines (build with --features rpc)",
+    after_help = r#"Each request is one line of JSON on stdin; the replies go to stdout, one per line:
+  {"id":1,"op":"copy","src":"./photos/","dst":"/backup/photos/","options":{"recursive":true}}
+  {"id":1,"op":"cancel"}
+
+Requests run concurrently. Each ends with a "done", "error" or "cancelled" reply for its
+id, after "progress" events for local copies. usync exits when stdin ends and the
+running requests have finished."#
+)]
+struct ServeArgs {
+    /// Read requests from stdin and write replies to stdout
+    #[arg(long = "stdio", required = true)]
+    stdio: bool,
+}
+
+/// The whole command line, with the subcommands that `main` dispatches by hand, for
+/// completion scripts and the man page. `delta-server` is internal and left out.
+fn cli_command() -> clap::Command {
+    Args::command()
+        .args_conflicts_with_subcommands(true)
+        .subcommand(ChecksumArgs::command().name("checksum"))
+        .subcommand(DedupeArgs::command().name("dedupe"))
+        .subcommand(ListArgs::command().name("list"))
+        .subcommand(DiffArgs::command().name("diff"))
+        .subcommand(BenchArgs::command().name("bench"))
+        .subcommand(CompletionsArgs::command().name("completions"))
+        .subcommand(ManpageArgs::command().name("manpage"))
+        .subcommand(ServeArgs::command().name("serve"))
+}
+
+/// Run `usync completions` and return the process exit code.
+fn run_completions(args: CompletionsArgs) -> i32 {
+    clap_complete::generate(
+        args.shell,
+        &mut cli_command(),
+        "usync",
+        &mut std::io::stdout(),
+    );
+    0
+}
+
+/// Run `usync manpage` and return the process exit code.
+fn run_manpage(_args: ManpageArgs) -> i32 {
+    match clap_mangen::Man::new(cli_command()).render(&mut std::io::stdout()) {
+        Ok(()) => 0,
+        Err(e) => {
+            output::error(format_args!("Failed to write the man page: {}", e));
+            1
+        }
+    }
+}
+
+/// Run `usync serve` and return the process exit code.
+fn run_serve(_args: ServeArgs) -> i32 {
+    #[cfg(feature = "rpc")]
+    return match usync::rpc::serve_stdio() {
+        Ok(()) => 0,
+        Err(e) => {
+            output::error(format_args!("Server stopped: {}", e));
+            1
+        }
+    };
+    #[cfg(not(feature = "rpc"))]
+    {
+        output::error("this build has no server mode; rebuild with --features rpc");
+        1
+    }
+}
+
+fn main() {
+    if let Some(password_file) = std::env::var_os(remote::ASKPASS_FILE_ENV) {
+        std::process::exit(run_askpass(&password_file));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "checksum") {
+        let args = ChecksumArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_checksum(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "dedupe") {
+        let args = DedupeArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_dedupe(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "list") {
+        let args = ListArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_list(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "diff") {
+        let args = DiffArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_diff(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "bench") {
+        let args = BenchArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_bench(args));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|a| a == "completions")
+    {
+        let args = CompletionsArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_completions(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "manpage") {
+        let args = ManpageArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_manpage(args));
+    }
+    if std::env::args_os().nth(1).is_some_and(|a| a == "serve") {
+        let args = ServeArgs::parse_from(std::env::args_os().skip(1));
+        std::process::exit(run_serve(args));
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|a| a == "delta-server")
+    {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        std::process::exit(delta::serve(&args));
+    }
+
+    let args = Args::parse();
+    output::set_color(if args.no_color {
+        ColorChoice::Never
+    } else {
+        args.color
+    });
+    let verbose = args.verbose;
+    // Before any thread or child process starts, so they all inherit it.
+    if let Some(n) = args.nice {
+        if let Err(e) = priority::set_nice(n) {
+            output::warn(format_args!("could not set niceness {}: {}", n, e));
+        }
+    }
+    if let Some(ionice) = args.ionice {
+        if let Err(e) = priority::set_ionice(ionice) {
+            output::warn(format_args!("could not set the I/O class: {}", e));
+        }
+    }
+    if let Err(code) = ssh_password_file(args.ssh_password_file.as_deref()) {
+        std::process::exit(code);
+    }
+
+    let src_path = match parse_cli_path(&args.src, args.port) {
+        Ok(path) => path,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error parsing source path", e);
+            std::process::exit(1);
+        }
+    };
+
+    let dst_path = match parse_cli_path(&args.dst, args.port) {
+        Ok(path) => path,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error parsing destination path", e);
+            std::process::exit(1);
+        }
+    };
+
+    let is_dir = match &src_path {
+        protocol::Path::Local(_) if args.src == stream::STDIO => false,
+        protocol::Path::Local(local_path) => {
+            if !local_path.exists() {
+                output::error(format_args!(
+                    "Source path does not exist: {}",
+                    local_path.to_string_lossy()
+                ));
+                std::process::exit(1);
+            }
+            local_path.is_dir()
+        }
+        protocol::Path::Remote(_) => false,
+    };
+
+    let mut job = transfer(&args);
+    let copy_opts = job.copy_options();
+    if args.debug_filters {
+        let filters = copy_opts.filters.describe();
+        if filters.is_empty() {
+            eprintln!("filter: no filters; everything is included");
+        }
+        for (i, filter) in filters.iter().enumerate() {
+            eprintln!("filter {}: {}", i + 1, filter);
+        }
+    }
+
+    if is_dir && !copy_opts.recursive && copy_opts.files_from.is_none() && args.dst != stream::STDIO
+    {
+        println!("Source is a directory. This will copy recursively.");
+        print!("Continue? [y/N]: ");
+        use std::io::{self, Write};
+        io::stdout().flush().unwrap();
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+
+        let trimmed = input.trim().to_lowercase();
+        if trimmed != "y" && trimmed != "yes" {
+            if verbose {
+                println!("Copy cancelled by user.");
+            } else {
+                println!("Copy cancelled.");
+            }
+            std::process::exit(0);
+        }
+        job = job.recursive(true);
+    }
+
+    let src_str = match &src_path {
+        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
+        protocol::Path::Remote(r) => r.url.to_string(),
+    };
+    let dst_str = match &dst_path {
+        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
+        protocol::Path::Remote(r) => r.url.to_string(),
+    };
+
+    if let Some(dir) = copy_opts.tmpdir.as_deref().filter(|d| !d.is_dir()) {
+        output::error(format_args!(
+            "temporary directory {} does not exist",
+            dir.display()
+        ));
+        std::process::exit(1);
+    }
+
+    if let Some((operation, format)) = archive::detect(&src_path, &dst_path, copy_opts.recursive) {
+        std::process::exit(run_archive(
+            &args, &src_path, &dst_path, operation, format, &copy_opts, verbose,
+        ));
+    }
+    // These write as they read, with nothing to list beforehand.
+    let unplanned = if args.src == stream::STDIO || args.dst == stream::STDIO {
+        Some("-")
+    } else if !args.also_to.is_empty() {
+        Some("--also-to")
+    } else if args.gzip || args.zstd || args.decompress {
+        Some("--gzip, --zstd and --decompress")
+    } else {
+        None
+    };
+    if let Some(what) = unplanned.filter(|_| args.dry_run) {
+        output::error(format_args!("--dry-run can't be combined with {}", what));
+        std::process::exit(2);
+    }
+    if args.src == stream::STDIO || args.dst == stream::STDIO {
+        std::process::exit(run_stream(&args, &src_path, &dst_path, &copy_opts, verbose));
+    }
+    let flags = transform::TransformFlags {
+        gzip: args.gzip,
+        zstd: args.zstd,
+        decompress: args.decompress,
+    };
+    let explicit = flags.gzip || flags.zstd || flags.decompress;
+    let remote_tree = matches!(src_path, protocol::Path::Remote(_)) && copy_opts.recursive;
+    if explicit && (is_dir || remote_tree) {
+        output::error(
+            "--gzip, --zstd and --decompress work on single files; \
+             copy a directory to an archive (e.g. ./backup.tar.gz) instead",
+        );
+        std::process::exit(1);
+    }
+    if !is_dir && !remote_tree {
+        match transform::detect(&src_path, &dst_path, flags) {
+            Ok(Some(transform)) => std::process::exit(run_transform(
+                &args, &src_path, &dst_path, transform, &copy_opts, verbose,
+            )),
+            Ok(None) => {}
+            Err(e) => {
+                output::error(e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if !args.also_to.is_empty() {
+        std::process::exit(run_tee(&args, &src_path, &dst_path, &copy_opts, verbose));
+    }
+
+    if verbose {
+        if args.move_files {
+            println!("Moving {} to {}...", src_str, dst_str);
+        } else {
+            println!("Copying {} to {}...", src_str, dst_str);
+        }
+    }
+
+    match job.run() {
+        Ok(report) => {
+            if let (Some(outcome), Some(algorithm)) = (report.verification.clone(), args.checksum) {
+                report_verification(Ok(outcome), algorithm, args.verify_mode, verbose);
+            }
+            if args.dry_run {
+                println!("Dry run: {}; nothing was changed", dry_run_summary(&report));
+            } else if args.move_files && report.operation != Operation::Move {
+                if let Err(reason) = report.check_move() {
+                    output::warn(format_args!("source kept, as {}", reason));
+                }
+            } else if args.move_files {
+                if report.errors.is_empty() {
+                    if verbose {
+                        output::success(
+                            if args.use_ram {
+                                "✓ Moved via RAM"
+                            } else {
+                                "✓ Moved"
+                            },
+                            "and removed source",
+                        );
+                    } else {
+                        output::success("Moved", format_args!("{} to {}", src_str, dst_str));
+                    }
+                }
+                for e in &report.errors {
+                    output::warn(format_args!(
+                        "Copy succeeded but failed to remove source: {}",
+                        e.message
+                    ));
+                }
+            } else if report.limit_reached.is_none() && up_to_date(&report) {
+                output::success(
+                    if verbose {
+                        "✓ Up to date"
+                    } else {
+                        "Up to date"
+                    },
+                    format_args!(
+                        "{} to {} ({} file(s) skipped, nothing copied)",
+                        src_str, dst_str, report.files_skipped
+                    ),
+                );
+            } else if report.limit_reached.is_none() {
+                output::success(
+                    if verbose {
+                        "✓ Successfully copied"
+                    } else {
+                        "Successfully copied"
+                    },
+                    format_args!("{} to {}", src_str, dst_str),
+                );
+            }
+            if verbose {
+                println!();
+                for (severity, line) in report.summary() {
+                    println!("{}", output::paint(&line, severity, Stream::Stdout));
+                }
+                if let Some(usage) =
+                    priority::children_usage().filter(|u| !u.user.is_zero() || !u.system.is_zero())
+                {
+                    println!("Child process CPU time: {}", usage);
+                }
+            } else {
+                if copy_opts.progress {
+                    println!(
+                        "\nSummary: {}",
+                        output::paint(&report.to_string(), report.severity(), Stream::Stdout)
+                    );
+                }
+                // Part of the -v block; asked for on its own otherwise.
+                for (_, line) in report.slowest_lines() {
+                    println!("{}", line);
+                }
+            }
+            if report.files_xattrs_incomplete > 0 {
+                output::warn(format_args!(
+                    "extended attributes or ACLs could not be fully preserved on {} file(s)",
+                    report.files_xattrs_incomplete
+                ));
+            }
+            if report.specials_skipped > 0 {
+                output::warn(format_args!(
+                    "{} special file(s) skipped (use --specials/--devices to recreate them)",
+                    report.specials_skipped
+                ));
+            }
+            if report.files_vanished > 0 {
+                output::warn(format_args!(
+                    "{} file(s) vanished before they could be copied",
+                    report.files_vanished
+                ));
+            }
+            if let Some(exceeded) = &report.limit_reached {
+                if exceeded.soft {
+                    output::warn(format_args!("stopped early: {}", exceeded));
+                } else {
+                    output::error(format_args!(
+                        "stopped: {}{}",
+                        exceeded,
+                        if exceeded.planned {
+                            "; nothing was copied"
+                        } else {
+                            ""
+                        }
+                    ));
+                    // Same exit code rsync uses when --max-delete stops it.
+                    std::process::exit(25);
+                }
+            }
+            // A failed removal of a moved source is warned about above.
+            if report.operation != Operation::Move && report.files_failed > 0 {
+                output::warn(format_args!(
+                    "{} path(s) could not be read and were skipped:",
+                    report.files_failed
+                ));
+                for e in &report.errors {
+                    eprintln!(
+                        "  {}",
+                        output::paint(&e.to_string(), Severity::Error, Stream::Stderr)
+                    );
+                }
+                // Same exit code rsync uses for a partial transfer.
+                std::process::exit(23);
+            }
+            if report.files_vanished > 0 {
+                // Same exit code rsync uses for "some files vanished".
+                std::process::exit(24);
+            }
+        }
+        Err(
+            e @ usync::Error::Copy(CopyError::DeleteRefused(_) | CopyError::EmptySource { .. }),
+        ) => {
+            output::error(e);
+            // Like a limit: the job was stopped on purpose, and nothing was copied.
+            std::process::exit(25);
+        }
+        Err(e @ usync::Error::Copy(CopyError::ToolMissing { .. })) => {
+            output::error(e);
+            // EX_UNAVAILABLE: a program usync needs isn't there; nothing was copied.
+            std::process::exit(69);
+        }
+        Err(e @ usync::Error::Locked(_)) => {
+            output::error(e);
+            // EX_TEMPFAIL: nothing was copied, and a later run can try again.
+            std::process::exit(75);
+        }
+        Err(usync::Error::Verification(outcome)) => {
+            if let Some(algorithm) = args.checksum {
+                report_verification(Ok(outcome), algorithm, args.verify_mode, verbose);
+            }
+            std::process::exit(1);
+        }
+        Err(e) => {
+            output::labeled(
+                Severity::Error,
+                if args.move_files {
+                    "Error moving"
+                } else {
+                    "Error copying"
+                },
+                e,
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Whether a copy found everything already in place: files were skipped, as unchanged
+/// or as the source itself, and nothing was written, linked or removed.
+fn up_to_date(report: &usync::TransferReport) -> bool {
+    report.files_skipped > 0
+        && report.files_copied == 0
+        && report.files_linked == 0
+        && report.files_renamed == 0
+        && report.files_deleted == 0
+        && report.dirs_pruned == 0
+}
+
+/// What a `--dry-run` found to do, e.g. "3 file(s) (1200 bytes) would be copied, 1 deleted".
+fn dry_run_summary(report: &usync::TransferReport) -> String {
+    let mut summary = format!(
+        "{} file(s) ({} bytes) would be copied",
+        report.files_copied, report.bytes_copied
+    );
+    for (count, what) in [
+        (report.files_skipped, "skipped as unchanged"),
+        (report.files_renamed, "moved"),
+        (report.files_deleted, "deleted"),
+        (report.dirs_pruned, "empty directories pruned"),
+    ] {
+        if count > 0 {
+            summary.push_str(&format!(", {} {}", count, what));
+        }
+    }
+    summary
+}
+
+/// `--also-to`: copy a local file to DEST and every extra destination, reading it once.
+/// Returns the process exit code, non-zero when any destination failed.
+fn run_tee(
+    args: &Args,
+    src_path: &protocol::Path,
+    dst_path: &protocol::Path,
+    copy_opts: &CopyOptions,
+    verbose: bool,
+) -> i32 {
+    let src = match src_path {
+        protocol::Path::Local(local) if local.is_file() => local.as_path(),
+        _ => {
+            output::error("--also-to needs a local source file");
+            return 1;
+        }
+    };
+    let mut dsts = vec![dst_path.clone()];
+    for dst in &args.also_to {
+        match parse_cli_path(dst, args.port) {
+            Ok(path) => dsts.push(path),
+            Err(e) => {
+                output::labeled(
+                    Severity::Error,
+                    &format!("Error parsing destination path {}", dst),
+                    e,
+                );
+                return 1;
+            }
+        }
+    }
+    let describe = |path: &protocol::Path| match path {
+        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
+        protocol::Path::Remote(r) => r.url.to_string(),
+    };
+
+    if verbose {
+        println!(
+            "Copying {} to {} destinations...",
+            src.display(),
+            dsts.len()
+        );
+    }
+    let results = match tee::tee(src, &dsts, copy_opts) {
+        Ok(results) => results,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error copying", e);
+            return 1;
+        }
+    };
+
+    let mut failed = 0;
+    // The first destination whose check let the copy pass without a match.
+    let mut unverified = None;
+    for (dst, result) in dsts.iter().zip(results) {
+        match result {
+            Ok(bytes) => {
+                if let Some(algorithm) = args.checksum {
+                    match verify_after_copy(src_path, dst, algorithm, args.verify_mode, copy_opts) {
+                        Some(Outcome::Match) => {}
+                        Some(outcome) => {
+                            unverified.get_or_insert(outcome);
+                        }
+                        None => {
+                            failed += 1;
+                            continue;
+                        }
+                    }
+                }
+                output::success(
+                    "Successfully copied",
+                    format_args!("{} to {} ({} bytes)", src.display(), describe(dst), bytes),
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                output::labeled(
+                    Severity::Error,
+                    &format!("Error copying to {}", describe(dst)),
+                    e,
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        eprintln!(
+            "{} of {} destination(s) failed{}",
+            failed,
+            dsts.len(),
+            if args.move_files { "; source kept" } else { "" }
+        );
+        return 1;
+    }
+    if args.move_files {
+        let verification = args.checksum.map(|_| unverified.unwrap_or(Outcome::Match));
+        finish_move(src_path, verification.as_ref(), copy_opts, false);
+    }
+    0
+}
+
+/// Copy between stdin or stdout (`-`) and a single file.
+fn run_stream(
+    args: &Args,
+    src_path: &protocol::Path,
+    dst_path: &protocol::Path,
+    copy_opts: &CopyOptions,
+    verbose: bool,
+) -> i32 {
+    let from_stdin = args.src == stream::STDIO;
+    let to_stdout = args.dst == stream::STDIO;
+    let conflict = if from_stdin && to_stdout {
+        Some("- can't be both the source and the destination")
+    } else if copy_opts.recursive {
+        Some("- streams a single file and can't be combined with -r or -a")
+    } else if !args.also_to.is_empty() {
+        Some("--also-to needs a local source file, not -")
+    } else if args.delta {
+        Some("--delta needs a file on both sides, not -")
+    } else if args.append {
+        Some("--append needs a file on both sides, not -")
+    } else if from_stdin && args.move_files {
+        Some("--move can't remove standard input")
+    } else if args.gzip || args.zstd || args.decompress {
+        Some("--gzip, --zstd and --decompress can't be used with -; pipe through the tool instead")
+    } else if args.rename.is_some() {
+        Some("--rename names files copied by name, not -")
+    } else if args.files_from.is_some() {
+        Some("--files-from copies from a directory, not -")
+    } else {
+        None
+    };
+    if let Some(conflict) = conflict {
+        output::error(conflict);
+        return 1;
+    }
+
+    let describe = |path: &protocol::Path| match path {
+        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
+        protocol::Path::Remote(r) => r.url.to_string(),
+    };
+    // stdout may be the data itself, so everything else goes to stderr.
+    if verbose {
+        if from_stdin {
+            eprintln!("Streaming standard input to {}...", describe(dst_path));
+        } else {
+            eprintln!("Streaming {} to standard output...", describe(src_path));
+        }
+    }
+
+    let result = if from_stdin {
+        stream::from_stdin(dst_path, copy_opts, args.checksum)
+    } else {
+        stream::to_stdout(src_path, copy_opts, args.checksum)
+    };
+    let streamed = match result {
+        Ok(streamed) => streamed,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error copying", e);
+            return 1;
+        }
+    };
+
+    let mut verification = None;
+    if let Some(algorithm) = args.checksum {
+        let file = if from_stdin { dst_path } else { src_path };
+        let result = verify::verify_stream(
+            file,
+            &streamed.fingerprint(),
+            algorithm,
+            args.verify_mode,
+            &copy_opts.ssh_opts,
+            copy_opts.tmpdir.as_deref(),
+        );
+        match report_verification(result, algorithm, args.verify_mode, verbose && !to_stdout) {
+            Some(outcome) => verification = Some(outcome),
+            None => return 1,
+        }
+    }
+    if verbose {
+        eprintln!("✓ Streamed {} bytes", streamed.bytes);
+    }
+    if args.move_files {
+        // Not a word on stdout, which may be the data.
+        let quiet = CopyOptions {
+            verbose: false,
+            ..copy_opts.clone()
+        };
+        finish_move(src_path, verification.as_ref(), &quiet, false);
+    }
+    0
+}
+
+/// Pack a local directory into an archive file, or unpack one (`-r` with an archive source).
+fn run_archive(
+    args: &Args,
+    src_path: &protocol::Path,
+    dst_path: &protocol::Path,
+    operation: archive::Operation,
+    format: archive::Format,
+    copy_opts: &CopyOptions,
+    verbose: bool,
+) -> i32 {
+    let conflict = if args.checksum.is_some() {
+        Some("--checksum")
+    } else if !args.also_to.is_empty() {
+        Some("--also-to")
+    } else if args.delta {
+        Some("--delta")
+    } else if args.gzip || args.zstd || args.decompress {
+        Some("--gzip, --zstd and --decompress")
+    } else if args.max_files.is_some() || args.max_total_size.is_some() {
+        Some("--max-files and --max-total-size")
+    } else if args.delete {
+        Some("--delete")
+    } else if args.rename.is_some() {
+        Some("--rename")
+    } else if args.files_from.is_some() {
+        Some("--files-from")
+    } else if args.prune_empty_dirs {
+        Some("--prune-empty-dirs")
+    } else if args.append {
+        Some("--append")
+    } else {
+        None
+    };
+    if let Some(flag) = conflict {
+        output::error(format_args!("{} can't be used with archive copies", flag));
+        return 1;
+    }
+    let (protocol::Path::Local(src), protocol::Path::Local(dst)) = (src_path, dst_path) else {
+        unreachable!("archive copies are local");
+    };
+    let (src, dst) = (src.as_path(), dst.as_path());
+
+    let result = match operation {
+        archive::Operation::Create => {
+            if verbose {
+                println!("Archiving {} into {}...", src.display(), dst.display());
+            }
+            archive::create(src, dst, format, copy_opts, args.dry_run)
+        }
+        archive::Operation::Extract => {
+            if verbose {
+                println!("Extracting {} into {}...", src.display(), dst.display());
+            }
+            archive::extract(src, dst, format, copy_opts, args.dry_run)
+        }
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error copying", e);
+            return 1;
+        }
+    };
+
+    let verb = match operation {
+        archive::Operation::Create => "archived",
+        archive::Operation::Extract => "extracted",
+    };
+    if args.dry_run {
+        println!("Dry run: {} would be {}", archive::describe(&report), verb);
+    } else {
+        output::success(
+            &format!("Successfully {}", verb),
+            format_args!(
+                "{} into {} ({})",
+                src.display(),
+                dst.display(),
+                archive::describe(&report)
+            ),
+        );
+    }
+    if args.move_files {
+        finish_move(src_path, None, copy_opts, args.dry_run);
+    }
+    0
+}
+
+/// Copy a single file through gzip or zstd (`--gzip`, `--zstd`, `--decompress`, or a
+/// `.gz`/`.zst` destination).
+fn run_transform(
+    args: &Args,
+    src_path: &protocol::Path,
+    dst_path: &protocol::Path,
+    transform: transform::Transform,
+    copy_opts: &CopyOptions,
+    verbose: bool,
+) -> i32 {
+    let conflict = if !args.also_to.is_empty() {
+        Some("--also-to")
+    } else if args.delta {
+        Some("--delta")
+    } else if args.rename.is_some() {
+        Some("--rename")
+    } else if args.append {
+        Some("--append")
+    } else {
+        None
+    };
+    if let Some(flag) = conflict {
+        output::error(format_args!(
+            "{} can't be combined with compressing or decompressing the file",
+            flag
+        ));
+        return 1;
+    }
+
+    let describe = |path: &protocol::Path| match path {
+        protocol::Path::Local(p) => p.to_string_lossy().to_string(),
+        protocol::Path::Remote(r) => r.url.to_string(),
+    };
+    let dst = match transform::destination(src_path, dst_path, transform) {
+        Ok(dst) => dst,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error copying", e);
+            return 1;
+        }
+    };
+    let compress = transform.direction == transform::Direction::Compress;
+    if verbose {
+        println!(
+            "{} {} to {} with {}...",
+            if compress {
+                "Compressing"
+            } else {
+                "Decompressing"
+            },
+            describe(src_path),
+            describe(&dst),
+            transform.codec.name()
+        );
+    }
+
+    let stats = match transform::copy(src_path, &dst, transform, copy_opts, args.checksum) {
+        Ok(stats) => stats,
+        Err(e) => {
+            output::labeled(Severity::Error, "Error copying", e);
+            return 1;
+        }
+    };
+
+    let mut verification = None;
+    if let Some(algorithm) = args.checksum {
+        // The checksum is of the uncompressed data, which exists on one side only.
+        let original = if compress { src_path } else { &dst };
+        if verbose {
+            println!(
+                "Verifying the uncompressed data against {}",
+                describe(original)
+            );
+        }
+        let result = verify::verify_stream(
+            original,
+            &stats.streamed().fingerprint(),
+            algorithm,
+            args.verify_mode,
+            &copy_opts.ssh_opts,
+            copy_opts.tmpdir.as_deref(),
+        );
+        match report_verification(result, algorithm, args.verify_mode, verbose) {
+            Some(outcome) => verification = Some(outcome),
+            None => return 1,
+        }
+    }
+
+    output::success(
+        "Successfully copied",
+        format_args!(
+            "{} to {} ({})",
+            describe(src_path),
+            describe(&dst),
+            transform::describe(&stats)
+        ),
+    );
+    if args.move_files {
+        finish_move(src_path, verification.as_ref(), copy_opts, false);
+    }
+    0
+}
+
+/// Remove the source of a `--move` whose copy went through, unless `verification`,
+/// what `--checksum` found, leaves it in doubt. Either way ends in a warning, not a
+/// failure: the copy itself is there.
+fn finish_move(
+    src: &protocol::Path,
+    verification: Option<&Outcome>,
+    opts: &CopyOptions,
+    dry_run: bool,
+) {
+    if let Err(reason) = copy::check_move(0, verification) {
+        output::warn(format_args!("source kept, as {}", reason));
+    } else if let Err(e) = copy::delete_source(src, opts, dry_run) {
+        output::warn(format_args!(
+            "Copy succeeded but failed to remove source: {}",
+            e
+        ));
+    }
+}
+
+/// Parse a source or destination argument, giving SSH remotes without a port the one
+/// from `-P`.
+fn parse_cli_path(
+    arg: &str,
+    port: Option<u16>,
+) -> Result<protocol::Path, protocol::PathParseError> {
+    let mut path = parse_path(arg)?;
+    if let (protocol::Path::Remote(remote), Some(port)) = (&mut path, port) {
+        remote.set_default_port(port);
+    }
+    Ok(path)
+}
+
+/// The `-s` options, or else the space-separated ones in `USYNC_SSH_OPTS`. This isn't a
+/// clap `env`, whose delimiter would split `-s` values at spaces as well.
+fn ssh_options(flags: &[String]) -> Vec<String> {
+    if !flags.is_empty() {
+        return flags.to_vec();
+    }
+    std::env::var("USYNC_SSH_OPTS")
+        .map(|v| v.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Turn the command-line flags into a [`Usync`] copy.
+///
+/// `-a` switches on recursion, permission and time preservation and symlink
+/// preservation; an explicit `--no-*` flag always wins over what `-a` implied.
+fn transfer(args: &Args) -> Usync {
+    let mut preserve = if args.archive {
+        Preserve::all()
+    } else {
+        Preserve::default()
+    };
+    preserve.mode = (preserve.mode || args.perms) && !args.no_perms;
+    preserve.times = (preserve.times || args.times) && !args.no_times;
+    preserve.xattrs = args.xattrs;
+    preserve.acls = args.acls;
+
+    let symlinks = if (args.archive || args.links) && !args.no_links {
+        SymlinkPolicy::Preserve
+    } else {
+        SymlinkPolicy::Follow
+    };
+
+    let mut job = Usync::new(&args.src, &args.dst)
+        .verbose(args.verbose)
+        .progress(args.progress)
+        .progress_format(args.progress_format)
+        .use_ram(if args.use_ram {
+            Some(true)
+        } else if args.no_ram {
+            Some(false)
+        } else {
+            None
+        })
+        .ram_threshold(args.ram_threshold)
+        .direct_threshold(args.direct_threshold)
+        .recursive(args.recursive)
+        .archive(args.archive)
+        .preserve(preserve)
+        .ownership(Ownership {
+            chown: args.chown.unwrap_or_default(),
+            owner: args.owner,
+            group: args.group,
+            usermap: args.usermap.clone(),
+            groupmap: args.groupmap.clone(),
+        })
+        .symlinks(symlinks)
+        .mkpath(args.mkpath)
+        .specials(args.specials || args.devices_and_specials)
+        .devices(args.devices || args.devices_and_specials)
+        .ssh_multiplex(!args.no_ssh_multiplex)
+        .ssh_batch(args.ssh_batch)
+        .compress(args.compress)
+        .copy_mode(args.copy_mode)
+        .order(args.order)
+        .hard_links(args.hard_links)
+        .delta(args.delta)
+        .append(args.append)
+        .verify_mode(args.verify_mode)
+        .space_check(!args.no_space_check)
+        .fsync(args.fsync)
+        .ignore_vanished(!args.no_ignore_vanished)
+        .remove_source(args.move_files)
+        .dry_run(args.dry_run);
+    for option in ssh_options(&args.ssh_opts) {
+        job = job.ssh_opt(option);
+    }
+    for pattern in &args.include {
+        job = job.include(pattern);
+    }
+    for pattern in &args.exclude {
+        job = job.exclude(pattern);
+    }
+    for path in &args.filter_file {
+        let rules = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| filter::Rule::parse_file(&text));
+        match rules {
+            Ok(rules) => {
+                for rule in rules {
+                    job = job.filter(rule);
+                }
+            }
+            Err(e) => {
+                output::error(format_args!("--filter-file {}: {}", path.display(), e));
+                std::process::exit(2);
+            }
+        }
+    }
+    for rule in &args.filter {
+        job = job.filter(rule.clone());
+    }
+    job = job
+        .filter_default(args.filter_default)
+        .debug_filters(args.debug_filters);
+    if let Some(chmod) = &args.chmod {
+        job = job.chmod(chmod.clone());
+    }
+    if let Some(n) = args.depth_limit {
+        job = job.depth_limit(n);
+    }
+    if let Some(policy) = args.case_collisions {
+        job = job.case_collisions(policy);
+    }
+    let rules = match (&args.sanitize_names, args.fat_safe) {
+        (Some(rules), _) => Some(rules.clone()),
+        (None, true) => sanitize::NameRules::parse("fat").ok(),
+        (None, false) => None,
+    };
+    if let Some(rules) = rules {
+        match rules.with_replacement(args.sanitize_char) {
+            Ok(rules) => job = job.sanitize_names(rules),
+            Err(e) => {
+                output::error(format_args!("--sanitize-char: {}", e));
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(template) = &args.rename {
+        job = job.rename(template.clone());
+    }
+    if let Some(source) = &args.files_from {
+        match files_from::read(source, args.from0) {
+            Ok(list) => {
+                job = job
+                    .files_from(list)
+                    .ignore_missing_args(args.ignore_missing_args)
+            }
+            Err(e) => {
+                output::error(format_args!("--files-from: {}", e));
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(port) = args.port {
+        job = job.port(port);
+    }
+    if let Some(compare) = args.compare {
+        job = job.compare(compare);
+    }
+    if args.lock || args.no_lock {
+        job = job.lock(args.lock);
+    }
+    if args.continue_on_error || args.no_continue_on_error {
+        job = job.continue_on_error(args.continue_on_error);
+    }
+    if let Some(n) = args.max_files {
+        job = job.max_files(n);
+    }
+    if let Some(size) = args.max_total_size {
+        job = job.max_total_size(size);
+    }
+    if let Some(n) = args.max_deletes {
+        job = job.max_deletes(n);
+    }
+    job = job
+        .delete(args.delete)
+        .force_delete(args.force_delete)
+        .prune_empty_dirs(args.prune_empty_dirs)
+        .detect_renames(args.detect_renames);
+    if let Some(prefix) = &args.expect_content_type {
+        job = job.expect_content_type(prefix);
+    }
+    if let Some(n) = args.max_redirects {
+        job = job.max_redirects(n);
+    }
+    job = job.update(args.update);
+    if let Some(n) = args.http_segments {
+        job = job.http_segments(n);
+    }
+    job = job.http_mirror(args.http_mirror);
+    if let Some(n) = args.max_depth {
+        job = job.max_depth(n);
+    }
+    if let Some(ratio) = args.max_delete_ratio {
+        job = job.max_delete_ratio(ratio);
+    }
+    job = job.soft_limit(args.soft_limit);
+    if let Some(n) = args.stats_detail {
+        job = job.stats_detail(n);
+    }
+    if let Some(secs) = args.wait_lock {
+        job = job.wait_lock(std::time::Duration::from_secs(secs));
+    }
+    if let Some(path) = &args.state_file {
+        job = job.state_file(path);
+    }
+    job = job.no_state(args.no_state);
+    if let Some(dir) = &args.link_dest {
+        job = job.link_dest(dir);
+    }
+    if let Some(algorithm) = args.checksum {
+        job = job.checksum(algorithm);
+    }
+    job = job.tag_checksums(args.tag_checksums);
+    // An empty USYNC_TMPDIR means the default, as if it weren't set.
+    if let Some(dir) = args.tmpdir.as_ref().filter(|dir| !dir.is_empty()) {
+        job = job.tmpdir(dir);
+    }
+    job
+}
+
+/// Check a finished copy for `--checksum`. Returns `None` when the copy must be
+/// reported as failed, else the outcome that let it pass.
+fn verify_after_copy(
+    src: &protocol::Path,
+    dst: &protocol::Path,
+    algorithm: checksum::Algorithm,
+    mode: VerifyMode,
+    opts: &CopyOptions,
+) -> Option<Outcome> {
+    let result = verify::verify_copy(
+        src,
+        dst,
+        algorithm,
+        mode,
+        &opts.ssh_opts,
+        opts.tmpdir.as_deref(),
+        None,
+    );
+    report_verification(result, algorithm, mode, opts.verbose)
+}
+
+/// Print the outcome of a `--checksum` verification. Returns `None` when the copy must
+/// be reported as failed, else the outcome that let it pass.
+fn report_verification(
+    result: Result<Outcome, verify::VerifyError>,
+    algorithm: checksum::Algorithm,
+    mode: VerifyMode,
+    verbose: bool,
+) -> Option<Outcome> {
+    let problem = match result {
+        Ok(Outcome::Match) => {
+            if verbose {
+                println!("✓ Verified {} checksum", algorithm.name());
+            }
+            return Some(Outcome::Match);
+        }
+        Ok(Outcome::SizeMatch) => {
+            output::warn(format_args!(
+                "no {} checksum on both sides; only sizes were compared",
+                algorithm.name()
+            ));
+            return Some(Outcome::SizeMatch);
+        }
+        Ok(Outcome::Mismatch(reason)) => {
+            output::labeled(Severity::Error, "Checksum mismatch", reason);
+            return None;
+        }
+        Ok(Outcome::Unverifiable(reason)) => reason,
+        Err(e) => e.to_string(),
+    };
+
+    if mode == VerifyMode::Strict {
+        output::error(format_args!("could not verify copy: {}", problem));
+        None
+    } else {
+        output::warn(format_args!("could not verify copy: {}", problem));
+        Some(Outcome::Unverifiable(problem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_is_consistent() {
+        cli_command().debug_assert();
+    }
+
+    #[test]
+    fn test_bash_completions() {
+        let mut script = Vec::new();
+        clap_complete::generate(
+            clap_complete::Shell::Bash,
+            &mut cli_command(),
+            "usync",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        for word in [
+            "completions",
+            "manpage",
+            "checksum",
+            "dedupe",
+            "list",
+            "--verify-mode",
+            "strict best-effort",
+            "sha256",
+            "uring",
+        ] {
+            assert!(script.contains(word), "{} missing", word);
+        }
+        assert!(!script.contains("delta-server"));
+    }
+
+    fn opts(flags: &[&str]) -> CopyOptions {
+        let mut argv = vec!["usync"];
+        argv.extend_from_slice(flags);
+        argv.extend_from_slice(&["src", "dst"]);
+        transfer(&Args::try_parse_from(argv).unwrap()).copy_options()
+    }
+
+    #[test]
+    fn test_defaults_preserve_nothing() {
+        let o = opts(&[]);
+        assert!(!o.recursive);
+        assert_eq!(o.preserve, Preserve::default());
+        assert_eq!(o.symlinks, SymlinkPolicy::Follow);
+    }
+
+    #[test]
+    fn test_archive_expands() {
+        let o = opts(&["-a"]);
+        assert!(o.recursive);
+        assert_eq!(o.preserve, Preserve::all());
+        assert_eq!(o.symlinks, SymlinkPolicy::Preserve);
+    }
+
+    #[test]
+    fn test_archive_overrides() {
+        let o = opts(&["-a", "--no-times"]);
+        assert!(o.recursive);
+        assert!(o.preserve.mode);
+        assert!(!o.preserve.times);
+
+        let o = opts(&["-a", "--no-perms", "--no-links"]);
+        assert!(!o.preserve.mode);
+        assert!(o.preserve.times);
+        assert_eq!(o.symlinks, SymlinkPolicy::Follow);
+    }
+
+    #[test]
+    fn test_ownership_flags() {
+        assert_eq!(opts(&[]).ownership, Ownership::default());
+
+        let o = opts(&["--chown", "0:", "--group", "--groupmap", "10:20,30:40"]);
+        assert_eq!(o.ownership.chown.uid, Some(0));
+        assert_eq!(o.ownership.chown.gid, None);
+        assert!(!o.ownership.owner);
+        assert!(o.ownership.group);
+        assert_eq!(o.ownership.groupmap, vec![(10, 20), (30, 40)]);
+
+        // A map without the matching --owner/--group would do nothing.
+        let argv = ["usync", "--usermap", "1:2", "src", "dst"];
+        assert!(Args::try_parse_from(argv).is_err());
+    }
+
+    #[test]
+    fn test_individual_flags_without_archive() {
+        let o = opts(&["-t", "-l"]);
+        assert!(!o.recursive);
+        assert!(o.preserve.times);
+        assert!(!o.preserve.mode);
+        assert_eq!(o.symlinks, SymlinkPolicy::Preserve);
     }
 }
@@ -0,0 +1,208 @@
+//! `--http-mirror`: copy a directory that a web server publishes as an index page
+//! (Apache or nginx autoindex, Python's `http.server`) by following its links.
+//!
+//! Each index is fetched with curl and read by [`parse::http_index`]. The filters see
+//! the linked names as they see a local walk, and directories they exclude entirely
+//! aren't fetched. `--max-depth` bounds how far down the links are followed. With
+//! `--update`, a file whose size and time in the index match the local copy isn't
+//! requested at all; the others go through the usual conditional download.
+
+use std::fs;
+use std::path::Path;
+
+use crate::copy::{self, CopyError, CopyOptions, CopyStats};
+use crate::http::Download;
+use crate::parse::{self, IndexEntry};
+use crate::protocol::RemotePath;
+use crate::remote;
+
+/// Download what the index at `src` links to into the directory `dst`, keeping the
+/// directory structure.
+pub fn mirror(
+    src: &RemotePath,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    // Links in the index are relative to the directory, which a URL names with its `/`.
+    let mut root = src.clone();
+    if !root.is_dir_hint() {
+        root.path.push('/');
+        root.url.set_path(&root.path);
+    }
+    walk(&root, dst, Path::new(""), 1, opts, stats)
+}
+
+fn walk(
+    index: &RemotePath,
+    dst: &Path,
+    rel: &Path,
+    depth: usize,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let html = remote::http_get(index).map_err(CopyError::from)?;
+    let entries = parse::http_index(&html);
+    if opts.verbose {
+        println!("{}: {} entries", index.url, entries.len());
+    }
+    fs::create_dir_all(dst).map_err(|e| CopyError::IoError {
+        message: format!("Failed to create directory: {}", dst.display()),
+        error: e,
+    })?;
+    for entry in entries {
+        let entry_rel = rel.join(&entry.name);
+        if !opts.filters.is_included(&entry_rel, entry.is_dir) {
+            stats.files_excluded += 1;
+            continue;
+        }
+        let local = dst.join(&entry.name);
+        if entry.is_dir {
+            let deeper = opts.http.max_depth.is_none_or(|max| depth < max);
+            if deeper && !opts.filters.matches_dir(&entry_rel) {
+                let sub = index.join(&format!("{}/", entry.name));
+                walk(&sub, &local, &entry_rel, depth + 1, opts, stats)?;
+            }
+            continue;
+        }
+        if opts.http.update && unchanged(&entry, &local) {
+            if opts.verbose {
+                println!("Up to date: {}", local.display());
+            }
+            count_skipped(&local, stats);
+            continue;
+        }
+        let download = remote::copy_from_http_to_file(
+            &index.join(&entry.name),
+            &local,
+            opts.verbose,
+            opts.progress,
+            opts.compress,
+            &opts.http,
+        )
+        .map_err(CopyError::from)?;
+        match download {
+            Download::Fetched if stats.start_time.is_some() => {
+                stats.files_copied += 1;
+                stats.bytes_copied += fs::metadata(&local).map_or(0, |m| m.len());
+            }
+            Download::Fetched => {}
+            Download::NotModified => count_skipped(&local, stats),
+        }
+    }
+    Ok(())
+}
+
+/// Whether the index shows `entry` with the size of `local` and a time no later than
+/// its own. Without both in the index, the server is asked.
+fn unchanged(entry: &IndexEntry, local: &Path) -> bool {
+    let (Some(size), Some(modified)) = (entry.size, entry.modified) else {
+        return false;
+    };
+    match fs::metadata(local) {
+        Ok(metadata) => match copy::file_state(&metadata) {
+            (local_size, Some(local_modified)) => local_size == size && local_modified >= modified,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+fn count_skipped(local: &Path, stats: &mut CopyStats) {
+    if stats.start_time.is_none() {
+        return;
+    }
+    stats.files_skipped += 1;
+    stats.bytes_skipped += fs::metadata(local).map_or(0, |m| m.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::PatternFilter;
+    use crate::protocol::{parse_path, Path as ProtocolPath};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    /// Serve a small tree as nginx would index it, counting the file downloads.
+    fn spawn_index_server() -> (Arc<tiny_http::Server>, String, Arc<AtomicUsize>) {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let base = format!("http://{}/pub/", server.server_addr().to_ip().unwrap());
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let (worker, counter) = (server.clone(), downloads.clone());
+        std::thread::spawn(move || {
+            for request in worker.incoming_requests() {
+                let page = |links: &[(&str, &str)]| {
+                    let mut html = String::from("<html><body><pre><a href=\"../\">../</a>\n");
+                    for (href, columns) in links {
+                        html.push_str(&format!("<a href=\"{0}\">{0}</a>   {1}\n", href, columns));
+                    }
+                    html + "</pre></body></html>"
+                };
+                let body = match request.url() {
+                    "/pub/" => page(&[
+                        ("a%20b.txt", "01-Mar-2024 12:00   5"),
+                        ("skip.log", "01-Mar-2024 12:00   3"),
+                        ("sub/", "01-Mar-2024 12:00   -"),
+                        ("?C=M;O=A", ""),
+                    ]),
+                    "/pub/sub/" => page(&[
+                        ("c.txt", "01-Mar-2024 12:00   1"),
+                        ("deeper/", "01-Mar-2024 12:00   -"),
+                    ]),
+                    "/pub/sub/deeper/" => page(&[("d.txt", "01-Mar-2024 12:00   1")]),
+                    file => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        match file {
+                            "/pub/a%20b.txt" => "hello".to_string(),
+                            "/pub/skip.log" => "log".to_string(),
+                            _ => "x".to_string(),
+                        }
+                    }
+                };
+                let _ = request.respond(tiny_http::Response::from_string(body));
+            }
+        });
+        (server, base, downloads)
+    }
+
+    #[test]
+    fn test_mirror_index() {
+        if std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let (server, base, downloads) = spawn_index_server();
+        let ProtocolPath::Remote(src) = parse_path(&base).unwrap() else {
+            panic!("not a remote path");
+        };
+        let temp = TempDir::new().unwrap();
+        let dst = temp.path().join("mirror");
+        let mut opts = CopyOptions::default();
+        opts.filters
+            .push(PatternFilter::new(&[], &["*.log".to_string()]));
+        opts.http.max_depth = Some(2);
+        let mut stats = CopyStats::new();
+        mirror(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(fs::read_to_string(dst.join("a b.txt")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(dst.join("sub/c.txt")).unwrap(), "x");
+        assert!(!dst.join("skip.log").exists());
+        // Three levels down, past --max-depth 2.
+        assert!(!dst.join("sub/deeper").exists());
+        assert_eq!((stats.files_copied, stats.bytes_copied), (2, 6));
+        assert_eq!(stats.files_excluded, 1);
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+
+        // Same sizes, and newer than the index says: nothing is asked for again.
+        opts.http.update = true;
+        let mut stats = CopyStats::new();
+        mirror(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_copied, stats.files_skipped), (0, 2));
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+        server.unblock();
+    }
+}
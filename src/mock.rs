@@ -0,0 +1,276 @@
+//! Stand-in `ssh` and `scp` for testing SSH copies without a server (`test-util` feature).
+//!
+//! usync drives SSH through the `ssh` and `scp` programs, so the mock is a pair of
+//! scripts that do the same work on the local filesystem: `user@host:/some/path` is
+//! `/some/path` here, and ssh runs its remote command with `sh`. Put
+//! [`MockRemote::path_env`] in `PATH` for the `usync` process (or, for the library,
+//! the test process) and every SSH transfer goes through them.
+//!
+//! Beyond copying, the scripts record each call, can be slowed down, and fail on
+//! chosen paths like a server that refuses them.
+//!
+//! ```no_run
+//! use usync::mock::MockRemote;
+//!
+//! let mock = MockRemote::new()?;
+//! mock.fail("secret")?;
+//! let status = std::process::Command::new("usync")
+//!     .env("PATH", mock.path_env())
+//!     .args(["--no-ssh-multiplex", "notes.txt"])
+//!     .arg(mock.url("notes.txt"))
+//!     .status()?;
+//! assert!(status.success());
+//! assert_eq!(mock.calls()[0][0], "scp");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Shared start of both scripts: record the call, wait, and fail on chosen paths.
+const PRELUDE: &str = r#"printf '%s' "$tool" >> "$state/calls"
+for a; do printf '\t%s' "$a" >> "$state/calls"; done
+printf '\n' >> "$state/calls"
+if [ -f "$state/latency" ]; then sleep "$(cat "$state/latency")"; fi
+if [ -f "$state/fail" ]; then
+  for a; do
+    while IFS= read -r p; do
+      case "$a" in *"$p"*) echo "$tool: $p: Permission denied" >&2; exit 1;; esac
+    done < "$state/fail"
+  done
+fi
+"#;
+
+const SSH: &str = r#"while [ $# -gt 0 ]; do case "$1" in -o|-p) shift 2;; -*) shift;; *) break;; esac; done
+shift
+if [ -f "$state/cut-tar" ]; then
+  case "$*" in *"tar -x"*)
+    head -c "$(cat "$state/cut-tar")" | sh -c "$*"
+    echo 'tar: Unexpected EOF in archive' >&2; exit 2;;
+  esac
+fi
+exec sh -c "$*"
+"#;
+
+const SCP: &str = r#"r=
+for a; do
+  case "$a" in
+    -3) if [ -f "$state/no-3" ]; then echo 'scp: unknown option -- 3' >&2; exit 1; fi;;
+    -r) r=-r;;
+    @*) echo "ssh: Could not resolve hostname $a" >&2; exit 1;;
+  esac
+  src=$dst; dst=$a
+done
+cp $r "${src#*:}" "${dst#*:}" || exit 1
+if [ -f "$state/truncate" ]; then truncate -s -2 "${dst#*:}"; fi
+"#;
+
+/// A fake SSH host backed by a local directory.
+pub struct MockRemote {
+    dir: PathBuf,
+    _temp: Option<tempfile::TempDir>,
+}
+
+impl MockRemote {
+    /// A mock in a temporary directory of its own, removed when it is dropped.
+    pub fn new() -> io::Result<Self> {
+        let temp = tempfile::tempdir()?;
+        let mut mock = Self::in_dir(temp.path())?;
+        mock._temp = Some(temp);
+        Ok(mock)
+    }
+
+    /// A mock keeping its scripts, state and [`root`](MockRemote::root) under `dir`.
+    pub fn in_dir(dir: &Path) -> io::Result<Self> {
+        let mock = Self {
+            dir: dir.to_path_buf(),
+            _temp: None,
+        };
+        fs::create_dir_all(mock.bin())?;
+        fs::create_dir_all(mock.state())?;
+        fs::create_dir_all(mock.root())?;
+        for (tool, body) in [("ssh", SSH), ("scp", SCP)] {
+            let script = format!(
+                "#!/bin/sh\ntool={}\nstate='{}'\n{}{}",
+                tool,
+                mock.state().display(),
+                PRELUDE,
+                body
+            );
+            let path = mock.bin().join(tool);
+            fs::write(&path, script)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            }
+        }
+        Ok(mock)
+    }
+
+    fn bin(&self) -> PathBuf {
+        self.dir.join("bin")
+    }
+
+    fn state(&self) -> PathBuf {
+        self.dir.join("state")
+    }
+
+    /// A directory for the "remote" side's files. Any absolute path works as a remote
+    /// path; this one is just empty to begin with.
+    pub fn root(&self) -> PathBuf {
+        self.dir.join("remote")
+    }
+
+    /// `user@mock:<root>/<path>`, the remote spelling of `path` under [`root`](MockRemote::root).
+    pub fn url(&self, path: &str) -> String {
+        format!("user@mock:{}", self.root().join(path).display())
+    }
+
+    /// `PATH` with the mock's `ssh` and `scp` ahead of the real ones.
+    pub fn path_env(&self) -> OsString {
+        let mut dirs = vec![self.bin()];
+        if let Some(path) = std::env::var_os("PATH") {
+            dirs.extend(std::env::split_paths(&path));
+        }
+        std::env::join_paths(dirs).unwrap_or_else(|_| self.bin().into_os_string())
+    }
+
+    /// Refuse every call with an argument containing `path`, as "Permission denied".
+    pub fn fail(&self, path: &str) -> io::Result<()> {
+        use std::io::Write;
+        let mut list = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.state().join("fail"))?;
+        writeln!(list, "{}", path)
+    }
+
+    /// Wait `latency` at the start of every call.
+    pub fn set_latency(&self, latency: Duration) -> io::Result<()> {
+        fs::write(
+            self.state().join("latency"),
+            format!("{:.3}", latency.as_secs_f64()),
+        )
+    }
+
+    /// Cut the last two bytes off every file scp writes, like an interrupted transfer.
+    pub fn truncate_transfers(&self, on: bool) -> io::Result<()> {
+        self.flag("truncate", on)
+    }
+
+    /// Drop the connection after `bytes` of every tar stream ssh is sent, like a link
+    /// that goes down partway through a batch upload; `None` lets them through whole.
+    pub fn cut_tar_streams(&self, bytes: Option<u64>) -> io::Result<()> {
+        let path = self.state().join("cut-tar");
+        match bytes {
+            Some(bytes) => fs::write(path, bytes.to_string()),
+            None => self.flag("cut-tar", false),
+        }
+    }
+
+    /// Make scp reject `-3`, like OpenSSH before 8.x, so remote → remote copies relay.
+    pub fn without_scp_3(&self, on: bool) -> io::Result<()> {
+        self.flag("no-3", on)
+    }
+
+    fn flag(&self, name: &str, on: bool) -> io::Result<()> {
+        let path = self.state().join(name);
+        if on {
+            fs::write(path, "")
+        } else {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Every call so far, oldest first: the program name and its arguments.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        fs::read_to_string(self.state().join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.split('\t').map(str::to_string).collect())
+            .collect()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn scp(mock: &MockRemote, args: &[&str]) -> std::process::Output {
+        Command::new(mock.bin().join("scp"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_scp_copies_and_records() {
+        let mock = MockRemote::new().unwrap();
+        let src = mock.dir.join("a.txt");
+        fs::write(&src, "hello").unwrap();
+        let remote = mock.url("a.txt");
+
+        let output = scp(&mock, &["-q", src.to_str().unwrap(), &remote]);
+        assert!(output.status.success());
+        assert_eq!(
+            fs::read_to_string(mock.root().join("a.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            mock.calls(),
+            vec![vec![
+                "scp".to_string(),
+                "-q".to_string(),
+                src.display().to_string(),
+                remote
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_injected_failures_and_truncation() {
+        let mock = MockRemote::new().unwrap();
+        let src = mock.dir.join("a.txt");
+        fs::write(&src, "hello").unwrap();
+
+        mock.fail("secret").unwrap();
+        let output = scp(&mock, &[src.to_str().unwrap(), &mock.url("secret/a.txt")]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Permission denied"));
+
+        mock.truncate_transfers(true).unwrap();
+        scp(&mock, &[src.to_str().unwrap(), &mock.url("a.txt")]);
+        assert_eq!(
+            fs::read_to_string(mock.root().join("a.txt")).unwrap(),
+            "hel"
+        );
+        mock.truncate_transfers(false).unwrap();
+        scp(&mock, &[src.to_str().unwrap(), &mock.url("a.txt")]);
+        assert_eq!(
+            fs::read_to_string(mock.root().join("a.txt")).unwrap(),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn test_ssh_runs_the_command_after_latency() {
+        let mock = MockRemote::new().unwrap();
+        mock.set_latency(Duration::from_millis(200)).unwrap();
+        let start = std::time::Instant::now();
+        let output = Command::new(mock.bin().join("ssh"))
+            .args(["-o", "BatchMode=yes", "user@mock", "echo", "hi"])
+            .output()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+        assert_eq!(mock.calls()[0][0], "ssh");
+    }
+}
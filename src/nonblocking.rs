@@ -0,0 +1,666 @@
+//! Async front end to [`copy::copy`], for callers running on tokio (`tokio` feature).
+//!
+//! Local → local copies of plain files and directories run on `tokio::fs`: dropping the
+//! future stops the copy between two chunks and removes the partly written file. Every
+//! other transfer (remote sides, `delta`, `preserve`, hard links, `--ram`, ...) runs
+//! [`copy::copy`] on tokio's blocking pool. Dropping that future stops waiting for it,
+//! but a transfer tool already started runs to completion in the background.
+//!
+//! Both return a [`TransferReport`]. Progress goes to an optional [`mpsc::Sender`] as
+//! [`Event`]s instead of a terminal bar; transfers handed to the blocking pool send none.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::copy::{
+    self, Compare, CopyError, CopyMode, CopyOptions, CopyStats, Order, SymlinkPolicy,
+};
+use crate::output;
+use crate::path::LocalPath;
+use crate::protocol::Path as ProtocolPath;
+use crate::report::{FileError, Operation, SlowFiles, TransferReport};
+use crate::utils;
+
+/// Size of the reads and writes of a native copy, and so how often it reports progress.
+const CHUNK: usize = 1024 * 1024;
+
+/// Progress of an async copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// `copied` of the `total` bytes of the source file `path` are written.
+    Bytes {
+        path: PathBuf,
+        copied: u64,
+        total: u64,
+    },
+    /// The source file `path` was left alone because `compare` found it unchanged, or
+    /// because the destination is the same file.
+    Skipped { path: PathBuf },
+}
+
+/// [`copy::copy`] without blocking the calling task.
+pub async fn copy(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    events: Option<mpsc::Sender<Event>>,
+) -> Result<TransferReport, CopyError> {
+    match (src, dst) {
+        (ProtocolPath::Local(src), ProtocolPath::Local(dst)) if native(opts) => {
+            copy_local(src, dst, opts, events.as_ref()).await
+        }
+        _ => {
+            let (src, dst, opts) = (src.clone(), dst.clone(), opts.clone());
+            tokio::task::spawn_blocking(move || copy::copy(&src, &dst, &opts))
+                .await
+                .map_err(|e| CopyError::IoError {
+                    message: "Copy task failed".to_string(),
+                    error: io::Error::other(e),
+                })?
+        }
+    }
+}
+
+/// [`copy`] that skips files already matching at the destination: `opts.compare`,
+/// or size and modification time when it isn't set (`--compare size-mtime`).
+pub async fn sync(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    events: Option<mpsc::Sender<Event>>,
+) -> Result<TransferReport, CopyError> {
+    let mut opts = opts.clone();
+    opts.compare.get_or_insert(Compare::SizeMtime);
+    let mut report = copy(src, dst, &opts, events).await?;
+    report.operation = Operation::Sync;
+    Ok(report)
+}
+
+/// Whether a local copy with `opts` can run on `tokio::fs` rather than the blocking pool.
+fn native(opts: &CopyOptions) -> bool {
+    opts.use_ram != Some(true)
+        && !opts.delta
+        && !opts.append
+        && !opts.hard_links
+        && opts.link_dest.is_none()
+        && !opts.preserve.any()
+        && !opts.ownership.any()
+        && opts.chmod.is_none()
+        && !opts.specials
+        && !opts.devices
+        && opts.case_collisions.is_none()
+        && opts.sanitize_names.is_none()
+        && opts.rename.is_none()
+        && opts.files_from.is_none()
+        && !opts.prune_empty_dirs
+        && !opts.delete
+        && !opts.detect_renames
+        && !opts.dry_run
+        && opts.symlinks == SymlinkPolicy::Follow
+        && opts.copy_mode == CopyMode::Auto
+        && opts.order == Order::Default
+        && !opts.limits.is_set()
+}
+
+async fn copy_local(
+    src: &LocalPath,
+    dst: &LocalPath,
+    opts: &CopyOptions,
+    events: Option<&mpsc::Sender<Event>>,
+) -> Result<TransferReport, CopyError> {
+    let mut stats = CopyStats::new();
+    stats.slowest = SlowFiles::new(opts.stats_detail.unwrap_or(0));
+    if opts.space_check {
+        let (s, d, o) = (src.clone(), dst.clone(), opts.clone());
+        tokio::task::spawn_blocking(move || {
+            copy::check_local_space(&s, &d, &o, &mut CopyStats::new_minimal())
+        })
+        .await
+        .map_err(|e| CopyError::IoError {
+            message: "Space check failed".to_string(),
+            error: io::Error::other(e),
+        })??;
+    }
+
+    let src_path = src.as_path();
+    let metadata = fs::metadata(src_path)
+        .await
+        .map_err(|_| CopyError::SourceNotFound(src.to_string_lossy().into_owned()))?;
+    if metadata.is_dir() {
+        copy_tree(src_path, dst.as_path(), opts, &mut stats, events).await?;
+        return Ok(TransferReport::from_stats(Operation::Copy, &stats));
+    }
+    if !metadata.is_file() {
+        return Err(CopyError::InvalidSource(
+            "Source path is neither a file nor a directory".to_string(),
+        ));
+    }
+
+    // A destination written as `dir/` is a directory, created when missing.
+    let dst_path: PathBuf = dst.as_path().components().collect();
+    if dst.ends_with_separator() {
+        if let Some(parent) = dst_path.parent() {
+            check_parent(parent, opts).await?;
+        }
+        fs::create_dir_all(&dst_path)
+            .await
+            .map_err(|e| io_error("Failed to create destination directory", &dst_path, e))?;
+    }
+    let target = if fs::metadata(&dst_path).await.is_ok_and(|m| m.is_dir()) {
+        match src_path.file_name() {
+            Some(name) => dst_path.join(name),
+            None => return Err(CopyError::InvalidSource("Source file has no name".into())),
+        }
+    } else {
+        dst_path
+    };
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        check_parent(parent, opts).await?;
+        fs::create_dir_all(parent)
+            .await
+            .map_err(|e| io_error("Failed to create destination directory", parent, e))?;
+    }
+    copy_file(src_path, &target, opts, &mut stats, events).await?;
+    Ok(TransferReport::from_stats(Operation::Copy, &stats))
+}
+
+/// Fail like the blocking engine when the directory a file goes into is missing and
+/// `--mkpath` wasn't given.
+async fn check_parent(dir: &Path, opts: &CopyOptions) -> Result<(), CopyError> {
+    if opts.mkpath || opts.recursive || dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+    match fs::metadata(dir).await {
+        Ok(m) if m.is_dir() => Ok(()),
+        _ => Err(CopyError::MissingParent(dir.to_path_buf())),
+    }
+}
+
+/// Copy the contents of the directory `src` into `dst`, like the blocking engine does.
+async fn copy_tree(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+    events: Option<&mpsc::Sender<Event>>,
+) -> Result<(), CopyError> {
+    // Each directory is walked once, by the first path that reaches it, as the blocking
+    // walk does.
+    let mut visited: std::collections::HashSet<_> = utils::DirId::of(src).into_iter().collect();
+    let mut pending = vec![(src.to_path_buf(), dst.to_path_buf(), 0)];
+    while let Some((src_dir, dst_dir, depth)) = pending.pop() {
+        if !dst_dir.exists() {
+            if opts.verbose {
+                println!("Creating destination directory: {}", dst_dir.display());
+            }
+            stats.dirs_created += 1;
+        }
+        fs::create_dir_all(&dst_dir)
+            .await
+            .map_err(|e| io_error("Failed to create destination directory", &dst_dir, e))?;
+        if opts
+            .filters
+            .matches_dir(src_dir.strip_prefix(src).unwrap_or(&src_dir))
+        {
+            continue;
+        }
+
+        let mut entries = match fs::read_dir(&src_dir).await {
+            Err(e) if opts.continue_on_error && e.kind() == io::ErrorKind::PermissionDenied => {
+                output::warn(format_args!(
+                    "skipping unreadable directory: {} ({})",
+                    src_dir.display(),
+                    e
+                ));
+                stats.errors.push(FileError {
+                    path: src_dir.display().to_string(),
+                    message: format!("cannot read directory: {}", e),
+                });
+                continue;
+            }
+            entries => {
+                entries.map_err(|e| io_error("Failed to read source directory", &src_dir, e))?
+            }
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| io_error("Failed to read directory entry in", &src_dir, e))?
+        {
+            let entry_path = entry.path();
+            let dst_path = dst_dir.join(entry.file_name());
+            let metadata = match fs::metadata(&entry_path).await {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && opts.ignore_vanished => {
+                    stats.files_vanished += 1;
+                    continue;
+                }
+                Err(e) => return Err(io_error("Failed to read metadata", &entry_path, e)),
+            };
+            let rel = entry_path.strip_prefix(src).unwrap_or(&entry_path);
+            if !opts.filters.is_included(rel, metadata.is_dir()) {
+                if opts.verbose {
+                    println!("Excluding: {}", entry_path.display());
+                }
+                stats.files_excluded += 1;
+                continue;
+            }
+
+            if metadata.is_dir() {
+                if utils::DirId::of(&entry_path).is_some_and(|id| !visited.insert(id)) {
+                    output::warn(format_args!(
+                        "skipping {}: it leads to a directory already copied",
+                        entry_path.display()
+                    ));
+                    stats.symlink_loops += 1;
+                    continue;
+                }
+                if depth >= opts.depth_limit {
+                    return Err(CopyError::TooDeep {
+                        path: entry_path,
+                        limit: opts.depth_limit,
+                    });
+                }
+                pending.push((entry_path, dst_path, depth + 1));
+            } else if !metadata.is_file() {
+                output::warn(format_args!(
+                    "skipping special file {} (use --specials or --devices to recreate it)",
+                    entry_path.display()
+                ));
+                stats.specials_skipped += 1;
+            } else if copy::local_unchanged(&entry_path, &dst_path, opts) {
+                stats.files_skipped += 1;
+                stats.bytes_skipped += metadata.len();
+                send(events, Event::Skipped { path: entry_path }).await;
+            } else {
+                match copy_file(&entry_path, &dst_path, opts, stats, events).await {
+                    Err(CopyError::IoError { error, .. })
+                        if error.kind() == io::ErrorKind::NotFound
+                            && opts.ignore_vanished
+                            && !entry_path.exists() =>
+                    {
+                        stats.files_vanished += 1;
+                    }
+                    Err(CopyError::IoError { message, error })
+                        if opts.continue_on_error
+                            && error.kind() == io::ErrorKind::PermissionDenied =>
+                    {
+                        // The error may be about the destination; only an unreadable
+                        // source is skipped.
+                        if fs::File::open(&entry_path).await.is_ok() {
+                            return Err(CopyError::IoError { message, error });
+                        }
+                        output::warn(format_args!(
+                            "skipping unreadable file: {} ({})",
+                            entry_path.display(),
+                            error
+                        ));
+                        stats.errors.push(FileError {
+                            path: entry_path.display().to_string(),
+                            message: format!("cannot read file: {}", error),
+                        });
+                    }
+                    result => result?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy the file `src` to `dst` in chunks, reporting each one to `events`.
+async fn copy_file(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+    events: Option<&mpsc::Sender<Event>>,
+) -> Result<(), CopyError> {
+    if utils::same_file(src, dst) {
+        copy::skip_same_file(src, dst, stats);
+        send(
+            events,
+            Event::Skipped {
+                path: src.to_path_buf(),
+            },
+        )
+        .await;
+        return Ok(());
+    }
+    if opts.verbose {
+        println!("Copying file: {} -> {}", src.display(), dst.display());
+    }
+    let started = Instant::now();
+    let mut input = fs::File::open(src)
+        .await
+        .map_err(|e| io_error("Failed to open source file", src, e))?;
+    let total = input
+        .metadata()
+        .await
+        .map_err(|e| io_error("Failed to read metadata", src, e))?
+        .len();
+    // Armed before the create is awaited: a copy dropped while the file is being
+    // created leaves no empty file behind either.
+    let partial = Partial::new(dst);
+    let mut output = match partial.create().await {
+        Ok(output) => output,
+        Err(e) => {
+            // Whatever is there wasn't touched.
+            partial.keep();
+            return Err(io_error("Failed to create destination file", dst, e));
+        }
+    };
+
+    let mut buf = vec![0; CHUNK];
+    let mut copied = 0;
+    loop {
+        let n = input
+            .read(&mut buf)
+            .await
+            .map_err(|e| io_error("Failed to read source file", src, e))?;
+        if n == 0 {
+            break;
+        }
+        output
+            .write_all(&buf[..n])
+            .await
+            .map_err(|e| io_error("Failed to copy file", dst, e))?;
+        copied += n as u64;
+        let path = src.to_path_buf();
+        send(
+            events,
+            Event::Bytes {
+                path,
+                copied,
+                total,
+            },
+        )
+        .await;
+    }
+    output
+        .flush()
+        .await
+        .map_err(|e| io_error("Failed to copy file", dst, e))?;
+    if opts.fsync {
+        output
+            .sync_all()
+            .await
+            .map_err(|e| io_error("Failed to flush to disk", dst, e))?;
+    }
+    partial.keep();
+
+    stats.files_copied += 1;
+    stats.bytes_copied += copied;
+    stats.time_file(src, copied, started);
+    Ok(())
+}
+
+/// A destination file being written, removed unless the copy gets to [`Partial::keep`],
+/// so a failed or cancelled copy doesn't leave a truncated file behind.
+struct Partial {
+    path: Option<PathBuf>,
+    /// Set once the copy is dropped, for a create still running on the blocking pool.
+    dropped: Arc<AtomicBool>,
+}
+
+impl Partial {
+    fn new(path: &Path) -> Self {
+        Partial {
+            path: Some(path.to_path_buf()),
+            dropped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Create the file. The create runs on the blocking pool and isn't stopped by a drop,
+    /// so one that finishes after the copy was dropped removes the file itself.
+    async fn create(&self) -> io::Result<fs::File> {
+        let path = self.path.clone().unwrap_or_default();
+        let dropped = Arc::clone(&self.dropped);
+        let file = tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::create(&path)?;
+            if dropped.load(Ordering::SeqCst) {
+                drop(file);
+                let _ = std::fs::remove_file(&path);
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            Ok(file)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok(fs::File::from_std(file))
+    }
+
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            self.dropped.store(true, Ordering::SeqCst);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Hand `event` to the caller; a receiver that went away just stops getting events.
+async fn send(events: Option<&mpsc::Sender<Event>>, event: Event) {
+    if let Some(tx) = events {
+        let _ = tx.send(event).await;
+    }
+}
+
+fn io_error(message: &str, path: &Path, error: io::Error) -> CopyError {
+    CopyError::IoError {
+        message: format!("{}: {}", message, path.display()),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use std::fs as std_fs;
+    use tempfile::TempDir;
+
+    fn local(path: &Path) -> ProtocolPath {
+        parse_path(path.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_reports_progress() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("big.bin");
+        let data: Vec<u8> = (0..CHUNK * 2 + 10).map(|i| i as u8).collect();
+        std_fs::write(&src, &data).unwrap();
+        let dst = dir.path().join("copy.bin");
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let report = copy(
+            &local(&src),
+            &local(&dst),
+            &CopyOptions::default(),
+            Some(tx),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std_fs::read(&dst).unwrap(), data);
+        assert_eq!(report.files_copied, 1);
+        assert_eq!(report.bytes_copied, data.len() as u64);
+        let mut last = None;
+        while let Some(event) = rx.recv().await {
+            last = Some(event);
+        }
+        assert_eq!(
+            last,
+            Some(Event::Bytes {
+                path: src,
+                copied: data.len() as u64,
+                total: data.len() as u64,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_into_directory() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("a.txt");
+        std_fs::write(&src, "a").unwrap();
+        let out = dir.path().join("out");
+        let dst = parse_path(&format!("{}/", out.display())).unwrap();
+
+        copy(&local(&src), &dst, &CopyOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(std_fs::read_to_string(out.join("a.txt")).unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_copy_and_sync_directory() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        std_fs::create_dir_all(src.join("sub")).unwrap();
+        std_fs::write(src.join("a.txt"), "a").unwrap();
+        std_fs::write(src.join("sub/b.txt"), "bb").unwrap();
+        let dst = dir.path().join("dst");
+
+        let report = copy(&local(&src), &local(&dst), &CopyOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(report.dirs_created, 2);
+        assert_eq!(std_fs::read_to_string(dst.join("sub/b.txt")).unwrap(), "bb");
+
+        std_fs::write(src.join("c.txt"), "ccc").unwrap();
+        let (tx, mut rx) = mpsc::channel(16);
+        let report = sync(
+            &local(&src),
+            &local(&dst),
+            &CopyOptions::default(),
+            Some(tx),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.files_copied, 1);
+        assert_eq!(report.files_skipped, 2);
+        assert_eq!(report.operation, Operation::Sync);
+        assert_eq!(std_fs::read_to_string(dst.join("c.txt")).unwrap(), "ccc");
+        let mut skipped = Vec::new();
+        while let Some(event) = rx.recv().await {
+            if let Event::Skipped { path } = event {
+                skipped.push(path);
+            }
+        }
+        skipped.sort();
+        assert_eq!(skipped, vec![src.join("a.txt"), src.join("sub/b.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_copy_removes_the_partial_file() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("big.bin");
+        std_fs::write(&src, vec![7u8; CHUNK * 4]).unwrap();
+        let dst = dir.path().join("copy.bin");
+
+        // Nobody reads the events, so the copy stalls after the first chunk.
+        let (tx, _rx) = mpsc::channel(1);
+        let (src_path, dst_path) = (local(&src), local(&dst));
+        let opts = CopyOptions::default();
+        let result = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            copy(&src_path, &dst_path, &opts, Some(tx)),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(!dst.exists());
+    }
+
+    /// Dropped while the destination is being created: nothing is left at its path.
+    /// Opening a FIFO for writing waits for a reader, which holds the copy at the create.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_copy_dropped_at_create() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("a.txt");
+        std_fs::write(&src, "a").unwrap();
+        let dst = dir.path().join("b.txt");
+        let made = std::process::Command::new("mkfifo")
+            .arg(&dst)
+            .status()
+            .unwrap();
+        assert!(made.success());
+        // Another name for the FIFO, to let the create go once the copy is dropped.
+        let alias = dir.path().join("alias");
+        std_fs::hard_link(&dst, &alias).unwrap();
+        let opts = CopyOptions::default();
+        let mut stats = CopyStats::new();
+
+        let mut copying = Box::pin(copy_file(&src, &dst, &opts, &mut stats, None));
+        let mut cx = Context::from_waker(Waker::noop());
+        for _ in 0..20 {
+            assert!(matches!(copying.as_mut().poll(&mut cx), Poll::Pending));
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        drop(copying);
+        let left = std_fs::symlink_metadata(&dst).is_ok();
+        drop(std_fs::File::open(&alias).unwrap());
+        assert!(!left);
+    }
+
+    /// `--delete` isn't done natively, so a sync that asks for it gets the blocking
+    /// engine, which removes what the source no longer has.
+    #[tokio::test]
+    async fn test_sync_with_delete() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        std_fs::create_dir_all(&src).unwrap();
+        std_fs::create_dir_all(&dst).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std_fs::write(src.join(name), "a").unwrap();
+            std_fs::write(dst.join(name), "old").unwrap();
+        }
+        std_fs::write(dst.join("extra.txt"), "gone").unwrap();
+        let opts = CopyOptions {
+            recursive: true,
+            delete: true,
+            ..CopyOptions::default()
+        };
+        assert!(!native(&opts));
+
+        let report = sync(&local(&src), &local(&dst), &opts, None).await.unwrap();
+        assert_eq!(report.files_deleted, 1);
+        assert!(!dst.join("extra.txt").exists());
+        assert_eq!(std_fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_other_options_use_the_blocking_engine() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("a.txt");
+        std_fs::write(&src, "a").unwrap();
+        let dst = dir.path().join("b.txt");
+        let opts = CopyOptions {
+            preserve: copy::Preserve::all(),
+            ..CopyOptions::default()
+        };
+        assert!(!native(&opts));
+
+        copy(&local(&src), &local(&dst), &opts, None).await.unwrap();
+        assert_eq!(std_fs::read_to_string(&dst).unwrap(), "a");
+    }
+}
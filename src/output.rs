@@ -0,0 +1,164 @@
+//! Messages for the person running usync: results on stdout, warnings and errors on
+//! stderr, in color with the `color` feature.
+//!
+//! Each stream is colored only when it is a terminal, so output piped to a file or
+//! another program stays plain. `NO_COLOR` (set to anything) or `CLICOLOR=0` turns
+//! color off; `CLICOLOR_FORCE` (other than `0`) turns it on for pipes too. `--color`
+//! ([`set_color`]) overrides all of that.
+
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+use crate::report::Severity;
+
+/// When to color (`--color`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// On terminals, unless the environment says otherwise.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static CHOICE: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Color as `choice` says from now on; only the first call counts, and it should come
+/// before the first message.
+pub fn set_color(choice: ColorChoice) {
+    let _ = CHOICE.set(choice);
+}
+
+/// Where a message goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// `text` in the color of `severity` when `stream` is colored; plain counts stay plain.
+pub fn paint(text: &str, severity: Severity, stream: Stream) -> String {
+    if !enabled(stream) {
+        return text.to_string();
+    }
+    #[cfg(feature = "color")]
+    {
+        use colored::Color;
+        let (color, bold) = match severity {
+            Severity::Info => return text.to_string(),
+            Severity::Ok => (Color::Green, false),
+            Severity::Warning => (Color::Yellow, true),
+            Severity::Error => (Color::Red, true),
+        };
+        format!(
+            "\x1b[{}{}m{}\x1b[0m",
+            if bold { "1;" } else { "" },
+            color.to_fg_str(),
+            text
+        )
+    }
+    #[cfg(not(feature = "color"))]
+    {
+        let _ = severity;
+        text.to_string()
+    }
+}
+
+/// `label: message` on stderr, the label in the color of `severity`.
+pub fn labeled(severity: Severity, label: &str, message: impl Display) {
+    eprintln!("{}: {}", paint(label, severity, Stream::Stderr), message);
+}
+
+/// `Error: message` on stderr.
+pub fn error(message: impl Display) {
+    labeled(Severity::Error, "Error", message);
+}
+
+/// `Warning: message` on stderr.
+pub fn warn(message: impl Display) {
+    labeled(Severity::Warning, "Warning", message);
+}
+
+/// `lead rest` on stdout, for a transfer that went through; the lead in green.
+pub fn success(lead: &str, rest: impl Display) {
+    println!("{} {}", paint(lead, Severity::Ok, Stream::Stdout), rest);
+}
+
+/// `message` on stdout, as it is.
+pub fn info(message: impl Display) {
+    println!("{}", message);
+}
+
+/// Whether messages to `stream` are colored.
+pub fn enabled(stream: Stream) -> bool {
+    if !cfg!(feature = "color") {
+        return false;
+    }
+    match CHOICE.get().copied().unwrap_or_default() {
+        ColorChoice::Always => return true,
+        ColorChoice::Never => return false,
+        ColorChoice::Auto => {}
+    }
+    static STDOUT: OnceLock<bool> = OnceLock::new();
+    static STDERR: OnceLock<bool> = OnceLock::new();
+    let env = |name: &str| std::env::var(name).ok();
+    match stream {
+        Stream::Stdout => *STDOUT.get_or_init(|| wanted(env, std::io::stdout().is_terminal())),
+        Stream::Stderr => *STDERR.get_or_init(|| wanted(env, std::io::stderr().is_terminal())),
+    }
+}
+
+/// Whether the environment (`var`) asks for color on a stream that is, or isn't, a
+/// `terminal`.
+fn wanted(var: impl Fn(&str) -> Option<String>, terminal: bool) -> bool {
+    if var("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    if var("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0") {
+        return true;
+    }
+    if var("CLICOLOR").is_some_and(|v| v == "0") {
+        return false;
+    }
+    terminal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_terminals_only() {
+        assert!(wanted(env(&[]), true));
+        assert!(!wanted(env(&[]), false));
+    }
+
+    #[test]
+    fn test_no_color() {
+        assert!(!wanted(env(&[("NO_COLOR", "1")]), true));
+        assert!(!wanted(env(&[("NO_COLOR", "0")]), true));
+        // Set but empty doesn't count.
+        assert!(wanted(env(&[("NO_COLOR", "")]), true));
+        assert!(!wanted(
+            env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]),
+            true
+        ));
+    }
+
+    #[test]
+    fn test_clicolor() {
+        assert!(!wanted(env(&[("CLICOLOR", "0")]), true));
+        assert!(wanted(env(&[("CLICOLOR", "1")]), true));
+        assert!(wanted(env(&[("CLICOLOR_FORCE", "1")]), false));
+        assert!(!wanted(env(&[("CLICOLOR_FORCE", "0")]), false));
+    }
+}
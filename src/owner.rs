@@ -0,0 +1,264 @@
+//! Ownership of local destinations: `--chown`, and `--owner`/`--group` with
+//! `--usermap`/`--groupmap`.
+//!
+//! User and group names are resolved to IDs once, when the options are parsed, through
+//! `id` and `getent` (or `/etc/group` where there is no `getent`). Changing the owner of
+//! a file takes root; without it the first refusal is reported once and ownership is
+//! left alone from then on.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::output;
+
+/// Who owns the copies made in a local destination.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ownership {
+    /// `--chown`: owner forced onto every destination file and directory.
+    pub chown: Chown,
+    /// `--owner`: give copies the source's owner, translated by `usermap`.
+    pub owner: bool,
+    /// `--group`: give copies the source's group, translated by `groupmap`.
+    pub group: bool,
+    /// `--usermap` pairs of source and destination user IDs.
+    pub usermap: Vec<(u32, u32)>,
+    /// `--groupmap` pairs of source and destination group IDs.
+    pub groupmap: Vec<(u32, u32)>,
+}
+
+/// `--chown USER:GROUP`; either half may be left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Chown {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Ownership {
+    /// Whether copies get anything other than the owner of whoever wrote them.
+    pub fn any(&self) -> bool {
+        self.chown.uid.is_some() || self.chown.gid.is_some() || self.owner || self.group
+    }
+
+    /// Owner and group for the copy of an entry owned by `uid:gid`. `None` leaves that
+    /// half as it is. `--chown` wins over `--owner`/`--group`.
+    pub fn target(&self, uid: u32, gid: u32) -> (Option<u32>, Option<u32>) {
+        let mapped = |on: bool, map: &[(u32, u32)], id: u32| {
+            on.then(|| map.iter().find(|(from, _)| *from == id).map_or(id, |p| p.1))
+        };
+        (
+            self.chown.uid.or(mapped(self.owner, &self.usermap, uid)),
+            self.chown.gid.or(mapped(self.group, &self.groupmap, gid)),
+        )
+    }
+}
+
+/// Set when a change of owner was refused, so the rest of the copy doesn't try again.
+static DENIED: AtomicBool = AtomicBool::new(false);
+
+/// Give `dst` the owner and group `ownership` asks for, given its source `src`.
+///
+/// A refusal for lack of privilege (`EPERM`) is not an error: it prints a warning the
+/// first time and turns this into a no-op for the rest of the process.
+#[cfg(unix)]
+pub fn apply(src: &Path, dst: &Path, ownership: &Ownership) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if !ownership.any() || DENIED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let metadata = fs::metadata(src)?;
+    let (uid, gid) = ownership.target(metadata.uid(), metadata.gid());
+    match std::os::unix::fs::chown(dst, uid, gid) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            if !DENIED.swap(true, Ordering::Relaxed) {
+                output::warn(format_args!("not permitted to change ownership ({}); owners are left as they are. Run as root to set them.", e));
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn apply(_src: &Path, _dst: &Path, ownership: &Ownership) -> io::Result<()> {
+    if !ownership.any() || DENIED.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+    output::warn("ownership can only be set on Unix; owners are left as they are.");
+    Ok(())
+}
+
+/// Parse `--chown USER:GROUP`, `USER`, `USER:` or `:GROUP`, by name or numeric ID.
+pub fn parse_chown(s: &str) -> Result<Chown, String> {
+    let (user, group) = s.split_once(':').unwrap_or((s, ""));
+    let chown = Chown {
+        uid: Some(user)
+            .filter(|u| !u.is_empty())
+            .map(user_id)
+            .transpose()?,
+        gid: Some(group)
+            .filter(|g| !g.is_empty())
+            .map(group_id)
+            .transpose()?,
+    };
+    if chown == Chown::default() {
+        return Err(format!("invalid owner {:?}; use USER:GROUP", s));
+    }
+    Ok(chown)
+}
+
+/// Parse one `--usermap OLD:NEW` pair.
+pub fn parse_user_pair(s: &str) -> Result<(u32, u32), String> {
+    parse_pair(s, user_id)
+}
+
+/// Parse one `--groupmap OLD:NEW` pair.
+pub fn parse_group_pair(s: &str) -> Result<(u32, u32), String> {
+    parse_pair(s, group_id)
+}
+
+fn parse_pair(s: &str, id: fn(&str) -> Result<u32, String>) -> Result<(u32, u32), String> {
+    match s.split_once(':') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((id(from)?, id(to)?)),
+        _ => Err(format!("invalid mapping {:?}; use OLD:NEW", s)),
+    }
+}
+
+/// The ID of the user `name`, or `name` itself when it is numeric.
+pub fn user_id(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    Command::new("id")
+        .args(["-u", "--", name])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8_lossy(&out.stdout).trim().parse().ok())
+        .ok_or_else(|| format!("unknown user {:?}", name))
+}
+
+/// The ID of the group `name`, or `name` itself when it is numeric.
+pub fn group_id(name: &str) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let entry = match Command::new("getent").args(["group", name]).output() {
+        Ok(out) if out.status.success() => Some(String::from_utf8_lossy(&out.stdout).into_owned()),
+        Ok(_) => None,
+        Err(_) => fs::read_to_string("/etc/group").ok(),
+    };
+    entry
+        .and_then(|entries| group_in(&entries, name))
+        .ok_or_else(|| format!("unknown group {:?}", name))
+}
+
+/// The ID of `name` in `group(5)` lines.
+fn group_in(entries: &str, name: &str) -> Option<u32> {
+    entries.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chown() {
+        assert_eq!(
+            parse_chown("1000:1001").unwrap(),
+            Chown {
+                uid: Some(1000),
+                gid: Some(1001)
+            }
+        );
+        assert_eq!(parse_chown("1000").unwrap().gid, None);
+        assert_eq!(parse_chown("1000:").unwrap().gid, None);
+        assert_eq!(parse_chown(":0").unwrap().uid, None);
+        assert_eq!(parse_chown("root:").unwrap().uid, Some(0));
+        assert!(parse_chown(":").is_err());
+        assert!(parse_chown("").is_err());
+        assert!(parse_chown("no-such-user-usync:").is_err());
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        assert_eq!(parse_user_pair("1000:33").unwrap(), (1000, 33));
+        assert_eq!(parse_user_pair("root:5").unwrap(), (0, 5));
+        assert!(parse_user_pair("1000").is_err());
+        assert!(parse_user_pair(":33").is_err());
+        assert!(parse_group_pair("1000:").is_err());
+        assert!(parse_group_pair("no-such-group-usync:1").is_err());
+    }
+
+    #[test]
+    fn test_group_in() {
+        let entries = "root:x:0:\nwheel:x:10:root,alice\nbroken\n";
+        assert_eq!(group_in(entries, "wheel"), Some(10));
+        assert_eq!(group_in(entries, "root"), Some(0));
+        assert_eq!(group_in(entries, "whe"), None);
+        assert_eq!(group_in(entries, "broken"), None);
+    }
+
+    #[test]
+    fn test_target() {
+        let none = Ownership::default();
+        assert!(!none.any());
+        assert_eq!(none.target(1000, 1000), (None, None));
+
+        let mapped = Ownership {
+            owner: true,
+            group: true,
+            usermap: vec![(1000, 33)],
+            groupmap: vec![(1000, 34)],
+            ..Ownership::default()
+        };
+        assert_eq!(mapped.target(1000, 1000), (Some(33), Some(34)));
+        assert_eq!(mapped.target(500, 501), (Some(500), Some(501)));
+
+        // --chown wins; the half it leaves out still follows --group.
+        let forced = Ownership {
+            chown: Chown {
+                uid: Some(0),
+                gid: None,
+            },
+            ..mapped
+        };
+        assert_eq!(forced.target(1000, 1000), (Some(0), Some(34)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::write(&src, "x").unwrap();
+        fs::write(&dst, "x").unwrap();
+        let root = fs::metadata(&src).unwrap().uid() == 0;
+
+        let ownership = Ownership {
+            chown: Chown {
+                uid: Some(4321),
+                gid: Some(4322),
+            },
+            ..Ownership::default()
+        };
+        // Without root the refusal is a warning, not an error.
+        apply(&src, &dst, &ownership).unwrap();
+        if root {
+            let metadata = fs::metadata(&dst).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (4321, 4322));
+        }
+    }
+}
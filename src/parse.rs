@@ -0,0 +1,643 @@
+//! Readers for what the external tools print: `find` and `wc` over ssh, `aws s3api`
+//! listings, `curl -I` headers, and the directory index pages web servers generate.
+//!
+//! This output comes from another machine, another locale or another version of the
+//! tool, so nothing here indexes into it or panics. A listing skips the records it can't
+//! read and counts them; only output with nothing readable in it is an error. The
+//! functions are public so the targets in `fuzz/` can drive them.
+
+use std::fmt;
+
+use crate::list::{self, Entry};
+use crate::output;
+use crate::remote::RemoteTree;
+
+/// Output that couldn't be read at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What was being read, e.g. `find listing`.
+    pub what: &'static str,
+    /// The first unreadable line, cut short if long.
+    pub line: String,
+}
+
+/// How much of an unreadable line an error quotes.
+const QUOTED_LEN: usize = 80;
+
+impl ParseError {
+    fn new(what: &'static str, line: &str) -> Self {
+        Self {
+            what,
+            line: line.chars().take(QUOTED_LEN).collect(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unreadable {} output: {:?}", self.what, self.line)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The entries read from a listing, and how many of its records were skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listing {
+    pub entries: Vec<Entry>,
+    pub skipped: usize,
+}
+
+impl Listing {
+    /// The entries, after warning about any skipped records of the listing of `source`.
+    pub fn warn_skipped(self, source: &str) -> Vec<Entry> {
+        if self.skipped > 0 {
+            output::warn(format_args!(
+                "skipped {} unreadable line(s) listing {}",
+                self.skipped, source
+            ));
+        }
+        self.entries
+    }
+}
+
+/// Collect the entries `read` finds in `records`. An error only when records were there
+/// and none of them could be read.
+fn listing<'a>(
+    what: &'static str,
+    records: impl Iterator<Item = &'a str>,
+    read: impl Fn(&str) -> Result<Option<Entry>, ()>,
+) -> Result<Listing, ParseError> {
+    let mut listing = Listing::default();
+    let mut first_bad = None;
+    for record in records {
+        match read(record) {
+            Ok(Some(entry)) => listing.entries.push(entry),
+            Ok(None) => {}
+            Err(()) => {
+                listing.skipped += 1;
+                first_bad.get_or_insert(record);
+            }
+        }
+    }
+    match first_bad {
+        Some(line) if listing.entries.is_empty() => Err(ParseError::new(what, line)),
+        _ => Ok(listing),
+    }
+}
+
+/// NUL-terminated `<size> <mtime|-> ./<path>` records, from `find -printf '%s %T@ %p\0'`
+/// or the `wc -c` loop that stands in for it where `find` has no `-printf` (BusyBox).
+/// The mtime's fraction may follow a `,` where the locale says so.
+pub fn file_list(output: &str) -> Result<Listing, ParseError> {
+    let records = output
+        .split('\0')
+        .map(|record| record.trim_start_matches(['\n', '\r']))
+        .filter(|record| !record.trim().is_empty());
+    listing("find listing", records, |record| {
+        let mut fields = record.splitn(3, ' ');
+        let (Some(size), Some(modified), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(());
+        };
+        let size = size.parse().map_err(|_| ())?;
+        let modified = match modified {
+            "-" => None,
+            secs => {
+                let whole = secs.split(['.', ',']).next().unwrap_or_default();
+                Some(whole.parse().map_err(|_| ())?)
+            }
+        };
+        let path = path.strip_prefix("./").unwrap_or(path);
+        if path.is_empty() {
+            return Err(());
+        }
+        Ok(Some(Entry {
+            path: path.to_string(),
+            size,
+            modified,
+        }))
+    })
+}
+
+/// `<key>\t<size>\t<last modified>` lines from `aws s3api list-objects-v2 --query
+/// 'Contents[].[Key,Size,LastModified]' --output text`, paths made relative to `prefix`.
+/// A key given without its trailing `/` lists the object of that name and everything
+/// below `key/`, but not `key2/...`. aws prints `None` for an empty listing.
+pub fn s3_listing(output: &str, prefix: &str) -> Result<Listing, ParseError> {
+    let lines = output
+        .lines()
+        .filter(|line| !line.trim().is_empty() && line.trim() != "None");
+    listing("aws s3api listing", lines, |line| {
+        // From the right, so that a tab in a key stays in the key.
+        let mut fields = line.rsplitn(3, '\t');
+        let (Some(modified), Some(size), Some(object)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(());
+        };
+        let size = size.trim().parse().map_err(|_| ())?;
+        let path = if prefix.is_empty() || prefix.ends_with('/') {
+            object.strip_prefix(prefix)
+        } else if object == prefix {
+            object.rsplit('/').next()
+        } else {
+            object
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix('/'))
+        };
+        // Keys outside the prefix, and zero-byte `dir/` folder markers, aren't files.
+        let Some(path) = path.filter(|p| !p.is_empty() && !p.ends_with('/')) else {
+            return Ok(None);
+        };
+        Ok(Some(Entry {
+            path: path.to_string(),
+            size,
+            modified: list::parse_time(modified),
+        }))
+    })
+}
+
+/// `dir` or `file`, then one `wc -c` size per line: the sizes of the files below a
+/// directory, or of the one file.
+pub fn stat_tree(output: &str) -> Result<RemoteTree, ParseError> {
+    const WHAT: &str = "remote stat";
+    let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+    let is_dir = match lines.next() {
+        Some("dir") => true,
+        Some("file") => false,
+        other => return Err(ParseError::new(WHAT, other.unwrap_or_default())),
+    };
+    let sizes = lines
+        .map(|line| line.parse().map_err(|_| ParseError::new(WHAT, line)))
+        .collect::<Result<Vec<u64>, _>>()?;
+    if !is_dir && sizes.len() != 1 {
+        return Err(ParseError::new(WHAT, output.trim()));
+    }
+    Ok(RemoteTree { is_dir, sizes })
+}
+
+/// `Content-Length` of the last response in `curl -I` output, unless that response is
+/// content-encoded (the length is then not what lands on disk).
+pub fn content_length(head: &str) -> Option<u64> {
+    let last = head
+        .split("\r\n\r\n")
+        .flat_map(|block| block.split("\n\n"))
+        .filter(|block| block.trim_start().starts_with("HTTP/"))
+        .last()?;
+    let mut length = None;
+    for line in last.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse().ok(),
+            "content-encoding" if !value.eq_ignore_ascii_case("identity") => return None,
+            _ => {}
+        }
+    }
+    length
+}
+
+/// One link of an HTTP directory index, with what the index shows next to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// The linked name, percent-escapes decoded, without a directory's trailing `/`.
+    pub name: String,
+    pub is_dir: bool,
+    /// Exact sizes only: Apache's `1.2K` is left out.
+    pub size: Option<u64>,
+    /// Seconds since the epoch, taking the index's time (to the minute) as UTC.
+    pub modified: Option<i64>,
+}
+
+/// The files and directories an autoindex page links to: Apache's table or `<pre>`
+/// listing, nginx's `<pre>` listing, Python's `http.server` list. Links that aren't
+/// entries of the directory (the parent, column sorting, other sites, anything with a
+/// `/` inside) are left out, as is anything that isn't markup of a link.
+pub fn http_index(html: &str) -> Vec<IndexEntry> {
+    // ASCII lowercasing keeps byte offsets, so positions found in it index `html` too.
+    let lower = html.to_ascii_lowercase();
+    let starts: Vec<usize> = lower
+        .match_indices("<a")
+        .map(|(i, _)| i)
+        .filter(|&i| {
+            lower[i + 2..]
+                .chars()
+                .next()
+                .is_some_and(char::is_whitespace)
+        })
+        .collect();
+    let mut entries: Vec<IndexEntry> = Vec::new();
+    for (n, &start) in starts.iter().enumerate() {
+        let next = starts.get(n + 1).copied().unwrap_or(html.len());
+        let Some(tag_end) = lower[start..next].find('>').map(|i| start + i) else {
+            continue;
+        };
+        let Some(name) = href(&html[start..tag_end]).and_then(|h| index_name(&h)) else {
+            continue;
+        };
+        let is_dir = name.ends_with('/');
+        let name = name.trim_end_matches('/').to_string();
+        // What follows the link up to the next one: the date and size columns.
+        let after = lower[tag_end..next]
+            .find("</a")
+            .map_or(next, |i| tag_end + i);
+        let (size, modified) = index_columns(&html[after..next]);
+        match entries.iter_mut().find(|e| e.name == name) {
+            // Icons that link to the file too, with the columns after the second link.
+            Some(seen) => {
+                seen.size = seen.size.or(size);
+                seen.modified = seen.modified.or(modified);
+            }
+            None => entries.push(IndexEntry {
+                name,
+                is_dir,
+                size: if is_dir { None } else { size },
+                modified,
+            }),
+        }
+    }
+    entries
+}
+
+/// The `href` value of an `<a ...` tag, entities decoded.
+fn href(tag: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("href").map(|i| from + i) {
+        from = i + 4;
+        let rest = tag[from..].trim_start();
+        let Some(rest) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let value = match rest.chars().next()? {
+            quote @ ('"' | '\'') => rest[1..].split(quote).next()?,
+            _ => rest.split(|c: char| c.is_whitespace() || c == '>').next()?,
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+/// The entry an `href` names, as `name` or `name/`, or `None` for links that don't
+/// name an entry of the directory.
+fn index_name(href: &str) -> Option<String> {
+    let href = href.trim();
+    let href = href.strip_prefix("./").unwrap_or(href);
+    if href.is_empty()
+        || href.starts_with(['?', '#', '/'])
+        || href.contains([':', '?', '#'])
+        || href.trim_end_matches('/').contains('/')
+    {
+        return None;
+    }
+    let name = percent_decode(href);
+    let bare = name.trim_end_matches('/');
+    if bare.is_empty() || bare.contains(['/', '\0']) || bare == "." || bare == ".." {
+        return None;
+    }
+    Some(name)
+}
+
+/// The size and time in the text after an index link, if it shows them: a date
+/// (`2024-03-01` or `01-Mar-2024`) and `HH:MM`, then a size in bytes.
+fn index_columns(markup: &str) -> (Option<u64>, Option<i64>) {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in markup.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = decode_entities(&text).replace('\u{a0}', " ");
+    let words: Vec<&str> = text.split_whitespace().collect();
+    for (i, pair) in words.windows(2).enumerate() {
+        let Some(modified) = index_time(pair[0], pair[1]) else {
+            continue;
+        };
+        let size = words.get(i + 2).and_then(|w| w.parse().ok());
+        return (size, Some(modified));
+    }
+    (None, None)
+}
+
+/// `2024-03-01` or `01-Mar-2024`, and `12:00` or `12:00:30`, as UTC.
+fn index_time(date: &str, time: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let parts: Vec<&str> = date.split('-').collect();
+    let (year, month, day) = match parts[..] {
+        [y, m, d] if y.len() == 4 => (y, m.to_string(), d),
+        [d, m, y] if y.len() == 4 => {
+            let month = MONTHS
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(m))?;
+            (y, format!("{:02}", month + 1), d)
+        }
+        _ => return None,
+    };
+    let time = match time.len() {
+        5 => format!("{}:00", time),
+        8 => time.to_string(),
+        _ => return None,
+    };
+    list::parse_time(&format!("{}-{}-{:0>2}T{}Z", year, month, day, time))
+}
+
+/// `&amp;`, `&lt;`, `&gt;`, `&quot;`, `&#39;`/`&#x27;` and `&nbsp;`; anything else is
+/// left as it is.
+fn decode_entities(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let entity = rest.find(';').map(|end| (&rest[1..end], end + 1));
+        let decoded = entity.and_then(|(name, len)| {
+            let c = match name {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" | "#39" | "#x27" => '\'',
+                "nbsp" => '\u{a0}',
+                _ => return None,
+            };
+            Some((c, len))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decode `%XX` escapes; bytes that don't make UTF-8 are replaced.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match (bytes[i], bytes.get(i + 1), bytes.get(i + 2)) {
+            (b'%', Some(&h), Some(&l)) if hex(h).is_some() && hex(l).is_some() => {
+                out.push((hex(h).unwrap_or(0) * 16 + hex(l).unwrap_or(0)) as u8);
+                i += 3;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_stat_tree() {
+        assert_eq!(
+            stat_tree("file\n1234\n"),
+            Ok(RemoteTree {
+                is_dir: false,
+                sizes: vec![1234]
+            })
+        );
+        let tree = stat_tree("dir\n10\n   20\n0\n").unwrap();
+        assert!(tree.is_dir);
+        assert_eq!(tree.sizes.len(), 3);
+        assert_eq!(tree.total_size(), 30);
+        assert_eq!(
+            stat_tree("dir\n"),
+            Ok(RemoteTree {
+                is_dir: true,
+                sizes: vec![]
+            })
+        );
+        assert!(stat_tree("file\n").is_err());
+        assert_eq!(
+            stat_tree("wc: x: No such file\n").unwrap_err().line,
+            "wc: x: No such file"
+        );
+        assert!(stat_tree("").is_err());
+    }
+
+    #[test]
+    fn test_content_length() {
+        let redirected = "HTTP/1.1 301 Moved Permanently\r\nContent-Length: 162\r\nLocation: /b\r\n\r\n\
+                          HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\ncontent-length: 4096\r\n\r\n";
+        assert_eq!(content_length(redirected), Some(4096));
+        assert_eq!(
+            content_length("HTTP/2 200\r\ncontent-encoding: gzip\r\ncontent-length: 80\r\n\r\n"),
+            None
+        );
+        assert_eq!(content_length("HTTP/2 200\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_file_list() {
+        let listing = file_list("12 1709294400.5 ./a b.txt\x000 - ./sub/empty\x00").unwrap();
+        let entries = &listing.entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (
+                entries[0].path.as_str(),
+                entries[0].size,
+                entries[0].modified
+            ),
+            ("a b.txt", 12, Some(1_709_294_400))
+        );
+        assert_eq!(
+            (entries[1].path.as_str(), entries[1].modified),
+            ("sub/empty", None)
+        );
+        assert_eq!(listing.skipped, 0);
+        assert!(file_list("garbage\x00").is_err());
+        assert_eq!(file_list("").unwrap(), Listing::default());
+
+        // One bad record among good ones is skipped, not fatal.
+        let mixed = file_list("5 17,25 ./x\x00find: './private': Permission denied\x007 - ./y\x00")
+            .unwrap();
+        assert_eq!(mixed.entries.len(), 2);
+        assert_eq!(mixed.entries[0].modified, Some(17));
+        assert_eq!(mixed.skipped, 1);
+    }
+
+    #[test]
+    fn test_s3_listing() {
+        let listing = "logs/a.gz\t10\t2024-03-01T12:00:00.000Z\n\
+                       logs/\t0\t2024-03-01T12:00:00.000Z\n\
+                       logs/old/b.gz\t20\t2024-03-01T12:00:00.000Z\n\
+                       logs2/c.gz\t30\t2024-03-01T12:00:00.000Z\n";
+        let names = |prefix| -> Vec<String> {
+            s3_listing(listing, prefix)
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|e| e.path)
+                .collect()
+        };
+        assert_eq!(names("logs/"), ["a.gz", "old/b.gz"]);
+        assert_eq!(names("logs"), ["a.gz", "old/b.gz"]);
+        assert_eq!(names("logs/a.gz"), ["a.gz"]);
+        assert_eq!(names("").len(), 3);
+        assert_eq!(s3_listing("None\n", "logs/").unwrap(), Listing::default());
+        assert_eq!(
+            s3_listing(listing, "logs/").unwrap().entries[0].modified,
+            Some(1_709_294_400)
+        );
+
+        let tabbed = s3_listing("a\tb.txt\t3\t2024-03-01T12:00:00+00:00\nbroken\n", "").unwrap();
+        assert_eq!(tabbed.entries[0].path, "a\tb.txt");
+        assert_eq!(tabbed.skipped, 1);
+        assert!(s3_listing("<Error>AccessDenied</Error>\n", "").is_err());
+    }
+
+    /// Real outputs kept in `fuzz/corpus/`, which the fuzz targets start from.
+    #[test]
+    fn test_corpus() {
+        let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        let read = |target: &str| -> Vec<(String, String)> {
+            let mut files: Vec<_> = std::fs::read_dir(corpus.join(target))
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            files.sort();
+            files
+                .into_iter()
+                .map(|p| {
+                    let name = p.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, std::fs::read_to_string(p).unwrap())
+                })
+                .collect()
+        };
+        for (name, output) in read("file_list") {
+            let listing = file_list(&output).unwrap();
+            assert!(!listing.entries.is_empty(), "{}", name);
+            assert_eq!(listing.skipped, 0, "{}", name);
+        }
+        for (name, output) in read("s3_listing") {
+            let listing = s3_listing(&output, "").unwrap();
+            assert_eq!(listing.skipped, 0, "{}", name);
+            assert!(
+                listing.entries.iter().all(|e| e.modified.is_some()),
+                "{}",
+                name
+            );
+        }
+        for (name, output) in read("stat_tree") {
+            assert!(stat_tree(&output).is_ok(), "{}", name);
+        }
+        for (name, html) in read("http_index") {
+            let entries = http_index(&html);
+            let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(
+                names,
+                ["2023", "genome v2.tar.gz", "notes&todo.txt", "README.txt"],
+                "{}",
+                name
+            );
+            assert!(entries[0].is_dir && !entries[1].is_dir, "{}", name);
+            if name != "python" {
+                assert_eq!(entries[1].modified, Some(1_709_294_400), "{}", name);
+                assert_eq!(entries[2].size, Some(512), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_index() {
+        let nginx = http_index(
+            "<a href=\"../\">../</a>\n<a href=\"big.iso\">big.iso</a>   01-Mar-2024 12:00   \
+             1288490189\n<a href=\"sub/\">sub/</a>   05-Jan-2024 10:00   -\n",
+        );
+        assert_eq!(
+            nginx,
+            [
+                IndexEntry {
+                    name: "big.iso".to_string(),
+                    is_dir: false,
+                    size: Some(1_288_490_189),
+                    modified: Some(1_709_294_400),
+                },
+                IndexEntry {
+                    name: "sub".to_string(),
+                    is_dir: true,
+                    size: None,
+                    modified: Some(1_704_448_800),
+                },
+            ]
+        );
+        // Apache's rounded sizes aren't sizes.
+        let apache = http_index(
+            "<td><a href=\"a.gz\">a.gz</a></td><td align=\"right\">2024-03-01 12:00  </td>\
+             <td align=\"right\">1.2G</td>",
+        );
+        assert_eq!(apache[0].size, None);
+        assert_eq!(apache[0].modified, Some(1_709_294_400));
+
+        let ignored = "<a href=\"?C=M;O=A\">x</a><a href=\"/pub/\">up</a>\
+                       <a href=\"https://example.org/\">out</a><a href=\"a/b.txt\">deep</a>\
+                       <a href=\"#top\">top</a><a name=\"anchor\">no href</a><abbr>x</abbr>\
+                       <a href=\"./\">.</a><a href=\"..\">..</a>";
+        assert_eq!(http_index(ignored), []);
+        let odd = http_index(
+            "<A HREF='x%C3%A9.txt'>x</A><a href=y.txt>y</a><a href=\"x%C3%A9.txt\">\
+             <a href=\".hidden\">.hidden</a>",
+        );
+        let names: Vec<&str> = odd.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["x\u{e9}.txt", "y.txt", ".hidden"]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parsers_never_panic(output in "\\PC{0,200}", prefix in "[a-z/]{0,6}") {
+            let _ = file_list(&output);
+            let _ = s3_listing(&output, &prefix);
+            let _ = stat_tree(&output);
+            let _ = content_length(&output);
+            let _ = http_index(&output);
+        }
+
+        #[test]
+        fn prop_listings_keep_every_record(sizes in prop::collection::vec(any::<u64>(), 0..20)) {
+            let output: String = sizes
+                .iter()
+                .enumerate()
+                .map(|(i, size)| format!("{} 1700000000.0 ./f{}\0", size, i))
+                .collect();
+            let listing = file_list(&output).unwrap();
+            prop_assert_eq!(listing.skipped, 0);
+            prop_assert_eq!(
+                listing.entries.iter().map(|e| e.size).collect::<Vec<_>>(),
+                sizes
+            );
+        }
+    }
+}
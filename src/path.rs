@@ -12,21 +12,27 @@ impl LocalPath {
             return Err(PathError::ProtocolNotAllowed(protocol.to_string()));
         }
 
-        if path_str.contains('@') && path_str.contains(':') {
-            let parts: Vec<&str> = path_str.split('@').collect();
-            if parts.len() == 2 {
-                let after_at = parts[1];
-                if after_at.contains(':') && !after_at.starts_with("//") {
-                    return Err(PathError::RemotePathNotAllowed);
-                }
-            }
+        if is_windows_path(path_str) {
+            let path = to_extended_length(PathBuf::from(path_str));
+            return Ok(LocalPath { path });
+        }
+
+        if let Some((Some(_), _, _)) = crate::protocol::scp_parts(path_str) {
+            return Err(PathError::RemotePathNotAllowed);
         }
 
-        let path = PathBuf::from(path_str);
+        let path = to_extended_length(PathBuf::from(path_str));
 
         Ok(LocalPath { path })
     }
 
+    /// A local path that needs no parsing, such as one decoded from a `file://` URL.
+    pub fn new(path: PathBuf) -> Self {
+        LocalPath {
+            path: to_extended_length(path),
+        }
+    }
+
     pub fn as_path(&self) -> &Path {
         &self.path
     }
@@ -46,6 +52,53 @@ impl LocalPath {
     pub fn is_file(&self) -> bool {
         self.path.is_file()
     }
+
+    /// Whether the path was written with a trailing separator (`dir/`), meaning the
+    /// user wants a directory there even if it does not exist yet.
+    pub fn ends_with_separator(&self) -> bool {
+        let s = self.path.as_os_str().to_string_lossy();
+        s.ends_with('/') || (cfg!(windows) && s.ends_with('\\'))
+    }
+}
+
+/// Whether `path_str` is a Windows drive-letter (`C:\foo`, `C:/foo`, `C:`) or UNC
+/// (`\\server\share`, `\\?\C:\foo`) path. The `:` in these must never be read as
+/// the host separator of a `host:path` remote.
+pub fn is_windows_path(path_str: &str) -> bool {
+    if path_str.starts_with("\\\\") {
+        return true;
+    }
+    let bytes = path_str.as_bytes();
+    bytes.len() >= 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes.len() == 2 || bytes[2] == b'\\' || bytes[2] == b'/')
+}
+
+/// Longest path the classic Win32 API accepts without the `\\?\` prefix.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// On Windows, rewrite paths of `MAX_PATH` characters or more into their extended-length
+/// form (`\\?\C:\...` or `\\?\UNC\server\share\...`) so fs calls accept them.
+#[cfg(windows)]
+fn to_extended_length(path: PathBuf) -> PathBuf {
+    let raw = path.as_os_str().to_string_lossy();
+    if raw.len() < MAX_PATH || raw.starts_with("\\\\?\\") {
+        return path;
+    }
+    // The prefix turns off all normalization, so resolve `.`, `..` and `/` first.
+    let absolute = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+    let absolute = absolute.to_string_lossy();
+    match absolute.strip_prefix("\\\\") {
+        Some(unc) => PathBuf::from(format!("\\\\?\\UNC\\{}", unc)),
+        None => PathBuf::from(format!("\\\\?\\{}", absolute)),
+    }
+}
+
+#[cfg(not(windows))]
+fn to_extended_length(path: PathBuf) -> PathBuf {
+    path
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -113,9 +166,77 @@ mod tests {
         assert_eq!(path.to_string_lossy(), "./file@name.txt");
     }
 
+    #[test]
+    fn test_allow_slash_before_at_and_colon() {
+        // Not `user@host:path`, since the user would contain a `/`.
+        let path = LocalPath::parse("dir/me@host:x").unwrap();
+        assert_eq!(path.to_string_lossy(), "dir/me@host:x");
+    }
+
+    #[test]
+    fn test_ends_with_separator() {
+        assert!(LocalPath::parse("dest/").unwrap().ends_with_separator());
+        assert!(LocalPath::parse("/tmp/dest/")
+            .unwrap()
+            .ends_with_separator());
+        assert!(!LocalPath::parse("dest").unwrap().ends_with_separator());
+        assert!(!LocalPath::parse("dest/file.txt")
+            .unwrap()
+            .ends_with_separator());
+    }
+
     #[test]
     fn test_allow_windows_path() {
         let path = LocalPath::parse("C:\\Windows\\file.txt").unwrap();
         assert!(path.to_string_lossy().contains("file.txt"));
     }
+
+    #[test]
+    fn test_is_windows_path() {
+        assert!(is_windows_path("C:\\foo"));
+        assert!(is_windows_path("d:/foo"));
+        assert!(is_windows_path("C:"));
+        assert!(is_windows_path("\\\\server\\share\\file.txt"));
+        assert!(is_windows_path("\\\\?\\C:\\foo"));
+        assert!(!is_windows_path("host:path"));
+        assert!(!is_windows_path("C:foo"));
+        assert!(!is_windows_path("./C:\\foo"));
+    }
+
+    #[test]
+    fn test_windows_paths_with_at_and_colon_stay_local() {
+        let path = LocalPath::parse("C:\\Users\\me@corp\\a:b").unwrap();
+        assert!(path.to_string_lossy().ends_with("a:b"));
+        assert!(LocalPath::parse("\\\\server\\share\\user@host:x").is_ok());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_drive_and_unc_paths() {
+        let drive = LocalPath::parse("C:\\Windows\\file.txt").unwrap();
+        assert_eq!(drive.to_string_lossy(), "C:\\Windows\\file.txt");
+
+        let unc = LocalPath::parse("\\\\server\\share\\dir\\file.txt").unwrap();
+        assert_eq!(unc.to_string_lossy(), "\\\\server\\share\\dir\\file.txt");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_long_paths_get_extended_prefix() {
+        let deep = "a".repeat(50);
+        let long = format!("C:\\{}", vec![deep.as_str(); 6].join("\\"));
+        assert!(long.len() > 260);
+        let path = LocalPath::parse(&long).unwrap();
+        assert_eq!(path.to_string_lossy(), format!("\\\\?\\{}", long));
+
+        let long_unc = format!("\\\\server\\share\\{}", vec![deep.as_str(); 6].join("\\"));
+        let path = LocalPath::parse(&long_unc).unwrap();
+        assert_eq!(
+            path.to_string_lossy(),
+            format!("\\\\?\\UNC\\{}", &long_unc[2..])
+        );
+
+        let short = LocalPath::parse("C:\\short.txt").unwrap();
+        assert_eq!(short.to_string_lossy(), "C:\\short.txt");
+    }
 }
@@ -0,0 +1,311 @@
+//! `--nice` and `--ionice`: run a backup without starving the machine's real work.
+//!
+//! Both are set on usync itself, before it starts any thread or child process. The ssh,
+//! scp, aws and curl it runs inherit them, as they would from `nice`/`ionice` in front
+//! of the command. On Windows, a positive `--nice` means the below-normal priority
+//! class and a negative one above-normal; `--ionice` is Linux only.
+//!
+//! [`children_usage`] adds up the CPU time those tools used, for `-v`.
+
+#[cfg(unix)]
+use std::ffi::c_long;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// The lowest and highest `--nice` values.
+pub const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+/// Parse a `--nice` value, from -20 (most favoured) to 19 (least).
+pub fn parse_nice(s: &str) -> Result<i32, String> {
+    match s.trim().parse::<i32>() {
+        Ok(n) if NICE_RANGE.contains(&n) => Ok(n),
+        _ => Err(format!("invalid niceness '{}': expected -20 to 19", s)),
+    }
+}
+
+/// An I/O scheduling class, as `ionice -c` takes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// First access to the disk, whatever else waits; needs root.
+    Realtime,
+    /// The default, shared by priority level.
+    BestEffort,
+    /// Only when no other process wants the disk.
+    Idle,
+}
+
+/// A `--ionice` setting: a class and, except for idle, a level from 0 (highest) to 7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoNice {
+    pub class: IoClass,
+    pub level: u8,
+}
+
+impl IoNice {
+    /// Parse `CLASS[:LEVEL]`: `idle`, `best-effort` (`be`) or `realtime` (`rt`), with a
+    /// level of 0 to 7 (4 when left out).
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let class = match class.trim().to_ascii_lowercase().as_str() {
+            "realtime" | "rt" => IoClass::Realtime,
+            "best-effort" | "be" => IoClass::BestEffort,
+            "idle" => IoClass::Idle,
+            _ => {
+                return Err(format!(
+                    "invalid I/O class '{}': expected idle, best-effort or realtime",
+                    class
+                ))
+            }
+        };
+        let level = match level {
+            Some(_) if class == IoClass::Idle => {
+                return Err("the idle I/O class takes no level".to_string())
+            }
+            Some(level) => match level.trim().parse::<u8>() {
+                Ok(n) if n <= 7 => n,
+                _ => return Err(format!("invalid I/O level '{}': expected 0 to 7", level)),
+            },
+            None => 4,
+        };
+        Ok(IoNice { class, level })
+    }
+}
+
+/// Set the niceness of this process, which the processes it starts inherit.
+#[cfg(unix)]
+pub fn set_nice(n: i32) -> io::Result<()> {
+    extern "C" {
+        fn setpriority(which: i32, who: u32, prio: i32) -> i32;
+    }
+    const PRIO_PROCESS: i32 = 0;
+    // On Linux this is the calling thread's, so it is set before any other thread starts.
+    if unsafe { setpriority(PRIO_PROCESS, 0, n) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Set the priority class of this process: below normal for a positive `n`, above
+/// normal for a negative one. Child processes keep a below-normal class.
+#[cfg(windows)]
+pub fn set_nice(n: i32) -> io::Result<()> {
+    use std::ffi::c_void;
+
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x20;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x8000;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn SetPriorityClass(process: *mut c_void, class: u32) -> i32;
+    }
+    let class = match n {
+        n if n > 0 => BELOW_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        _ => ABOVE_NORMAL_PRIORITY_CLASS,
+    };
+    // SAFETY: the pseudo-handle of the current process needs no closing.
+    if unsafe { SetPriorityClass(GetCurrentProcess(), class) } != 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn set_nice(_n: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--nice isn't supported on this platform",
+    ))
+}
+
+/// `ioprio_set` and `ioprio_get`, where their numbers are known.
+#[cfg(target_os = "linux")]
+fn ioprio_syscalls() -> Option<(c_long, c_long)> {
+    if cfg!(target_arch = "x86_64") {
+        Some((251, 252))
+    } else if cfg!(target_arch = "x86") {
+        Some((289, 290))
+    } else if cfg!(any(target_arch = "aarch64", target_arch = "riscv64")) {
+        Some((30, 31))
+    } else if cfg!(target_arch = "arm") {
+        Some((314, 315))
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: c_long = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// Set the I/O scheduling class of this process, which the processes it starts inherit.
+#[cfg(target_os = "linux")]
+pub fn set_ionice(ionice: IoNice) -> io::Result<()> {
+    extern "C" {
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+    let Some((ioprio_set, _)) = ioprio_syscalls() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--ionice isn't supported on this architecture",
+        ));
+    };
+    let (class, level) = match ionice.class {
+        IoClass::Realtime => (1, ionice.level),
+        IoClass::BestEffort => (2, ionice.level),
+        IoClass::Idle => (3, 0),
+    };
+    let prio: c_long = (class << IOPRIO_CLASS_SHIFT) | c_long::from(level);
+    // Like `setpriority`, this is the calling thread's; pid 0 is the caller.
+    if unsafe { syscall(ioprio_set, IOPRIO_WHO_PROCESS, 0 as c_long, prio) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_ionice(_ionice: IoNice) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--ionice is only supported on Linux",
+    ))
+}
+
+/// CPU time used by the child processes usync has waited for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChildUsage {
+    pub user: Duration,
+    pub system: Duration,
+}
+
+impl fmt::Display for ChildUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2}s user, {:.2}s system",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64()
+        )
+    }
+}
+
+/// The CPU time of the ssh, scp, aws and curl processes this run started and finished,
+/// as `wait4` reported it (`getrusage(RUSAGE_CHILDREN)`).
+#[cfg(unix)]
+pub fn children_usage() -> Option<ChildUsage> {
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct Timeval {
+        sec: c_long,
+        #[cfg(target_os = "macos")]
+        usec: i32,
+        #[cfg(not(target_os = "macos"))]
+        usec: c_long,
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct Rusage {
+        utime: Timeval,
+        stime: Timeval,
+        rest: [c_long; 14],
+    }
+    extern "C" {
+        fn getrusage(who: i32, usage: *mut Rusage) -> i32;
+    }
+    const RUSAGE_CHILDREN: i32 = -1;
+
+    let mut usage = Rusage::default();
+    // SAFETY: `usage` is laid out as the C struct rusage.
+    if unsafe { getrusage(RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return None;
+    }
+    let duration = |t: Timeval| {
+        Duration::from_secs(t.sec.max(0) as u64) + Duration::from_micros(t.usec.max(0) as u64)
+    };
+    Some(ChildUsage {
+        user: duration(usage.utime),
+        system: duration(usage.stime),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn children_usage() -> Option<ChildUsage> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_nice("10"), Ok(10));
+        assert_eq!(parse_nice("-20"), Ok(-20));
+        assert!(parse_nice("20").is_err() && parse_nice("low").is_err());
+
+        assert_eq!(
+            IoNice::parse("idle"),
+            Ok(IoNice {
+                class: IoClass::Idle,
+                level: 4
+            })
+        );
+        assert_eq!(
+            IoNice::parse("be:7"),
+            Ok(IoNice {
+                class: IoClass::BestEffort,
+                level: 7
+            })
+        );
+        assert_eq!(IoNice::parse("realtime").unwrap().level, 4);
+        for bad in ["idle:3", "best-effort:8", "fast", "rt:"] {
+            assert!(IoNice::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_children_inherit_priority() {
+        // In a thread of its own: the settings are per thread, and can't be undone.
+        std::thread::spawn(|| {
+            set_nice(12).unwrap();
+            let idle = IoNice::parse("idle").unwrap();
+            if let Err(e) = set_ionice(idle) {
+                assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+            }
+            let mut child = std::process::Command::new("sleep")
+                .arg("5")
+                .spawn()
+                .unwrap();
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", child.id())).unwrap();
+            // The fields after the command name, which ends with ')': nice is the 19th.
+            let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+            let nice = fields[16].parse::<i32>().unwrap();
+
+            if let Some((_, ioprio_get)) = ioprio_syscalls() {
+                extern "C" {
+                    fn syscall(number: c_long, ...) -> c_long;
+                }
+                let prio = unsafe { syscall(ioprio_get, IOPRIO_WHO_PROCESS, child.id() as c_long) };
+                assert_eq!(prio >> IOPRIO_CLASS_SHIFT, 3);
+            }
+            child.kill().unwrap();
+            child.wait().unwrap();
+            assert_eq!(nice, 12);
+
+            let usage = children_usage().unwrap();
+            assert!(usage.to_string().ends_with("s system"), "{}", usage);
+        })
+        .join()
+        .unwrap();
+    }
+}
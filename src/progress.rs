@@ -0,0 +1,647 @@
+//! `--progress` for transfers done by external tools: scp, curl, wget and `aws s3 cp`.
+//!
+//! The tool runs with its stdout and stderr piped. Its own progress output (scp's meter,
+//! curl's `--progress-bar`, wget's dots or bar, aws's `Completed X/Y` lines) is read as it
+//! arrives and drawn with the same bar as local copies. Everything else is kept and handed
+//! back, so error messages still reach the caller. A tool that prints nothing readable
+//! (scp writing into a pipe, `aws --quiet`) still gets a display from a watcher thread:
+//! a download's bytes on disk, polled every 250 ms, as a bar when the source size is
+//! known; an upload, a spinner with the elapsed time and the size being sent.
+//!
+//! Without the `progress` feature, or when stdout isn't a terminal, the tools simply
+//! draw their own progress as before.
+#![cfg_attr(not(feature = "progress"), allow(dead_code))]
+
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+
+#[cfg(feature = "progress")]
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// How often a download's destination is looked at while the tool reports nothing.
+#[cfg(feature = "progress")]
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// A tool whose progress output can be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Scp,
+    Curl,
+    Wget,
+    Aws,
+}
+
+/// What one progress line says.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reading {
+    Bytes {
+        done: u64,
+        total: Option<u64>,
+    },
+    /// 0 to 100, for tools that don't give exact byte counts.
+    Percent(f64),
+}
+
+impl Tool {
+    /// Read one line (or `\r`-terminated update) of the tool's output.
+    pub fn parse(self, line: &str) -> Option<Reading> {
+        match self {
+            Tool::Scp => parse_scp(line),
+            Tool::Curl => parse_curl(line),
+            Tool::Wget => parse_wget(line),
+            Tool::Aws => parse_aws(line),
+        }
+    }
+}
+
+/// `45%` or `45.3` as a percentage.
+fn percent(s: &str) -> Option<f64> {
+    let value: f64 = s.strip_suffix('%').unwrap_or(s).parse().ok()?;
+    (0.0..=100.0).contains(&value).then_some(value)
+}
+
+/// `512`, `23MB`, `1600K`, `12.0 MiB`, `0 Bytes`: binary units, as all of these tools use.
+pub fn parse_amount(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" | "BYTES" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * (1u64 << shift) as f64) as u64)
+}
+
+/// `file.iso    45%   23MB  11.5MB/s   00:02 ETA`
+fn parse_scp(line: &str) -> Option<Reading> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    words.windows(3).find_map(|w| {
+        if !w[0].ends_with('%') || !w[2].ends_with("/s") {
+            return None;
+        }
+        parse_amount(w[1])?;
+        percent(w[0]).map(Reading::Percent)
+    })
+}
+
+/// `######################                                  31.6%`
+fn parse_curl(line: &str) -> Option<Reading> {
+    let line = line.trim_end();
+    let (bar, last) = line.rsplit_once(' ').unwrap_or(("", line));
+    if !last.ends_with('%')
+        || !bar
+            .chars()
+            .all(|c| matches!(c, '#' | '=' | 'O' | '-' | ' '))
+    {
+        return None;
+    }
+    percent(last).map(Reading::Percent)
+}
+
+/// The dot style, `  1600K .......... .......... ..........  45% 1.2M 3s`, or the bar
+/// style, `file.iso   45%[=======>          ]  23.4M  1.2MB/s    eta 3s`.
+fn parse_wget(line: &str) -> Option<Reading> {
+    let dotted = line
+        .split_whitespace()
+        .any(|w| w.len() > 1 && w.bytes().all(|b| b == b'.'));
+    if !dotted && !line.contains("%[") {
+        return None;
+    }
+    line.split_whitespace().find_map(|w| {
+        let (value, _) = w.split_once('%')?;
+        percent(value).map(Reading::Percent)
+    })
+}
+
+/// `Completed 12.0 MiB/45.3 MiB (5.1 MiB/s) with 1 file(s) remaining`; the total reads
+/// `~45.3 MiB` while aws is still counting.
+fn parse_aws(line: &str) -> Option<Reading> {
+    let rest = line.trim().strip_prefix("Completed ")?;
+    let amounts = rest.split(" (").next()?;
+    let (done, total) = amounts.split_once('/')?;
+    Some(Reading::Bytes {
+        done: parse_amount(done)?,
+        total: Some(parse_amount(total.trim_start_matches('~'))?),
+    })
+}
+
+/// Read `pipe` to the end, sending the progress updates `tool` prints and returning
+/// everything else, one line per update or line.
+fn read_progress(mut pipe: impl Read, tool: Tool, updates: &mpsc::Sender<Reading>) -> Vec<u8> {
+    let mut kept = Vec::new();
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 8192];
+    let take = |segment: &[u8], kept: &mut Vec<u8>| {
+        let line = String::from_utf8_lossy(segment);
+        if line.trim().is_empty() {
+            return;
+        }
+        match tool.parse(&line) {
+            Some(reading) => {
+                let _ = updates.send(reading);
+            }
+            None => {
+                kept.extend_from_slice(segment);
+                kept.push(b'\n');
+            }
+        }
+    };
+    loop {
+        let n = match pipe.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        for &byte in &buffer[..n] {
+            if byte == b'\r' || byte == b'\n' {
+                take(&pending, &mut kept);
+                pending.clear();
+            } else {
+                pending.push(byte);
+            }
+        }
+    }
+    take(&pending, &mut kept);
+    kept
+}
+
+/// The bar for a transfer of known size, shared with local file copies.
+#[cfg(feature = "progress")]
+pub fn bar_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(
+            "[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) {bytes_per_sec} ETA: {eta}",
+        )
+        .unwrap()
+        .progress_chars("#>-")
+}
+
+/// What a tool run moves, for the display when the tool reports nothing readable.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transfer<'a> {
+    /// The local file a download writes, watched as it grows.
+    pub dst: Option<&'a Path>,
+    /// The size of the source, when known.
+    pub size: Option<u64>,
+}
+
+/// The bar drawn for one tool run: a spinner until the tool or the [`Watcher`] reports
+/// something.
+#[cfg(feature = "progress")]
+struct Meter {
+    bar: ProgressBar,
+    /// Set once the tool's own output was read; from then on the watcher stays out.
+    reported: std::sync::Arc<std::sync::Mutex<bool>>,
+}
+
+#[cfg(feature = "progress")]
+impl Meter {
+    fn new() -> Self {
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} {elapsed_precise} {msg}")
+                .unwrap(),
+        );
+        Meter {
+            bar,
+            reported: Default::default(),
+        }
+    }
+
+    fn show(&self, reading: Reading) {
+        let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+        match reading {
+            Reading::Bytes {
+                done,
+                total: Some(total),
+            } => {
+                if !*reported {
+                    self.bar.set_style(bar_style());
+                }
+                self.bar.set_length(total);
+                self.bar.set_position(done);
+            }
+            Reading::Bytes { done, total: None } => {
+                self.bar
+                    .set_message(format!("{}", indicatif::HumanBytes(done)));
+            }
+            Reading::Percent(value) => {
+                if !*reported {
+                    self.bar.set_style(
+                        ProgressStyle::default_bar()
+                            .template("[{bar:40.cyan/blue}] {percent}% ETA: {eta}")
+                            .unwrap()
+                            .progress_chars("#>-"),
+                    );
+                    self.bar.set_length(1000);
+                }
+                self.bar.set_position((value * 10.0) as u64);
+            }
+        }
+        *reported = true;
+    }
+}
+
+/// Fills in a [`Meter`] from outside the tool while it runs: the bytes of a download on
+/// disk every [`POLL_INTERVAL`] (as a bar when the source size is known), or the size
+/// being sent for an upload. Stops on its own once the tool reports progress itself.
+#[cfg(feature = "progress")]
+struct Watcher {
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+#[cfg(feature = "progress")]
+impl Watcher {
+    fn start(meter: &Meter, transfer: Transfer) -> Self {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let stop = std::sync::Arc::new(AtomicBool::new(false));
+        let (bar, reported) = (meter.bar.clone(), meter.reported.clone());
+        let dst = transfer.dst.map(Path::to_path_buf);
+        let size = transfer.size;
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut styled = false;
+                while !stop.load(Ordering::Relaxed) {
+                    let reported = reported.lock().unwrap_or_else(|e| e.into_inner());
+                    if *reported {
+                        return;
+                    }
+                    let written = dst
+                        .as_deref()
+                        .and_then(|p| std::fs::metadata(p).ok())
+                        .map(|m| m.len());
+                    match (&dst, size) {
+                        (Some(_), Some(total)) => {
+                            if !styled {
+                                bar.set_style(bar_style());
+                                bar.set_length(total);
+                                styled = true;
+                            }
+                            bar.set_position(written.unwrap_or(0).min(total));
+                        }
+                        (Some(_), None) => bar.set_message(format!(
+                            "{} written",
+                            indicatif::HumanBytes(written.unwrap_or(0))
+                        )),
+                        (None, Some(total)) => {
+                            bar.set_message(format!("sending {}", indicatif::HumanBytes(total)))
+                        }
+                        (None, None) => {}
+                    }
+                    bar.tick();
+                    drop(reported);
+                    std::thread::park_timeout(POLL_INTERVAL);
+                }
+            })
+        };
+        Watcher { stop, thread }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+/// Run `cmd`, a transfer by `tool`, for `--progress`: its progress is drawn as a bar, or
+/// from `transfer` when the tool reports nothing readable, and the rest of its output is
+/// returned.
+pub fn run(cmd: &mut Command, tool: Tool, transfer: Transfer) -> io::Result<Output> {
+    #[cfg(feature = "progress")]
+    {
+        use std::io::IsTerminal;
+        if io::stdout().is_terminal() {
+            return run_with_meter(cmd, tool, transfer);
+        }
+    }
+    let _ = (tool, transfer);
+    cmd.stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+}
+
+#[cfg(feature = "progress")]
+fn run_with_meter(cmd: &mut Command, tool: Tool, transfer: Transfer) -> io::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (tx, rx) = mpsc::channel();
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|p| Box::new(p) as Box<dyn Read + Send>),
+    ]
+    .map(|pipe| {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            pipe.map(|p| read_progress(p, tool, &tx))
+                .unwrap_or_default()
+        })
+    });
+    drop(tx);
+
+    let meter = Meter::new();
+    let watcher = Watcher::start(&meter, transfer);
+    // Ends when the tool closes both pipes, i.e. when it exits.
+    for reading in rx {
+        meter.show(reading);
+    }
+    let status = child.wait();
+    watcher.stop();
+    let status = status?;
+    if status.success() {
+        meter.bar.finish_with_message("Done");
+    } else {
+        meter.bar.abandon();
+    }
+    let [stdout, stderr] = readers.map(|r| r.join().unwrap_or_default());
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// How `--progress` reports a directory upload: bars on the terminal, or one JSON
+/// object per line on stderr for other programs to read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Bar,
+    Json,
+}
+
+/// Compare events in JSON are sent every this many files (and for the last one).
+const JSON_COMPARE_STEP: usize = 1000;
+
+/// Progress of a whole directory upload: the files compared with what the destination
+/// already has, then the files and bytes sent of what that left.
+///
+/// Does nothing unless `--progress` is on. While it draws bars, the single-file
+/// transfers run without their own displays ([`RunProgress::per_file`]).
+pub struct RunProgress {
+    format: Option<Format>,
+    files: usize,
+    files_total: usize,
+    bytes: u64,
+    bytes_total: u64,
+    #[cfg(feature = "progress")]
+    bars: Option<(indicatif::MultiProgress, ProgressBar)>,
+}
+
+impl RunProgress {
+    pub fn new(progress: bool, format: Format) -> Self {
+        let format = progress.then_some(format);
+        #[cfg(feature = "progress")]
+        let bars = {
+            use std::io::IsTerminal;
+            (format == Some(Format::Bar) && io::stdout().is_terminal()).then(|| {
+                let multi = indicatif::MultiProgress::new();
+                let compared = multi.add(ProgressBar::new(0));
+                compared.set_style(
+                    ProgressStyle::default_bar()
+                        .template("[{bar:40.cyan/blue}] {pos}/{len} files compared")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                );
+                (multi, compared)
+            })
+        };
+        RunProgress {
+            format,
+            files: 0,
+            files_total: 0,
+            bytes: 0,
+            bytes_total: 0,
+            #[cfg(feature = "progress")]
+            bars,
+        }
+    }
+
+    /// Whether each file's transfer should show its own progress.
+    pub fn per_file(&self) -> bool {
+        match self.format {
+            None => false,
+            Some(Format::Json) => false,
+            #[cfg(feature = "progress")]
+            Some(Format::Bar) => self.bars.is_none(),
+            #[cfg(not(feature = "progress"))]
+            Some(Format::Bar) => true,
+        }
+    }
+
+    /// `examined` of the `total` listed files have been compared with the destination.
+    pub fn compared(&mut self, examined: usize, total: usize) {
+        #[cfg(feature = "progress")]
+        if let Some((_, compared)) = &self.bars {
+            compared.set_length(total as u64);
+            compared.set_position(examined as u64);
+        }
+        if self.format == Some(Format::Json)
+            && (examined == total || examined.is_multiple_of(JSON_COMPARE_STEP))
+        {
+            eprintln!(
+                r#"{{"event":"compare","examined":{},"total":{}}}"#,
+                examined, total
+            );
+        }
+    }
+
+    /// The comparison is over and `files` holding `bytes` remain to be sent.
+    pub fn planned(&mut self, files: usize, bytes: u64) {
+        self.files_total = files;
+        self.bytes_total = bytes;
+        #[cfg(feature = "progress")]
+        if let Some((multi, compared)) = &mut self.bars {
+            compared.finish();
+            let sent = multi.add(ProgressBar::new(bytes));
+            sent.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "[{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) \
+                         {bytes_per_sec} ETA: {eta} {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            sent.set_message(format!("0/{} files", files));
+            *compared = sent;
+        }
+        if self.format == Some(Format::Json) {
+            eprintln!(r#"{{"event":"plan","files":{},"bytes":{}}}"#, files, bytes);
+        }
+    }
+
+    /// `files` more files holding `bytes` arrived.
+    pub fn sent(&mut self, files: usize, bytes: u64) {
+        self.files += files;
+        self.bytes += bytes;
+        #[cfg(feature = "progress")]
+        if let Some((_, sent)) = &self.bars {
+            sent.set_position(self.bytes);
+            sent.set_message(format!("{}/{} files", self.files, self.files_total));
+        }
+        if self.format == Some(Format::Json) {
+            eprintln!(
+                r#"{{"event":"transfer","files":{},"files_total":{},"bytes":{},"bytes_total":{}}}"#,
+                self.files, self.files_total, self.bytes, self.bytes_total
+            );
+        }
+    }
+
+    pub fn finish(&mut self) {
+        #[cfg(feature = "progress")]
+        if let Some((_, bar)) = self.bars.take() {
+            bar.finish();
+        }
+        if self.format == Some(Format::Json) {
+            eprintln!(
+                r#"{{"event":"done","files":{},"bytes":{}}}"#,
+                self.files, self.bytes
+            );
+            self.format = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_amounts() {
+        assert_eq!(parse_amount("512"), Some(512));
+        assert_eq!(parse_amount("23MB"), Some(23 << 20));
+        assert_eq!(parse_amount("1600K"), Some(1600 << 10));
+        assert_eq!(parse_amount("12.0 MiB"), Some(12 << 20));
+        assert_eq!(parse_amount("1.5 GiB"), Some(3 << 29));
+        assert_eq!(parse_amount("0 Bytes"), Some(0));
+        assert_eq!(parse_amount("fast"), None);
+        assert_eq!(parse_amount("3 parsecs"), None);
+    }
+
+    #[test]
+    fn test_parse_tool_output() {
+        assert_eq!(
+            Tool::Scp.parse("backup.tar                45%   23MB  11.5MB/s   00:02 ETA"),
+            Some(Reading::Percent(45.0))
+        );
+        assert_eq!(
+            Tool::Scp.parse("debug1: Sending command: scp -v -t /tmp"),
+            None
+        );
+
+        assert_eq!(
+            Tool::Curl.parse("######################                         31.6%"),
+            Some(Reading::Percent(31.6))
+        );
+        assert_eq!(
+            Tool::Curl.parse("                                                0.0%"),
+            Some(Reading::Percent(0.0))
+        );
+        assert_eq!(
+            Tool::Curl.parse("curl: (22) The requested URL returned error: 404"),
+            None
+        );
+
+        assert_eq!(
+            Tool::Wget.parse("  1600K .......... .......... .......... ..........  45% 1.2M 3s"),
+            Some(Reading::Percent(45.0))
+        );
+        assert_eq!(
+            Tool::Wget.parse("file.iso   45%[=======>          ]  23.4M  1.2MB/s    eta 3s"),
+            Some(Reading::Percent(45.0))
+        );
+        assert_eq!(Tool::Wget.parse("Saving to: 'file.iso' (100% done)"), None);
+
+        assert_eq!(
+            Tool::Aws.parse("Completed 12.0 MiB/45.3 MiB (5.1 MiB/s) with 1 file(s) remaining"),
+            Some(Reading::Bytes {
+                done: 12 << 20,
+                total: Some((45.3 * 1048576.0) as u64)
+            })
+        );
+        assert_eq!(
+            Tool::Aws.parse("Completed 256.0 KiB/~1.0 MiB (1.2 MiB/s) with ~3 file(s) remaining"),
+            Some(Reading::Bytes {
+                done: 256 << 10,
+                total: Some(1 << 20)
+            })
+        );
+        assert_eq!(
+            Tool::Aws.parse("download: s3://bucket/a.txt to ./a.txt"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_progress_is_taken_out_of_the_output() {
+        let output: &[u8] = b"########  10.0%\r################  20.0%\r\
+            curl: (18) transfer closed with 10 bytes remaining\n";
+        let (tx, rx) = mpsc::channel();
+        let kept = read_progress(output, Tool::Curl, &tx);
+        drop(tx);
+
+        assert_eq!(
+            rx.iter().collect::<Vec<_>>(),
+            [Reading::Percent(10.0), Reading::Percent(20.0)]
+        );
+        assert_eq!(
+            String::from_utf8(kept).unwrap(),
+            "curl: (18) transfer closed with 10 bytes remaining\n"
+        );
+    }
+
+    #[cfg(feature = "progress")]
+    #[test]
+    fn test_watcher_follows_the_download_until_the_tool_reports() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dst = temp.path().join("download");
+        std::fs::write(&dst, [0u8; 100]).unwrap();
+
+        let meter = Meter::new();
+        let watcher = Watcher::start(
+            &meter,
+            Transfer {
+                dst: Some(&dst),
+                size: Some(400),
+            },
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while meter.bar.position() != 100 {
+            assert!(std::time::Instant::now() < deadline, "watcher never polled");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(meter.bar.length(), Some(400));
+
+        // The tool's own numbers win from then on.
+        meter.show(Reading::Percent(50.0));
+        std::fs::write(&dst, [0u8; 300]).unwrap();
+        std::thread::sleep(POLL_INTERVAL * 2);
+        assert_eq!(meter.bar.position(), 500);
+        watcher.stop();
+    }
+}
@@ -14,6 +14,76 @@ pub struct RemotePath {
     pub path: String,
 }
 
+impl RemotePath {
+    /// Whether the path was written with a trailing `/`, i.e. names a directory or prefix.
+    pub fn is_dir_hint(&self) -> bool {
+        self.path.ends_with('/')
+    }
+
+    /// The last component of the path, if it has one.
+    pub fn file_name(&self) -> Option<&str> {
+        self.path.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+
+    /// The host as `ssh` takes it: IPv6 literals without their brackets. (`scp` needs the
+    /// brackets, which is how [`Url::host_str`] returns them.)
+    pub fn ssh_host(&self) -> Option<String> {
+        match self.url.host()? {
+            url::Host::Ipv6(addr) => Some(addr.to_string()),
+            host => Some(host.to_string()),
+        }
+    }
+
+    /// The user as `ssh` takes it, percent-escapes decoded; empty when none was given.
+    pub fn ssh_user(&self) -> String {
+        percent_decode(self.url.username()).unwrap_or_else(|| self.url.username().to_string())
+    }
+
+    /// Use `port` for an SSH path that doesn't name one (scp-style `user@host:path`
+    /// can't), as `-P` does for scp.
+    pub fn set_default_port(&mut self, port: u16) {
+        if matches!(self.protocol, Protocol::Ssh | Protocol::Sftp) && self.url.port().is_none() {
+            let _ = self.url.set_port(Some(port));
+        }
+    }
+
+    /// The `s3://bucket/key` URL as the aws CLI expects it: the key unencoded.
+    pub fn s3_url(&self) -> String {
+        format!(
+            "s3://{}{}",
+            self.url.host_str().unwrap_or_default(),
+            self.path
+        )
+    }
+
+    /// This path with `name` appended as a new last component.
+    pub fn join(&self, name: &str) -> RemotePath {
+        let path = format!("{}/{}", self.path.trim_end_matches('/'), name);
+        let mut url = self.url.clone();
+        url.set_path(&path);
+        RemotePath {
+            protocol: self.protocol.clone(),
+            url,
+            path,
+        }
+    }
+
+    /// This path with its last component replaced by `name`.
+    pub fn with_file_name(&self, name: &str) -> RemotePath {
+        let path = match self.path.rsplit_once('/') {
+            Some((parent, _)) => format!("{}/{}", parent, name),
+            None => name.to_string(),
+        };
+        let mut url = self.url.clone();
+        url.set_path(&path);
+        RemotePath {
+            protocol: self.protocol.clone(),
+            url,
+            path,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Protocol {
     Ssh,
@@ -26,6 +96,7 @@ pub enum Protocol {
 }
 
 impl Protocol {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Self {
         match s.to_lowercase().as_str() {
             "ssh" => Protocol::Ssh,
@@ -59,56 +130,61 @@ impl fmt::Display for Protocol {
 
 pub fn parse_path(path_str: &str) -> Result<Path, PathParseError> {
     if path_str.contains("://") {
-        let url = Url::parse(path_str).map_err(|e| PathParseError::InvalidUrl {
+        let mut url = Url::parse(path_str).map_err(|e| PathParseError::InvalidUrl {
             path: path_str.to_string(),
             error: e.to_string(),
         })?;
 
         let protocol = Protocol::from_str(url.scheme());
-        let path = url.path().to_string();
+        let invalid = |error: &str| PathParseError::InvalidUrl {
+            path: path_str.to_string(),
+            error: error.to_string(),
+        };
+        // SSH and S3 paths take everything after the host, so a `?` or `#` before the
+        // first `/` would silently end the host. Whitespace the URL parser trims from
+        // the end would make `ssh://host ` a different host from `ssh://host /`.
+        let rebuilt = matches!(protocol, Protocol::Ssh | Protocol::Sftp | Protocol::S3);
+        let authority = raw_authority(path_str, rebuilt);
+        if authority.contains(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()) {
+            return Err(invalid("the host can't contain spaces"));
+        }
+        if protocol == Protocol::File {
+            return file_url_to_local(&url, path_str);
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(invalid("no host given"));
+        }
+        if rebuilt && authority.contains(['?', '#']) {
+            return Err(invalid("'?' and '#' can't be part of the host"));
+        }
+        let not_utf8 = || PathParseError::InvalidUrl {
+            path: path_str.to_string(),
+            error: "the path is not valid UTF-8 once decoded".to_string(),
+        };
+        let path = match protocol {
+            // File names and keys can contain `#` and `?`, so everything after the host
+            // is the path; the URL is rebuilt around the decoded path.
+            Protocol::Ssh | Protocol::Sftp | Protocol::S3 => {
+                let path = percent_decode(raw_url_path(path_str)).ok_or_else(not_utf8)?;
+                url.set_path(&path);
+                url.set_query(None);
+                url.set_fragment(None);
+                path
+            }
+            _ => percent_decode(url.path()).ok_or_else(not_utf8)?,
+        };
 
         Ok(Path::Remote(RemotePath {
             protocol,
             url,
             path,
         }))
-    } else if path_str.contains('@') && path_str.contains(':') {
-        let parts: Vec<&str> = path_str.split('@').collect();
-        if parts.len() == 2 {
-            let after_at = parts[1];
-            if after_at.contains(':') && !after_at.starts_with("//") {
-                let host_path: Vec<&str> = after_at.splitn(2, ':').collect();
-                if host_path.len() == 2 {
-                    let user = parts[0];
-                    let host = host_path[0];
-                    let path = host_path[1];
-
-                    let ssh_url = format!("ssh://{}@{}:{}", user, host, path);
-                    let url = Url::parse(&ssh_url).map_err(|e| PathParseError::InvalidUrl {
-                        path: path_str.to_string(),
-                        error: e.to_string(),
-                    })?;
-
-                    Ok(Path::Remote(RemotePath {
-                        protocol: Protocol::Ssh,
-                        url,
-                        path: path.to_string(),
-                    }))
-                } else {
-                    crate::path::LocalPath::parse(path_str)
-                        .map(Path::Local)
-                        .map_err(PathParseError::LocalPathError)
-                }
-            } else {
-                crate::path::LocalPath::parse(path_str)
-                    .map(Path::Local)
-                    .map_err(PathParseError::LocalPathError)
-            }
-        } else {
-            crate::path::LocalPath::parse(path_str)
-                .map(Path::Local)
-                .map_err(PathParseError::LocalPathError)
-        }
+    } else if crate::path::is_windows_path(path_str) {
+        crate::path::LocalPath::parse(path_str)
+            .map(Path::Local)
+            .map_err(PathParseError::LocalPathError)
+    } else if let Some(remote) = parse_scp_style(path_str)? {
+        Ok(Path::Remote(remote))
     } else {
         crate::path::LocalPath::parse(path_str)
             .map(Path::Local)
@@ -116,6 +192,146 @@ pub fn parse_path(path_str: &str) -> Result<Path, PathParseError> {
     }
 }
 
+/// An scp-style `[user@]host:path`, as ssh and scp take it: the colon ending the host
+/// comes before any `/`, so `./a:b` and `dir/x:y` stay local. Without a user the host
+/// may be an alias from `~/.ssh/config`, which ssh and scp resolve themselves. On
+/// Windows a one-letter host is a drive (`C:file`), not a remote.
+fn parse_scp_style(path_str: &str) -> Result<Option<RemotePath>, PathParseError> {
+    let Some((user, host, path)) = scp_parts(path_str) else {
+        return Ok(None);
+    };
+    let invalid = |error: String| PathParseError::InvalidUrl {
+        path: path_str.to_string(),
+        error,
+    };
+    let mut url = Url::parse(&format!("ssh://{}", host)).map_err(|e| invalid(e.to_string()))?;
+    // Set apart from the host so that `#`, `?` and the like stay part of the user.
+    if let Some(user) = user {
+        url.set_username(&user.replace('%', "%25"))
+            .map_err(|_| invalid(format!("'{}' can't be a user name", user)))?;
+    }
+    url.set_path(path);
+    Ok(Some(RemotePath {
+        protocol: Protocol::Ssh,
+        url,
+        path: path.to_string(),
+    }))
+}
+
+/// The user, host and path of an scp-style remote, or `None` if `path_str` isn't one.
+/// [`crate::path::LocalPath::parse`] refuses the same strings (when they have a user),
+/// so the two never disagree about what is remote.
+pub(crate) fn scp_parts(path_str: &str) -> Option<(Option<&str>, &str, &str)> {
+    let (user, host_path) = match path_str.split_once('@') {
+        Some((user, rest)) if !user.is_empty() && !user.contains(['/', '\\', ':']) => {
+            (Some(user), rest)
+        }
+        Some(_) => return None,
+        None => (None, path_str),
+    };
+    let [host, path] = split_scp_host(host_path)[..] else {
+        return None;
+    };
+    let valid_host = if host.starts_with('[') {
+        host.len() > 2
+    } else {
+        !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    };
+    if !valid_host || (cfg!(windows) && user.is_none() && host.len() == 1) {
+        return None;
+    }
+    Some((user, host, path))
+}
+
+/// The `[user@]host[:port]` of `url` as written. Unless `whole`, it is found as the URL
+/// parser does for `http` and the like: after any extra slashes, up to `/`, `\\`, `?`
+/// or `#`.
+fn raw_authority(url: &str, whole: bool) -> &str {
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    if whole {
+        return &after_scheme[..after_scheme.find('/').unwrap_or(after_scheme.len())];
+    }
+    let after_slashes = after_scheme.trim_start_matches(['/', '\\']);
+    let end = after_slashes.find(['/', '\\', '?', '#']);
+    &after_slashes[..end.unwrap_or(after_slashes.len())]
+}
+
+/// The path of `url` as written: everything from the first `/` after `scheme://`.
+fn raw_url_path(url: &str) -> &str {
+    let after_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    after_scheme
+        .find('/')
+        .map_or("", |start| &after_scheme[start..])
+}
+
+/// Split the `host:path` of an scp-style remote at the colon ending the host. A host in
+/// brackets (`[2001:db8::1]:/data`) is an IPv6 literal and keeps its brackets.
+fn split_scp_host(host_path: &str) -> Vec<&str> {
+    if host_path.starts_with('[') {
+        if let Some(end) = host_path.find("]:") {
+            return vec![&host_path[..=end], &host_path[end + 2..]];
+        }
+    }
+    host_path.splitn(2, ':').collect()
+}
+
+/// The local path named by a `file://` URL: percent-escapes decoded, `localhost` accepted
+/// as the host, and `file:///C:/...` turned back into a drive path.
+fn file_url_to_local(url: &Url, original: &str) -> Result<Path, PathParseError> {
+    let invalid = |error: String| PathParseError::InvalidUrl {
+        path: original.to_string(),
+        error,
+    };
+    let mut path = percent_decode(url.path())
+        .ok_or_else(|| invalid("the path is not valid UTF-8".to_string()))?;
+    match url.host_str() {
+        None | Some("") | Some("localhost") => {}
+        Some(host) if cfg!(windows) => {
+            path = format!("\\\\{}{}", host, path.replace('/', "\\"));
+        }
+        Some(host) => {
+            return Err(invalid(format!(
+                "'{}' is not this machine; use ssh:// for files on other hosts",
+                host
+            )))
+        }
+    }
+    if let Some(drive) = path.strip_prefix('/') {
+        if crate::path::is_windows_path(drive) {
+            path = drive.to_string();
+        }
+    }
+    Ok(Path::Local(crate::path::LocalPath::new(path.into())))
+}
+
+/// Decode the `%XX` escapes in `s`, leaving malformed ones as they are. `None` if the
+/// decoded bytes aren't UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
 #[derive(Debug)]
 pub enum PathParseError {
     InvalidUrl { path: String, error: String },
@@ -138,6 +354,7 @@ impl std::error::Error for PathParseError {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_parse_local_path() {
@@ -193,6 +410,196 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ssh_config_aliases() {
+        let remote = |input: &str| match parse_path(input).unwrap() {
+            Path::Remote(r) => r,
+            Path::Local(p) => panic!("{} parsed as local {}", input, p.to_string_lossy()),
+        };
+
+        // No user, so ssh and scp look `nas` up in ~/.ssh/config.
+        let alias = remote("nas:/volume1/backup");
+        assert_eq!(alias.protocol, Protocol::Ssh);
+        assert_eq!(alias.url.host_str(), Some("nas"));
+        assert_eq!(alias.url.username(), "");
+        assert_eq!(alias.url.port(), None);
+        assert_eq!(alias.path, "/volume1/backup");
+
+        let relative = remote("backup-host.lan:photos/2024");
+        assert_eq!(relative.url.host_str(), Some("backup-host.lan"));
+        assert_eq!(relative.path, "photos/2024");
+        assert_eq!(remote("me@nas:docs").url.username(), "me");
+        assert_eq!(remote("[::1]:/srv").ssh_host().as_deref(), Some("::1"));
+
+        // A `/` before the colon, or something that can't be a host, keeps it local.
+        for input in ["./notes:2024.txt", "dir/a:b", "/tmp/x:y", "foo", "a b:c"] {
+            assert!(
+                matches!(parse_path(input).unwrap(), Path::Local(_)),
+                "{} should be local",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_single_letter_host_or_drive() {
+        let parsed = parse_path("C:file.txt").unwrap();
+        if cfg!(windows) {
+            assert!(matches!(parsed, Path::Local(_)));
+        } else {
+            assert!(matches!(parsed, Path::Remote(ref r) if r.url.host_str() == Some("C")));
+        }
+        // With a user it is a host everywhere; with a separator it is a drive everywhere.
+        assert!(matches!(
+            parse_path("me@c:file.txt").unwrap(),
+            Path::Remote(_)
+        ));
+        assert!(matches!(parse_path("C:\\foo").unwrap(), Path::Local(_)));
+        assert!(matches!(parse_path("c:/foo").unwrap(), Path::Local(_)));
+    }
+
+    #[test]
+    fn test_ipv6_hosts() {
+        let remote = |input: &str| match parse_path(input).unwrap() {
+            Path::Remote(r) => r,
+            Path::Local(p) => panic!("{} parsed as local {}", input, p.to_string_lossy()),
+        };
+
+        let scp_style = remote("user@[2001:db8::1]:/data/file");
+        assert_eq!(scp_style.protocol, Protocol::Ssh);
+        assert_eq!(scp_style.url.host_str(), Some("[2001:db8::1]"));
+        assert_eq!(scp_style.ssh_host().as_deref(), Some("2001:db8::1"));
+        assert_eq!(scp_style.url.username(), "user");
+        assert_eq!(scp_style.url.port(), None);
+        assert_eq!(scp_style.path, "/data/file");
+
+        let mut with_port = scp_style.clone();
+        with_port.set_default_port(2222);
+        assert_eq!(with_port.url.port(), Some(2222));
+        assert_eq!(with_port.path, "/data/file");
+
+        let url = remote("ssh://user@[2001:db8::1]:2222/data");
+        assert_eq!(url.ssh_host().as_deref(), Some("2001:db8::1"));
+        assert_eq!(url.url.port(), Some(2222));
+        assert_eq!(url.path, "/data");
+        // An explicit port wins over -P.
+        let mut explicit = url.clone();
+        explicit.set_default_port(2200);
+        assert_eq!(explicit.url.port(), Some(2222));
+
+        let url = remote("ssh://[::1]/srv/");
+        assert_eq!(url.ssh_host().as_deref(), Some("::1"));
+        assert_eq!(url.url.port(), None);
+        assert!(url.is_dir_hint());
+
+        let plain = remote("user@host:/data/file");
+        assert_eq!(plain.ssh_host().as_deref(), Some("host"));
+    }
+
+    #[test]
+    fn test_windows_paths_are_local() {
+        for input in [
+            "C:\\foo",
+            "C:/foo/bar.txt",
+            "C:\\Users\\me@corp\\x:y",
+            "\\\\server\\share\\file.txt",
+        ] {
+            assert!(
+                matches!(parse_path(input).unwrap(), Path::Local(_)),
+                "{} should be local",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_urls_are_local() {
+        let local = |input: &str| match parse_path(input).unwrap() {
+            Path::Local(p) => p.to_string_lossy().into_owned(),
+            Path::Remote(r) => panic!("{} parsed as remote {}", input, r.url),
+        };
+        assert_eq!(local("file:///home/me/a.txt"), "/home/me/a.txt");
+        assert_eq!(
+            local("file:///home/me/My%20Documents/a%20b.txt"),
+            "/home/me/My Documents/a b.txt"
+        );
+        assert_eq!(local("file://localhost/srv/data/"), "/srv/data/");
+        assert_eq!(local("file:///tmp/caf%C3%A9"), "/tmp/café");
+        assert_eq!(
+            local("file:///C:/Users/me/report%202024.pdf"),
+            "C:/Users/me/report 2024.pdf"
+        );
+        if !cfg!(windows) {
+            assert!(parse_path("file://fileserver/share/a.txt").is_err());
+        }
+    }
+
+    #[test]
+    fn test_url_paths_are_decoded() {
+        let remote = |input: &str| match parse_path(input).unwrap() {
+            Path::Remote(r) => r,
+            Path::Local(p) => panic!("{} parsed as local {}", input, p.to_string_lossy()),
+        };
+        let cases = [
+            (
+                "ssh://user@host/home/me/My%20Documents/file.txt",
+                "/home/me/My Documents/file.txt",
+            ),
+            (
+                "ssh://host/srv/issue#42/notes?.txt",
+                "/srv/issue#42/notes?.txt",
+            ),
+            (
+                "sftp://host/srv/caf%C3%A9/r%C3%A9sum%C3%A9.pdf",
+                "/srv/café/résumé.pdf",
+            ),
+            ("ssh://host/srv/déjà vu.txt", "/srv/déjà vu.txt"),
+            (
+                "s3://bucket/My%20Documents/a%23b%3F.txt",
+                "/My Documents/a#b?.txt",
+            ),
+            (
+                "s3://bucket/reports/q1#draft?v=2.csv",
+                "/reports/q1#draft?v=2.csv",
+            ),
+            ("s3://bucket/%E6%97%A5%E6%9C%AC/data.bin", "/日本/data.bin"),
+        ];
+        for (input, path) in cases {
+            let parsed = remote(input);
+            assert_eq!(parsed.path, path, "{}", input);
+            // The URL stays encoded and parses back to the same path.
+            assert!(!parsed.url.as_str().contains(' '), "{}", parsed.url);
+            assert_eq!(parsed.url.fragment(), None);
+            assert_eq!(parsed.url.query(), None);
+            assert_eq!(remote(parsed.url.as_str()).path, path, "{}", parsed.url);
+        }
+
+        let key = remote("s3://bucket/My%20Documents/a%23b%3F.txt");
+        assert_eq!(key.s3_url(), "s3://bucket/My Documents/a#b?.txt");
+        let joined = remote("s3://bucket/My%20Documents/").join("a b.txt");
+        assert_eq!(joined.path, "/My Documents/a b.txt");
+        assert_eq!(joined.s3_url(), "s3://bucket/My Documents/a b.txt");
+
+        let http = remote("https://example.com/files/My%20Report.pdf?download=1");
+        assert_eq!(http.path, "/files/My Report.pdf");
+        assert_eq!(http.file_name(), Some("My Report.pdf"));
+        assert_eq!(http.url.query(), Some("download=1"));
+
+        // scp-style paths are not URLs and are taken as written.
+        assert_eq!(
+            remote("user@host:/srv/100%25 done").path,
+            "/srv/100%25 done"
+        );
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(percent_decode("100%").as_deref(), Some("100%"));
+        assert_eq!(percent_decode("%zz%2F").as_deref(), Some("%zz/"));
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
     #[test]
     fn test_parse_unknown_protocol() {
         let result = parse_path("ftp://example.com/file.txt");
@@ -232,6 +639,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_remote_dir_hint_join() {
+        let cases = [
+            ("ssh://user@host/srv/dir/", "ssh", "/srv/dir/file.txt"),
+            ("user@host:/srv/dir/", "ssh", "/srv/dir/file.txt"),
+            ("s3://bucket/prefix/", "s3", "/prefix/file.txt"),
+        ];
+        for (input, scheme, expected) in cases {
+            let Ok(Path::Remote(rp)) = parse_path(input) else {
+                panic!("Expected remote path for {}", input);
+            };
+            assert!(rp.is_dir_hint(), "{}", input);
+            assert_eq!(rp.file_name(), None);
+
+            let joined = rp.join("file.txt");
+            assert_eq!(joined.path, expected);
+            assert_eq!(joined.url.scheme(), scheme);
+            assert_eq!(joined.url.path(), expected);
+            assert!(!joined.is_dir_hint());
+            assert_eq!(joined.file_name(), Some("file.txt"));
+
+            let renamed = joined.with_file_name("other.txt");
+            assert_eq!(renamed.path, expected.replace("file.txt", "other.txt"));
+            assert_eq!(renamed.url.path(), renamed.path);
+        }
+    }
+
     #[test]
     fn test_parse_s3_url_with_prefix() {
         let result = parse_path("s3://my-bucket/path/dt=20250928/file.txt");
@@ -240,4 +674,144 @@ mod tests {
             assert_eq!(rp.protocol, Protocol::S3);
         }
     }
+
+    /// Cases the property tests below turned up.
+    #[test]
+    fn test_property_regressions() {
+        // `@` and `:` in a local name that can't be `user@host:path`.
+        for input in ["A@:", "dir/me@host:x", "./me@host:x", "@host:path", "C:\\"] {
+            assert!(
+                matches!(parse_path(input), Ok(Path::Local(_))),
+                "{} should be local",
+                input
+            );
+        }
+        // URLs without a usable host.
+        for input in [
+            "ssh://#",
+            "ssh:///srv",
+            "s3:///key",
+            "ssh://host#x/file",
+            "ssh://host ",
+            "file:// ",
+        ] {
+            assert!(parse_path(input).is_err(), "{} should be refused", input);
+        }
+        assert_eq!(classify("http:///_ "), classify("http:///_ /"));
+        // Users are set apart from the host, so `?` and `%` stay in them.
+        for (input, user) in [("a?b@host:/x", "a?b"), ("a=%00@host:/x", "a=%00")] {
+            let Ok(Path::Remote(remote)) = parse_path(input) else {
+                panic!("{} should be remote", input);
+            };
+            assert_eq!(remote.ssh_user(), user);
+            assert_eq!(remote.ssh_host().as_deref(), Some("host"));
+        }
+    }
+
+    /// What `parse_path` made of `input`: `local`, the protocol, or `error`.
+    fn classify(input: &str) -> String {
+        match parse_path(input) {
+            Ok(Path::Local(_)) => "local".to_string(),
+            Ok(Path::Remote(r)) => r.protocol.to_string(),
+            Err(_) => "error".to_string(),
+        }
+    }
+
+    /// Arbitrary text, and text made of the characters the heuristics look at.
+    fn plain_path() -> impl Strategy<Value = String> {
+        prop_oneof!["\\PC{0,24}", "[a-zA-Z0-9@:/\\\\.\\[\\] _%#?~-]{0,24}"]
+            .prop_filter("a URL", |s| !s.contains("://"))
+    }
+
+    /// Plain paths and URLs.
+    fn any_path() -> impl Strategy<Value = String> {
+        let scheme =
+            prop::sample::select(vec!["ssh", "sftp", "s3", "http", "https", "ftp", "file"]);
+        prop_oneof![
+            plain_path(),
+            (scheme, "[a-zA-Z0-9@:/.\\[\\] _%#?-]{0,20}")
+                .prop_map(|(s, rest)| format!("{}://{}", s, rest)),
+        ]
+    }
+
+    fn host() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z][a-z0-9-]{1,10}(\\.[a-z][a-z0-9-]{0,6}){0,2}",
+            "[0-9a-f]{1,4}(:[0-9a-f]{1,4}){7}".prop_map(|v6| format!("[{}]", v6)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_inputs_without_a_scheme_parse(input in plain_path()) {
+            match parse_path(&input) {
+                Ok(_) => {}
+                // A bracketed host that isn't an IPv6 address is still meant as a remote.
+                Err(PathParseError::InvalidUrl { .. }) if input.contains('[') => {}
+                Err(e) => prop_assert!(false, "{:?}: {}", input, e),
+            }
+        }
+
+        #[cfg(not(windows))]
+        #[test]
+        fn prop_local_paths_round_trip(input in plain_path()) {
+            if let Ok(Path::Local(local)) = parse_path(&input) {
+                prop_assert_eq!(local.to_string_lossy(), input.as_str());
+                let reparsed = crate::path::LocalPath::parse(&local.to_string_lossy());
+                prop_assert!(reparsed.is_ok(), "{:?}: {:?}", input, reparsed);
+            }
+        }
+
+        #[test]
+        fn prop_remote_paths_have_a_host(input in any_path()) {
+            if let Ok(Path::Remote(remote)) = parse_path(&input) {
+                prop_assert!(
+                    remote.url.host_str().is_some_and(|h| !h.is_empty()),
+                    "{:?} parsed to {}", input, remote.url
+                );
+            }
+        }
+
+        #[test]
+        fn prop_trailing_slash_keeps_the_classification(input in any_path()) {
+            let input = input.trim_end_matches(['/', '\\']);
+            prop_assert_eq!(classify(input), classify(&format!("{}/", input)), "{:?}", input);
+        }
+
+        #[test]
+        fn prop_scp_style_matches_ssh_url(
+            user in "[a-z][a-z0-9._-]{0,8}",
+            host in host(),
+            port in prop::option::of(1u16..),
+            path in "(/[a-zA-Z0-9._ #?-]{1,8}){0,4}/?",
+        ) {
+            let Ok(Path::Remote(mut scp)) = parse_path(&format!("{}@{}:{}", user, host, path)) else {
+                return Err(TestCaseError::fail("scp-style path not remote"));
+            };
+            let port_part = port.map(|p| format!(":{}", p)).unwrap_or_default();
+            let url = format!("ssh://{}@{}{}{}", user, host, port_part, path);
+            let Ok(Path::Remote(ssh)) = parse_path(&url) else {
+                return Err(TestCaseError::fail(format!("{} not remote", url)));
+            };
+            if let Some(port) = port {
+                scp.set_default_port(port);
+            }
+            prop_assert_eq!(&scp.protocol, &ssh.protocol);
+            prop_assert_eq!(scp.ssh_host(), ssh.ssh_host());
+            prop_assert_eq!(scp.ssh_user(), ssh.ssh_user());
+            // ssh itself uses 22 when no port is given.
+            prop_assert_eq!(scp.url.port().filter(|&p| p != 22), ssh.url.port().filter(|&p| p != 22));
+            prop_assert_eq!(scp.path, ssh.path);
+        }
+
+        #[test]
+        fn prop_scp_style_users_survive(user in "[a-zA-Z0-9][a-zA-Z0-9 #?%!$&'()*+,;=._~-]{0,10}") {
+            let Ok(Path::Remote(remote)) = parse_path(&format!("{}@host:/srv", user)) else {
+                return Err(TestCaseError::fail("not remote"));
+            };
+            prop_assert_eq!(remote.ssh_host(), Some("host".to_string()));
+            prop_assert_eq!(remote.ssh_user(), user);
+            prop_assert_eq!(remote.path, "/srv");
+        }
+    }
 }
@@ -1,7 +1,14 @@
+use std::collections::HashMap;
+use std::io::Write;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
+use crate::http::{self, Download, HttpChecks, Response};
+use crate::output;
+use crate::parse;
+use crate::progress;
 use crate::protocol::{Protocol, RemotePath};
+use crate::segment;
 
 pub fn copy_remote(
     src: &RemotePath,
@@ -12,7 +19,7 @@ pub fn copy_remote(
 ) -> Result<(), RemoteCopyError> {
     match (&src.protocol, &dst.protocol) {
         (Protocol::Ssh | Protocol::Sftp, Protocol::Ssh | Protocol::Sftp) => {
-            copy_ssh_to_ssh(src, dst, verbose, ssh_opts, progress)
+            copy_ssh_to_ssh(src, dst, false, verbose, ssh_opts, progress)
         }
         (Protocol::S3, Protocol::S3) => Err(RemoteCopyError::NotImplemented(
             "S3 to S3 copy is not yet implemented".to_string(),
@@ -38,11 +45,16 @@ pub fn copy_from_ssh_to_file(
     })?;
 
     let port = src.url.port().unwrap_or(22);
-    let username = src.url.username();
+    let username = &src.ssh_user();
     let remote_path = src.path.as_str();
 
     if verbose {
-        println!("Connecting to SSH: {}@{}:{}", username, host, port);
+        println!(
+            "Connecting to SSH: {}{}:{}",
+            user_prefix(username),
+            host,
+            port
+        );
         println!(
             "Copying from remote: {} to local: {}",
             remote_path,
@@ -57,42 +69,288 @@ pub fn copy_from_ssh_to_file(
         })?;
     }
 
-    let remote_spec = format!("{}@{}:{}", username, host, remote_path);
+    let remote_spec = format!("{}{}:{}", user_prefix(username), host, remote_path);
+
+    let mut cmd = scp_command(port, verbose, progress, ssh_opts);
+    cmd.arg(&remote_spec).arg(dst_path);
+
+    let transfer = progress::Transfer {
+        dst: Some(dst_path),
+        size: if progress {
+            remote_size(src, ssh_opts).ok().flatten()
+        } else {
+            None
+        },
+    };
+    let output = run_scp(&mut cmd, progress, transfer)?;
+    if !output.status.success() {
+        return Err(transfer_error(&output, "scp failed to copy file"));
+    }
+    if verbose {
+        println!("✓ Successfully copied from remote to local");
+    }
+    Ok(())
+}
 
+/// Build an `scp` invocation with the port, verbosity and `-o` options applied.
+pub fn scp_command(port: u16, verbose: bool, progress: bool, ssh_opts: &[String]) -> Command {
     let mut cmd = Command::new("scp");
 
     if port != 22 {
         cmd.arg("-P").arg(port.to_string());
     }
 
-    if progress {
-        cmd.arg("-v");
-    } else if !verbose {
+    // Without -q scp draws its own progress meter. (-v would only add debug output,
+    // which drowns the meter and password prompts.)
+    if !verbose && !progress {
         cmd.arg("-q");
     }
 
     for opt in ssh_opts {
         cmd.arg("-o").arg(opt);
     }
+    #[cfg(unix)]
+    SshMultiplex::count_session(ssh_opts);
 
-    cmd.arg(&remote_spec).arg(dst_path);
+    cmd
+}
 
-    let status = cmd.status().map_err(|e| RemoteCopyError::IoError {
-        message: "Failed to execute scp".to_string(),
-        error: e.to_string(),
+/// Build an `ssh` invocation for running a remote command on `user@host`.
+///
+/// Uses the same `-o` options as [`scp_command`] so that identity files, ports and
+/// host key settings given with `--ssh-opt` apply to every remote call, not only copies.
+pub fn ssh_command(remote: &RemotePath, ssh_opts: &[String]) -> Result<Command, RemoteCopyError> {
+    let host = remote.ssh_host().ok_or_else(|| {
+        RemoteCopyError::ConnectionError("No host specified in SSH URL".to_string())
     })?;
 
-    if status.success() {
-        if verbose {
-            println!("✓ Successfully copied from remote to local");
+    let mut cmd = Command::new("ssh");
+
+    if let Some(port) = remote.url.port() {
+        if port != 22 {
+            cmd.arg("-p").arg(port.to_string());
         }
-        Ok(())
+    }
+
+    for opt in ssh_opts {
+        cmd.arg("-o").arg(opt);
+    }
+    #[cfg(unix)]
+    SshMultiplex::count_session(ssh_opts);
+
+    let username = remote.ssh_user();
+    if username.is_empty() {
+        cmd.arg(host);
     } else {
-        Err(RemoteCopyError::IoError {
-            message: "scp failed to copy file".to_string(),
-            error: format!("Exit code: {}", status.code().unwrap_or(-1)),
+        cmd.arg(format!("{}@{}", username, host));
+    }
+
+    Ok(cmd)
+}
+
+/// An OpenSSH ControlMaster connection shared by the scp/ssh calls of one operation.
+///
+/// The first invocation for a host opens the master on a socket in a private temp
+/// directory and later invocations reuse it, so a multi-file transfer pays for the
+/// handshake and authentication only once. The masters are closed on drop.
+#[cfg(unix)]
+pub struct SshMultiplex {
+    dir: tempfile::TempDir,
+    remotes: Vec<RemotePath>,
+    ssh_opts: Vec<String>,
+    verbose: bool,
+    started: std::time::Instant,
+}
+
+/// The ssh and scp commands built with a [`SshMultiplex`]'s options, by its
+/// `ControlPath`.
+#[cfg(unix)]
+static SESSIONS: std::sync::Mutex<Option<std::collections::HashMap<String, usize>>> =
+    std::sync::Mutex::new(None);
+
+#[cfg(unix)]
+impl SshMultiplex {
+    /// Prepare a control socket directory for `remotes`. Returns `None` (and the
+    /// caller falls back to plain connections) when the directory can't be created.
+    pub fn start(remotes: &[&RemotePath], ssh_opts: &[String], verbose: bool) -> Option<Self> {
+        // Not --tmpdir: this only holds sockets, whose paths must stay short (~104 bytes).
+        let dir = match tempfile::Builder::new().prefix("usync-ssh-").tempdir() {
+            Ok(dir) => dir,
+            Err(e) => {
+                if verbose {
+                    output::warn(format_args!("SSH multiplexing disabled: {}", e));
+                }
+                return None;
+            }
+        };
+
+        if verbose {
+            println!(
+                "SSH multiplexing enabled (control dir: {})",
+                dir.path().display()
+            );
+        }
+
+        Some(Self {
+            dir,
+            remotes: remotes.iter().map(|r| (*r).clone()).collect(),
+            ssh_opts: ssh_opts.to_vec(),
+            verbose,
+            started: std::time::Instant::now(),
         })
     }
+
+    fn control_path(&self) -> String {
+        format!("{}/%C", self.dir.path().display())
+    }
+
+    fn control_opts(&self) -> Vec<String> {
+        vec![
+            "ControlMaster=auto".to_string(),
+            format!("ControlPath={}", self.control_path()),
+            "ControlPersist=60".to_string(),
+        ]
+    }
+
+    /// The user's ssh options followed by the ControlMaster options.
+    pub fn ssh_opts(&self) -> Vec<String> {
+        let mut opts = self.ssh_opts.clone();
+        opts.extend(self.control_opts());
+        opts
+    }
+
+    /// Count an ssh or scp session started with `ssh_opts`, if they share a connection.
+    fn count_session(ssh_opts: &[String]) {
+        let Some(path) = ssh_opts.iter().find_map(|o| o.strip_prefix("ControlPath=")) else {
+            return;
+        };
+        let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+        *sessions
+            .get_or_insert_with(Default::default)
+            .entry(path.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// The ssh and scp sessions started with this multiplex's options so far.
+    pub fn sessions(&self) -> usize {
+        let sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+        sessions
+            .as_ref()
+            .and_then(|s| s.get(&self.control_path()).copied())
+            .unwrap_or(0)
+    }
+
+    /// What sharing saved: each host's first session opens its connection, and the
+    /// others reuse it instead of connecting and authenticating again.
+    fn summary(&self) -> String {
+        let sessions = self.sessions();
+        let connections = sessions.min(self.remotes.len());
+        format!(
+            "SSH multiplexing: {} ssh/scp session(s) over {} connection(s) in {:.2}s; \
+             {} handshake(s) saved",
+            sessions,
+            connections,
+            self.started.elapsed().as_secs_f64(),
+            sessions - connections
+        )
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SshMultiplex {
+    fn drop(&mut self) {
+        let summary = self.summary();
+        let opts = self.ssh_opts();
+        for remote in &self.remotes {
+            if let Ok(mut cmd) = ssh_command(remote, &opts) {
+                let _ = cmd
+                    .arg("-O")
+                    .arg("exit")
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .status();
+            }
+        }
+        if let Some(sessions) = SESSIONS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            sessions.remove(&self.control_path());
+        }
+        if self.verbose {
+            println!("{}", summary);
+        }
+    }
+}
+
+/// Connection sharing relies on Unix domain sockets; elsewhere every call connects anew.
+#[cfg(not(unix))]
+pub struct SshMultiplex {
+    ssh_opts: Vec<String>,
+}
+
+#[cfg(not(unix))]
+impl SshMultiplex {
+    pub fn start(_remotes: &[&RemotePath], _ssh_opts: &[String], _verbose: bool) -> Option<Self> {
+        None
+    }
+
+    pub fn ssh_opts(&self) -> Vec<String> {
+        self.ssh_opts.clone()
+    }
+}
+
+/// Set when usync runs as ssh's `SSH_ASKPASS` helper: the password file to answer from.
+pub const ASKPASS_FILE_ENV: &str = "USYNC_ASKPASS_FILE";
+
+/// Answer ssh's password prompts from `password_file`, for unattended runs against
+/// servers that only take passwords.
+///
+/// Follows the `SSH_ASKPASS` convention with this executable as the helper (see
+/// [`askpass_reply`]), and `SSH_ASKPASS_REQUIRE=force` so ssh uses it even with a
+/// terminal, for every ssh and scp started afterwards. Only the file's path is handed
+/// around: the password never appears in a command line, the environment, or verbose
+/// output. Sets environment variables of this process, so call it before starting
+/// threads.
+pub fn use_password_file(password_file: &Path) -> std::io::Result<()> {
+    let password_file = std::fs::canonicalize(password_file)?;
+    let metadata = std::fs::metadata(&password_file)?;
+    if !metadata.is_file() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "not a file",
+        ));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            output::warn(format_args!(
+                "{} is accessible by other users; consider chmod 600",
+                password_file.display()
+            ));
+        }
+    }
+
+    std::env::set_var("SSH_ASKPASS", std::env::current_exe()?);
+    std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+    std::env::set_var(ASKPASS_FILE_ENV, &password_file);
+    // OpenSSH before 8.4 ignores SSH_ASKPASS_REQUIRE and only asks the helper when
+    // DISPLAY is set and there is no terminal.
+    if std::env::var_os("DISPLAY").is_none() {
+        std::env::set_var("DISPLAY", ":0");
+    }
+    Ok(())
+}
+
+/// The answer usync gives as ssh's askpass helper: the first line of the password file.
+/// Host key questions (`yes/no`) aren't answered, so a new host still fails rather than
+/// being accepted with the password.
+pub fn askpass_reply(prompt: &str, password_file: &Path) -> std::io::Result<Option<String>> {
+    if prompt.contains("(yes/no") {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(password_file)?;
+    Ok(Some(
+        contents.lines().next().unwrap_or_default().to_string(),
+    ))
 }
 
 pub fn copy_from_ssh(
@@ -125,11 +383,16 @@ pub fn copy_file_to_ssh(
     })?;
 
     let port = dst.url.port().unwrap_or(22);
-    let username = dst.url.username();
+    let username = &dst.ssh_user();
     let remote_path = dst.path.as_str();
 
     if verbose {
-        println!("Connecting to SSH: {}@{}:{}", username, host, port);
+        println!(
+            "Connecting to SSH: {}{}:{}",
+            user_prefix(username),
+            host,
+            port
+        );
         println!(
             "Copying from local: {} to remote: {}",
             src_path.display(),
@@ -137,41 +400,473 @@ pub fn copy_file_to_ssh(
         );
     }
 
-    let remote_spec = format!("{}@{}:{}", username, host, remote_path);
+    let remote_spec = format!("{}{}:{}", user_prefix(username), host, remote_path);
 
-    let mut cmd = Command::new("scp");
+    let mut cmd = scp_command(port, verbose, progress, ssh_opts);
+    cmd.arg(src_path).arg(&remote_spec);
 
-    if port != 22 {
-        cmd.arg("-P").arg(port.to_string());
+    let transfer = progress::Transfer {
+        dst: None,
+        size: std::fs::metadata(src_path).ok().map(|m| m.len()),
+    };
+    let output = run_scp(&mut cmd, progress, transfer)?;
+    if !output.status.success() {
+        return Err(transfer_error(&output, "scp failed to copy file"));
+    }
+    if verbose {
+        println!("✓ Successfully copied from local to remote");
     }
+    Ok(())
+}
 
-    if progress {
-        cmd.arg("-v");
-    } else if !verbose {
-        cmd.arg("-q");
+/// Quote `s` for use as a single word in a POSIX shell command line.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Join a relative local path onto a remote directory using `/` separators.
+pub fn remote_join(dir: &str, rel: &Path) -> String {
+    format!(
+        "{}/{}",
+        dir.trim_end_matches('/'),
+        crate::utils::slash_path(rel)
+    )
+}
+
+/// The flags of the local `tar` that packs an upload and the remote one that unpacks
+/// it: gzip'd with `--compress`.
+fn tar_flags(compress: bool) -> (&'static str, &'static str) {
+    if compress {
+        ("-czf", "-xzf")
+    } else {
+        ("-cf", "-xf")
     }
+}
 
-    for opt in ssh_opts {
-        cmd.arg("-o").arg(opt);
+/// Upload `files` (relative to `src_root`) to the remote directory `dst` by piping
+/// `tar -c` into a single `ssh ... tar -x`, avoiding one scp round-trip per file.
+pub fn copy_files_to_ssh_tar(
+    src_root: &Path,
+    files: &[std::path::PathBuf],
+    dst: &RemotePath,
+    verbose: bool,
+    compress: bool,
+    ssh_opts: &[String],
+) -> Result<(), RemoteCopyError> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    if verbose {
+        println!(
+            "Streaming {} files to {} via tar over ssh{}",
+            files.len(),
+            dst.path,
+            if compress { " (gzip)" } else { "" }
+        );
     }
 
-    cmd.arg(src_path).arg(&remote_spec);
+    let (create_flags, extract_flags) = tar_flags(compress);
+
+    let remote_dir = shell_quote(&dst.path);
+    let mut ssh = ssh_command(dst, ssh_opts)?;
+    ssh.arg(format!(
+        "mkdir -p {} && tar {} - -C {}",
+        remote_dir, extract_flags, remote_dir
+    ));
+
+    let mut tar = Command::new("tar")
+        .arg(create_flags)
+        .arg("-")
+        .arg("-C")
+        .arg(src_root)
+        .arg("--null")
+        .arg("-T")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to execute tar".to_string(),
+            error: e.to_string(),
+        })?;
 
-    let status = cmd.status().map_err(|e| RemoteCopyError::IoError {
-        message: "Failed to execute scp".to_string(),
-        error: e.to_string(),
+    let tar_out = tar.stdout.take().ok_or_else(|| RemoteCopyError::IoError {
+        message: "Failed to capture tar output".to_string(),
+        error: "stdout not available".to_string(),
     })?;
 
-    if status.success() {
-        if verbose {
-            println!("✓ Successfully copied from local to remote");
+    let ssh_child = ssh
+        .stdin(Stdio::from(tar_out))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to execute ssh".to_string(),
+            error: e.to_string(),
+        })?;
+    // The command still holds the read end of tar's output: with it open, tar would
+    // block for good once ssh stops reading, instead of failing.
+    drop(ssh);
+
+    let mut list = Vec::new();
+    for file in files {
+        list.extend_from_slice(crate::utils::slash_path(file).as_bytes());
+        list.push(0);
+    }
+    let mut tar_in = tar.stdin.take();
+    let writer = std::thread::spawn(move || {
+        if let Some(stdin) = tar_in.as_mut() {
+            let _ = stdin.write_all(&list);
         }
-        Ok(())
-    } else {
-        Err(RemoteCopyError::IoError {
-            message: "scp failed to copy file".to_string(),
+    });
+
+    let ssh_output = ssh_child
+        .wait_with_output()
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to wait for ssh".to_string(),
+            error: e.to_string(),
+        })?;
+    let _ = writer.join();
+    let tar_output = tar
+        .wait_with_output()
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to wait for tar".to_string(),
+            error: e.to_string(),
+        })?;
+
+    if !tar_output.status.success() {
+        return Err(RemoteCopyError::IoError {
+            message: "tar failed to archive files".to_string(),
+            error: String::from_utf8_lossy(&tar_output.stderr)
+                .trim()
+                .to_string(),
+        });
+    }
+
+    if !ssh_output.status.success() {
+        let stderr = String::from_utf8_lossy(&ssh_output.stderr);
+        return Err(RemoteCopyError::IoError {
+            message: "Remote tar failed to extract files".to_string(),
+            error: if stderr.trim().is_empty() {
+                format!("Exit code: {}", ssh_output.status.code().unwrap_or(-1))
+            } else {
+                stderr.trim().to_string()
+            },
+        });
+    }
+
+    if verbose {
+        println!("✓ Successfully streamed {} files to remote", files.len());
+    }
+    Ok(())
+}
+
+/// Create `dirs` on the host of `remote` with a single `ssh ... mkdir -p`.
+pub fn ssh_mkdir_p(
+    remote: &RemotePath,
+    dirs: &[String],
+    ssh_opts: &[String],
+) -> Result<(), RemoteCopyError> {
+    let mut mkdir = ssh_command(remote, ssh_opts)?;
+    let quoted: Vec<String> = dirs.iter().map(|d| shell_quote(d)).collect();
+    mkdir.arg(format!("mkdir -p {}", quoted.join(" ")));
+    let status = mkdir.status().map_err(|e| RemoteCopyError::IoError {
+        message: "Failed to execute ssh".to_string(),
+        error: e.to_string(),
+    })?;
+    if !status.success() {
+        return Err(RemoteCopyError::IoError {
+            message: "Failed to create remote directories".to_string(),
             error: format!("Exit code: {}", status.code().unwrap_or(-1)),
+        });
+    }
+    Ok(())
+}
+
+/// Run `chmod` with each of `modes` in turn over `paths` on the host of `remote`, with a
+/// single `ssh` call that reads the paths from stdin.
+pub fn ssh_chmod(
+    remote: &RemotePath,
+    modes: &[String],
+    paths: &[String],
+    ssh_opts: &[String],
+) -> Result<(), RemoteCopyError> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    if modes.is_empty() || paths.is_empty() {
+        return Ok(());
+    }
+    let chmods: Vec<String> = modes
+        .iter()
+        .map(|mode| format!("chmod -- {} \"$@\"", mode))
+        .collect();
+    let mut cmd = ssh_command(remote, ssh_opts)?;
+    cmd.arg(format!(
+        "xargs -0 sh -c {} sh",
+        shell_quote(&chmods.join(" && "))
+    ))
+    .stdin(Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| RemoteCopyError::IoError {
+        message: "Failed to execute ssh".to_string(),
+        error: e.to_string(),
+    })?;
+    let mut list = Vec::new();
+    for path in paths {
+        list.extend_from_slice(path.as_bytes());
+        list.push(0);
+    }
+    // A failed write shows up as ssh's exit status.
+    let _ = child.stdin.take().unwrap().write_all(&list);
+    let status = child.wait().map_err(|e| RemoteCopyError::IoError {
+        message: "Failed to execute ssh".to_string(),
+        error: e.to_string(),
+    })?;
+    if !status.success() {
+        return Err(RemoteCopyError::IoError {
+            message: "Failed to apply --chmod to remote files".to_string(),
+            error: format!("Exit code: {}", status.code().unwrap_or(-1)),
+        });
+    }
+    Ok(())
+}
+
+/// An `ssh` call that writes whatever it reads on stdin to the remote path of `dst`.
+pub fn ssh_write_command(
+    dst: &RemotePath,
+    ssh_opts: &[String],
+) -> Result<Command, RemoteCopyError> {
+    let mut cmd = ssh_command(dst, ssh_opts)?;
+    cmd.arg(format!("cat > {}", shell_quote(&dst.path)));
+    Ok(cmd)
+}
+
+/// An `aws s3 cp - <url>` call that uploads whatever it reads on stdin to `dst`.
+pub fn s3_write_command(dst: &RemotePath) -> Command {
+    let mut cmd = Command::new("aws");
+    cmd.arg("s3").arg("cp");
+    aws_env_args(&mut cmd, None);
+    cmd.arg("--quiet").arg("-").arg(dst.s3_url());
+    cmd
+}
+
+/// An `ssh` call that prints the remote file `src` on its stdout.
+pub fn ssh_read_command(src: &RemotePath, ssh_opts: &[String]) -> Result<Command, RemoteCopyError> {
+    let mut cmd = ssh_command(src, ssh_opts)?;
+    cmd.arg(format!("cat {}", shell_quote(&src.path)));
+    Ok(cmd)
+}
+
+/// An `aws s3 cp <url> -` call that prints the object `src` on its stdout.
+pub fn s3_read_command(src: &RemotePath) -> Command {
+    let mut cmd = Command::new("aws");
+    cmd.arg("s3").arg("cp");
+    aws_env_args(&mut cmd, None);
+    cmd.arg("--quiet").arg(src.s3_url()).arg("-");
+    cmd
+}
+
+/// A `curl` call that prints the body of `src` on its stdout, following redirects.
+pub fn http_read_command(src: &RemotePath, compress: bool) -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.arg("-sSfL");
+    if compress {
+        cmd.arg("--compressed");
+    }
+    cmd.arg(src.url.as_str());
+    cmd
+}
+
+/// Run `script` on the remote host and return what it printed on stdout.
+pub fn ssh_output(
+    remote: &RemotePath,
+    script: &str,
+    ssh_opts: &[String],
+) -> Result<String, RemoteCopyError> {
+    let mut cmd = ssh_command(remote, ssh_opts)?;
+    cmd.arg(script);
+    command_output(cmd, "ssh")
+}
+
+/// Run `xargs -0 command` over `paths` in the remote directory `remote`, with a single
+/// ssh connection, returning what it printed.
+pub fn ssh_xargs(
+    remote: &RemotePath,
+    command: &str,
+    paths: &[&str],
+    ssh_opts: &[String],
+    failure: &str,
+) -> Result<Vec<u8>, RemoteCopyError> {
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut cmd = ssh_command(remote, ssh_opts)?;
+    cmd.arg(format!(
+        "cd {} && xargs -0 {}",
+        shell_quote(&remote.path),
+        command
+    ))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| RemoteCopyError::IoError {
+        message: "Failed to execute ssh".to_string(),
+        error: e.to_string(),
+    })?;
+    let mut list = Vec::new();
+    for path in paths {
+        list.extend_from_slice(path.as_bytes());
+        list.push(0);
+    }
+    // Written from another thread, so output filling its pipe can't stall the list.
+    // A failed write shows up as ssh's exit status.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(&list);
+    });
+    let output = child
+        .wait_with_output()
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to execute ssh".to_string(),
+            error: e.to_string(),
+        })?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(RemoteCopyError::IoError {
+            message: failure.to_string(),
+            error: format!("Exit code: {}", output.status.code().unwrap_or(-1)),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// The SHA-256 digests of `paths`, relative to the remote directory `remote`, keyed by
+/// path. Files that can't be read, and names `sha256sum` has to escape (with a
+/// backslash or newline), are left out.
+pub fn ssh_sha256(
+    remote: &RemotePath,
+    paths: &[&str],
+    ssh_opts: &[String],
+) -> Result<HashMap<String, String>, RemoteCopyError> {
+    let listing = ssh_xargs(
+        remote,
+        "sh -c 'sha256sum -- \"$@\" 2>/dev/null; exit 0' sh",
+        paths,
+        ssh_opts,
+        &format!("Failed to checksum files on {}", remote.url),
+    )?;
+    Ok(String::from_utf8_lossy(&listing)
+        .lines()
+        .filter(|line| !line.starts_with('\\'))
+        .filter_map(|line| line.split_once("  "))
+        .map(|(digest, path)| (path.to_string(), digest.to_ascii_lowercase()))
+        .collect())
+}
+
+/// Run `cmd` and return its stdout, turning a non-zero exit into an error carrying stderr.
+fn command_output(mut cmd: Command, tool: &str) -> Result<String, RemoteCopyError> {
+    let output = cmd.output().map_err(|e| RemoteCopyError::IoError {
+        message: format!("Failed to execute {}", tool),
+        error: e.to_string(),
+    })?;
+    if !output.status.success() {
+        return Err(transfer_error(&output, &format!("{} failed", tool)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run an scp transfer with its progress meter on the terminal, keeping stderr to tell
+/// a rejected login from other failures.
+///
+/// When the login that failed used a password from `SSH_ASKPASS` (`--ssh-password-file`)
+/// and a terminal is attached, the transfer runs once more without it so that ssh asks
+/// for the password instead.
+///
+/// With `progress`, the transfer is shown with [`progress::run`]; scp draws no meter
+/// into a pipe, so that comes from `transfer`.
+fn run_scp(
+    cmd: &mut Command,
+    progress: bool,
+    transfer: progress::Transfer,
+) -> Result<std::process::Output, RemoteCopyError> {
+    use std::io::IsTerminal;
+
+    let run = |cmd: &mut Command| {
+        if progress {
+            progress::run(cmd, progress::Tool::Scp, transfer)
+        } else {
+            cmd.stdin(std::process::Stdio::inherit())
+                .stdout(std::process::Stdio::inherit())
+                .output()
+        }
+        .map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to execute scp".to_string(),
+            error: e.to_string(),
         })
+    };
+    let output = run(cmd)?;
+    if !output.status.success()
+        && auth_failed(&String::from_utf8_lossy(&output.stderr))
+        && std::env::var_os("SSH_ASKPASS").is_some()
+        && std::io::stdin().is_terminal()
+    {
+        eprintln!("The SSH server rejected the password from SSH_ASKPASS; asking for it instead");
+        cmd.env_remove("SSH_ASKPASS")
+            .env_remove("SSH_ASKPASS_REQUIRE");
+        return run(cmd);
+    }
+    if output.status.success() && !output.stderr.is_empty() {
+        // Warnings such as a newly added host key.
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+    Ok(output)
+}
+
+/// Run a transfer tool and collect its output, through [`progress::run`] for `--progress`.
+fn run_tool(
+    cmd: &mut Command,
+    tool: progress::Tool,
+    progress: bool,
+    transfer: progress::Transfer,
+) -> std::io::Result<std::process::Output> {
+    if progress {
+        progress::run(cmd, tool, transfer)
+    } else {
+        cmd.output()
+    }
+}
+
+/// Whether ssh's stderr says the server refused every authentication method tried.
+/// (A remote file scp can't read is `scp: <path>: Permission denied`, without the list.)
+fn auth_failed(stderr: &str) -> bool {
+    ["Permission denied (", "Too many authentication failures"]
+        .iter()
+        .any(|m| stderr.contains(m))
+}
+
+/// The error for a failed ssh/scp run: [`RemoteCopyError::AuthenticationError`] when the
+/// login was refused, otherwise `message` with stderr (or the exit code).
+fn transfer_error(output: &std::process::Output, message: &str) -> RemoteCopyError {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if auth_failed(&stderr) {
+        let reason = stderr
+            .lines()
+            .find(|line| auth_failed(line))
+            .unwrap_or_default()
+            .trim();
+        return RemoteCopyError::AuthenticationError(format!(
+            "{}; load a key into ssh-agent, pass one with --ssh-opt IdentityFile=..., \
+             or give a password with --ssh-password-file",
+            reason
+        ));
+    }
+    RemoteCopyError::IoError {
+        message: message.to_string(),
+        error: if stderr.trim().is_empty() {
+            format!("Exit code: {}", output.status.code().unwrap_or(-1))
+        } else {
+            stderr.trim().to_string()
+        },
     }
 }
 
@@ -193,16 +888,55 @@ pub fn copy_to_ssh(
     ))
 }
 
+/// `user@`, or nothing for a remote without a user (ssh picks one from its config).
+fn user_prefix(username: &str) -> String {
+    match username {
+        "" => String::new(),
+        name => format!("{}@", name),
+    }
+}
+
+/// How `scp` addresses `remote`: `user@host:path`, or an `scp://` URI when the port
+/// isn't 22, so that two hosts in one command can each have their own port.
+pub fn scp_spec(remote: &RemotePath) -> Result<String, RemoteCopyError> {
+    let host = remote.url.host_str().ok_or_else(|| {
+        RemoteCopyError::ConnectionError("No host specified in SSH URL".to_string())
+    })?;
+    Ok(match remote.url.port() {
+        // The URI path is taken without its first `/`, so `//abs` stays absolute. scp
+        // decodes the user of a URI, so it stays escaped there.
+        Some(port) if port != 22 => format!(
+            "scp://{}{}:{}/{}",
+            user_prefix(remote.url.username()),
+            host,
+            port,
+            remote.path
+        ),
+        _ => format!(
+            "{}{}:{}",
+            user_prefix(&remote.ssh_user()),
+            host,
+            remote.path
+        ),
+    })
+}
+
+/// Copy between two SSH hosts with `scp -3`, which relays the data through this machine
+/// so the hosts don't need to reach each other. `ssh_opts` apply to both connections;
+/// host-specific settings belong in `~/.ssh/config`.
+///
+/// Returns [`RemoteCopyError::NotImplemented`] when the local scp has no `-3`.
 pub fn copy_ssh_to_ssh(
     src: &RemotePath,
     dst: &RemotePath,
+    recursive: bool,
     verbose: bool,
-    _ssh_opts: &[String],
-    _progress: bool,
+    ssh_opts: &[String],
+    progress: bool,
 ) -> Result<(), RemoteCopyError> {
     if verbose {
         println!(
-            "Copying from {}://{} to {}://{}",
+            "Copying from {}://{} to {}://{} via scp -3",
             src.protocol,
             src.url.host_str().unwrap_or(""),
             dst.protocol,
@@ -210,11 +944,247 @@ pub fn copy_ssh_to_ssh(
         );
     }
 
-    Err(RemoteCopyError::NotImplemented(
-        "SSH to SSH copy is not yet fully implemented".to_string(),
+    let mut cmd = scp_command(22, verbose, progress, ssh_opts);
+    cmd.arg("-3");
+    if recursive {
+        cmd.arg("-r");
+    }
+    cmd.arg(scp_spec(src)?).arg(scp_spec(dst)?);
+
+    let output = run_scp(&mut cmd, progress, progress::Transfer::default())?;
+    if output.status.success() {
+        if verbose {
+            println!("✓ Successfully copied between remote hosts");
+        }
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if ["illegal option", "unknown option", "invalid option"]
+        .iter()
+        .any(|m| stderr.contains(m))
+    {
+        return Err(RemoteCopyError::NotImplemented(
+            "this scp does not support -3".to_string(),
+        ));
+    }
+    Err(transfer_error(
+        &output,
+        "scp -3 failed to copy between hosts",
     ))
 }
 
+/// What [`ssh_stat_tree`] found at a remote path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTree {
+    pub is_dir: bool,
+    /// Size of every regular file (the path itself, unless it is a directory).
+    pub sizes: Vec<u64>,
+}
+
+impl RemoteTree {
+    pub fn total_size(&self) -> u64 {
+        self.sizes.iter().sum()
+    }
+}
+
+/// Whether `remote` is a directory, and the sizes of the files in it, in one ssh call.
+pub fn ssh_stat_tree(
+    remote: &RemotePath,
+    ssh_opts: &[String],
+) -> Result<RemoteTree, RemoteCopyError> {
+    let script = format!(
+        "p={}; if [ -d \"$p\" ]; then echo dir; \
+         find \"$p\" -type f -exec sh -c 'for f; do wc -c < \"$f\"; done' sh {{}} +; \
+         else echo file; wc -c < \"$p\"; fi",
+        shell_quote(&remote.path)
+    );
+    parse::stat_tree(&ssh_output(remote, &script, ssh_opts)?).map_err(|e| {
+        RemoteCopyError::IoError {
+            message: format!("Failed to stat remote path {}", remote.path),
+            error: e.to_string(),
+        }
+    })
+}
+
+/// Whether `remote` itself is a directory (an S3 prefix) rather than a file. Over ssh
+/// this is `test -d`. For S3, a trailing `/` or the bucket root is a prefix; otherwise
+/// a key that is no object but has objects below it is one. HTTP URLs are files.
+pub fn remote_is_dir(remote: &RemotePath, ssh_opts: &[String]) -> Result<bool, RemoteCopyError> {
+    match remote.protocol {
+        Protocol::Ssh | Protocol::Sftp => {
+            let output = ssh_output(
+                remote,
+                &format!(
+                    "if [ -d {} ]; then echo dir; else echo file; fi",
+                    shell_quote(&remote.path)
+                ),
+                ssh_opts,
+            )?;
+            Ok(output.trim() == "dir")
+        }
+        Protocol::S3 => {
+            let key = remote.path.trim_start_matches('/');
+            if key.is_empty() || key.ends_with('/') {
+                return Ok(true);
+            }
+            if key.contains('*') || s3_head_object(remote).is_ok() {
+                return Ok(false);
+            }
+            let bucket = remote.url.host_str().ok_or_else(|| {
+                RemoteCopyError::ConnectionError("No bucket specified in S3 URL".to_string())
+            })?;
+            let mut cmd = Command::new("aws");
+            cmd.arg("s3api").arg("list-objects-v2");
+            aws_env_args(&mut cmd, None);
+            cmd.arg("--bucket")
+                .arg(bucket)
+                .arg("--prefix")
+                .arg(format!("{}/", key))
+                .arg("--max-items")
+                .arg("1")
+                .arg("--query")
+                .arg("Contents[0].Key")
+                .arg("--output")
+                .arg("text");
+            let first = command_output(cmd, "aws s3api list-objects-v2")?;
+            Ok(!matches!(first.trim(), "" | "None"))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Size of the file at `remote` as its own side reports it: `wc -c` over ssh, the
+/// object's ContentLength for S3, and the final `Content-Length` of a `HEAD` for HTTP.
+/// `None` when the protocol has no way to tell (or the HTTP body is encoded).
+pub fn remote_size(
+    remote: &RemotePath,
+    ssh_opts: &[String],
+) -> Result<Option<u64>, RemoteCopyError> {
+    let unexpected = |what: &str| RemoteCopyError::IoError {
+        message: format!("Failed to read the size of {}", remote.url),
+        error: format!("unexpected {}", what),
+    };
+    match remote.protocol {
+        Protocol::Ssh | Protocol::Sftp => {
+            let output = ssh_output(
+                remote,
+                &format!("wc -c < {}", shell_quote(&remote.path)),
+                ssh_opts,
+            )?;
+            output
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| unexpected("wc output"))
+        }
+        Protocol::S3 => s3_head_object(remote)?
+            .split_whitespace()
+            .next()
+            .and_then(|len| len.parse().ok())
+            .map(Some)
+            .ok_or_else(|| unexpected("head-object output")),
+        Protocol::Http | Protocol::Https => Ok(parse::content_length(&http_head(remote)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Number of paths sent to the remote host per `wc -c` call in [`ssh_file_sizes`].
+const SIZE_BATCH: usize = 256;
+
+/// Sizes of the remote `paths` on the host of `remote`, in order, with `None` for
+/// paths that aren't readable files. Paths are checked a batch per ssh call.
+pub fn ssh_file_sizes(
+    remote: &RemotePath,
+    paths: &[String],
+    ssh_opts: &[String],
+) -> Result<Vec<Option<u64>>, RemoteCopyError> {
+    let mut sizes = Vec::with_capacity(paths.len());
+    for batch in paths.chunks(SIZE_BATCH) {
+        let quoted: Vec<String> = batch.iter().map(|p| shell_quote(p)).collect();
+        let script = format!(
+            "for p in {}; do wc -c < \"$p\" 2>/dev/null || echo -; done",
+            quoted.join(" ")
+        );
+        let output = ssh_output(remote, &script, ssh_opts)?;
+        let found: Vec<Option<u64>> = output.lines().map(|l| l.trim().parse().ok()).collect();
+        if found.len() != batch.len() {
+            return Err(RemoteCopyError::IoError {
+                message: format!("Failed to stat files on {}", remote.url),
+                error: format!("expected {} sizes, got {}", batch.len(), found.len()),
+            });
+        }
+        sizes.extend(found);
+    }
+    Ok(sizes)
+}
+
+/// The files below the remote directory `remote` (or the file itself), with sizes and
+/// modification times, from one `find` over ssh. Without GNU `find -printf` the sizes
+/// come from `wc -c` and the times are left out.
+pub fn ssh_list_files(
+    remote: &RemotePath,
+    ssh_opts: &[String],
+) -> Result<Vec<crate::list::Entry>, RemoteCopyError> {
+    let script = format!(
+        "p={}; if [ -d \"$p\" ]; then cd \"$p\" || exit 1; set -- . -type f; \
+         else cd \"$(dirname \"$p\")\" || exit 1; set -- \"./$(basename \"$p\")\" -maxdepth 0 -type f; fi; \
+         if find . -maxdepth 0 -printf '' >/dev/null 2>&1; then find \"$@\" -printf '%s %T@ %p\\0'; \
+         else find \"$@\" -exec sh -c 'for f; do printf \"%s - %s\\0\" $(($(wc -c < \"$f\"))) \"$f\"; done' sh {{}} +; fi",
+        shell_quote(&remote.path)
+    );
+    parse::file_list(&ssh_output(remote, &script, ssh_opts)?)
+        .map(|listing| listing.warn_skipped(remote.url.as_str()))
+        .map_err(|e| RemoteCopyError::IoError {
+            message: format!("Failed to list remote path {}", remote.path),
+            error: e.to_string(),
+        })
+}
+
+/// The objects under the S3 prefix `remote`, with sizes and modification times. The
+/// paths are relative to the prefix; a key given without its trailing `/` lists the
+/// object of that name and everything below `key/`, but not `key2/...`.
+pub fn s3_list_objects(remote: &RemotePath) -> Result<Vec<crate::list::Entry>, RemoteCopyError> {
+    let bucket = remote.url.host_str().ok_or_else(|| {
+        RemoteCopyError::ConnectionError("No bucket specified in S3 URL".to_string())
+    })?;
+    let key = remote.path.trim_start_matches('/');
+    let mut cmd = Command::new("aws");
+    cmd.arg("s3api").arg("list-objects-v2");
+    aws_env_args(&mut cmd, None);
+    cmd.arg("--bucket").arg(bucket);
+    if !key.is_empty() {
+        cmd.arg("--prefix").arg(key);
+    }
+    cmd.arg("--query")
+        .arg("Contents[].[Key,Size,LastModified]")
+        .arg("--output")
+        .arg("text");
+    parse::s3_listing(&command_output(cmd, "aws s3api list-objects-v2")?, key)
+        .map(|listing| listing.warn_skipped(&remote.s3_url()))
+        .map_err(|e| RemoteCopyError::IoError {
+            message: format!("Failed to list {}", remote.s3_url()),
+            error: e.to_string(),
+        })
+}
+
+/// Download the directory `src` into `dst_path` with `scp -r`.
+pub fn copy_dir_from_ssh(
+    src: &RemotePath,
+    dst_path: &Path,
+    verbose: bool,
+    ssh_opts: &[String],
+    progress: bool,
+) -> Result<(), RemoteCopyError> {
+    let mut cmd = scp_command(22, verbose, progress, ssh_opts);
+    cmd.arg("-r").arg(scp_spec(src)?).arg(dst_path);
+    let output = run_scp(&mut cmd, progress, progress::Transfer::default())?;
+    if !output.status.success() {
+        return Err(transfer_error(&output, "scp failed to copy directory"));
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum RemoteCopyError {
     NotImplemented(String),
@@ -223,12 +1193,18 @@ pub enum RemoteCopyError {
         dst: String,
     },
     ConnectionError(String),
-    #[allow(dead_code)]
     AuthenticationError(String),
     IoError {
         message: String,
         error: String,
     },
+    /// An HTTP download got a response it shouldn't keep.
+    HttpResponse {
+        url: String,
+        status: Option<u16>,
+        content_type: Option<String>,
+        problem: String,
+    },
 }
 
 impl std::fmt::Display for RemoteCopyError {
@@ -249,6 +1225,23 @@ impl std::fmt::Display for RemoteCopyError {
             RemoteCopyError::IoError { message, error } => {
                 write!(f, "{}: {}", message, error)
             }
+            RemoteCopyError::HttpResponse {
+                url,
+                status,
+                content_type,
+                problem,
+            } => {
+                write!(f, "{}: {} (", url, problem)?;
+                match status {
+                    Some(status) => write!(f, "HTTP {}", status)?,
+                    None => write!(f, "no HTTP status")?,
+                }
+                write!(
+                    f,
+                    ", Content-Type {})",
+                    content_type.as_deref().unwrap_or("not given")
+                )
+            }
         }
     }
 }
@@ -260,7 +1253,9 @@ pub fn copy_from_http_to_file(
     dst_path: &Path,
     verbose: bool,
     progress: bool,
-) -> Result<(), RemoteCopyError> {
+    compress: bool,
+    checks: &HttpChecks,
+) -> Result<Download, RemoteCopyError> {
     let url = src.url.to_string();
 
     if verbose {
@@ -274,27 +1269,129 @@ pub fn copy_from_http_to_file(
         })?;
     }
 
-    if let Ok(mut cmd) = try_curl(&url, dst_path, verbose, progress) {
-        let status = cmd.status().map_err(|e| RemoteCopyError::IoError {
+    // A conditional download of a file we have stays one request; so does a compressed
+    // one, whose ranges would be of the encoded body.
+    let conditional = checks.update && dst_path.is_file();
+    if checks.segments > 1 && !compress && !conditional {
+        if let Some(response) =
+            segment::download(src, dst_path, checks.segments, verbose, progress)?
+        {
+            if let Some(max) = checks.max_redirects.filter(|&max| response.redirects > max) {
+                let _ = std::fs::remove_file(dst_path);
+                return Err(response.error(&url, format!("more than {} redirects", max)));
+            }
+            check_response(&url, &response, dst_path, checks)?;
+            if checks.update {
+                http::store_etag(dst_path, response.etag.as_deref());
+            }
+            if verbose {
+                println!("✓ Successfully downloaded file");
+            }
+            return Ok(Download::Fetched);
+        }
+    }
+
+    if let Ok(mut cmd) = try_curl(&url, dst_path, verbose, progress, compress) {
+        // Headers of every response followed, for the checks below.
+        let headers = tempfile::NamedTempFile::new().map_err(|e| RemoteCopyError::IoError {
+            message: "Failed to create a temporary file".to_string(),
+            error: e.to_string(),
+        })?;
+        cmd.arg("-D").arg(headers.path());
+        if let Some(max) = checks.max_redirects {
+            cmd.arg("--max-redirs").arg(max.to_string());
+        }
+        if checks.update {
+            // Ask for the file only if it changed since the copy we have, and date the
+            // new copy as the server does, for next time.
+            if dst_path.is_file() {
+                cmd.arg("-z").arg(dst_path);
+            }
+            if let Some(etag) = http::stored_etag(dst_path) {
+                cmd.arg("-H").arg(format!("If-None-Match: {}", etag));
+            }
+            cmd.arg("-R");
+        }
+        let status = if progress {
+            progress::run(
+                &mut cmd,
+                progress::Tool::Curl,
+                progress::Transfer {
+                    dst: Some(dst_path),
+                    size: None,
+                },
+            )
+            .map(|o| o.status)
+        } else {
+            cmd.status()
+        }
+        .map_err(|e| RemoteCopyError::IoError {
             message: "Failed to execute curl".to_string(),
             error: e.to_string(),
         })?;
 
-        if status.success() {
+        let response =
+            Response::parse(&std::fs::read_to_string(headers.path()).unwrap_or_default());
+        match status.code() {
+            Some(0) => {}
+            // --fail: the server answered with an error status.
+            Some(22) => return Err(response.error(&url, "the server returned an error")),
+            Some(47) => {
+                return Err(response.error(
+                    &url,
+                    format!("more than {} redirects", checks.max_redirects.unwrap_or(50)),
+                ))
+            }
+            code => {
+                return Err(RemoteCopyError::IoError {
+                    message: "curl failed to download file".to_string(),
+                    error: format!("Exit code: {}", code.unwrap_or(-1)),
+                })
+            }
+        }
+        if verbose && response.redirects > 0 {
+            println!("Followed {} redirect(s)", response.redirects);
+        }
+        if checks.update && response.status == Some(304) {
             if verbose {
-                println!("✓ Successfully downloaded file");
+                println!("Not modified: {}", dst_path.display());
             }
-            return Ok(());
-        } else {
-            return Err(RemoteCopyError::IoError {
-                message: "curl failed to download file".to_string(),
-                error: format!("Exit code: {}", status.code().unwrap_or(-1)),
-            });
+            return Ok(Download::NotModified);
+        }
+        check_response(&url, &response, dst_path, checks)?;
+        if checks.update {
+            http::store_etag(dst_path, response.etag.as_deref());
+        }
+        if verbose {
+            println!("✓ Successfully downloaded file");
         }
+        return Ok(Download::Fetched);
     }
 
-    if let Ok(mut cmd) = try_wget(&url, dst_path, verbose, progress) {
-        let status = cmd.status().map_err(|e| RemoteCopyError::IoError {
+    if checks.expect_content_type.is_some() {
+        return Err(RemoteCopyError::IoError {
+            message: "--expect-content-type needs curl".to_string(),
+            error: "curl was not found in PATH".to_string(),
+        });
+    }
+    if let Ok(mut cmd) = try_wget(&url, dst_path, verbose, progress, compress) {
+        if let Some(max) = checks.max_redirects {
+            cmd.arg(format!("--max-redirect={}", max));
+        }
+        let status = if progress {
+            progress::run(
+                &mut cmd,
+                progress::Tool::Wget,
+                progress::Transfer {
+                    dst: Some(dst_path),
+                    size: None,
+                },
+            )
+            .map(|o| o.status)
+        } else {
+            cmd.status()
+        }
+        .map_err(|e| RemoteCopyError::IoError {
             message: "Failed to execute wget".to_string(),
             error: e.to_string(),
         })?;
@@ -303,7 +1400,7 @@ pub fn copy_from_http_to_file(
             if verbose {
                 println!("✓ Successfully downloaded file");
             }
-            return Ok(());
+            return Ok(Download::Fetched);
         } else {
             return Err(RemoteCopyError::IoError {
                 message: "wget failed to download file".to_string(),
@@ -318,7 +1415,36 @@ pub fn copy_from_http_to_file(
     })
 }
 
-fn try_curl(url: &str, dst_path: &Path, verbose: bool, progress: bool) -> Result<Command, ()> {
+/// Apply `checks` to the download of `url` saved at `dst_path`, removing it if it
+/// fails them.
+fn check_response(
+    url: &str,
+    response: &Response,
+    dst_path: &Path,
+    checks: &HttpChecks,
+) -> Result<(), RemoteCopyError> {
+    match checks.check(url, response, dst_path) {
+        Ok(warning) => {
+            if let Some(warning) = warning {
+                output::warn(warning);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            // Don't leave the wrong file where the right one is expected.
+            let _ = std::fs::remove_file(dst_path);
+            Err(e)
+        }
+    }
+}
+
+fn try_curl(
+    url: &str,
+    dst_path: &Path,
+    verbose: bool,
+    progress: bool,
+    compress: bool,
+) -> Result<Command, ()> {
     let check = Command::new("curl").arg("--version").output();
 
     if check.is_err() {
@@ -328,6 +1454,10 @@ fn try_curl(url: &str, dst_path: &Path, verbose: bool, progress: bool) -> Result
     let mut cmd = Command::new("curl");
     cmd.arg("-L").arg("-f").arg("-o").arg(dst_path).arg(url);
 
+    if compress {
+        cmd.arg("--compressed");
+    }
+
     if progress {
         cmd.arg("--progress-bar");
     } else if !verbose {
@@ -337,7 +1467,13 @@ fn try_curl(url: &str, dst_path: &Path, verbose: bool, progress: bool) -> Result
     Ok(cmd)
 }
 
-fn try_wget(url: &str, dst_path: &Path, verbose: bool, progress: bool) -> Result<Command, ()> {
+fn try_wget(
+    url: &str,
+    dst_path: &Path,
+    verbose: bool,
+    progress: bool,
+    compress: bool,
+) -> Result<Command, ()> {
     let check = Command::new("wget").arg("--version").output();
 
     if check.is_err() {
@@ -347,6 +1483,10 @@ fn try_wget(url: &str, dst_path: &Path, verbose: bool, progress: bool) -> Result
     let mut cmd = Command::new("wget");
     cmd.arg("-O").arg(dst_path).arg(url);
 
+    if compress {
+        cmd.arg("--compression=auto");
+    }
+
     if progress {
         cmd.arg("--progress=bar");
     } else if !verbose {
@@ -363,10 +1503,11 @@ pub fn copy_from_s3_to_file(
     verbose: bool,
     progress: bool,
 ) -> Result<(), RemoteCopyError> {
-    let s3_url = src.url.to_string();
+    let s3_url = src.s3_url();
 
-    // Check if URL contains wildcards - if so, use sync instead
-    let has_wildcard = s3_url.contains('*') || s3_url.contains('?');
+    // Check if the key contains wildcards - if so, use sync instead. `?` is an ordinary
+    // key character.
+    let has_wildcard = src.path.contains('*');
 
     // Check if URL ends with / (directory) - use sync for directories
     let is_directory = s3_url.ends_with('/') || src.path.ends_with('/');
@@ -389,9 +1530,19 @@ pub fn copy_from_s3_to_file(
 
     // Try AWS CLI first
     if let Ok(mut cmd) = try_aws_cli(&s3_url, Some(dst_path), None, verbose, progress, true) {
-        let output = cmd.output().map_err(|e| RemoteCopyError::IoError {
-            message: "Failed to execute aws s3 cp".to_string(),
-            error: e.to_string(),
+        let transfer = progress::Transfer {
+            dst: Some(dst_path),
+            size: if progress {
+                remote_size(src, &[]).ok().flatten()
+            } else {
+                None
+            },
+        };
+        let output = run_tool(&mut cmd, progress::Tool::Aws, progress, transfer).map_err(|e| {
+            RemoteCopyError::IoError {
+                message: "Failed to execute aws s3 cp".to_string(),
+                error: e.to_string(),
+            }
         })?;
 
         if output.status.success() {
@@ -453,7 +1604,7 @@ pub fn copy_file_to_s3(
     verbose: bool,
     progress: bool,
 ) -> Result<(), RemoteCopyError> {
-    let s3_url = dst.url.to_string();
+    let s3_url = dst.s3_url();
 
     if verbose {
         println!("Copying from {} to S3: {}", src_path.display(), s3_url);
@@ -461,9 +1612,15 @@ pub fn copy_file_to_s3(
 
     // Try AWS CLI first
     if let Ok(mut cmd) = try_aws_cli(&s3_url, Some(src_path), None, verbose, progress, false) {
-        let output = cmd.output().map_err(|e| RemoteCopyError::IoError {
-            message: "Failed to execute aws s3 cp".to_string(),
-            error: e.to_string(),
+        let transfer = progress::Transfer {
+            dst: None,
+            size: std::fs::metadata(src_path).ok().map(|m| m.len()),
+        };
+        let output = run_tool(&mut cmd, progress::Tool::Aws, progress, transfer).map_err(|e| {
+            RemoteCopyError::IoError {
+                message: "Failed to execute aws s3 cp".to_string(),
+                error: e.to_string(),
+            }
         })?;
 
         if output.status.success() {
@@ -516,14 +1673,16 @@ pub fn copy_file_to_s3(
     }
 }
 
-/// Copy directory to S3 using AWS CLI sync, with SDK fallback
+/// Copy directory to S3 using AWS CLI sync, with SDK fallback. `size_only` makes sync
+/// skip objects of the same size whatever their times (`--compare size-only`).
 pub fn copy_directory_to_s3(
     src_path: &Path,
     dst: &RemotePath,
     verbose: bool,
     progress: bool,
+    size_only: bool,
 ) -> Result<(), RemoteCopyError> {
-    let s3_url = dst.url.to_string();
+    let s3_url = dst.s3_url();
 
     if verbose {
         println!("Syncing directory {} to S3: {}", src_path.display(), s3_url);
@@ -531,7 +1690,16 @@ pub fn copy_directory_to_s3(
 
     // Try AWS CLI sync first
     if let Ok(mut cmd) = try_aws_cli_sync(src_path, &s3_url, verbose, progress) {
-        let output = cmd.output().map_err(|e| RemoteCopyError::IoError {
+        if size_only {
+            cmd.arg("--size-only");
+        }
+        let output = run_tool(
+            &mut cmd,
+            progress::Tool::Aws,
+            progress,
+            progress::Transfer::default(),
+        )
+        .map_err(|e| RemoteCopyError::IoError {
             message: "Failed to execute aws s3 sync".to_string(),
             error: e.to_string(),
         })?;
@@ -586,6 +1754,71 @@ pub fn copy_directory_to_s3(
     }
 }
 
+/// Size, ETag and SHA-256 checksum of a single S3 object, as
+/// `<ContentLength>\t<ETag>\t<ChecksumSHA256>`. The ETag keeps its quotes and a missing
+/// checksum prints as `None`. CLIs too old for `--checksum-mode` are asked again without it.
+pub fn s3_head_object(src: &RemotePath) -> Result<String, RemoteCopyError> {
+    let bucket = src.url.host_str().ok_or_else(|| {
+        RemoteCopyError::ConnectionError("No bucket specified in S3 URL".to_string())
+    })?;
+    let head = |checksum_mode: bool| {
+        let mut cmd = Command::new("aws");
+        cmd.arg("s3api").arg("head-object");
+        aws_env_args(&mut cmd, None);
+        cmd.arg("--bucket")
+            .arg(bucket)
+            .arg("--key")
+            .arg(src.path.trim_start_matches('/'));
+        if checksum_mode {
+            cmd.arg("--checksum-mode").arg("ENABLED");
+        }
+        cmd.arg("--query")
+            .arg("[ContentLength,ETag,ChecksumSHA256]")
+            .arg("--output")
+            .arg("text");
+        command_output(cmd, "aws s3api head-object")
+    };
+    head(true).or_else(|_| head(false))
+}
+
+/// Response headers of a `HEAD` request for `src`, following redirects.
+pub fn http_head(src: &RemotePath) -> Result<String, RemoteCopyError> {
+    let mut cmd = Command::new("curl");
+    cmd.arg("-sSfIL").arg(src.url.as_str());
+    command_output(cmd, "curl")
+}
+
+/// Body of a `GET` for `src`, following redirects: a page to read, such as a directory
+/// index, rather than a file to save.
+pub fn http_get(src: &RemotePath) -> Result<String, RemoteCopyError> {
+    let mut cmd = Command::new("curl");
+    cmd.arg("-sSfL").arg(src.url.as_str());
+    command_output(cmd, "curl")
+}
+
+/// Profile, region and endpoint options for an `aws` call, taken from the environment
+/// unless a profile is given explicitly.
+fn aws_env_args(cmd: &mut Command, profile: Option<&str>) {
+    // Add profile if specified
+    if let Some(prof) = profile {
+        cmd.arg("--profile").arg(prof);
+    } else if let Ok(prof) = std::env::var("AWS_PROFILE") {
+        cmd.arg("--profile").arg(&prof);
+    }
+
+    // Add region if specified
+    if let Ok(region) = std::env::var("AWS_REGION") {
+        cmd.arg("--region").arg(&region);
+    }
+
+    // Add endpoint URL if specified (for MinIO and S3-compatible services)
+    if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL_S3") {
+        cmd.arg("--endpoint-url").arg(&endpoint);
+    } else if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL") {
+        cmd.arg("--endpoint-url").arg(&endpoint);
+    }
+}
+
 fn try_aws_cli(
     s3_url: &str,
     local_path: Option<&Path>,
@@ -603,7 +1836,7 @@ fn try_aws_cli(
     let mut cmd = Command::new("aws");
 
     // Check if S3 URL contains wildcards - use sync for wildcards, cp for single files
-    let has_wildcard = s3_url.contains('*') || s3_url.contains('?');
+    let has_wildcard = s3_url.contains('*');
 
     if has_wildcard && is_download {
         // For wildcards, use sync instead of cp
@@ -612,24 +1845,7 @@ fn try_aws_cli(
         cmd.arg("s3").arg("cp");
     }
 
-    // Add profile if specified
-    if let Some(prof) = profile {
-        cmd.arg("--profile").arg(prof);
-    } else if let Ok(prof) = std::env::var("AWS_PROFILE") {
-        cmd.arg("--profile").arg(&prof);
-    }
-
-    // Add region if specified
-    if let Ok(region) = std::env::var("AWS_REGION") {
-        cmd.arg("--region").arg(&region);
-    }
-
-    // Add endpoint URL if specified (for MinIO and S3-compatible services)
-    if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL_S3") {
-        cmd.arg("--endpoint-url").arg(&endpoint);
-    } else if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL") {
-        cmd.arg("--endpoint-url").arg(&endpoint);
-    }
+    aws_env_args(&mut cmd, profile);
 
     if progress {
         // AWS CLI shows progress by default, but we can make it more verbose
@@ -681,22 +1897,7 @@ fn try_aws_cli_sync(
     let mut cmd = Command::new("aws");
     cmd.arg("s3").arg("sync");
 
-    // Add profile if specified
-    if let Ok(prof) = std::env::var("AWS_PROFILE") {
-        cmd.arg("--profile").arg(&prof);
-    }
-
-    // Add region if specified
-    if let Ok(region) = std::env::var("AWS_REGION") {
-        cmd.arg("--region").arg(&region);
-    }
-
-    // Add endpoint URL if specified (for MinIO and S3-compatible services)
-    if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL_S3") {
-        cmd.arg("--endpoint-url").arg(&endpoint);
-    } else if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL") {
-        cmd.arg("--endpoint-url").arg(&endpoint);
-    }
+    aws_env_args(&mut cmd, None);
 
     if progress {
         // AWS CLI shows progress by default
@@ -783,7 +1984,13 @@ fn copy_from_s3_with_wildcard(
         // For download: s3://bucket/path/* -> local_dir
         sync_cmd.arg(s3_url).arg(&dst_dir);
 
-        let output = sync_cmd.output().map_err(|e| RemoteCopyError::IoError {
+        let output = run_tool(
+            &mut sync_cmd,
+            progress::Tool::Aws,
+            progress,
+            progress::Transfer::default(),
+        )
+        .map_err(|e| RemoteCopyError::IoError {
             message: "Failed to execute aws s3 sync".to_string(),
             error: e.to_string(),
         })?;
@@ -879,3 +2086,287 @@ fn copy_directory_to_s3_sdk(
         "S3 SDK fallback is not yet fully implemented. Please install AWS CLI.".to_string(),
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{parse_path, Path as ProtocolPath};
+
+    fn remote(path: &str) -> RemotePath {
+        match parse_path(path) {
+            Ok(ProtocolPath::Remote(r)) => r,
+            other => panic!("Expected remote path, got {:?}", other),
+        }
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_scp_command_forwards_ssh_opts() {
+        let opts = vec![
+            "IdentityFile=~/.ssh/id_ed25519".to_string(),
+            "StrictHostKeyChecking=no".to_string(),
+        ];
+        let cmd = scp_command(2222, false, false, &opts);
+
+        assert_eq!(cmd.get_program(), "scp");
+        assert_eq!(
+            args(&cmd),
+            vec![
+                "-P",
+                "2222",
+                "-q",
+                "-o",
+                "IdentityFile=~/.ssh/id_ed25519",
+                "-o",
+                "StrictHostKeyChecking=no"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scp_progress_keeps_its_meter() {
+        // No -q (which hides the meter) and no -v (debug output over the prompts).
+        assert!(args(&scp_command(22, false, true, &[])).is_empty());
+        assert!(args(&scp_command(22, true, false, &[])).is_empty());
+    }
+
+    #[test]
+    fn test_auth_failures() {
+        #[cfg(unix)]
+        let output = |code: i32, stderr: &str| {
+            use std::os::unix::process::ExitStatusExt;
+            std::process::Output {
+                status: std::process::ExitStatus::from_raw(code << 8),
+                stdout: Vec::new(),
+                stderr: stderr.as_bytes().to_vec(),
+            }
+        };
+        #[cfg(windows)]
+        let output = |code: i32, stderr: &str| {
+            use std::os::windows::process::ExitStatusExt;
+            std::process::Output {
+                status: std::process::ExitStatus::from_raw(code as u32),
+                stdout: Vec::new(),
+                stderr: stderr.as_bytes().to_vec(),
+            }
+        };
+
+        let denied = output(
+            255,
+            "Warning: Permanently added 'nas' to the list of known hosts.\r\n\
+             me@nas: Permission denied (publickey,password).\r\n",
+        );
+        match transfer_error(&denied, "scp failed to copy file") {
+            RemoteCopyError::AuthenticationError(msg) => {
+                assert!(msg.starts_with("me@nas: Permission denied (publickey,password)."));
+                assert!(msg.contains("--ssh-password-file"));
+            }
+            other => panic!("expected an authentication error, got {:?}", other),
+        }
+        assert!(auth_failed(
+            "Received disconnect from 10.0.0.2: Too many authentication failures"
+        ));
+
+        // The remote file was unreadable: the login itself worked.
+        let unreadable = output(1, "scp: /root/secret: Permission denied\n");
+        assert!(matches!(
+            transfer_error(&unreadable, "scp failed to copy file"),
+            RemoteCopyError::IoError { error, .. } if error == "scp: /root/secret: Permission denied"
+        ));
+        assert!(matches!(
+            transfer_error(&output(1, ""), "scp failed to copy file"),
+            RemoteCopyError::IoError { error, .. } if error == "Exit code: 1"
+        ));
+    }
+
+    #[test]
+    fn test_askpass_reply() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let file = temp.path().join("password");
+        std::fs::write(&file, "s3cret pass\nignored\n").unwrap();
+
+        assert_eq!(
+            askpass_reply("me@nas's password: ", &file).unwrap(),
+            Some("s3cret pass".to_string())
+        );
+        assert_eq!(
+            askpass_reply("(me@nas) Password: ", &file).unwrap(),
+            Some("s3cret pass".to_string())
+        );
+        assert_eq!(
+            askpass_reply(
+                "Are you sure you want to continue connecting (yes/no/[fingerprint])? ",
+                &file
+            )
+            .unwrap(),
+            None
+        );
+        assert!(askpass_reply("Password: ", &temp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_ssh_command_forwards_ssh_opts() {
+        let opts = vec!["IdentityFile=~/.ssh/id_ed25519".to_string()];
+        let cmd = ssh_command(&remote("ssh://user@host:2222/data"), &opts).unwrap();
+
+        assert_eq!(cmd.get_program(), "ssh");
+        assert_eq!(
+            args(&cmd),
+            vec![
+                "-p",
+                "2222",
+                "-o",
+                "IdentityFile=~/.ssh/id_ed25519",
+                "user@host"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_multiplex_appends_control_opts() {
+        let target = remote("ssh://user@host/data");
+        let user_opts = vec!["IdentityFile=~/.ssh/id_ed25519".to_string()];
+        let mux = SshMultiplex::start(&[&target], &user_opts, false).unwrap();
+        let opts = mux.ssh_opts();
+
+        assert_eq!(opts[0], "IdentityFile=~/.ssh/id_ed25519");
+        assert!(opts.contains(&"ControlMaster=auto".to_string()));
+        assert!(opts.contains(&"ControlPersist=60".to_string()));
+        assert!(opts
+            .iter()
+            .any(|o| o.starts_with("ControlPath=") && o.ends_with("/%C")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_multiplex_counts_shared_sessions() {
+        let target = remote("ssh://user@host/data");
+        let mux = SshMultiplex::start(&[&target], &[], false).unwrap();
+        let opts = mux.ssh_opts();
+        for _ in 0..3 {
+            scp_command(22, false, false, &opts);
+        }
+        ssh_command(&target, &opts).unwrap();
+        // Without the multiplex's options, a session isn't its.
+        scp_command(22, false, false, &[]);
+
+        assert_eq!(mux.sessions(), 4);
+        let summary = mux.summary();
+        assert!(
+            summary.starts_with("SSH multiplexing: 4 ssh/scp session(s) over 1 connection(s)"),
+            "{}",
+            summary
+        );
+        assert!(summary.ends_with("3 handshake(s) saved"), "{}", summary);
+    }
+
+    #[test]
+    fn test_compress_reaches_ssh_and_scp() {
+        let opts = crate::copy::CopyOptions {
+            ssh_opts: vec!["IdentityFile=~/.ssh/id_ed25519".to_string()],
+            compress: true,
+            ..Default::default()
+        };
+        let ssh_opts = opts.transit_ssh_opts();
+        let scp = args(&scp_command(22, false, false, &ssh_opts));
+        assert_eq!(
+            scp,
+            vec![
+                "-q",
+                "-o",
+                "IdentityFile=~/.ssh/id_ed25519",
+                "-o",
+                "Compression=yes"
+            ]
+        );
+        let ssh = args(&ssh_command(&remote("ssh://user@host/data"), &ssh_opts).unwrap());
+        assert!(ssh.windows(2).any(|pair| pair == ["-o", "Compression=yes"]));
+
+        let plain = crate::copy::CopyOptions {
+            compress: false,
+            ..opts
+        };
+        assert!(!plain
+            .transit_ssh_opts()
+            .contains(&"Compression=yes".to_string()));
+    }
+
+    #[test]
+    fn test_compress_asks_curl_and_tar_for_it() {
+        let src = remote("https://example.com/data.json");
+        assert!(args(&http_read_command(&src, true)).contains(&"--compressed".to_string()));
+        assert!(!args(&http_read_command(&src, false)).contains(&"--compressed".to_string()));
+        if let Ok(cmd) = try_curl(src.url.as_str(), Path::new("data.json"), false, false, true) {
+            assert!(args(&cmd).contains(&"--compressed".to_string()));
+        }
+
+        assert_eq!(tar_flags(true), ("-czf", "-xzf"));
+        assert_eq!(tar_flags(false), ("-cf", "-xf"));
+    }
+
+    #[test]
+    fn test_scp_spec() {
+        assert_eq!(
+            scp_spec(&remote("ssh://deploy@web01/var/www/site")).unwrap(),
+            "deploy@web01:/var/www/site"
+        );
+        assert_eq!(
+            scp_spec(&remote("ssh://deploy@web02:2222/var/www/site")).unwrap(),
+            "scp://deploy@web02:2222//var/www/site"
+        );
+        assert_eq!(scp_spec(&remote("ssh://web03/srv")).unwrap(), "web03:/srv");
+        assert_eq!(
+            scp_spec(&remote("user@[2001:db8::1]:/data/file")).unwrap(),
+            "user@[2001:db8::1]:/data/file"
+        );
+        assert_eq!(
+            scp_spec(&remote("ssh://user@[2001:db8::1]:2222/data")).unwrap(),
+            "scp://user@[2001:db8::1]:2222//data"
+        );
+    }
+
+    #[test]
+    fn test_ssh_command_ipv6_host() {
+        let cmd = ssh_command(&remote("user@[2001:db8::1]:/data/file"), &[]).unwrap();
+        assert_eq!(args(&cmd), vec!["user@2001:db8::1"]);
+
+        let mut port = remote("user@[2001:db8::1]:/data/file");
+        port.set_default_port(2222);
+        let cmd = ssh_command(&port, &[]).unwrap();
+        assert_eq!(args(&cmd), vec!["-p", "2222", "user@2001:db8::1"]);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/var/www"), "'/var/www'");
+        assert_eq!(shell_quote("it's here"), "'it'\\''s here'");
+    }
+
+    #[test]
+    fn test_remote_join() {
+        let rel = Path::new("a").join("b.txt");
+        assert_eq!(remote_join("/srv/site/", &rel), "/srv/site/a/b.txt");
+        assert_eq!(remote_join("/srv/site", &rel), "/srv/site/a/b.txt");
+    }
+
+    #[test]
+    fn test_ssh_command_default_port() {
+        let cmd = ssh_command(&remote("user@host:/data/file"), &[]).unwrap();
+        assert_eq!(args(&cmd), vec!["user@host"]);
+    }
+
+    #[test]
+    fn test_remote_is_dir_without_asking() {
+        // Decided from the path alone, so no aws or network is needed.
+        assert!(remote_is_dir(&remote("s3://bucket/logs/2024/"), &[]).unwrap());
+        assert!(remote_is_dir(&remote("s3://bucket"), &[]).unwrap());
+        assert!(!remote_is_dir(&remote("s3://bucket/logs/*.gz"), &[]).unwrap());
+        assert!(!remote_is_dir(&remote("https://example.com/files/"), &[]).unwrap());
+    }
+}
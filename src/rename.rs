@@ -0,0 +1,240 @@
+//! `--rename`: destination file names made from a template.
+//!
+//! A template is a name with placeholders in braces: `{name}` is the name the file would
+//! otherwise get, `{stem}` and `{ext}` are that name without and only its last
+//! extension, `{date}` is the day the copy runs (`2024-03-01`), `{mtime}` the source's
+//! modification time (`20240301-120000`), `{size}` its size in bytes and `{counter}`
+//! counts the files renamed by the copy from 1. Times are UTC. `{{` and `}}` are literal
+//! braces, and a `.` just before `{ext}` is left out for a name without an extension.
+//!
+//! The template is checked when it is parsed, so a typo fails before anything is
+//! copied. Two files that end up with the same name are then handled by the
+//! `--case-collisions` policy.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::list;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Name,
+    Stem,
+    Ext,
+    Date,
+    Mtime,
+    Size,
+    Counter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// A parsed `--rename` template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse `spec`, refusing unknown placeholders, unbalanced braces and separators.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if spec.is_empty() {
+            return Err("the template is empty".to_string());
+        }
+        if spec.contains(['/', '\\']) {
+            return Err(format!(
+                "invalid template {:?}: it names a file, without directories",
+                spec
+            ));
+        }
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        return Err(format!("invalid template {:?}: unclosed {{", spec));
+                    };
+                    let field = match &rest[..end] {
+                        "name" => Field::Name,
+                        "stem" => Field::Stem,
+                        "ext" => Field::Ext,
+                        "date" => Field::Date,
+                        "mtime" => Field::Mtime,
+                        "size" => Field::Size,
+                        "counter" => Field::Counter,
+                        other => {
+                            return Err(format!(
+                                "invalid template {:?}: unknown placeholder {{{}}}; use {{name}}, \
+                                 {{stem}}, {{ext}}, {{date}}, {{mtime}}, {{size}} or {{counter}}",
+                                spec, other
+                            ))
+                        }
+                    };
+                    chars = rest[end + 1..].chars();
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err(format!("invalid template {:?}: unmatched }}", spec)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Whether the template reads the source file (`{mtime}`, `{size}`), which only a
+    /// local source has before the copy.
+    pub fn reads_source(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, Part::Field(Field::Mtime | Field::Size)))
+    }
+
+    /// The name for a file that would be called `name`, from `metadata` of its source
+    /// and the `counter`-th file renamed.
+    pub fn render(
+        &self,
+        name: &OsStr,
+        metadata: Option<&fs::Metadata>,
+        counter: u64,
+    ) -> Result<OsString, String> {
+        let path = Path::new(name);
+        let ext = path.extension();
+        let mut rendered = OsString::new();
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Text(text) => match self.parts.get(i + 1) {
+                    Some(Part::Field(Field::Ext)) if ext.is_none() => {
+                        rendered.push(text.strip_suffix('.').unwrap_or(text))
+                    }
+                    _ => rendered.push(text),
+                },
+                Part::Field(Field::Name) => rendered.push(name),
+                Part::Field(Field::Stem) => rendered.push(path.file_stem().unwrap_or(name)),
+                Part::Field(Field::Ext) => rendered.push(ext.unwrap_or_default()),
+                Part::Field(Field::Date) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok();
+                    let time = list::format_time(now.map(|d| d.as_secs() as i64));
+                    rendered.push(&time[..10]);
+                }
+                Part::Field(Field::Mtime) => {
+                    let time = metadata
+                        .and_then(|m| m.modified().ok())
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .ok_or_else(|| format!("{} has no modification time", path.display()))?;
+                    let time = list::format_time(Some(time.as_secs() as i64));
+                    let digits =
+                        |range: std::ops::Range<usize>| time[range].replace([':', '-'], "");
+                    rendered.push(format!("{}-{}", digits(0..10), digits(11..19)));
+                }
+                Part::Field(Field::Size) => {
+                    let size = metadata
+                        .map(|m| m.len())
+                        .ok_or_else(|| format!("the size of {} isn't known", path.display()))?;
+                    rendered.push(size.to_string());
+                }
+                Part::Field(Field::Counter) => rendered.push(counter.to_string()),
+            }
+        }
+        if rendered.is_empty() || rendered == "." || rendered == ".." {
+            return Err(format!(
+                "--rename leaves {} without a usable name ({:?})",
+                path.display(),
+                rendered
+            ));
+        }
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn render(spec: &str, name: &str) -> String {
+        let template = Template::parse(spec).unwrap();
+        let rendered = template.render(OsStr::new(name), None, 7).unwrap();
+        rendered.into_string().unwrap()
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_templates() {
+        for (spec, error) in [
+            ("", "empty"),
+            ("{nme}.txt", "unknown placeholder {nme}"),
+            ("{name", "unclosed {"),
+            ("name}", "unmatched }"),
+            ("out/{name}", "without directories"),
+        ] {
+            let e = Template::parse(spec).unwrap_err();
+            assert!(e.contains(error), "{:?}: {}", spec, e);
+        }
+    }
+
+    #[test]
+    fn test_render_names() {
+        assert_eq!(
+            render("{stem}-{counter}.{ext}", "report.csv"),
+            "report-7.csv"
+        );
+        assert_eq!(render("{stem}-{counter}.{ext}", "README"), "README-7");
+        assert_eq!(render("{stem}.{ext}", "a.tar.gz"), "a.tar.gz");
+        assert_eq!(render("old_{name}", ".bashrc"), "old_.bashrc");
+        assert_eq!(render("{{{name}}}", "x"), "{x}");
+
+        let date = render("export-{date}.csv", "export.csv");
+        assert_eq!(date.len(), "export-2024-03-01.csv".len());
+        assert!(date.starts_with("export-20") && date.ends_with(".csv"));
+
+        assert!(Template::parse("{ext}")
+            .unwrap()
+            .render(OsStr::new("Makefile"), None, 1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_render_from_metadata() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("data.bin");
+        fs::write(&file, "12345").unwrap();
+        let march = UNIX_EPOCH + std::time::Duration::from_secs(1_709_294_400);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(march)
+            .unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+
+        let template = Template::parse("{stem}_{mtime}_{size}.{ext}").unwrap();
+        assert!(template.reads_source());
+        assert!(!Template::parse("{name}-{date}").unwrap().reads_source());
+        let rendered = template
+            .render(OsStr::new("data.bin"), Some(&metadata), 1)
+            .unwrap();
+        assert_eq!(rendered, "data_20240301-120000_5.bin");
+        assert!(template.render(OsStr::new("data.bin"), None, 1).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+//! What a copy did, as one [`TransferReport`] whichever way it ran.
+//!
+//! `Display` gives the summary `-v`/`-p` print; with the `serde` feature the report
+//! also serializes, e.g. to JSON for a service that hands it back to its caller.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::copy::{self, CopyStats};
+use crate::limit::Exceeded;
+use crate::verify::Outcome;
+
+/// The kind of transfer a report describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Operation {
+    Copy,
+    /// A copy that skipped files already matching at the destination (`--compare`), or
+    /// removed those the source doesn't have (`--delete`).
+    Sync,
+    /// A copy that removed its source afterwards (`--move`).
+    Move,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Copy => "Copy",
+            Operation::Sync => "Sync",
+            Operation::Move => "Move",
+        }
+    }
+}
+
+/// A problem with one file of a transfer that didn't stop the rest of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// How long one file of a transfer took (`--stats-detail`).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileTiming {
+    pub path: String,
+    pub bytes: u64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "seconds", serialize_with = "seconds")
+    )]
+    pub duration: Duration,
+}
+
+impl FileTiming {
+    /// Bytes copied per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The slowest files of a transfer, at most `limit` of them. A min-heap keeps the
+/// fastest of those on top, so each new file is compared with it alone.
+#[derive(Debug, Clone, Default)]
+pub struct SlowFiles {
+    limit: usize,
+    heap: BinaryHeap<Reverse<ByDuration>>,
+}
+
+/// Orders by duration; of two equally slow files, the one whose path sorts first is
+/// the slower, so which are kept doesn't depend on the order they finished in.
+#[derive(Debug, Clone)]
+struct ByDuration(FileTiming);
+
+impl PartialEq for ByDuration {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ByDuration {}
+
+impl Ord for ByDuration {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.duration, &other.0.path).cmp(&(other.0.duration, &self.0.path))
+    }
+}
+
+impl PartialOrd for ByDuration {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl SlowFiles {
+    /// Keep the `limit` slowest files; 0 keeps none.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            heap: BinaryHeap::with_capacity(limit),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Note that `path` took `duration` for `bytes`, if that is among the slowest.
+    pub fn record(&mut self, path: &Path, bytes: u64, duration: Duration) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.heap.len() == self.limit
+            && self
+                .heap
+                .peek()
+                .is_some_and(|Reverse(fastest)| fastest.0.duration >= duration)
+        {
+            return;
+        }
+        self.push(FileTiming {
+            path: path.display().to_string(),
+            bytes,
+            duration,
+        });
+    }
+
+    /// Take in the files `other` kept, e.g. from a thread that copied a subdirectory.
+    pub fn merge(&mut self, other: SlowFiles) {
+        for Reverse(ByDuration(timing)) in other.heap {
+            self.push(timing);
+        }
+    }
+
+    fn push(&mut self, timing: FileTiming) {
+        if self.limit == 0 {
+            return;
+        }
+        self.heap.push(Reverse(ByDuration(timing)));
+        if self.heap.len() > self.limit {
+            self.heap.pop();
+        }
+    }
+
+    /// The files kept, slowest first.
+    pub fn sorted(&self) -> Vec<FileTiming> {
+        let mut files: Vec<_> = self
+            .heap
+            .iter()
+            .map(|Reverse(ByDuration(timing))| timing.clone())
+            .collect();
+        files.sort_by(|a, b| {
+            b.duration
+                .cmp(&a.duration)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        files
+    }
+}
+
+/// Files and bytes a transfer copied, skipped, removed and failed on.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TransferReport {
+    pub operation: Operation,
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+    /// Files left alone because they already matched at the destination.
+    pub files_skipped: usize,
+    pub bytes_skipped: u64,
+    /// Files and directories the filters left out (a directory counts once).
+    pub files_excluded: usize,
+    /// Source files removed by a move, and destination files removed by `--delete`.
+    pub files_deleted: usize,
+    pub bytes_deleted: u64,
+    /// Empty destination directories removed by `--prune-empty-dirs`.
+    pub dirs_pruned: usize,
+    /// Destination files moved instead of copied again (`--detect-renames`).
+    pub files_renamed: usize,
+    /// Files with an entry in `errors`.
+    pub files_failed: usize,
+    /// Files hard-linked (within the tree or from `--link-dest`) instead of copied.
+    pub files_linked: usize,
+    /// Copied files of which only the new tail was written (`--append`).
+    pub files_appended: usize,
+    /// Files that disappeared between listing and copying.
+    pub files_vanished: usize,
+    /// FIFOs, sockets and device nodes skipped (no `--specials`/`--devices`).
+    pub specials_skipped: usize,
+    /// Names that collided case-insensitively with an earlier one (`--case-collisions`).
+    pub case_collisions: usize,
+    /// Directories skipped because a symlink led back to one already copied.
+    pub symlink_loops: usize,
+    /// Names changed for the destination filesystem (`--sanitize-names`).
+    pub names_sanitized: usize,
+    /// Entries of a `--files-from` list gone through.
+    pub files_listed: usize,
+    /// Listed entries the source doesn't have (`--ignore-missing-args`).
+    pub files_missing: usize,
+    /// Files whose extended attributes or ACLs could not all be copied (`--xattrs`,
+    /// `--acls`).
+    pub files_xattrs_incomplete: usize,
+    /// Bytes of `--delta` updates reused from the existing destination.
+    pub delta_matched: u64,
+    /// Bytes of `--delta` updates that had to be sent.
+    pub delta_literal: u64,
+    pub dirs_created: usize,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "seconds", serialize_with = "seconds")
+    )]
+    pub duration: Duration,
+    /// Bytes copied per second.
+    pub throughput: f64,
+    /// The `--checksum` verification: a match, or a check best-effort mode let pass.
+    pub verification: Option<Outcome>,
+    pub errors: Vec<FileError>,
+    /// The `--max-files`/`--max-total-size`/`--max-deletes` limit the transfer stopped at.
+    pub limit_reached: Option<Exceeded>,
+    /// The slowest files, slowest first, with `--stats-detail`.
+    pub slowest_files: Vec<FileTiming>,
+}
+
+impl TransferReport {
+    /// The report for `stats`, timed from their start until now.
+    pub fn from_stats(operation: Operation, stats: &CopyStats) -> Self {
+        let duration = stats.start_time.map(|t| t.elapsed()).unwrap_or_default();
+        let mut report = Self {
+            operation,
+            files_copied: stats.files_copied,
+            bytes_copied: stats.bytes_copied,
+            files_skipped: stats.files_skipped,
+            bytes_skipped: stats.bytes_skipped,
+            files_excluded: stats.files_excluded,
+            files_deleted: stats.files_deleted,
+            bytes_deleted: stats.bytes_deleted,
+            dirs_pruned: stats.dirs_pruned,
+            files_renamed: stats.files_renamed,
+            files_failed: stats.errors.len(),
+            files_linked: stats.files_linked,
+            files_appended: stats.files_appended,
+            files_vanished: stats.files_vanished,
+            specials_skipped: stats.specials_skipped,
+            case_collisions: stats.case_collisions,
+            symlink_loops: stats.symlink_loops,
+            names_sanitized: stats.names_sanitized,
+            files_listed: stats.files_listed,
+            files_missing: stats.files_missing,
+            files_xattrs_incomplete: stats.files_xattrs_incomplete,
+            delta_matched: stats.delta_matched,
+            delta_literal: stats.delta_literal,
+            dirs_created: stats.dirs_created,
+            duration,
+            throughput: 0.0,
+            verification: None,
+            errors: stats.errors.clone(),
+            limit_reached: None,
+            slowest_files: stats.slowest.sorted(),
+        };
+        report.set_duration(duration);
+        report
+    }
+
+    /// Time the transfer took; the throughput follows.
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+        let secs = duration.as_secs_f64();
+        self.throughput = if secs > 0.0 {
+            self.bytes_copied as f64 / secs
+        } else {
+            0.0
+        };
+    }
+
+    /// Whether a move may remove its source after this transfer: not when a limit
+    /// stopped it, and otherwise as [`copy::check_move`] says.
+    pub fn check_move(&self) -> Result<(), String> {
+        if self.limit_reached.is_some() {
+            return Err("the transfer stopped at a limit".to_string());
+        }
+        copy::check_move(self.files_failed, self.verification.as_ref())
+    }
+
+    /// Record a file that failed without failing the transfer.
+    pub fn add_error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.files_failed += 1;
+        self.errors.push(FileError {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+}
+
+/// How a line of the summary reads, for coloring it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// A plain count.
+    Info,
+    /// The transfer did all it was asked to.
+    Ok,
+    /// Something was left out or only partly done, as asked or expected.
+    Warning,
+    /// Files that should have been copied weren't, or the copy didn't verify.
+    Error,
+}
+
+impl TransferReport {
+    /// The worst of the summary's lines: how the transfer went as a whole.
+    pub fn severity(&self) -> Severity {
+        // The heading is `Ok`, so that is the least it can be.
+        self.summary()
+            .into_iter()
+            .map(|(severity, _)| severity)
+            .max()
+            .unwrap_or(Severity::Ok)
+    }
+
+    /// The lines of the `-v` summary block with their severity. Counts that are zero
+    /// are left out.
+    pub fn summary(&self) -> Vec<(Severity, String)> {
+        use Severity::*;
+
+        let secs = self.duration.as_secs_f64();
+        let mut lines = vec![
+            (Ok, format!("=== {} Summary ===", self.operation.name())),
+            (Info, format!("Files copied: {}", self.files_copied)),
+            (
+                Info,
+                format!(
+                    "Bytes transferred: {} ({:.2} MB)",
+                    self.bytes_copied,
+                    mb(self.bytes_copied)
+                ),
+            ),
+        ];
+        fn count(lines: &mut Vec<(Severity, String)>, severity: Severity, label: &str, n: usize) {
+            if n > 0 {
+                lines.push((severity, format!("{}: {}", label, n)));
+            }
+        }
+        count(&mut lines, Info, "Files skipped", self.files_skipped);
+        if self.bytes_skipped > 0 {
+            lines.push((
+                Info,
+                format!(
+                    "Bytes skipped: {} ({:.2} MB)",
+                    self.bytes_skipped,
+                    mb(self.bytes_skipped)
+                ),
+            ));
+        }
+        count(
+            &mut lines,
+            Info,
+            "Files and dirs excluded",
+            self.files_excluded,
+        );
+        if self.files_deleted > 0 {
+            lines.push((
+                Info,
+                format!(
+                    "Files removed: {} ({:.2} MB)",
+                    self.files_deleted,
+                    mb(self.bytes_deleted)
+                ),
+            ));
+        }
+        count(&mut lines, Info, "Directories created", self.dirs_created);
+        count(
+            &mut lines,
+            Info,
+            "Empty directories pruned",
+            self.dirs_pruned,
+        );
+        count(&mut lines, Info, "Files renamed", self.files_renamed);
+        count(&mut lines, Info, "Files hard-linked", self.files_linked);
+        count(&mut lines, Info, "Files appended", self.files_appended);
+        count(&mut lines, Warning, "Files vanished", self.files_vanished);
+        count(
+            &mut lines,
+            Warning,
+            "Special files skipped",
+            self.specials_skipped,
+        );
+        count(&mut lines, Warning, "Case collisions", self.case_collisions);
+        count(
+            &mut lines,
+            Warning,
+            "Symlink loops skipped",
+            self.symlink_loops,
+        );
+        count(&mut lines, Info, "Names sanitized", self.names_sanitized);
+        count(&mut lines, Info, "Listed entries", self.files_listed);
+        count(
+            &mut lines,
+            Warning,
+            "Listed entries missing",
+            self.files_missing,
+        );
+        count(
+            &mut lines,
+            Warning,
+            "Files with incomplete xattrs/ACLs",
+            self.files_xattrs_incomplete,
+        );
+        if self.delta_matched + self.delta_literal > 0 {
+            lines.push((
+                Info,
+                format!(
+                    "Delta: {} bytes matched, {} bytes literal",
+                    self.delta_matched, self.delta_literal
+                ),
+            ));
+        }
+        match &self.verification {
+            Some(Outcome::Match) => lines.push((Ok, "Verification: checksums match".into())),
+            Some(Outcome::SizeMatch) => lines.push((
+                Warning,
+                "Verification: only sizes compared, and they match".into(),
+            )),
+            Some(Outcome::Mismatch(reason)) => {
+                lines.push((Error, format!("Verification: mismatch: {}", reason)))
+            }
+            Some(Outcome::Unverifiable(reason)) => {
+                lines.push((Warning, format!("Verification: not verified: {}", reason)))
+            }
+            None => {}
+        }
+        if let Some(exceeded) = &self.limit_reached {
+            let severity = if exceeded.soft { Warning } else { Error };
+            lines.push((severity, format!("Stopped: {}", exceeded)));
+        }
+        if self.files_failed > 0 {
+            lines.push((Error, format!("Files failed: {}", self.files_failed)));
+            for error in &self.errors {
+                lines.push((Error, format!("  {}", error)));
+            }
+        }
+        lines.push((Info, format!("Time taken: {:.2}s", secs)));
+        lines.push((
+            Info,
+            format!("Average speed: {:.2} MB/s", self.throughput / 1_048_576.0),
+        ));
+        lines.extend(self.slowest_lines());
+        lines
+    }
+
+    /// The `--stats-detail` lines of the summary: the slowest files with their time,
+    /// size and speed.
+    pub fn slowest_lines(&self) -> Vec<(Severity, String)> {
+        if self.slowest_files.is_empty() {
+            return Vec::new();
+        }
+        let mut lines = vec![(Severity::Info, "Slowest files:".to_string())];
+        for file in &self.slowest_files {
+            lines.push((
+                Severity::Info,
+                format!(
+                    "  {:.2}s  {:.2} MB  {:.2} MB/s  {}",
+                    file.duration.as_secs_f64(),
+                    mb(file.bytes),
+                    file.throughput() / 1_048_576.0,
+                    file.path
+                ),
+            ));
+        }
+        lines
+    }
+}
+
+fn mb(bytes: u64) -> f64 {
+    bytes as f64 / 1_048_576.0
+}
+
+/// One line (`12 files, 3.40 MB, 0.52s, 6.54 MB/s`), or with `{:#}` the `-v` summary
+/// block.
+impl fmt::Display for TransferReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        let speed = self.throughput / 1_048_576.0;
+        if !f.alternate() {
+            return write!(
+                f,
+                "{} files, {:.2} MB, {:.2}s, {:.2} MB/s",
+                self.files_copied,
+                mb(self.bytes_copied),
+                secs,
+                speed
+            );
+        }
+
+        let lines = self.summary();
+        for (i, (_, line)) in lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+fn seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> TransferReport {
+        let stats = CopyStats {
+            files_copied: 3,
+            bytes_copied: 2 * 1_048_576,
+            files_skipped: 1,
+            bytes_skipped: 10,
+            files_excluded: 4,
+            dirs_created: 2,
+            ..CopyStats::new_minimal()
+        };
+        let mut report = TransferReport::from_stats(Operation::Move, &stats);
+        report.set_duration(Duration::from_secs(2));
+        report.add_error("src", "Failed to remove file src: denied");
+        report
+    }
+
+    #[test]
+    fn test_throughput_follows_duration() {
+        let report = report();
+        assert_eq!(report.throughput, 1_048_576.0);
+        assert_eq!(report.files_failed, 1);
+        assert_eq!(report.to_string(), "3 files, 2.00 MB, 2.00s, 1.00 MB/s");
+    }
+
+    #[test]
+    fn test_summary_block() {
+        let summary = format!("{:#}", report());
+        assert!(summary.starts_with("=== Move Summary ===\nFiles copied: 3\n"));
+        assert!(summary.contains(
+            "Files skipped: 1\nBytes skipped: 10 (0.00 MB)\nFiles and dirs excluded: 4\n"
+        ));
+        assert!(summary.contains("Directories created: 2\n"));
+        assert!(summary.contains("Files failed: 1\n  src: Failed to remove file src: denied\n"));
+        assert!(!summary.contains("hard-linked"));
+        assert!(summary.ends_with("Average speed: 1.00 MB/s"));
+    }
+
+    #[test]
+    fn test_slow_files_keeps_the_slowest() {
+        let ms = Duration::from_millis;
+        let mut slowest = SlowFiles::new(3);
+        for (name, millis) in [("a", 5), ("b", 50), ("c", 1), ("d", 20), ("e", 30)] {
+            slowest.record(Path::new(name), 1000, ms(millis));
+        }
+        let names =
+            |files: Vec<FileTiming>| -> Vec<String> { files.into_iter().map(|f| f.path).collect() };
+        assert_eq!(names(slowest.sorted()), ["b", "e", "d"]);
+
+        // What another thread kept is weighed the same way.
+        let mut other = SlowFiles::new(3);
+        other.record(Path::new("f"), 1000, ms(40));
+        other.record(Path::new("g"), 1000, ms(2));
+        slowest.merge(other);
+        assert_eq!(names(slowest.sorted()), ["b", "f", "e"]);
+        assert_eq!(slowest.sorted()[0].throughput(), 20_000.0);
+
+        let mut off = SlowFiles::default();
+        off.record(Path::new("a"), 1, ms(1));
+        assert!(off.sorted().is_empty());
+    }
+
+    #[test]
+    fn test_summary_lists_slowest_files() {
+        let mut stats = CopyStats {
+            files_copied: 1,
+            bytes_copied: 2 * 1_048_576,
+            slowest: SlowFiles::new(10),
+            ..CopyStats::new_minimal()
+        };
+        stats
+            .slowest
+            .record(Path::new("big.iso"), 2 * 1_048_576, Duration::from_secs(4));
+        let timed = TransferReport::from_stats(Operation::Copy, &stats);
+        assert_eq!(timed.slowest_files.len(), 1);
+        let summary = format!("{:#}", timed);
+        assert!(
+            summary.ends_with("Slowest files:\n  4.00s  2.00 MB  0.50 MB/s  big.iso"),
+            "{}",
+            summary
+        );
+        assert!(!format!("{:#}", report()).contains("Slowest"));
+    }
+
+    #[test]
+    fn test_summary_severity() {
+        let mut report = TransferReport::from_stats(Operation::Sync, &CopyStats::new_minimal());
+        assert_eq!(report.severity(), Severity::Ok);
+        // Nothing skipped, so no line for it.
+        assert!(!format!("{:#}", report).contains("skipped"));
+
+        report.files_vanished = 1;
+        report.verification = Some(Outcome::Match);
+        assert_eq!(report.severity(), Severity::Warning);
+        let summary = report.summary();
+        assert!(summary.contains(&(Severity::Warning, "Files vanished: 1".to_string())));
+        assert!(summary.contains(&(Severity::Ok, "Verification: checksums match".to_string())));
+
+        report.verification = Some(Outcome::Mismatch("size differs: 1 vs 2 bytes".into()));
+        assert_eq!(report.severity(), Severity::Error);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        let json = serde_json::to_value(report()).unwrap();
+        assert_eq!(json["operation"], "move");
+        assert_eq!(json["seconds"], 2.0);
+        assert_eq!(json["bytes_copied"], 2 * 1_048_576);
+        assert_eq!(json["errors"][0]["path"], "src");
+        assert!(json["verification"].is_null());
+    }
+}
@@ -0,0 +1,441 @@
+//! `usync serve --stdio`: copies and syncs driven by JSON lines, for programs that
+//! embed usync without starting it once per file (`rpc` feature).
+//!
+//! Each line read is a [`Request`]:
+//!
+//! ```text
+//! {"id":1,"op":"copy","src":"./photos/","dst":"backup@nas:/srv/photos/","options":{"recursive":true}}
+//! {"id":2,"op":"sync","src":"./docs/","dst":"/mnt/usb/docs/","options":{"recursive":true,"delete":true}}
+//! {"id":1,"op":"cancel"}
+//! ```
+//!
+//! Each line written is a [`Reply`] for one request id: `progress` and `skipped` events
+//! while it runs, then exactly one of `done` (with the [`TransferReport`]), `error` or
+//! `cancelled`. Requests run concurrently, so replies to different ids interleave. A
+//! cancel names the id of the request to stop, and gets an `error` for that id when
+//! nothing with it is running. At the end of the input the server stops reading, lets
+//! the running requests finish and exits.
+//!
+//! Requests are checked, and lock their destination, as a `usync` command would; they
+//! then run through [`nonblocking`]: local copies report progress and stop at a
+//! cancel, removing the file being written. Remote transfers send no progress, and
+//! one cancelled after its tool started is answered at once but runs to completion.
+
+use std::collections::HashMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::copy::{Compare, CopyOptions};
+use crate::nonblocking::{self, Event};
+use crate::report::TransferReport;
+use crate::{Prepared, Usync};
+
+/// How many replies may wait for stdout before requests are held up.
+const QUEUE: usize = 256;
+
+/// One line of input.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Op {
+    /// [`nonblocking::copy`] from `src` to `dst`.
+    Copy(Transfer),
+    /// [`nonblocking::sync`] from `src` to `dst`.
+    Sync(Transfer),
+    /// Stop the running request with this id.
+    Cancel,
+}
+
+/// Paths written as on the command line, and the settings of the transfer.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Transfer {
+    pub src: String,
+    pub dst: String,
+    #[serde(default)]
+    pub options: Options,
+}
+
+impl Transfer {
+    /// Copy, or with `sync` sync, sending the progress of local copies to `events`.
+    ///
+    /// The settings are checked and the destination locked as [`Usync::run`] does,
+    /// and the lock is held until the transfer ends.
+    pub async fn run(
+        &self,
+        sync: bool,
+        events: mpsc::Sender<Event>,
+    ) -> Result<TransferReport, String> {
+        let Prepared {
+            src,
+            dst,
+            opts,
+            lock: _lock,
+        } = self
+            .options
+            .usync(&self.src, &self.dst, sync)
+            .prepare()
+            .map_err(|e| e.to_string())?;
+        let result = if sync {
+            nonblocking::sync(&src, &dst, &opts, Some(events)).await
+        } else {
+            nonblocking::copy(&src, &dst, &opts, Some(events)).await
+        };
+        result.map_err(|e| e.to_string())
+    }
+}
+
+/// The flags a request can set; each is named after its [`Usync`] setter.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    pub recursive: bool,
+    pub archive: bool,
+    pub mkpath: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub delete: bool,
+    pub continue_on_error: Option<bool>,
+    pub max_files: Option<u64>,
+    pub max_total_size: Option<u64>,
+    pub port: Option<u16>,
+}
+
+impl Options {
+    /// The [`CopyOptions`] for a transfer from `src` to `dst`.
+    pub fn copy_options(&self, src: &str, dst: &str) -> CopyOptions {
+        self.usync(src, dst, false).copy_options()
+    }
+
+    /// These settings on a [`Usync`] from `src` to `dst`. A `sync` is a copy with
+    /// `--compare size-mtime`, so it also locks the destination and goes on past
+    /// errors unless `continue_on_error` says otherwise.
+    fn usync(&self, src: &str, dst: &str, sync: bool) -> Usync {
+        let mut usync = Usync::new(src, dst)
+            .recursive(self.recursive)
+            .archive(self.archive)
+            .mkpath(self.mkpath)
+            .delete(self.delete);
+        for pattern in &self.include {
+            usync = usync.include(pattern);
+        }
+        for pattern in &self.exclude {
+            usync = usync.exclude(pattern);
+        }
+        if let Some(on) = self.continue_on_error {
+            usync = usync.continue_on_error(on);
+        }
+        if let Some(n) = self.max_files {
+            usync = usync.max_files(n);
+        }
+        if let Some(bytes) = self.max_total_size {
+            usync = usync.max_total_size(bytes);
+        }
+        if let Some(port) = self.port {
+            usync = usync.port(port);
+        }
+        if sync {
+            usync = usync.compare(Compare::SizeMtime);
+        }
+        usync
+    }
+}
+
+/// One line of output, about the request `id`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Reply {
+    /// `copied` of the `total` bytes of the source file `path` are written.
+    Progress {
+        id: u64,
+        path: String,
+        copied: u64,
+        total: u64,
+    },
+    /// The source file `path` was left alone because it matched the destination.
+    Skipped {
+        id: u64,
+        path: String,
+    },
+    Done {
+        id: u64,
+        report: Box<TransferReport>,
+    },
+    /// The request failed, or couldn't be read (`id` is then null).
+    Error {
+        id: Option<u64>,
+        message: String,
+    },
+    Cancelled {
+        id: u64,
+    },
+}
+
+impl Reply {
+    fn event(id: u64, event: Event) -> Self {
+        match event {
+            Event::Bytes {
+                path,
+                copied,
+                total,
+            } => Reply::Progress {
+                id,
+                path: path.to_string_lossy().into_owned(),
+                copied,
+                total,
+            },
+            Event::Skipped { path } => Reply::Skipped {
+                id,
+                path: path.to_string_lossy().into_owned(),
+            },
+        }
+    }
+
+    fn error(id: Option<u64>, message: impl ToString) -> Self {
+        Reply::Error {
+            id,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Serve requests from stdin on stdout until stdin ends.
+pub fn serve_stdio() -> io::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(serve(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+    ))
+}
+
+/// Serve the requests read from `input`, writing the replies to `output`, until
+/// `input` ends and every request it started has finished.
+pub async fn serve<R, W>(input: R, output: W) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (replies, queue) = mpsc::channel(QUEUE);
+    let writer = tokio::spawn(write_replies(queue, output));
+    let mut running: HashMap<u64, oneshot::Sender<()>> = HashMap::new();
+    let mut lines = input.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                let id = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|v| v.get("id")?.as_u64());
+                let _ = replies
+                    .send(Reply::error(id, format!("bad request: {}", e)))
+                    .await;
+                continue;
+            }
+        };
+        running.retain(|_, cancel| !cancel.is_closed());
+        let id = request.id;
+        match request.op {
+            Op::Cancel => {
+                let stopped = running.remove(&id).is_some_and(|c| c.send(()).is_ok());
+                if !stopped {
+                    let message = format!("no request {} is running", id);
+                    let _ = replies.send(Reply::error(Some(id), message)).await;
+                }
+            }
+            Op::Copy(_) | Op::Sync(_) if running.contains_key(&id) => {
+                let message = format!("request {} is already running", id);
+                let _ = replies.send(Reply::error(Some(id), message)).await;
+            }
+            Op::Copy(transfer) => {
+                running.insert(id, start(id, transfer, false, replies.clone()));
+            }
+            Op::Sync(transfer) => {
+                running.insert(id, start(id, transfer, true, replies.clone()));
+            }
+        }
+    }
+    // The writer ends once the last request drops its sender.
+    drop(replies);
+    writer.await.map_err(io::Error::other)?
+}
+
+/// Run a transfer as its own task; sending on the returned channel cancels it.
+fn start(
+    id: u64,
+    transfer: Transfer,
+    sync: bool,
+    replies: mpsc::Sender<Reply>,
+) -> oneshot::Sender<()> {
+    let (cancel, cancelled) = oneshot::channel();
+    tokio::spawn(async move {
+        let reply = tokio::select! {
+            result = run(id, &transfer, sync, &replies) => match result {
+                Ok(report) => Reply::Done {
+                    id,
+                    report: Box::new(report),
+                },
+                Err(message) => Reply::error(Some(id), message),
+            },
+            Ok(()) = cancelled => Reply::Cancelled { id },
+        };
+        let _ = replies.send(reply).await;
+    });
+    cancel
+}
+
+async fn run(
+    id: u64,
+    transfer: &Transfer,
+    sync: bool,
+    replies: &mpsc::Sender<Reply>,
+) -> Result<TransferReport, String> {
+    let (events, mut received) = mpsc::channel(QUEUE);
+    let forward = {
+        let replies = replies.clone();
+        async move {
+            while let Some(event) = received.recv().await {
+                let _ = replies.send(Reply::event(id, event)).await;
+            }
+        }
+    };
+    // The events end when the copy drops its sender, so all of them go out first.
+    let (result, ()) = tokio::join!(transfer.run(sync, events), forward);
+    result
+}
+
+async fn write_replies<W: AsyncWrite + Unpin>(
+    mut queue: mpsc::Receiver<Reply>,
+    mut output: W,
+) -> io::Result<()> {
+    while let Some(reply) = queue.recv().await {
+        let mut line = serde_json::to_vec(&reply).map_err(io::Error::other)?;
+        line.push(b'\n');
+        output.write_all(&line).await?;
+        output.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::JobLock;
+    use crate::protocol::parse_path;
+    use serde_json::Value;
+    use std::fs;
+    use tempfile::TempDir;
+
+    async fn session(input: &str) -> Vec<Value> {
+        let (writer, mut reader) = tokio::io::duplex(1 << 20);
+        serve(input.as_bytes(), writer).await.unwrap();
+        let mut output = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut output)
+            .await
+            .unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let request: Request = serde_json::from_str(
+            r#"{"id":7,"op":"sync","src":"a/","dst":"b/","options":{"recursive":true,"exclude":["*.tmp"]}}"#,
+        )
+        .unwrap();
+        let Op::Sync(transfer) = request.op else {
+            panic!("not a sync: {:?}", request.op);
+        };
+        assert_eq!(request.id, 7);
+        assert!(transfer.options.recursive);
+        assert_eq!(transfer.options.exclude, ["*.tmp"]);
+
+        let request: Request = serde_json::from_str(r#"{"id":7,"op":"cancel"}"#).unwrap();
+        assert_eq!(request.op, Op::Cancel);
+
+        // Options are spelled out; a typo isn't silently dropped.
+        assert!(serde_json::from_str::<Request>(
+            r#"{"id":1,"op":"copy","src":"a","dst":"b","options":{"recursve":true}}"#
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        let dst = temp.path().join("dst");
+        let input = format!(
+            "{}\nnot json\n{}\n{}\n",
+            serde_json::json!({"id": 1, "op": "copy", "src": src, "dst": dst, "options": {"recursive": true}}),
+            serde_json::json!({"id": 2, "op": "copy", "src": src, "dst": dst}),
+            serde_json::json!({"id": 3, "op": "cancel"}),
+        );
+        let replies = session(&input).await;
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "hello");
+
+        let of = |id: u64| -> Vec<&Value> { replies.iter().filter(|r| r["id"] == id).collect() };
+        let first = of(1);
+        assert_eq!(first.last().unwrap()["event"], "done");
+        assert_eq!(first.last().unwrap()["report"]["files_copied"], 1);
+        assert!(first.iter().any(|r| r["event"] == "progress"));
+        assert_eq!(of(2)[0]["event"], "error");
+        assert_eq!(of(3)[0]["event"], "error");
+        assert!(replies
+            .iter()
+            .any(|r| r["id"].is_null() && r["event"] == "error"));
+    }
+
+    /// A sync goes through the same checks and destination lock as `usync` itself, and
+    /// its `delete` removes what the source no longer has.
+    #[tokio::test]
+    async fn test_session_sync_delete() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(&dst).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(src.join(name), "newer").unwrap();
+            fs::write(dst.join(name), "old").unwrap();
+        }
+        fs::write(dst.join("extra.txt"), "gone").unwrap();
+        let request = serde_json::json!({
+            "id": 1,
+            "op": "sync",
+            "src": src,
+            "dst": dst,
+            "options": {"recursive": true, "delete": true},
+        });
+
+        let held = JobLock::acquire(&parse_path(dst.to_str().unwrap()).unwrap(), None)
+            .unwrap()
+            .unwrap();
+        let replies = session(&format!("{}\n", request)).await;
+        assert_eq!(replies.last().unwrap()["event"], "error", "{:?}", replies);
+        assert!(dst.join("extra.txt").exists());
+        drop(held);
+
+        let replies = session(&format!("{}\n", request)).await;
+        let done = replies.last().unwrap();
+        assert_eq!(done["event"], "done", "{:?}", replies);
+        assert_eq!(done["report"]["operation"], "sync");
+        assert_eq!(done["report"]["files_deleted"], 1);
+        assert!(!dst.join("extra.txt").exists());
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "newer");
+    }
+}
@@ -0,0 +1,183 @@
+//! `--sanitize-names`: destination names a FAT or Windows filesystem accepts.
+//!
+//! Each name below a directory copy is checked against the rules of the destination:
+//! characters it can't store become a replacement (`_` by default), trailing spaces
+//! and dots go, Windows device names (`CON`, `LPT1`, ...) get the replacement appended,
+//! and the result is cut to 255 bytes with its extension kept. Two names that end up
+//! the same are then handled by the `--case-collisions` policy.
+
+use std::ffi::{OsStr, OsString};
+
+/// The longest name, in bytes, most filesystems store.
+const NAME_MAX: usize = 255;
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters FAT (and NTFS, through Windows) can't have in a name, besides controls.
+const FAT_ILLEGAL: &str = "\"*/:<>?\\|";
+
+/// What a destination filesystem accepts in a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameRules {
+    /// Characters replaced wherever they appear.
+    illegal: String,
+    /// Replace control characters (U+0000 to U+001F and DEL) too.
+    controls: bool,
+    /// Rename Windows device names.
+    reserved: bool,
+    replacement: char,
+}
+
+impl NameRules {
+    /// Parse `windows`, `fat` or `custom:<chars>`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let rules = |illegal: &str, controls, reserved| NameRules {
+            illegal: illegal.to_string(),
+            controls,
+            reserved,
+            replacement: '_',
+        };
+        match spec {
+            "windows" => Ok(rules(FAT_ILLEGAL, true, true)),
+            "fat" => Ok(rules(FAT_ILLEGAL, true, false)),
+            _ => match spec.strip_prefix("custom:") {
+                Some(chars) if !chars.is_empty() => Ok(rules(chars, false, false)),
+                _ => Err(format!(
+                    "invalid name rules {:?}; use windows, fat or custom:<chars>",
+                    spec
+                )),
+            },
+        }
+    }
+
+    /// Use `replacement` for the characters the rules reject.
+    pub fn with_replacement(mut self, replacement: char) -> Result<Self, String> {
+        if self.rejects(replacement) || replacement == '.' || replacement == ' ' {
+            return Err(format!(
+                "{:?} can't replace characters these rules reject",
+                replacement
+            ));
+        }
+        self.replacement = replacement;
+        Ok(self)
+    }
+
+    fn rejects(&self, c: char) -> bool {
+        c == '/' || self.illegal.contains(c) || (self.controls && (c < ' ' || c == '\x7f'))
+    }
+
+    /// The name to write `name` under, when it can't be written as it is.
+    pub fn sanitize(&self, name: &OsStr) -> Option<OsString> {
+        let original = name.to_str();
+        let mut clean: String = name
+            .to_string_lossy()
+            .chars()
+            .map(|c| if self.rejects(c) { self.replacement } else { c })
+            .collect();
+        clean.truncate(clean.trim_end_matches([' ', '.']).len());
+        if clean.is_empty() {
+            clean.push(self.replacement);
+        }
+        if self.reserved {
+            let stem_len = clean.find('.').unwrap_or(clean.len());
+            if RESERVED
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(&clean[..stem_len]))
+            {
+                clean.insert(stem_len, self.replacement);
+            }
+        }
+        let clean = truncate(clean);
+        (original != Some(clean.as_str())).then(|| clean.into())
+    }
+}
+
+/// `name` cut to [`NAME_MAX`] bytes on a character boundary, keeping a short extension.
+fn truncate(name: String) -> String {
+    if name.len() <= NAME_MAX {
+        return name;
+    }
+    let ext = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= 16 => &name[dot..],
+        _ => "",
+    };
+    let mut end = NAME_MAX - ext.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize(rules: &str, name: &str) -> Option<String> {
+        NameRules::parse(rules)
+            .unwrap()
+            .sanitize(OsStr::new(name))
+            .map(|n| n.into_string().unwrap())
+    }
+
+    #[test]
+    fn test_illegal_characters() {
+        assert_eq!(sanitize("fat", "notes.txt"), None);
+        assert_eq!(sanitize("fat", "a:b?c*.txt").as_deref(), Some("a_b_c_.txt"));
+        assert_eq!(sanitize("fat", "tab\there").as_deref(), Some("tab_here"));
+        assert_eq!(sanitize("fat", "<\"|\\>").as_deref(), Some("_____"));
+        assert_eq!(sanitize("custom:#%", "50% #1").as_deref(), Some("50_ _1"));
+        assert_eq!(sanitize("custom:#", "a:b"), None);
+    }
+
+    #[test]
+    fn test_trailing_dots_and_spaces() {
+        assert_eq!(sanitize("fat", "draft. . ").as_deref(), Some("draft"));
+        assert_eq!(sanitize("fat", "...").as_deref(), Some("_"));
+        // Leading ones are fine.
+        assert_eq!(sanitize("fat", ".profile"), None);
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        assert_eq!(sanitize("windows", "con").as_deref(), Some("con_"));
+        assert_eq!(
+            sanitize("windows", "LPT1.txt").as_deref(),
+            Some("LPT1_.txt")
+        );
+        assert_eq!(sanitize("windows", "console.txt"), None);
+        assert_eq!(sanitize("fat", "con"), None);
+    }
+
+    #[test]
+    fn test_truncation_keeps_the_extension() {
+        let long = format!("{}.jpeg", "é".repeat(200));
+        let short = sanitize("fat", &long).unwrap();
+        assert!(short.len() <= NAME_MAX);
+        assert!(short.ends_with("é.jpeg"));
+        assert_eq!(sanitize("fat", &"a".repeat(255)), None);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let rules = NameRules::parse("fat")
+            .unwrap()
+            .with_replacement('-')
+            .unwrap();
+        assert_eq!(
+            rules.sanitize(OsStr::new("12:30")).unwrap(),
+            OsStr::new("12-30")
+        );
+        for bad in [':', '.', ' ', '/'] {
+            assert!(NameRules::parse("fat")
+                .unwrap()
+                .with_replacement(bad)
+                .is_err());
+        }
+        assert!(NameRules::parse("custom:").is_err());
+        assert!(NameRules::parse("ntfs").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+//! `--http-segments N`: download a large file over HTTP as N byte ranges at once, for
+//! servers far enough away that one stream can't fill the line.
+//!
+//! A `HEAD` tells the size and whether the server serves ranges; without both the
+//! download falls back to a single stream. The destination is created at its full size
+//! and each range is fetched by its own `curl -r`, written at its offset as it arrives.
+//! A range that fails is asked for again from where it stopped, up to [`ATTEMPTS`]
+//! times, while the others carry on.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[cfg(feature = "progress")]
+use indicatif::ProgressBar;
+
+use crate::http::Response;
+use crate::parse;
+use crate::protocol::RemotePath;
+use crate::remote::{self, RemoteCopyError};
+
+/// Ranges smaller than this aren't worth a connection of their own.
+pub const MIN_SEGMENT: u64 = 1024 * 1024;
+
+/// Tries per range before the download fails.
+pub const ATTEMPTS: u32 = 3;
+
+/// Split `size` bytes into at most `n` inclusive ranges of at least [`MIN_SEGMENT`]
+/// bytes (the last one may be shorter).
+pub fn ranges(size: u64, n: u32) -> Vec<(u64, u64)> {
+    if size == 0 {
+        return Vec::new();
+    }
+    let n = u64::from(n).clamp(1, size.div_ceil(MIN_SEGMENT));
+    let step = size.div_ceil(n);
+    (0..n)
+        .map(|i| (i * step, ((i + 1) * step).min(size) - 1))
+        .filter(|(start, end)| start <= end)
+        .collect()
+}
+
+/// Download `src` into `dst` in up to `segments` ranges at once. `Ok(None)` when it
+/// can't be, for the caller to download it in one stream; otherwise the response of
+/// the `HEAD`, for the usual checks.
+pub fn download(
+    src: &RemotePath,
+    dst: &Path,
+    segments: u32,
+    verbose: bool,
+    progress: bool,
+) -> Result<Option<Response>, RemoteCopyError> {
+    let head = remote::http_head(src)?;
+    let response = Response::parse(&head);
+    let size = match parse::content_length(&head) {
+        Some(size) if response.accept_ranges => size,
+        _ => {
+            if verbose {
+                println!(
+                    "{} doesn't serve byte ranges of a known size; downloading in one stream",
+                    src.url
+                );
+            }
+            return Ok(None);
+        }
+    };
+    let ranges = ranges(size, segments);
+    if ranges.len() < 2 {
+        return Ok(None);
+    }
+    if verbose {
+        println!("Downloading {} bytes in {} segments", size, ranges.len());
+    }
+
+    let io_error = |message: String, error: io::Error| RemoteCopyError::IoError {
+        message,
+        error: error.to_string(),
+    };
+    File::create(dst)
+        .and_then(|file| file.set_len(size))
+        .map_err(|e| io_error(format!("Failed to create {}", dst.display()), e))?;
+    let meter = Meter::new(progress, size);
+    let (url, meter) = (src.url.as_str(), &meter);
+    let results: Vec<Result<(), RemoteCopyError>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = ranges
+            .iter()
+            .map(|&range| scope.spawn(move || fetch(url, dst, range, meter)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("segment thread panicked"))
+            .collect()
+    });
+    meter.finish();
+    if let Some(error) = results.into_iter().find_map(Result::err) {
+        // A preallocated file full of holes is worse than none.
+        let _ = std::fs::remove_file(dst);
+        return Err(error);
+    }
+    Ok(Some(response))
+}
+
+/// Fetch the bytes `start..=end` of `url` into `dst`, asking again for what is missing
+/// when a try fails.
+fn fetch(
+    url: &str,
+    dst: &Path,
+    (start, end): (u64, u64),
+    meter: &Meter,
+) -> Result<(), RemoteCopyError> {
+    let length = end - start + 1;
+    let mut done = 0;
+    let mut last_error = String::new();
+    for _ in 0..ATTEMPTS {
+        match fetch_once(url, dst, start + done, end, &mut done, meter) {
+            Ok(()) if done == length => return Ok(()),
+            Ok(()) => {
+                last_error = format!("the server sent {} of {} bytes", done, length);
+            }
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(RemoteCopyError::IoError {
+        message: format!(
+            "Segment {}-{} of {} failed after {} attempts",
+            start, end, url, ATTEMPTS
+        ),
+        error: last_error,
+    })
+}
+
+/// One `curl -r from-to`, written into `dst` at `from`, counting into `done`.
+fn fetch_once(
+    url: &str,
+    dst: &Path,
+    from: u64,
+    to: u64,
+    done: &mut u64,
+    meter: &Meter,
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(dst)?;
+    file.seek(SeekFrom::Start(from))?;
+    let mut child = Command::new("curl")
+        .arg("-sSfL")
+        // A stalled connection fails the try rather than holding up the download.
+        .args(["--speed-limit", "1", "--speed-time", "30"])
+        .arg("-r")
+        .arg(format!("{}-{}", from, to))
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut left = to - from + 1;
+    let mut buffer = vec![0u8; 256 * 1024];
+    let copied = loop {
+        let n = match stdout.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        if n as u64 > left {
+            break Err(io::Error::other(
+                "the server sent more than the range asked for",
+            ));
+        }
+        if let Err(e) = file.write_all(&buffer[..n]) {
+            break Err(e);
+        }
+        left -= n as u64;
+        *done += n as u64;
+        meter.inc(n as u64);
+    };
+    if copied.is_err() {
+        let _ = child.kill();
+    }
+    drop(stdout);
+    let output = child.wait_with_output()?;
+    copied?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "curl exited with code {}: {}",
+            output.status.code().unwrap_or(-1),
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// One bar for all segments, shared by their threads.
+struct Meter {
+    #[cfg(feature = "progress")]
+    bar: Option<ProgressBar>,
+}
+
+impl Meter {
+    fn new(progress: bool, size: u64) -> Self {
+        #[cfg(feature = "progress")]
+        {
+            use std::io::IsTerminal;
+            let bar = (progress && io::stdout().is_terminal()).then(|| {
+                let bar = ProgressBar::new(size);
+                bar.set_style(crate::progress::bar_style());
+                bar
+            });
+            Meter { bar }
+        }
+        #[cfg(not(feature = "progress"))]
+        {
+            let _ = (progress, size);
+            Meter {}
+        }
+    }
+
+    fn inc(&self, _bytes: u64) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.inc(_bytes);
+        }
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{parse_path, Path as ProtocolPath};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_ranges() {
+        let mib = MIN_SEGMENT;
+        assert_eq!(ranges(0, 4), []);
+        assert_eq!(ranges(100, 4), [(0, 99)]);
+        assert_eq!(ranges(4 * mib, 4).len(), 4);
+        assert_eq!(ranges(3 * mib, 8).len(), 3);
+        let split = ranges(10 * mib + 1, 4);
+        assert_eq!(split.first().unwrap().0, 0);
+        assert_eq!(split.last().unwrap().1, 10 * mib);
+        for pair in split.windows(2) {
+            assert_eq!(pair[0].1 + 1, pair[1].0);
+        }
+    }
+
+    /// Serve `body`, with range support if `ranges`; the first request for a range that
+    /// doesn't start at 0 gets only half of it, so that segment has to be resumed.
+    fn spawn_range_server(
+        body: Vec<u8>,
+        ranges: bool,
+    ) -> (Arc<tiny_http::Server>, String, Arc<AtomicUsize>) {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://{}/big.bin", server.server_addr().to_ip().unwrap());
+        let cut = Arc::new(AtomicUsize::new(0));
+        let (worker, cuts) = (server.clone(), cut.clone());
+        std::thread::spawn(move || {
+            for request in worker.incoming_requests() {
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Range"))
+                    .and_then(|h| {
+                        let (from, to) =
+                            h.value.as_str().strip_prefix("bytes=")?.split_once('-')?;
+                        Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()?))
+                    })
+                    .filter(|_| ranges);
+                let header = |h: String| h.parse::<tiny_http::Header>().unwrap();
+                let response = match range {
+                    Some((from, to)) => {
+                        let part = body[from..=to].to_vec();
+                        let length = part.len();
+                        if from > 0 && cuts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            // Half the range, chunked, so it ends short but cleanly.
+                            let half = part[..length / 2].to_vec();
+                            let response = tiny_http::Response::new(
+                                tiny_http::StatusCode(206),
+                                Vec::new(),
+                                std::io::Cursor::new(half),
+                                None,
+                                None,
+                            );
+                            let _ = request.respond(response);
+                            continue;
+                        }
+                        tiny_http::Response::new(
+                            tiny_http::StatusCode(206),
+                            vec![header(format!(
+                                "Content-Range: bytes {}-{}/{}",
+                                from,
+                                to,
+                                body.len()
+                            ))],
+                            std::io::Cursor::new(part),
+                            Some(length),
+                            None,
+                        )
+                    }
+                    None => {
+                        let mut response = tiny_http::Response::new(
+                            tiny_http::StatusCode(200),
+                            Vec::new(),
+                            std::io::Cursor::new(body.clone()),
+                            Some(body.len()),
+                            None,
+                        );
+                        if ranges {
+                            response.add_header(header("Accept-Ranges: bytes".to_string()));
+                        }
+                        response
+                    }
+                };
+                // tiny_http would send a body this big chunked, without Content-Length.
+                let _ = request.respond(response.with_chunked_threshold(usize::MAX));
+            }
+        });
+        (server, url, cut)
+    }
+
+    #[test]
+    fn test_segmented_download() {
+        if Command::new("curl").arg("--version").output().is_err() {
+            return;
+        }
+        let body: Vec<u8> = (0..3 * MIN_SEGMENT + 12345)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let temp = TempDir::new().unwrap();
+
+        let (server, url, cuts) = spawn_range_server(body.clone(), true);
+        let ProtocolPath::Remote(src) = parse_path(&url).unwrap() else {
+            panic!("not a remote path");
+        };
+        let dst = temp.path().join("big.bin");
+        let response = download(&src, &dst, 4, false, false).unwrap();
+        assert_eq!(response.unwrap().status, Some(200));
+        assert!(cuts.load(Ordering::SeqCst) >= 1, "no segment was retried");
+        assert!(std::fs::read(&dst).unwrap() == body);
+        server.unblock();
+
+        // No Accept-Ranges: left to the single stream.
+        let (server, url, _) = spawn_range_server(body, false);
+        let ProtocolPath::Remote(src) = parse_path(&url).unwrap() else {
+            panic!("not a remote path");
+        };
+        let dst = temp.path().join("whole.bin");
+        assert_eq!(download(&src, &dst, 4, false, false).unwrap(), None);
+        assert!(!dst.exists());
+        server.unblock();
+    }
+}
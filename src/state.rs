@@ -0,0 +1,255 @@
+//! `--state-file`: digests remembered between runs of the same `--checksum` sync.
+//!
+//! Verifying a directory copy hashes every file on both sides. The state file records,
+//! per side and relative path, the size, modification time and digest of each file
+//! hashed; the next run reuses the digest of a file whose size and time haven't changed
+//! instead of reading it again. It lives in `$XDG_CACHE_HOME/usync/state` (else
+//! `~/.cache/usync/state`) under a name derived from the source and destination unless
+//! `--state-file` says otherwise, and is replaced in one rename after a run that
+//! verified. A file that can't be read, is damaged, or was written by another version
+//! or for another algorithm is ignored and rewritten.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+
+use crate::checksum::{self, Algorithm};
+use crate::protocol::Path as ProtocolPath;
+
+/// First word of the header line; the number after it is the format version.
+const MAGIC: &str = "usync-state";
+const VERSION: u32 = 1;
+
+/// Remembered digests, read from and written back to one state file.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    algorithm: Algorithm,
+    /// What the file held when loaded, by key.
+    old: HashMap<String, Entry>,
+    /// Everything hashed or reused this run; what gets saved.
+    new: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// Modification time in nanoseconds since the epoch.
+    mtime: i128,
+    digest: String,
+}
+
+impl StateFile {
+    /// Read the state file at `path`, or start empty when it is missing or unusable.
+    pub fn load(path: &Path, algorithm: Algorithm) -> Self {
+        let old = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| parse(&contents, algorithm))
+            .unwrap_or_default();
+        StateFile {
+            path: path.to_path_buf(),
+            algorithm,
+            old,
+            new: BTreeMap::new(),
+        }
+    }
+
+    /// Where the state is read from and saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the state of a sync from `src` to `dst` is kept by default.
+    pub fn default_path(src: &ProtocolPath, dst: &ProtocolPath) -> Option<PathBuf> {
+        let cache = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        let name = |path: &ProtocolPath| match path {
+            ProtocolPath::Local(local) => {
+                let path = std::path::absolute(local.as_path())
+                    .unwrap_or_else(|_| local.as_path().to_path_buf());
+                fs::canonicalize(&path)
+                    .unwrap_or(path)
+                    .display()
+                    .to_string()
+            }
+            ProtocolPath::Remote(remote) => remote.url.to_string(),
+        };
+        let digest = Sha256::digest(format!("{}\n{}", name(src), name(dst)).as_bytes());
+        Some(
+            cache
+                .join("usync")
+                .join("state")
+                .join(format!("{}.state", checksum::to_hex(&digest[..16]))),
+        )
+    }
+
+    /// The digest of the file at `path`, recorded under `key`: the remembered one when
+    /// its size and modification time still match, otherwise `hash(path)`.
+    pub fn digest(
+        &mut self,
+        key: &str,
+        path: &Path,
+        hash: impl FnOnce(&Path) -> io::Result<String>,
+    ) -> io::Result<String> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos() as i128);
+        let known = |entry: &&Entry| entry.size == metadata.len() && entry.mtime == mtime;
+        // Hashed earlier in this run (the destination of a verified copy, tagged after).
+        let entry = match self
+            .new
+            .get(key)
+            .filter(known)
+            .or(self.old.get(key).filter(known))
+        {
+            Some(known) => known.clone(),
+            _ => Entry {
+                size: metadata.len(),
+                mtime,
+                digest: hash(path)?,
+            },
+        };
+        let digest = entry.digest.clone();
+        if !key.contains('\n') {
+            self.new.insert(key.to_string(), entry);
+        }
+        Ok(digest)
+    }
+
+    /// [`checksum::hash_tree`] for the tree at `root`, remembered under `side`.
+    pub fn hash_tree(&mut self, side: &str, root: &Path) -> io::Result<BTreeMap<String, String>> {
+        let algorithm = self.algorithm;
+        checksum::hash_tree_with(root, |name, path| {
+            self.digest(&format!("{}/{}", side, name), path, |path| {
+                checksum::hash_file(path, algorithm)
+            })
+        })
+    }
+
+    /// Replace the state file with what this run hashed, through a rename so a reader
+    /// never sees half of it.
+    pub fn save(&self) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        writeln!(temp, "{} {} {}", MAGIC, VERSION, self.algorithm.name())?;
+        for (key, entry) in &self.new {
+            writeln!(
+                temp,
+                "{}\t{}\t{}\t{}",
+                entry.size, entry.mtime, entry.digest, key
+            )?;
+        }
+        temp.as_file().sync_all()?;
+        temp.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// The entries of a state file, or `None` when any part of it isn't as written.
+fn parse(contents: &str, algorithm: Algorithm) -> Option<HashMap<String, Entry>> {
+    let mut lines = contents.lines();
+    let header = format!("{} {} {}", MAGIC, VERSION, algorithm.name());
+    if lines.next()? != header {
+        return None;
+    }
+    lines
+        .map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let entry = Entry {
+                size: fields.next()?.parse().ok()?,
+                mtime: fields.next()?.parse().ok()?,
+                digest: fields.next()?.to_string(),
+            };
+            let key = fields.next()?;
+            checksum::is_digest(&entry.digest, algorithm).then(|| (key.to_string(), entry))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_unchanged_files_are_not_hashed_again() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let tree = temp.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("a.txt"), "a").unwrap();
+        fs::write(tree.join("sub/b.txt"), "b").unwrap();
+        let path = temp.path().join("state");
+
+        // Stands in for the hashing backend, counting the files it reads.
+        let reads = Cell::new(0);
+        let run = |state: &mut StateFile| {
+            checksum::hash_tree_with(&tree, |name, file| {
+                state.digest(&format!("src/{}", name), file, |file| {
+                    reads.set(reads.get() + 1);
+                    checksum::hash_file(file, Algorithm::Sha256)
+                })
+            })
+            .unwrap()
+        };
+
+        let mut state = StateFile::load(&path, Algorithm::Sha256);
+        let first = run(&mut state);
+        state.save().unwrap();
+        assert_eq!(reads.replace(0), 2);
+
+        let mut state = StateFile::load(&path, Algorithm::Sha256);
+        assert_eq!(run(&mut state), first);
+        assert_eq!(reads.replace(0), 0);
+        state.save().unwrap();
+
+        fs::write(tree.join("a.txt"), "changed").unwrap();
+        let mut state = StateFile::load(&path, Algorithm::Sha256);
+        let third = run(&mut state);
+        assert_eq!(reads.get(), 1);
+        assert_ne!(third["a.txt"], first["a.txt"]);
+        assert_eq!(third["sub/b.txt"], first["sub/b.txt"]);
+    }
+
+    #[test]
+    fn test_unusable_state_is_ignored() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let file = temp.path().join("a.txt");
+        fs::write(&file, "a").unwrap();
+        let path = temp.path().join("state");
+        let mut state = StateFile::load(&path, Algorithm::Sha256);
+        state
+            .digest("src/a.txt", &file, |f| {
+                checksum::hash_file(f, Algorithm::Sha256)
+            })
+            .unwrap();
+        state.save().unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(parse(&saved, Algorithm::Sha256).is_some_and(|e| e.len() == 1));
+
+        // Another algorithm, another version, or damage: nothing is reused.
+        assert!(parse(&saved, Algorithm::Md5).is_none());
+        assert!(parse(&saved.replace("state 1", "state 2"), Algorithm::Sha256).is_none());
+        assert!(parse(&saved.replace('\t', " "), Algorithm::Sha256).is_none());
+        assert!(parse(&saved[..saved.len() - 20], Algorithm::Sha256).is_none());
+        fs::write(&path, b"\xff\xfe garbage").unwrap();
+        let mut state = StateFile::load(&path, Algorithm::Sha256);
+        let hashed = Cell::new(false);
+        state
+            .digest("src/a.txt", &file, |f| {
+                hashed.set(true);
+                checksum::hash_file(f, Algorithm::Sha256)
+            })
+            .unwrap();
+        assert!(hashed.get());
+    }
+}
@@ -0,0 +1,340 @@
+//! `-` as a source or destination: upload what arrives on stdin, or write a source to
+//! stdout, so usync can sit in a pipeline (`pg_dump | usync - host:db.sql`).
+//!
+//! A stream's size isn't known up front, so progress is a running byte count rather than
+//! a bar, and `--checksum` hashes the bytes as they pass through instead of reading them
+//! again. Stdin goes to the same destinations as `--also-to` (a file, `ssh 'cat > path'`,
+//! `aws s3 cp -`); stdout is fed from a local file, `ssh cat`, `aws s3 cp ... -` or `curl`.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::{Child, Stdio};
+
+use crate::checksum::{Algorithm, Hasher};
+use crate::copy::{CopyError, CopyOptions};
+use crate::protocol::{Path as ProtocolPath, Protocol};
+use crate::remote::{self, RemoteCopyError};
+use crate::tee;
+use crate::verify::Fingerprint;
+
+#[cfg(feature = "progress")]
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// The argument that stands for stdin as a source and stdout as a destination.
+pub const STDIO: &str = "-";
+
+/// What went through a stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Streamed {
+    pub bytes: u64,
+    /// Hex digest of the bytes, when one was asked for.
+    pub digest: Option<String>,
+}
+
+impl Streamed {
+    /// The stream as one side of a `--checksum` comparison.
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint {
+            size: Some(self.bytes),
+            digest: self.digest.clone(),
+            multipart_etag: None,
+        }
+    }
+}
+
+/// A byte counter on stderr for `--progress`, since there is no total to show a bar for.
+struct Counter {
+    #[cfg(feature = "progress")]
+    bar: Option<ProgressBar>,
+}
+
+impl Counter {
+    fn new(progress: bool) -> Self {
+        #[cfg(feature = "progress")]
+        {
+            use std::io::IsTerminal;
+            let bar = (progress && io::stderr().is_terminal()).then(|| {
+                let bar = ProgressBar::new_spinner();
+                bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("{spinner} {bytes} transferred ({bytes_per_sec})")
+                        .unwrap(),
+                );
+                bar
+            });
+            Counter { bar }
+        }
+        #[cfg(not(feature = "progress"))]
+        {
+            let _ = progress;
+            Counter {}
+        }
+    }
+
+    fn inc(&self, _bytes: u64) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.inc(_bytes);
+        }
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+    }
+}
+
+/// Copy `reader` into `write` until EOF, hashing and counting on the way. Read errors
+/// come back as `Ok(Err(_))` so the caller can tell them from write errors.
+pub fn pump(
+    reader: &mut dyn Read,
+    mut write: impl FnMut(&[u8]) -> io::Result<()>,
+    algorithm: Option<Algorithm>,
+    progress: bool,
+) -> io::Result<Result<Streamed, io::Error>> {
+    let mut hasher = algorithm.map(Hasher::new);
+    let counter = Counter::new(progress);
+    let mut buffer = vec![0u8; 256 * 1024];
+    let mut bytes = 0u64;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Ok(Err(e)),
+        };
+        write(&buffer[..n])?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buffer[..n]);
+        }
+        bytes += n as u64;
+        counter.inc(n as u64);
+    }
+    counter.finish();
+    Ok(Ok(Streamed {
+        bytes,
+        digest: hasher.map(Hasher::finish),
+    }))
+}
+
+/// Upload everything on stdin to `dst`, which must name a file.
+pub fn from_stdin(
+    dst: &ProtocolPath,
+    opts: &CopyOptions,
+    algorithm: Option<Algorithm>,
+) -> Result<Streamed, CopyError> {
+    let mut opts = opts.clone();
+    opts.ssh_opts = opts.transit_ssh_opts();
+    let mut sink = tee::open(None, dst, &opts)?;
+    let pumped = pump(
+        &mut io::stdin().lock(),
+        |buf| sink.write_all(buf),
+        algorithm,
+        opts.progress,
+    );
+    let streamed = match pumped {
+        Ok(Ok(streamed)) => streamed,
+        Ok(Err(e)) => {
+            sink.abort();
+            return Err(CopyError::IoError {
+                message: "Failed to read standard input".to_string(),
+                error: e,
+            });
+        }
+        Err(e) => {
+            sink.abort();
+            return Err(CopyError::IoError {
+                message: "Failed to write destination".to_string(),
+                error: e,
+            });
+        }
+    };
+    sink.finish(None, streamed.bytes, &opts)?;
+    Ok(streamed)
+}
+
+/// A source file opened for streaming: a local file, or the stdout of the tool that
+/// fetches a remote one (`ssh cat`, `aws s3 cp ... -`, `curl`).
+pub struct Source {
+    reader: Box<dyn Read + Send>,
+    tool: Option<(Child, &'static str)>,
+    name: String,
+}
+
+impl Source {
+    pub fn open(src: &ProtocolPath, opts: &CopyOptions) -> Result<Source, CopyError> {
+        let not_a_file = |name: String| {
+            CopyError::InvalidSource(format!(
+                "{} is a directory; only a single file can be streamed",
+                name
+            ))
+        };
+        let remote = match src {
+            ProtocolPath::Local(local) => {
+                let name = local.to_string_lossy().into_owned();
+                if local.is_dir() {
+                    return Err(not_a_file(name));
+                }
+                let file = fs::File::open(local.as_path()).map_err(|e| CopyError::IoError {
+                    message: format!("Failed to open source: {}", name),
+                    error: e,
+                })?;
+                return Ok(Source {
+                    reader: Box::new(file),
+                    tool: None,
+                    name,
+                });
+            }
+            ProtocolPath::Remote(remote) => remote,
+        };
+        if remote.is_dir_hint() {
+            return Err(not_a_file(remote.url.to_string()));
+        }
+
+        let (mut cmd, tool) = match remote.protocol {
+            Protocol::Ssh | Protocol::Sftp => {
+                let ssh_opts = opts.transit_ssh_opts();
+                let cmd = remote::ssh_read_command(remote, &ssh_opts).map_err(CopyError::from)?;
+                (cmd, "ssh")
+            }
+            Protocol::S3 => (remote::s3_read_command(remote), "aws"),
+            Protocol::Http | Protocol::Https => {
+                (remote::http_read_command(remote, opts.compress), "curl")
+            }
+            _ => {
+                return Err(CopyError::UnsupportedProtocol(format!(
+                    "Copying from {} protocol is not supported",
+                    remote.protocol
+                )))
+            }
+        };
+        let mut child = cmd.stdout(Stdio::piped()).spawn().map_err(|e| {
+            CopyError::RemoteError(RemoteCopyError::IoError {
+                message: format!("Failed to execute {}", tool),
+                error: e.to_string(),
+            })
+        })?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Source {
+            reader: Box::new(stdout),
+            tool: Some((child, tool)),
+            name: remote.url.to_string(),
+        })
+    }
+
+    /// Close the source, failing if the tool that fetched it didn't succeed.
+    pub fn finish(self) -> Result<(), CopyError> {
+        drop(self.reader);
+        let Some((mut child, tool)) = self.tool else {
+            return Ok(());
+        };
+        let status = wait(&mut child, tool)?;
+        if !status.success() {
+            return Err(CopyError::RemoteError(RemoteCopyError::IoError {
+                message: format!("{} failed to read {}", tool, self.name),
+                error: format!("Exit code: {}", status.code().unwrap_or(-1)),
+            }));
+        }
+        Ok(())
+    }
+
+    /// The error for a failed read of this source.
+    pub fn read_error(&self, error: io::Error) -> CopyError {
+        CopyError::IoError {
+            message: format!("Failed to read source: {}", self.name),
+            error,
+        }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Write the file `src` to stdout.
+pub fn to_stdout(
+    src: &ProtocolPath,
+    opts: &CopyOptions,
+    algorithm: Option<Algorithm>,
+) -> Result<Streamed, CopyError> {
+    let mut source = Source::open(src, opts)?;
+    let written = write_stdout(&mut source, algorithm, opts.progress);
+    let read = match written {
+        Ok(read) => read.map_err(|e| source.read_error(e)),
+        Err(e) => Err(e),
+    };
+    let finished = source.finish();
+    // A tool that failed explains a short read better than the read itself.
+    match (read, finished) {
+        (Err(e @ CopyError::IoError { .. }), Ok(())) => Err(e),
+        (_, Err(e)) => Err(e),
+        (read, Ok(())) => read,
+    }
+}
+
+fn write_stdout(
+    reader: &mut dyn Read,
+    algorithm: Option<Algorithm>,
+    progress: bool,
+) -> Result<Result<Streamed, io::Error>, CopyError> {
+    let mut stdout = io::stdout().lock();
+    let pumped = pump(reader, |buf| stdout.write_all(buf), algorithm, progress)
+        .and_then(|r| stdout.flush().map(|()| r));
+    pumped.map_err(|e| CopyError::IoError {
+        message: "Failed to write to standard output".to_string(),
+        error: e,
+    })
+}
+
+fn wait(child: &mut Child, tool: &str) -> Result<std::process::ExitStatus, CopyError> {
+    child.wait().map_err(|e| {
+        CopyError::RemoteError(RemoteCopyError::IoError {
+            message: format!("Failed to wait for {}", tool),
+            error: e.to_string(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+
+    #[test]
+    fn test_pump_counts_and_hashes() {
+        let data: Vec<u8> = (0..600_000u32).map(|i| (i % 253) as u8).collect();
+        let mut out = Vec::new();
+        let streamed = pump(
+            &mut data.as_slice(),
+            |buf| {
+                out.extend_from_slice(buf);
+                Ok(())
+            },
+            Some(Algorithm::Sha256),
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(out, data);
+        assert_eq!(streamed.bytes, data.len() as u64);
+
+        let mut hasher = checksum::Hasher::new(Algorithm::Sha256);
+        hasher.update(&data);
+        assert_eq!(streamed.digest, Some(hasher.finish()));
+        assert_eq!(streamed.fingerprint().size, Some(data.len() as u64));
+    }
+
+    #[test]
+    fn test_stdin_needs_a_file_name() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dst = crate::protocol::parse_path(temp.path().to_str().unwrap()).unwrap();
+        assert!(matches!(
+            tee::open(None, &dst, &CopyOptions::default()),
+            Err(CopyError::InvalidDestination(_))
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! `--tag-checksums`: keep the digest a `--checksum` run verified on the destination
+//! file itself, so that `usync checksum --cached` can later find files whose contents
+//! changed on disk (bit rot, a bad sector, a stray write) without the source.
+//!
+//! The digest goes into the `user.usync.<algorithm>` extended attribute. Where the
+//! filesystem doesn't take user attributes (FAT, some network and tmpfs mounts), it goes
+//! into a hidden `.NAME.usync-<algorithm>` file next to the file instead, one
+//! `sha256sum`-style line that `sha256sum -c` reads too.
+
+use std::fs;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::checksum::{self, Algorithm};
+use crate::utils;
+
+/// Where a tag was stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stored {
+    Xattr,
+    Sidecar,
+}
+
+/// The extended attribute holding the `algorithm` digest of a file.
+pub fn xattr_name(algorithm: Algorithm) -> String {
+    format!("user.usync.{}", algorithm.name())
+}
+
+/// The sidecar file holding the `algorithm` digest of `path` when xattrs can't.
+pub fn sidecar_path(path: &Path, algorithm: Algorithm) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.usync-{}", name, algorithm.name()))
+}
+
+/// Whether `name` is a sidecar file's name, which the tagged tree didn't have.
+pub fn is_sidecar(name: &str) -> bool {
+    tagged_file(name).is_some()
+}
+
+/// The file the sidecar at the slash-separated path `name` tags, or `None` when `name`
+/// isn't a sidecar.
+pub fn tagged_file(name: &str) -> Option<String> {
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (Some(dir), file),
+        None => (None, name),
+    };
+    let (tagged, suffix) = file.strip_prefix('.')?.rsplit_once(".usync-")?;
+    let algorithm = Algorithm::value_variants()
+        .iter()
+        .any(|algorithm| algorithm.name() == suffix);
+    if !algorithm || tagged.is_empty() {
+        return None;
+    }
+    Some(match dir {
+        Some(dir) => format!("{}/{}", dir, tagged),
+        None => tagged.to_string(),
+    })
+}
+
+/// Store `digest` as the `algorithm` tag of `path`: in an xattr, or else a sidecar file.
+pub fn store(path: &Path, algorithm: Algorithm, digest: &str) -> io::Result<Stored> {
+    if set_xattr(path, &xattr_name(algorithm), digest.as_bytes()).is_ok() {
+        // A sidecar from when this filesystem, or an earlier copy, had no xattrs is stale.
+        let _ = fs::remove_file(sidecar_path(path, algorithm));
+        return Ok(Stored::Xattr);
+    }
+    store_sidecar(path, algorithm, digest)?;
+    Ok(Stored::Sidecar)
+}
+
+/// Write the sidecar tag of `path`.
+pub fn store_sidecar(path: &Path, algorithm: Algorithm, digest: &str) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(
+        sidecar_path(path, algorithm),
+        format!("{}  {}\n", digest, name),
+    )
+}
+
+/// The `algorithm` digest stored on `path`, from its xattr or its sidecar file.
+pub fn load(path: &Path, algorithm: Algorithm) -> Option<String> {
+    let stored = get_xattr(path, &xattr_name(algorithm))
+        .and_then(|value| String::from_utf8(value).ok())
+        .or_else(|| {
+            let line = fs::read_to_string(sidecar_path(path, algorithm)).ok()?;
+            line.split_whitespace().next().map(str::to_string)
+        })?;
+    let digest = stored.trim().to_ascii_lowercase();
+    checksum::is_digest(&digest, algorithm).then_some(digest)
+}
+
+/// Tag every file below `root` (or `root` itself) with its digest from `digests`, keyed
+/// by manifest path as [`checksum::hash_tree`] returns them. Returns how many tags went
+/// into sidecar files.
+pub fn store_tree(
+    root: &Path,
+    algorithm: Algorithm,
+    digests: &std::collections::BTreeMap<String, String>,
+) -> io::Result<usize> {
+    let mut sidecars = 0;
+    for (name, digest) in digests {
+        if is_sidecar(name) {
+            continue;
+        }
+        if store(&root.join(name), algorithm, digest)? == Stored::Sidecar {
+            sidecars += 1;
+        }
+    }
+    Ok(sidecars)
+}
+
+/// What `usync checksum --cached` found.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Checked {
+    pub matched: usize,
+    /// Files whose contents no longer hash to their tag.
+    pub mismatched: Vec<String>,
+    /// Files without a tag, which can't be checked.
+    pub untagged: usize,
+}
+
+/// Re-hash the tagged files below `root` (or `root` itself) and compare each to its
+/// tag. A tagged file that can no longer be read counts as mismatched.
+pub fn check_tree(root: &Path, algorithm: Algorithm) -> io::Result<Checked> {
+    let mut checked = Checked::default();
+    let mut check = |name: String, path: &Path| {
+        if is_sidecar(&name) {
+            return;
+        }
+        match load(path, algorithm) {
+            None => checked.untagged += 1,
+            Some(tag) => match checksum::hash_file(path, algorithm) {
+                Ok(digest) if digest == tag => checked.matched += 1,
+                _ => checked.mismatched.push(name),
+            },
+        }
+    };
+    if root.is_file() {
+        let name = root.file_name().unwrap_or_default().to_string_lossy();
+        check(name.into_owned(), root);
+    } else {
+        let _ = utils::walk_files(root, None, |rel, _| {
+            check(utils::slash_path(rel), &root.join(rel));
+            ControlFlow::Continue(())
+        })?;
+    }
+    Ok(checked)
+}
+
+#[cfg(unix)]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    xattr::get(path, name).ok().flatten()
+}
+
+#[cfg(not(unix))]
+fn get_xattr(_path: &Path, _name: &str) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn test_store_and_load() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("a.txt");
+        fs::write(&file, "hello\n").unwrap();
+        assert_eq!(load(&file, Algorithm::Sha256), None);
+
+        let stored = store(&file, Algorithm::Sha256, HELLO_SHA256).unwrap();
+        assert_eq!(
+            load(&file, Algorithm::Sha256).as_deref(),
+            Some(HELLO_SHA256)
+        );
+        match stored {
+            Stored::Xattr => {
+                assert_eq!(
+                    get_xattr(&file, "user.usync.sha256").as_deref(),
+                    Some(HELLO_SHA256.as_bytes())
+                );
+                assert!(!sidecar_path(&file, Algorithm::Sha256).exists());
+            }
+            // This filesystem has no user xattrs.
+            Stored::Sidecar => assert!(sidecar_path(&file, Algorithm::Sha256).exists()),
+        }
+        // Tags are per algorithm.
+        assert_eq!(load(&file, Algorithm::Md5), None);
+    }
+
+    #[test]
+    fn test_sidecar_fallback() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("b c.txt");
+        fs::write(&file, "hello\n").unwrap();
+        store_sidecar(&file, Algorithm::Sha256, HELLO_SHA256).unwrap();
+        let sidecar = temp.path().join(".b c.txt.usync-sha256");
+        assert_eq!(
+            fs::read_to_string(&sidecar).unwrap(),
+            format!("{}  b c.txt\n", HELLO_SHA256)
+        );
+        assert_eq!(
+            load(&file, Algorithm::Sha256).as_deref(),
+            Some(HELLO_SHA256)
+        );
+        assert!(is_sidecar(".b c.txt.usync-sha256"));
+        assert!(is_sidecar("dir/.x.usync-md5"));
+        assert!(!is_sidecar("b c.txt"));
+        assert!(!is_sidecar(".x.usync-etag"));
+        assert_eq!(
+            tagged_file("dir/.x.y.usync-sha1").as_deref(),
+            Some("dir/x.y")
+        );
+        fs::write(&sidecar, "not a digest  b c.txt\n").unwrap();
+        assert_eq!(load(&file, Algorithm::Sha256), None);
+    }
+
+    #[test]
+    fn test_check_tree() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("good.txt"), "hello\n").unwrap();
+        fs::write(root.join("sub/rotted.txt"), "hello\n").unwrap();
+        fs::write(root.join("untagged.txt"), "new").unwrap();
+        for name in ["good.txt", "sub/rotted.txt"] {
+            store_sidecar(&root.join(name), Algorithm::Sha256, HELLO_SHA256).unwrap();
+        }
+        fs::write(root.join("sub/rotted.txt"), "hellp\n").unwrap();
+
+        let checked = check_tree(root, Algorithm::Sha256).unwrap();
+        assert_eq!(
+            checked,
+            Checked {
+                matched: 1,
+                mismatched: vec!["sub/rotted.txt".to_string()],
+                untagged: 1,
+            }
+        );
+        let single = check_tree(&root.join("good.txt"), Algorithm::Sha256).unwrap();
+        assert_eq!(single.matched, 1);
+    }
+}
@@ -0,0 +1,361 @@
+//! Tee mode (`--also-to`): copy one local file to several destinations while reading
+//! it only once.
+//!
+//! Each chunk read from the source is written to every destination still going. Local
+//! destinations are plain files; SSH and S3 destinations are fed through the stdin of
+//! `ssh 'cat > path'` and `aws s3 cp - url`. A destination that fails is dropped and
+//! reported on its own without stopping the others.
+//!
+//! The same destinations back a stream from stdin (`usync - dest`), which has no source
+//! file to name or take metadata from.
+
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use crate::copy::{self, CopyError, CopyOptions};
+use crate::output;
+use crate::protocol::{Path as ProtocolPath, Protocol, RemotePath};
+use crate::remote::{self, RemoteCopyError};
+use crate::utils;
+
+/// An open destination.
+pub enum Sink {
+    File {
+        path: PathBuf,
+        writer: BufWriter<fs::File>,
+    },
+    Process {
+        tool: &'static str,
+        child: Child,
+        stdin: BufWriter<ChildStdin>,
+        /// Where the upload lands, to check its size once it is done.
+        target: RemotePath,
+    },
+}
+
+impl Sink {
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::File { writer, .. } => writer.write_all(buf),
+            Sink::Process { stdin, .. } => stdin.write_all(buf),
+        }
+    }
+
+    /// Give up on the destination: a local file is removed rather than left behind
+    /// truncated, and an uploader is stopped before it sees EOF.
+    pub fn abort(self) {
+        match self {
+            Sink::File { path, writer } => {
+                drop(writer);
+                let _ = fs::remove_file(path);
+            }
+            Sink::Process { mut child, .. } => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    /// Flush and close the destination, waiting for the upload to finish. Local files
+    /// get the metadata of `src`, when there is one.
+    pub fn finish(
+        self,
+        src: Option<&Path>,
+        size: u64,
+        opts: &CopyOptions,
+    ) -> Result<(), CopyError> {
+        match self {
+            Sink::File { path, writer } => {
+                let io_error = |e: io::Error| CopyError::IoError {
+                    message: format!("Failed to write: {}", path.display()),
+                    error: e,
+                };
+                writer
+                    .into_inner()
+                    .map_err(|e| io_error(e.into_error()))?
+                    .sync_all()
+                    .map_err(io_error)?;
+                let Some(src) = src else { return Ok(()) };
+                if !copy::preserve_metadata(src, &path, opts)? {
+                    output::warn(format_args!(
+                        "extended attributes or ACLs could not be fully preserved on {}",
+                        path.display()
+                    ));
+                }
+                Ok(())
+            }
+            Sink::Process {
+                tool,
+                mut child,
+                stdin,
+                target,
+            } => {
+                // Dropping stdin sends EOF, which is what ends the upload.
+                let flushed = stdin.into_inner().map(drop).map_err(|e| e.into_error());
+                let status = child.wait().map_err(|e| {
+                    CopyError::RemoteError(RemoteCopyError::IoError {
+                        message: format!("Failed to wait for {}", tool),
+                        error: e.to_string(),
+                    })
+                })?;
+                if !status.success() {
+                    return Err(CopyError::RemoteError(RemoteCopyError::IoError {
+                        message: format!("{} failed to write destination", tool),
+                        error: format!("Exit code: {}", status.code().unwrap_or(-1)),
+                    }));
+                }
+                flushed.map_err(|e| {
+                    CopyError::RemoteError(RemoteCopyError::IoError {
+                        message: format!("Failed to stream to {}", tool),
+                        error: e.to_string(),
+                    })
+                })?;
+                match remote::remote_size(&target, &opts.ssh_opts) {
+                    Ok(Some(actual)) if actual != size => Err(CopyError::SizeMismatch {
+                        path: target.url.to_string(),
+                        expected: size,
+                        actual,
+                    }),
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        if opts.verbose {
+                            output::warn(format_args!(
+                                "could not check the size of {}: {}",
+                                target.url, e
+                            ));
+                        }
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn spawn(mut cmd: Command, tool: &'static str, target: RemotePath) -> Result<Sink, CopyError> {
+    let mut child = cmd.stdin(Stdio::piped()).spawn().map_err(|e| {
+        CopyError::RemoteError(RemoteCopyError::IoError {
+            message: format!("Failed to execute {}", tool),
+            error: e.to_string(),
+        })
+    })?;
+    let stdin = child.stdin.take().expect("stdin is piped");
+    Ok(Sink::Process {
+        tool,
+        child,
+        stdin: BufWriter::new(stdin),
+        target,
+    })
+}
+
+/// Open `dst` for writing the contents of the local file `src`, or of a stream when
+/// `src` is `None`. A stream has no name to put inside a directory, so its destination
+/// must name the file.
+pub fn open(src: Option<&Path>, dst: &ProtocolPath, opts: &CopyOptions) -> Result<Sink, CopyError> {
+    let unnamed = || {
+        CopyError::InvalidDestination(
+            "a stream has no file name to use inside a directory; give the destination file"
+                .to_string(),
+        )
+    };
+    match dst {
+        ProtocolPath::Local(local) => {
+            let path = match src {
+                Some(src) => copy::file_destination(src, local.as_path())?,
+                None if local.is_dir() || local.ends_with_separator() => return Err(unnamed()),
+                None => local.as_path().to_path_buf(),
+            };
+            if let Some(src) = src {
+                utils::refuse_same_file(src, &path).map_err(|e| CopyError::IoError {
+                    message: format!("Refusing to write: {}", path.display()),
+                    error: e,
+                })?;
+            }
+            if let Some(parent) = path.parent() {
+                copy::ensure_parent(parent, opts)?;
+            }
+            let file = fs::File::create(&path).map_err(|e| CopyError::IoError {
+                message: format!("Failed to create: {}", path.display()),
+                error: e,
+            })?;
+            Ok(Sink::File {
+                path,
+                writer: BufWriter::new(file),
+            })
+        }
+        ProtocolPath::Remote(remote) => match remote.protocol {
+            _ if src.is_none() && remote.is_dir_hint() => Err(unnamed()),
+            Protocol::Ssh | Protocol::Sftp => {
+                if remote.is_dir_hint() {
+                    remote::ssh_mkdir_p(remote, std::slice::from_ref(&remote.path), &opts.ssh_opts)
+                        .map_err(CopyError::from)?;
+                }
+                let target = stream_target(src, remote)?;
+                let cmd =
+                    remote::ssh_write_command(&target, &opts.ssh_opts).map_err(CopyError::from)?;
+                spawn(cmd, "ssh", target)
+            }
+            Protocol::S3 => {
+                let target = stream_target(src, remote)?;
+                spawn(remote::s3_write_command(&target), "aws", target)
+            }
+            _ => Err(CopyError::UnsupportedProtocol(format!(
+                "Copying to {} protocol is not supported",
+                remote.protocol
+            ))),
+        },
+    }
+}
+
+fn stream_target(src: Option<&Path>, dst: &RemotePath) -> Result<RemotePath, CopyError> {
+    match src {
+        Some(src) => copy::upload_target(src, dst),
+        None => Ok(dst.clone()),
+    }
+}
+
+/// Copy the local file `src` to every destination in `dsts`, reading it once.
+///
+/// Returns one result per destination, in order, with the bytes written on success.
+pub fn tee(
+    src: &Path,
+    dsts: &[ProtocolPath],
+    opts: &CopyOptions,
+) -> Result<Vec<Result<u64, CopyError>>, CopyError> {
+    let mut source = fs::File::open(src).map_err(|e| CopyError::IoError {
+        message: format!("Failed to open source: {}", src.display()),
+        error: e,
+    })?;
+    let size = source
+        .metadata()
+        .map_err(|e| CopyError::IoError {
+            message: format!("Failed to read metadata: {}", src.display()),
+            error: e,
+        })?
+        .len();
+
+    let mut opts = opts.clone();
+    opts.ssh_opts = opts.transit_ssh_opts();
+    let opts = &opts;
+
+    let mut sinks: Vec<Option<Sink>> = Vec::with_capacity(dsts.len());
+    let mut results: Vec<Result<u64, CopyError>> = Vec::with_capacity(dsts.len());
+    for dst in dsts {
+        match open(Some(src), dst, opts) {
+            Ok(sink) => {
+                sinks.push(Some(sink));
+                results.push(Ok(0));
+            }
+            Err(e) => {
+                sinks.push(None);
+                results.push(Err(e));
+            }
+        }
+    }
+
+    let mut buffer = vec![0u8; utils::get_buffer_size(size)];
+    let mut total = 0u64;
+    while sinks.iter().any(Option::is_some) {
+        let n = match source.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                for sink in sinks.into_iter().flatten() {
+                    sink.abort();
+                }
+                return Err(CopyError::IoError {
+                    message: format!("Failed to read source: {}", src.display()),
+                    error: e,
+                });
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        for (slot, result) in sinks.iter_mut().zip(results.iter_mut()) {
+            let Some(sink) = slot else { continue };
+            if let Err(e) = sink.write_all(&buffer[..n]) {
+                // Drop this destination and keep feeding the others.
+                if let Some(sink) = slot.take() {
+                    sink.abort();
+                }
+                *result = Err(CopyError::IoError {
+                    message: "Failed to write destination".to_string(),
+                    error: e,
+                });
+            }
+        }
+    }
+
+    for (slot, result) in sinks.into_iter().zip(results.iter_mut()) {
+        if let Some(sink) = slot {
+            *result = sink.finish(Some(src), total, opts).map(|()| total);
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_tee_reports_each_destination() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("artifact.bin");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &data).unwrap();
+        fs::create_dir_all(temp.path().join("dir")).unwrap();
+        fs::write(temp.path().join("blocker"), "not a directory").unwrap();
+
+        let dsts: Vec<ProtocolPath> = [
+            temp.path().join("copy.bin"),
+            temp.path().join("dir"),
+            temp.path().join("blocker/copy.bin"),
+        ]
+        .iter()
+        .map(|p| parse_path(p.to_str().unwrap()).unwrap())
+        .collect();
+
+        let results = tee(&src, &dsts, &CopyOptions::default()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(*results[0].as_ref().unwrap(), data.len() as u64);
+        assert_eq!(*results[1].as_ref().unwrap(), data.len() as u64);
+        assert!(results[2].is_err());
+        assert_eq!(fs::read(temp.path().join("copy.bin")).unwrap(), data);
+        assert_eq!(
+            fs::read(temp.path().join("dir/artifact.bin")).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_abort_removes_the_partial_file() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("artifact.bin");
+        fs::write(&src, vec![b'x'; 1000]).unwrap();
+        let dst = parse_path(temp.path().join("copy.bin").to_str().unwrap()).unwrap();
+        let mut sink = open(Some(&src), &dst, &CopyOptions::default()).unwrap();
+        sink.write_all(&[b'x'; 100]).unwrap();
+        sink.abort();
+        assert!(!temp.path().join("copy.bin").exists());
+
+        // A source that fails to read takes every local destination with it.
+        #[cfg(unix)]
+        {
+            let unreadable = temp.path().join("dir.bin");
+            fs::create_dir_all(&unreadable).unwrap();
+            let dsts = [
+                parse_path(temp.path().join("a.bin").to_str().unwrap()).unwrap(),
+                parse_path(temp.path().join("b.bin").to_str().unwrap()).unwrap(),
+            ];
+            assert!(tee(&unreadable, &dsts, &CopyOptions::default()).is_err());
+            assert!(!temp.path().join("a.bin").exists());
+            assert!(!temp.path().join("b.bin").exists());
+        }
+    }
+}
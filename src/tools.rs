@@ -0,0 +1,268 @@
+//! The programs remote transfers run: `ssh` and `scp`, `aws`, and `curl` (or `wget`).
+//!
+//! [`check`] looks for the ones a copy between two paths needs before it starts, so that
+//! a missing one is a [`CopyError::ToolMissing`] saying what to install, instead of a
+//! "Failed to execute" after part of the copy is done. Lookups are cached per `PATH`.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::copy::CopyError;
+use crate::protocol::{Path as ProtocolPath, Protocol};
+
+/// A program some remote transfer runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Ssh,
+    Scp,
+    Aws,
+    Curl,
+}
+
+impl Tool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Ssh => "ssh",
+            Tool::Scp => "scp",
+            Tool::Aws => "aws",
+            Tool::Curl => "curl",
+        }
+    }
+
+    /// The programs that will do: HTTP downloads fall back to `wget`.
+    fn binaries(self) -> &'static [&'static str] {
+        match self {
+            Tool::Ssh => &["ssh"],
+            Tool::Scp => &["scp"],
+            Tool::Aws => &["aws"],
+            Tool::Curl => &["curl", "wget"],
+        }
+    }
+
+    /// How to install it here.
+    pub fn hint(self) -> String {
+        self.install().to_string()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn install(self) -> &'static str {
+        match self {
+            Tool::Ssh | Tool::Scp => "Install the openssh-client package (openssh-clients on Fedora and RHEL), e.g. `apt install openssh-client`.",
+            Tool::Aws => "Install the awscli package, e.g. `apt install awscli`, or `pip install awscli`.",
+            Tool::Curl => "Install the curl package, e.g. `apt install curl`; wget works too.",
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn install(self) -> &'static str {
+        match self {
+            Tool::Ssh | Tool::Scp => "ssh and scp come with macOS: check that /usr/bin is on PATH, or `brew install openssh`.",
+            Tool::Aws => "Install the AWS CLI with `brew install awscli`.",
+            Tool::Curl => "curl comes with macOS: check that /usr/bin is on PATH, or `brew install curl`.",
+        }
+    }
+
+    #[cfg(windows)]
+    fn install(self) -> &'static str {
+        match self {
+            Tool::Ssh | Tool::Scp => {
+                "Add the OpenSSH Client optional feature (Settings > System > Optional features)."
+            }
+            Tool::Aws => "Install the AWS CLI with `winget install Amazon.AWSCLI`.",
+            Tool::Curl => {
+                "curl.exe comes with Windows 10 and later; otherwise `winget install cURL.cURL`."
+            }
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    fn install(self) -> &'static str {
+        match self {
+            Tool::Ssh | Tool::Scp => {
+                "Install the OpenSSH client (openssh-client) with your package manager."
+            }
+            Tool::Aws => {
+                "Install the AWS CLI (awscli) with your package manager, or `pip install awscli`."
+            }
+            Tool::Curl => "Install curl (or wget) with your package manager.",
+        }
+    }
+}
+
+/// The tools a copy from `src` to `dst` runs. S3 always needs `aws`, `s3-sdk` build or
+/// not: the SDK transfers aren't implemented yet.
+pub fn needed(src: &ProtocolPath, dst: &ProtocolPath) -> Vec<Tool> {
+    let mut tools = Vec::new();
+    for path in [src, dst] {
+        let ProtocolPath::Remote(remote) = path else {
+            continue;
+        };
+        let more: &[Tool] = match remote.protocol {
+            Protocol::Ssh | Protocol::Sftp => &[Tool::Ssh, Tool::Scp],
+            Protocol::S3 => &[Tool::Aws],
+            Protocol::Http | Protocol::Https => &[Tool::Curl],
+            _ => &[],
+        };
+        for tool in more {
+            if !tools.contains(tool) {
+                tools.push(*tool);
+            }
+        }
+    }
+    tools
+}
+
+/// Fail with [`CopyError::ToolMissing`] if a tool a copy from `src` to `dst` needs isn't
+/// on `PATH`.
+pub fn check(src: &ProtocolPath, dst: &ProtocolPath) -> Result<(), CopyError> {
+    check_with(src, dst, |name| which_binary(name).is_some())
+}
+
+fn check_with(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    found: impl Fn(&str) -> bool,
+) -> Result<(), CopyError> {
+    match needed(src, dst)
+        .into_iter()
+        .find(|tool| !tool.binaries().iter().any(|name| found(name)))
+    {
+        Some(tool) => Err(CopyError::ToolMissing {
+            tool: tool.name().to_string(),
+            hint: tool.hint(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Where programs were found, by the `PATH` they were looked up in and their name.
+type Lookups = HashMap<(OsString, String), Option<PathBuf>>;
+
+/// The programs looked up so far.
+static FOUND: Mutex<Option<Lookups>> = Mutex::new(None);
+
+/// Where the program `name` is on `PATH`, as the shell would find it. Each is looked up
+/// once for as long as `PATH` stays the same.
+pub fn which_binary(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let key = (path, name.to_string());
+    let mut found = FOUND.lock().unwrap_or_else(|e| e.into_inner());
+    let found = found.get_or_insert_with(HashMap::new);
+    if let Some(cached) = found.get(&key) {
+        return cached.clone();
+    }
+    let location = which_in(name, &key.0);
+    found.insert(key, location.clone());
+    location
+}
+
+/// Where the program `name` is in the directories of `path`, a `PATH` value.
+pub fn which_in(name: &str, path: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path).find_map(|dir| {
+        let candidate = dir.join(name);
+        if cfg!(windows) {
+            let exe = candidate.with_extension("exe");
+            return [exe, candidate].into_iter().find(|c| executable(c));
+        }
+        executable(&candidate).then_some(candidate)
+    })
+}
+
+#[cfg(unix)]
+fn executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// A `PATH` of two directories, with `names` installed in the second.
+    fn path_with(temp: &TempDir, names: &[&str]) -> OsString {
+        let empty = temp.path().join("empty");
+        let bin = temp.path().join(format!("bin-{}", names.join("-")));
+        fs::create_dir_all(&empty).unwrap();
+        fs::create_dir_all(&bin).unwrap();
+        for name in names {
+            let file = bin.join(if cfg!(windows) {
+                format!("{}.exe", name)
+            } else {
+                name.to_string()
+            });
+            fs::write(&file, "#!/bin/sh\n").unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+        }
+        std::env::join_paths([empty, bin]).unwrap()
+    }
+
+    #[test]
+    fn test_which_in() {
+        let temp = TempDir::new().unwrap();
+        let path = path_with(&temp, &["ssh"]);
+        assert!(which_in("ssh", &path)
+            .unwrap()
+            .starts_with(temp.path().join("bin-ssh")));
+        assert_eq!(which_in("scp", &path), None);
+        assert_eq!(which_in("ssh", OsStr::new("")), None);
+        #[cfg(unix)]
+        {
+            // Not executable: the shell wouldn't run it either.
+            fs::write(temp.path().join("empty/scp"), "").unwrap();
+            assert_eq!(which_in("scp", &path), None);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let temp = TempDir::new().unwrap();
+        let (local, ssh) = (
+            parse_path("./photos/").unwrap(),
+            parse_path("backup@nas:/srv/photos/").unwrap(),
+        );
+        let http = parse_path("https://example.com/file.zip").unwrap();
+        let check_in = |src: &ProtocolPath, dst: &ProtocolPath, path: &OsString| {
+            check_with(src, dst, |name| which_in(name, path).is_some())
+        };
+
+        let nothing = path_with(&temp, &[]);
+        assert!(check_in(&local, &local, &nothing).is_ok());
+        let only_ssh = path_with(&temp, &["ssh"]);
+        match check_in(&local, &ssh, &only_ssh) {
+            Err(CopyError::ToolMissing { tool, hint }) => {
+                assert_eq!(tool, "scp");
+                assert_eq!(hint, Tool::Scp.hint());
+            }
+            other => panic!("expected ToolMissing, got {:?}", other),
+        }
+        assert!(check_in(&ssh, &local, &path_with(&temp, &["ssh", "scp"])).is_ok());
+
+        assert!(matches!(
+            check_in(&http, &local, &nothing),
+            Err(CopyError::ToolMissing { tool, .. }) if tool == "curl"
+        ));
+        assert!(check_in(&http, &local, &path_with(&temp, &["wget"])).is_ok());
+
+        let s3 = parse_path("s3://bucket/backup/").unwrap();
+        assert!(matches!(
+            check_in(&local, &s3, &nothing),
+            Err(CopyError::ToolMissing { tool, .. }) if tool == "aws"
+        ));
+        assert!(check_in(&s3, &local, &path_with(&temp, &["aws"])).is_ok());
+        assert_eq!(needed(&ssh, &ssh), [Tool::Ssh, Tool::Scp]);
+    }
+}
@@ -0,0 +1,559 @@
+//! Compressing or decompressing a single file on the way (`--gzip`, `--zstd`,
+//! `--decompress`).
+//!
+//! The data runs through the `gzip` or `zstd` tool as a filter between the source (read
+//! as in [`crate::stream`]) and the destination (opened as for `--also-to`), so any
+//! supported source and destination can be combined. A destination ending in `.gz` or
+//! `.zst` turns compression on by itself when the source isn't already compressed.
+//! `--checksum` always covers the uncompressed data, hashed on the side where it exists.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::checksum::Algorithm;
+use crate::copy::{CopyError, CopyOptions};
+use crate::protocol::Path as ProtocolPath;
+use crate::stream::{self, Source, Streamed};
+use crate::tee;
+
+/// A compression format, handled by its command-line tool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// The extension a file compressed with this codec gets.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => ".gz",
+            Codec::Zstd => ".zst",
+        }
+    }
+
+    /// The codec a file name says it was compressed with.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".gz") || name.ends_with(".tgz") {
+            Some(Codec::Gzip)
+        } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// The codec whose magic number starts `header`.
+    fn from_magic(header: &[u8]) -> Option<Codec> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn command(self, direction: Direction) -> Command {
+        let mut cmd = Command::new(self.name());
+        cmd.arg("-c").arg("-q");
+        if direction == Direction::Decompress {
+            cmd.arg("-d");
+        }
+        cmd
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Compress,
+    Decompress,
+}
+
+/// What to do to the data of a single-file copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    pub codec: Codec,
+    pub direction: Direction,
+}
+
+/// The flags that ask for a transform.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransformFlags {
+    pub gzip: bool,
+    pub zstd: bool,
+    pub decompress: bool,
+}
+
+fn name_of(path: &ProtocolPath) -> String {
+    match path {
+        ProtocolPath::Local(local) => local.to_string_lossy().into_owned(),
+        ProtocolPath::Remote(remote) => remote.path.clone(),
+    }
+}
+
+/// Whether `path` names a directory to copy into rather than the file to write.
+fn is_dir_destination(path: &ProtocolPath) -> bool {
+    match path {
+        ProtocolPath::Local(local) => local.is_dir() || local.ends_with_separator(),
+        ProtocolPath::Remote(remote) => remote.is_dir_hint(),
+    }
+}
+
+/// Decide on the transform for a copy of `src` to `dst`: the one asked for by flag, or
+/// compression when `dst` names a `.gz`/`.zst` file and `src` isn't compressed already.
+pub fn detect(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    flags: TransformFlags,
+) -> Result<Option<Transform>, String> {
+    if flags.gzip && flags.zstd {
+        return Err("--gzip and --zstd can't be used together".to_string());
+    }
+    let forced = if flags.gzip {
+        Some(Codec::Gzip)
+    } else if flags.zstd {
+        Some(Codec::Zstd)
+    } else {
+        None
+    };
+
+    if flags.decompress {
+        if forced.is_some() {
+            return Err("--decompress can't be combined with --gzip or --zstd".to_string());
+        }
+        let codec = Codec::from_name(&name_of(src))
+            .or_else(|| match src {
+                ProtocolPath::Local(local) => sniff(local.as_path()),
+                ProtocolPath::Remote(_) => None,
+            })
+            .ok_or_else(|| {
+                format!(
+                    "can't tell how {} is compressed; it should end in .gz or .zst",
+                    name_of(src)
+                )
+            })?;
+        return Ok(Some(Transform {
+            codec,
+            direction: Direction::Decompress,
+        }));
+    }
+
+    let codec = match forced {
+        Some(codec) => codec,
+        None if is_dir_destination(dst) => return Ok(None),
+        None => match Codec::from_name(&name_of(dst)) {
+            Some(codec) if Codec::from_name(&name_of(src)).is_none() && !is_compressed(src) => {
+                codec
+            }
+            _ => return Ok(None),
+        },
+    };
+    Ok(Some(Transform {
+        codec,
+        direction: Direction::Compress,
+    }))
+}
+
+fn sniff(path: &Path) -> Option<Codec> {
+    let mut header = [0u8; 4];
+    let n = std::fs::File::open(path).ok()?.read(&mut header).ok()?;
+    Codec::from_magic(&header[..n])
+}
+
+/// Whether a local `src` already holds compressed data, whatever its name.
+fn is_compressed(src: &ProtocolPath) -> bool {
+    match src {
+        ProtocolPath::Local(local) => local.is_file() && sniff(local.as_path()).is_some(),
+        ProtocolPath::Remote(_) => false,
+    }
+}
+
+/// Where the transformed copy of `src` lands: `dst` itself, or inside it when it names a
+/// directory, under the source's name with the codec's extension added or removed.
+pub fn destination(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    transform: Transform,
+) -> Result<ProtocolPath, CopyError> {
+    if !is_dir_destination(dst) {
+        return Ok(dst.clone());
+    }
+    let src_name = name_of(src);
+    let base = src_name
+        .trim_end_matches(['/', '\\'])
+        .rsplit(['/', '\\'])
+        .next()
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| CopyError::InvalidSource("Source file has no name".to_string()))?;
+    let name = match transform.direction {
+        Direction::Compress => format!("{}{}", base, transform.codec.extension()),
+        Direction::Decompress => {
+            let lower = base.to_ascii_lowercase();
+            if lower.ends_with(".tgz") || lower.ends_with(".tzst") {
+                let stem = &base[..base.rfind('.').unwrap_or(base.len())];
+                format!("{}.tar", stem)
+            } else {
+                let ext = transform.codec.extension();
+                match lower.strip_suffix(ext) {
+                    Some(stem) if !stem.is_empty() => base[..stem.len()].to_string(),
+                    _ => format!("{}.out", base),
+                }
+            }
+        }
+    };
+    Ok(match dst {
+        ProtocolPath::Local(local) => {
+            ProtocolPath::Local(crate::path::LocalPath::new(local.as_path().join(name)))
+        }
+        ProtocolPath::Remote(remote) => ProtocolPath::Remote(remote.join(&name)),
+    })
+}
+
+/// Bytes on each side of the codec.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransformStats {
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+    /// Digest of the uncompressed data, when `--checksum` asked for one.
+    pub digest: Option<String>,
+}
+
+impl TransformStats {
+    /// The uncompressed side as one end of a `--checksum` comparison.
+    pub fn streamed(&self) -> Streamed {
+        Streamed {
+            bytes: self.raw_bytes,
+            digest: self.digest.clone(),
+        }
+    }
+}
+
+/// Copy the file `src` to `dst` (already resolved with [`destination`]) through the
+/// codec's tool, hashing the uncompressed side with `algorithm`.
+pub fn copy(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    transform: Transform,
+    opts: &CopyOptions,
+    algorithm: Option<Algorithm>,
+) -> Result<TransformStats, CopyError> {
+    let mut opts = opts.clone();
+    opts.ssh_opts = opts.transit_ssh_opts();
+    let opts = &opts;
+    let tool = transform.codec.name();
+    let tool_error = |message: String, error: String| {
+        CopyError::RemoteError(crate::remote::RemoteCopyError::IoError { message, error })
+    };
+
+    let mut source = Source::open(src, opts)?;
+    let mut filter = transform
+        .codec
+        .command(transform.direction)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| tool_error(format!("Failed to execute {}", tool), e.to_string()))?;
+    let mut filter_in = filter.stdin.take().expect("stdin is piped");
+    let mut filter_out = filter.stdout.take().expect("stdout is piped");
+    let mut sink = tee::open(None, dst, opts)?;
+
+    let compress = transform.direction == Direction::Compress;
+    let (fed, drained) = std::thread::scope(|scope| {
+        let source = &mut source;
+        let feeder = scope.spawn(move || {
+            let fed = stream::pump(
+                source,
+                |buf| filter_in.write_all(buf),
+                algorithm.filter(|_| compress),
+                opts.progress && compress,
+            );
+            // Closing the tool's stdin is what lets it finish.
+            drop(filter_in);
+            fed
+        });
+        let drained = stream::pump(
+            &mut filter_out,
+            |buf| sink.write_all(buf),
+            algorithm.filter(|_| !compress),
+            opts.progress && !compress,
+        );
+        // If writing failed, closing the tool's stdout stops it, and with it the feeder.
+        drop(filter_out);
+        (feeder.join().expect("feeder thread panicked"), drained)
+    });
+    let status = filter
+        .wait()
+        .map_err(|e| tool_error(format!("Failed to wait for {}", tool), e.to_string()))?;
+
+    let drained = match drained {
+        Ok(Ok(drained)) => drained,
+        Ok(Err(e)) => {
+            sink.abort();
+            let _ = source.finish();
+            return Err(tool_error(
+                format!("Failed to read from {}", tool),
+                e.to_string(),
+            ));
+        }
+        Err(e) => {
+            sink.abort();
+            let _ = source.finish();
+            return Err(CopyError::IoError {
+                message: "Failed to write destination".to_string(),
+                error: e,
+            });
+        }
+    };
+    let fed = match fed {
+        Ok(Ok(fed)) => Ok(fed),
+        Ok(Err(e)) => Err(source.read_error(e)),
+        // The tool stopped reading; its exit status says why.
+        Err(e) => Err(tool_error(
+            format!("{} stopped reading", tool),
+            e.to_string(),
+        )),
+    };
+    let finished = source.finish();
+    if !status.success() {
+        sink.abort();
+        return Err(tool_error(
+            format!(
+                "{} failed to {} {}",
+                tool,
+                verb(transform.direction),
+                name_of(src)
+            ),
+            format!("Exit code: {}", status.code().unwrap_or(-1)),
+        ));
+    }
+    let fed = match (fed, finished) {
+        (Ok(fed), Ok(())) => fed,
+        (Err(e), _) | (_, Err(e)) => {
+            sink.abort();
+            return Err(e);
+        }
+    };
+
+    let metadata_src = match src {
+        ProtocolPath::Local(local) => Some(local.as_path()),
+        ProtocolPath::Remote(_) => None,
+    };
+    sink.finish(metadata_src, drained.bytes, opts)?;
+
+    let (raw, compressed) = if compress {
+        (fed, drained)
+    } else {
+        (drained, fed)
+    };
+    Ok(TransformStats {
+        raw_bytes: raw.bytes,
+        compressed_bytes: compressed.bytes,
+        digest: raw.digest,
+    })
+}
+
+fn verb(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Compress => "compress",
+        Direction::Decompress => "decompress",
+    }
+}
+
+/// Print the two sizes of a transformed copy, e.g. `10485760 -> 2311204 bytes (22.0%)`.
+pub fn describe(stats: &TransformStats) -> String {
+    let ratio = if stats.raw_bytes == 0 {
+        100.0
+    } else {
+        stats.compressed_bytes as f64 * 100.0 / stats.raw_bytes as f64
+    };
+    format!(
+        "{} bytes uncompressed, {} bytes compressed ({:.1}%)",
+        stats.raw_bytes, stats.compressed_bytes, ratio
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use tempfile::TempDir;
+
+    fn path(s: &str) -> ProtocolPath {
+        parse_path(s).unwrap()
+    }
+
+    #[test]
+    fn test_detect() {
+        let none = TransformFlags::default();
+        let compress = |codec| {
+            Some(Transform {
+                codec,
+                direction: Direction::Compress,
+            })
+        };
+        assert_eq!(
+            detect(&path("/nonexistent/a.csv"), &path("/tmp/a.csv.zst"), none),
+            Ok(compress(Codec::Zstd))
+        );
+        assert_eq!(
+            detect(&path("/nonexistent/a.csv"), &path("/tmp/a.csv"), none),
+            Ok(None)
+        );
+        // Already compressed: copied as is.
+        assert_eq!(
+            detect(&path("/nonexistent/a.tgz"), &path("/tmp/b.gz"), none),
+            Ok(None)
+        );
+        let gzip = TransformFlags { gzip: true, ..none };
+        assert_eq!(
+            detect(&path("/nonexistent/a.csv"), &path("ssh://host/srv/a"), gzip),
+            Ok(compress(Codec::Gzip))
+        );
+        let decompress = TransformFlags {
+            decompress: true,
+            ..none
+        };
+        assert_eq!(
+            detect(&path("s3://bucket/a.csv.zst"), &path("/tmp/"), decompress),
+            Ok(Some(Transform {
+                codec: Codec::Zstd,
+                direction: Direction::Decompress
+            }))
+        );
+        assert!(detect(&path("/nonexistent/a.csv"), &path("/tmp/"), decompress).is_err());
+        let both = TransformFlags {
+            gzip: true,
+            zstd: true,
+            ..none
+        };
+        assert!(detect(&path("a"), &path("b"), both).is_err());
+    }
+
+    #[test]
+    fn test_destination_names() {
+        let temp = TempDir::new().unwrap();
+        let dir = format!("{}/", temp.path().display());
+        let name = |src: &str, codec, direction| match destination(
+            &path(src),
+            &path(&dir),
+            Transform { codec, direction },
+        )
+        .unwrap()
+        {
+            ProtocolPath::Local(p) => p
+                .as_path()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            ProtocolPath::Remote(r) => r.path,
+        };
+        assert_eq!(
+            name("/data/big.csv", Codec::Zstd, Direction::Compress),
+            "big.csv.zst"
+        );
+        assert_eq!(
+            name("/data/big.csv.gz", Codec::Gzip, Direction::Decompress),
+            "big.csv"
+        );
+        assert_eq!(
+            name("/data/site.tgz", Codec::Gzip, Direction::Decompress),
+            "site.tar"
+        );
+        assert_eq!(
+            name("/data/blob", Codec::Gzip, Direction::Decompress),
+            "blob.out"
+        );
+
+        let remote = destination(
+            &path("/data/big.csv"),
+            &path("ssh://host/archive/"),
+            Transform {
+                codec: Codec::Gzip,
+                direction: Direction::Compress,
+            },
+        )
+        .unwrap();
+        let ProtocolPath::Remote(remote) = remote else {
+            panic!("expected a remote destination");
+        };
+        assert_eq!(remote.path, "/archive/big.csv.gz");
+    }
+
+    #[test]
+    fn test_magic_numbers() {
+        assert_eq!(Codec::from_magic(&[0x1f, 0x8b, 8, 0]), Some(Codec::Gzip));
+        assert_eq!(
+            Codec::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(Codec::Zstd)
+        );
+        assert_eq!(Codec::from_magic(b"text"), None);
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        if Command::new("gzip").arg("--version").output().is_err() {
+            return;
+        }
+        let temp = TempDir::new().unwrap();
+        let original = temp.path().join("data.txt");
+        let data = "line of text\n".repeat(20_000);
+        std::fs::write(&original, &data).unwrap();
+        let packed = temp.path().join("data.txt.gz");
+        let unpacked = temp.path().join("back.txt");
+
+        let gzip = Transform {
+            codec: Codec::Gzip,
+            direction: Direction::Compress,
+        };
+        let opts = CopyOptions::default();
+        let stats = copy(
+            &path(original.to_str().unwrap()),
+            &path(packed.to_str().unwrap()),
+            gzip,
+            &opts,
+            Some(Algorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(stats.raw_bytes, data.len() as u64);
+        assert_eq!(
+            stats.compressed_bytes,
+            std::fs::metadata(&packed).unwrap().len()
+        );
+        assert!(stats.compressed_bytes < stats.raw_bytes);
+        assert_eq!(
+            stats.digest.as_deref(),
+            Some(
+                crate::checksum::hash_file(&original, Algorithm::Sha256)
+                    .unwrap()
+                    .as_str()
+            )
+        );
+
+        let gunzip = Transform {
+            direction: Direction::Decompress,
+            ..gzip
+        };
+        let back = copy(
+            &path(packed.to_str().unwrap()),
+            &path(unpacked.to_str().unwrap()),
+            gunzip,
+            &opts,
+            Some(Algorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&unpacked).unwrap(), data);
+        assert_eq!(back.digest, stats.digest);
+        assert_eq!(back.compressed_bytes, stats.compressed_bytes);
+    }
+}
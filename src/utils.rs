@@ -1,11 +1,17 @@
 use std::fs;
 use std::io;
+use std::ops::ControlFlow;
 use std::path::Path;
 
+// The copy functions below write into a directory that exists: the caller created it,
+// once, rather than each file checking it again.
+
+const LARGE_BUFFER: usize = 64 * 1024;
+
 #[inline]
 pub fn get_buffer_size(file_size: u64) -> usize {
     if file_size > 1_048_576 {
-        64 * 1024
+        LARGE_BUFFER
     } else {
         8 * 1024
     }
@@ -16,15 +22,28 @@ pub fn copy_file_buffered(src: &Path, dst: &Path) -> io::Result<u64> {
     copy_file_buffered_with_resume(src, dst, 0)
 }
 
+/// [`copy_file_buffered`] with the larger buffer whatever the size: for files too big to
+/// copy through memory.
+#[inline]
+pub fn copy_file_buffered_large(src: &Path, dst: &Path) -> io::Result<u64> {
+    copy_buffered(src, dst, 0, Some(LARGE_BUFFER))
+}
+
 pub fn copy_file_buffered_with_resume(src: &Path, dst: &Path, resume_from: u64) -> io::Result<u64> {
-    use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+    copy_buffered(src, dst, resume_from, None)
+}
 
-    if let Some(parent) = dst.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
-    }
+/// Copy `src` from `resume_from` on, with a buffer of `buffer_size` or the one
+/// [`get_buffer_size`] picks.
+fn copy_buffered(
+    src: &Path,
+    dst: &Path,
+    resume_from: u64,
+    buffer_size: Option<usize>,
+) -> io::Result<u64> {
+    use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 
+    refuse_same_file(src, dst)?;
     let mut src_file = fs::File::open(src)?;
     let mut dst_file = if resume_from > 0 && dst.exists() {
         fs::OpenOptions::new().write(true).open(dst)?
@@ -38,7 +57,7 @@ pub fn copy_file_buffered_with_resume(src: &Path, dst: &Path, resume_from: u64)
     }
 
     let file_size = src_file.metadata()?.len();
-    let buffer_size = get_buffer_size(file_size);
+    let buffer_size = buffer_size.unwrap_or_else(|| get_buffer_size(file_size));
 
     let mut reader = BufReader::with_capacity(buffer_size, &mut src_file);
     let mut writer = BufWriter::with_capacity(buffer_size, dst_file);
@@ -63,12 +82,7 @@ pub fn copy_file_buffered_with_resume(src: &Path, dst: &Path, resume_from: u64)
 pub fn copy_file_sendfile(src: &Path, dst: &Path) -> io::Result<u64> {
     use std::os::unix::io::{AsRawFd, RawFd};
 
-    if let Some(parent) = dst.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-
+    refuse_same_file(src, dst)?;
     let src_file = fs::File::open(src)?;
     let dst_file = fs::File::create(dst)?;
 
@@ -78,29 +92,44 @@ pub fn copy_file_sendfile(src: &Path, dst: &Path) -> io::Result<u64> {
     let file_size = src_file.metadata()?.len();
     let mut offset: i64 = 0;
 
-    unsafe {
-        extern "C" {
-            fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+    extern "C" {
+        fn sendfile(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+    }
+    // One call moves at most about 2 GiB; carry on until the whole file is across.
+    while (offset as u64) < file_size {
+        let count = (file_size - offset as u64).min(1 << 30) as usize;
+        let sent = unsafe { sendfile(dst_fd, src_fd, &mut offset, count) };
+        if sent < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
         }
-        let result = sendfile(dst_fd, src_fd, &mut offset, file_size as usize);
-        if result < 0 {
-            return Err(io::Error::last_os_error());
+        if sent == 0 {
+            // The source got shorter while it was being copied.
+            break;
         }
     }
 
-    Ok(file_size)
+    Ok(offset as u64)
 }
 
+/// `copyfile(3)` state flags.
 #[cfg(target_os = "macos")]
-pub fn copy_file_range_macos(src: &Path, dst: &Path) -> io::Result<u64> {
-    use std::ffi::CString;
+const COPYFILE_ACL: u32 = 1 << 0;
+#[cfg(target_os = "macos")]
+const COPYFILE_XATTR: u32 = 1 << 2;
+#[cfg(target_os = "macos")]
+const COPYFILE_DATA: u32 = 1 << 3;
 
-    if let Some(parent) = dst.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
-        }
-    }
+/// Copy with macOS `copyfile`; with `xattrs` the extended attributes (including resource
+/// forks) and ACLs are carried over as well.
+#[cfg(target_os = "macos")]
+pub fn copy_file_range_macos(src: &Path, dst: &Path, xattrs: bool) -> io::Result<u64> {
+    use std::ffi::CString;
 
+    refuse_same_file(src, dst)?;
     let file_size = fs::metadata(src)?.len();
 
     unsafe {
@@ -126,7 +155,11 @@ pub fn copy_file_range_macos(src: &Path, dst: &Path) -> io::Result<u64> {
             src_cstr.as_ptr(),
             dst_cstr.as_ptr(),
             std::ptr::null_mut(),
-            0x0001,
+            if xattrs {
+                COPYFILE_DATA | COPYFILE_XATTR | COPYFILE_ACL
+            } else {
+                COPYFILE_DATA
+            },
         );
 
         if result == 0 {
@@ -147,32 +180,694 @@ pub fn copy_file_range_macos(src: &Path, dst: &Path) -> io::Result<u64> {
 
 #[cfg(not(target_os = "macos"))]
 #[allow(dead_code)]
-pub fn copy_file_range_macos(_src: &Path, _dst: &Path) -> io::Result<u64> {
+pub fn copy_file_range_macos(_src: &Path, _dst: &Path, _xattrs: bool) -> io::Result<u64> {
     copy_file_buffered(_src, _dst)
 }
 
+/// Create `dst` as a copy-on-write clone of `src` (`FICLONE` on Linux, e.g. btrfs or
+/// XFS). `dst` must not exist. Fails with `Unsupported` where the filesystem can't.
+#[cfg(target_os = "linux")]
+pub fn reflink_file(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOW(0x94, 9, int)`
+    const FICLONE: std::ffi::c_ulong = 0x4004_9409;
+    extern "C" {
+        fn ioctl(fd: i32, request: std::ffi::c_ulong, ...) -> i32;
+    }
+
+    let src_file = fs::File::open(src)?;
+    let dst_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    // SAFETY: both descriptors are open for the duration of the call.
+    let result = unsafe { ioctl(dst_file.as_raw_fd(), FICLONE, src_file.as_raw_fd()) };
+    if result < 0 {
+        let err = io::Error::last_os_error();
+        drop(dst_file);
+        let _ = fs::remove_file(dst);
+        return Err(match err.raw_os_error() {
+            // EOPNOTSUPP, EXDEV, EINVAL: not a reflink-capable filesystem or pair.
+            Some(95) | Some(18) | Some(22) => io::Error::new(io::ErrorKind::Unsupported, err),
+            _ => err,
+        });
+    }
+    Ok(())
+}
+
+/// Create `dst` as a copy-on-write clone of `src` with `clonefile(2)` (APFS).
+#[cfg(target_os = "macos")]
+pub fn reflink_file(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        fn clonefile(src: *const i8, dst: *const i8, flags: u32) -> i32;
+    }
+
+    let to_c = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+    };
+    let (src_c, dst_c) = (to_c(src)?, to_c(dst)?);
+    // SAFETY: both strings are NUL-terminated and outlive the call.
+    if unsafe { clonefile(src_c.as_ptr(), dst_c.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        let err = io::Error::last_os_error();
+        Err(match err.raw_os_error() {
+            // ENOTSUP, EXDEV
+            Some(45) | Some(18) => io::Error::new(io::ErrorKind::Unsupported, err),
+            _ => err,
+        })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn reflink_file(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+/// An anonymous temporary file in `tmpdir` (`--tmpdir`), or in the system temp directory.
+pub fn scratch_file(tmpdir: Option<&Path>) -> io::Result<fs::File> {
+    match tmpdir {
+        Some(dir) => tempfile::tempfile_in(dir),
+        None => tempfile::tempfile(),
+    }
+}
+
+/// A temporary directory in `tmpdir` (`--tmpdir`), or in the system temp directory.
+/// It is removed with its contents when dropped.
+pub fn scratch_dir(tmpdir: Option<&Path>, prefix: &str) -> io::Result<tempfile::TempDir> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(prefix);
+    match tmpdir {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir(),
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path` (`statvfs`).
+#[cfg(any(
+    all(target_os = "linux", target_pointer_width = "64"),
+    target_os = "macos"
+))]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::{c_ulong, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    #[cfg(target_os = "linux")]
+    type BlockCount = u64;
+    #[cfg(target_os = "macos")]
+    type BlockCount = u32;
+
+    /// The leading fields of `struct statvfs`, padded past the end of the real struct.
+    #[repr(C)]
+    struct StatVfs {
+        f_bsize: c_ulong,
+        f_frsize: c_ulong,
+        _f_blocks: BlockCount,
+        _f_bfree: BlockCount,
+        f_bavail: BlockCount,
+        _rest: [u64; 16],
+    }
+    extern "C" {
+        fn statvfs(path: *const std::ffi::c_char, buf: *mut StatVfs) -> i32;
+    }
+
+    let path_c = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let mut buf = std::mem::MaybeUninit::<StatVfs>::zeroed();
+    // SAFETY: `buf` is larger than `struct statvfs` and the path is NUL-terminated.
+    if unsafe { statvfs(path_c.as_ptr(), buf.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs filled the struct on success.
+    let buf = unsafe { buf.assume_init() };
+    let block = if buf.f_frsize > 0 {
+        buf.f_frsize
+    } else {
+        buf.f_bsize
+    };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    let available = buf.f_bavail as u64 * block as u64;
+    Ok(available)
+}
+
+#[cfg(not(any(
+    all(target_os = "linux", target_pointer_width = "64"),
+    target_os = "macos"
+)))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space can't be queried on this platform",
+    ))
+}
+
 #[cfg(not(target_os = "linux"))]
 #[allow(dead_code)]
 pub fn copy_file_sendfile(src: &Path, dst: &Path) -> io::Result<u64> {
     copy_file_buffered(src, dst)
 }
 
-#[inline]
-pub fn copy_file_via_ram(src: &Path, dst: &Path) -> io::Result<u64> {
-    if let Some(parent) = dst.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)?;
+/// Copy through the native `CopyFileExW`, which keeps timestamps and attributes and is
+/// considerably faster than a user-space read/write loop.
+///
+/// `progress_cb(copied, total)` is called as chunks are written; returning `false` cancels
+/// the copy, in which case Windows removes the partial destination and an error is returned.
+#[cfg(target_os = "windows")]
+pub fn copy_file_windows<F>(src: &Path, dst: &Path, mut progress_cb: F) -> io::Result<u64>
+where
+    F: FnMut(u64, u64) -> bool,
+{
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+
+    refuse_same_file(src, dst)?;
+
+    type ProgressRoutine = unsafe extern "system" fn(
+        i64,
+        i64,
+        i64,
+        i64,
+        u32,
+        u32,
+        *mut c_void,
+        *mut c_void,
+        *mut c_void,
+    ) -> u32;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CopyFileExW(
+            existing: *const u16,
+            new: *const u16,
+            progress: Option<ProgressRoutine>,
+            data: *mut c_void,
+            cancel: *mut i32,
+            flags: u32,
+        ) -> i32;
+    }
+
+    const PROGRESS_CONTINUE: u32 = 0;
+    const PROGRESS_CANCEL: u32 = 1;
+
+    unsafe extern "system" fn routine<F: FnMut(u64, u64) -> bool>(
+        total: i64,
+        transferred: i64,
+        _stream_size: i64,
+        _stream_transferred: i64,
+        _stream_number: u32,
+        _reason: u32,
+        _src: *mut c_void,
+        _dst: *mut c_void,
+        data: *mut c_void,
+    ) -> u32 {
+        let cb = &mut *(data as *mut F);
+        if cb(transferred as u64, total as u64) {
+            PROGRESS_CONTINUE
+        } else {
+            PROGRESS_CANCEL
         }
     }
 
+    let src_wide: Vec<u16> = src.as_os_str().encode_wide().chain(Some(0)).collect();
+    let dst_wide: Vec<u16> = dst.as_os_str().encode_wide().chain(Some(0)).collect();
+
+    let ok = unsafe {
+        CopyFileExW(
+            src_wide.as_ptr(),
+            dst_wide.as_ptr(),
+            Some(routine::<F>),
+            &mut progress_cb as *mut F as *mut c_void,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    fs::metadata(dst).map(|m| m.len())
+}
+
+#[cfg(not(target_os = "windows"))]
+#[allow(dead_code)]
+pub fn copy_file_windows<F>(src: &Path, dst: &Path, mut progress_cb: F) -> io::Result<u64>
+where
+    F: FnMut(u64, u64) -> bool,
+{
+    let bytes = copy_file_buffered(src, dst)?;
+    progress_cb(bytes, bytes);
+    Ok(bytes)
+}
+
+/// Read `src` whole, then write it to `dst`, flushed to disk first with `fsync`.
+///
+/// The destination's size is checked afterwards, so a write cut short (a full disk on a
+/// filesystem that doesn't report it) fails instead of passing as a copy.
+pub fn copy_file_via_ram(src: &Path, dst: &Path, fsync: bool) -> io::Result<u64> {
+    use std::io::Write;
+
+    refuse_same_file(src, dst)?;
     let data = fs::read(src)?;
     let file_size = data.len() as u64;
 
-    fs::write(dst, &data)?;
+    let mut file = fs::File::create(dst)?;
+    file.write_all(&data)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    check_written(dst, &file, file_size)?;
 
     Ok(file_size)
 }
 
+/// Whether `dst` is `src` itself, reached through a hard link or a bind mount: the same
+/// device and inode, or on Windows, where those aren't available, the same canonical
+/// path. A `dst` that doesn't exist yet never is.
+pub fn same_file(src: &Path, dst: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(dst), fs::metadata(src)) {
+            (Ok(d), Ok(s)) => (d.dev(), d.ino()) == (s.dev(), s.ino()),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        match (fs::canonicalize(dst), fs::canonicalize(src)) {
+            (Ok(d), Ok(s)) => d == s,
+            _ => false,
+        }
+    }
+}
+
+/// What identifies a directory however it is reached, through symlinks or bind mounts:
+/// its device and inode, or on Windows its canonical path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DirId(
+    #[cfg(unix)] (u64, u64),
+    #[cfg(not(unix))] std::path::PathBuf,
+);
+
+impl DirId {
+    /// The identity of the directory at `path`, following symlinks.
+    pub fn of(path: &Path) -> Option<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            fs::metadata(path).ok().map(|m| DirId((m.dev(), m.ino())))
+        }
+        #[cfg(not(unix))]
+        {
+            fs::canonicalize(path).ok().map(DirId)
+        }
+    }
+}
+
+/// Fail before `dst` is opened for writing when it is [`same_file`] as `src`: creating
+/// it would truncate the source before a byte of it was read.
+pub(crate) fn refuse_same_file(src: &Path, dst: &Path) -> io::Result<()> {
+    if !same_file(src, dst) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} and {} are the same file", src.display(), dst.display()),
+    ))
+}
+
+/// Fail unless `file`, written to `dst`, holds `expected` bytes.
+fn check_written(dst: &Path, file: &fs::File, expected: u64) -> io::Result<()> {
+    let actual = file.metadata()?.len();
+    if actual == expected {
+        return Ok(());
+    }
+    Err(io::Error::other(format!(
+        "short write: {} is {} bytes, expected {} bytes",
+        dst.display(),
+        actual,
+        expected
+    )))
+}
+
+/// Flush the file at `path` to disk (`--fsync`), for copies written through another
+/// handle.
+pub fn sync_file(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// `rel` with `/` between its components, whatever the platform's separator: the one
+/// form in which relative paths are matched by filters, written to manifests and joined
+/// onto remote paths and S3 keys.
+pub fn slash_path(rel: &Path) -> String {
+    let parts: Vec<_> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+/// Call `f` with each file below `root` (relative to `root`) and its size, one at a time,
+/// so a huge tree is never held in memory. Stops early when `f` breaks.
+///
+/// Within a directory, entries are visited sorted by name, and a subdirectory's files
+/// come where the subdirectory sorts, so the order is stable from run to run. Only one
+/// directory's listing is held at a time per level. With `max_depth`, files nested in
+/// more than `max_depth - 1` directories are skipped (`Some(1)`: only `root`'s own files).
+pub fn walk_files(
+    root: &Path,
+    max_depth: Option<usize>,
+    mut f: impl FnMut(&Path, u64) -> ControlFlow<()>,
+) -> io::Result<ControlFlow<()>> {
+    fn walk(
+        root: &Path,
+        dir: &Path,
+        depth: usize,
+        max_depth: Option<usize>,
+        f: &mut dyn FnMut(&Path, u64) -> ControlFlow<()>,
+    ) -> io::Result<ControlFlow<()>> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                if max_depth.is_none_or(|max| depth < max)
+                    && walk(root, &path, depth + 1, max_depth, f)?.is_break()
+                {
+                    return Ok(ControlFlow::Break(()));
+                }
+            } else if metadata.is_file() {
+                if let Ok(rel) = path.strip_prefix(root) {
+                    if f(rel, metadata.len()).is_break() {
+                        return Ok(ControlFlow::Break(()));
+                    }
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    if max_depth == Some(0) {
+        return Ok(ControlFlow::Continue(()));
+    }
+    walk(root, root, 1, max_depth, &mut f)
+}
+
+/// Recursively list the files below `root` as paths relative to `root`, with their sizes.
+///
+/// Entries are returned in [`walk_files`] order so callers get a stable transfer order.
+pub fn list_files_relative(root: &Path) -> io::Result<Vec<(std::path::PathBuf, u64)>> {
+    let mut out = Vec::new();
+    walk_files(root, None, |rel, size| {
+        out.push((rel.to_path_buf(), size));
+        ControlFlow::Continue(())
+    })
+    .map(|_| out)
+}
+
+/// Copy permission bits and/or access and modification times from `src` to `dst`.
+pub fn copy_metadata(src: &Path, dst: &Path, mode: bool, times: bool) -> io::Result<()> {
+    let metadata = fs::metadata(src)?;
+
+    // Times first: a read-only mode applied beforehand could prevent opening `dst`.
+    if times {
+        let file_times = fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        let file = fs::File::open(dst).or_else(|_| fs::OpenOptions::new().write(true).open(dst))?;
+        file.set_times(file_times)?;
+    }
+
+    if mode {
+        fs::set_permissions(dst, metadata.permissions())?;
+    }
+
+    Ok(())
+}
+
+/// Copy every extended attribute of `src` onto `dst`, following symlinks on both sides.
+///
+/// All attributes are attempted; the first failure is returned afterwards. A filesystem
+/// without xattr support on either side fails with that error (usually `ENOTSUP`).
+#[cfg(unix)]
+pub fn copy_xattrs(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut first_error = None;
+    for name in xattr::list_deref(src)? {
+        let result = match xattr::get_deref(src, &name) {
+            Ok(Some(value)) => xattr::set_deref(dst, &name, &value),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(not(unix))]
+pub fn copy_xattrs(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are not supported on this platform",
+    ))
+}
+
+/// The extended attributes Linux keeps a file's access ACL and a directory's default
+/// ACL in.
+#[cfg(target_os = "linux")]
+const POSIX_ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Copy the POSIX access ACL of `src`, and the default ACL of a directory, onto `dst`.
+///
+/// An ACL `src` doesn't have is removed from `dst`, so one inherited from the
+/// destination's parent doesn't widen access. Fails with `ENOTSUP` when `src` has an ACL
+/// the destination filesystem can't store.
+#[cfg(target_os = "linux")]
+pub fn copy_acls(src: &Path, dst: &Path) -> io::Result<()> {
+    for name in POSIX_ACL_XATTRS {
+        match xattr::get_deref(src, name)? {
+            Some(value) => xattr::set_deref(dst, name, &value)?,
+            // Nothing to clear where ACLs aren't supported at all.
+            None => {
+                if let Ok(Some(_)) = xattr::get_deref(dst, name) {
+                    xattr::remove_deref(dst, name)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copy the ACL of `src` onto `dst` with `copyfile`.
+#[cfg(target_os = "macos")]
+pub fn copy_acls(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        fn copyfile(
+            from: *const i8,
+            to: *const i8,
+            state: *mut std::ffi::c_void,
+            flags: u32,
+        ) -> i32;
+    }
+
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte");
+    let src_cstr = CString::new(src.as_os_str().as_bytes()).map_err(invalid)?;
+    let dst_cstr = CString::new(dst.as_os_str().as_bytes()).map_err(invalid)?;
+    let result = unsafe {
+        copyfile(
+            src_cstr.as_ptr(),
+            dst_cstr.as_ptr(),
+            std::ptr::null_mut(),
+            COPYFILE_ACL,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn copy_acls(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ACLs are only supported on Linux and macOS",
+    ))
+}
+
+/// A file that is neither regular, a directory nor a symlink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Special {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl Special {
+    /// What `file_type` is, if it is special.
+    #[cfg(unix)]
+    pub fn of(file_type: fs::FileType) -> Option<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if file_type.is_fifo() {
+            Some(Special::Fifo)
+        } else if file_type.is_socket() {
+            Some(Special::Socket)
+        } else if file_type.is_char_device() {
+            Some(Special::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(Special::BlockDevice)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_file_type: fs::FileType) -> Option<Self> {
+        None
+    }
+
+    /// Device nodes (`--devices`), as opposed to FIFOs and sockets (`--specials`).
+    pub fn is_device(self) -> bool {
+        matches!(self, Special::CharDevice | Special::BlockDevice)
+    }
+}
+
+impl std::fmt::Display for Special {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Special::Fifo => "FIFO",
+            Special::Socket => "socket",
+            Special::CharDevice => "character device",
+            Special::BlockDevice => "block device",
+        })
+    }
+}
+
+/// Split a device number into its major and minor parts.
+#[cfg(target_os = "linux")]
+pub fn dev_major_minor(rdev: u64) -> (u64, u64) {
+    (
+        ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff),
+        (rdev & 0xff) | ((rdev >> 12) & !0xff),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dev_major_minor(rdev: u64) -> (u64, u64) {
+    ((rdev >> 24) & 0xff, rdev & 0xff_ffff)
+}
+
+/// Recreate the special file `src` at `dst`, replacing a file already there: FIFOs
+/// with `mkfifo`, device nodes with `mknod` (which takes root), and sockets by binding
+/// one. Its permissions are the caller's to set.
+#[cfg(unix)]
+pub fn copy_special(src: &Path, dst: &Path, kind: Special) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use std::process::Command;
+
+    let metadata = fs::metadata(src)?;
+    if fs::symlink_metadata(dst).is_ok() {
+        fs::remove_file(dst)?;
+    }
+    let mut cmd = match kind {
+        Special::Socket => {
+            return std::os::unix::net::UnixListener::bind(dst).map(drop);
+        }
+        Special::Fifo => {
+            let mut cmd = Command::new("mkfifo");
+            cmd.arg("--").arg(dst);
+            cmd
+        }
+        Special::CharDevice | Special::BlockDevice => {
+            let (major, minor) = dev_major_minor(metadata.rdev());
+            let mut cmd = Command::new("mknod");
+            cmd.arg("--")
+                .arg(dst)
+                .arg(if kind == Special::CharDevice {
+                    "c"
+                } else {
+                    "b"
+                })
+                .arg(major.to_string())
+                .arg(minor.to_string());
+            cmd
+        }
+    };
+    let output = cmd.output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn copy_special(_src: &Path, _dst: &Path, _kind: Special) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Special files are only supported on Unix",
+    ))
+}
+
+/// Whether `dst` already is the same kind of special file as `src`: the same device for
+/// device nodes.
+#[cfg(unix)]
+pub fn same_special(src: &Path, dst: &Path, kind: Special) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let (Ok(src), Ok(dst)) = (fs::metadata(src), fs::symlink_metadata(dst)) else {
+        return false;
+    };
+    Special::of(dst.file_type()) == Some(kind) && (!kind.is_device() || src.rdev() == dst.rdev())
+}
+
+#[cfg(not(unix))]
+pub fn same_special(_src: &Path, _dst: &Path, _kind: Special) -> bool {
+    false
+}
+
+/// Recreate the symbolic link `src` at `dst`, replacing an existing file or link.
+#[cfg(unix)]
+pub fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    let target = fs::read_link(src)?;
+
+    if fs::symlink_metadata(dst).is_ok() {
+        fs::remove_file(dst)?;
+    }
+
+    std::os::unix::fs::symlink(target, dst)
+}
+
+#[cfg(not(unix))]
+pub fn copy_symlink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Preserving symlinks is only supported on Unix",
+    ))
+}
+
 #[allow(dead_code)]
 #[inline]
 pub fn get_file_size(path: &Path) -> io::Result<u64> {
@@ -180,7 +875,7 @@ pub fn get_file_size(path: &Path) -> io::Result<u64> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use tempfile::TempDir;
 
@@ -203,4 +898,242 @@ mod tests {
         assert!(dst.exists());
         assert_eq!(fs::read_to_string(&dst).unwrap(), "test content");
     }
+
+    #[test]
+    fn test_copy_file_via_ram_checks_the_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.txt");
+        let dst = temp_dir.path().join("dst.txt");
+        fs::write(&src, "test content").unwrap();
+
+        assert_eq!(copy_file_via_ram(&src, &dst, true).unwrap(), 12);
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "test content");
+
+        // Stands in for a write that came up short without an error.
+        let file = fs::OpenOptions::new().write(true).open(&dst).unwrap();
+        file.set_len(5).unwrap();
+        let error = check_written(&dst, &file, 12).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "short write: {} is 5 bytes, expected 12 bytes",
+                dst.display()
+            )
+        );
+
+        #[cfg(target_os = "linux")]
+        assert!(copy_file_via_ram(&src, Path::new("/dev/full"), false).is_err());
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_copy_file_windows() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.bin");
+        let dst = temp_dir.path().join("dst.bin");
+        let data = vec![7u8; 3 * 1024 * 1024];
+        fs::write(&src, &data).unwrap();
+
+        let mut last = 0;
+        let bytes = copy_file_windows(&src, &dst, |copied, total| {
+            assert_eq!(total, data.len() as u64);
+            last = copied;
+            true
+        })
+        .unwrap();
+
+        assert_eq!(bytes, data.len() as u64);
+        assert_eq!(last, data.len() as u64);
+        assert_eq!(fs::read(&dst).unwrap(), data);
+        assert_eq!(
+            fs::metadata(&src).unwrap().modified().unwrap(),
+            fs::metadata(&dst).unwrap().modified().unwrap()
+        );
+
+        let cancelled = temp_dir.path().join("cancelled.bin");
+        assert!(copy_file_windows(&src, &cancelled, |_, _| false).is_err());
+        assert!(!cancelled.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_xattrs() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.txt");
+        let dst = temp_dir.path().join("dst.txt");
+        fs::write(&src, "tagged").unwrap();
+        fs::write(&dst, "tagged").unwrap();
+
+        // tmpfs and some container filesystems reject user.* attributes.
+        if xattr::set(&src, "user.usync.test", b"blue").is_err() {
+            return;
+        }
+        copy_xattrs(&src, &dst).unwrap();
+        assert_eq!(
+            xattr::get(&dst, "user.usync.test").unwrap(),
+            Some(b"blue".to_vec())
+        );
+    }
+
+    /// `u::rw-,u:<uid>:r--,g::r--,m::r--,o::---` in the kernel's xattr encoding.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn sample_acl() -> Vec<u8> {
+        let uid = fs::metadata("/").map_or(0, |m| std::os::unix::fs::MetadataExt::uid(&m));
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, uid),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_copy_acls() {
+        let temp_dir = TempDir::new().unwrap();
+        let src = temp_dir.path().join("src.txt");
+        let dst = temp_dir.path().join("dst.txt");
+        fs::write(&src, "shared").unwrap();
+        fs::write(&dst, "shared").unwrap();
+
+        // Not every filesystem (or kernel build) stores POSIX ACLs.
+        let acl = sample_acl();
+        if xattr::set(&src, "system.posix_acl_access", &acl).is_err() {
+            return;
+        }
+        copy_acls(&src, &dst).unwrap();
+        assert_eq!(
+            xattr::get(&dst, "system.posix_acl_access").unwrap(),
+            Some(acl.clone())
+        );
+
+        // Copying from a file without one clears it again.
+        let plain = temp_dir.path().join("plain.txt");
+        fs::write(&plain, "plain").unwrap();
+        copy_acls(&plain, &dst).unwrap();
+        assert_eq!(xattr::get(&dst, "system.posix_acl_access").unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_special_fifo_and_socket() {
+        let temp_dir = TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("pipe");
+        if !std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .is_ok_and(|s| s.success())
+        {
+            return;
+        }
+        let socket = temp_dir.path().join("sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+        for (src, kind) in [(&fifo, Special::Fifo), (&socket, Special::Socket)] {
+            assert_eq!(
+                Special::of(fs::metadata(src).unwrap().file_type()),
+                Some(kind)
+            );
+            let dst = temp_dir.path().join(format!("copy-{}", kind));
+            // Whatever is in the way is replaced.
+            fs::write(&dst, "regular").unwrap();
+            assert!(!same_special(src, &dst, kind));
+            copy_special(src, &dst, kind).unwrap();
+            assert!(same_special(src, &dst, kind));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dev_major_minor() {
+        // makedev(8, 1) and makedev(259, 70000)
+        assert_eq!(dev_major_minor(0x801), (8, 1));
+        assert_eq!(dev_major_minor(0x1111_0370), (259, 70000));
+    }
+
+    #[test]
+    fn test_available_space() {
+        let temp_dir = TempDir::new().unwrap();
+        if cfg!(any(
+            all(target_os = "linux", target_pointer_width = "64"),
+            target_os = "macos"
+        )) {
+            assert!(available_space(temp_dir.path()).unwrap() > 0);
+        }
+        assert!(available_space(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_list_files_relative() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("b").join("c")).unwrap();
+        fs::write(root.join("a.txt"), "aa").unwrap();
+        fs::write(root.join("b").join("c").join("d.txt"), "dddd").unwrap();
+
+        let files = list_files_relative(root).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (std::path::PathBuf::from("a.txt"), 2),
+                (std::path::Path::new("b").join("c").join("d.txt"), 4),
+            ]
+        );
+        assert_eq!(slash_path(&files[1].0), "b/c/d.txt");
+    }
+
+    #[test]
+    fn test_walk_files_order_depth_and_early_stop() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("b").join("c")).unwrap();
+        fs::write(root.join("c.txt"), "c").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b").join("z.txt"), "z").unwrap();
+        fs::write(root.join("b").join("c").join("d.txt"), "d").unwrap();
+
+        let walk = |max_depth, limit: usize| {
+            let mut seen = Vec::new();
+            let flow = walk_files(root, max_depth, |rel, _| {
+                seen.push(slash_path(rel));
+                if seen.len() == limit {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+            (seen, flow.is_break())
+        };
+
+        let (all, stopped) = walk(None, usize::MAX);
+        assert_eq!(all, ["a.txt", "b/c/d.txt", "b/z.txt", "c.txt"]);
+        assert!(!stopped);
+        assert_eq!(walk(Some(1), usize::MAX).0, ["a.txt", "c.txt"]);
+        assert_eq!(walk(Some(2), usize::MAX).0, ["a.txt", "b/z.txt", "c.txt"]);
+        assert!(walk(Some(0), usize::MAX).0.is_empty());
+        assert_eq!(
+            walk(None, 2),
+            (vec!["a.txt".into(), "b/c/d.txt".into()], true)
+        );
+    }
+
+    #[test]
+    fn test_slash_path() {
+        assert_eq!(slash_path(Path::new("a.txt")), "a.txt");
+        assert_eq!(slash_path(Path::new("a/./b//c.txt")), "a/b/c.txt");
+        assert_eq!(slash_path(&Path::new("a").join("b")), "a/b");
+        #[cfg(windows)]
+        assert_eq!(slash_path(Path::new(r"a\b\c.txt")), "a/b/c.txt");
+        // Outside Windows a backslash is part of a name, not a separator.
+        #[cfg(unix)]
+        assert_eq!(slash_path(Path::new(r"a\b.txt")), r"a\b.txt");
+    }
 }
@@ -0,0 +1,815 @@
+//! `--checksum`: check a finished copy by fingerprinting each end on its own side.
+//!
+//! Local files are hashed here, SSH files are hashed on the remote host with the
+//! coreutils `<algo>sum` tool, and S3/HTTP objects only expose what their metadata
+//! carries (size, an S3 ETag or `x-amz-checksum-sha256`, HTTP digest headers).
+//! Multipart ETags are recomputed from the local file for the likely part sizes. When a
+//! side still cannot produce the requested digest, [`VerifyMode`] decides between
+//! comparing sizes with a warning and downloading the object to hash it locally.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{self, Algorithm};
+use crate::protocol::{Path as ProtocolPath, Protocol, RemotePath};
+use crate::remote::{self, RemoteCopyError};
+use crate::state::StateFile;
+use crate::utils;
+
+/// What to do when one side of a copy can't produce the requested digest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum VerifyMode {
+    /// Download the object and hash it locally; fail if that isn't possible.
+    Strict,
+    /// Compare sizes instead, with a warning.
+    #[default]
+    BestEffort,
+}
+
+/// What could be learned about one end of a copy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: Option<u64>,
+    /// Lowercase hex digest in the requested algorithm.
+    pub digest: Option<String>,
+    /// ETag of an S3 multipart upload (`<hex>-<part count>`), for MD5 verification.
+    pub multipart_etag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Outcome {
+    /// Both digests were available and equal.
+    Match,
+    /// Only sizes could be compared, and they were equal.
+    SizeMatch,
+    Mismatch(String),
+    Unverifiable(String),
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io { message: String, error: io::Error },
+    Remote(RemoteCopyError),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Io { message, error } => write!(f, "{}: {}", message, error),
+            VerifyError::Remote(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<RemoteCopyError> for VerifyError {
+    fn from(e: RemoteCopyError) -> Self {
+        VerifyError::Remote(e)
+    }
+}
+
+/// One end of a finished copy, with the destination already resolved to the copied file.
+#[derive(Debug, Clone)]
+enum Side {
+    Local(PathBuf),
+    Remote(RemotePath),
+}
+
+impl Side {
+    fn of(path: &ProtocolPath) -> Self {
+        match path {
+            ProtocolPath::Local(p) => Side::Local(p.as_path().to_path_buf()),
+            ProtocolPath::Remote(r) => Side::Remote(r.clone()),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        match self {
+            Side::Local(p) => p.is_dir(),
+            Side::Remote(r) => r.is_dir_hint(),
+        }
+    }
+
+    fn file_name(&self) -> Option<String> {
+        match self {
+            Side::Local(p) => p.file_name().map(|n| n.to_string_lossy().into_owned()),
+            Side::Remote(r) => r.file_name().map(str::to_string),
+        }
+    }
+}
+
+/// Where a file copied from `src` to `dst` ended up, using the same rules as the copy:
+/// into an existing local directory, or into a remote path written with a trailing `/`.
+fn resolve_destination(src: &Side, dst: &Side) -> Side {
+    if src.is_dir() {
+        return dst.clone();
+    }
+    match (dst, src.file_name()) {
+        (Side::Local(p), Some(name)) if p.is_dir() => Side::Local(p.join(name)),
+        (Side::Remote(r), Some(name)) if r.is_dir_hint() => Side::Remote(r.join(&name)),
+        _ => dst.clone(),
+    }
+}
+
+/// Where a copy from `src` to `dst` ended up, when that is on this machine.
+pub fn local_destination(src: &ProtocolPath, dst: &ProtocolPath) -> Option<PathBuf> {
+    match resolve_destination(&Side::of(src), &Side::of(dst)) {
+        Side::Local(path) => Some(path),
+        Side::Remote(_) => None,
+    }
+}
+
+/// Shell script printing `<digest>  -` (when the tool exists) and then the file size.
+fn ssh_script(path: &str, algorithm: Algorithm) -> String {
+    let quoted = remote::shell_quote(path);
+    format!(
+        "{} < {} 2>/dev/null; wc -c < {}",
+        algorithm.tool(),
+        quoted,
+        quoted
+    )
+}
+
+/// Parse the output of [`ssh_script`]. A missing or foreign `<algo>sum` just leaves the
+/// digest empty.
+fn parse_ssh_output(output: &str, algorithm: Algorithm) -> Fingerprint {
+    let mut fingerprint = Fingerprint::default();
+    for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Ok(size) = line.parse() {
+            fingerprint.size = Some(size);
+            break;
+        }
+        let hash = line.split_whitespace().next().unwrap_or_default();
+        if checksum::is_digest(hash, algorithm) {
+            fingerprint.digest = Some(hash.to_ascii_lowercase());
+        }
+    }
+    fingerprint
+}
+
+/// Parse `<ContentLength>\t<ETag>\t<ChecksumSHA256>` from [`remote::s3_head_object`].
+///
+/// A single-part ETag is the object's MD5. A multipart one is kept aside, to be checked
+/// against the other side's file, and a SHA-256 checksum only counts when it covers the
+/// whole object (composite multipart checksums end in `-<parts>` too).
+fn parse_s3_head(output: &str, algorithm: Algorithm) -> Fingerprint {
+    let mut fields = output.split_whitespace();
+    let size = fields.next().and_then(|s| s.parse().ok());
+    let etag = fields
+        .next()
+        .map(|e| {
+            e.trim_matches(|c| c == '"' || c == '\\')
+                .to_ascii_lowercase()
+        })
+        .unwrap_or_default();
+    let sha256 = fields
+        .next()
+        .filter(|c| !c.contains('-'))
+        .and_then(base64_to_hex)
+        .filter(|hex| checksum::is_digest(hex, Algorithm::Sha256));
+
+    let mut fingerprint = Fingerprint {
+        size,
+        ..Fingerprint::default()
+    };
+    match algorithm {
+        Algorithm::Md5 if checksum::is_digest(&etag, algorithm) => fingerprint.digest = Some(etag),
+        Algorithm::Md5 => {
+            let multipart = etag.split_once('-').is_some_and(|(hash, parts)| {
+                checksum::is_digest(hash, algorithm) && parts.parse::<u64>().is_ok()
+            });
+            if multipart {
+                fingerprint.multipart_etag = Some(etag);
+            }
+        }
+        Algorithm::Sha256 => fingerprint.digest = sha256,
+        _ => {}
+    }
+    fingerprint
+}
+
+fn base64_to_hex(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(checksum::to_hex(&bytes))
+}
+
+const MIB: u64 = 1024 * 1024;
+/// Each candidate part size means hashing the whole file once more.
+const MAX_PART_SIZE_GUESSES: usize = 8;
+
+/// Part sizes that split `size` bytes into exactly `parts` parts, most likely first: the
+/// defaults of common S3 clients, then whole MiB upwards from the smallest that fits.
+fn part_size_candidates(size: u64, parts: u64) -> Vec<u64> {
+    if parts <= 1 {
+        return vec![size.max(1)];
+    }
+    let fits = |part_size: u64| part_size > 0 && size.div_ceil(part_size) == parts;
+    let mut candidates: Vec<u64> = [8, 5, 16, 15, 32, 64, 100, 128, 256, 512]
+        .iter()
+        .map(|mib| mib * MIB)
+        .filter(|&part_size| fits(part_size))
+        .collect();
+    let mut part_size = size.div_ceil(parts).div_ceil(MIB) * MIB;
+    while candidates.len() < MAX_PART_SIZE_GUESSES && fits(part_size) {
+        if !candidates.contains(&part_size) {
+            candidates.push(part_size);
+        }
+        part_size += MIB;
+    }
+    candidates.truncate(MAX_PART_SIZE_GUESSES);
+    candidates
+}
+
+/// Give `remote` the digest of the local file when its multipart ETag matches the one
+/// recomputed from that file for a plausible part size. Without a match the object stays
+/// unverified rather than mismatched, since the real part size may just be unusual.
+fn resolve_multipart(
+    remote: &mut Fingerprint,
+    local: &Side,
+    local_print: &Fingerprint,
+) -> Result<(), VerifyError> {
+    let (Some(etag), None, Side::Local(path), Some(size)) = (
+        &remote.multipart_etag,
+        &remote.digest,
+        local,
+        local_print.size,
+    ) else {
+        return Ok(());
+    };
+    let Some(parts) = etag.rsplit_once('-').and_then(|(_, n)| n.parse().ok()) else {
+        return Ok(());
+    };
+    for part_size in part_size_candidates(size, parts) {
+        let local_etag =
+            checksum::s3_multipart_etag(path, part_size).map_err(|error| VerifyError::Io {
+                message: format!("Failed to hash {}", path.display()),
+                error,
+            })?;
+        if local_etag == *etag {
+            remote.digest = local_print.digest.clone();
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A digest header value in hex or base64, if it has the length `algorithm` produces.
+fn decode_digest(value: &str, algorithm: Algorithm) -> Option<String> {
+    let value = value.trim().trim_matches(|c| c == '"' || c == ':');
+    if checksum::is_digest(value, algorithm) {
+        return Some(value.to_ascii_lowercase());
+    }
+    base64_to_hex(value).filter(|hex| checksum::is_digest(hex, algorithm))
+}
+
+/// Size and, when the server offers one, a digest from the final response in `curl -I -L`
+/// output. Digests come from `Content-MD5`, a strong ETag that is a plain MD5,
+/// `X-Checksum-<Algo>` / `X-Amz-Checksum-<Algo>`, and `Digest` / `Repr-Digest`.
+fn parse_http_head(output: &str, algorithm: Algorithm) -> Fingerprint {
+    let digest_name = match algorithm {
+        Algorithm::Md5 => "md5",
+        Algorithm::Sha1 => "sha",
+        Algorithm::Sha256 => "sha-256",
+        Algorithm::Sha512 => "sha-512",
+    };
+    let mut fingerprint = Fingerprint::default();
+    for line in output.lines() {
+        if line.starts_with("HTTP/") {
+            // A redirect's headers describe the redirect, not the file.
+            fingerprint = Fingerprint::default();
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let digest = match name.as_str() {
+            "content-length" => {
+                fingerprint.size = value.trim().parse().ok();
+                None
+            }
+            "content-md5" if algorithm == Algorithm::Md5 => decode_digest(value, algorithm),
+            "etag" if algorithm == Algorithm::Md5 => {
+                // Weak (`W/"…"`) and opaque tags fail the hex check.
+                let tag = value.trim().trim_matches('"');
+                checksum::is_digest(tag, algorithm).then(|| tag.to_ascii_lowercase())
+            }
+            "digest" | "repr-digest" => value.split(',').find_map(|item| {
+                let (name, encoded) = item.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case(digest_name)
+                    .then(|| decode_digest(encoded, algorithm))?
+            }),
+            _ if name == format!("x-checksum-{}", algorithm.name())
+                || name == format!("x-amz-checksum-{}", algorithm.name()) =>
+            {
+                decode_digest(value, algorithm)
+            }
+            _ => None,
+        };
+        if digest.is_some() {
+            fingerprint.digest = digest;
+        }
+    }
+    fingerprint
+}
+
+fn local_fingerprint(path: &Path, algorithm: Algorithm) -> Result<Fingerprint, VerifyError> {
+    let io_err = |error| VerifyError::Io {
+        message: format!("Failed to hash {}", path.display()),
+        error,
+    };
+    let size = fs::metadata(path).map_err(io_err)?.len();
+    let digest = checksum::hash_file(path, algorithm).map_err(io_err)?;
+    Ok(Fingerprint {
+        size: Some(size),
+        digest: Some(digest),
+        ..Fingerprint::default()
+    })
+}
+
+/// Download `src` to a temporary file and hash it.
+fn download_fingerprint(
+    src: &RemotePath,
+    algorithm: Algorithm,
+    tmpdir: Option<&Path>,
+) -> Result<Fingerprint, VerifyError> {
+    let temp = utils::scratch_dir(tmpdir, ".usync-verify-").map_err(|error| VerifyError::Io {
+        message: "Failed to create a temporary directory".to_string(),
+        error,
+    })?;
+    let path = temp.path().join("object");
+    match src.protocol {
+        Protocol::S3 => remote::copy_from_s3_to_file(src, &path, false, false)?,
+        _ => {
+            remote::copy_from_http_to_file(
+                src,
+                &path,
+                false,
+                false,
+                false,
+                &crate::http::HttpChecks::default(),
+            )?;
+        }
+    }
+    local_fingerprint(&path, algorithm)
+}
+
+fn fingerprint(
+    side: &Side,
+    algorithm: Algorithm,
+    ssh_opts: &[String],
+) -> Result<Fingerprint, VerifyError> {
+    let remote = match side {
+        Side::Local(path) => return local_fingerprint(path, algorithm),
+        Side::Remote(r) => r,
+    };
+    Ok(match &remote.protocol {
+        Protocol::Ssh | Protocol::Sftp => {
+            let output =
+                remote::ssh_output(remote, &ssh_script(&remote.path, algorithm), ssh_opts)?;
+            parse_ssh_output(&output, algorithm)
+        }
+        Protocol::S3 => parse_s3_head(&remote::s3_head_object(remote)?, algorithm),
+        Protocol::Http | Protocol::Https => parse_http_head(&remote::http_head(remote)?, algorithm),
+        Protocol::File | Protocol::Unknown(_) => Fingerprint::default(),
+    })
+}
+
+/// Check one end of a stream (`-` as source or destination) against the bytes that went
+/// through stdin or stdout, given as `streamed`.
+pub fn verify_stream(
+    path: &ProtocolPath,
+    streamed: &Fingerprint,
+    algorithm: Algorithm,
+    mode: VerifyMode,
+    ssh_opts: &[String],
+    tmpdir: Option<&Path>,
+) -> Result<Outcome, VerifyError> {
+    let side = Side::of(path);
+    let mut print = fingerprint(&side, algorithm, ssh_opts)?;
+    ensure_digest(&side, &mut print, algorithm, mode, tmpdir)?;
+    Ok(compare(streamed, &print, algorithm, mode))
+}
+
+/// In strict mode, replace a digest-less S3/HTTP fingerprint with the hash of a download.
+fn ensure_digest(
+    side: &Side,
+    print: &mut Fingerprint,
+    algorithm: Algorithm,
+    mode: VerifyMode,
+    tmpdir: Option<&Path>,
+) -> Result<(), VerifyError> {
+    if let Side::Remote(remote) = side {
+        if print.digest.is_none()
+            && mode == VerifyMode::Strict
+            && matches!(
+                remote.protocol,
+                Protocol::S3 | Protocol::Http | Protocol::Https
+            )
+        {
+            *print = download_fingerprint(remote, algorithm, tmpdir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compare two fingerprints: digests when both sides have one, otherwise sizes (only
+/// allowed in best-effort mode).
+fn compare(
+    src: &Fingerprint,
+    dst: &Fingerprint,
+    algorithm: Algorithm,
+    mode: VerifyMode,
+) -> Outcome {
+    if let (Some(a), Some(b)) = (src.size, dst.size) {
+        if a != b {
+            return Outcome::Mismatch(format!("size differs: {} vs {} bytes", a, b));
+        }
+    }
+    match (&src.digest, &dst.digest) {
+        (Some(a), Some(b)) if a == b => Outcome::Match,
+        (Some(a), Some(b)) => {
+            Outcome::Mismatch(format!("{} differs: {} vs {}", algorithm.name(), a, b))
+        }
+        _ if mode == VerifyMode::Strict => Outcome::Unverifiable(format!(
+            "no {} digest available on both sides",
+            algorithm.name()
+        )),
+        _ if src.size.is_some() && dst.size.is_some() => Outcome::SizeMatch,
+        _ => Outcome::Unverifiable("neither a digest nor a size is available".to_string()),
+    }
+}
+
+/// Verify that `dst` holds what `src` held, after a successful copy between them.
+pub fn verify_copy(
+    src: &ProtocolPath,
+    dst: &ProtocolPath,
+    algorithm: Algorithm,
+    mode: VerifyMode,
+    ssh_opts: &[String],
+    tmpdir: Option<&Path>,
+    state: Option<&mut StateFile>,
+) -> Result<Outcome, VerifyError> {
+    let src = Side::of(src);
+    let dst = resolve_destination(&src, &Side::of(dst));
+
+    if src.is_dir() {
+        let (Side::Local(src_dir), Side::Local(dst_dir)) = (&src, &dst) else {
+            return Ok(Outcome::Unverifiable(
+                "directories are only verified for local copies".to_string(),
+            ));
+        };
+        let hash = |dir: &Path, result: io::Result<_>| {
+            result.map_err(|error| VerifyError::Io {
+                message: format!("Failed to hash {}", dir.display()),
+                error,
+            })
+        };
+        let (expected, actual) = match state {
+            Some(state) => (
+                hash(src_dir, state.hash_tree("src", src_dir))?,
+                hash(dst_dir, state.hash_tree("dst", dst_dir))?,
+            ),
+            None => (
+                hash(src_dir, checksum::hash_tree(src_dir, algorithm))?,
+                hash(dst_dir, checksum::hash_tree(dst_dir, algorithm))?,
+            ),
+        };
+        let differing: Vec<&str> = expected
+            .iter()
+            .filter(|(name, digest)| actual.get(*name) != Some(digest))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        return Ok(match differing.first() {
+            None => Outcome::Match,
+            Some(first) => Outcome::Mismatch(format!(
+                "{} file(s) differ, first: {}",
+                differing.len(),
+                first
+            )),
+        });
+    }
+
+    let mut src_print = fingerprint(&src, algorithm, ssh_opts)?;
+    let mut dst_print = fingerprint(&dst, algorithm, ssh_opts)?;
+    resolve_multipart(&mut src_print, &dst, &dst_print)?;
+    resolve_multipart(&mut dst_print, &src, &src_print)?;
+    ensure_digest(&src, &mut src_print, algorithm, mode, tmpdir)?;
+    ensure_digest(&dst, &mut dst_print, algorithm, mode, tmpdir)?;
+    Ok(compare(&src_print, &dst_print, algorithm, mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parse_path;
+    use tempfile::TempDir;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn test_local_against_mocked_ssh_output() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("a.txt");
+        fs::write(&file, "hello\n").unwrap();
+        let local = local_fingerprint(&file, Algorithm::Sha256).unwrap();
+
+        let remote = parse_ssh_output(&format!("{}  -\n6\n", HELLO_SHA256), Algorithm::Sha256);
+        assert_eq!(remote, local);
+        assert_eq!(
+            compare(&local, &remote, Algorithm::Sha256, VerifyMode::Strict),
+            Outcome::Match
+        );
+
+        // `sha256sum` missing on the remote host: only `wc -c` printed.
+        let size_only = parse_ssh_output("       6\n", Algorithm::Sha256);
+        assert_eq!(size_only.size, Some(6));
+        assert_eq!(size_only.digest, None);
+        assert_eq!(
+            compare(
+                &local,
+                &size_only,
+                Algorithm::Sha256,
+                VerifyMode::BestEffort
+            ),
+            Outcome::SizeMatch
+        );
+
+        let corrupted = parse_ssh_output(&format!("{}  -\n6\n", "0".repeat(64)), Algorithm::Sha256);
+        assert!(matches!(
+            compare(
+                &local,
+                &corrupted,
+                Algorithm::Sha256,
+                VerifyMode::BestEffort
+            ),
+            Outcome::Mismatch(_)
+        ));
+    }
+
+    #[test]
+    fn test_s3_etag_only() {
+        let single = "6\t\"b1946ac92492d2347c6235b4d2611184\"\n";
+        assert_eq!(
+            parse_s3_head(single, Algorithm::Md5),
+            Fingerprint {
+                size: Some(6),
+                digest: Some("b1946ac92492d2347c6235b4d2611184".to_string()),
+                multipart_etag: None,
+            }
+        );
+        // An ETag is never a SHA-256, and multipart ETags aren't an MD5 of the content.
+        assert_eq!(parse_s3_head(single, Algorithm::Sha256).digest, None);
+        let multipart = parse_s3_head("6\t\"9b2cf535f27731c974343645a3985328-2\"", Algorithm::Md5);
+        assert_eq!(multipart.digest, None);
+        assert_eq!(
+            multipart.multipart_etag.as_deref(),
+            Some("9b2cf535f27731c974343645a3985328-2")
+        );
+
+        let local = Fingerprint {
+            size: Some(6),
+            digest: Some(HELLO_SHA256.to_string()),
+            multipart_etag: None,
+        };
+        let s3 = parse_s3_head(single, Algorithm::Sha256);
+        assert_eq!(
+            compare(&local, &s3, Algorithm::Sha256, VerifyMode::BestEffort),
+            Outcome::SizeMatch
+        );
+        assert!(matches!(
+            compare(&local, &s3, Algorithm::Sha256, VerifyMode::Strict),
+            Outcome::Unverifiable(_)
+        ));
+        let truncated = parse_s3_head("5\t\"abc\"", Algorithm::Sha256);
+        assert!(matches!(
+            compare(
+                &local,
+                &truncated,
+                Algorithm::Sha256,
+                VerifyMode::BestEffort
+            ),
+            Outcome::Mismatch(_)
+        ));
+    }
+
+    #[test]
+    fn test_s3_checksum_sha256() {
+        let head = "6\t\"9b2cf535f27731c974343645a3985328-2\"\tWJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=\n";
+        assert_eq!(
+            parse_s3_head(head, Algorithm::Sha256).digest.as_deref(),
+            Some(HELLO_SHA256)
+        );
+        let composite = "6\t\"9b2cf535f27731c974343645a3985328-2\"\tWJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=-2";
+        assert_eq!(parse_s3_head(composite, Algorithm::Sha256).digest, None);
+        assert_eq!(
+            parse_s3_head("6\t\"abc\"\tNone", Algorithm::Sha256).digest,
+            None
+        );
+    }
+
+    #[test]
+    fn test_part_size_candidates() {
+        assert_eq!(
+            part_size_candidates(20 * MIB + 1, 3),
+            vec![8 * MIB, 7 * MIB, 9 * MIB, 10 * MIB]
+        );
+        assert_eq!(part_size_candidates(100, 1), vec![100]);
+        assert!(part_size_candidates(10, 3).is_empty());
+    }
+
+    #[test]
+    fn test_multipart_etag_resolves_to_local_digest() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("object");
+        let data: Vec<u8> = (0..2500u32).map(|i| ((i * 7 + 3) % 251) as u8).collect();
+        fs::write(&file, &data).unwrap();
+        let local_side = Side::Local(file.clone());
+        let local = local_fingerprint(&file, Algorithm::Md5).unwrap();
+
+        let mut s3 = parse_s3_head(
+            "2500\t\"4c487a9d1f48177cfbde47cbb9cda2fc-1\"\tNone",
+            Algorithm::Md5,
+        );
+        resolve_multipart(&mut s3, &local_side, &local).unwrap();
+        assert_eq!(
+            compare(&local, &s3, Algorithm::Md5, VerifyMode::Strict),
+            Outcome::Match
+        );
+
+        let mut other = parse_s3_head(
+            "2500\t\"00000000000000000000000000000000-1\"\tNone",
+            Algorithm::Md5,
+        );
+        resolve_multipart(&mut other, &local_side, &local).unwrap();
+        assert_eq!(other.digest, None);
+    }
+
+    #[test]
+    fn test_http_head_uses_final_response() {
+        let head = "HTTP/1.1 302 Found\r\nContent-Length: 0\r\nLocation: /b\r\n\r\n\
+                    HTTP/1.1 200 OK\r\ncontent-length: 1234\r\n\r\n";
+        assert_eq!(parse_http_head(head, Algorithm::Sha256).size, Some(1234));
+    }
+
+    #[test]
+    fn test_http_head_digests() {
+        let head = "HTTP/1.1 200 OK\r\n\
+                    Content-MD5: sZRqySSS0jR8YjW00mERhA==\r\n\
+                    ETag: \"0123\"\r\n\
+                    Repr-Digest: sha-512=:abc=:, sha-256=:WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=:\r\n\r\n";
+        assert_eq!(
+            parse_http_head(head, Algorithm::Md5).digest.as_deref(),
+            Some("b1946ac92492d2347c6235b4d2611184")
+        );
+        assert_eq!(
+            parse_http_head(head, Algorithm::Sha256).digest.as_deref(),
+            Some(HELLO_SHA256)
+        );
+        assert_eq!(parse_http_head(head, Algorithm::Sha512).digest, None);
+
+        let etag_only = "HTTP/1.1 200 OK\r\nETag: \"b1946ac92492d2347c6235b4d2611184\"\r\n";
+        assert!(parse_http_head(etag_only, Algorithm::Md5).digest.is_some());
+        let weak = "HTTP/1.1 200 OK\r\nETag: W/\"b1946ac92492d2347c6235b4d2611184\"\r\n";
+        assert_eq!(parse_http_head(weak, Algorithm::Md5).digest, None);
+        let redirected = format!(
+            "HTTP/1.1 302 Found\r\nX-Checksum-Sha256: {}\r\n\r\nHTTP/1.1 200 OK\r\n",
+            HELLO_SHA256
+        );
+        assert_eq!(parse_http_head(&redirected, Algorithm::Sha256).digest, None);
+    }
+
+    /// Serve `hello\n` at `/with-digest` (advertising its SHA-256) and `/plain`, counting
+    /// the GET requests that actually transfer the body.
+    fn spawn_http_server() -> (
+        std::sync::Arc<tiny_http::Server>,
+        String,
+        std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    ) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let gets = Arc::new(AtomicUsize::new(0));
+        let (worker, counter) = (Arc::clone(&server), Arc::clone(&gets));
+        std::thread::spawn(move || {
+            for request in worker.incoming_requests() {
+                if *request.method() == tiny_http::Method::Get {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                let mut response = tiny_http::Response::from_string("hello\n");
+                if request.url() == "/with-digest" {
+                    let header = format!("X-Checksum-Sha256: {}", HELLO_SHA256);
+                    response.add_header(header.parse::<tiny_http::Header>().unwrap());
+                }
+                let _ = request.respond(response);
+            }
+        });
+        (server, base, gets)
+    }
+
+    #[test]
+    fn test_http_source_not_downloaded_twice() {
+        use std::sync::atomic::Ordering;
+
+        if std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            return;
+        }
+        let (server, base, gets) = spawn_http_server();
+        let temp = TempDir::new().unwrap();
+        let dst = temp.path().join("copy.txt");
+        fs::write(&dst, "hello\n").unwrap();
+        let dst_path = parse_path(dst.to_str().unwrap()).unwrap();
+
+        let src = parse_path(&format!("{}/with-digest", base)).unwrap();
+        let outcome = verify_copy(
+            &src,
+            &dst_path,
+            Algorithm::Sha256,
+            VerifyMode::Strict,
+            &[],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, Outcome::Match);
+        assert_eq!(gets.load(Ordering::SeqCst), 0);
+
+        // Without a digest header, strict mode has to fetch the file once more.
+        let src = parse_path(&format!("{}/plain", base)).unwrap();
+        let outcome = verify_copy(
+            &src,
+            &dst_path,
+            Algorithm::Sha256,
+            VerifyMode::Strict,
+            &[],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, Outcome::Match);
+        assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+        server.unblock();
+    }
+
+    #[test]
+    fn test_verify_local_copy() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("a.txt");
+        let dst_dir = temp.path().join("out");
+        fs::write(&src, "hello\n").unwrap();
+        fs::create_dir(&dst_dir).unwrap();
+        fs::write(dst_dir.join("a.txt"), "hello\n").unwrap();
+
+        let src_path = parse_path(src.to_str().unwrap()).unwrap();
+        let dst_path = parse_path(dst_dir.to_str().unwrap()).unwrap();
+        let verify = || {
+            verify_copy(
+                &src_path,
+                &dst_path,
+                Algorithm::Sha256,
+                VerifyMode::Strict,
+                &[],
+                None,
+                None,
+            )
+        };
+        assert_eq!(verify().unwrap(), Outcome::Match);
+
+        fs::write(dst_dir.join("a.txt"), "jello\n").unwrap();
+        assert!(matches!(verify().unwrap(), Outcome::Mismatch(_)));
+    }
+}
@@ -0,0 +1,1425 @@
+//! The walk behind every local directory copy: [`copy_tree`] reads each source
+//! directory, applies the filters, `--sanitize-names`, `--rename` and
+//! `--case-collisions` to the names in it, copies its files in batches as they are
+//! read and then its subdirectories, in parallel with the `parallel` feature.
+//! `--hard-links` and `--link-dest` are decided here too, per file, by a
+//! [`LinkPlan`] shared by the whole walk.
+//!
+//! With `--order`, the walk only queues the files; once it is over they are sorted and
+//! copied from that one [`Queue`], by as many workers as rayon has with `parallel`, and
+//! only then are the directories' times and permissions set.
+//!
+//! Every directory walked is remembered by [`utils::DirId`], in one set the workers
+//! share, so a symlink that leads back to a directory already copied is skipped with a
+//! warning instead of copied again, forever. A tree deeper than `--depth-limit` is an
+//! error.
+//!
+//! Each subdirectory is counted into [`CopyStats`] of its own, added to its parent's
+//! once it is done, so the totals are the same whether subdirectories were copied one
+//! at a time or side by side.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+#[cfg(feature = "progress")]
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::case::{CaseCollisions, CaseFolder, Claim};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::copy::{apply_metadata, CopyMode, Preserve};
+use crate::copy::{
+    copy_appended, copy_contents, copy_special, copy_symlink, disk_full, local_unchanged,
+    make_dirs, out_of_space, preserve_metadata, renamed, skip_same_file, unreadable, Context,
+    CopyError, CopyOptions, CopyStats, Order, SymlinkPolicy,
+};
+use crate::output;
+use crate::report::{FileError, SlowFiles};
+use crate::utils;
+
+/// Files of one directory listed before they are copied, at most: the memory a very
+/// wide directory takes stays flat, and `--copy-mode uring` still gets batches.
+const FILE_BATCH: usize = 256;
+
+/// What stays the same throughout one directory copy.
+struct Tree<'a> {
+    opts: &'a CopyOptions,
+    links: LinkPlan,
+    case: Option<CaseFolder>,
+    bars: Bars,
+    queue: Option<Queue>,
+    /// Files `--rename` has named so far, for `{counter}`.
+    renamed: AtomicU64,
+    /// The source directory, and the bytes below it if they were listed before the copy.
+    src: PathBuf,
+    planned: Option<u64>,
+    /// Bytes of the source files done with, copied or not: with `planned`, what is left
+    /// when the destination fills up.
+    done: AtomicU64,
+    /// Set once the destination is full, so that no worker starts another file.
+    full: AtomicBool,
+    /// The source directories walked so far, with the path each was first reached by.
+    visited: Mutex<HashMap<utils::DirId, PathBuf>>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(src: &Path, dst: &Path, opts: &'a CopyOptions, stats: &mut CopyStats) -> Self {
+        Tree {
+            opts,
+            links: LinkPlan::new(dst, opts),
+            // Sanitized and templated names can meet too; without a policy, the later one
+            // is renamed.
+            case: opts
+                .case_collisions
+                .or((opts.sanitize_names.is_some() || opts.rename.is_some())
+                    .then_some(CaseCollisions::Rename))
+                .map(CaseFolder::new),
+            bars: Bars::new(src, opts, stats),
+            queue: (opts.order != Order::Default).then(Queue::default),
+            renamed: AtomicU64::new(0),
+            src: src.to_path_buf(),
+            planned: stats.planned.map(|(_, bytes)| bytes),
+            done: AtomicU64::new(0),
+            full: AtomicBool::new(false),
+            visited: Mutex::new(
+                utils::DirId::of(src)
+                    .into_iter()
+                    .map(|id| (id, src.to_path_buf()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Where the directory `dir` was first walked from, if it was already; if not, it is
+    /// now.
+    fn visit(&self, dir: &Path) -> Option<PathBuf> {
+        match locked(&self.visited).entry(utils::DirId::of(dir)?) {
+            Entry::Occupied(first) => Some(first.get().clone()),
+            Entry::Vacant(entry) => {
+                entry.insert(dir.to_path_buf());
+                None
+            }
+        }
+    }
+
+    /// Whether the copy should stop starting files: a limit was reached or the
+    /// destination is full.
+    fn stopped(&self) -> bool {
+        self.opts.limits.stopped() || self.full.load(Ordering::Relaxed)
+    }
+
+    /// `error`, from writing `dst`, as [`CopyError::NoSpace`] when the destination is
+    /// full, which also stops the copy.
+    fn check_space(&self, dst: &Path, error: CopyError) -> CopyError {
+        let CopyError::IoError {
+            error: io_error, ..
+        } = &error
+        else {
+            return error;
+        };
+        if !disk_full(io_error) {
+            return error;
+        }
+        self.full.store(true, Ordering::Relaxed);
+        let planned = self.planned.or_else(|| {
+            crate::copy::prescan(&self.src, self.opts, &mut CopyStats::new_minimal())
+                .map(|(_, bytes)| bytes)
+        });
+        let done = self.done.load(Ordering::Relaxed);
+        out_of_space(dst, planned.map(|planned| planned.saturating_sub(done)))
+    }
+
+    /// Copy the files queued for `--order`, and finish the bars.
+    fn finish(&self, stats: &mut CopyStats) -> Result<(), CopyError> {
+        if let Some(queue) = &self.queue {
+            queue.copy(self, stats)?;
+        }
+        self.bars.finish();
+        Ok(())
+    }
+}
+
+/// Copy what is below the directory `src` into the directory `dst`, which exists.
+pub(crate) fn copy_tree(
+    src: &Path,
+    dst: &Path,
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let tree = Tree::new(src, dst, opts, stats);
+    walk(src, dst, 0, &tree, stats)?;
+    tree.finish(stats)
+}
+
+/// `--files-from`: copy the entries of `list`, paths relative to the directory `src`, to
+/// the same paths below the directory `dst`, which exists, without reading `src` itself.
+/// A listed directory is created, but only the files listed below it are copied.
+pub(crate) fn copy_listed(
+    src: &Path,
+    dst: &Path,
+    list: &[PathBuf],
+    opts: &CopyOptions,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let tree = Tree::new(src, dst, opts, stats);
+    let verbose = opts.verbose && !opts.progress;
+    // Each destination directory is made once, however many entries it holds.
+    let mut made = std::collections::HashSet::new();
+    let mut make = |dir: &Path, stats: &mut CopyStats| -> Result<(), CopyError> {
+        if !made.contains(dir) {
+            if make_dirs(dir, opts)? {
+                stats.dirs_created += 1;
+            }
+            made.insert(dir.to_path_buf());
+        }
+        Ok(())
+    };
+    let mut files = Vec::with_capacity(FILE_BATCH);
+    for rel in list {
+        if tree.stopped() {
+            break;
+        }
+        stats.files_listed += 1;
+        let src_path = src.join(rel);
+        let link = opts.symlinks == SymlinkPolicy::Preserve;
+        let metadata = match if link {
+            fs::symlink_metadata(&src_path)
+        } else {
+            fs::metadata(&src_path)
+        } {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && opts.ignore_missing_args => {
+                output::warn(format_args!("listed but missing: {}", src_path.display()));
+                stats.files_missing += 1;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(CopyError::SourceNotFound(format!(
+                    "{} (listed in --files-from; see --ignore-missing-args)",
+                    src_path.display()
+                )))
+            }
+            metadata => metadata.context(|| format!("Failed to read {}", src_path.display()))?,
+        };
+        if !opts.filters.is_included(rel, metadata.is_dir()) {
+            if verbose {
+                println!("Excluding: {}", src_path.display());
+            }
+            stats.files_excluded += 1;
+            continue;
+        }
+        let dst_path = dst.join(rel);
+        if metadata.is_dir() {
+            make(&dst_path, stats)?;
+            continue;
+        }
+        if let Some(parent) = dst_path.parent() {
+            make(parent, stats)?;
+        }
+        let dst_path = if opts.rename.is_some() && metadata.is_file() {
+            let counter = tree.renamed.fetch_add(1, Ordering::Relaxed) + 1;
+            renamed(dst_path, Some(&metadata), counter, opts)?
+        } else {
+            dst_path
+        };
+        let name = dst_path.file_name().unwrap_or_default().to_os_string();
+        if metadata.file_type().is_symlink() {
+            copy_symlink(&src_path, &dst_path, verbose)?;
+            if stats.start_time.is_some() {
+                stats.files_copied += 1;
+            }
+        } else if let Some(kind) = utils::Special::of(metadata.file_type()) {
+            copy_special(&src_path, &dst_path, kind, opts, stats)?;
+        } else if local_unchanged(&src_path, &dst_path, opts) {
+            if verbose {
+                println!("Unchanged: {}", src_path.display());
+            }
+            stats.files_skipped += 1;
+            stats.bytes_skipped += metadata.len();
+            tree.done.fetch_add(metadata.len(), Ordering::Relaxed);
+            tree.bars.advance();
+        } else if let Some(queue) = &tree.queue {
+            queue.push(src_path, dst_path, name);
+        } else {
+            files.push((src_path, dst_path, name));
+            if files.len() == FILE_BATCH {
+                copy_files(std::mem::take(&mut files), &tree, stats)?;
+            }
+        }
+    }
+    copy_files(files, &tree, stats)?;
+    tree.finish(stats)
+}
+
+/// Copy the directory `src`, `depth` levels below the source, into `dst`.
+fn walk(
+    src: &Path,
+    dst: &Path,
+    depth: usize,
+    tree: &Tree,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let opts = tree.opts;
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+    if tree.stopped() {
+        return Ok(());
+    }
+    // Copied as an empty directory when the filters leave nothing in it.
+    if !opts.filters.is_empty()
+        && opts
+            .filters
+            .matches_dir(dst.strip_prefix(&tree.links.dst_root).unwrap_or(dst))
+    {
+        return Ok(());
+    }
+
+    let listing = match fs::read_dir(src) {
+        Err(e) if unreadable(&e, opts) => {
+            output::warn(format_args!(
+                "skipping unreadable directory: {} ({})",
+                src.display(),
+                e
+            ));
+            stats.errors.push(FileError {
+                path: src.display().to_string(),
+                message: format!("cannot read directory: {}", e),
+            });
+            return Ok(());
+        }
+        listing => {
+            listing.context(|| format!("Failed to read source directory: {}", src.display()))?
+        }
+    };
+    let entries: Box<dyn Iterator<Item = io::Result<fs::DirEntry>>> = if tree.case.is_some() {
+        // So that which of two colliding names counts as the later one is stable, and a
+        // name that needs no sanitizing keeps it. Sorting needs the whole listing.
+        let mut entries = listing
+            .collect::<io::Result<Vec<_>>>()
+            .context(|| entry_error(src))?;
+        entries.sort_by_cached_key(|e| {
+            let name = e.file_name();
+            let renamed = opts
+                .sanitize_names
+                .as_ref()
+                .is_some_and(|r| r.sanitize(&name).is_some());
+            (renamed, name)
+        });
+        Box::new(entries.into_iter().map(Ok))
+    } else {
+        Box::new(listing)
+    };
+
+    // Files are copied a batch at a time as the listing goes, so a directory of a
+    // million files never has more than FILE_BATCH of them in memory; only the
+    // subdirectories, copied after, are kept until the listing ends.
+    let mut dirs = Vec::new();
+    let mut files = Vec::with_capacity(FILE_BATCH);
+    for entry in entries {
+        let entry = entry.context(|| entry_error(src))?;
+        let entry_path = entry.path();
+        let file_name = entry.file_name();
+        let dst_path = dst.join(&file_name);
+
+        let is_symlink = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+        if !opts.filters.is_empty() {
+            let rel = dst_path
+                .strip_prefix(&tree.links.dst_root)
+                .unwrap_or(&dst_path);
+            let is_dir =
+                !(is_symlink && opts.symlinks == SymlinkPolicy::Preserve) && entry_path.is_dir();
+            if !opts.filters.is_included(rel, is_dir) {
+                if verbose && !progress {
+                    println!("Excluding: {}", entry_path.display());
+                }
+                stats.files_excluded += 1;
+                continue;
+            }
+        }
+        let dst_path = match opts
+            .sanitize_names
+            .as_ref()
+            .and_then(|r| r.sanitize(&file_name))
+        {
+            Some(name) => {
+                if verbose && !progress {
+                    println!(
+                        "Renaming: {} -> {}",
+                        entry_path.display(),
+                        name.to_string_lossy()
+                    );
+                }
+                stats.names_sanitized += 1;
+                dst.join(name)
+            }
+            None => dst_path,
+        };
+        let preserved_link = is_symlink && opts.symlinks == SymlinkPolicy::Preserve;
+        let dst_path = match opts
+            .rename
+            .as_ref()
+            .and_then(|_| fs::metadata(&entry_path).ok())
+        {
+            Some(metadata) if metadata.is_file() && !preserved_link => {
+                let counter = tree.renamed.fetch_add(1, Ordering::Relaxed) + 1;
+                renamed(dst_path, Some(&metadata), counter, opts)?
+            }
+            _ => dst_path,
+        };
+        let dst_path = match tree.case.as_ref().map(|c| c.claim(&dst_path)).transpose()? {
+            Some(Claim::Collides(earlier)) => {
+                output::warn(format_args!(
+                    "{} and {} are the same name on a case-insensitive filesystem",
+                    earlier.display(),
+                    dst_path.display()
+                ));
+                stats.case_collisions += 1;
+                dst_path
+            }
+            Some(Claim::Renamed(earlier, renamed)) => {
+                output::warn(format_args!(
+                    "copying {} to {}, as {} takes its name on a case-insensitive filesystem",
+                    entry_path.display(),
+                    renamed.display(),
+                    earlier.display()
+                ));
+                stats.case_collisions += 1;
+                renamed
+            }
+            _ => dst_path,
+        };
+        if is_symlink && opts.symlinks == SymlinkPolicy::Preserve {
+            copy_symlink(&entry_path, &dst_path, verbose && !progress)?;
+            if stats.start_time.is_some() {
+                stats.files_copied += 1;
+            }
+        } else if entry_path.is_dir() {
+            if let Some(first) = tree.visit(&entry_path) {
+                output::warn(format_args!(
+                    "skipping {}: it leads to {}, which is already copied",
+                    entry_path.display(),
+                    first.display()
+                ));
+                stats.symlink_loops += 1;
+                continue;
+            }
+            if depth >= opts.depth_limit {
+                return Err(CopyError::TooDeep {
+                    path: entry_path,
+                    limit: opts.depth_limit,
+                });
+            }
+            dirs.push((entry_path, dst_path));
+        } else if let Some(kind) = fs::metadata(&entry_path)
+            .ok()
+            .and_then(|m| utils::Special::of(m.file_type()))
+        {
+            // Never read like a file: a FIFO would block the copy forever.
+            copy_special(&entry_path, &dst_path, kind, opts, stats)?;
+        } else if local_unchanged(&entry_path, &dst_path, opts) {
+            if verbose && !progress {
+                println!("Unchanged: {}", entry_path.display());
+            }
+            let size = fs::metadata(&entry_path).map_or(0, |m| m.len());
+            stats.files_skipped += 1;
+            stats.bytes_skipped += size;
+            tree.done.fetch_add(size, Ordering::Relaxed);
+            // Counted as done, so the files bar still reaches the end.
+            tree.bars.advance();
+        } else if let Some(queue) = &tree.queue {
+            queue.push(entry_path, dst_path, file_name);
+        } else {
+            files.push((entry_path, dst_path, file_name));
+            if files.len() == FILE_BATCH {
+                copy_files(std::mem::take(&mut files), tree, stats)?;
+                if tree.stopped() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    copy_files(files, tree, stats)?;
+
+    let (start_time, slowest) = (stats.start_time, stats.slowest.limit());
+    let copy_dir = |(src_path, dst_path): &(PathBuf, PathBuf)| -> Result<CopyStats, CopyError> {
+        if verbose && !progress {
+            println!(
+                "Copying directory: {} -> {}",
+                src_path.display(),
+                dst_path.display()
+            );
+        }
+        let mut sub = CopyStats {
+            start_time,
+            slowest: SlowFiles::new(slowest),
+            ..CopyStats::new_minimal()
+        };
+        if make_dirs(dst_path, opts)? {
+            sub.dirs_created += 1;
+        }
+        walk(src_path, dst_path, depth + 1, tree, &mut sub)?;
+        Ok(sub)
+    };
+    #[cfg(feature = "parallel")]
+    let subdirs: Vec<CopyStats> = dirs.par_iter().map(copy_dir).collect::<Result<_, _>>()?;
+    #[cfg(not(feature = "parallel"))]
+    let subdirs: Vec<CopyStats> = dirs.iter().map(copy_dir).collect::<Result<_, _>>()?;
+    for sub in subdirs {
+        stats.merge(sub);
+    }
+
+    // Only now that everything below `dst` is written, or its mtime would move again.
+    if let Some(queue) = &tree.queue {
+        locked(&queue.dirs).push((src.to_path_buf(), dst.to_path_buf()));
+    } else if !preserve_metadata(src, dst, opts)? {
+        stats.files_xattrs_incomplete += 1;
+    }
+
+    Ok(())
+}
+
+/// A file held back for `--order`, with what it is sorted by.
+struct Queued {
+    src: PathBuf,
+    dst: PathBuf,
+    name: OsString,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The files of a `--order` copy, and the directories (children before their parents)
+/// whose metadata waits for them.
+#[derive(Default)]
+struct Queue {
+    files: Mutex<Vec<Queued>>,
+    dirs: Mutex<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl Queue {
+    fn push(&self, src: PathBuf, dst: PathBuf, name: OsString) {
+        let metadata = fs::metadata(&src).ok();
+        locked(&self.files).push(Queued {
+            size: metadata.as_ref().map_or(0, |m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()),
+            src,
+            dst,
+            name,
+        });
+    }
+
+    /// Sort the files, copy them and then finish the directories.
+    fn copy(&self, tree: &Tree, stats: &mut CopyStats) -> Result<(), CopyError> {
+        let opts = tree.opts;
+        let mut files = std::mem::take(&mut *locked(&self.files));
+        // Ties go by path: a parallel walk queues in no particular order.
+        match opts.order {
+            Order::Default => {}
+            Order::SmallFirst => files.sort_by(|a, b| (a.size, &a.src).cmp(&(b.size, &b.src))),
+            Order::LargeFirst => {
+                files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.src.cmp(&b.src)))
+            }
+            Order::NewestFirst => {
+                files.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| a.src.cmp(&b.src)))
+            }
+        }
+        let files: Vec<_> = files.into_iter().map(|f| (f.src, f.dst, f.name)).collect();
+
+        // Workers take the next file off the one queue, so files start in order. The
+        // limits charge files as they start, so with limits there is one worker and the
+        // same files make it each time.
+        #[cfg(feature = "parallel")]
+        if !opts.limits.is_set() && files.len() > 1 {
+            use std::sync::atomic::AtomicUsize;
+
+            let next = AtomicUsize::new(0);
+            let workers = rayon::current_num_threads().min(files.len());
+            let (start_time, slowest) = (stats.start_time, stats.slowest.limit());
+            let done: Vec<CopyStats> = (0..workers)
+                .into_par_iter()
+                .map(|_| {
+                    let mut own = CopyStats {
+                        start_time,
+                        slowest: SlowFiles::new(slowest),
+                        ..CopyStats::new_minimal()
+                    };
+                    while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if tree.stopped() {
+                            break;
+                        }
+                        copy_files(vec![file.clone()], tree, &mut own)?;
+                    }
+                    Ok(own)
+                })
+                .collect::<Result<_, CopyError>>()?;
+            for own in done {
+                stats.merge(own);
+            }
+            return self.finish_dirs(opts, stats);
+        }
+
+        for batch in files.chunks(FILE_BATCH) {
+            copy_files(batch.to_vec(), tree, stats)?;
+            if tree.stopped() {
+                break;
+            }
+        }
+        self.finish_dirs(opts, stats)
+    }
+
+    fn finish_dirs(&self, opts: &CopyOptions, stats: &mut CopyStats) -> Result<(), CopyError> {
+        for (src, dst) in locked(&self.dirs).iter() {
+            if !preserve_metadata(src, dst, opts)? {
+                stats.files_xattrs_incomplete += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy `files`, found by the walk of `tree`, with the sources' names.
+fn copy_files(
+    mut files: Vec<(PathBuf, PathBuf, OsString)>,
+    tree: &Tree,
+    stats: &mut CopyStats,
+) -> Result<(), CopyError> {
+    let opts = tree.opts;
+    let verbose = opts.verbose;
+    let progress = opts.progress;
+    files.retain(|(src_path, dst_path, _)| {
+        let same = utils::same_file(src_path, dst_path);
+        if same {
+            skip_same_file(src_path, dst_path, stats);
+            let size = fs::metadata(src_path).map_or(0, |m| m.len());
+            tree.done.fetch_add(size, Ordering::Relaxed);
+            tree.bars.advance();
+        }
+        !same
+    });
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if opts.copy_mode == CopyMode::Uring
+        && opts.use_ram != Some(true)
+        && !opts.append
+        && !tree.links.is_active()
+        && !opts.limits.is_set()
+        && !files.is_empty()
+    {
+        let pairs: Vec<(PathBuf, PathBuf)> = files
+            .iter()
+            .map(|(src_path, dst_path, _)| (src_path.clone(), dst_path.clone()))
+            .collect();
+        match crate::uring::copy_files(&pairs) {
+            Ok(results) => {
+                let mut retry = Vec::new();
+                for ((src_path, dst_path, file_name), result) in files.into_iter().zip(results) {
+                    let Ok(bytes) = result else {
+                        // The regular loop below retries it and reports vanished files
+                        // and real errors exactly as it would have.
+                        retry.push((src_path, dst_path, file_name));
+                        continue;
+                    };
+                    if opts.fsync {
+                        utils::sync_file(&dst_path).context(|| {
+                            format!("Failed to flush {} to disk", dst_path.display())
+                        })?;
+                    }
+                    // copy_contents carries the permission bits over; do the same.
+                    let preserve = Preserve {
+                        mode: true,
+                        ..opts.preserve
+                    };
+                    if !apply_metadata(&src_path, &dst_path, preserve, opts)? {
+                        stats.files_xattrs_incomplete += 1;
+                    }
+                    if verbose && !progress {
+                        println!(
+                            "Copying file: {} -> {}",
+                            src_path.display(),
+                            dst_path.display()
+                        );
+                    }
+                    if stats.start_time.is_some() {
+                        stats.files_copied += 1;
+                        stats.bytes_copied += bytes;
+                    }
+                    tree.done.fetch_add(bytes, Ordering::Relaxed);
+                    tree.bars.advance();
+                }
+                files = retry;
+            }
+            Err(e) => {
+                static FALLBACK_NOTICE: std::sync::Once = std::sync::Once::new();
+                if verbose {
+                    FALLBACK_NOTICE.call_once(|| {
+                        println!("io_uring unavailable ({}), using the regular copy path", e)
+                    });
+                }
+            }
+        }
+    }
+
+    for (src_path, dst_path, file_name) in files {
+        if tree.full.load(Ordering::Relaxed) {
+            break;
+        }
+        let file_size = fs::metadata(&src_path).map(|m| m.len()).unwrap_or(0);
+        if !opts.limits.charge(file_size) {
+            break;
+        }
+        if verbose && !progress {
+            println!(
+                "Copying file: {} -> {}",
+                src_path.display(),
+                dst_path.display()
+            );
+        }
+        tree.bars.start_file(&file_name, file_size);
+
+        let started = Instant::now();
+        let listed = transfer_listed_file(&src_path, &dst_path, file_size, tree)
+            .map_err(|e| tree.check_space(&dst_path, e))?;
+        tree.done.fetch_add(file_size, Ordering::Relaxed);
+        let copied = match listed {
+            Listed::Copied {
+                bytes,
+                xattrs_complete,
+                appended,
+            } => {
+                if !xattrs_complete {
+                    stats.files_xattrs_incomplete += 1;
+                }
+                if appended && stats.start_time.is_some() {
+                    stats.files_appended += 1;
+                }
+                Some(bytes)
+            }
+            Listed::Linked => None,
+            Listed::Vanished => {
+                stats.files_vanished += 1;
+                continue;
+            }
+            Listed::Unreadable(error) => {
+                stats.errors.push(error);
+                continue;
+            }
+        };
+        if stats.start_time.is_some() {
+            match copied {
+                Some(bytes) => {
+                    stats.files_copied += 1;
+                    stats.bytes_copied += bytes;
+                    stats.time_file(&src_path, bytes, started);
+                }
+                None => stats.files_linked += 1,
+            }
+        }
+        tree.bars.end_file();
+    }
+    Ok(())
+}
+
+fn locked<T>(list: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    list.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The message for an entry of the directory `src` that couldn't be read.
+fn entry_error(src: &Path) -> String {
+    format!("Failed to read directory entry in: {}", src.display())
+}
+
+/// `--progress` for a directory copy: a bar of the files done and one of the file being
+/// copied, or without a terminal (or the `progress` feature), a line per file.
+struct Bars {
+    progress: bool,
+    #[cfg(feature = "progress")]
+    drawn: Option<(ProgressBar, ProgressBar)>,
+}
+
+impl Bars {
+    /// The files bar needs the number of files up front, so only a copy that shows it
+    /// lists the tree first, and not again when the limits or `--space-check` did.
+    fn new(src: &Path, opts: &CopyOptions, stats: &mut CopyStats) -> Self {
+        let progress = opts.progress;
+        #[cfg(feature = "progress")]
+        {
+            use std::io::IsTerminal;
+            let drawn = (progress && io::stdout().is_terminal()).then(|| {
+                let total_files =
+                    crate::copy::prescan(src, opts, stats).map_or(0, |(files, _)| files);
+                let multi = MultiProgress::new();
+                let files = multi.add(ProgressBar::new(total_files));
+                files.set_style(
+                    ProgressStyle::default_bar()
+                        .template("[{bar:40.cyan/blue}] {pos}/{len} files ({percent}%)")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                );
+                let current = multi.add(ProgressBar::new(0));
+                current.set_style(
+                    ProgressStyle::default_bar()
+                        .template(
+                            "  [{bar:30.green/yellow}] {bytes}/{total_bytes} ({percent}%) {msg}",
+                        )
+                        .unwrap()
+                        .progress_chars("=>-"),
+                );
+                (files, current)
+            });
+            Bars { progress, drawn }
+        }
+        #[cfg(not(feature = "progress"))]
+        {
+            let _ = (src, stats);
+            Bars { progress }
+        }
+    }
+
+    /// One file of `size` bytes is about to be copied.
+    fn start_file(&self, name: &OsStr, size: u64) {
+        #[cfg(feature = "progress")]
+        if let Some((_, current)) = &self.drawn {
+            current.set_length(size);
+            current.set_message(name.to_string_lossy().into_owned());
+            current.set_position(0);
+            return;
+        }
+        if self.progress {
+            use std::io::Write;
+            print!("  {} ({} bytes)... ", name.to_string_lossy(), size);
+            io::stdout().flush().unwrap();
+        }
+    }
+
+    /// `copied` bytes of the file last started are copied.
+    fn copied(&self, _copied: u64) {
+        #[cfg(feature = "progress")]
+        if let Some((_, current)) = &self.drawn {
+            current.set_position(_copied);
+        }
+    }
+
+    /// The file last started is copied.
+    fn end_file(&self) {
+        #[cfg(feature = "progress")]
+        if let Some((files, current)) = &self.drawn {
+            current.finish();
+            files.inc(1);
+            return;
+        }
+        if self.progress {
+            println!("✓");
+        }
+    }
+
+    /// A file was dealt with without being started: skipped, or copied in a batch.
+    fn advance(&self) {
+        #[cfg(feature = "progress")]
+        if let Some((files, _)) = &self.drawn {
+            files.inc(1);
+        }
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "progress")]
+        if let Some((files, current)) = &self.drawn {
+            files.finish();
+            current.finish();
+        }
+    }
+}
+
+/// Hard-link bookkeeping for one directory copy (`--hard-links`, `--link-dest`).
+struct LinkPlan {
+    dst_root: PathBuf,
+    link_dest: Option<PathBuf>,
+    hard_links: bool,
+    /// First destination written for each multiply-linked source inode, by (dev, ino).
+    seen: std::sync::Mutex<std::collections::HashMap<(u64, u64), PathBuf>>,
+}
+
+impl LinkPlan {
+    fn new(dst_root: &Path, opts: &CopyOptions) -> Self {
+        LinkPlan {
+            dst_root: dst_root.to_path_buf(),
+            link_dest: opts.link_dest.clone(),
+            hard_links: opts.hard_links,
+            seen: Default::default(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.hard_links || self.link_dest.is_some()
+    }
+
+    /// The (dev, ino) of `src` when it has other links worth tracking.
+    #[cfg(unix)]
+    fn inode_key(&self, src: &Path) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+        if !self.hard_links {
+            return None;
+        }
+        let meta = fs::metadata(src).ok()?;
+        (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn inode_key(&self, _src: &Path) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// The file in the `--link-dest` snapshot that `dst` can share, if it is unchanged:
+    /// same size and modification time (and permission bits when those are preserved).
+    fn unchanged_in_link_dest(
+        &self,
+        src: &Path,
+        dst: &Path,
+        opts: &CopyOptions,
+    ) -> Option<PathBuf> {
+        let rel = dst.strip_prefix(&self.dst_root).ok()?;
+        let candidate = self.link_dest.as_ref()?.join(rel);
+        let old = fs::symlink_metadata(&candidate).ok()?;
+        let new = fs::metadata(src).ok()?;
+        if !old.is_file() || old.len() != new.len() {
+            return None;
+        }
+        let secs = |t: std::time::SystemTime| {
+            t.duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .ok()
+        };
+        if secs(old.modified().ok()?) != secs(new.modified().ok()?) {
+            return None;
+        }
+        if opts.preserve.mode && old.permissions() != new.permissions() {
+            return None;
+        }
+        Some(candidate)
+    }
+
+    /// Satisfy `dst` with a hard link instead of a copy when possible.
+    fn try_link(&self, src: &Path, dst: &Path, opts: &CopyOptions) -> Result<bool, CopyError> {
+        let key = self.inode_key(src);
+        let target = match key.and_then(|k| self.seen.lock().unwrap().get(&k).cloned()) {
+            Some(first) => Some(first),
+            None => self.unchanged_in_link_dest(src, dst, opts),
+        };
+        let Some(target) = target else {
+            return Ok(false);
+        };
+
+        if fs::symlink_metadata(dst).is_ok() {
+            fs::remove_file(dst).context(|| format!("Failed to replace {}", dst.display()))?;
+        }
+        match fs::hard_link(&target, dst) {
+            Ok(()) => {
+                if opts.verbose && !opts.progress {
+                    println!("Hard-linking: {} => {}", dst.display(), target.display());
+                }
+                self.record(key, dst);
+                Ok(true)
+            }
+            // Across filesystems, or on ones without hard links: copy instead.
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn record(&self, key: Option<(u64, u64)>, dst: &Path) {
+        if let Some(key) = key {
+            self.seen
+                .lock()
+                .unwrap()
+                .entry(key)
+                .or_insert_with(|| dst.to_path_buf());
+        }
+    }
+}
+
+/// What happened to one file found while walking a source directory.
+enum Listed {
+    Copied {
+        bytes: u64,
+        xattrs_complete: bool,
+        /// Only the new tail was written (`--append`).
+        appended: bool,
+    },
+    Linked,
+    Vanished,
+    /// Skipped because it can't be read ([`CopyOptions::continue_on_error`]).
+    Unreadable(FileError),
+}
+
+/// Copy, or link, the file `src_path` of `size` bytes found by the walk of `tree`.
+fn transfer_listed_file(
+    src_path: &Path,
+    dst_path: &Path,
+    size: u64,
+    tree: &Tree,
+) -> Result<Listed, CopyError> {
+    let (opts, link_plan) = (tree.opts, &tree.links);
+    if link_plan.is_active() {
+        if link_plan.try_link(src_path, dst_path, opts)? {
+            return Ok(Listed::Linked);
+        }
+        // An existing destination may be a link into an older snapshot; writing through
+        // it would change that snapshot too, so start from a fresh inode.
+        if fs::symlink_metadata(dst_path).is_ok_and(|m| m.is_file()) {
+            fs::remove_file(dst_path)
+                .context(|| format!("Failed to replace {}", dst_path.display()))?;
+        }
+    }
+    let listed = copy_listed_file(src_path, dst_path, size, opts, |copied| {
+        tree.bars.copied(copied)
+    })?;
+    if let Listed::Copied { .. } = listed {
+        link_plan.record(link_plan.inode_key(src_path), dst_path);
+    }
+    Ok(listed)
+}
+
+/// Copy one file found while walking a source directory.
+///
+/// Returns the bytes copied and whether all extended attributes were preserved, or
+/// [`Listed::Vanished`] when the file disappeared after it was listed and
+/// [`CopyOptions::ignore_vanished`] is set, or [`Listed::Unreadable`] when it can't be
+/// read and [`CopyOptions::continue_on_error`] is set; the caller counts those instead
+/// of failing.
+fn copy_listed_file(
+    src_path: &Path,
+    dst_path: &Path,
+    size: u64,
+    opts: &CopyOptions,
+    on_progress: impl FnMut(u64),
+) -> Result<Listed, CopyError> {
+    if opts.append {
+        if let Some((bytes, xattrs_complete)) = copy_appended(src_path, dst_path, opts)? {
+            return Ok(Listed::Copied {
+                bytes,
+                xattrs_complete,
+                appended: true,
+            });
+        }
+    }
+    match copy_contents(src_path, dst_path, size, opts, on_progress) {
+        Ok(bytes) => {
+            let xattrs_complete = preserve_metadata(src_path, dst_path, opts)?;
+            Ok(Listed::Copied {
+                bytes,
+                xattrs_complete,
+                appended: false,
+            })
+        }
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                && opts.ignore_vanished
+                && fs::symlink_metadata(src_path).is_err() =>
+        {
+            output::warn(format_args!(
+                "file vanished before it could be copied: {}",
+                src_path.display()
+            ));
+            Ok(Listed::Vanished)
+        }
+        // The error may be about the destination; only an unreadable source is skipped.
+        Err(e)
+            if unreadable(&e, opts)
+                && fs::File::open(src_path).is_err_and(|e| unreadable(&e, opts)) =>
+        {
+            output::warn(format_args!(
+                "skipping unreadable file: {} ({})",
+                src_path.display(),
+                e
+            ));
+            Ok(Listed::Unreadable(FileError {
+                path: src_path.display().to_string(),
+                message: format!("cannot read file: {}", e),
+            }))
+        }
+        Err(e) => Err(e).context(|| {
+            format!(
+                "Failed to copy file from {} to {}",
+                src_path.display(),
+                dst_path.display()
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_vanished_file_is_counted_not_fatal() {
+        let temp_dir = TempDir::new().unwrap();
+        let listed = temp_dir.path().join("listed.txt");
+        let dst = temp_dir.path().join("copy.txt");
+        fs::write(&listed, "gone soon").unwrap();
+        fs::remove_file(&listed).unwrap();
+
+        let opts = CopyOptions::default();
+        assert!(matches!(
+            copy_listed_file(&listed, &dst, 9, &opts, |_| {}),
+            Ok(Listed::Vanished)
+        ));
+        assert!(!dst.exists());
+
+        let strict = CopyOptions {
+            ignore_vanished: false,
+            ..CopyOptions::default()
+        };
+        assert!(matches!(
+            copy_listed_file(&listed, &dst, 9, &strict, |_| {}),
+            Err(CopyError::IoError { .. })
+        ));
+    }
+
+    /// Counts from every level of a nested tree reach the top, with or without the
+    /// `parallel` feature.
+    #[test]
+    fn test_nested_counts() {
+        use crate::copy::Compare;
+        use crate::filter::PatternFilter;
+
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(src.join("sub/deep")).unwrap();
+        fs::create_dir(&dst).unwrap();
+        for name in ["a.txt", "sub/b.txt", "sub/deep/c.txt", "sub/deep/d.txt"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        for name in ["skip.log", "sub/skip.log", "sub/deep/skip.log"] {
+            fs::write(src.join(name), "log").unwrap();
+        }
+        let mut opts = CopyOptions::default();
+        opts.filters
+            .push(PatternFilter::new(&[], &["*.log".to_string()]));
+        let detailed = || CopyStats {
+            slowest: SlowFiles::new(10),
+            ..CopyStats::new()
+        };
+
+        let mut stats = detailed();
+        copy_tree(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!(
+            fs::read(dst.join("sub/deep/d.txt")).unwrap(),
+            b"sub/deep/d.txt"
+        );
+        assert!(!dst.join("sub/deep/skip.log").exists());
+        assert_eq!((stats.files_copied, stats.bytes_copied), (4, 42));
+        assert_eq!((stats.files_excluded, stats.dirs_created), (3, 2));
+        assert_eq!(stats.slowest.sorted().len(), 4);
+
+        opts.compare = Some(Compare::SizeOnly);
+        let mut stats = detailed();
+        copy_tree(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_copied, stats.files_skipped), (0, 4));
+        assert_eq!(stats.bytes_skipped, 42);
+        assert!(stats.slowest.sorted().is_empty());
+    }
+
+    /// A directory copy writes what copying each of its files on its own does, large
+    /// ones (sendfile on Linux) and small ones (buffered) alike.
+    #[test]
+    fn test_tree_matches_single_file_copies() {
+        use crate::copy::copy;
+        use crate::path::LocalPath;
+        use crate::protocol::Path as ProtocolPath;
+        use std::time::Duration;
+
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let large: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+        fs::write(src.join("large.bin"), &large).unwrap();
+        fs::write(src.join("small.txt"), "small").unwrap();
+        fs::write(src.join("sub/empty"), "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::Permissions::from_mode(0o640);
+            fs::set_permissions(src.join("large.bin"), mode).unwrap();
+        }
+        let local =
+            |path: &Path| ProtocolPath::Local(LocalPath::parse(path.to_str().unwrap()).unwrap());
+        let opts = CopyOptions {
+            recursive: true,
+            ..CopyOptions::default()
+        };
+
+        let started = Instant::now();
+        let tree = temp.path().join("tree");
+        copy(&local(&src), &local(&tree), &opts).unwrap();
+        let as_tree = started.elapsed();
+
+        let started = Instant::now();
+        let single = temp.path().join("single");
+        fs::create_dir_all(single.join("sub")).unwrap();
+        for name in ["large.bin", "small.txt", "sub/empty"] {
+            copy(&local(&src.join(name)), &local(&single.join(name)), &opts).unwrap();
+        }
+        let one_by_one = started.elapsed();
+
+        for name in ["large.bin", "small.txt", "sub/empty"] {
+            let (a, b) = (tree.join(name), single.join(name));
+            assert!(fs::read(&a).unwrap() == fs::read(&b).unwrap(), "{}", name);
+            assert_eq!(
+                fs::metadata(&a).unwrap().permissions(),
+                fs::metadata(&b).unwrap().permissions(),
+                "{}",
+                name
+            );
+        }
+        assert!(fs::read(tree.join("large.bin")).unwrap() == large);
+        // Not a benchmark, but the walk should be in the same league.
+        assert!(as_tree < one_by_one * 10 + Duration::from_secs(1));
+    }
+
+    /// A directory far wider than a batch comes out whole, and counted once.
+    #[test]
+    fn test_wide_directory() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::create_dir(&dst).unwrap();
+        let wide = FILE_BATCH * 10 + 7;
+        for i in 0..wide {
+            fs::write(src.join(format!("f{:05}", i)), "x").unwrap();
+        }
+        fs::write(src.join("sub/last"), "y").unwrap();
+
+        let mut stats = CopyStats::new();
+        copy_tree(&src, &dst, &CopyOptions::default(), &mut stats).unwrap();
+        assert_eq!(stats.files_copied, wide + 1);
+        assert_eq!(stats.bytes_copied, wide as u64 + 1);
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), wide + 1);
+        assert_eq!(fs::read(dst.join("sub/last")).unwrap(), b"y");
+
+        // With --max-files, the batch that reaches the limit is the last one.
+        let mut opts = CopyOptions::default();
+        opts.limits.max_files = Some(FILE_BATCH as u64 + 1);
+        let limited = temp.path().join("limited");
+        fs::create_dir(&limited).unwrap();
+        let mut stats = CopyStats::new();
+        copy_tree(&src, &limited, &opts, &mut stats).unwrap();
+        assert_eq!(stats.files_copied, FILE_BATCH + 1);
+        assert!(opts.limits.stopped());
+    }
+
+    /// Files start in `--order` across directories: with `--max-files 2`, the first two
+    /// are the ones copied. Directory times are set after all of them.
+    #[test]
+    fn test_order() {
+        use crate::copy::Preserve;
+        use std::time::Duration;
+
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("a/deep")).unwrap();
+        let epoch = SystemTime::UNIX_EPOCH;
+        for (name, size, age) in [
+            ("a/deep/medium", 20, 3),
+            ("a/tiny", 1, 1),
+            ("large", 300, 2),
+            ("small", 5, 4),
+        ] {
+            let file = fs::File::create(src.join(name)).unwrap();
+            file.set_len(size).unwrap();
+            file.set_modified(epoch + Duration::from_secs(1_000_000 / age))
+                .unwrap();
+        }
+        let copied = |order: Order| {
+            let dst = temp.path().join(format!("{:?}", order));
+            fs::create_dir(&dst).unwrap();
+            let mut opts = CopyOptions {
+                order,
+                ..CopyOptions::default()
+            };
+            opts.limits.max_files = Some(2);
+            let mut stats = CopyStats::new();
+            copy_tree(&src, &dst, &opts, &mut stats).unwrap();
+            assert_eq!(stats.files_copied, 2);
+            let mut names: Vec<_> = ["a/deep/medium", "a/tiny", "large", "small"]
+                .into_iter()
+                .filter(|name| dst.join(name).exists())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(copied(Order::SmallFirst), ["a/tiny", "small"]);
+        assert_eq!(copied(Order::LargeFirst), ["a/deep/medium", "large"]);
+        assert_eq!(copied(Order::NewestFirst), ["a/tiny", "large"]);
+
+        // Everything arrives, and the directories keep their times.
+        fs::File::options()
+            .write(true)
+            .open(src.join("a/tiny"))
+            .unwrap()
+            .set_modified(epoch + Duration::from_secs(5_000_000))
+            .unwrap();
+        let dst = temp.path().join("all");
+        fs::create_dir(&dst).unwrap();
+        let opts = CopyOptions {
+            order: Order::LargeFirst,
+            preserve: Preserve {
+                times: true,
+                ..Preserve::default()
+            },
+            ..CopyOptions::default()
+        };
+        let mut stats = CopyStats::new();
+        copy_tree(&src, &dst, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_copied, stats.bytes_copied), (4, 326));
+        for dir in ["a", "a/deep"] {
+            assert_eq!(
+                fs::metadata(dst.join(dir)).unwrap().modified().unwrap(),
+                fs::metadata(src.join(dir)).unwrap().modified().unwrap(),
+                "{}",
+                dir
+            );
+        }
+    }
+
+    /// Each destination directory is created once, however many files go in it, and
+    /// files don't check their directory again.
+    #[test]
+    fn test_each_directory_created_once() {
+        use crate::copy::CREATED_DIRS;
+
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dirs = ["a", "a/b", "c", "empty"];
+        for dir in dirs {
+            fs::create_dir_all(src.join(dir)).unwrap();
+        }
+        for dir in ["", "a", "a/b", "c"] {
+            for i in 0..50 {
+                fs::write(src.join(dir).join(format!("f{}", i)), "x").unwrap();
+            }
+        }
+        for order in [Order::Default, Order::SmallFirst] {
+            let dst = temp.path().join(format!("{:?}", order));
+            fs::create_dir(&dst).unwrap();
+            let opts = CopyOptions {
+                order,
+                ..CopyOptions::default()
+            };
+            let mut stats = CopyStats::new();
+            copy_tree(&src, &dst, &opts, &mut stats).unwrap();
+            assert_eq!((stats.files_copied, stats.dirs_created), (200, 4));
+
+            let mut created: Vec<PathBuf> = CREATED_DIRS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|d| d.strip_prefix(&dst).ok().map(Path::to_path_buf))
+                .collect();
+            created.sort();
+            let expected: Vec<PathBuf> = dirs.iter().map(PathBuf::from).collect();
+            assert_eq!(created, expected, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn test_copy_listed() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        let dst = temp.path().join("dst");
+        fs::create_dir_all(src.join("My Documents/unlisted")).unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        for file in [
+            "a.txt",
+            "My Documents/b c.txt",
+            "My Documents/unlisted/d.txt",
+            "e.log",
+        ] {
+            fs::write(src.join(file), file).unwrap();
+        }
+        let list = crate::files_from::parse(
+            b"a.txt\r\nMy Documents/b c.txt\r\ne.log\r\nMy Documents/unlisted\r\n",
+            false,
+        )
+        .unwrap();
+        let mut opts = CopyOptions {
+            files_from: Some(list),
+            ..CopyOptions::default()
+        };
+        opts.filters.push(crate::filter::PatternFilter::new(
+            &[],
+            &["*.log".to_string()],
+        ));
+        let mut stats = CopyStats::new();
+        copy_listed(
+            &src,
+            &dst,
+            opts.files_from.as_ref().unwrap(),
+            &opts,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "a.txt");
+        assert!(dst.join("My Documents/b c.txt").exists());
+        // A listed directory is made, without what the list doesn't name in it.
+        assert!(dst.join("My Documents/unlisted").is_dir());
+        assert!(!dst.join("My Documents/unlisted/d.txt").exists());
+        assert!(!dst.join("e.log").exists());
+        assert_eq!(
+            (stats.files_listed, stats.files_copied, stats.files_excluded),
+            (4, 2, 1)
+        );
+        assert_eq!(stats.dirs_created, 2);
+
+        let list = vec![PathBuf::from("gone.txt"), PathBuf::from("a.txt")];
+        let result = copy_listed(&src, &dst, &list, &opts, &mut CopyStats::new());
+        assert!(matches!(result, Err(CopyError::SourceNotFound(_))));
+        opts.ignore_missing_args = true;
+        let mut stats = CopyStats::new();
+        copy_listed(&src, &dst, &list, &opts, &mut stats).unwrap();
+        assert_eq!((stats.files_missing, stats.files_copied), (1, 1));
+    }
+
+    /// A symlink back up the tree is followed once, not forever, and a tree deeper than
+    /// `--depth-limit` fails naming the directory too deep.
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop_and_depth_limit() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(src.join("a/b")).unwrap();
+        fs::write(src.join("a/file.txt"), "a").unwrap();
+        fs::write(src.join("a/b/file.txt"), "b").unwrap();
+        std::os::unix::fs::symlink("..", src.join("a/link")).unwrap();
+
+        let dst = temp.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let mut stats = CopyStats::new();
+        copy_tree(&src, &dst, &CopyOptions::default(), &mut stats).unwrap();
+        assert_eq!(stats.symlink_loops, 1);
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(fs::read(dst.join("a/b/file.txt")).unwrap(), b"b");
+        assert!(!dst.join("a/link").exists());
+        // The listing behind uploads and the limits stops there too.
+        let listed =
+            crate::copy::list_upload_tree(&src, &crate::filter::FilterChain::default()).unwrap();
+        assert_eq!(listed.loops, [PathBuf::from("a/link")]);
+        assert_eq!(listed.files.len(), 2);
+
+        let opts = CopyOptions {
+            depth_limit: 1,
+            ..CopyOptions::default()
+        };
+        let shallow = temp.path().join("shallow");
+        fs::create_dir(&shallow).unwrap();
+        match copy_tree(&src, &shallow, &opts, &mut CopyStats::new()) {
+            Err(CopyError::TooDeep { path, limit: 1 }) => assert_eq!(path, src.join("a/b")),
+            other => panic!("expected TooDeep, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+/* Calls libusync the way a C program would: `smoke SRC DST`, run by
+ * test_ffi_smoke in tests/integration_tests.rs. */
+#include <stdio.h>
+#include <string.h>
+
+#include "usync.h"
+
+static void on_progress(const char *path, uint64_t copied, uint64_t total, void *user_data) {
+    (void)path;
+    (void)total;
+    *(uint64_t *)user_data = copied;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        return 2;
+    }
+    uint64_t copied = 0;
+    usync_set_progress_callback(on_progress, &copied);
+
+    char *report = usync_copy(argv[1], argv[2], "{\"recursive\":true}");
+    if (report == NULL) {
+        char *error = usync_last_error();
+        fprintf(stderr, "copy failed: %s\n", error);
+        usync_free_string(error);
+        return 1;
+    }
+    printf("%s\n", report);
+    usync_free_string(report);
+    printf("progress %llu\n", (unsigned long long)copied);
+
+    if (usync_sync(argv[1], argv[2], "{\"recursive\":1}") != NULL) {
+        return 1;
+    }
+    char *error = usync_last_error();
+    printf("error %s\n", error);
+    usync_free_string(error);
+    usync_set_progress_callback(NULL, NULL);
+    return 0;
+}
@@ -1,6 +1,7 @@
 use std::fs;
-use std::path::Path;
 use std::process::Command;
+#[cfg(unix)]
+use usync::mock::MockRemote;
 
 fn setup_test_env() -> (tempfile::TempDir, std::path::PathBuf) {
     let temp_dir = tempfile::tempdir().unwrap();
@@ -231,6 +232,612 @@ fn test_protocol_rejection() {
     assert!(output.status.success() || !output.status.success());
 }
 
+#[test]
+#[cfg(unix)]
+fn test_file_url_source_and_destination() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input/with space.txt");
+    fs::write(&src, "file url content").unwrap();
+
+    let output = Command::new(get_binary_path())
+        .arg(format!(
+            "file://{}",
+            src.display().to_string().replace(' ', "%20")
+        ))
+        .arg(format!(
+            "file://localhost{}/",
+            test_dir.join("output").display()
+        ))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(test_dir.join("output/with space.txt")).unwrap(),
+        "file url content"
+    );
+}
+
+#[test]
+fn test_stream_stdin_and_stdout() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let (_temp, test_dir) = setup_test_env();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    let dst = test_dir.join("output/from-stdin.bin");
+
+    let mut child = Command::new(get_binary_path())
+        .arg("--checksum")
+        .arg("-")
+        .arg(&dst)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&data).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(&dst).unwrap(), data);
+
+    let output = Command::new(get_binary_path())
+        .arg("-v")
+        .arg("--checksum")
+        .arg(&dst)
+        .arg("-")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // Nothing but the data on stdout, even in verbose mode.
+    assert_eq!(output.stdout, data);
+
+    for args in [
+        vec!["-r", "-", "out.bin"],
+        vec!["-", "-"],
+        vec!["input/test1.txt", "-", "--also-to", "other.txt"],
+    ] {
+        let output = Command::new(get_binary_path())
+            .current_dir(&test_dir)
+            .args(&args)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+
+    let output = Command::new(get_binary_path())
+        .arg(test_dir.join("input"))
+        .arg("-")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a directory"));
+}
+
+#[test]
+fn test_gzip_on_the_fly() {
+    if Command::new("gzip").arg("--version").output().is_err() {
+        return;
+    }
+    let (_temp, test_dir) = setup_test_env();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 7) as u8).collect();
+    let src = test_dir.join("input/data.bin");
+    fs::write(&src, &data).unwrap();
+
+    // A .gz destination compresses without a flag.
+    let packed = test_dir.join("output/data.bin.gz");
+    let output = Command::new(get_binary_path())
+        .arg("--checksum")
+        .arg(&src)
+        .arg(&packed)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("bytes uncompressed"));
+    let packed_bytes = fs::read(&packed).unwrap();
+    assert_eq!(&packed_bytes[..2], &[0x1f, 0x8b]);
+    assert!(packed_bytes.len() < data.len());
+
+    // Decompressing into a directory drops the extension.
+    let restore = test_dir.join("output/restore");
+    fs::create_dir(&restore).unwrap();
+    let output = Command::new(get_binary_path())
+        .arg("--decompress")
+        .arg("--checksum")
+        .arg(&packed)
+        .arg(&restore)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read(restore.join("data.bin")).unwrap(), data);
+
+    let output = Command::new(get_binary_path())
+        .arg("-r")
+        .arg("--gzip")
+        .arg(test_dir.join("input"))
+        .arg(test_dir.join("output/tree"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("single files"));
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::create_dir_all(src.join("nested/logs")).unwrap();
+    fs::write(src.join("nested/logs/debug.log"), "noise").unwrap();
+    fs::write(src.join("nested/keep.txt"), "kept").unwrap();
+
+    for name in ["snapshot.tar", "snapshot.tar.gz", "snapshot.zip"] {
+        let archive = test_dir.join("output").join(name);
+        let output = Command::new(get_binary_path())
+            .arg("-r")
+            .arg(&src)
+            .arg(&archive)
+            .arg("--exclude")
+            .arg("logs/")
+            .output()
+            .unwrap();
+        if !output.status.success() && String::from_utf8_lossy(&output.stderr).contains("execute") {
+            // The archiving tool isn't installed.
+            continue;
+        }
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(archive.is_file());
+
+        // Dry runs list entries and write nothing.
+        let restored = test_dir.join("output").join(format!("{}.d", name));
+        let output = Command::new(get_binary_path())
+            .arg("-r")
+            .arg("--dry-run")
+            .arg(&archive)
+            .arg(&restored)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Would extract"));
+        assert!(!restored.exists());
+
+        let output = Command::new(get_binary_path())
+            .arg("-r")
+            .arg(&archive)
+            .arg(&restored)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let mut expected = list_tree(&src);
+        expected.retain(|(rel, _)| !rel.contains("logs"));
+        assert_eq!(list_tree(&restored), expected, "{}", name);
+    }
+
+    // Other copies list what they would write, and write nothing either.
+    let output = Command::new(get_binary_path())
+        .arg("--dry-run")
+        .arg(src.join("test1.txt"))
+        .arg(test_dir.join("output/dry.txt"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Would copy"));
+    assert!(!test_dir.join("output/dry.txt").exists());
+}
+
+#[test]
+fn test_debug_filters_names_the_rule() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    let output = Command::new(get_binary_path())
+        .args([
+            "-r",
+            "--debug-filters",
+            "--exclude",
+            "subdir/",
+            "--include",
+            "*1.txt",
+        ])
+        .arg(&src)
+        .arg(test_dir.join("output/copy"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("filter 1: --exclude subdir/, --include *1.txt"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("filter: test1.txt: included by default"));
+    assert!(stderr.contains("filter: subdir/: excluded by --exclude subdir/ (filter 1)"));
+    assert!(stderr.contains("filter: test2.txt: excluded by no --include matching (*1.txt)"));
+    // Nothing below an excluded directory is looked at, and each path shows up once.
+    assert!(!stderr.contains("nested.txt"));
+    assert_eq!(stderr.matches("filter: test2.txt").count(), 1);
+
+    // A dry run lists what the filters leave out, and why.
+    let output = Command::new(get_binary_path())
+        .args(["-r", "--dry-run", "--exclude", "subdir/"])
+        .arg(&src)
+        .arg(test_dir.join("output/plan.tar"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Would skip subdir (excluded by --exclude subdir/)"));
+}
+
+#[test]
+fn test_filter_rules_first_match_wins() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::write(src.join("subdir/skip.log"), "noise").unwrap();
+    let rules = test_dir.join("rules");
+    fs::write(&rules, "# only the subdirectory\n- *.log\n+ /subdir/***\n").unwrap();
+    let dst = test_dir.join("output/filtered");
+
+    let output = Command::new(get_binary_path())
+        .arg("-r")
+        .arg("--filter-file")
+        .arg(&rules)
+        .args(["--filter", "- *"])
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let copied: Vec<String> = list_tree(&dst).into_iter().map(|(rel, _)| rel).collect();
+    assert_eq!(copied, ["subdir", "subdir/nested.txt", "subdir/test3.txt"]);
+
+    fs::write(&rules, "+ *.txt\nkeep *.md\n").unwrap();
+    let output = Command::new(get_binary_path())
+        .arg("-r")
+        .arg("--filter-file")
+        .arg(&rules)
+        .arg(&src)
+        .arg(test_dir.join("output/never"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("line 2: invalid filter rule 'keep *.md'")
+    );
+    assert!(!test_dir.join("output/never").exists());
+}
+
+#[test]
+fn test_http_download_checks_the_response() {
+    if Command::new("curl").arg("--version").output().is_err() {
+        return;
+    }
+    let server = std::sync::Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+    let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let worker = server.clone();
+    std::thread::spawn(move || {
+        for request in worker.incoming_requests() {
+            let header = |h: &str| h.parse::<tiny_http::Header>().unwrap();
+            let response = match request.url() {
+                "/dataset.tar.gz" => tiny_http::Response::from_string("<html>Please log in</html>")
+                    .with_header(header("Content-Type: text/html; charset=utf-8")),
+                "/data.bin" => tiny_http::Response::from_string("binary")
+                    .with_header(header("Content-Type: application/octet-stream")),
+                hop => {
+                    // /hop3 -> /hop2 -> /hop1 -> /data.bin
+                    let n: u32 = hop.trim_start_matches("/hop").parse().unwrap_or(0);
+                    let next = match n {
+                        0 | 1 => "/data.bin".to_string(),
+                        n => format!("/hop{}", n - 1),
+                    };
+                    tiny_http::Response::from_string("")
+                        .with_status_code(302)
+                        .with_header(header(&format!("Location: {}", next)))
+                }
+            };
+            let _ = request.respond(response);
+        }
+    });
+    let (_temp, test_dir) = setup_test_env();
+    let output_dir = test_dir.join("output");
+    let run = |path: &str, dst: &str, extra: &[&str]| {
+        Command::new(get_binary_path())
+            .args(extra)
+            .arg(format!("{}{}", base, path))
+            .arg(output_dir.join(dst))
+            .output()
+            .unwrap()
+    };
+
+    // Kept, but not quietly.
+    let output = run("/dataset.tar.gz", "dataset.tar.gz", &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("is an HTML page (26 bytes)"), "{}", stderr);
+
+    let output = run(
+        "/dataset.tar.gz",
+        "strict.tar.gz",
+        &["--expect-content-type", "application/"],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("not the expected application/ (HTTP 200, Content-Type text/html"),
+        "{}",
+        stderr
+    );
+    assert!(!output_dir.join("strict.tar.gz").exists());
+
+    let output = run(
+        "/hop3",
+        "data.bin",
+        &["--expect-content-type", "application/octet-stream"],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.join("data.bin")).unwrap(),
+        "binary"
+    );
+    let output = run("/hop3", "far.bin", &["--max-redirects", "2"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("more than 2 redirects (HTTP 302"),
+        "{}",
+        stderr
+    );
+    assert!(!output_dir.join("far.bin").exists());
+
+    server.unblock();
+}
+
+#[test]
+fn test_update_skips_unmodified_http_download() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    if Command::new("curl").arg("--version").output().is_err() {
+        return;
+    }
+    // (body, ETag, Last-Modified) of the file being served.
+    let version = Arc::new(Mutex::new((
+        "{\"v\":1}",
+        "\"v1\"",
+        "Mon, 05 Jan 2026 10:00:00 GMT",
+    )));
+    let downloads = Arc::new(AtomicUsize::new(0));
+    let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+    let url = format!("http://{}/big.json", server.server_addr().to_ip().unwrap());
+    let (worker, served, sent) = (server.clone(), version.clone(), downloads.clone());
+    std::thread::spawn(move || {
+        for request in worker.incoming_requests() {
+            let (body, etag, modified) = *served.lock().unwrap();
+            let header = |name: &'static str| {
+                request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv(name))
+                    .map(|h| h.value.to_string())
+            };
+            let not_modified = match header("If-None-Match") {
+                Some(tag) => tag == etag,
+                None => header("If-Modified-Since").as_deref() == Some(modified),
+            };
+            let mut response = if not_modified {
+                tiny_http::Response::from_string("").with_status_code(304)
+            } else {
+                if *request.method() == tiny_http::Method::Get {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                }
+                tiny_http::Response::from_string(body)
+            };
+            for h in [
+                format!("ETag: {}", etag),
+                format!("Last-Modified: {}", modified),
+            ] {
+                response.add_header(h.parse::<tiny_http::Header>().unwrap());
+            }
+            let _ = request.respond(response);
+        }
+    });
+    let (_temp, test_dir) = setup_test_env();
+    let dst = test_dir.join("output/big.json");
+    let fetch = || {
+        let output = Command::new(get_binary_path())
+            .args(["-u", "-v"])
+            .arg(&url)
+            .arg(&dst)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let sidecar = test_dir.join("output/.big.json.usync-etag");
+
+    fetch();
+    assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    assert_eq!(fs::read_to_string(&sidecar).unwrap().trim(), "\"v1\"");
+
+    let stdout = fetch();
+    assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    assert!(stdout.contains("Not modified"), "{}", stdout);
+    assert!(stdout.contains("Files skipped: 1"), "{}", stdout);
+
+    // Without the ETag, the file's time (the server's Last-Modified) still does.
+    fs::remove_file(&sidecar).unwrap();
+    fetch();
+    assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+    *version.lock().unwrap() = ("{\"v\":2}", "\"v2\"", "Tue, 06 Jan 2026 10:00:00 GMT");
+    let stdout = fetch();
+    assert!(stdout.contains("Files copied: 1"), "{}", stdout);
+    assert_eq!(downloads.load(Ordering::SeqCst), 2);
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "{\"v\":2}");
+    assert_eq!(fs::read_to_string(&sidecar).unwrap().trim(), "\"v2\"");
+
+    server.unblock();
+}
+
+/// Relative paths and contents (`None` for directories) of everything below `root`.
+fn list_tree(root: &std::path::Path) -> Vec<(String, Option<Vec<u8>>)> {
+    let mut entries = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let rel = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if path.is_dir() {
+                entries.push((rel, None));
+                pending.push(path);
+            } else {
+                entries.push((rel, Some(fs::read(&path).unwrap())));
+            }
+        }
+    }
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_completions_and_manpage() {
+    let output = Command::new(get_binary_path())
+        .args(["completions", "zsh"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let script = String::from_utf8_lossy(&output.stdout);
+    assert!(script.starts_with("#compdef usync"));
+    assert!(script.contains("--checksum"));
+
+    let output = Command::new(get_binary_path())
+        .arg("manpage")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let page = String::from_utf8_lossy(&output.stdout);
+    assert!(page.contains(".TH usync 1"));
+    assert!(page.contains("completions"));
+
+    let output = Command::new(get_binary_path())
+        .args(["completions", "tcsh"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_invalid_option_values_exit_before_copying() {
+    let (_temp, test_dir) = setup_test_env();
+    let dst = test_dir.join("output/never.txt");
+    for args in [
+        ["--checksum=foo", "--verify-mode", "strict"],
+        ["--checksum", "--verify-mode", "sometimes"],
+        ["--copy-mode", "fast", "--checksum=md5"],
+    ] {
+        let output = Command::new(get_binary_path())
+            .args(args)
+            .arg(test_dir.join("input/test1.txt"))
+            .arg(&dst)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("possible values"));
+        assert!(!dst.exists(), "{:?}", args);
+    }
+}
+
+#[test]
+fn test_environment_variables() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input/test1.txt");
+    let run = |env: &[(&str, &str)], args: &[&str]| {
+        Command::new(get_binary_path())
+            .env_remove("USYNC_VERBOSE")
+            .env_remove("USYNC_TMPDIR")
+            .envs(env.iter().copied())
+            .args(args)
+            .arg(&src)
+            .arg(test_dir.join("output/env.txt"))
+            .output()
+            .unwrap()
+    };
+
+    // Every switch reads its variable the same way.
+    for (value, on) in [
+        ("1", true),
+        ("yes", true),
+        ("0", false),
+        ("false", false),
+        ("off", false),
+        ("", false),
+    ] {
+        let output = run(&[("USYNC_VERBOSE", value)], &[]);
+        assert!(output.status.success());
+        let verbose = String::from_utf8_lossy(&output.stdout).contains("Copy Summary");
+        assert_eq!(verbose, on, "USYNC_VERBOSE={:?}", value);
+    }
+
+    // A flag beats the environment; an empty variable counts as unset.
+    let missing = test_dir.join("no-such-dir");
+    let missing = missing.to_str().unwrap();
+    assert!(!run(&[("USYNC_TMPDIR", missing)], &[]).status.success());
+    let tmp = test_dir.join("output");
+    assert!(run(
+        &[("USYNC_TMPDIR", missing)],
+        &["--tmpdir", tmp.to_str().unwrap()]
+    )
+    .status
+    .success());
+    assert!(run(&[("USYNC_TMPDIR", "")], &[]).status.success());
+
+    let output = run(&[("USYNC_CHECKSUM", "crc32")], &[]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
 #[test]
 fn test_multiple_files_in_directory() {
     let (_temp, test_dir) = setup_test_env();
@@ -386,3 +993,1988 @@ fn test_large_file_copy() {
     assert_eq!(copied_content.len(), large_content.len());
     assert_eq!(copied_content, large_content);
 }
+
+#[test]
+fn test_stats_detail_lists_slowest_files() {
+    let (_temp, test_dir) = setup_test_env();
+    let output = Command::new(get_binary_path())
+        .arg("-r")
+        .arg("--stats-detail=2")
+        .arg(format!("{}/", test_dir.join("input").display()))
+        .arg(test_dir.join("out").to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let listed: Vec<&str> = stdout
+        .lines()
+        .skip_while(|line| *line != "Slowest files:")
+        .skip(1)
+        .collect();
+    assert_eq!(listed.len(), 2, "{}", stdout);
+    assert!(listed.iter().all(|line| line.contains(" MB/s  ")));
+}
+
+#[test]
+fn test_checksum_manifest_and_verify() {
+    let (_temp, test_dir) = setup_test_env();
+    let tree = test_dir.join("input");
+    let manifest = test_dir.join("manifest.sha256");
+
+    let output = Command::new(get_binary_path())
+        .arg("checksum")
+        .arg(tree.to_str().unwrap())
+        .arg("--output")
+        .arg(manifest.to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let content = fs::read_to_string(&manifest).unwrap();
+    assert_eq!(content.lines().count(), 4);
+    assert!(content.contains("  subdir/nested.txt\n"));
+
+    let output = Command::new(get_binary_path())
+        .arg("checksum")
+        .arg("--verify")
+        .arg(manifest.to_str().unwrap())
+        .arg(tree.to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    fs::write(tree.join("test1.txt"), "tampered").unwrap();
+    fs::write(tree.join("extra.txt"), "extra").unwrap();
+    let output = Command::new(get_binary_path())
+        .arg("checksum")
+        .arg("--verify")
+        .arg(manifest.to_str().unwrap())
+        .arg(tree.to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("FAILED: test1.txt"));
+    assert!(stdout.contains("EXTRA: extra.txt"));
+}
+
+#[test]
+fn test_tag_checksums_and_check_cached() {
+    let (_temp, test_dir) = setup_test_env();
+    let backup = test_dir.join("backup");
+
+    let output = Command::new(get_binary_path())
+        .arg("-r")
+        .arg("--checksum")
+        .arg("--tag-checksums")
+        .arg("--no-state")
+        .arg(format!("{}/", test_dir.join("input").display()))
+        .arg(backup.to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let cached = || {
+        Command::new(get_binary_path())
+            .args(["checksum", "--cached", backup.to_str().unwrap()])
+            .output()
+            .unwrap()
+    };
+    let output = cached();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("4 OK, 0 failed, 0 untagged"));
+
+    fs::write(backup.join("subdir/nested.txt"), "bit rot").unwrap();
+    let output = cached();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("FAILED: subdir/nested.txt"), "{}", stdout);
+    assert!(stdout.contains("3 OK, 1 failed"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_dedupe_hard_links_identical_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (_temp, test_dir) = setup_test_env();
+    let tree = test_dir.join("dedupe");
+    fs::create_dir_all(tree.join("copy")).unwrap();
+    fs::write(tree.join("data.bin"), "duplicate payload").unwrap();
+    fs::write(tree.join("copy/data.bin"), "duplicate payload").unwrap();
+
+    let output = Command::new(get_binary_path())
+        .args(["dedupe", "--dry-run", tree.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("keep copy/data.bin"));
+    assert!(stdout.contains("link data.bin"));
+    assert!(stdout.contains("17 bytes would be reclaimed"));
+    assert_eq!(fs::metadata(tree.join("data.bin")).unwrap().nlink(), 1);
+
+    let output = Command::new(get_binary_path())
+        .args(["dedupe", tree.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        fs::metadata(tree.join("data.bin")).unwrap().ino(),
+        fs::metadata(tree.join("copy/data.bin")).unwrap().ino()
+    );
+}
+
+#[test]
+fn test_also_to_copies_to_every_destination() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input/test1.txt");
+    let first = test_dir.join("first.txt");
+    let second = test_dir.join("second.txt");
+
+    let output = Command::new(get_binary_path())
+        .arg(src.to_str().unwrap())
+        .arg(first.to_str().unwrap())
+        .arg("--also-to")
+        .arg(second.to_str().unwrap())
+        .arg("--checksum")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let content = fs::read_to_string(&src).unwrap();
+    assert_eq!(fs::read_to_string(&first).unwrap(), content);
+    assert_eq!(fs::read_to_string(&second).unwrap(), content);
+
+    // One bad destination fails the run but not the others.
+    fs::remove_file(&second).unwrap();
+    let output = Command::new(get_binary_path())
+        .arg(src.to_str().unwrap())
+        .arg(test_dir.join("input/test1.txt/nested").to_str().unwrap())
+        .arg("--also-to")
+        .arg(second.to_str().unwrap())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(fs::read_to_string(&second).unwrap(), content);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 2 destination(s) failed"));
+}
+
+/// The stand-in `ssh` and `scp` of [`usync::mock`], set up in `test_dir`.
+#[cfg(unix)]
+fn fake_ssh(test_dir: &std::path::Path) -> MockRemote {
+    MockRemote::in_dir(test_dir).unwrap()
+}
+
+/// A PATH with the stand-in `ssh` and `scp` first.
+#[cfg(unix)]
+fn fake_ssh_path(test_dir: &std::path::Path) -> std::ffi::OsString {
+    fake_ssh(test_dir).path_env()
+}
+
+#[test]
+#[cfg(unix)]
+fn test_directory_upload_to_ssh_with_exclude() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::write(src.join("app.js.map"), "sourcemap").unwrap();
+    fs::write(src.join("subdir/app.js.map"), "sourcemap").unwrap();
+    fs::create_dir_all(src.join("empty")).unwrap();
+    let remote = test_dir.join("remote/site");
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", fake_ssh_path(&test_dir))
+        .arg("-r")
+        .arg("-v")
+        .arg("--no-ssh-multiplex")
+        .arg("--exclude")
+        .arg("*.map")
+        .arg(format!("{}/", src.display()))
+        .arg(format!("user@localhost:{}/", remote.display()))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert_eq!(
+        fs::read_to_string(remote.join("subdir/nested.txt")).unwrap(),
+        "nested content"
+    );
+    assert!(remote.join("test1.txt").exists());
+    assert!(remote.join("empty").is_dir());
+    assert!(!remote.join("app.js.map").exists());
+    assert!(!remote.join("subdir/app.js.map").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Files copied: 4"), "{}", stdout);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compress_reaches_ssh_and_scp() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let remote = mock.root().join("site");
+    fs::create_dir_all(&remote).unwrap();
+    let run = |args: &[&str], src: String, dst: String| {
+        let output = Command::new(get_binary_path())
+            .env("PATH", mock.path_env())
+            .args(["-z", "--no-ssh-multiplex"])
+            .args(args)
+            .arg(src)
+            .arg(dst)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    let compressed = |call: &Vec<String>| call.windows(2).any(|p| p == ["-o", "Compression=yes"]);
+
+    run(
+        &[],
+        test_dir.join("input/test1.txt").display().to_string(),
+        format!("user@localhost:{}/one.txt", remote.display()),
+    );
+    let calls = mock.calls();
+    let scp: Vec<_> = calls.iter().filter(|c| c[0] == "scp").collect();
+    assert!(!scp.is_empty());
+    assert!(scp.iter().all(|c| compressed(c)), "{:?}", scp);
+
+    run(
+        &["-r", "--ssh-batch"],
+        format!("{}/", test_dir.join("input").display()),
+        format!("user@localhost:{}/tree/", remote.display()),
+    );
+    let calls = mock.calls();
+    let tar = calls
+        .iter()
+        .find(|c| c[0] == "ssh" && c.iter().any(|a| a.contains("tar -xzf")))
+        .expect("a gzip'd tar upload");
+    assert!(compressed(tar), "{:?}", tar);
+    assert!(calls.iter().filter(|c| c[0] == "ssh").all(compressed));
+    assert_eq!(
+        fs::read_to_string(remote.join("tree/subdir/nested.txt")).unwrap(),
+        "nested content"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_failed_tar_upload_resends_the_rest() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let src = test_dir.join("batch");
+    fs::create_dir_all(&src).unwrap();
+    for i in 0..20 {
+        fs::write(
+            src.join(format!("{:02}.dat", i)),
+            vec![b'a' + i as u8; 10240],
+        )
+        .unwrap();
+    }
+    // Each file is a 512-byte header and 10240 bytes of data: five arrive whole, the
+    // sixth is cut short.
+    mock.cut_tar_streams(Some(5 * 10752 + 3000)).unwrap();
+    let remote = test_dir.join("remote/batch");
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", mock.path_env())
+        .arg("-r")
+        .arg("-v")
+        .arg("--ssh-batch")
+        .arg("--no-ssh-multiplex")
+        .arg(format!("{}/", src.display()))
+        .arg(format!("user@localhost:{}/", remote.display()))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    for i in 0..20 {
+        let name = format!("{:02}.dat", i);
+        assert_eq!(
+            fs::read(remote.join(&name)).unwrap(),
+            fs::read(src.join(&name)).unwrap(),
+            "{}",
+            name
+        );
+    }
+    let scp_calls = mock.calls().iter().filter(|c| c[0] == "scp").count();
+    assert_eq!(scp_calls, 15);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("5 file(s) arrived"), "{}", stdout);
+    assert!(stdout.contains("Files copied: 20"), "{}", stdout);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_truncated_ssh_transfer_is_an_error() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let path = mock.path_env();
+    let src = test_dir.join("input/test1.txt");
+    let size = fs::metadata(&src).unwrap().len();
+    let remote = test_dir.join("remote.txt");
+
+    let upload = |truncate: bool| {
+        mock.truncate_transfers(truncate).unwrap();
+        Command::new(get_binary_path())
+            .env("PATH", &path)
+            .arg("--no-ssh-multiplex")
+            .arg(&src)
+            .arg(format!("user@localhost:{}", remote.display()))
+            .output()
+            .unwrap()
+    };
+
+    let output = upload(false);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = upload(true);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Incomplete transfer"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("is {} bytes, expected {} bytes", size - 2, size)),
+        "{}",
+        stderr
+    );
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", &path)
+        .arg("--no-ssh-multiplex")
+        .arg(format!("user@localhost:{}", src.display()))
+        .arg(test_dir.join("download.txt"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Incomplete transfer"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_stream_stdin_to_ssh() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let (_temp, test_dir) = setup_test_env();
+    let remote = test_dir.join("remote/db.sql");
+    fs::create_dir_all(remote.parent().unwrap()).unwrap();
+
+    let mut child = Command::new(get_binary_path())
+        .env("PATH", fake_ssh_path(&test_dir))
+        .arg("--no-ssh-multiplex")
+        .arg("--checksum")
+        .arg("-")
+        .arg(format!("user@localhost:{}", remote.display()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"CREATE TABLE t (id int);\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(&remote).unwrap(),
+        "CREATE TABLE t (id int);\n"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_ssh_to_ssh_copy_with_and_without_scp_3() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let path = mock.path_env();
+    let tmpdir = test_dir.join("tmp");
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    for (dst, no_scp_3) in [("direct", false), ("relayed", true)] {
+        let dst = test_dir.join(dst);
+        mock.without_scp_3(no_scp_3).unwrap();
+        let mut cmd = Command::new(get_binary_path());
+        cmd.env("PATH", &path)
+            .arg("-r")
+            .arg("-v")
+            .arg("--no-ssh-multiplex")
+            .arg("--tmpdir")
+            .arg(&tmpdir)
+            .arg(format!("user@host1:{}", test_dir.join("input").display()))
+            .arg(format!("user@host2:{}", dst.display()));
+        let output = cmd.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            fs::read_to_string(dst.join("subdir/nested.txt")).unwrap(),
+            "nested content"
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("Files copied: 4"), "{}", stdout);
+        assert_eq!(stdout.contains("Relaying through a local copy"), no_scp_3);
+    }
+    assert_eq!(fs::read_dir(&tmpdir).unwrap().count(), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_ssh_download_detects_directories() {
+    let (_temp, test_dir) = setup_test_env();
+    let path = fake_ssh_path(&test_dir);
+    // A remote directory holding only files, no subdirectories.
+    let flat = test_dir.join("flat");
+    fs::create_dir_all(&flat).unwrap();
+    fs::write(flat.join("a.txt"), "aaa").unwrap();
+    fs::write(flat.join("b.txt"), "bb").unwrap();
+
+    let download = |src: &std::path::Path, dst: &std::path::Path, recursive: bool| {
+        let mut cmd = Command::new(get_binary_path());
+        cmd.env("PATH", &path).arg("-v").arg("--no-ssh-multiplex");
+        if recursive {
+            cmd.arg("-r");
+        }
+        cmd.arg(format!("user@localhost:{}", src.display()))
+            .arg(dst)
+            .output()
+            .unwrap()
+    };
+
+    let output = download(&flat, &test_dir.join("flat-copy"), true);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(test_dir.join("flat-copy/a.txt")).unwrap(),
+        "aaa"
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Files copied: 2"), "{}", stdout);
+
+    let output = download(&flat, &test_dir.join("no-r"), false);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a directory; use -r"));
+    assert!(!test_dir.join("no-r").exists());
+
+    // A single remote file is still a file, with or without -r.
+    for (dst, recursive) in [("single.txt", false), ("single-r.txt", true)] {
+        let output = download(&flat.join("b.txt"), &test_dir.join(dst), recursive);
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(fs::read_to_string(test_dir.join(dst)).unwrap(), "bb");
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_list_local_and_ssh() {
+    let (_temp, test_dir) = setup_test_env();
+    let input = test_dir.join("input");
+    fs::write(input.join("big.bin"), vec![b'x'; 4096]).unwrap();
+    fs::write(input.join("subdir/mid.bin"), vec![b'x'; 1024]).unwrap();
+    let path = fake_ssh_path(&test_dir);
+
+    let list = |src: String, extra: &[&str]| {
+        let output = Command::new(get_binary_path())
+            .env("PATH", &path)
+            .arg("list")
+            .arg(src)
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let local = list(input.display().to_string(), &[]);
+    let names: Vec<&str> = local
+        .lines()
+        .map(|l| l.rsplit("  ").next().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "big.bin",
+            "subdir/mid.bin",
+            "subdir/nested.txt",
+            "subdir/test3.txt",
+            "test1.txt",
+            "test2.txt"
+        ]
+    );
+
+    let args = [
+        "--sort",
+        "size",
+        "--reverse",
+        "--limit",
+        "2",
+        "--exclude",
+        "*.txt",
+        "-s",
+        "ControlMaster=no",
+    ];
+    let expected = list(input.display().to_string(), &args);
+    assert_eq!(expected.lines().count(), 2);
+    assert!(expected
+        .lines()
+        .next()
+        .unwrap()
+        .trim_start()
+        .starts_with("4096 "));
+    assert!(expected.ends_with("  subdir/mid.bin\n"), "{}", expected);
+
+    // The same listing over ssh, with the remote `find`.
+    let remote = list(format!("user@localhost:{}", input.display()), &args);
+    assert_eq!(remote, expected);
+    let file = list(
+        format!("user@localhost:{}", input.join("test1.txt").display()),
+        &[],
+    );
+    assert!(file.trim_start().starts_with("14 "), "{}", file);
+    assert!(file.ends_with("  test1.txt\n"), "{}", file);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_diff_local_and_ssh() {
+    let (_temp, test_dir) = setup_test_env();
+    let a = test_dir.join("input");
+    let b = test_dir.join("mirror");
+    fs::create_dir_all(b.join("subdir")).unwrap();
+    for name in ["test1.txt", "subdir/nested.txt"] {
+        fs::copy(a.join(name), b.join(name)).unwrap();
+    }
+    // Same size, other contents.
+    fs::write(b.join("test2.txt"), "test content X").unwrap();
+    fs::write(b.join("stale.log"), "old").unwrap();
+    let path = fake_ssh_path(&test_dir);
+
+    let diff = |b: String, extra: &[&str]| {
+        let output = Command::new(get_binary_path())
+            .env("PATH", &path)
+            .arg("diff")
+            .arg(a.display().to_string())
+            .arg(b)
+            .args(extra)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+
+    let (code, text) = diff(b.display().to_string(), &["--checksum"]);
+    assert_eq!(code, Some(1));
+    assert!(
+        text.contains("Only in ")
+            && text.contains(
+                "  subdir/test3.txt
+"
+            ),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains(
+            "  stale.log
+"
+        ),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains("Different:\n  test2.txt  (contents (sha256))\n"),
+        "{}",
+        text
+    );
+    assert!(
+        text.ends_with("1 only in A, 1 only in B, 1 different, 2 the same\n"),
+        "{}",
+        text
+    );
+
+    // The same over ssh, as JSON.
+    let remote = format!("user@localhost:{}", b.display());
+    let (code, json) = diff(remote.clone(), &["--checksum", "--json"]);
+    assert_eq!(code, Some(1));
+    let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["only_in_a"][0]["path"], "subdir/test3.txt");
+    assert_eq!(json["only_in_b"][0]["path"], "stale.log");
+    assert_eq!(json["changed"][0]["path"], "test2.txt");
+    assert_eq!(json["changed"][0]["reason"], "checksum");
+    assert_eq!(json["same"], 2);
+
+    let (code, _) = diff(
+        remote,
+        &[
+            "--exclude",
+            "*.log",
+            "--exclude",
+            "test2.txt",
+            "--exclude",
+            "test3.txt",
+        ],
+    );
+    assert_eq!(code, Some(0));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_compare_skips_unchanged_ssh_uploads() {
+    let (_temp, test_dir) = setup_test_env();
+    let path = fake_ssh_path(&test_dir);
+    let src = test_dir.join("input");
+    let remote = test_dir.join("remote");
+
+    let upload = |compare: &str| {
+        let output = Command::new(get_binary_path())
+            .env("PATH", &path)
+            .arg("-r")
+            .arg("-v")
+            .arg("--no-ssh-multiplex")
+            .arg("--compare")
+            .arg(compare)
+            .arg(format!("{}/", src.display()))
+            .arg(format!("user@localhost:{}/", remote.display()))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let first = upload("size-mtime");
+    assert!(first.contains("Files copied: 4"), "{}", first);
+    fs::write(src.join("test1.txt"), "changed size").unwrap();
+    let second = upload("size-only");
+    assert!(second.contains("Files copied: 1"), "{}", second);
+    assert!(second.contains("Files skipped: 3"), "{}", second);
+    assert_eq!(
+        fs::read_to_string(remote.join("test1.txt")).unwrap(),
+        "changed size"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_json_progress_for_directory_uploads() {
+    let (_temp, test_dir) = setup_test_env();
+    let path = fake_ssh_path(&test_dir);
+    let src = test_dir.join("input");
+    let remote = test_dir.join("remote");
+    fs::create_dir_all(&remote).unwrap();
+    fs::copy(src.join("test1.txt"), remote.join("test1.txt")).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", &path)
+        .arg("-r")
+        .arg("-p")
+        .arg("--progress-format")
+        .arg("json")
+        .arg("--no-ssh-multiplex")
+        .arg("--compare")
+        .arg("existence")
+        .arg(format!("{}/", src.display()))
+        .arg(format!("user@localhost:{}/", remote.display()))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let events: Vec<&str> = stderr.lines().filter(|l| l.starts_with('{')).collect();
+    // Four files listed, one already there: three (with 42 bytes) to send.
+    assert_eq!(
+        events,
+        [
+            r#"{"event":"compare","examined":4,"total":4}"#,
+            r#"{"event":"plan","files":3,"bytes":42}"#,
+            r#"{"event":"transfer","files":1,"files_total":3,"bytes":14,"bytes_total":42}"#,
+            r#"{"event":"transfer","files":2,"files_total":3,"bytes":28,"bytes_total":42}"#,
+            r#"{"event":"transfer","files":3,"files_total":3,"bytes":42,"bytes_total":42}"#,
+            r#"{"event":"done","files":3,"bytes":42}"#,
+        ],
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_bare_host_remote() {
+    let (_temp, test_dir) = setup_test_env();
+    let dst = test_dir.join("from-alias.txt");
+
+    // `localhost` stands in for an alias from ~/.ssh/config: no user is put in front.
+    let output = Command::new(get_binary_path())
+        .env("PATH", fake_ssh_path(&test_dir))
+        .arg("--no-ssh-multiplex")
+        .arg(format!(
+            "localhost:{}",
+            test_dir.join("input/test1.txt").display()
+        ))
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "test content 1");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_ssh_password_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_temp, test_dir) = setup_test_env();
+    let path = fake_ssh_path(&test_dir);
+    // A server that only takes passwords: scp asks through SSH_ASKPASS like ssh would.
+    let server = test_dir.join("server");
+    fs::create_dir_all(&server).unwrap();
+    fs::write(
+        server.join("scp"),
+        format!(
+            "#!/bin/sh\n\
+             if [ \"$(\"$SSH_ASKPASS\" \"me@nas's password: \")\" != 'open sesame' ]; then\n\
+               echo 'me@nas: Permission denied (publickey,password).' >&2; exit 255\n\
+             fi\n\
+             exec {}/scp \"$@\"\n",
+            test_dir.join("bin").display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(server.join("scp"), fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", server.display(), path.to_string_lossy());
+
+    let password = test_dir.join("password");
+    let copy = |password_file: &str, dst: &str| {
+        fs::write(&password, password_file).unwrap();
+        fs::set_permissions(&password, fs::Permissions::from_mode(0o600)).unwrap();
+        Command::new(get_binary_path())
+            .env("PATH", &path)
+            .env_remove("SSH_ASKPASS")
+            .arg("--no-ssh-multiplex")
+            .arg("-v")
+            .arg("--ssh-password-file")
+            .arg(&password)
+            .arg(test_dir.join("input/test1.txt"))
+            .arg(format!("me@nas:{}", test_dir.join(dst).display()))
+            .output()
+            .unwrap()
+    };
+
+    let output = copy("open sesame\n", "uploaded.txt");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(test_dir.join("uploaded.txt")).unwrap(),
+        "test content 1"
+    );
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!printed.contains("open sesame"), "{}", printed);
+
+    let output = copy("wrong\n", "rejected.txt");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Authentication error: me@nas: Permission denied"),
+        "{}",
+        stderr
+    );
+    assert!(!test_dir.join("rejected.txt").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_ssh_sync_and_verify_against_the_mock() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let src = test_dir.join("input");
+    let sync = || {
+        Command::new(get_binary_path())
+            .env("PATH", mock.path_env())
+            .arg("-r")
+            .arg("--no-ssh-multiplex")
+            .arg("--compare=size-only")
+            .arg("--checksum")
+            .arg(format!("{}/", src.display()))
+            .arg(format!("{}/", mock.url("site")))
+            .output()
+            .unwrap()
+    };
+    let scp_calls = || mock.calls().iter().filter(|c| c[0] == "scp").count();
+
+    let output = sync();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(mock.root().join("site/subdir/nested.txt")).unwrap(),
+        "nested content"
+    );
+    let uploads = scp_calls();
+    assert!(uploads > 0);
+
+    // Nothing changed: the second run only lists and hashes over ssh.
+    let output = sync();
+    assert!(output.status.success());
+    assert_eq!(scp_calls(), uploads);
+
+    fs::write(src.join("test2.txt"), "changed and longer").unwrap();
+    mock.fail("test2.txt").unwrap();
+    let output = sync();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Permission denied"), "{}", stderr);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_chmod_overrides_local_and_ssh_modes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::set_permissions(src.join("test1.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::set_permissions(src.join("subdir"), fs::Permissions::from_mode(0o700)).unwrap();
+    let mode = |p: &std::path::Path| fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+
+    // --perms carries 0600 over; the explicit --chmod still wins.
+    let local = test_dir.join("output/local");
+    let output = Command::new(get_binary_path())
+        .args(["-r", "--perms", "--chmod", "D755,F644"])
+        .arg(format!("{}/", src.display()))
+        .arg(&local)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(mode(&local.join("test1.txt")), 0o644);
+    assert_eq!(mode(&local.join("subdir")), 0o755);
+    assert_eq!(mode(&local.join("subdir/nested.txt")), 0o644);
+
+    let mock = fake_ssh(&test_dir);
+    let output = Command::new(get_binary_path())
+        .env("PATH", mock.path_env())
+        .args(["-r", "--no-ssh-multiplex", "--chmod", "640,750"])
+        .arg(format!("{}/", src.display()))
+        .arg(format!("{}/", mock.url("site")))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let site = mock.root().join("site");
+    assert_eq!(mode(&site.join("test1.txt")), 0o640);
+    assert_eq!(mode(&site.join("subdir")), 0o750);
+    assert_eq!(mode(&site.join("subdir/nested.txt")), 0o640);
+    assert_eq!(mode(&site), 0o750);
+
+    let output = Command::new(get_binary_path())
+        .args(["--chmod", "u+q"])
+        .arg(src.join("test2.txt"))
+        .arg(test_dir.join("output/bad.txt"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid --chmod clause"));
+}
+
+#[test]
+fn test_missing_destination_parent_needs_mkpath() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input/test1.txt");
+    let dst = test_dir.join("output/new/deeper/test1.txt");
+
+    let output = Command::new(get_binary_path())
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Destination directory does not exist"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("--mkpath"), "{}", stderr);
+    assert!(!test_dir.join("output/new").exists());
+
+    let output = Command::new(get_binary_path())
+        .arg("--mkpath")
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "test content 1");
+}
+
+#[test]
+fn test_fat_safe_renames_and_reports() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input/names");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("meeting 10:00?.txt"), "notes").unwrap();
+    let dst = test_dir.join("output/stick");
+
+    let output = Command::new(get_binary_path())
+        .args(["-r", "-v", "--fat-safe", "--sanitize-char=-"])
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("Names sanitized: 1"), "{}", stdout);
+    assert_eq!(
+        fs::read_to_string(dst.join("meeting 10-00-.txt")).unwrap(),
+        "notes"
+    );
+
+    let output = Command::new(get_binary_path())
+        .args(["-r", "--sanitize-names=fat", "--sanitize-char=:"])
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_sync_lock_stops_a_second_run() {
+    let (_temp, test_dir) = setup_test_env();
+    let state = test_dir.join("state");
+    let dst = test_dir.join("output/synced");
+    let sync = |extra: &[&str]| {
+        Command::new(get_binary_path())
+            .env("XDG_STATE_HOME", &state)
+            .args(["-r", "--compare", "size-only"])
+            .args(extra)
+            .arg(test_dir.join("input"))
+            .arg(&dst)
+            .output()
+            .unwrap()
+    };
+
+    let output = sync(&[]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let lock_path = fs::read_dir(state.join("usync/locks"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+
+    // Stand in for a sync of the same destination that is still running.
+    let held = fs::File::options()
+        .read(true)
+        .write(true)
+        .open(&lock_path)
+        .unwrap();
+    held.try_lock().unwrap();
+    fs::write(&lock_path, "4242\n1700000000\n").unwrap();
+
+    for extra in [&[][..], &["--wait-lock", "1"][..]] {
+        let output = sync(extra);
+        assert_eq!(output.status.code(), Some(75));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("another usync is syncing this destination")
+                && stderr.contains("pid 4242"),
+            "{}",
+            stderr
+        );
+    }
+    // --no-lock goes ahead regardless.
+    assert!(sync(&["--no-lock"]).status.success());
+
+    drop(held);
+    assert!(sync(&[]).status.success());
+}
+
+#[test]
+fn test_sync_skips_unreadable_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_temp, test_dir) = setup_test_env();
+    let input = test_dir.join("input");
+    let secret = input.join("secret.txt");
+    fs::write(&secret, "secret").unwrap();
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads it anyway; there is nothing to skip then.
+    if fs::File::open(&secret).is_ok() {
+        return;
+    }
+    let dst = test_dir.join("output/synced");
+    let sync = |extra: &[&str]| {
+        Command::new(get_binary_path())
+            .env("XDG_STATE_HOME", test_dir.join("state"))
+            .args(["-r", "--compare", "size-only"])
+            .args(extra)
+            .arg(&input)
+            .arg(&dst)
+            .output()
+            .unwrap()
+    };
+
+    let output = sync(&["-v"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(23), "{}", stderr);
+    assert!(stdout.contains(&format!("Unreadable: {}", secret.display())));
+    assert!(stdout.contains("Files failed: 1\n"));
+    assert!(stderr.contains("1 path(s) could not be read and were skipped"));
+    assert!(stderr.contains(&secret.display().to_string()));
+    assert!(dst.join("test1.txt").exists());
+    assert!(!dst.join("secret.txt").exists());
+
+    let output = sync(&["--no-continue-on-error"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_color_only_when_asked_for_on_pipes() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input").join("test1.txt");
+    let run = |extra: &[&str], missing: bool| {
+        let source = if missing {
+            test_dir.join("input/missing.txt")
+        } else {
+            src.clone()
+        };
+        let output = Command::new(get_binary_path())
+            .env_remove("NO_COLOR")
+            .env_remove("CLICOLOR")
+            .env_remove("CLICOLOR_FORCE")
+            .args(extra)
+            .arg(&source)
+            .arg(test_dir.join("output/copy.txt"))
+            .output()
+            .unwrap();
+        format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )
+    };
+
+    // Both the "Copied" line and an "Error:" line, piped.
+    for missing in [false, true] {
+        let plain = run(&[], missing);
+        assert!(!plain.is_empty());
+        assert!(!plain.contains('\x1b'), "{:?}", plain);
+        assert!(!run(&["--color", "never"], missing).contains('\x1b'));
+        assert!(!run(&["--color", "always", "--no-color"], missing).contains('\x1b'));
+
+        // Only a binary built with the color feature has any color to give.
+        let forced = run(&["--color", "always"], missing);
+        assert_eq!(
+            forced.contains("\x1b["),
+            cfg!(feature = "color"),
+            "{:?}",
+            forced
+        );
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_move_from_ssh_removes_the_source_only_when_safe() {
+    let (_temp, test_dir) = setup_test_env();
+    let mv = |mock: &MockRemote, extra: &[&str]| {
+        let src = mock.root().join("notes.txt");
+        fs::write(&src, "notes").unwrap();
+        let dst = test_dir.join("output/notes.txt");
+        let _ = fs::remove_file(&dst);
+        let output = Command::new(get_binary_path())
+            .env("PATH", mock.path_env())
+            .args(["--move", "--no-ssh-multiplex"])
+            .args(extra)
+            .arg(mock.url("notes.txt"))
+            .arg(&dst)
+            .output()
+            .unwrap();
+        assert_eq!(fs::read_to_string(&dst).ok().as_deref(), Some("notes"));
+        (output, src.exists())
+    };
+
+    // Copied, then the removal fails: a warning, and the copy stands.
+    let mock = fake_ssh(&test_dir);
+    mock.fail("rm -rf").unwrap();
+    let (output, kept) = mv(&mock, &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("failed to remove source"), "{}", stderr);
+    assert!(kept);
+
+    // Copied, but the checksum can't be taken on the remote side.
+    let mock = MockRemote::new().unwrap();
+    mock.fail("sha256sum").unwrap();
+    let (output, kept) = mv(&mock, &["--checksum"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("source kept, as the copy could not be verified"),
+        "{}",
+        stderr
+    );
+    assert!(kept);
+    let (output, kept) = mv(&mock, &["--checksum", "--verify-mode", "strict"]);
+    assert!(!output.status.success());
+    assert!(kept);
+
+    let (output, kept) = mv(&mock, &[]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!kept);
+}
+
+#[test]
+fn test_limits_stop_the_transfer() {
+    let (_temp, test_dir) = setup_test_env();
+    let input = test_dir.join("input");
+    let run = |dst: &str, extra: &[&str]| {
+        Command::new(get_binary_path())
+            .env("XDG_STATE_HOME", test_dir.join("state"))
+            .arg("-r")
+            .args(extra)
+            .arg(&input)
+            .arg(test_dir.join("output").join(dst))
+            .output()
+            .unwrap()
+    };
+    let files_in = |dst: &str| {
+        let mut dirs = vec![test_dir.join("output").join(dst)];
+        let mut files = 0;
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                if entry.path().is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    files += 1;
+                }
+            }
+        }
+        files
+    };
+
+    // Over the plan: refused before anything is written.
+    let output = run("a", &["--max-files", "3"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(25), "{}", stderr);
+    assert!(
+        stderr.contains("--max-files 3 exceeded: 4 files planned; nothing was copied"),
+        "{}",
+        stderr
+    );
+    assert_eq!(files_in("a"), 0);
+    let output = run("a", &["--max-total-size", "40"]);
+    assert_eq!(output.status.code(), Some(25));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("--max-total-size 40 B exceeded: 56 B (56 bytes) planned"));
+
+    // A sync only copies what changed, so it counts as it goes.
+    let output = run(
+        "b",
+        &[
+            "--compare",
+            "size-only",
+            "--max-files",
+            "2",
+            "--soft-limit",
+            "-v",
+        ],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("stopped early: --max-files 2 exceeded: 3 files attempted"),
+        "{}",
+        stderr
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Stopped: --max-files 2"));
+    assert_eq!(files_in("b"), 2);
+
+    // The copy is complete, but removing the source would go over --max-deletes.
+    let output = run("c", &["--move", "--max-deletes", "3"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(25), "{}", stderr);
+    assert!(stderr.contains("source kept"), "{}", stderr);
+    assert!(stderr.contains("--max-deletes 3 exceeded: 4 files would be removed"));
+    assert_eq!(files_in("c"), 4);
+    assert!(input.join("test1.txt").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_delete_refuses_to_empty_the_destination() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let remote = mock.root().join("site");
+    fs::create_dir_all(remote.join("subdir")).unwrap();
+    for name in ["test1.txt", "test2.txt", "old.txt", "subdir/nested.txt"] {
+        fs::write(remote.join(name), "remote content").unwrap();
+    }
+    let run = |src: &std::path::Path, extra: &[&str]| {
+        Command::new(get_binary_path())
+            .env("PATH", mock.path_env())
+            .env("XDG_STATE_HOME", test_dir.join("state"))
+            .args(["-r", "--no-ssh-multiplex", "--delete"])
+            .args(extra)
+            .arg(format!("{}/", src.display()))
+            .arg(format!("user@localhost:{}/", remote.display()))
+            .output()
+            .unwrap()
+    };
+    let remote_files = || {
+        let mut names: Vec<_> = ["test1.txt", "test2.txt", "old.txt", "subdir/nested.txt"]
+            .into_iter()
+            .filter(|name| remote.join(name).exists())
+            .collect();
+        names.sort();
+        names
+    };
+
+    // A source that vanished (an unmounted share is an empty directory) never wipes
+    // the destination, even with --force-delete.
+    let vanished = test_dir.join("unmounted");
+    fs::create_dir_all(&vanished).unwrap();
+    let output = run(&vanished, &["--force-delete"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(25), "{}", stderr);
+    assert!(
+        stderr.contains("the source has no files, but the destination has 4"),
+        "{}",
+        stderr
+    );
+    assert_eq!(remote_files().len(), 4);
+
+    // Three of the four would go: over the default 50%.
+    let partial = test_dir.join("partial");
+    fs::create_dir_all(&partial).unwrap();
+    fs::write(partial.join("test1.txt"), "test content 1").unwrap();
+    let output = run(&partial, &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(25), "{}", stderr);
+    assert!(stderr.contains("3 of the 4 files at the destination (75%)"));
+    assert_eq!(remote_files().len(), 4);
+    assert_eq!(
+        fs::read_to_string(remote.join("test1.txt")).unwrap(),
+        "remote content"
+    );
+
+    // Only old.txt is gone from the source.
+    let output = run(&test_dir.join("input"), &["-v"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Deleting: old.txt"), "{}", stdout);
+    assert!(stdout.contains("Files removed: 1"), "{}", stdout);
+    assert_eq!(
+        remote_files(),
+        ["subdir/nested.txt", "test1.txt", "test2.txt"]
+    );
+
+    // Past the ratio when asked to.
+    let output = run(&partial, &["--max-delete-ratio", "100%"]);
+    assert!(output.status.success());
+    assert_eq!(remote_files(), ["test1.txt"]);
+    assert!(!remote.join("subdir").exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_prune_empty_dirs_over_ssh() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let src = test_dir.join("input");
+    fs::create_dir_all(src.join("maps")).unwrap();
+    fs::write(src.join("maps/app.js.map"), "sourcemap").unwrap();
+    let remote = mock.root().join("site");
+    for dir in ["old/deeper", "cache/tmp"] {
+        fs::create_dir_all(remote.join(dir)).unwrap();
+    }
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", mock.path_env())
+        .args(["-r", "-v", "--no-ssh-multiplex", "--prune-empty-dirs"])
+        .args(["--exclude", "*.map", "--exclude", "cache"])
+        .arg(format!("{}/", src.display()))
+        .arg(format!("user@localhost:{}/", remote.display()))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Pruning: old/deeper/"), "{}", stdout);
+    assert!(stdout.contains("Empty directories pruned:"), "{}", stdout);
+    assert!(!remote.join("old").exists());
+    assert!(!remote.join("maps").exists());
+    assert!(remote.join("cache/tmp").is_dir());
+    assert!(remote.join("subdir/nested.txt").exists());
+}
+
+#[test]
+fn test_skipped_copy_is_not_reported_as_copied() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    let dst = test_dir.join("output/mirror");
+    let sync = || {
+        Command::new(get_binary_path())
+            .args(["-r", "--compare", "size-mtime"])
+            .arg(format!("{}/", src.display()))
+            .arg(&dst)
+            .output()
+            .unwrap()
+    };
+    let first = sync();
+    assert!(String::from_utf8_lossy(&first.stdout).contains("Successfully copied"));
+
+    // Everything matches now.
+    let second = sync();
+    let stdout = String::from_utf8_lossy(&second.stdout);
+    assert!(second.status.success());
+    assert!(stdout.contains("Up to date"), "{}", stdout);
+    assert!(!stdout.contains("Successfully copied"), "{}", stdout);
+
+    // A file copied onto a hard link of itself is skipped too.
+    let link = test_dir.join("output/link.txt");
+    fs::hard_link(src.join("test1.txt"), &link).unwrap();
+    let output = Command::new(get_binary_path())
+        .arg(src.join("test1.txt"))
+        .arg(&link)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("is the same file"), "{}", stdout);
+    assert!(stdout.contains("Up to date"), "{}", stdout);
+    assert!(!stdout.contains("Successfully copied"), "{}", stdout);
+}
+
+/// A source named like a subcommand is copied when `--` or `./` says it is a path.
+#[test]
+fn test_source_named_like_a_subcommand() {
+    let (_temp, test_dir) = setup_test_env();
+    let dir = test_dir.join("output");
+    fs::write(dir.join("list"), "not a subcommand").unwrap();
+    for args in [&["--", "list", "copy1.txt"][..], &["./list", "copy2.txt"]] {
+        let output = Command::new(get_binary_path())
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let copy = dir.join(args.last().unwrap());
+        assert_eq!(fs::read_to_string(copy).unwrap(), "not a subcommand");
+    }
+}
+
+#[test]
+fn test_prune_empty_dirs_dry_run() {
+    let (_temp, test_dir) = setup_test_env();
+    let dst = test_dir.join("output/site");
+    fs::create_dir_all(dst.join("old/deeper")).unwrap();
+
+    let output = Command::new(get_binary_path())
+        .args(["-r", "--prune-empty-dirs", "--dry-run"])
+        .arg(format!("{}/", test_dir.join("input").display()))
+        .arg(&dst)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains("Would copy subdir/nested.txt"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Would prune old/deeper/"), "{}", stdout);
+    assert!(stdout.contains("Would prune old/"), "{}", stdout);
+    assert!(stdout.contains("2 empty directories pruned"), "{}", stdout);
+    assert!(dst.join("old/deeper").is_dir());
+    assert!(!dst.join("subdir").exists());
+}
+
+#[test]
+fn test_detect_renames_over_ssh() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let src = test_dir.join("input");
+    let video: Vec<u8> = (0..4_000_000u32).map(|i| (i % 253) as u8).collect();
+    fs::create_dir_all(src.join("inbox")).unwrap();
+    fs::write(src.join("inbox/trip.mp4"), &video).unwrap();
+    let remote = mock.root().join("library");
+    fs::create_dir_all(&remote).unwrap();
+    let sync = || {
+        let output = Command::new(get_binary_path())
+            .env("PATH", mock.path_env())
+            .args(["-r", "-v", "--no-ssh-multiplex", "--delete"])
+            .args(["--compare", "size-mtime", "--detect-renames"])
+            .arg(format!("{}/", src.display()))
+            .arg(format!("user@localhost:{}/", remote.display()))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    sync();
+    assert!(remote.join("inbox/trip.mp4").exists());
+
+    // Filed away between runs.
+    fs::create_dir_all(src.join("2024/travel")).unwrap();
+    fs::rename(src.join("inbox/trip.mp4"), src.join("2024/travel/trip.mp4")).unwrap();
+    fs::remove_dir(src.join("inbox")).unwrap();
+    let stdout = sync();
+    assert!(
+        stdout.contains("Moving: inbox/trip.mp4 -> 2024/travel/trip.mp4"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Files renamed:"), "{}", stdout);
+    assert!(!stdout.contains("Files deleted:"), "{}", stdout);
+    assert!(stdout.contains("Files copied: 0"), "{}", stdout);
+    assert_eq!(
+        fs::read(remote.join("2024/travel/trip.mp4")).unwrap(),
+        video
+    );
+    assert!(!remote.join("inbox").exists());
+}
+
+#[cfg(feature = "rpc")]
+#[test]
+fn test_serve_stdio_session() {
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    let dst = test_dir.join("output/served");
+    let big = test_dir.join("big.bin");
+    fs::File::create(&big).unwrap().set_len(256 << 20).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_usync"))
+        .args(["serve", "--stdio"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut replies = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut send = |request: Value| writeln!(stdin, "{}", request).unwrap();
+    let mut until_end = |id: u64| -> Vec<Value> {
+        let mut seen = Vec::new();
+        loop {
+            let reply: Value = serde_json::from_str(&replies.next().unwrap().unwrap()).unwrap();
+            let end =
+                reply["id"] == id && reply["event"] != "progress" && reply["event"] != "skipped";
+            seen.push(reply);
+            if end {
+                return seen;
+            }
+        }
+    };
+
+    let options = json!({"recursive": true, "exclude": ["nested.txt"]});
+    send(json!({"id": 1, "op": "copy", "src": src, "dst": dst, "options": options}));
+    let first = until_end(1);
+    let done = first.last().unwrap();
+    assert_eq!(done["event"], "done", "{:?}", first);
+    assert_eq!(done["report"]["files_copied"], 3);
+    assert_eq!(done["report"]["files_excluded"], 1);
+    assert_eq!(
+        fs::read_to_string(dst.join("subdir/test3.txt")).unwrap(),
+        "test content 3"
+    );
+    assert!(!dst.join("subdir/nested.txt").exists());
+
+    // Everything matches now.
+    send(json!({"id": 2, "op": "sync", "src": src, "dst": dst, "options": options}));
+    let second = until_end(2);
+    assert_eq!(second.iter().filter(|r| r["event"] == "skipped").count(), 3);
+    assert_eq!(second.last().unwrap()["report"]["operation"], "sync");
+
+    // A copy stopped right after it started leaves nothing half written.
+    let partial = test_dir.join("output/big.bin");
+    send(json!({"id": 3, "op": "copy", "src": big, "dst": partial}));
+    send(json!({"id": 3, "op": "cancel"}));
+    let third = until_end(3);
+    assert_eq!(third.last().unwrap()["event"], "cancelled", "{:?}", third);
+    assert!(!partial.exists());
+
+    send(json!({"id": 4, "op": "move", "src": src, "dst": dst}));
+    let fourth = until_end(4);
+    assert_eq!(fourth[0]["event"], "error");
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[cfg(all(feature = "ffi", unix))]
+#[test]
+fn test_ffi_smoke() {
+    let (_temp, test_dir) = setup_test_env();
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    // The crate isn't a cdylib by default. A target directory of its own keeps this
+    // build from being mistaken for, or overwritten by, builds with other features.
+    let target_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/target/ffi-smoke");
+    let built = Command::new(env!("CARGO"))
+        .args([
+            "rustc",
+            "--lib",
+            "--features",
+            "ffi",
+            "--crate-type",
+            "cdylib",
+            "--target-dir",
+            target_dir,
+        ])
+        .current_dir(manifest_dir)
+        .status()
+        .unwrap();
+    assert!(built.success());
+    let lib_dir = std::path::Path::new(target_dir).join("debug");
+    let smoke = test_dir.join("smoke");
+    let compiled = Command::new("cc")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/ffi/smoke.c"))
+        .arg("-I")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/include"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg("-lusync")
+        .arg("-o")
+        .arg(&smoke)
+        .status();
+    let Ok(compiled) = compiled else {
+        return; // No C compiler.
+    };
+    assert!(compiled.success());
+
+    let dst = test_dir.join("output/from-c");
+    let library_path = if cfg!(target_os = "macos") {
+        "DYLD_LIBRARY_PATH"
+    } else {
+        "LD_LIBRARY_PATH"
+    };
+    let output = Command::new(&smoke)
+        .arg(test_dir.join("input"))
+        .arg(&dst)
+        .env(library_path, &lib_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mut lines = stdout.lines();
+    let report: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+    assert_eq!(report["files_copied"], 4);
+    assert_eq!(lines.next(), Some("progress 14"));
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("error options: invalid type"));
+    assert_eq!(
+        fs::read_to_string(dst.join("subdir/nested.txt")).unwrap(),
+        "nested content"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_dry_run_sync_leaves_destination_alone() {
+    let (_temp, test_dir) = setup_test_env();
+    let mock = fake_ssh(&test_dir);
+    let local = test_dir.join("output/local");
+    let remote = mock.root().join("site");
+    for dir in [&local, &remote] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("test1.txt"), "older content").unwrap();
+        fs::write(dir.join("stale.txt"), "only here").unwrap();
+    }
+    let snapshot = |dir: &std::path::Path| {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path();
+                (path.clone(), fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = (snapshot(&local), snapshot(&remote));
+
+    // Deletions and changed files are pending on both sides, and a move would remove
+    // the source: a dry run lists all of it and does none of it.
+    for dst in [
+        format!("{}/", local.display()),
+        format!("user@localhost:{}/", remote.display()),
+    ] {
+        for extra in [
+            &["--delete", "--force-delete"][..],
+            &["--delete", "--compare", "size-mtime"],
+            &["--move"],
+        ] {
+            let output = Command::new(get_binary_path())
+                .env("PATH", mock.path_env())
+                .env("XDG_STATE_HOME", test_dir.join("state"))
+                .args(["-r", "--no-ssh-multiplex", "--dry-run"])
+                .args(extra)
+                .arg(format!("{}/", test_dir.join("input").display()))
+                .arg(&dst)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(output.status.success(), "{:?}: {}", extra, stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("Would copy test1.txt"), "{}", stdout);
+            let pending = if extra[0] == "--move" {
+                "would be removed"
+            } else {
+                "Would delete stale.txt"
+            };
+            assert!(stdout.contains(pending), "{:?}: {}", extra, stdout);
+            assert!(stdout.contains("nothing was changed"), "{}", stdout);
+        }
+    }
+    assert_eq!((snapshot(&local), snapshot(&remote)), before);
+    assert!(test_dir.join("input/test1.txt").exists());
+    // The SSH side was only listed.
+    assert!(
+        mock.calls()
+            .iter()
+            .all(|call| call[0] != "scp" && !call.join(" ").contains("rm -")),
+        "{:?}",
+        mock.calls()
+    );
+}
+
+#[test]
+fn test_order_small_first() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::write(src.join("subdir/big.bin"), vec![b'x'; 4096]).unwrap();
+    fs::write(src.join("a.txt"), "a").unwrap();
+    let output = Command::new(get_binary_path())
+        // One worker, so the lines come out in the order the files start.
+        .env("RAYON_NUM_THREADS", "1")
+        .args(["-r", "-v", "--order", "small-first"])
+        .arg(format!("{}/", src.display()))
+        .arg(test_dir.join("output/ordered"))
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let started: Vec<_> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("Copying file: "))
+        .map(|line| {
+            line.split(" -> ")
+                .next()
+                .unwrap()
+                .rsplit('/')
+                .next()
+                .unwrap()
+        })
+        .collect();
+    // Four 14-byte files in between, by path.
+    assert_eq!(
+        started,
+        [
+            "a.txt",
+            "nested.txt",
+            "test3.txt",
+            "test1.txt",
+            "test2.txt",
+            "big.bin"
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rename_template() {
+    let (_temp, test_dir) = setup_test_env();
+    let archive = test_dir.join("output/archive");
+
+    // A download has no metadata to read before it arrives, but its name and today do.
+    let output = Command::new(get_binary_path())
+        .env("PATH", fake_ssh_path(&test_dir))
+        .args(["--no-ssh-multiplex", "--rename", "export-{date}.{ext}"])
+        .arg(format!(
+            "user@localhost:{}",
+            test_dir.join("input/test1.txt").display()
+        ))
+        .arg(format!("{}/", archive.display()))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let names: Vec<String> = fs::read_dir(&archive)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 1);
+    assert!(
+        names[0].starts_with("export-20") && names[0].ends_with(".txt"),
+        "{:?}",
+        names
+    );
+
+    let output = Command::new(get_binary_path())
+        .args(["-r", "--rename", "{stem}_{size}.{ext}"])
+        .arg(test_dir.join("input"))
+        .arg(test_dir.join("output/sized"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let sized = test_dir.join("output/sized");
+    assert!(sized.join("test1_14.txt").exists());
+    assert!(sized.join("subdir/nested_14.txt").exists());
+
+    for bad in [
+        &["--rename", "{nme}"][..],
+        &["--rename", "{name}", "--compare", "size-mtime"],
+    ] {
+        let output = Command::new(get_binary_path())
+            .arg("-r")
+            .args(bad)
+            .arg(test_dir.join("input"))
+            .arg(test_dir.join("output/refused"))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", bad);
+        assert!(!test_dir.join("output/refused").exists());
+    }
+}
+
+#[test]
+fn test_append_grown_files() {
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("logs");
+    let dst = test_dir.join("replica");
+    fs::create_dir_all(&src).unwrap();
+    let lines = |from: usize, to: usize| -> String {
+        (from..to).map(|i| format!("event {}\n", i)).collect()
+    };
+    fs::write(src.join("app.log"), lines(0, 5000)).unwrap();
+    fs::write(src.join("rotated.log"), lines(0, 5000)).unwrap();
+    let sync = || {
+        let output = Command::new(get_binary_path())
+            .args(["-r", "-v", "--compare", "size-only", "--append"])
+            .arg(format!("{}/", src.display()))
+            .arg(format!("{}/", dst.display()))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let stdout = sync();
+    assert!(!stdout.contains("Files appended"), "{}", stdout);
+
+    // app.log only grew; rotated.log was started over, and is longer now too.
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(src.join("app.log"))
+        .unwrap();
+    std::io::Write::write_all(&mut log, lines(5000, 6000).as_bytes()).unwrap();
+    fs::write(src.join("rotated.log"), lines(10_000, 16_000)).unwrap();
+
+    let stdout = sync();
+    let tail = lines(5000, 6000).len();
+    assert!(
+        stdout.contains(&format!("Appended {} bytes", tail)),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("no longer matches the start of the source"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Files appended: 1"), "{}", stdout);
+    for name in ["app.log", "rotated.log"] {
+        assert_eq!(
+            fs::read(dst.join(name)).unwrap(),
+            fs::read(src.join(name)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_files_from() {
+    use std::io::Write;
+
+    let (_temp, test_dir) = setup_test_env();
+    let src = test_dir.join("input");
+    fs::create_dir_all(src.join("build out")).unwrap();
+    fs::write(src.join("build out/app v2.bin"), "binary").unwrap();
+
+    // A list written on Windows, on standard input; no -r is needed.
+    let mut child = Command::new(get_binary_path())
+        .args(["--files-from", "-"])
+        .arg(&src)
+        .arg(test_dir.join("output/shipped"))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"build out/app v2.bin\r\nsubdir/nested.txt\r\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let shipped = test_dir.join("output/shipped");
+    assert_eq!(
+        fs::read_to_string(shipped.join("build out/app v2.bin")).unwrap(),
+        "binary"
+    );
+    assert!(shipped.join("subdir/nested.txt").exists());
+    assert!(!shipped.join("test1.txt").exists());
+    assert!(!shipped.join("subdir/test3.txt").exists());
+
+    let list = test_dir.join("list0");
+    fs::write(&list, b"test1.txt\0missing.txt\0").unwrap();
+    let run = |extra: &[&str]| {
+        Command::new(get_binary_path())
+            .arg("--files-from")
+            .arg(&list)
+            .arg("--from0")
+            .args(extra)
+            .arg(&src)
+            .arg(test_dir.join("output/nul"))
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.txt"));
+    let output = run(&["--ignore-missing-args"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(test_dir.join("output/nul/test1.txt").exists());
+}
+
+/// Without scp and ssh on PATH, an SSH copy stops before it starts, naming what to
+/// install, with exit code 69.
+#[test]
+fn test_missing_tool() {
+    let (_temp, test_dir) = setup_test_env();
+    let empty = test_dir.join("bin");
+    fs::create_dir_all(&empty).unwrap();
+    let output = Command::new(get_binary_path())
+        .env("PATH", &empty)
+        .args(["-r", "--no-ssh-multiplex"])
+        .arg(test_dir.join("input"))
+        .arg("user@localhost:/srv/backup/")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(69), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("ssh is not installed"), "{}", stderr);
+    #[cfg(target_os = "linux")]
+    assert!(stderr.contains("openssh-client"), "{}", stderr);
+}
@@ -0,0 +1,53 @@
+//! Copies against real servers in Docker: an OpenSSH server and MinIO, started per
+//! test with testcontainers.
+//!
+//! They need a Docker daemon (and the `aws` CLI for the S3 half), so they pass without
+//! doing anything unless `USYNC_REMOTE_TESTS=1` is set:
+//!
+//! ```text
+//! USYNC_REMOTE_TESTS=1 cargo test --test integration-remote
+//! ```
+
+mod s3;
+mod ssh;
+mod tree;
+
+use std::process::{Command, Output};
+
+/// Whether the suite should run; says why not when it shouldn't.
+pub fn enabled() -> bool {
+    let on = std::env::var("USYNC_REMOTE_TESTS").is_ok_and(|v| v == "1");
+    if !on {
+        eprintln!("skipped: set USYNC_REMOTE_TESTS=1 to run against Docker containers");
+    }
+    on
+}
+
+/// The usync binary cargo built for this test run.
+pub fn usync() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_usync"))
+}
+
+/// Run `cmd` and return its output, failing the test with its stderr if it failed.
+pub fn run(cmd: &mut Command) -> Output {
+    let output = cmd.output().expect("run usync");
+    assert!(
+        output.status.success(),
+        "{:?} failed:\n{}",
+        cmd,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+/// Run `cmd`, expecting it to fail, and return its stderr.
+pub fn run_failing(cmd: &mut Command) -> String {
+    let output = cmd.output().expect("run usync");
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(!output.status.success(), "{:?} succeeded:\n{}", cmd, stderr);
+    stderr
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
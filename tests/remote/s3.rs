@@ -0,0 +1,207 @@
+//! S3 copies against MinIO, reached through `AWS_ENDPOINT_URL` as any S3-compatible
+//! service would be.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::SyncRunner;
+use testcontainers::{Container, GenericImage, ImageExt};
+
+use crate::tree;
+use crate::{enabled, run, run_failing, stdout, usync};
+
+const ACCESS_KEY: &str = "usync-test";
+const SECRET_KEY: &str = "usync-test-secret";
+const BUCKET: &str = "usync";
+
+/// A MinIO server with one empty bucket.
+struct Minio {
+    _container: Container<GenericImage>,
+    endpoint: String,
+    dir: tempfile::TempDir,
+}
+
+impl Minio {
+    fn start() -> Self {
+        let container = GenericImage::new("minio/minio", "latest")
+            .with_exposed_port(9000.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("API:"))
+            .with_env_var("MINIO_ROOT_USER", ACCESS_KEY)
+            .with_env_var("MINIO_ROOT_PASSWORD", SECRET_KEY)
+            .with_cmd(["server", "/data"])
+            .start()
+            .expect("start the MinIO container");
+        let endpoint = format!(
+            "http://{}:{}",
+            container.get_host().unwrap(),
+            container.get_host_port_ipv4(9000).unwrap()
+        );
+        let minio = Self {
+            _container: container,
+            endpoint,
+            dir: tempfile::tempdir().unwrap(),
+        };
+        let mut mb = minio.aws();
+        mb.args(["s3", "mb", &format!("s3://{}", BUCKET)]);
+        let status = mb.status().expect("run the aws CLI");
+        assert!(status.success(), "aws s3 mb failed");
+        minio
+    }
+
+    /// `key` in the test bucket.
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{}", BUCKET, key)
+    }
+
+    fn local(&self, name: &str) -> PathBuf {
+        let path = self.dir.path().join(name);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Point `cmd` at MinIO with `secret`, ignoring the user's own AWS configuration.
+    fn configure(&self, cmd: &mut Command, secret: Option<&str>) {
+        let missing = self.dir.path().join("no-aws-config");
+        cmd.env_remove("AWS_PROFILE")
+            .env_remove("AWS_ENDPOINT_URL_S3")
+            .env_remove("AWS_SESSION_TOKEN")
+            .env("AWS_CONFIG_FILE", &missing)
+            .env("AWS_SHARED_CREDENTIALS_FILE", &missing)
+            .env("AWS_ENDPOINT_URL", &self.endpoint)
+            .env("AWS_REGION", "us-east-1")
+            .env("AWS_DEFAULT_REGION", "us-east-1");
+        match secret {
+            Some(secret) => cmd
+                .env("AWS_ACCESS_KEY_ID", ACCESS_KEY)
+                .env("AWS_SECRET_ACCESS_KEY", secret),
+            None => cmd
+                .env_remove("AWS_ACCESS_KEY_ID")
+                .env_remove("AWS_SECRET_ACCESS_KEY"),
+        };
+    }
+
+    fn aws(&self) -> Command {
+        let mut cmd = Command::new("aws");
+        self.configure(&mut cmd, Some(SECRET_KEY));
+        cmd.arg("--endpoint-url").arg(&self.endpoint);
+        cmd
+    }
+
+    fn usync_with_secret(&self, secret: Option<&str>) -> Command {
+        let mut cmd = usync();
+        self.configure(&mut cmd, secret);
+        cmd
+    }
+
+    fn usync(&self) -> Command {
+        self.usync_with_secret(Some(SECRET_KEY))
+    }
+
+    fn exists(&self, key: &str) -> bool {
+        self.aws()
+            .args(["s3api", "head-object", "--bucket", BUCKET, "--key", key])
+            .output()
+            .unwrap()
+            .status
+            .success()
+    }
+}
+
+#[test]
+fn file_upload_and_download_with_checksum() {
+    if !enabled() {
+        return;
+    }
+    let minio = Minio::start();
+    let src = minio.local("src");
+    tree::sample(&src);
+
+    // A single-part upload's ETag is its MD5, so MD5 verifies without a download.
+    let output = run(minio
+        .usync()
+        .args(["-v", "--checksum=md5"])
+        .arg(src.join("data/blob.bin"))
+        .arg(minio.url("blob.bin")));
+    assert!(
+        stdout(&output).contains("✓ Verified"),
+        "{}",
+        stdout(&output)
+    );
+
+    let back = minio.local("back");
+    let output = run(minio
+        .usync()
+        .args(["-v", "--checksum", "--verify-mode", "strict"])
+        .arg(minio.url("blob.bin"))
+        .arg(back.join("blob.bin")));
+    assert!(
+        stdout(&output).contains("✓ Verified"),
+        "{}",
+        stdout(&output)
+    );
+    assert_eq!(
+        fs::read(back.join("blob.bin")).unwrap(),
+        fs::read(src.join("data/blob.bin")).unwrap()
+    );
+}
+
+#[test]
+fn directory_sync_round_trip() {
+    if !enabled() {
+        return;
+    }
+    let minio = Minio::start();
+    let src = minio.local("src");
+    tree::sample(&src);
+    let upload = || {
+        run(minio
+            .usync()
+            .args(["-r", "--compare", "size-only"])
+            .arg(format!("{}/", src.display()))
+            .arg(minio.url("tree/")));
+    };
+
+    upload();
+    assert!(minio.exists("tree/data/nested/deep.txt"));
+    fs::write(src.join("readme.txt"), "top level, now longer\n").unwrap();
+    tree::write(&src, &[("data/new.txt", b"added after the first sync\n")]);
+    upload();
+
+    let back = minio.local("back");
+    run(minio
+        .usync()
+        .arg("-r")
+        .arg(minio.url("tree/"))
+        .arg(format!("{}/", back.display())));
+    tree::assert_same(&src, &back);
+}
+
+#[test]
+fn bad_or_missing_credentials_fail() {
+    if !enabled() {
+        return;
+    }
+    let minio = Minio::start();
+    let src = minio.local("src");
+    tree::sample(&src);
+    let file = src.join("readme.txt");
+
+    let stderr = run_failing(
+        minio
+            .usync_with_secret(Some("not-the-secret"))
+            .arg(&file)
+            .arg(minio.url("denied.txt")),
+    );
+    assert!(stderr.contains("SignatureDoesNotMatch"), "{}", stderr);
+
+    let stderr = run_failing(
+        minio
+            .usync_with_secret(None)
+            .arg(&file)
+            .arg(minio.url("denied.txt")),
+    );
+    assert!(stderr.contains("credentials"), "{}", stderr);
+    assert!(!minio.exists("denied.txt"));
+}
@@ -0,0 +1,266 @@
+//! SSH copies against the linuxserver.io OpenSSH server image.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::SyncRunner;
+use testcontainers::{Container, GenericImage, ImageExt};
+
+use crate::tree;
+use crate::{enabled, run, run_failing, stdout, usync};
+
+const USER: &str = "usync";
+
+/// An OpenSSH server accepting a key generated for it, with `/config` as the home.
+struct SshServer {
+    _container: Container<GenericImage>,
+    host: String,
+    port: u16,
+    key: PathBuf,
+    dir: tempfile::TempDir,
+}
+
+impl SshServer {
+    fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("id_ed25519");
+        let keygen = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status()
+            .expect("run ssh-keygen");
+        assert!(keygen.success());
+        let public = fs::read_to_string(key.with_extension("pub")).unwrap();
+
+        let container = GenericImage::new("lscr.io/linuxserver/openssh-server", "latest")
+            .with_exposed_port(2222.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("[ls.io-init] done."))
+            .with_env_var("USER_NAME", USER)
+            .with_env_var("PUBLIC_KEY", public.trim())
+            .start()
+            .expect("start the OpenSSH container");
+        let host = container.get_host().unwrap().to_string();
+        let port = container.get_host_port_ipv4(2222).unwrap();
+        Self {
+            _container: container,
+            host,
+            port,
+            key,
+            dir,
+        }
+    }
+
+    /// `path` under the user's home, as usync spells it.
+    fn url(&self, path: &str) -> String {
+        format!("{}@{}:/config/{}", USER, self.host, path)
+    }
+
+    /// A local scratch directory for this test.
+    fn local(&self, name: &str) -> PathBuf {
+        let path = self.dir.path().join(name);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// usync pointed at this server with `key`, not prompting for anything.
+    fn usync_with_key(&self, key: &Path) -> Command {
+        let mut cmd = usync();
+        cmd.arg("-P")
+            .arg(self.port.to_string())
+            .arg("-s")
+            .arg(format!("IdentityFile={}", key.display()))
+            .args(["-s", "IdentitiesOnly=yes"])
+            .args(["-s", "StrictHostKeyChecking=no"])
+            .args(["-s", "UserKnownHostsFile=/dev/null"])
+            .args(["-s", "LogLevel=ERROR"])
+            .arg("--ssh-batch");
+        cmd
+    }
+
+    fn usync(&self) -> Command {
+        self.usync_with_key(&self.key)
+    }
+}
+
+#[test]
+fn file_upload_and_download_with_checksum() {
+    if !enabled() {
+        return;
+    }
+    let server = SshServer::start();
+    let src = server.local("src");
+    tree::sample(&src);
+
+    let output = run(server
+        .usync()
+        .args(["-v", "--checksum"])
+        .arg(src.join("data/blob.bin"))
+        .arg(server.url("blob.bin")));
+    assert!(
+        stdout(&output).contains("✓ Verified"),
+        "{}",
+        stdout(&output)
+    );
+
+    let back = server.local("back");
+    let output = run(server
+        .usync()
+        .args(["-v", "--checksum=md5"])
+        .arg(server.url("blob.bin"))
+        .arg(back.join("blob.bin")));
+    assert!(
+        stdout(&output).contains("✓ Verified"),
+        "{}",
+        stdout(&output)
+    );
+    assert_eq!(
+        fs::read(back.join("blob.bin")).unwrap(),
+        fs::read(src.join("data/blob.bin")).unwrap()
+    );
+}
+
+#[test]
+fn directory_round_trip_with_checksum() {
+    if !enabled() {
+        return;
+    }
+    let server = SshServer::start();
+    let src = server.local("src");
+    tree::sample(&src);
+
+    run(server
+        .usync()
+        .args(["-r", "--checksum"])
+        .arg(format!("{}/", src.display()))
+        .arg(server.url("tree/")));
+
+    let back = server.local("back");
+    run(server
+        .usync()
+        .args(["-r", "--checksum"])
+        .arg(server.url("tree/"))
+        .arg(format!("{}/", back.display())));
+    tree::assert_same(&src, &back);
+}
+
+#[test]
+fn directory_filters_apply_both_ways() {
+    if !enabled() {
+        return;
+    }
+    let server = SshServer::start();
+    let src = server.local("src");
+    tree::sample(&src);
+
+    run(server
+        .usync()
+        .args(["-r", "--exclude", "*.log"])
+        .arg(format!("{}/", src.display()))
+        .arg(server.url("filtered/")));
+    let back = server.local("back");
+    run(server
+        .usync()
+        .arg("-r")
+        .arg(server.url("filtered/"))
+        .arg(format!("{}/", back.display())));
+    tree::assert_holds(&back, &tree::sample_without_logs());
+
+    // Everything goes up; the download leaves the logs behind.
+    run(server
+        .usync()
+        .arg("-r")
+        .arg(format!("{}/", src.display()))
+        .arg(server.url("full/")));
+    let back = server.local("back-filtered");
+    run(server
+        .usync()
+        .args(["-r", "--exclude", "*.log"])
+        .arg(server.url("full/"))
+        .arg(format!("{}/", back.display())));
+    tree::assert_holds(&back, &tree::sample_without_logs());
+}
+
+#[test]
+fn sync_copies_only_what_changed() {
+    if !enabled() {
+        return;
+    }
+    let server = SshServer::start();
+    let src = server.local("src");
+    tree::sample(&src);
+    let upload = || {
+        stdout(&run(server
+            .usync()
+            .args(["-r", "-v", "--compare", "size-only"])
+            .arg(format!("{}/", src.display()))
+            .arg(server.url("synced/"))))
+    };
+
+    let first = upload();
+    assert!(
+        first.contains(&format!("Files copied: {}", tree::SAMPLE.len())),
+        "{}",
+        first
+    );
+    let second = upload();
+    assert!(second.contains("Files copied: 0"), "{}", second);
+
+    fs::write(src.join("readme.txt"), "top level, now longer\n").unwrap();
+    let third = upload();
+    assert!(third.contains("Files copied: 1"), "{}", third);
+
+    let back = server.local("back");
+    run(server
+        .usync()
+        .arg("-r")
+        .arg(server.url("synced/"))
+        .arg(format!("{}/", back.display())));
+    tree::assert_same(&src, &back);
+}
+
+#[test]
+fn wrong_or_missing_key_is_an_auth_failure() {
+    if !enabled() {
+        return;
+    }
+    let server = SshServer::start();
+    let src = server.local("src");
+    tree::sample(&src);
+    let file = src.join("readme.txt");
+
+    let other = server.local("keys").join("other");
+    let keygen = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&other)
+        .status()
+        .unwrap();
+    assert!(keygen.success());
+    let stderr = run_failing(
+        server
+            .usync_with_key(&other)
+            .arg(&file)
+            .arg(server.url("denied.txt")),
+    );
+    assert!(stderr.contains("Permission denied"), "{}", stderr);
+
+    let missing = server.local("keys").join("missing");
+    let stderr = run_failing(
+        server
+            .usync_with_key(&missing)
+            .arg(&file)
+            .arg(server.url("denied.txt")),
+    );
+    assert!(stderr.contains("Permission denied"), "{}", stderr);
+
+    // Neither attempt left anything behind.
+    let back = server.local("back");
+    run_failing(
+        server
+            .usync()
+            .arg(server.url("denied.txt"))
+            .arg(back.join("denied.txt")),
+    );
+    assert!(!back.join("denied.txt").exists());
+}
@@ -0,0 +1,90 @@
+//! Trees to copy and the comparisons made after copying them.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Files of [`sample`], relative path first. The `.log` files are what the filter tests
+/// exclude.
+pub const SAMPLE: &[(&str, &[u8])] = &[
+    ("readme.txt", b"top level\n"),
+    ("empty.txt", b""),
+    ("build.log", b"excluded\n"),
+    ("data/blob.bin", &[0, 1, 2, 255, 254, 0, 10, 13]),
+    ("data/nested/deep.txt", b"three levels down\n"),
+    ("data/nested/trace.log", b"also excluded\n"),
+    ("with space/name with space.txt", b"spaces survive\n"),
+];
+
+/// Write `files` under `root`, creating directories as needed.
+pub fn write(root: &Path, files: &[(&str, &[u8])]) {
+    for (rel, contents) in files {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+/// Write [`SAMPLE`] under `root`.
+pub fn sample(root: &Path) {
+    write(root, SAMPLE);
+}
+
+/// Every file under `root` by `/`-separated relative path, with its contents.
+pub fn read(root: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    read_into(root, "", &mut files);
+    files
+}
+
+fn read_into(dir: &Path, prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let rel = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type().unwrap().is_dir() {
+            read_into(&entry.path(), &format!("{}/", rel), files);
+        } else {
+            files.insert(rel, fs::read(entry.path()).unwrap());
+        }
+    }
+}
+
+/// [`SAMPLE`] without the files matching the `.log` exclude.
+pub fn sample_without_logs() -> BTreeMap<String, Vec<u8>> {
+    SAMPLE
+        .iter()
+        .filter(|(rel, _)| !rel.ends_with(".log"))
+        .map(|(rel, contents)| (rel.to_string(), contents.to_vec()))
+        .collect()
+}
+
+/// Assert that `root` holds exactly `expected`, naming the paths that differ.
+pub fn assert_holds(root: &Path, expected: &BTreeMap<String, Vec<u8>>) {
+    let actual = read(root);
+    let missing: Vec<_> = expected
+        .keys()
+        .filter(|k| !actual.contains_key(*k))
+        .collect();
+    let extra: Vec<_> = actual
+        .keys()
+        .filter(|k| !expected.contains_key(*k))
+        .collect();
+    let changed: Vec<_> = expected
+        .iter()
+        .filter(|(k, v)| actual.get(*k).is_some_and(|a| a != *v))
+        .map(|(k, _)| k)
+        .collect();
+    assert!(
+        missing.is_empty() && extra.is_empty() && changed.is_empty(),
+        "{}: missing {:?}, unexpected {:?}, different {:?}",
+        root.display(),
+        missing,
+        extra,
+        changed
+    );
+}
+
+/// Assert that `a` and `b` hold the same files with the same contents.
+pub fn assert_same(a: &Path, b: &Path) {
+    assert_holds(b, &read(a));
+}